@@ -4,7 +4,7 @@ local M = {}
 M.config = {
 	-- Default shell command
 	shell = vim.env.SHELL or "/bin/sh",
-	-- Socket directory (nil = let daemon decide)
+	-- Socket directory (nil = let daemon decide); passed as --socket-dir
 	socket_dir = nil,
 	auto_redraw = true,
 	auto_redraw_delay_ms = 1000,
@@ -48,11 +48,21 @@ local function find_binary()
 	error("pterm binary not found. Install pterm with Nix or build it in this repository.")
 end
 
+--- argv for a pterm subcommand. A configured `socket_dir` is passed as
+--- `--socket-dir`, so the CLI and the daemons it starts use the same socket
+--- root the plugin scans.
+local function pterm_command(...)
+	local cmd = { find_binary() }
+	if M.config.socket_dir then
+		vim.list_extend(cmd, { "--socket-dir", M.config.socket_dir })
+	end
+	return vim.list_extend(cmd, { ... })
+end
+
 local function trigger_redraw(session_name)
 	local conn = connections[session_name]
 	if conn and conn.job_id then
-		local bin = find_binary()
-		vim.fn.jobstart({ bin, "redraw", session_name })
+		vim.fn.jobstart(pterm_command("redraw", session_name))
 	end
 end
 
@@ -131,8 +141,7 @@ function M.kill(session_name)
 		M.detach(session_name)
 	end
 
-	local bin = find_binary()
-	vim.fn.system({ bin, "kill", session_name })
+	vim.fn.system(pterm_command("kill", session_name))
 	vim.notify("Killed session: " .. session_name, vim.log.levels.INFO)
 end
 
@@ -207,6 +216,34 @@ local function schedule_redraw(session_name, delay_ms)
 	)
 end
 
+--- Fill buffer-local variables from `pterm info`: b:pterm_cmd, b:pterm_cwd
+--- and b:pterm_term.
+local function set_session_vars(buf, session_name)
+	vim.system(pterm_command("info", "--json", session_name), { text = true }, function(res)
+		if res.code ~= 0 then
+			return
+		end
+		local ok, info = pcall(vim.json.decode, res.stdout)
+		if not ok or type(info) ~= "table" then
+			return
+		end
+		vim.schedule(function()
+			if not vim.api.nvim_buf_is_valid(buf) then
+				return
+			end
+			if type(info.command) == "table" then
+				vim.b[buf].pterm_cmd = table.concat(info.command, " ")
+			end
+			if type(info.cwd) == "string" then
+				vim.b[buf].pterm_cwd = info.cwd
+			end
+			if type(info.term) == "string" then
+				vim.b[buf].pterm_term = info.term
+			end
+		end)
+	end)
+end
+
 --- Internal: create a terminal buffer and start a pterm bridge process.
 --- `cmd` is the full argv for jobstart (e.g. {"pterm","open","main"}).
 local function start_terminal(session_name, cmd)
@@ -260,6 +297,7 @@ local function start_terminal(session_name, cmd)
 	end
 
 	vim.api.nvim_buf_set_name(buf, buf_name)
+	vim.b[buf].pterm_session = session_name
 
 	-- Store connection
 	connections[session_name] = {
@@ -331,6 +369,40 @@ local function start_terminal(session_name, cmd)
 		})
 	end
 
+	-- Once attached, the bridge reports the session's cwd as OSC 7. The first
+	-- report also means the daemon is up, so fetch the rest of the session
+	-- info then. Other clients typing into the session are reported as the
+	-- `pterm_typing` user variable (OSC 1337 SetUserVar, base64 value).
+	if vim.fn.exists("##TermRequest") == 1 then
+		vim.api.nvim_create_autocmd("TermRequest", {
+			group = augroup,
+			buffer = buf,
+			callback = function(ev)
+				local sequence = type(ev.data) == "table" and ev.data.sequence or ev.data
+				if type(sequence) ~= "string" then
+					return
+				end
+				local typing = sequence:match("^\27%]1337;SetUserVar=pterm_typing=([%w+/=]*)")
+				if typing then
+					local ok, label = pcall(vim.base64.decode, typing)
+					vim.b[buf].pterm_typing = (ok and label ~= "") and label or nil
+					vim.cmd.redrawstatus()
+					return
+				end
+				local path = sequence:match("^\27%]7;file://[^/]*(/[^\27\7]*)")
+				if not path then
+					return
+				end
+				vim.b[buf].pterm_cwd = path:gsub("%%(%x%x)", function(hex)
+					return string.char(tonumber(hex, 16))
+				end)
+				if vim.b[buf].pterm_cmd == nil then
+					set_session_vars(buf, session_name)
+				end
+			end,
+		})
+	end
+
 	vim.api.nvim_create_autocmd("BufEnter", {
 		group = augroup,
 		buffer = buf,
@@ -377,10 +449,8 @@ function M.open(session_name, args)
 		end
 	end
 
-	local bin = find_binary()
-
 	-- Build `pterm open` command with optional child command arguments.
-	local cmd = { bin, "open", session_name }
+	local cmd = pterm_command("open", session_name)
 
 	local cmd_parts = {}
 	local found_name = false
@@ -411,8 +481,8 @@ function M.attach(session_name)
 		return
 	end
 
-	local bin = find_binary()
-	start_terminal(session_name, { bin, "attach", session_name })
+	-- Nesting is the point here when Neovim itself runs in a session.
+	start_terminal(session_name, pterm_command("attach", session_name, "--force-nested"))
 end
 
 --- Detach from a session (does not kill the daemon).
@@ -429,8 +499,7 @@ function M.redraw(session_name)
 		return
 	end
 
-	local bin = find_binary()
-	vim.fn.system({ bin, "redraw", session_name })
+	vim.fn.system(pterm_command("redraw", session_name))
 	if vim.v.shell_error ~= 0 then
 		vim.notify("Failed to redraw session '" .. session_name .. "'", vim.log.levels.ERROR)
 	end
@@ -0,0 +1,121 @@
+//! `PtermCodec`: the frame format as a `tokio_util` codec (feature `tokio`),
+//! so async consumers can wrap a socket in a `Framed` instead of
+//! reimplementing the framing. It follows [`FrameDecoder`]: a header over the
+//! length limit is an error, since the stream cannot be resynchronized
+//! after it.
+//!
+//! [`FrameDecoder`]: crate::FrameDecoder
+
+use crate::{decode_header, Frame, FrameTooLarge, HEADER_SIZE};
+use bytes::{Buf, BufMut, BytesMut};
+use std::io;
+use tokio_util::codec::{Decoder, Encoder};
+
+/// Encodes and decodes [`Frame`]s.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PtermCodec {
+    max_len: usize,
+}
+
+impl Default for PtermCodec {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PtermCodec {
+    /// A codec that accepts any frame length the header can express.
+    pub fn new() -> Self {
+        Self::with_max_len(u32::MAX as usize)
+    }
+
+    /// A codec that fails on frames with payloads over `max_len` bytes.
+    pub fn with_max_len(max_len: usize) -> Self {
+        Self { max_len }
+    }
+}
+
+impl Decoder for PtermCodec {
+    type Item = Frame;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<Frame>> {
+        let Some(header) = src.get(..HEADER_SIZE) else {
+            return Ok(None);
+        };
+        let (msg_type, len) = decode_header(header.try_into().unwrap());
+        let len = len as usize;
+        if len > self.max_len {
+            let too_large = FrameTooLarge {
+                msg_type,
+                len,
+                max_len: self.max_len,
+            };
+            return Err(io::Error::new(io::ErrorKind::InvalidData, too_large));
+        }
+        if src.len() < HEADER_SIZE + len {
+            src.reserve(HEADER_SIZE + len - src.len());
+            return Ok(None);
+        }
+        src.advance(HEADER_SIZE);
+        let payload = src.split_to(len).to_vec();
+        Ok(Some(Frame { msg_type, payload }))
+    }
+}
+
+impl Encoder<Frame> for PtermCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, frame: Frame, dst: &mut BytesMut) -> io::Result<()> {
+        let len = u32::try_from(frame.payload.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "payload too large"))?;
+        dst.reserve(HEADER_SIZE + frame.payload.len());
+        dst.put_u8(frame.msg_type);
+        dst.put_u32_le(len);
+        dst.put_slice(&frame.payload);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{client, encode};
+
+    #[test]
+    fn round_trips_frames_split_anywhere() {
+        let frames = [
+            Frame {
+                msg_type: client::INPUT,
+                payload: b"ls\r".to_vec(),
+            },
+            Frame {
+                msg_type: client::INFO,
+                payload: Vec::new(),
+            },
+        ];
+        let mut codec = PtermCodec::new();
+        let mut wire = BytesMut::new();
+        for frame in frames.clone() {
+            codec.encode(frame, &mut wire).unwrap();
+        }
+        let expected: Vec<u8> =
+            [encode(client::INPUT, b"ls\r"), encode(client::INFO, &[])].concat();
+        assert_eq!(&wire[..], &expected[..]);
+
+        let mut src = BytesMut::new();
+        let mut decoded = Vec::new();
+        for byte in expected {
+            src.put_u8(byte);
+            decoded.extend(codec.decode(&mut src).unwrap());
+        }
+        assert_eq!(decoded, frames);
+        assert!(src.is_empty());
+
+        let mut oversized = BytesMut::from(&encode(client::INPUT, b"too long")[..]);
+        let error = PtermCodec::with_max_len(4)
+            .decode(&mut oversized)
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,672 @@
+//! Golden frames for checking an implementation of the wire protocol
+//! against this one (feature `alloc`).
+//!
+//! [`vectors`] lists one canonical frame for every message type in both
+//! directions, frames at the payload sizes where the length field changes
+//! bytes, structured payloads with what they decode to, and payloads the
+//! parsers must refuse. Each vector carries its exact bytes under both
+//! [`Framing`]s, so an independent client (the Lua client, the web gateway)
+//! can compare its own encoder and decoder byte for byte; [`verify`] does
+//! that for anything implementing [`Implementation`], including every
+//! truncation of every frame and corrupted checksums.
+//!
+//! The vectors are part of the protocol: a change to them is a change to
+//! the wire format and needs a [`PROTOCOL_VERSION`] bump.
+//!
+//! [`PROTOCOL_VERSION`]: crate::PROTOCOL_VERSION
+
+use crate::{
+    client, crc32, encode_channel, encode_client_id, encode_credit, encode_exit,
+    encode_exit_details, encode_input_seq, encode_resize, server, ClientMessage, DecodeError,
+    Framing, ServerMessage, CRC_SIZE, HEADER_SIZE,
+};
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+
+/// Who sends a frame: the message type registry it belongs to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Client → daemon, [`client`] types.
+    Client,
+    /// Daemon → client, [`server`] types.
+    Server,
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Client => "client",
+            Self::Server => "server",
+        })
+    }
+}
+
+/// What a vector's payload means to a receiver.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Payload {
+    /// Bytes passed on as they are (terminal I/O, text, no payload).
+    Bytes,
+    /// A JSON document.
+    Json,
+    /// `RESIZE`.
+    Resize { cols: u16, rows: u16 },
+    /// `INPUT_SEQ`.
+    InputSeq { seq: u64, input: Vec<u8> },
+    /// A client id, as in `DETACH_CLIENTS`.
+    ClientId(u32),
+    /// `EXIT`, with the JSON details (empty if none).
+    Exit { code: i32, details: Vec<u8> },
+    /// `CREDIT`.
+    Credit(u64),
+    /// `CHANNEL`: the frame carried for `channel`.
+    Channel {
+        channel: u32,
+        msg_type: u8,
+        payload: Vec<u8>,
+    },
+    /// `COMPRESSED_OUTPUT` / `COMPRESSED_STATE_SYNC`: one zstd frame that
+    /// decompresses to `content`.
+    Zstd { content: Vec<u8> },
+    /// A well-framed payload the parser must refuse with this error.
+    Invalid(DecodeError),
+}
+
+/// One golden frame.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Vector {
+    /// Unique, e.g. `client RESIZE` or `server OUTPUT (256 bytes)`.
+    pub name: String,
+    pub direction: Direction,
+    pub msg_type: u8,
+    pub payload: Vec<u8>,
+    pub meaning: Payload,
+    /// The frame under [`Framing::Plain`].
+    pub plain: Vec<u8>,
+    /// The frame under [`Framing::Crc32`].
+    pub crc32: Vec<u8>,
+}
+
+impl Vector {
+    fn new(direction: Direction, msg_type: u8, payload: Vec<u8>, meaning: Payload) -> Self {
+        let name = match direction {
+            Direction::Client => ClientMessage::from_u8(msg_type).map(ClientMessage::name),
+            Direction::Server => ServerMessage::from_u8(msg_type).map(ServerMessage::name),
+        };
+        let mut plain = Vec::with_capacity(HEADER_SIZE + payload.len() + CRC_SIZE);
+        plain.push(msg_type);
+        plain.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        plain.extend_from_slice(&payload);
+        let mut checked = plain.clone();
+        checked.extend_from_slice(&crc32(&plain).to_le_bytes());
+        Self {
+            name: format!("{} {}", direction, name.unwrap_or("unknown")),
+            direction,
+            msg_type,
+            payload,
+            meaning,
+            plain,
+            crc32: checked,
+        }
+    }
+
+    fn named(mut self, detail: &str) -> Self {
+        self.name = format!("{} ({})", self.name, detail);
+        self
+    }
+
+    /// The frame under `framing`.
+    pub fn encoded(&self, framing: Framing) -> &[u8] {
+        match framing {
+            Framing::Plain => &self.plain,
+            Framing::Crc32 => &self.crc32,
+        }
+    }
+}
+
+/// Every golden frame: one per message type, then edge sizes, then
+/// payloads the parsers refuse.
+pub fn vectors() -> Vec<Vector> {
+    let mut vectors: Vec<Vector> = ClientMessage::ALL
+        .iter()
+        .map(|&m| canonical_client(m))
+        .collect();
+    vectors.extend(ServerMessage::ALL.iter().map(|&m| canonical_server(m)));
+
+    // Payload lengths around the length field's byte boundaries.
+    for len in [1, 255, 256, 65_535, 65_536] {
+        let payload = (0..len).map(|i| (i % 251) as u8).collect();
+        vectors.push(
+            Vector::new(Direction::Server, server::OUTPUT, payload, Payload::Bytes)
+                .named(&format!("{} bytes", len)),
+        );
+    }
+    let details = br#"{"code":0,"signal":9,"core_dumped":false}"#;
+    vectors.push(
+        Vector::new(
+            Direction::Server,
+            server::EXIT,
+            encode_exit_details(137, details),
+            Payload::Exit {
+                code: 137,
+                details: details.to_vec(),
+            },
+        )
+        .named("details"),
+    );
+    vectors.push(
+        Vector::new(
+            Direction::Server,
+            server::EXIT,
+            encode_exit(-1).to_vec(),
+            Payload::Exit {
+                code: -1,
+                details: Vec::new(),
+            },
+        )
+        .named("negative"),
+    );
+    vectors.push(
+        Vector::new(
+            Direction::Client,
+            client::RESIZE,
+            encode_resize(10_000, 1).to_vec(),
+            Payload::Resize {
+                cols: 10_000,
+                rows: 1,
+            },
+        )
+        .named("largest"),
+    );
+    vectors.push(
+        Vector::new(
+            Direction::Client,
+            client::INPUT_SEQ,
+            encode_input_seq(u64::MAX, b""),
+            Payload::InputSeq {
+                seq: u64::MAX,
+                input: Vec::new(),
+            },
+        )
+        .named("no input"),
+    );
+    let empty_frame = encode_channel(u32::MAX, client::INFO, b"");
+    vectors.push(
+        Vector::new(
+            Direction::Client,
+            client::CHANNEL,
+            empty_frame,
+            Payload::Channel {
+                channel: u32::MAX,
+                msg_type: client::INFO,
+                payload: Vec::new(),
+            },
+        )
+        .named("empty frame"),
+    );
+
+    let invalid = [
+        (
+            "short",
+            client::RESIZE,
+            vec![80, 0, 24],
+            DecodeError::InvalidResizePayloadLen(3),
+        ),
+        (
+            "zero",
+            client::RESIZE,
+            encode_resize(0, 24).to_vec(),
+            DecodeError::InvalidResizeSize(0, 24),
+        ),
+        (
+            "too large",
+            client::RESIZE,
+            encode_resize(10_001, 24).to_vec(),
+            DecodeError::InvalidResizeSize(10_001, 24),
+        ),
+        (
+            "short",
+            client::INPUT_SEQ,
+            vec![0; 7],
+            DecodeError::InvalidInputSeqPayloadLen(7),
+        ),
+        (
+            "long",
+            client::DETACH_CLIENTS,
+            vec![0; 5],
+            DecodeError::InvalidClientIdPayloadLen(5),
+        ),
+        (
+            "short",
+            client::CREDIT,
+            vec![0; 4],
+            DecodeError::InvalidCreditPayloadLen(4),
+        ),
+        (
+            "short",
+            client::CHANNEL,
+            vec![1, 0, 0],
+            DecodeError::InvalidChannelPayloadLen(3),
+        ),
+        (
+            "partial frame",
+            client::CHANNEL,
+            vec![1, 0, 0, 0, client::INPUT, 2, 0, 0, 0, b'x'],
+            DecodeError::InvalidChannelPayloadLen(10),
+        ),
+        (
+            "trailing bytes",
+            client::CHANNEL,
+            vec![1, 0, 0, 0, client::INFO, 0, 0, 0, 0, 0],
+            DecodeError::InvalidChannelPayloadLen(10),
+        ),
+    ];
+    for (detail, msg_type, payload, error) in invalid {
+        vectors.push(
+            Vector::new(
+                Direction::Client,
+                msg_type,
+                payload,
+                Payload::Invalid(error),
+            )
+            .named(&format!("invalid: {}", detail)),
+        );
+    }
+    vectors.push(
+        Vector::new(
+            Direction::Server,
+            server::EXIT,
+            vec![0; 3],
+            Payload::Invalid(DecodeError::InvalidExitPayloadLen(3)),
+        )
+        .named("invalid: short"),
+    );
+    vectors
+}
+
+fn canonical_client(message: ClientMessage) -> Vector {
+    use ClientMessage as M;
+    let json = |payload: &str| (payload.as_bytes().to_vec(), Payload::Json);
+    let (payload, meaning) = match message {
+        M::Input => (b"ls\r".to_vec(), Payload::Bytes),
+        M::Resize => (
+            encode_resize(80, 24).to_vec(),
+            Payload::Resize { cols: 80, rows: 24 },
+        ),
+        M::InputSeq => (
+            encode_input_seq(7, b"y\r"),
+            Payload::InputSeq {
+                seq: 7,
+                input: b"y\r".to_vec(),
+            },
+        ),
+        M::Detach
+        | M::Redraw
+        | M::PtyInputFd
+        | M::Pong
+        | M::ReadOnly
+        | M::ListClients
+        | M::Suspend
+        | M::Resume
+        | M::Info
+        | M::Capture
+        | M::Metadata => (Vec::new(), Payload::Bytes),
+        M::Upgrade => (b"/usr/local/bin/pterm".to_vec(), Payload::Bytes),
+        M::DetachClients => (encode_client_id(3).to_vec(), Payload::ClientId(3)),
+        M::Options => json(r#"{"name":"hold","value":"on"}"#),
+        M::Paste => (b"echo hi\n".to_vec(), Payload::Bytes),
+        M::Watch => json(r#"{"remove":1}"#),
+        M::ConfirmReply => json(r#"{"id":1,"accept":true}"#),
+        M::Exec => json(r#"{"command":["make","test"]}"#),
+        M::Transfer => json(r#"{"uid":1000}"#),
+        M::InputStream => json(r#"{"stream":1}"#),
+        M::Hello => json(r#"{"version":2,"min_version":1,"compression":["zstd"]}"#),
+        M::Signal => json(r#"{"signal":2}"#),
+        M::Attach => json(r#"{"skip_replay":false,"scrollback_lines":100}"#),
+        M::Identify => json(r#"{"pid":4242,"host":"box","tty":"/dev/pts/3","cols":80,"rows":24}"#),
+        M::Channel => (
+            encode_channel(1, client::RESIZE, &encode_resize(80, 24)),
+            Payload::Channel {
+                channel: 1,
+                msg_type: client::RESIZE,
+                payload: encode_resize(80, 24).to_vec(),
+            },
+        ),
+        M::OpenChannel => json(r#"{"channel":1,"session":"dev"}"#),
+        M::CloseChannel => json(r#"{"channel":1}"#),
+        M::Command => json(r#"{"cmd":"ping"}"#),
+        M::Credit => (encode_credit(65_536).to_vec(), Payload::Credit(65_536)),
+    };
+    Vector::new(Direction::Client, message.as_u8(), payload, meaning)
+}
+
+fn canonical_server(message: ServerMessage) -> Vector {
+    use ServerMessage as M;
+    let json = |payload: &str| (payload.as_bytes().to_vec(), Payload::Json);
+    let (payload, meaning) = match message {
+        M::Output => (b"hello\r\n".to_vec(), Payload::Bytes),
+        M::Exit => (
+            encode_exit(3).to_vec(),
+            Payload::Exit {
+                code: 3,
+                details: Vec::new(),
+            },
+        ),
+        M::PtyFd | M::Ping => (Vec::new(), Payload::Bytes),
+        M::Detached => (b"detached by client 2".to_vec(), Payload::Bytes),
+        M::Reply => json(r#"{"ok":true}"#),
+        M::CompressedOutput => (
+            zstd_raw(b"hello\r\n"),
+            Payload::Zstd {
+                content: b"hello\r\n".to_vec(),
+            },
+        ),
+        M::StderrOutput => (b"oops\r\n".to_vec(), Payload::Bytes),
+        M::SessionInfo => json(
+            r#"{"session":"dev","command":["bash"],"cwd":null,"term":"xterm-256color","cols":80,"rows":24,"pid":4242}"#,
+        ),
+        M::Confirm => json(r#"{"id":1,"line":"rm -rf build","pattern":"rm -rf"}"#),
+        M::Presence => json(r#"{"client":2,"label":"ssh","last_input":1700000000}"#),
+        M::ExecStatus => json(r#"{"id":1,"status":0}"#),
+        M::Hello => json(r#"{"version":2,"compression":"zstd"}"#),
+        M::OutputSeq => json(r#"{"seq":7}"#),
+        M::Channel => (
+            encode_channel(1, server::OUTPUT, b"hi"),
+            Payload::Channel {
+                channel: 1,
+                msg_type: server::OUTPUT,
+                payload: b"hi".to_vec(),
+            },
+        ),
+        M::ChannelClosed => json(r#"{"channel":1}"#),
+        M::FrameMark => (
+            format!(
+                r#"{{"frame":1,"seq":1,"type":1,"len":7,"crc32":{}}}"#,
+                crc32(b"hello\r\n")
+            )
+            .into_bytes(),
+            Payload::Json,
+        ),
+        M::StateSync => (b"\x1b[H\x1b[2Jhello".to_vec(), Payload::Bytes),
+        M::CompressedStateSync => (
+            zstd_raw(b"\x1b[H\x1b[2Jhello"),
+            Payload::Zstd {
+                content: b"\x1b[H\x1b[2Jhello".to_vec(),
+            },
+        ),
+    };
+    Vector::new(Direction::Server, message.as_u8(), payload, meaning)
+}
+
+/// A zstd frame holding `content` (at most 255 bytes) as one raw block,
+/// which any zstd decoder reads and which needs no compressor here.
+fn zstd_raw(content: &[u8]) -> Vec<u8> {
+    let len = u8::try_from(content.len()).expect("raw zstd vectors hold at most 255 bytes");
+    // Magic, then a single-segment frame header with a one-byte content
+    // size, then the last block's header: raw, `len` bytes.
+    let block = (u32::from(len) << 3) | 1;
+    let mut frame = vec![0x28, 0xB5, 0x2F, 0xFD, 0x20, len];
+    frame.extend_from_slice(&block.to_le_bytes()[..3]);
+    frame.extend_from_slice(content);
+    frame
+}
+
+/// What an implementation made of the bytes at the start of a buffer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Decoded {
+    /// A whole frame, `len` bytes including any trailer.
+    Frame {
+        msg_type: u8,
+        payload: Vec<u8>,
+        len: usize,
+    },
+    /// Not a whole frame yet.
+    Incomplete,
+    /// The frame failed its checksum.
+    Corrupt,
+}
+
+/// The framing of an implementation under test.
+pub trait Implementation {
+    /// Encode one frame as `framing` delimits it.
+    fn encode(&mut self, framing: Framing, msg_type: u8, payload: &[u8]) -> Vec<u8>;
+
+    /// Decode the frame at the start of `bytes`, which may be followed by
+    /// more, as `framing` delimits it.
+    fn decode(&mut self, framing: Framing, bytes: &[u8]) -> Decoded;
+}
+
+/// A check a vector failed in [`verify`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mismatch {
+    pub vector: String,
+    pub framing: Framing,
+    pub check: String,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} ({:?} framing): {}",
+            self.vector, self.framing, self.check
+        )
+    }
+}
+
+/// Check `implementation` against every vector under both framings: that
+/// it encodes the exact bytes, decodes them (followed by the next frame's
+/// first byte) back to the type, payload and length, reports every proper
+/// prefix as incomplete, and reports a flipped payload or trailer byte as
+/// corrupt under [`Framing::Crc32`]. Payload meanings are not checked: the
+/// framing carries payloads as opaque bytes.
+pub fn verify(implementation: &mut impl Implementation) -> Result<(), Vec<Mismatch>> {
+    let mut mismatches = Vec::new();
+    for vector in vectors() {
+        for framing in [Framing::Plain, Framing::Crc32] {
+            let mut fail = |check: String| {
+                mismatches.push(Mismatch {
+                    vector: vector.name.clone(),
+                    framing,
+                    check,
+                })
+            };
+            let encoded = vector.encoded(framing);
+            if implementation.encode(framing, vector.msg_type, &vector.payload) != encoded {
+                fail(String::from("encodes to other bytes"));
+            }
+
+            let mut followed = encoded.to_vec();
+            followed.push(server::OUTPUT);
+            let expected = Decoded::Frame {
+                msg_type: vector.msg_type,
+                payload: vector.payload.clone(),
+                len: encoded.len(),
+            };
+            let decoded = implementation.decode(framing, &followed);
+            if decoded != expected {
+                fail(format!("decodes to {}", describe(&decoded)));
+            }
+
+            for len in truncations(encoded.len()) {
+                let decoded = implementation.decode(framing, &encoded[..len]);
+                if decoded != Decoded::Incomplete {
+                    fail(format!(
+                        "first {} bytes decode to {}",
+                        len,
+                        describe(&decoded)
+                    ));
+                }
+            }
+
+            if framing == Framing::Crc32 {
+                let mut flips = vec![encoded.len() - 1];
+                if !vector.payload.is_empty() {
+                    flips.push(HEADER_SIZE);
+                }
+                for at in flips {
+                    let mut corrupted = encoded.to_vec();
+                    corrupted[at] ^= 0x01;
+                    let decoded = implementation.decode(framing, &corrupted);
+                    if decoded != Decoded::Corrupt {
+                        fail(format!(
+                            "byte {} flipped decodes to {}",
+                            at,
+                            describe(&decoded)
+                        ));
+                    }
+                }
+            }
+        }
+    }
+
+    // A header announcing more than the buffer holds, up to the largest
+    // length it can express.
+    let mut huge = vec![server::STATE_SYNC];
+    huge.extend_from_slice(&u32::MAX.to_le_bytes());
+    for framing in [Framing::Plain, Framing::Crc32] {
+        let decoded = implementation.decode(framing, &huge);
+        if decoded != Decoded::Incomplete {
+            mismatches.push(Mismatch {
+                vector: String::from("server STATE_SYNC (header of 4 GiB)"),
+                framing,
+                check: format!("decodes to {}", describe(&decoded)),
+            });
+        }
+    }
+
+    if mismatches.is_empty() {
+        Ok(())
+    } else {
+        Err(mismatches)
+    }
+}
+
+/// Prefix lengths of a `len`-byte frame to check: all of them for small
+/// frames, the ones around the header and the end for large ones.
+fn truncations(len: usize) -> Vec<usize> {
+    if len <= 64 {
+        return (0..len).collect();
+    }
+    let mut lens: Vec<usize> = (0..=HEADER_SIZE + 1).collect();
+    lens.extend([len / 2, len - CRC_SIZE - 1, len - 1]);
+    lens
+}
+
+fn describe(decoded: &Decoded) -> String {
+    match decoded {
+        Decoded::Frame {
+            msg_type,
+            payload,
+            len,
+        } => format!(
+            "a frame of type 0x{:02x} with {} payload bytes, {} bytes long",
+            msg_type,
+            payload.len(),
+            len
+        ),
+        Decoded::Incomplete => String::from("an incomplete frame"),
+        Decoded::Corrupt => String::from("a checksum error"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        encode, exit_details, parse_channel, parse_client_id, parse_credit, parse_exit,
+        parse_frame, parse_input_seq, parse_resize, FrameDecoder,
+    };
+
+    /// This crate's framing, through [`FrameDecoder`] so its checksum
+    /// handling is what gets checked.
+    struct Crate;
+
+    impl Implementation for Crate {
+        fn encode(&mut self, framing: Framing, msg_type: u8, payload: &[u8]) -> Vec<u8> {
+            framing.encode(msg_type, payload)
+        }
+
+        fn decode(&mut self, framing: Framing, bytes: &[u8]) -> Decoded {
+            let mut decoder = FrameDecoder::new();
+            decoder.set_framing(framing);
+            decoder.feed(bytes);
+            match decoder.next_frame() {
+                Some(frame) => Decoded::Frame {
+                    len: bytes.len() - decoder.buffered(),
+                    msg_type: frame.msg_type,
+                    payload: frame.payload,
+                },
+                None if decoder.checksum_error().is_some() => Decoded::Corrupt,
+                None => Decoded::Incomplete,
+            }
+        }
+    }
+
+    #[test]
+    fn this_crate_conforms_to_its_vectors() {
+        if let Err(mismatches) = verify(&mut Crate) {
+            panic!("{:#?}", mismatches);
+        }
+
+        let vectors = vectors();
+        let mut names: Vec<&str> = vectors.iter().map(|v| v.name.as_str()).collect();
+        names.sort_unstable();
+        names.dedup();
+        assert_eq!(names.len(), vectors.len(), "vector names must be unique");
+
+        for vector in &vectors {
+            assert_eq!(encode(vector.msg_type, &vector.payload), vector.plain);
+            let (frame, len) = parse_frame(&vector.plain).unwrap();
+            assert_eq!((frame.msg_type, len), (vector.msg_type, vector.plain.len()));
+
+            let payload = &vector.payload[..];
+            let name = &vector.name;
+            match &vector.meaning {
+                Payload::Bytes | Payload::Zstd { .. } => {}
+                Payload::Json => assert!(
+                    payload.starts_with(b"{") && payload.ends_with(b"}"),
+                    "{}",
+                    name
+                ),
+                Payload::Resize { cols, rows } => {
+                    assert_eq!(parse_resize(payload), Ok((*cols, *rows)), "{}", name)
+                }
+                Payload::InputSeq { seq, input } => {
+                    assert_eq!(parse_input_seq(payload), Ok((*seq, &input[..])), "{}", name)
+                }
+                Payload::ClientId(id) => assert_eq!(parse_client_id(payload), Ok(*id), "{}", name),
+                Payload::Exit { code, details } => {
+                    assert_eq!(parse_exit(payload), Ok(*code), "{}", name);
+                    assert_eq!(exit_details(payload), &details[..], "{}", name);
+                }
+                Payload::Credit(bytes) => assert_eq!(parse_credit(payload), Ok(*bytes), "{}", name),
+                Payload::Channel {
+                    channel,
+                    msg_type,
+                    payload: inner,
+                } => {
+                    let (id, frame) = parse_channel(payload).unwrap();
+                    assert_eq!((id, frame.msg_type), (*channel, *msg_type), "{}", name);
+                    assert_eq!(&frame.payload, inner, "{}", name);
+                }
+                Payload::Invalid(error) => {
+                    let refused = match (vector.direction, vector.msg_type) {
+                        (Direction::Client, client::RESIZE) => parse_resize(payload).err(),
+                        (Direction::Client, client::INPUT_SEQ) => parse_input_seq(payload).err(),
+                        (Direction::Client, client::DETACH_CLIENTS) => {
+                            parse_client_id(payload).err()
+                        }
+                        (Direction::Client, client::CREDIT) => parse_credit(payload).err(),
+                        (Direction::Client, client::CHANNEL) => parse_channel(payload).err(),
+                        (Direction::Server, server::EXIT) => parse_exit(payload).err(),
+                        _ => unreachable!("{}", name),
+                    };
+                    assert_eq!(refused.as_ref(), Some(error), "{}", name);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,264 @@
+//! Frame encryption for encrypted sessions: ChaCha20-Poly1305 (RFC 8439),
+//! with the `crypto` feature.
+//!
+//! A client that can read the session key asks for [`ENCRYPT_CHACHA20_POLY1305`]
+//! in `client::HELLO`, with a nonce of its own; the daemon answers with one
+//! of its own. From the `server::HELLO` answer on, every frame's payload in
+//! both directions is sealed: the header stays readable (and gives the
+//! sealed length), the payload is followed by a 16-byte tag, and the message
+//! type is authenticated along with it.
+//!
+//! Each connection derives its own key per direction from the session key
+//! and both nonces (see [`Channel::client`]), so frame nonces can be a plain
+//! counter: they never repeat under one key. The cipher itself is the
+//! `chacha20poly1305` crate; this module only derives the keys and counts
+//! the nonces.
+//!
+//! [`ENCRYPT_CHACHA20_POLY1305`]: crate::ENCRYPT_CHACHA20_POLY1305
+
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
+use chacha20::cipher::consts::U10;
+use chacha20::cipher::{KeyIvInit, StreamCipher};
+use chacha20::{hchacha, ChaCha20};
+use chacha20poly1305::{AeadInPlace, ChaCha20Poly1305, KeyInit, Tag};
+use core::fmt;
+
+/// Length of the session key.
+pub const KEY_LEN: usize = 32;
+/// Length of the tag that follows each sealed payload.
+pub const TAG_LEN: usize = 16;
+/// Length of the nonce a client sends in `client::HELLO`.
+pub const CLIENT_NONCE_LEN: usize = 16;
+/// Length of the nonce the daemon answers with in `server::HELLO`.
+pub const SERVER_NONCE_LEN: usize = 12;
+
+/// A sealed payload did not authenticate: it was tampered with, or the ends
+/// hold different keys. The connection cannot be trusted after it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Unauthenticated {
+    pub msg_type: u8,
+}
+
+impl fmt::Display for Unauthenticated {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "frame of type 0x{:02x} failed authentication (tampered with, or another session key)",
+            self.msg_type
+        )
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for Unauthenticated {}
+
+/// The sealing state of one connection: a key and frame counter per
+/// direction.
+#[derive(Clone)]
+pub struct Channel {
+    send: Direction,
+    recv: Direction,
+}
+
+#[derive(Clone)]
+struct Direction {
+    cipher: ChaCha20Poly1305,
+    counter: u64,
+}
+
+impl Direction {
+    fn new(key: &[u8]) -> Self {
+        Self {
+            cipher: ChaCha20Poly1305::new(key.into()),
+            counter: 0,
+        }
+    }
+
+    fn next_nonce(&mut self) -> [u8; 12] {
+        let mut nonce = [0u8; 12];
+        nonce[..8].copy_from_slice(&self.counter.to_le_bytes());
+        self.counter += 1;
+        nonce
+    }
+}
+
+impl fmt::Debug for Channel {
+    // The keys stay out of logs.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Channel")
+            .field("sent", &self.send.counter)
+            .field("received", &self.recv.counter)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Channel {
+    /// The client's end of a connection to a session with `key`. The
+    /// client-to-daemon key is the first half of the ChaCha20 block of
+    /// `HChaCha20(key, client_nonce)` at counter 0 and `server_nonce`, the
+    /// daemon-to-client key the second half.
+    pub fn client(
+        key: &[u8; KEY_LEN],
+        client_nonce: &[u8; CLIENT_NONCE_LEN],
+        server_nonce: &[u8; SERVER_NONCE_LEN],
+    ) -> Self {
+        let (up, down) = derive(key, client_nonce, server_nonce);
+        Self {
+            send: up,
+            recv: down,
+        }
+    }
+
+    /// The daemon's end of the connection [`Channel::client`] describes.
+    pub fn server(
+        key: &[u8; KEY_LEN],
+        client_nonce: &[u8; CLIENT_NONCE_LEN],
+        server_nonce: &[u8; SERVER_NONCE_LEN],
+    ) -> Self {
+        let (up, down) = derive(key, client_nonce, server_nonce);
+        Self {
+            send: down,
+            recv: up,
+        }
+    }
+
+    /// Encrypt the payload of the next outgoing frame, of type `msg_type`,
+    /// in place, and return its tag.
+    pub fn seal_in_place(&mut self, msg_type: u8, payload: &mut [u8]) -> [u8; TAG_LEN] {
+        let nonce = self.send.next_nonce();
+        self.send
+            .cipher
+            .encrypt_in_place_detached(&nonce.into(), &[msg_type], payload)
+            .expect("frame payloads fit ChaCha20's block counter")
+            .into()
+    }
+
+    /// Decrypt the payload of the next incoming frame in place, checking it
+    /// against `tag`.
+    pub fn open_in_place(
+        &mut self,
+        msg_type: u8,
+        payload: &mut [u8],
+        tag: &[u8; TAG_LEN],
+    ) -> Result<(), Unauthenticated> {
+        let nonce = self.recv.next_nonce();
+        self.recv
+            .cipher
+            .decrypt_in_place_detached(&nonce.into(), &[msg_type], payload, Tag::from_slice(tag))
+            .map_err(|_| Unauthenticated { msg_type })
+    }
+
+    /// The payload of the next outgoing frame, sealed: ciphertext then tag.
+    #[cfg(feature = "alloc")]
+    pub fn seal(&mut self, msg_type: u8, payload: &[u8]) -> Vec<u8> {
+        let mut sealed = Vec::with_capacity(payload.len() + TAG_LEN);
+        sealed.extend_from_slice(payload);
+        let tag = self.seal_in_place(msg_type, &mut sealed);
+        sealed.extend_from_slice(&tag);
+        sealed
+    }
+
+    /// The plain payload of the next incoming frame, from its sealed one.
+    #[cfg(feature = "alloc")]
+    pub fn open(&mut self, msg_type: u8, sealed: &[u8]) -> Result<Vec<u8>, Unauthenticated> {
+        let Some(len) = sealed.len().checked_sub(TAG_LEN) else {
+            return Err(Unauthenticated { msg_type });
+        };
+        let mut payload = sealed[..len].to_vec();
+        let tag = sealed[len..].try_into().expect("16-byte tag");
+        self.open_in_place(msg_type, &mut payload, &tag)?;
+        Ok(payload)
+    }
+
+    /// Append the encoded `frames`, each with its payload sealed, to `out`.
+    #[cfg(feature = "alloc")]
+    pub fn seal_frames(&mut self, mut frames: &[u8], out: &mut Vec<u8>) {
+        while let Some((frame, len)) = crate::parse_frame(frames) {
+            let sealed = self.seal(frame.msg_type, frame.payload);
+            out.extend_from_slice(&crate::encode(frame.msg_type, &sealed));
+            frames = &frames[len..];
+        }
+    }
+}
+
+fn derive(
+    key: &[u8; KEY_LEN],
+    client_nonce: &[u8; CLIENT_NONCE_LEN],
+    server_nonce: &[u8; SERVER_NONCE_LEN],
+) -> (Direction, Direction) {
+    let subkey = hchacha::<U10>(key.into(), client_nonce.into());
+    let mut block = [0u8; 64];
+    ChaCha20::new(&subkey, server_nonce.into()).apply_keystream(&mut block);
+    (Direction::new(&block[..32]), Direction::new(&block[32..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unhex(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn sealed_frames_stay_the_same_on_the_wire() {
+        // Sealed by earlier releases, whose daemons and bridges outlive a
+        // hot upgrade: key derivation and nonces must not change.
+        let key: [u8; KEY_LEN] = core::array::from_fn(|i| i as u8);
+        let (client_nonce, server_nonce) = ([0xa5; 16], [0x5a; 12]);
+        let mut client = Channel::client(&key, &client_nonce, &server_nonce);
+        let mut server = Channel::server(&key, &client_nonce, &server_nonce);
+        let up = client.seal(0x01, b"ls\r");
+        assert_eq!(up, unhex("eb0568660f0775d1d54e1b2baa2379772438b3"));
+        assert_eq!(server.open(0x01, &up).unwrap(), b"ls\r");
+        let empty = server.seal(0x80, b"");
+        let down = server.seal(0x80, b"hello");
+        assert_eq!(down, unhex("a3aa75dd85d1d8933f25b2f2337f37979f4ba43d9c"));
+
+        assert!(client.open(0x80, &empty).unwrap().is_empty());
+        let mut tampered = down.clone();
+        tampered[1] ^= 1;
+        assert!(client.clone().open(0x80, &tampered).is_err());
+        assert!(client.clone().open(0x81, &down).is_err());
+        assert_eq!(client.open(0x80, &down).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn channel_ends_agree_and_reject_replays() {
+        let key = [7u8; KEY_LEN];
+        let (client_nonce, server_nonce) = ([1u8; 16], [2u8; 12]);
+        let mut client = Channel::client(&key, &client_nonce, &server_nonce);
+        let mut server = Channel::server(&key, &client_nonce, &server_nonce);
+
+        let mut wire = Vec::new();
+        let frames = [
+            crate::encode(crate::client::INPUT, b"ls\r"),
+            crate::encode(crate::client::DETACH, b""),
+        ]
+        .concat();
+        client.seal_frames(&frames, &mut wire);
+        let (first, len) = crate::parse_frame(&wire).unwrap();
+        assert_eq!(first.msg_type, crate::client::INPUT);
+        assert_eq!(first.payload.len(), 3 + TAG_LEN);
+        assert_eq!(server.open(first.msg_type, first.payload).unwrap(), b"ls\r");
+        // The same frame again comes under the next nonce and fails.
+        assert!(server.open(first.msg_type, first.payload).is_err());
+        let (second, _) = crate::parse_frame(&wire[len..]).unwrap();
+        let mut server = Channel::server(&key, &client_nonce, &server_nonce);
+        server.open(crate::client::INPUT, first.payload).unwrap();
+        assert!(server.open(crate::client::INPUT, second.payload).is_err());
+
+        // Directions use different keys, and another session key fails.
+        let sealed = server.seal(crate::server::OUTPUT, b"hi");
+        assert!(
+            Channel::client(&[8u8; KEY_LEN], &client_nonce, &server_nonce)
+                .open(crate::server::OUTPUT, &sealed)
+                .is_err()
+        );
+        assert_eq!(client.open(crate::server::OUTPUT, &sealed).unwrap(), b"hi");
+    }
+}
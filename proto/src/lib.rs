@@ -10,66 +10,611 @@
 //! └──┴┬──────────┴────────
 //!     └─ payload length
 //! ```
+//!
+//! Message types are allocated from three ranges:
+//!
+//! | Range       | Class   | Used for                                       |
+//! |-------------|---------|------------------------------------------------|
+//! | `0x01–0x1F` | data    | terminal I/O on an attached connection         |
+//! | `0x20–0x7F` | control | requests, replies and connection management    |
+//! | `0x80–0xFF` | bulk    | large payloads such as state snapshots         |
+//!
+//! `0x00` is reserved. Types allocated before the ranges existed (client
+//! `0x03–0x0B`, server `0x03–0x06`) are control messages that sit in the data
+//! range; they keep their values so daemons and bridges from older releases,
+//! which survive a hot upgrade, keep understanding each other. New control
+//! messages must be allocated from `0x20–0x7F`, and an endpoint that receives
+//! a control type it does not know must not treat it as data.
+//!
+//! The helpers here are synchronous; with the `tokio` feature, `PtermCodec`
+//! speaks the same framing as a `tokio_util` codec. Sealing the frames of
+//! encrypted sessions, `crypto`, needs the `crypto` feature.
+//!
+//! The crate is `no_std` without its default `std` feature. The helpers that
+//! return a `Vec` (and [`Frame`], [`FrameDecoder`]) then need the `alloc`
+//! feature; the `_into` encoders, [`parse_frame`] and the payload parsers
+//! work on caller-provided slices and need neither.
+
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+#[cfg(feature = "alloc")]
+extern crate alloc;
+
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
+use core::fmt;
+use core::ops::RangeInclusive;
+
+#[cfg(feature = "tokio")]
+mod codec;
+#[cfg(feature = "alloc")]
+pub mod conformance;
+#[cfg(feature = "crypto")]
+pub mod crypto;
+#[cfg(feature = "tokio")]
+pub use codec::PtermCodec;
+
+/// Wire protocol version. Bump whenever a frame's meaning or payload layout
+/// changes incompatibly.
+pub const PROTOCOL_VERSION: u16 = 2;
 
-use std::fmt;
+/// Oldest protocol version this build still speaks. Version 1 predates
+/// `HELLO`: peers that do not send one speak it.
+pub const MIN_PROTOCOL_VERSION: u16 = 1;
 
-/// Client → Daemon message types
-pub mod client {
-    /// Forward keyboard input to pty
-    /// Payload: raw bytes to write to pty stdin
-    pub const INPUT: u8 = 0x01;
+/// Output compression a client can ask for in `client::HELLO`: the daemon
+/// then sends `COMPRESSED_OUTPUT` / `COMPRESSED_STATE_SYNC`, zstd frames of
+/// the payloads it would send as `OUTPUT` / `STATE_SYNC`, wherever that
+/// saves bytes.
+pub const COMPRESSION_ZSTD: &str = "zstd";
 
-    /// Resize the pty
-    /// Payload: [cols: u16 LE] [rows: u16 LE]
-    pub const RESIZE: u8 = 0x02;
+/// Frame checksum a client can ask for in `client::HELLO`: from the
+/// `server::HELLO` answer on, frames in both directions use
+/// [`Framing::Crc32`].
+pub const CHECKSUM_CRC32: &str = "crc32";
 
-    /// Graceful detach request (no payload)
-    pub const DETACH: u8 = 0x03;
+/// Frame encryption a client holding the session key can ask for in
+/// `client::HELLO`; see `crypto`.
+pub const ENCRYPT_CHACHA20_POLY1305: &str = "chacha20-poly1305";
+
+/// Message types for terminal I/O.
+pub const DATA_RANGE: RangeInclusive<u8> = 0x01..=0x1F;
+/// Message types for requests, replies and connection management.
+pub const CONTROL_RANGE: RangeInclusive<u8> = 0x20..=0x7F;
+/// Message types for large payloads.
+pub const BULK_RANGE: RangeInclusive<u8> = 0x80..=0xFF;
+
+/// Class of a message type, see the module documentation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameClass {
+    Data,
+    Control,
+    Bulk,
+}
+
+impl FrameClass {
+    /// Class implied by the range `msg_type` falls in, or `None` for the
+    /// reserved `0x00`. Known types may differ (see the module docs); use
+    /// [`ClientMessage::class`] / [`ServerMessage::class`] for those.
+    pub fn of_range(msg_type: u8) -> Option<Self> {
+        if DATA_RANGE.contains(&msg_type) {
+            Some(Self::Data)
+        } else if CONTROL_RANGE.contains(&msg_type) {
+            Some(Self::Control)
+        } else if BULK_RANGE.contains(&msg_type) {
+            Some(Self::Bulk)
+        } else {
+            None
+        }
+    }
+}
+
+/// Defines a direction's message type constants (`pub mod $module`) together
+/// with a registry enum mapping each of them to its name and class.
+macro_rules! message_types {
+    (
+        $(#[$module_doc:meta])*
+        $module:ident, $registry:ident {
+            $(
+                $(#[$doc:meta])*
+                $name:ident = $value:literal => $variant:ident, $class:ident;
+            )*
+        }
+    ) => {
+        $(#[$module_doc])*
+        pub mod $module {
+            $(
+                $(#[$doc])*
+                pub const $name: u8 = $value;
+            )*
+        }
+
+        #[doc = concat!("Registry of the message types in [`", stringify!($module), "`].")]
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        pub enum $registry {
+            $(
+                #[doc = concat!("[`", stringify!($module), "::", stringify!($name), "`]")]
+                $variant = $value,
+            )*
+        }
+
+        impl $registry {
+            pub const ALL: &'static [Self] = &[$(Self::$variant),*];
+
+            /// Look up a known message type.
+            pub fn from_u8(msg_type: u8) -> Option<Self> {
+                match msg_type {
+                    $($value => Some(Self::$variant),)*
+                    _ => None,
+                }
+            }
+
+            pub fn as_u8(self) -> u8 {
+                self as u8
+            }
+
+            /// Constant name, e.g. `"RESIZE"`.
+            pub fn name(self) -> &'static str {
+                match self {
+                    $(Self::$variant => stringify!($name),)*
+                }
+            }
+
+            pub fn class(self) -> FrameClass {
+                match self {
+                    $(Self::$variant => FrameClass::$class,)*
+                }
+            }
+
+            pub fn is_data(self) -> bool {
+                self.class() == FrameClass::Data
+            }
+
+            pub fn is_control(self) -> bool {
+                self.class() == FrameClass::Control
+            }
+        }
+    };
+}
+
+/// Whether `msg_type` is in the data range.
+pub fn is_data(msg_type: u8) -> bool {
+    DATA_RANGE.contains(&msg_type)
+}
 
-    /// Request terminal redraw (no payload)
-    pub const REDRAW: u8 = 0x04;
+/// Whether `msg_type` is in the control range.
+pub fn is_control(msg_type: u8) -> bool {
+    CONTROL_RANGE.contains(&msg_type)
 }
 
-/// Daemon → Client message types
-pub mod server {
-    /// pty output (raw bytes from pty stdout)
-    /// Payload: raw bytes
-    pub const OUTPUT: u8 = 0x01;
+/// Whether `msg_type` is in the bulk range.
+pub fn is_bulk(msg_type: u8) -> bool {
+    BULK_RANGE.contains(&msg_type)
+}
+
+message_types! {
+    /// Client → Daemon message types
+    client, ClientMessage {
+        /// Forward keyboard input to pty
+        /// Payload: raw bytes to write to pty stdin
+        INPUT = 0x01 => Input, Data;
+
+        /// Resize the pty
+        /// Payload: [cols: u16 LE] [rows: u16 LE]
+        RESIZE = 0x02 => Resize, Data;
+
+        /// Keyboard input numbered within the stream bound with
+        /// `client::INPUT_STREAM`. Input the daemon already applied is
+        /// dropped, so a client may resend everything it is unsure about
+        /// after reconnecting.
+        /// Payload: [seq: u64 LE] [raw bytes]
+        INPUT_SEQ = 0x0C => InputSeq, Data;
+
+        /// Graceful detach request (no payload)
+        DETACH = 0x03 => Detach, Control;
+
+        /// Request terminal redraw (no payload)
+        REDRAW = 0x04 => Redraw, Control;
+
+        /// Ask the daemon to re-exec itself as a new pterm binary, keeping the
+        /// session, its socket and attached clients.
+        /// Payload: path of the new binary (raw OS bytes)
+        UPGRADE = 0x05 => Upgrade, Control;
+
+        /// Ask the daemon for a duplicate of the pty master fd so the client can
+        /// write input to the pty directly (no payload). Sent on a dedicated
+        /// connection, which the daemon closes after answering with
+        /// `server::PTY_FD`.
+        PTY_INPUT_FD = 0x06 => PtyInputFd, Control;
+
+        /// Disconnect other clients without killing the session, telling
+        /// them why in `server::DETACHED`. Answered with `server::REPLY`
+        /// (`{"detached": [ids]}`).
+        /// Payload: empty for every attached client, [client_id: u32 LE], or
+        /// JSON `{"client": n, "reason": "..."}`, both optional
+        DETACH_CLIENTS = 0x07 => DetachClients, Control;
+
+        /// Answer to `server::PING` (no payload)
+        PONG = 0x08 => Pong, Control;
+
+        /// Attach as a read-only viewer: the daemon ignores this client's INPUT
+        /// and RESIZE and sends it a snapshot right away (no payload)
+        READ_ONLY = 0x09 => ReadOnly, Control;
+
+        /// List connected clients (no payload). Answered with `server::REPLY`
+        /// (`{"clients": [...]}`).
+        LIST_CLIENTS = 0x0A => ListClients, Control;
+
+        /// Query or change session options. Answered with `server::REPLY`
+        /// (`{"options": {...}}` or `{"error": "..."}`).
+        /// Payload: empty to query, or JSON `{"name": ..., "value": ...}`
+        OPTIONS = 0x0B => Options, Control;
+
+        /// Stop the session's processes with `SIGSTOP`. Answered with
+        /// `server::REPLY` (`{"suspended": true}` or `{"error": "..."}`).
+        /// No payload
+        SUSPEND = 0x20 => Suspend, Control;
+
+        /// Continue processes stopped by `SUSPEND` with `SIGCONT`. Answered
+        /// with `server::REPLY` (`{"suspended": false}` or `{"error": "..."}`).
+        /// No payload
+        RESUME = 0x21 => Resume, Control;
+
+        /// Query session information (no payload). Answered with
+        /// `server::REPLY` (`{"info": {...}}`, the `server::SESSION_INFO`
+        /// document).
+        INFO = 0x22 => Info, Control;
+
+        /// Capture the session's text (no payload). Answered with
+        /// `server::REPLY` (`{"lines": [...], "screen": n, "cursor": n}`):
+        /// scrollback and screen as plain-text lines with soft-wrapped rows
+        /// joined, the index of the first screen line and of the cursor line.
+        CAPTURE = 0x23 => Capture, Control;
+
+        /// Paste text into the session. Newlines become carriage returns and
+        /// the text is bracketed when the application enabled bracketed
+        /// paste. Answered with `server::REPLY` (`{"pasted": bytes}`).
+        /// Payload: UTF-8 text
+        PASTE = 0x24 => Paste, Control;
+
+        /// List, add or remove output watchers. Answered with
+        /// `server::REPLY` (`{"watches": [...]}`, plus `"added": id` after
+        /// an add, or `{"error": ...}`).
+        /// Payload: empty to list, JSON `{"add": {"patterns": [...],
+        /// "command": [...]}}` or `{"remove": id}`
+        WATCH = 0x25 => Watch, Control;
+
+        /// Answer to `server::CONFIRM`: submit (`true`) or drop the held
+        /// input.
+        /// Payload: JSON `{"id": ..., "accept": bool}`
+        CONFIRM_REPLY = 0x26 => ConfirmReply, Control;
+
+        /// Type a command line into the shell (`pterm exec`). Answered with
+        /// `server::REPLY` (`{"id": ...}` or `{"error": ...}`), then
+        /// `server::EXEC_STATUS` once the command finished.
+        /// Payload: JSON `{"command": [...], "wrap": bool}`
+        EXEC = 0x27 => Exec, Control;
+
+        /// Hand the session to another user (`pterm transfer`); only
+        /// accepted from root. Clients of other users are detached.
+        /// Answered with `server::REPLY` (`{"owner": uid, "detached":
+        /// [ids]}` or `{"error": ...}`).
+        /// Payload: JSON `{"owner": uid}`
+        TRANSFER = 0x28 => Transfer, Control;
+
+        /// Bind the connection to input stream `stream`, an id chosen by the
+        /// client and kept across its reconnects, before sending
+        /// `client::INPUT_SEQ`. Answered with `server::REPLY` (`{"stream":
+        /// id, "applied": seq}`, `applied` being the last sequence number
+        /// applied in the stream, or 0). Daemons that answer with an error
+        /// do not know `INPUT_SEQ`; clients then fall back to `INPUT`.
+        /// Payload: JSON `{"stream": id}`
+        INPUT_STREAM = 0x29 => InputStream, Control;
+
+        /// Protocol handshake, sent first on a connection. Answered with
+        /// `server::HELLO`; daemons older than protocol 2 answer with a
+        /// `server::REPLY` error instead. `compression` lists the output
+        /// compressions the client accepts (see [`COMPRESSION_ZSTD`]);
+        /// `stderr: true` asks for `server::STDERR_OUTPUT` frames and
+        /// `seq: true` for `server::OUTPUT_SEQ`; `exit_details: true` asks
+        /// for the termination details in `server::EXIT`. `resume` is the output
+        /// stream position a reconnecting client had reached: if the
+        /// daemon still has the output from there and the size is
+        /// unchanged, the client's attach gets that output instead of a
+        /// `STATE_SYNC`.
+        /// Payload: JSON `{"version": n, "min_version": n, "compression":
+        /// [...], "stderr": bool, "seq": bool, "exit_details": bool,
+        /// "resume": n}`, all but the versions optional
+        HELLO = 0x2A => Hello, Control;
+
+        /// Send a signal to the child's process group and, if different,
+        /// the pty's foreground process group, as typing Ctrl-C would for
+        /// `SIGINT`. Answered with `server::REPLY` (`{"signal": n}` or
+        /// `{"error": ...}`).
+        /// Payload: JSON `{"signal": n}`
+        SIGNAL = 0x2B => Signal, Control;
+
+        /// How the client's attach snapshot is replayed, sent before the
+        /// `client::RESIZE` or `client::READ_ONLY` that attaches. Not
+        /// answered. `skip_replay: true` skips the snapshot, for a client
+        /// that already shows the session: it gets only output from then
+        /// on. `scrollback_lines` asks for up to that many of the newest
+        /// scrollback lines ahead of the screen, in the same `STATE_SYNC`,
+        /// and `scrollback_bytes` caps their size, dropping older lines
+        /// first.
+        /// Payload: JSON `{"skip_replay": bool, "scrollback_lines": n,
+        /// "scrollback_bytes": n}`, all optional
+        ATTACH = 0x2C => Attach, Control;
+
+        /// Who is behind the connection, sent by clients that attach before
+        /// their `client::RESIZE` or `client::READ_ONLY`, for `pterm clients`.
+        /// Not answered. The daemon keeps `cols` / `rows` up to date from
+        /// the client's later `RESIZE`s.
+        /// Payload: JSON `{"pid": n, "host": "...", "tty": "...", "cols": n,
+        /// "rows": n}`, all optional
+        IDENTIFY = 0x2D => Identify, Control;
+
+        /// A frame for the session behind a channel of a multiplexed
+        /// connection (`pterm mux`), exactly as it would be sent on that
+        /// session's own connection. Frames that pass file descriptors
+        /// (`PTY_INPUT_FD`, `UPGRADE`) cannot be carried.
+        /// Payload: [channel: u32 LE][frame]
+        CHANNEL = 0x2E => Channel, Control;
+
+        /// Open a channel of a multiplexed connection to session `session`.
+        /// The multiplexer connects and answers with `server::REPLY`
+        /// (`{"channel": n}` or `{"channel": n, "error": "..."}`); the
+        /// channel then carries a fresh session connection, starting with
+        /// its `HELLO`.
+        /// Payload: JSON `{"channel": n, "session": "..."}`
+        OPEN_CHANNEL = 0x2F => OpenChannel, Control;
+
+        /// Close a channel of a multiplexed connection, as closing the
+        /// session connection would. Not answered.
+        /// Payload: JSON `{"channel": n}`
+        CLOSE_CHANNEL = 0x30 => CloseChannel, Control;
+
+        /// Query what tooling needs to know about a live session (no
+        /// payload). Answered with `server::REPLY` (`{"metadata": {...}}`:
+        /// child pid, command line, cwd, pty size, scrollback usage and
+        /// client counts).
+        METADATA = 0x31 => Metadata, Control;
+
+        /// A named command, for low-frequency requests that do not need a
+        /// message type of their own. Answered with `server::REPLY`
+        /// (`{"cmd": "...", "result": ...}` or `{"cmd": "...", "error":
+        /// "..."}`).
+        /// Payload: JSON `{"cmd": "...", "args": {...}}`, `args` optional
+        COMMAND = 0x32 => Command, Control;
+
+        /// Bytes of terminal output the client has passed on since its
+        /// last `CREDIT`, returned to the window it asked for in `HELLO`.
+        /// Payload: `bytes:u64`
+        CREDIT = 0x33 => Credit, Control;
+    }
+}
+
+message_types! {
+    /// Daemon → Client message types
+    server, ServerMessage {
+        /// pty output (raw bytes from pty stdout)
+        /// Payload: raw bytes
+        OUTPUT = 0x01 => Output, Data;
+
+        /// Child process exited. The exit code of a child killed by a
+        /// signal is 128 + the signal. Clients that asked for them in
+        /// `client::HELLO` also get the details after the code, which older
+        /// clients ignore.
+        /// Payload: [exit_code: i32 LE][details: JSON `{"code": n, "signal":
+        /// n, "core_dumped": bool, "user_us": n, "system_us": n,
+        /// "max_rss_kib": n}`, optional]
+        EXIT = 0x02 => Exit, Data;
+
+        /// Reply to `client::PTY_INPUT_FD` (no payload). The pty master fd is
+        /// attached to this frame as `SCM_RIGHTS` ancillary data.
+        PTY_FD = 0x03 => PtyFd, Control;
 
-    /// Child process exited
-    /// Payload: [exit_code: i32 LE]
-    pub const EXIT: u8 = 0x02;
+        /// The daemon detaches the client, e.g. for another client's
+        /// `DETACH_CLIENTS`, and closes the connection right after. The
+        /// client should restore its terminal and exit as on a detach.
+        /// Payload: the reason as UTF-8 text; empty from older daemons
+        DETACHED = 0x04 => Detached, Control;
 
-    /// Terminal state snapshot (sent on initial attach)
-    /// Payload: escape sequences reproducing current terminal state
-    pub const STATE_SYNC: u8 = 0x80;
+        /// Answer to a control request
+        /// Payload: JSON document
+        REPLY = 0x05 => Reply, Control;
+
+        /// Keepalive probe for an idle client, answered with `client::PONG`
+        /// (no payload)
+        PING = 0x06 => Ping, Control;
+
+        /// `OUTPUT` for a client that negotiated compression in `HELLO`.
+        /// Payload: one zstd frame holding the `OUTPUT` payload
+        COMPRESSED_OUTPUT = 0x07 => CompressedOutput, Data;
+
+        /// What the child wrote to stderr in a `pterm new --split-stderr`
+        /// session, for a client that asked for it in `HELLO`; others get
+        /// these bytes as `OUTPUT`.
+        /// Payload: raw bytes, `\n` turned into `\r\n` as on the pty
+        STDERR_OUTPUT = 0x08 => StderrOutput, Data;
+
+        /// Session information, sent once when a client attaches.
+        /// Payload: JSON `{"session", "command", "cwd", "term", "cols",
+        /// "rows", "pid"}`; `cwd` and `term` may be null
+        SESSION_INFO = 0x20 => SessionInfo, Control;
+
+        /// The input guard held back the Enter of a command line this
+        /// client typed; ask the user and answer with
+        /// `client::CONFIRM_REPLY`.
+        /// Payload: JSON `{"id", "line", "pattern"}`
+        CONFIRM = 0x21 => Confirm, Control;
+
+        /// Another client sent input, at most once a second per client;
+        /// bridges show who is typing.
+        /// Payload: JSON `{"client", "label", "last_input"}` (Unix seconds)
+        PRESENCE = 0x22 => Presence, Control;
+
+        /// A command started with `client::EXEC` finished.
+        /// Payload: JSON `{"id", "status"}`
+        EXEC_STATUS = 0x23 => ExecStatus, Control;
+
+        /// Answer to `client::HELLO`: the version both ends speak from now
+        /// on, or an error, after which the daemon closes the connection.
+        /// `compression` names the compression the daemon picked from the
+        /// client's list; absent if none. `stderr` is `true` if the client
+        /// asked for `STDERR_OUTPUT` and the session keeps stderr apart,
+        /// `seq` if it asked for `OUTPUT_SEQ`, `exit_details` if it asked
+        /// for the exit details.
+        /// Payload: JSON `{"version": n, "compression": "...", "stderr":
+        /// bool, "seq": bool, "exit_details": bool}` or
+        /// `{"error": "...", "version": n, "min_version": n}`
+        HELLO = 0x24 => Hello, Control;
+
+        /// Position in the output stream, for a client that asked for it in
+        /// `HELLO`: sent after every `STATE_SYNC`, and before the missed
+        /// output of a resumed attach. Every byte of `OUTPUT` and
+        /// `STDERR_OUTPUT` payload (decompressed) after it advances the
+        /// position by one; the position reached is what `HELLO` `resume`
+        /// takes.
+        /// Payload: JSON `{"seq": n}`
+        OUTPUT_SEQ = 0x25 => OutputSeq, Control;
+
+        /// A frame from the session behind a channel of a multiplexed
+        /// connection, see `client::CHANNEL`.
+        /// Payload: [channel: u32 LE][frame]
+        CHANNEL = 0x26 => Channel, Control;
+
+        /// The session closed the connection behind a channel, e.g. after
+        /// `EXIT` or `DETACHED`; the channel id is free again.
+        /// Payload: JSON `{"channel": n}`
+        CHANNEL_CLOSED = 0x27 => ChannelClosed, Control;
+
+        /// Debug mark sent ahead of each `OUTPUT`, `STDERR_OUTPUT` and
+        /// `STATE_SYNC` to a client that asked for `frame_marks` in
+        /// `HELLO`: the daemon's number for the frame (the same for every
+        /// client a broadcast goes to), the client's count of marked frames
+        /// (`seq`, from 1) and the type, length and CRC32 of its
+        /// (decompressed) payload.
+        /// Payload: JSON `{"frame", "seq", "type", "len", "crc32"}`
+        FRAME_MARK = 0x28 => FrameMark, Control;
+
+        /// Terminal state snapshot (sent on initial attach)
+        /// Payload: escape sequences reproducing current terminal state
+        STATE_SYNC = 0x80 => StateSync, Bulk;
+
+        /// `STATE_SYNC` for a client that negotiated compression in `HELLO`.
+        /// Payload: one zstd frame holding the `STATE_SYNC` payload
+        COMPRESSED_STATE_SYNC = 0x81 => CompressedStateSync, Bulk;
+    }
 }
 
 /// Encode a framed message into a Vec<u8>.
+#[cfg(feature = "alloc")]
 pub fn encode(msg_type: u8, payload: &[u8]) -> Vec<u8> {
-    let len = payload.len() as u32;
     let mut buf = Vec::with_capacity(HEADER_SIZE + payload.len());
-    buf.push(msg_type);
-    buf.extend_from_slice(&len.to_le_bytes());
+    buf.extend_from_slice(&encode_header(msg_type, payload.len() as u32));
     buf.extend_from_slice(payload);
     buf
 }
 
+/// Encode the header of a frame with a `len`-byte payload.
+pub fn encode_header(msg_type: u8, len: u32) -> [u8; HEADER_SIZE] {
+    let len = len.to_le_bytes();
+    [msg_type, len[0], len[1], len[2], len[3]]
+}
+
+/// Encode a framed message into the start of `buf`. Returns the number of
+/// bytes written.
+pub fn encode_into(msg_type: u8, payload: &[u8], buf: &mut [u8]) -> Result<usize, BufferTooSmall> {
+    let header = encode_header(msg_type, payload.len() as u32);
+    write_parts(buf, &[&header, payload])
+}
+
+/// A buffer too small for what was to be encoded into it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BufferTooSmall {
+    pub needed: usize,
+    pub len: usize,
+}
+
+impl fmt::Display for BufferTooSmall {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "buffer of {} bytes is too small for {} bytes",
+            self.len, self.needed
+        )
+    }
+}
+
+impl core::error::Error for BufferTooSmall {}
+
+/// Write `parts` one after another to the start of `buf`.
+fn write_parts(buf: &mut [u8], parts: &[&[u8]]) -> Result<usize, BufferTooSmall> {
+    let needed = parts.iter().map(|part| part.len()).sum();
+    if needed > buf.len() {
+        return Err(BufferTooSmall {
+            needed,
+            len: buf.len(),
+        });
+    }
+    let mut offset = 0;
+    for part in parts {
+        buf[offset..offset + part.len()].copy_from_slice(part);
+        offset += part.len();
+    }
+    Ok(needed)
+}
+
 /// Header size: 1 byte type + 4 bytes length
 pub const HEADER_SIZE: usize = 5;
 pub const RESIZE_PAYLOAD_SIZE: usize = 4;
+/// Largest terminal width or height a `RESIZE` may ask for.
+pub const MAX_TERMINAL_SIZE: u16 = 10_000;
 pub const EXIT_PAYLOAD_SIZE: usize = 4;
+pub const CLIENT_ID_PAYLOAD_SIZE: usize = 4;
+/// Size of the sequence number heading an `INPUT_SEQ` payload.
+pub const INPUT_SEQ_HEADER_SIZE: usize = 8;
+/// Size of the channel id heading a `CHANNEL` payload.
+pub const CHANNEL_HEADER_SIZE: usize = 4;
+pub const CREDIT_PAYLOAD_SIZE: usize = 8;
 
+#[cfg(feature = "alloc")]
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Frame {
     pub msg_type: u8,
     pub payload: Vec<u8>,
 }
 
+/// A frame borrowed from the buffer it was read from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameRef<'a> {
+    pub msg_type: u8,
+    pub payload: &'a [u8],
+}
+
+#[cfg(feature = "alloc")]
+impl From<FrameRef<'_>> for Frame {
+    fn from(frame: FrameRef<'_>) -> Self {
+        Self {
+            msg_type: frame.msg_type,
+            payload: frame.payload.to_vec(),
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum DecodeError {
     InvalidResizePayloadLen(usize),
+    /// `(cols, rows)` of a `RESIZE` with a zero or absurd dimension.
+    InvalidResizeSize(u16, u16),
     InvalidExitPayloadLen(usize),
+    InvalidClientIdPayloadLen(usize),
+    InvalidInputSeqPayloadLen(usize),
+    /// Length of a `CHANNEL` payload that is not a channel id and one whole
+    /// frame.
+    InvalidChannelPayloadLen(usize),
+    InvalidCreditPayloadLen(usize),
 }
 
 impl fmt::Display for DecodeError {
@@ -82,18 +627,53 @@ impl fmt::Display for DecodeError {
                     RESIZE_PAYLOAD_SIZE, len
                 )
             }
+            Self::InvalidResizeSize(cols, rows) => {
+                write!(
+                    f,
+                    "invalid terminal size {}x{}: each dimension must be 1 to {}",
+                    cols, rows, MAX_TERMINAL_SIZE
+                )
+            }
             Self::InvalidExitPayloadLen(len) => {
                 write!(
                     f,
-                    "invalid exit payload length: expected {} bytes, got {}",
+                    "invalid exit payload length: expected at least {} bytes, got {}",
                     EXIT_PAYLOAD_SIZE, len
                 )
             }
+            Self::InvalidClientIdPayloadLen(len) => {
+                write!(
+                    f,
+                    "invalid client id payload length: expected {} bytes, got {}",
+                    CLIENT_ID_PAYLOAD_SIZE, len
+                )
+            }
+            Self::InvalidInputSeqPayloadLen(len) => {
+                write!(
+                    f,
+                    "invalid input seq payload length: expected at least {} bytes, got {}",
+                    INPUT_SEQ_HEADER_SIZE, len
+                )
+            }
+            Self::InvalidChannelPayloadLen(len) => {
+                write!(
+                    f,
+                    "invalid channel payload length {}: expected a {}-byte channel id and one whole frame",
+                    len, CHANNEL_HEADER_SIZE
+                )
+            }
+            Self::InvalidCreditPayloadLen(len) => {
+                write!(
+                    f,
+                    "invalid credit payload length: expected {} bytes, got {}",
+                    CREDIT_PAYLOAD_SIZE, len
+                )
+            }
         }
     }
 }
 
-impl std::error::Error for DecodeError {}
+impl core::error::Error for DecodeError {}
 
 /// Parse a message header. Returns (msg_type, payload_length).
 pub fn decode_header(header: &[u8; HEADER_SIZE]) -> (u8, u32) {
@@ -104,33 +684,329 @@ pub fn decode_header(header: &[u8; HEADER_SIZE]) -> (u8, u32) {
 
 /// Decode all complete frames from `recv_buf`, leaving any trailing partial
 /// frame bytes in place for the next read.
+#[cfg(feature = "alloc")]
 pub fn decode_frames(recv_buf: &mut Vec<u8>) -> Vec<Frame> {
     let mut frames = Vec::new();
     let mut offset = 0;
+    while let Some((frame, len)) = split_frame(&recv_buf[offset..]) {
+        frames.push(frame);
+        offset += len;
+    }
+    if offset > 0 {
+        recv_buf.drain(..offset);
+    }
+    frames
+}
+
+/// The frame at the start of `buf` and its encoded length, if complete.
+pub fn parse_frame(buf: &[u8]) -> Option<(FrameRef<'_>, usize)> {
+    let header: &[u8; HEADER_SIZE] = buf.get(..HEADER_SIZE)?.try_into().ok()?;
+    let (msg_type, payload_len) = decode_header(header);
+    let end = HEADER_SIZE + payload_len as usize;
+    let payload = buf.get(HEADER_SIZE..end)?;
+    Some((FrameRef { msg_type, payload }, end))
+}
+
+/// [`parse_frame`], copying the payload.
+#[cfg(feature = "alloc")]
+fn split_frame(buf: &[u8]) -> Option<(Frame, usize)> {
+    parse_frame(buf).map(|(frame, len)| (frame.into(), len))
+}
+
+/// Payload limit the daemon applies to client frames unless configured
+/// otherwise. A length field is read straight from the peer, so without a
+/// limit a corrupted or hostile frame header could make the reader buffer
+/// up to 4 GiB.
+pub const DEFAULT_MAX_FRAME_LEN: usize = 64 << 20;
+
+/// A frame header announcing a payload over the reader's limit. The stream
+/// cannot be resynchronized after it, so the connection must be closed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FrameTooLarge {
+    pub msg_type: u8,
+    pub len: usize,
+    pub max_len: usize,
+}
+
+impl fmt::Display for FrameTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "frame 0x{:02x} of {} bytes exceeds the limit of {} bytes",
+            self.msg_type, self.len, self.max_len
+        )
+    }
+}
 
-    while offset + HEADER_SIZE <= recv_buf.len() {
-        let header: [u8; HEADER_SIZE] = recv_buf[offset..offset + HEADER_SIZE]
+impl core::error::Error for FrameTooLarge {}
+
+/// Reassembles frames from a byte stream read in arbitrary pieces: feed it
+/// whatever a read returned and take out the frames completed so far.
+#[cfg(feature = "alloc")]
+#[derive(Debug)]
+pub struct FrameDecoder {
+    buf: Vec<u8>,
+    /// Start of the first frame not yet taken out of `buf`.
+    start: usize,
+    max_len: usize,
+    framing: Framing,
+    /// The frame that failed its checksum; nothing is decoded after it.
+    corrupt: Option<ChecksumError>,
+}
+
+#[cfg(feature = "alloc")]
+impl Default for FrameDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl FrameDecoder {
+    /// A decoder that accepts any frame length the header can express.
+    pub fn new() -> Self {
+        Self::with_max_len(u32::MAX as usize)
+    }
+
+    /// A decoder that stops at frames with payloads over `max_len` bytes
+    /// (see [`FrameDecoder::oversized`]).
+    pub fn with_max_len(max_len: usize) -> Self {
+        Self {
+            buf: Vec::new(),
+            start: 0,
+            max_len,
+            framing: Framing::Plain,
+            corrupt: None,
+        }
+    }
+
+    /// Decode the frames not taken out yet, and all later ones, as
+    /// `framing` delimits them, e.g. once `HELLO` negotiated checksums.
+    pub fn set_framing(&mut self, framing: Framing) {
+        self.framing = framing;
+    }
+
+    /// Append bytes read from the stream.
+    pub fn feed(&mut self, data: &[u8]) {
+        // Drop consumed frames before growing, not after every frame.
+        if self.start > 0 {
+            self.buf.drain(..self.start);
+            self.start = 0;
+        }
+        self.buf.extend_from_slice(data);
+    }
+
+    /// The next complete frame, or `None` until more bytes are fed. Also
+    /// `None` for good once an oversized frame is next or a frame failed
+    /// its checksum.
+    pub fn next_frame(&mut self) -> Option<Frame> {
+        if self.oversized().is_some() || self.corrupt.is_some() {
+            return None;
+        }
+        let (frame, mut len) = split_frame(&self.buf[self.start..])?;
+        if self.framing == Framing::Crc32 {
+            let trailer = self
+                .buf
+                .get(self.start + len..self.start + len + CRC_SIZE)?;
+            let expected = u32::from_le_bytes(trailer.try_into().ok()?);
+            let actual = crc32(&self.buf[self.start..self.start + len]);
+            if expected != actual {
+                self.corrupt = Some(ChecksumError {
+                    msg_type: frame.msg_type,
+                    expected,
+                    actual,
+                });
+                return None;
+            }
+            len += CRC_SIZE;
+        }
+        self.start += len;
+        if self.start == self.buf.len() {
+            self.buf.clear();
+            self.start = 0;
+        }
+        Some(frame)
+    }
+
+    /// Feed `data` and take out every frame completed by it.
+    pub fn decode(&mut self, data: &[u8]) -> Vec<Frame> {
+        self.feed(data);
+        core::iter::from_fn(|| self.next_frame()).collect()
+    }
+
+    /// Bytes of incomplete frames held back.
+    pub fn buffered(&self) -> usize {
+        self.buf.len() - self.start
+    }
+
+    /// The next frame's header, once received, if it announces a payload
+    /// over the limit. Known before the payload arrives, so the caller can
+    /// drop the connection instead of buffering it.
+    pub fn oversized(&self) -> Option<FrameTooLarge> {
+        let header: &[u8; HEADER_SIZE] = self
+            .buf
+            .get(self.start..self.start + HEADER_SIZE)?
             .try_into()
-            .expect("header slice length should match HEADER_SIZE");
-        let (msg_type, payload_len) = decode_header(&header);
-        let payload_len = payload_len as usize;
+            .ok()?;
+        let (msg_type, len) = decode_header(header);
+        (len as usize > self.max_len).then_some(FrameTooLarge {
+            msg_type,
+            len: len as usize,
+            max_len: self.max_len,
+        })
+    }
+
+    /// The frame that failed its checksum under [`Framing::Crc32`]. The
+    /// stream cannot be trusted after it, so the connection must be closed.
+    pub fn checksum_error(&self) -> Option<&ChecksumError> {
+        self.corrupt.as_ref()
+    }
+}
 
-        if offset + HEADER_SIZE + payload_len > recv_buf.len() {
-            break;
+/// Size of the CRC32 trailer added by [`Framing::Crc32`].
+pub const CRC_SIZE: usize = 4;
+
+/// How frames are delimited on a connection. Connections start out
+/// [`Framing::Plain`]; [`Framing::Crc32`] is for transports that can corrupt
+/// data in flight (e.g. a socket tunnelled over a serial line), where it
+/// must be agreed by both ends before the first checked frame is sent
+/// ([`CHECKSUM_CRC32`] in `HELLO`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Framing {
+    #[default]
+    Plain,
+    /// Every frame is followed by a CRC32 (IEEE, LE) of its header and
+    /// payload.
+    Crc32,
+}
+
+/// A frame whose CRC32 trailer did not match. The stream cannot be
+/// resynchronized after this, so the connection must be reset.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChecksumError {
+    pub msg_type: u8,
+    pub expected: u32,
+    pub actual: u32,
+}
+
+impl fmt::Display for ChecksumError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "checksum mismatch in frame 0x{:02x}: expected {:08x}, got {:08x}",
+            self.msg_type, self.expected, self.actual
+        )
+    }
+}
+
+impl core::error::Error for ChecksumError {}
+
+impl Framing {
+    /// Bytes that follow each frame: the CRC32 for [`Framing::Crc32`].
+    pub fn trailer_len(self) -> usize {
+        match self {
+            Self::Plain => 0,
+            Self::Crc32 => CRC_SIZE,
         }
+    }
+}
 
-        offset += HEADER_SIZE;
-        let payload = recv_buf[offset..offset + payload_len].to_vec();
-        offset += payload_len;
+#[cfg(feature = "alloc")]
+impl Framing {
+    /// Append `frames`, whole plainly encoded frames, to `out`, adding a
+    /// CRC32 trailer to each for [`Framing::Crc32`].
+    pub fn append(self, mut frames: &[u8], out: &mut Vec<u8>) {
+        if self == Self::Plain {
+            out.extend_from_slice(frames);
+            return;
+        }
+        while let Some((_, len)) = parse_frame(frames) {
+            out.extend_from_slice(&frames[..len]);
+            out.extend_from_slice(&crc32(&frames[..len]).to_le_bytes());
+            frames = &frames[len..];
+        }
+    }
 
-        frames.push(Frame { msg_type, payload });
+    /// Encode a framed message, with a CRC32 trailer for [`Framing::Crc32`].
+    pub fn encode(self, msg_type: u8, payload: &[u8]) -> Vec<u8> {
+        let mut buf = encode(msg_type, payload);
+        if self == Self::Crc32 {
+            let crc = crc32(&buf);
+            buf.extend_from_slice(&crc.to_le_bytes());
+        }
+        buf
     }
 
-    if offset > 0 {
+    /// Decode all complete frames from `recv_buf` like [`decode_frames`],
+    /// verifying checksums for [`Framing::Crc32`]. A mismatch is returned as
+    /// an error; the caller must then reset the connection.
+    pub fn decode_frames(self, recv_buf: &mut Vec<u8>) -> Result<Vec<Frame>, ChecksumError> {
+        if self == Self::Plain {
+            return Ok(decode_frames(recv_buf));
+        }
+        let mut frames = Vec::new();
+        let mut offset = 0;
+        let mut result = Ok(());
+        while offset + HEADER_SIZE <= recv_buf.len() {
+            let header: [u8; HEADER_SIZE] = recv_buf[offset..offset + HEADER_SIZE]
+                .try_into()
+                .expect("header slice length should match HEADER_SIZE");
+            let (msg_type, payload_len) = decode_header(&header);
+            let end = offset + HEADER_SIZE + payload_len as usize;
+            if end + CRC_SIZE > recv_buf.len() {
+                break;
+            }
+            let expected = u32::from_le_bytes(
+                recv_buf[end..end + CRC_SIZE]
+                    .try_into()
+                    .expect("trailer slice length should match CRC_SIZE"),
+            );
+            let actual = crc32(&recv_buf[offset..end]);
+            if expected != actual {
+                result = Err(ChecksumError {
+                    msg_type,
+                    expected,
+                    actual,
+                });
+                break;
+            }
+            frames.push(Frame {
+                msg_type,
+                payload: recv_buf[offset + HEADER_SIZE..end].to_vec(),
+            });
+            offset = end + CRC_SIZE;
+        }
         recv_buf.drain(..offset);
+        result.map(|()| frames)
     }
+}
 
-    frames
+/// CRC-32 (IEEE 802.3, reflected, as used by zlib and Ethernet).
+pub fn crc32(data: &[u8]) -> u32 {
+    const TABLE: [u32; 256] = {
+        let mut table = [0u32; 256];
+        let mut i = 0;
+        while i < 256 {
+            let mut crc = i as u32;
+            let mut bit = 0;
+            while bit < 8 {
+                crc = if crc & 1 != 0 {
+                    (crc >> 1) ^ 0xEDB8_8320
+                } else {
+                    crc >> 1
+                };
+                bit += 1;
+            }
+            table[i] = crc;
+            i += 1;
+        }
+        table
+    };
+    let mut crc = !0u32;
+    for &b in data {
+        crc = TABLE[((crc ^ b as u32) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    !crc
 }
 
 /// Encode a resize payload.
@@ -148,25 +1024,155 @@ pub fn decode_resize(payload: &[u8; 4]) -> (u16, u16) {
     (cols, rows)
 }
 
+/// Decode a resize payload, rejecting sizes no terminal has: a zero
+/// dimension (a pty not sized yet) or one above [`MAX_TERMINAL_SIZE`].
 pub fn parse_resize(payload: &[u8]) -> Result<(u16, u16), DecodeError> {
     let payload: &[u8; 4] = payload
         .try_into()
         .map_err(|_| DecodeError::InvalidResizePayloadLen(payload.len()))?;
-    Ok(decode_resize(payload))
+    let (cols, rows) = decode_resize(payload);
+    let valid = 1..=MAX_TERMINAL_SIZE;
+    if !valid.contains(&cols) || !valid.contains(&rows) {
+        return Err(DecodeError::InvalidResizeSize(cols, rows));
+    }
+    Ok((cols, rows))
 }
 
 pub fn encode_exit(exit_code: i32) -> [u8; 4] {
     exit_code.to_le_bytes()
 }
 
+/// Encode an exit payload with the JSON `details` after the code.
+#[cfg(feature = "alloc")]
+pub fn encode_exit_details(exit_code: i32, details: &[u8]) -> Vec<u8> {
+    let mut buf = encode_exit(exit_code).to_vec();
+    buf.extend_from_slice(details);
+    buf
+}
+
+/// [`encode_exit_details`] into the start of `buf`. Returns the number of
+/// bytes written.
+pub fn encode_exit_details_into(
+    exit_code: i32,
+    details: &[u8],
+    buf: &mut [u8],
+) -> Result<usize, BufferTooSmall> {
+    write_parts(buf, &[&encode_exit(exit_code), details])
+}
+
+/// Decode the exit code of an exit payload, ignoring any details.
 pub fn parse_exit(payload: &[u8]) -> Result<i32, DecodeError> {
+    let code: &[u8; 4] = payload
+        .get(..EXIT_PAYLOAD_SIZE)
+        .and_then(|code| code.try_into().ok())
+        .ok_or(DecodeError::InvalidExitPayloadLen(payload.len()))?;
+    Ok(i32::from_le_bytes(*code))
+}
+
+/// The JSON details of an exit payload; empty if it has none.
+pub fn exit_details(payload: &[u8]) -> &[u8] {
+    payload.get(EXIT_PAYLOAD_SIZE..).unwrap_or_default()
+}
+
+pub fn encode_client_id(client_id: u32) -> [u8; 4] {
+    client_id.to_le_bytes()
+}
+
+pub fn parse_client_id(payload: &[u8]) -> Result<u32, DecodeError> {
     let payload: &[u8; 4] = payload
         .try_into()
-        .map_err(|_| DecodeError::InvalidExitPayloadLen(payload.len()))?;
-    Ok(i32::from_le_bytes(*payload))
+        .map_err(|_| DecodeError::InvalidClientIdPayloadLen(payload.len()))?;
+    Ok(u32::from_le_bytes(*payload))
+}
+
+/// Version to speak with a peer that speaks `peer_min..=peer_version`:
+/// the newest one both ends know, or `None` if the ranges do not overlap.
+pub fn negotiate_version(peer_version: u16, peer_min: u16) -> Option<u16> {
+    let version = PROTOCOL_VERSION.min(peer_version);
+    (version >= MIN_PROTOCOL_VERSION.max(peer_min)).then_some(version)
 }
 
-#[cfg(test)]
+/// Encode an `INPUT_SEQ` payload.
+#[cfg(feature = "alloc")]
+pub fn encode_input_seq(seq: u64, input: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(INPUT_SEQ_HEADER_SIZE + input.len());
+    buf.extend_from_slice(&seq.to_le_bytes());
+    buf.extend_from_slice(input);
+    buf
+}
+
+/// [`encode_input_seq`] into the start of `buf`. Returns the number of bytes
+/// written.
+pub fn encode_input_seq_into(
+    seq: u64,
+    input: &[u8],
+    buf: &mut [u8],
+) -> Result<usize, BufferTooSmall> {
+    write_parts(buf, &[&seq.to_le_bytes(), input])
+}
+
+/// Split an `INPUT_SEQ` payload into its sequence number and input.
+pub fn parse_input_seq(payload: &[u8]) -> Result<(u64, &[u8]), DecodeError> {
+    let Some((seq, input)) = payload.split_first_chunk::<INPUT_SEQ_HEADER_SIZE>() else {
+        return Err(DecodeError::InvalidInputSeqPayloadLen(payload.len()));
+    };
+    Ok((u64::from_le_bytes(*seq), input))
+}
+
+pub fn encode_credit(bytes: u64) -> [u8; CREDIT_PAYLOAD_SIZE] {
+    bytes.to_le_bytes()
+}
+
+/// Decode a `CREDIT` payload into the number of bytes returned.
+pub fn parse_credit(payload: &[u8]) -> Result<u64, DecodeError> {
+    let payload: [u8; CREDIT_PAYLOAD_SIZE] = payload
+        .try_into()
+        .map_err(|_| DecodeError::InvalidCreditPayloadLen(payload.len()))?;
+    Ok(u64::from_le_bytes(payload))
+}
+
+/// Encode a `CHANNEL` payload carrying a `msg_type` frame for `channel`.
+#[cfg(feature = "alloc")]
+pub fn encode_channel(channel: u32, msg_type: u8, payload: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(CHANNEL_HEADER_SIZE + HEADER_SIZE + payload.len());
+    buf.extend_from_slice(&channel.to_le_bytes());
+    buf.extend_from_slice(&encode(msg_type, payload));
+    buf
+}
+
+/// [`encode_channel`] into the start of `buf`. Returns the number of bytes
+/// written.
+pub fn encode_channel_into(
+    channel: u32,
+    msg_type: u8,
+    payload: &[u8],
+    buf: &mut [u8],
+) -> Result<usize, BufferTooSmall> {
+    let header = encode_header(msg_type, payload.len() as u32);
+    write_parts(buf, &[&channel.to_le_bytes(), &header, payload])
+}
+
+/// Split a `CHANNEL` payload into its channel id and frame.
+#[cfg(feature = "alloc")]
+pub fn parse_channel(payload: &[u8]) -> Result<(u32, Frame), DecodeError> {
+    parse_channel_ref(payload).map(|(channel, frame)| (channel, frame.into()))
+}
+
+/// [`parse_channel`] without copying the frame.
+pub fn parse_channel_ref(payload: &[u8]) -> Result<(u32, FrameRef<'_>), DecodeError> {
+    let invalid = || DecodeError::InvalidChannelPayloadLen(payload.len());
+    let (channel, frame) = payload
+        .split_first_chunk::<CHANNEL_HEADER_SIZE>()
+        .ok_or_else(invalid)?;
+    match parse_frame(frame) {
+        Some((frame, len)) if len == payload.len() - CHANNEL_HEADER_SIZE => {
+            Ok((u32::from_le_bytes(*channel), frame))
+        }
+        _ => Err(invalid()),
+    }
+}
+
+#[cfg(all(test, feature = "alloc"))]
 mod tests {
     use super::*;
 
@@ -183,6 +1189,66 @@ mod tests {
         assert_eq!(&encoded[HEADER_SIZE..], payload);
     }
 
+    #[test]
+    fn crc32_matches_reference_value() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn crc_framing_detects_corruption() {
+        let framing = Framing::Crc32;
+        let mut buf = framing.encode(server::OUTPUT, b"hello");
+        buf.extend(framing.encode(server::EXIT, &encode_exit(0)));
+        assert_eq!(buf.len(), 2 * (HEADER_SIZE + CRC_SIZE) + 5 + 4);
+
+        let mut clean = buf.clone();
+        let frames = framing.decode_frames(&mut clean).unwrap();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].payload, b"hello");
+        assert!(clean.is_empty());
+
+        // Partial trailer: wait for more bytes.
+        let mut partial = buf[..HEADER_SIZE + 5 + 2].to_vec();
+        assert!(framing.decode_frames(&mut partial).unwrap().is_empty());
+        assert_eq!(partial.len(), HEADER_SIZE + 5 + 2);
+
+        let mut corrupted = buf.clone();
+        corrupted[HEADER_SIZE + 1] ^= 0x20;
+        let err = framing.decode_frames(&mut corrupted).unwrap_err();
+        assert_eq!(err.msg_type, server::OUTPUT);
+
+        assert_eq!(
+            Framing::default().encode(server::OUTPUT, b"x"),
+            encode(server::OUTPUT, b"x")
+        );
+
+        let mut appended = Vec::new();
+        let plain = [
+            encode(server::OUTPUT, b"hello"),
+            encode(server::EXIT, &encode_exit(0)),
+        ];
+        framing.append(&plain.concat(), &mut appended);
+        assert_eq!(appended, buf);
+
+        // A decoder switched to CRC framing after the first frame.
+        let mut decoder = FrameDecoder::new();
+        let mut stream = encode(server::HELLO, b"{}");
+        stream.extend(&corrupted);
+        decoder.feed(&stream[..HEADER_SIZE + 2]);
+        assert_eq!(decoder.next_frame().unwrap().msg_type, server::HELLO);
+        decoder.set_framing(framing);
+        assert_eq!(decoder.decode(&stream[HEADER_SIZE + 2..]), []);
+        assert_eq!(decoder.checksum_error().unwrap().msg_type, server::OUTPUT);
+
+        let mut decoder = FrameDecoder::new();
+        decoder.set_framing(framing);
+        assert_eq!(decoder.decode(&buf[..HEADER_SIZE + 5]), []);
+        assert_eq!(decoder.decode(&buf[HEADER_SIZE + 5..]).len(), 2);
+        assert!(decoder.checksum_error().is_none());
+        assert_eq!(decoder.buffered(), 0);
+    }
+
     #[test]
     fn roundtrip_resize() {
         let buf = encode_resize(120, 40);
@@ -213,10 +1279,229 @@ mod tests {
         assert_eq!(err, DecodeError::InvalidResizePayloadLen(3));
     }
 
+    #[test]
+    fn parse_resize_rejects_zero_and_absurd_sizes() {
+        assert_eq!(parse_resize(&encode_resize(1, 10_000)), Ok((1, 10_000)));
+        assert_eq!(
+            parse_resize(&encode_resize(0, 0)),
+            Err(DecodeError::InvalidResizeSize(0, 0))
+        );
+        assert_eq!(
+            parse_resize(&encode_resize(80, 10_001)),
+            Err(DecodeError::InvalidResizeSize(80, 10_001))
+        );
+    }
+
     #[test]
     fn parse_exit_roundtrip() {
         let payload = encode_exit(42);
         let exit_code = parse_exit(&payload).unwrap();
         assert_eq!(exit_code, 42);
+        assert!(exit_details(&payload).is_empty());
+
+        let payload = encode_exit_details(139, br#"{"signal":11}"#);
+        assert_eq!(parse_exit(&payload), Ok(139));
+        assert_eq!(exit_details(&payload), br#"{"signal":11}"#);
+        assert_eq!(
+            parse_exit(&[1, 0]),
+            Err(DecodeError::InvalidExitPayloadLen(2))
+        );
+    }
+
+    #[test]
+    fn registry_matches_constants_and_ranges() {
+        assert_eq!(
+            ClientMessage::from_u8(client::RESIZE),
+            Some(ClientMessage::Resize)
+        );
+        assert_eq!(ClientMessage::Resize.name(), "RESIZE");
+        assert_eq!(ServerMessage::StateSync.as_u8(), server::STATE_SYNC);
+        assert_eq!(ClientMessage::from_u8(0x7F), None);
+
+        for msg in ClientMessage::ALL {
+            assert_eq!(ClientMessage::from_u8(msg.as_u8()), Some(*msg));
+        }
+        for msg in ServerMessage::ALL {
+            assert_eq!(ServerMessage::from_u8(msg.as_u8()), Some(*msg));
+        }
+        // Only types allocated before the ranges existed may disagree with
+        // the class implied by their range.
+        let legacy = 0x03..=0x0B;
+        for (value, class) in ClientMessage::ALL
+            .iter()
+            .map(|m| (m.as_u8(), m.class()))
+            .chain(ServerMessage::ALL.iter().map(|m| (m.as_u8(), m.class())))
+        {
+            assert!(
+                legacy.contains(&value) || FrameClass::of_range(value) == Some(class),
+                "0x{:02x} is outside the range for {:?}",
+                value,
+                class
+            );
+        }
+
+        assert_eq!(FrameClass::of_range(0x00), None);
+        assert!(is_data(0x1F) && is_control(0x20) && is_control(0x7F) && is_bulk(0x80));
+    }
+
+    #[test]
+    fn frame_decoder_reassembles_frames_split_across_reads() {
+        let mut stream = encode(client::INPUT, b"hello");
+        stream.extend(encode(client::REDRAW, &[]));
+        stream.extend(encode(client::RESIZE, &encode_resize(80, 24)));
+
+        // Partial header, then the rest of the header and a partial payload.
+        let mut decoder = FrameDecoder::new();
+        assert!(decoder.decode(&stream[..3]).is_empty());
+        assert!(decoder.decode(&stream[3..7]).is_empty());
+        assert_eq!(decoder.buffered(), 7);
+        let frames = decoder.decode(&stream[7..12]);
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].msg_type, client::INPUT);
+        assert_eq!(frames[0].payload, b"hello");
+
+        // Several frames and the start of the next one in a single read.
+        let mut more = stream[12..].to_vec();
+        more.extend(&encode(client::DETACH, &[])[..2]);
+        let frames = decoder.decode(&more);
+        let types: Vec<u8> = frames.iter().map(|f| f.msg_type).collect();
+        assert_eq!(types, [client::REDRAW, client::RESIZE]);
+        assert_eq!(parse_resize(&frames[1].payload), Ok((80, 24)));
+        assert_eq!(decoder.buffered(), 2);
+
+        // One byte at a time, taking frames out as they complete.
+        let mut decoder = FrameDecoder::new();
+        let mut frames = Vec::new();
+        for byte in &stream {
+            decoder.feed(std::slice::from_ref(byte));
+            frames.extend(std::iter::from_fn(|| decoder.next_frame()));
+        }
+        assert_eq!(frames.len(), 3);
+        assert_eq!(decoder.buffered(), 0);
+    }
+
+    #[test]
+    fn frame_decoder_rejects_oversized_frames_from_the_header() {
+        let mut decoder = FrameDecoder::with_max_len(8);
+        let mut stream = encode(client::INPUT, b"12345678");
+        let oversized = encode(client::PASTE, &[b'x'; 9]);
+        stream.extend_from_slice(&oversized[..HEADER_SIZE]);
+
+        let frames = decoder.decode(&stream);
+        assert_eq!(frames.len(), 1);
+        assert_eq!(
+            decoder.oversized(),
+            Some(FrameTooLarge {
+                msg_type: client::PASTE,
+                len: 9,
+                max_len: 8,
+            })
+        );
+        // Nothing past the oversized header is ever decoded.
+        decoder.feed(&oversized[HEADER_SIZE..]);
+        decoder.feed(&encode(client::REDRAW, &[]));
+        assert_eq!(decoder.next_frame(), None);
+
+        // A header claiming 4 GiB is rejected before any payload arrives.
+        let mut decoder = FrameDecoder::with_max_len(DEFAULT_MAX_FRAME_LEN);
+        decoder.feed(&[client::INPUT, 0xff, 0xff, 0xff, 0xff]);
+        assert_eq!(decoder.oversized().map(|e| e.len), Some(u32::MAX as usize));
+        assert_eq!(FrameDecoder::new().decode(&oversized).len(), 1);
+    }
+
+    #[test]
+    fn parse_client_id_roundtrip_and_rejects_invalid_lengths() {
+        assert_eq!(parse_client_id(&encode_client_id(7)).unwrap(), 7);
+        assert_eq!(
+            parse_client_id(&[1, 2]),
+            Err(DecodeError::InvalidClientIdPayloadLen(2))
+        );
+    }
+
+    #[test]
+    fn negotiates_the_newest_common_version() {
+        assert_eq!(
+            negotiate_version(PROTOCOL_VERSION, MIN_PROTOCOL_VERSION),
+            Some(PROTOCOL_VERSION)
+        );
+        assert_eq!(
+            negotiate_version(PROTOCOL_VERSION + 5, 1),
+            Some(PROTOCOL_VERSION)
+        );
+        assert_eq!(negotiate_version(1, 1), Some(1));
+        assert_eq!(
+            negotiate_version(PROTOCOL_VERSION + 5, PROTOCOL_VERSION + 1),
+            None
+        );
+        assert_eq!(negotiate_version(MIN_PROTOCOL_VERSION - 1, 0), None);
+    }
+
+    #[test]
+    fn parse_input_seq_roundtrip_and_rejects_short_payloads() {
+        let payload = encode_input_seq(42, b"make\r");
+        assert_eq!(parse_input_seq(&payload).unwrap(), (42, &b"make\r"[..]));
+        assert_eq!(
+            parse_input_seq(&encode_input_seq(1, b"")).unwrap(),
+            (1, &[][..])
+        );
+        assert_eq!(
+            parse_input_seq(&[1, 2, 3]),
+            Err(DecodeError::InvalidInputSeqPayloadLen(3))
+        );
+        assert_eq!(parse_credit(&encode_credit(65536)), Ok(65536));
+        assert_eq!(
+            parse_credit(&[0; 4]),
+            Err(DecodeError::InvalidCreditPayloadLen(4))
+        );
+    }
+
+    #[test]
+    fn parse_channel_roundtrip_and_rejects_partial_frames() {
+        let payload = encode_channel(7, client::INPUT, b"ls\r");
+        let frame = Frame {
+            msg_type: client::INPUT,
+            payload: b"ls\r".to_vec(),
+        };
+        assert_eq!(parse_channel(&payload), Ok((7, frame)));
+        assert_eq!(
+            parse_channel(&payload[..payload.len() - 1]),
+            Err(DecodeError::InvalidChannelPayloadLen(payload.len() - 1))
+        );
+        let mut trailing = payload.clone();
+        trailing.push(0);
+        assert!(parse_channel(&trailing).is_err());
+        assert!(parse_channel(&[7, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn slice_encoders_match_the_vec_ones() {
+        let mut buf = [0u8; 32];
+        let n = encode_into(client::INPUT, b"ls\r", &mut buf).unwrap();
+        assert_eq!(&buf[..n], &encode(client::INPUT, b"ls\r")[..]);
+        let (frame, len) = parse_frame(&buf[..n]).unwrap();
+        assert_eq!(len, n);
+        assert_eq!(frame.payload, b"ls\r");
+        assert_eq!(parse_frame(&buf[..n - 1]), None);
+
+        let mut channel = [0u8; 32];
+        let n = encode_channel_into(7, client::INPUT, b"ls\r", &mut channel).unwrap();
+        assert_eq!(
+            &channel[..n],
+            &encode_channel(7, client::INPUT, b"ls\r")[..]
+        );
+        assert_eq!(parse_channel_ref(&channel[..n]).unwrap(), (7, frame));
+
+        let n = encode_input_seq_into(9, b"x", &mut buf).unwrap();
+        assert_eq!(&buf[..n], &encode_input_seq(9, b"x")[..]);
+        let n = encode_exit_details_into(1, b"{}", &mut buf).unwrap();
+        assert_eq!(&buf[..n], &encode_exit_details(1, b"{}")[..]);
+
+        assert_eq!(
+            encode_into(client::INPUT, &[0; 28], &mut buf),
+            Err(BufferTooSmall {
+                needed: 33,
+                len: 32
+            })
+        );
     }
 }
@@ -0,0 +1,119 @@
+//! `pterm export` / `pterm import`: a session's context as a single
+//! `.tar.zst` file, e.g. to attach to an incident ticket.
+//!
+//! The archive holds the session's `meta.json`, its output as `scrollback`
+//! and, for a session started with `--record-io`, the recording under
+//! `recording/`. The output is the scrollback file's contents, or the
+//! daemon's plain-text capture for a session without one.
+//!
+//! Importing unpacks the archive into the new session's directory and runs
+//! the hidden `pterm __show-export <dir>` as its command, in hold mode: it
+//! prints the output and exits with the exported exit code, so the session
+//! can be attached to and copied from but runs nothing.
+
+use crate::meta::{self, SessionMeta, META_FILENAME};
+use crate::recording::{RECORD_FILENAME, SESSION_FILENAME};
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+
+/// Hidden subcommand run inside an imported session.
+pub const SHOW_COMMAND: &str = "__show-export";
+/// Directory within an imported session's directory holding the unpacked
+/// archive.
+pub const IMPORT_DIRNAME: &str = "export";
+/// Archive entry holding the session's output.
+const OUTPUT_ENTRY: &str = "scrollback";
+/// Archive directory holding the `--record-io` recording.
+const RECORDING_DIR: &str = "recording";
+
+/// Write the archive for a session to `path`.
+pub fn write(path: &Path, meta: &SessionMeta, output: &[u8]) -> io::Result<()> {
+    let encoder = zstd::Encoder::new(File::create(path)?, 0)?;
+    let mut tar = tar::Builder::new(encoder);
+    let meta_json = serde_json::to_vec_pretty(meta).map_err(io::Error::other)?;
+    append(&mut tar, META_FILENAME, &meta_json)?;
+    append(&mut tar, OUTPUT_ENTRY, output)?;
+    if let Some(dir) = &meta.recording {
+        for name in [SESSION_FILENAME, RECORD_FILENAME] {
+            let entry = format!("{}/{}", RECORDING_DIR, name);
+            tar.append_path_with_name(dir.join(name), entry)?;
+        }
+    }
+    tar.into_inner()?.finish()?.flush()
+}
+
+fn append<W: Write>(tar: &mut tar::Builder<W>, name: &str, data: &[u8]) -> io::Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o600);
+    header.set_mtime(meta::unix_now());
+    tar.append_data(&mut header, name, data)
+}
+
+/// Unpack an archive into `dir` and return the exported metadata. Entries
+/// are confined to `dir`.
+pub fn unpack(archive: &Path, dir: &Path) -> io::Result<SessionMeta> {
+    let decoder = zstd::Decoder::new(File::open(archive)?)?;
+    std::fs::create_dir_all(dir)?;
+    tar::Archive::new(decoder).unpack(dir)?;
+    meta::read(dir).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("not a pterm export ({}: {})", META_FILENAME, e),
+        )
+    })
+}
+
+/// Run inside an imported session: print the exported output and return
+/// the exported exit code.
+pub fn show(dir: &Path) -> io::Result<i32> {
+    let output = std::fs::read(dir.join(OUTPUT_ENTRY))?;
+    let mut stdout = io::stdout().lock();
+    stdout.write_all(&output)?;
+    stdout.flush()?;
+    Ok(meta::read(dir)?.exit_code.unwrap_or(0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::recording::{RecordedSession, Recorder};
+
+    #[test]
+    fn export_roundtrip_with_recording() {
+        let base = std::env::temp_dir().join(format!("pterm-archive-test-{}", std::process::id()));
+        let recording = base.join("rec");
+        let recorded = RecordedSession {
+            session: "ci".to_string(),
+            command: vec!["make".to_string()],
+            cols: 80,
+            rows: 24,
+        };
+        Recorder::create(&recording, &recorded)
+            .unwrap()
+            .flush()
+            .unwrap();
+        let meta = SessionMeta {
+            session: "ci".to_string(),
+            exit_code: Some(2),
+            recording: Some(recording),
+            ..SessionMeta::default()
+        };
+        let archive = base.join("ci.tar.zst");
+        write(&archive, &meta, b"make: *** [all] Error 2\r\n").unwrap();
+
+        let dir = base.join("import");
+        assert_eq!(unpack(&archive, &dir).unwrap(), meta);
+        assert_eq!(
+            std::fs::read(dir.join(OUTPUT_ENTRY)).unwrap(),
+            b"make: *** [all] Error 2\r\n"
+        );
+        assert!(dir.join(RECORDING_DIR).join(SESSION_FILENAME).exists());
+
+        let bogus = base.join("bogus.tar.zst");
+        std::fs::write(&bogus, b"not an archive").unwrap();
+        assert!(unpack(&bogus, &base.join("bogus")).is_err());
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+}
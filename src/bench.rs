@@ -0,0 +1,339 @@
+//! `pterm bench`: measure how fast output gets from a session's program to
+//! an attached client on this machine.
+//!
+//! The benchmark starts an ephemeral session running `pterm
+//! __bench-generator`, attaches to it like a bridge would and types `go`.
+//! The generator then writes lines of `--payload-size` bytes as fast as the
+//! pty takes them, for `--duration`, each starting with the wall clock time
+//! it was written at, and exits. The client counts the `OUTPUT` frames and
+//! bytes it receives and, for every line, the time from the write to its
+//! arrival: everything the daemon adds (pty reads, terminal emulation,
+//! batching, queueing) is in there.
+
+use crate::clients::format_bytes;
+use crate::constants::{DEFAULT_TERMINAL_COLS, DEFAULT_TERMINAL_ROWS};
+use crate::control;
+use crate::paths::{self, session_dir, session_socket_path};
+use pterm_proto as proto;
+use serde::Serialize;
+use std::io::{self, BufRead, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Hidden subcommand the benchmark session runs.
+pub const GENERATOR_COMMAND: &str = "__bench-generator";
+
+/// Start of every generated line, followed by the nanoseconds since the
+/// epoch it was written at.
+const STAMP: &[u8] = b"pterm-bench ";
+/// Digits of the time after [`STAMP`].
+const STAMP_DIGITS: usize = 20;
+
+pub const DEFAULT_PAYLOAD_SIZE: usize = 4096;
+pub const DEFAULT_DURATION: Duration = Duration::from_secs(5);
+/// Smallest payload that fits a stamp and a newline.
+pub const MIN_PAYLOAD_SIZE: usize = STAMP.len() + STAMP_DIGITS + 2;
+pub const MAX_PAYLOAD_SIZE: usize = 1 << 20;
+
+/// Longest wait for the next bytes from the session before giving up.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Options {
+    pub payload_size: usize,
+    pub duration: Duration,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            payload_size: DEFAULT_PAYLOAD_SIZE,
+            duration: DEFAULT_DURATION,
+        }
+    }
+}
+
+/// Latency percentiles in microseconds.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct Latency {
+    pub p50: u64,
+    pub p90: u64,
+    pub p99: u64,
+    pub max: u64,
+}
+
+impl Latency {
+    fn of(samples: &mut [u64]) -> Self {
+        samples.sort_unstable();
+        let at = |p: usize| match samples.len() {
+            0 => 0,
+            n => samples[((n - 1) * p).div_ceil(100)],
+        };
+        Self {
+            p50: at(50),
+            p90: at(90),
+            p99: at(99),
+            max: at(100),
+        }
+    }
+}
+
+/// What a benchmark run measured.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Report {
+    pub payload_size: usize,
+    /// From the first `OUTPUT` frame to the last.
+    pub seconds: f64,
+    pub bytes: u64,
+    pub frames: u64,
+    pub bytes_per_sec: f64,
+    pub frames_per_sec: f64,
+    /// Generated lines timed.
+    pub samples: usize,
+    pub latency_us: Latency,
+}
+
+impl Report {
+    pub fn render(&self) -> String {
+        let latency = self.latency_us;
+        let average = self.bytes.checked_div(self.frames).unwrap_or(0);
+        format!(
+            "payload     {} per write\n\
+             throughput  {}/s ({} in {:.2}s)\n\
+             frames      {:.0}/s ({} frames, {} average)\n\
+             latency     p50 {}  p90 {}  p99 {}  max {} ({} samples)\n",
+            format_bytes(self.payload_size as u64),
+            format_bytes(self.bytes_per_sec as u64),
+            format_bytes(self.bytes),
+            self.seconds,
+            self.frames_per_sec,
+            self.frames,
+            format_bytes(average),
+            format_micros(latency.p50),
+            format_micros(latency.p90),
+            format_micros(latency.p99),
+            format_micros(latency.max),
+            self.samples,
+        )
+    }
+}
+
+/// `850us`, `12.3ms` or `1.20s`.
+fn format_micros(us: u64) -> String {
+    match us {
+        0..1000 => format!("{}us", us),
+        1000..1_000_000 => format!("{:.1}ms", us as f64 / 1e3),
+        _ => format!("{:.2}s", us as f64 / 1e6),
+    }
+}
+
+fn now_nanos() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos() as u64)
+}
+
+/// The generator: wait for a line on stdin, then write stamped lines of
+/// `payload_size` bytes for `duration`.
+pub fn generate(payload_size: usize, duration: Duration) -> io::Result<()> {
+    io::stdin().lock().read_line(&mut String::new())?;
+    let mut line = vec![b'x'; payload_size.max(MIN_PAYLOAD_SIZE)];
+    *line.last_mut().expect("line is not empty") = b'\n';
+    let mut out = io::stdout().lock();
+    let started = Instant::now();
+    while started.elapsed() < duration {
+        let stamp = format!("{:0width$} ", now_nanos(), width = STAMP_DIGITS);
+        line[..STAMP.len()].copy_from_slice(STAMP);
+        line[STAMP.len()..STAMP.len() + stamp.len()].copy_from_slice(stamp.as_bytes());
+        out.write_all(&line)?;
+    }
+    out.flush()
+}
+
+/// Run a benchmark in an ephemeral session, removed again afterwards.
+pub fn run(options: &Options) -> io::Result<Report> {
+    let name = format!("bench-{}", std::process::id());
+    let exe = std::env::current_exe()?;
+    let status = Command::new(&exe)
+        .args(paths::socket_dir_args())
+        .args(["new", "--wait-ready", &name, "--"])
+        .arg(&exe)
+        .arg(GENERATOR_COMMAND)
+        .arg(options.payload_size.to_string())
+        .arg(options.duration.as_millis().to_string())
+        .stdout(Stdio::null())
+        .status()?;
+    if !status.success() {
+        return Err(io::Error::other("failed to start the benchmark session"));
+    }
+    let report = measure(&name, options);
+    // Already gone once the generator exited, unless `hold` is configured.
+    let dir = session_dir(&name);
+    if dir.exists() {
+        paths::remove_session_dir(&dir)?;
+    }
+    report
+}
+
+fn measure(name: &str, options: &Options) -> io::Result<Report> {
+    let mut socket = UnixStream::connect(session_socket_path(name))?;
+    control::hello(&mut socket)?;
+    socket.write_all(&proto::encode(
+        proto::client::RESIZE,
+        &proto::encode_resize(DEFAULT_TERMINAL_COLS, DEFAULT_TERMINAL_ROWS),
+    ))?;
+    socket.write_all(&proto::encode(proto::client::INPUT, b"go\r"))?;
+    socket.set_read_timeout(Some(IDLE_TIMEOUT))?;
+
+    let mut decoder = proto::FrameDecoder::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut meter = Meter::default();
+    loop {
+        let n = match socket.read(&mut buf) {
+            Ok(0) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "the session closed the connection before the generator finished",
+                ));
+            }
+            Ok(n) => n,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("no output for {}s", IDLE_TIMEOUT.as_secs()),
+                ));
+            }
+            Err(e) => return Err(e),
+        };
+        let (now, wall) = (Instant::now(), now_nanos());
+        for frame in decoder.decode(&buf[..n]) {
+            match frame.msg_type {
+                proto::server::OUTPUT => meter.output(&frame.payload, now, wall),
+                proto::server::PING => {
+                    socket.write_all(&proto::encode(proto::client::PONG, &[]))?;
+                }
+                proto::server::EXIT => {
+                    return match proto::parse_exit(&frame.payload) {
+                        Ok(0) => Ok(meter.report(options.payload_size)),
+                        Ok(code) => Err(io::Error::other(format!(
+                            "the generator exited with {}",
+                            code
+                        ))),
+                        Err(e) => Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+                    };
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Counts output and times the generated lines in it.
+#[derive(Debug, Default)]
+struct Meter {
+    /// Start of the line that continues in the next frame, up to the end
+    /// of its stamp.
+    head: Vec<u8>,
+    frames: u64,
+    bytes: u64,
+    first: Option<Instant>,
+    last: Option<Instant>,
+    latencies: Vec<u64>,
+}
+
+impl Meter {
+    /// An `OUTPUT` payload received at `now`, `wall` nanoseconds after the
+    /// epoch.
+    fn output(&mut self, payload: &[u8], now: Instant, wall: u64) {
+        self.frames += 1;
+        self.bytes += payload.len() as u64;
+        self.first.get_or_insert(now);
+        self.last = Some(now);
+        let mut lines = payload.split(|&b| b == b'\n').peekable();
+        while let Some(part) = lines.next() {
+            let want = (STAMP.len() + STAMP_DIGITS).saturating_sub(self.head.len());
+            self.head.extend_from_slice(&part[..part.len().min(want)]);
+            if lines.peek().is_none() {
+                // Unterminated: the line goes on in the next frame.
+                break;
+            }
+            if let Some(written) = self.stamp() {
+                self.latencies.push(wall.saturating_sub(written) / 1000);
+            }
+            self.head.clear();
+        }
+    }
+
+    /// The time at the start of the current line, if it is a generated one.
+    fn stamp(&self) -> Option<u64> {
+        let digits = self.head.strip_prefix(STAMP)?;
+        std::str::from_utf8(digits).ok()?.parse().ok()
+    }
+
+    fn report(mut self, payload_size: usize) -> Report {
+        let seconds = match (self.first, self.last) {
+            (Some(first), Some(last)) => (last - first).as_secs_f64(),
+            _ => 0.0,
+        };
+        let per_sec = |n: u64| {
+            if seconds > 0.0 {
+                n as f64 / seconds
+            } else {
+                0.0
+            }
+        };
+        Report {
+            payload_size,
+            seconds,
+            bytes: self.bytes,
+            frames: self.frames,
+            bytes_per_sec: per_sec(self.bytes),
+            frames_per_sec: per_sec(self.frames),
+            samples: self.latencies.len(),
+            latency_us: Latency::of(&mut self.latencies),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn meter_times_stamped_lines_split_across_frames() {
+        let mut meter = Meter::default();
+        let now = Instant::now();
+        let line = |nanos: u64| format!("pterm-bench {:020} xxxx\r\n", nanos);
+        let output = format!("go\r\n{}{}", line(1_000_000), line(2_000_000));
+        let (a, b) = output.split_at(20);
+        meter.output(a.as_bytes(), now, 5_000_000);
+        meter.output(b.as_bytes(), now, 5_000_000);
+        let last = line(4_000_000);
+        let (c, d) = last.split_at(3);
+        meter.output(c.as_bytes(), now, 6_000_000);
+        meter.output(d.as_bytes(), now, 6_000_000);
+        assert_eq!(meter.latencies, [4000, 3000, 2000]);
+        assert_eq!(meter.frames, 4);
+
+        let report = meter.report(4096);
+        assert_eq!(report.samples, 3);
+        assert_eq!(
+            report.latency_us,
+            Latency {
+                p50: 3000,
+                p90: 4000,
+                p99: 4000,
+                max: 4000,
+            }
+        );
+        assert_eq!(Latency::of(&mut []), Latency::default());
+        assert_eq!(format_micros(850), "850us");
+        assert_eq!(format_micros(12_345), "12.3ms");
+    }
+}
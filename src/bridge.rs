@@ -4,22 +4,49 @@
 //! Neovim owns the PTY that the bridge's stdin/stdout are connected to, so
 //! libvterm processes escape sequences natively in C -- no Lua intermediary.
 
+use crate::clients::{self, ClientIdentity};
 use crate::constants::{DEFAULT_TERMINAL_COLS, DEFAULT_TERMINAL_ROWS};
+use crate::control;
+use crate::frame_log::FrameLog;
+use crate::meta::ExitInfo;
+use crate::session_key;
+use crate::share;
+use crate::status_bar;
 use mio::net::UnixStream;
 use mio::unix::SourceFd;
 use mio::{Events, Interest, Poll, Token};
 use nix::libc;
 use nix::sys::termios;
 use pterm_proto as proto;
+use std::collections::VecDeque;
 use std::io::{self, Read, Write};
 use std::os::fd::{AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
 use std::path::Path;
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 
 const TOKEN_STDIN: Token = Token(0);
 const TOKEN_SOCKET: Token = Token(1);
 const TOKEN_WAKE: Token = Token(2);
 
+/// How long to wait for the daemon to answer a `PTY_INPUT_FD` request
+/// before falling back to `INPUT` frames (e.g. an older daemon).
+const PTY_FD_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How long another client counts as typing after its last `PRESENCE`.
+const TYPING_SHOWN_FOR: Duration = Duration::from_secs(3);
+
+/// How often the status bar's contents are refreshed from `meta.json`.
+const STATUS_BAR_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Connect retries when reconnecting after the connection dropped.
+const RECONNECT_RETRIES: u32 = 5;
+
+/// Output window asked for in `HELLO`: the daemon holds output back while
+/// this much of it has not been written to the terminal, so a stalled
+/// terminal does not make its send buffer grow without bound.
+const CREDIT_WINDOW: u64 = 1024 * 1024;
+
 // Some interactive programs enable xterm/kitty keyboard enhancement modes.
 // Reset them on detach so the next shell prompt does not inherit CSI-u style
 // encodings such as Ctrl-D => `CSI 100;5u`.
@@ -37,7 +64,7 @@ const TOKEN_WAKE: Token = Token(2);
 // - `CSI > 4 n`: reset xterm's modifyOtherKeys state.
 // - `CSI < u`: disable kitty's progressive keyboard enhancement flags.
 // - `CSI = 0 u`: reset kitty keyboard protocol to the base mode.
-const DETACH_CLEANUP_SEQUENCES: &[u8] = b"\
+pub(crate) const DETACH_CLEANUP_SEQUENCES: &[u8] = b"\
 \x1b[?1000l\
 \x1b[?1002l\
 \x1b[?1003l\
@@ -61,13 +88,13 @@ const STATE_SYNC_KEYBOARD_CLEANUP_SEQUENCES: &[u8] = b"\x1b[<u\x1b[=0u";
 static SIGWINCH_RECEIVED: AtomicBool = AtomicBool::new(false);
 
 /// RAII guard that restores terminal settings on drop.
-struct RawModeGuard {
+pub(crate) struct RawModeGuard {
     fd: RawFd,
     original: termios::Termios,
 }
 
 impl RawModeGuard {
-    fn enter(fd: RawFd) -> io::Result<Self> {
+    pub(crate) fn enter(fd: RawFd) -> io::Result<Self> {
         let borrowed = unsafe { BorrowedFd::borrow_raw(fd) };
         let original = termios::tcgetattr(borrowed).map_err(io::Error::other)?;
         let mut raw = original.clone();
@@ -85,7 +112,7 @@ impl Drop for RawModeGuard {
 }
 
 /// Get the current terminal size from a file descriptor.
-fn get_winsize(fd: RawFd) -> io::Result<(u16, u16)> {
+pub(crate) fn get_winsize(fd: RawFd) -> io::Result<(u16, u16)> {
     let mut ws: libc::winsize = unsafe { std::mem::zeroed() };
     let ret = unsafe { libc::ioctl(fd, libc::TIOCGWINSZ, &mut ws) };
     if ret == -1 {
@@ -95,6 +122,19 @@ fn get_winsize(fd: RawFd) -> io::Result<(u16, u16)> {
     }
 }
 
+/// The size of the terminal at `fd`, unless it reports a zero dimension,
+/// as a pty does before anything sized it. Oversized dimensions are
+/// clamped to what the daemon accepts.
+fn terminal_size(fd: RawFd) -> Option<(u16, u16)> {
+    let (cols, rows) = get_winsize(fd).ok()?;
+    (cols > 0 && rows > 0).then(|| (clamp_dimension(cols), clamp_dimension(rows)))
+}
+
+/// `n` within the terminal dimensions the daemon accepts in `RESIZE`.
+fn clamp_dimension(n: u16) -> u16 {
+    n.clamp(1, proto::MAX_TERMINAL_SIZE)
+}
+
 /// Create a pipe and return (read_fd, write_fd) as OwnedFd.
 fn make_pipe() -> io::Result<(OwnedFd, OwnedFd)> {
     let mut fds = [0i32; 2];
@@ -120,8 +160,38 @@ extern "C" fn sigwinch_handler(_sig: libc::c_int) {
     }
 }
 
+/// Route SIGWINCH to a non-blocking self-pipe and return its (read, write)
+/// ends. The write end must stay open while the handler is installed.
+pub(crate) fn sigwinch_pipe() -> io::Result<(OwnedFd, OwnedFd)> {
+    let (wake_read, wake_write) = make_pipe()?;
+    unsafe {
+        WAKE_WRITE_FD = wake_write.as_raw_fd();
+    }
+
+    unsafe {
+        let mut sa: libc::sigaction = std::mem::zeroed();
+        sa.sa_sigaction = sigwinch_handler as *const () as usize;
+        sa.sa_flags = libc::SA_RESTART;
+        libc::sigemptyset(&mut sa.sa_mask);
+        libc::sigaction(libc::SIGWINCH, &sa, std::ptr::null_mut());
+    }
+    Ok((wake_read, wake_write))
+}
+
+/// Drain the self-pipe and report whether SIGWINCH arrived since last call.
+pub(crate) fn take_sigwinch(wake_read_fd: RawFd) -> bool {
+    let mut drain = [0u8; 64];
+    loop {
+        match nix::unistd::read(wake_read_fd, &mut drain) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {}
+        }
+    }
+    SIGWINCH_RECEIVED.swap(false, Ordering::SeqCst)
+}
+
 /// Write all bytes to a raw fd, retrying on EAGAIN.
-fn write_all_raw(fd: RawFd, data: &[u8]) -> io::Result<()> {
+pub(crate) fn write_all_raw(fd: RawFd, data: &[u8]) -> io::Result<()> {
     let borrowed = unsafe { BorrowedFd::borrow_raw(fd) };
     let mut written = 0;
     while written < data.len() {
@@ -136,17 +206,409 @@ fn write_all_raw(fd: RawFd, data: &[u8]) -> io::Result<()> {
     Ok(())
 }
 
+/// Options for [`run`].
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// Override the terminal width sent in the initial RESIZE.
+    pub cols: Option<u16>,
+    /// Override the terminal height sent in the initial RESIZE.
+    pub rows: Option<u16>,
+    /// Write keyboard input straight to the pty master fd obtained from the
+    /// daemon instead of framing it as `INPUT`.
+    pub direct_input: bool,
+    /// Attach as a viewer: announce `READ_ONLY` instead of `RESIZE`, drop
+    /// keyboard input and never resize the session.
+    pub read_only: bool,
+    /// Keep retrying a failed connect until this much time has passed.
+    pub connect_timeout: Option<Duration>,
+    /// Retry a failed connect up to this many times.
+    pub retries: u32,
+    /// Size of the socket read buffer; 64 KiB if unset.
+    pub read_buffer: Option<usize>,
+    /// Keep the bottom row for a status bar (see `status_bar.rs`); the
+    /// session gets the rows above it.
+    pub status_bar: bool,
+    /// Ask the daemon for zstd-compressed output, for sockets forwarded
+    /// over slow links.
+    pub compress: bool,
+    /// Ask for CRC32-checked frames, for sockets tunnelled over transports
+    /// that can corrupt data (`--checksum`).
+    pub checksum: bool,
+    /// How the attach snapshot is replayed (`--replay`, `--replay-bytes`,
+    /// `--no-replay`).
+    pub replay: control::AttachRequest,
+    /// Write lifecycle events as JSON lines to this fd (`--events-fd`).
+    pub events_fd: Option<RawFd>,
+    /// Token from `pterm share` to attach to another user's session with
+    /// (`--token`); implies `read_only`.
+    pub share_token: Option<[u8; share::TOKEN_LEN]>,
+    /// Log every output frame against the daemon's `FRAME_MARK`s to this
+    /// file (`--debug-frames`).
+    pub debug_frames: Option<std::path::PathBuf>,
+}
+
+/// Lifecycle events for whoever launched the bridge (`--events-fd`), one
+/// JSON object per line with its name in `"event"`, so a frontend can react
+/// to them without parsing the terminal stream: `connected`,
+/// `scrollback-replayed`, `reconnected`, `resized`, `exited`, `detached`
+/// and `disconnected`.
+struct EventLog {
+    fd: Option<RawFd>,
+}
+
+impl EventLog {
+    fn emit(&self, event: &str, fields: serde_json::Value) {
+        let Some(fd) = self.fd else {
+            return;
+        };
+        // A reader that went away must not take the session view with it.
+        let _ = write_all_raw(fd, &event_line(event, fields));
+    }
+}
+
+/// `fields` with `"event": event` as one line of JSON.
+fn event_line(event: &str, mut fields: serde_json::Value) -> Vec<u8> {
+    if !fields.is_object() {
+        fields = serde_json::json!({});
+    }
+    fields["event"] = event.into();
+    let mut line = fields.to_string().into_bytes();
+    line.push(b'\n');
+    line
+}
+
+/// Delay before connect retry number `attempt` (0-based): 50ms doubling,
+/// capped at 1s.
+fn retry_backoff(attempt: u32) -> Duration {
+    Duration::from_millis(50u64.saturating_mul(1 << attempt.min(5)).min(1000))
+}
+
+/// `true` for connect errors caused by a daemon that is still starting up:
+/// the socket file is not there yet, or it is bound but not yet listening.
+fn is_transient_connect_error(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused | io::ErrorKind::WouldBlock
+    )
+}
+
+/// Connect to the daemon, retrying transient failures as allowed by
+/// `retries` and `timeout`. With neither set, a single attempt is made.
+fn connect_with_retry(
+    socket_path: &Path,
+    retries: u32,
+    timeout: Option<Duration>,
+) -> io::Result<std::os::unix::net::UnixStream> {
+    let deadline = timeout.map(|t| Instant::now() + t);
+    let mut attempt = 0;
+    loop {
+        let err = match std::os::unix::net::UnixStream::connect(socket_path) {
+            Ok(stream) => return Ok(stream),
+            Err(e) if is_transient_connect_error(&e) => e,
+            Err(e) => return Err(e),
+        };
+        let delay = retry_backoff(attempt);
+        let retries_left = attempt < retries;
+        let time_left = deadline.is_some_and(|d| Instant::now() + delay < d);
+        if !retries_left && !time_left {
+            return Err(io::Error::new(
+                err.kind(),
+                format!("cannot connect to {}: {}", socket_path.display(), err),
+            ));
+        }
+        std::thread::sleep(delay);
+        attempt += 1;
+    }
+}
+
+/// Output written to the terminal and not yet returned to the daemon's
+/// window with `CREDIT`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Credit {
+    /// Window the daemon granted; `None` for daemons without one, which are
+    /// sent no `CREDIT`.
+    window: Option<u64>,
+    unreturned: u64,
+}
+
+impl Credit {
+    fn new(window: Option<u64>) -> Self {
+        Self {
+            window,
+            unreturned: 0,
+        }
+    }
+
+    /// Note that `bytes` of output were written to the terminal. Returns the
+    /// bytes to return in a `CREDIT` once a quarter of the window is used,
+    /// so the daemon never waits on a full window while the terminal keeps
+    /// up.
+    fn passed_on(&mut self, bytes: u64) -> Option<u64> {
+        let window = self.window?;
+        self.unreturned += bytes;
+        if self.unreturned == 0 || self.unreturned < window / 4 {
+            return None;
+        }
+        Some(std::mem::take(&mut self.unreturned))
+    }
+}
+
+/// `HELLO` of the bridge: it always asks for `OUTPUT_SEQ`, so it can resume
+/// the output stream at `resume` after a dropped connection, for the exit
+/// details, to say which signal killed the command, and for an output
+/// window of [`CREDIT_WINDOW`]. With the `key` of an encrypted session it
+/// encrypts the connection.
+fn hello_request(
+    options: &Options,
+    key: Option<[u8; proto::crypto::KEY_LEN]>,
+    resume: Option<u64>,
+) -> control::HelloRequest {
+    control::HelloRequest {
+        compression: options.compress,
+        output_seq: true,
+        resume,
+        exit_details: true,
+        checksum: options.checksum,
+        encrypt: key,
+        window: Some(CREDIT_WINDOW),
+        share: options.share_token,
+        frame_marks: options.debug_frames.is_some(),
+    }
+}
+
+/// Let `note` write to the frame log, if there is one. A log that cannot
+/// be written to is given up on rather than ending the attach.
+fn note_frames(
+    frame_log: &mut Option<FrameLog<std::fs::File>>,
+    note: impl FnOnce(&mut FrameLog<std::fs::File>) -> io::Result<()>,
+) {
+    if let Some(log) = frame_log.as_mut() {
+        if let Err(e) = note(log) {
+            log::warn!("Stopped logging frames: {}", e);
+            *frame_log = None;
+        }
+    }
+}
+
+/// Connect again after the connection dropped, asking to resume the output
+/// stream at `position`. Returns the connection and what was agreed on it.
+fn reconnect(
+    socket_path: &Path,
+    options: &Options,
+    key: Option<[u8; proto::crypto::KEY_LEN]>,
+    position: u64,
+) -> io::Result<(UnixStream, control::Handshake)> {
+    let mut stream = connect_with_retry(socket_path, RECONNECT_RETRIES, None)?;
+    let handshake = control::handshake(&mut stream, hello_request(options, key, Some(position)))?;
+    stream.set_nonblocking(true)?;
+    Ok((UnixStream::from_std(stream), handshake))
+}
+
+/// Ask the daemon for the pty master fd on a dedicated connection.
+/// Stream bytes are ignored; only the `SCM_RIGHTS` payload matters.
+fn request_pty_input_fd(socket_path: &Path) -> io::Result<OwnedFd> {
+    use nix::sys::socket::{recvmsg, ControlMessageOwned, MsgFlags};
+
+    let mut stream = std::os::unix::net::UnixStream::connect(socket_path)?;
+    stream.set_read_timeout(Some(PTY_FD_TIMEOUT))?;
+    stream.write_all(&proto::encode(proto::client::PTY_INPUT_FD, &[]))?;
+
+    let mut buf = [0u8; 4096];
+    loop {
+        let mut cmsg_buf = nix::cmsg_space!([RawFd; 1]);
+        let mut iov = [io::IoSliceMut::new(&mut buf)];
+        let msg = recvmsg::<()>(
+            stream.as_raw_fd(),
+            &mut iov,
+            Some(&mut cmsg_buf),
+            MsgFlags::MSG_CMSG_CLOEXEC,
+        )
+        .map_err(io::Error::other)?;
+        for cmsg in msg.cmsgs().map_err(io::Error::other)? {
+            if let ControlMessageOwned::ScmRights(fds) = cmsg {
+                if let Some(&fd) = fds.first() {
+                    return Ok(unsafe { OwnedFd::from_raw_fd(fd) });
+                }
+            }
+        }
+        if msg.bytes == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "daemon closed the connection without passing a pty fd",
+            ));
+        }
+    }
+}
+
+/// `frame` with a `COMPRESSED_OUTPUT` / `COMPRESSED_STATE_SYNC` payload
+/// unpacked into the `OUTPUT` / `STATE_SYNC` it stands for; other frames as
+/// they are.
+fn decompress(frame: proto::Frame) -> io::Result<proto::Frame> {
+    let msg_type = match frame.msg_type {
+        proto::server::COMPRESSED_OUTPUT => proto::server::OUTPUT,
+        proto::server::COMPRESSED_STATE_SYNC => proto::server::STATE_SYNC,
+        _ => return Ok(frame),
+    };
+    Ok(proto::Frame {
+        msg_type,
+        payload: zstd::stream::decode_all(&frame.payload[..])?,
+    })
+}
+
+/// OSC 7 (`file://<host><path>`) announcing the session's working directory
+/// to the hosting terminal, so it can open new windows there and Neovim can
+/// pick it up through `TermRequest`.
+fn cwd_report(info: &[u8]) -> Option<Vec<u8>> {
+    let info: serde_json::Value = serde_json::from_slice(info).ok()?;
+    let cwd = info["cwd"].as_str()?;
+    let host = clients::hostname().unwrap_or_default();
+    let mut path = String::new();
+    for b in cwd.bytes() {
+        if b.is_ascii_alphanumeric() || b"/-._~".contains(&b) {
+            path.push(b as char);
+        } else {
+            path.push_str(&format!("%{:02X}", b));
+        }
+    }
+    Some(format!("\x1b]7;file://{}{}\x1b\\", host, path).into_bytes())
+}
+
+/// Markers the hosting terminal puts around a paste once the application
+/// enabled bracketed paste (mode 2004).
+const PASTE_START: &[u8] = b"\x1b[200~";
+const PASTE_END: &[u8] = b"\x1b[201~";
+
+/// A piece of keyboard input.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Typed {
+    Keys(Vec<u8>),
+    /// Text of a bracketed paste, markers removed.
+    Paste(Vec<u8>),
+}
+
+/// Splits what is read from stdin into keystrokes and bracketed pastes, so
+/// pastes go to the daemon as `PASTE`. Keystrokes are passed on at once,
+/// even a lone `ESC`; a paste is held back until its end marker arrives.
+#[derive(Debug, Default)]
+struct PasteSplitter {
+    /// Text of a paste whose end marker has not arrived yet.
+    paste: Option<Vec<u8>>,
+    /// Bytes at the end of a read inside a paste that may begin the end
+    /// marker.
+    held: Vec<u8>,
+}
+
+impl PasteSplitter {
+    fn split(&mut self, data: &[u8]) -> Vec<Typed> {
+        let mut input = std::mem::take(&mut self.held);
+        input.extend_from_slice(data);
+        let mut typed = Vec::new();
+        let mut rest = &input[..];
+        while !rest.is_empty() {
+            let Some(text) = self.paste.as_mut() else {
+                let Some(at) = find(rest, PASTE_START) else {
+                    typed.push(Typed::Keys(rest.to_vec()));
+                    break;
+                };
+                if at > 0 {
+                    typed.push(Typed::Keys(rest[..at].to_vec()));
+                }
+                self.paste = Some(Vec::new());
+                rest = &rest[at + PASTE_START.len()..];
+                continue;
+            };
+            match find(rest, PASTE_END) {
+                Some(at) => {
+                    text.extend_from_slice(&rest[..at]);
+                    typed.extend(self.paste.take().map(Typed::Paste));
+                    rest = &rest[at + PASTE_END.len()..];
+                }
+                None => {
+                    let keep = (1..PASTE_END.len())
+                        .rev()
+                        .find(|&n| rest.ends_with(&PASTE_END[..n]))
+                        .unwrap_or(0);
+                    text.extend_from_slice(&rest[..rest.len() - keep]);
+                    self.held = rest[rest.len() - keep..].to_vec();
+                    break;
+                }
+            }
+        }
+        typed
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+/// Parse a `CONFIRM` request into its id and the prompt to show for it.
+fn confirm_prompt(payload: &[u8]) -> Option<(u64, Vec<u8>)> {
+    let ask: serde_json::Value = serde_json::from_slice(payload).ok()?;
+    let id = ask["id"].as_u64()?;
+    let line = ask["line"].as_str().unwrap_or_default();
+    let pattern = ask["pattern"].as_str().unwrap_or_default();
+    let prompt = format!(
+        "\r\n\x1b[1;7m pterm guard \x1b[m {:?} matches {:?}. Run it? [y/N] ",
+        line.trim(),
+        pattern
+    );
+    Some((id, prompt.into_bytes()))
+}
+
+/// Who is typing, as an iTerm2-style user variable (`pterm_typing`, value
+/// base64-encoded) that terminals such as WezTerm can show in their status
+/// bar and the Neovim plugin turns into `b:pterm_typing`. An empty label
+/// clears it.
+fn typing_report(label: &str) -> Vec<u8> {
+    format!(
+        "\x1b]1337;SetUserVar=pterm_typing={}\x07",
+        base64(label.as_bytes())
+    )
+    .into_bytes()
+}
+
+/// Standard base64 with padding, for OSC payloads.
+pub(crate) fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::new();
+    for chunk in data.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+/// Handle a daemon message the bridge does not act on. Only control types
+/// unknown to this build are worth a note (a newer daemon); they are never
+/// written to the terminal.
+fn handle_unknown_frame(msg_type: u8) {
+    if proto::is_control(msg_type) && proto::ServerMessage::from_u8(msg_type).is_none() {
+        log::debug!("Ignoring unsupported control message 0x{:02x}", msg_type);
+    }
+}
+
 /// Run the bridge, connecting stdin/stdout to the daemon session at `socket_path`.
 /// Returns the child process exit code (from the daemon's EXIT message).
 ///
-/// `initial_cols` / `initial_rows` override the terminal size sent in the
+/// `options.cols` / `options.rows` override the terminal size sent in the
 /// initial RESIZE message. When `None`, the size is read from `TIOCGWINSZ`
 /// (stdout) with a final fallback to the default terminal size.
-pub fn run(
-    socket_path: &Path,
-    initial_cols: Option<u16>,
-    initial_rows: Option<u16>,
-) -> io::Result<i32> {
+///
+/// With `options.direct_input`, keyboard input is written straight to the
+/// pty master fd obtained from the daemon instead of being framed as
+/// `INPUT`, removing the daemon copy from the input path. Output still flows
+/// through the daemon.
+pub fn run(socket_path: &Path, options: &Options) -> io::Result<i32> {
     let stdin_fd = libc::STDIN_FILENO;
     let stdout_fd = libc::STDOUT_FILENO;
 
@@ -158,22 +620,61 @@ pub fn run(
     };
 
     // Set up self-pipe for SIGWINCH
-    let (wake_read, wake_write) = make_pipe()?;
-    unsafe {
-        WAKE_WRITE_FD = wake_write.as_raw_fd();
-    }
+    let (wake_read, _wake_write) = sigwinch_pipe()?;
 
-    // Install SIGWINCH handler
-    unsafe {
-        let mut sa: libc::sigaction = std::mem::zeroed();
-        sa.sa_sigaction = sigwinch_handler as usize;
-        sa.sa_flags = libc::SA_RESTART;
-        libc::sigemptyset(&mut sa.sa_mask);
-        libc::sigaction(libc::SIGWINCH, &sa, std::ptr::null_mut());
+    // Connect to daemon socket and agree on the protocol before anything
+    // else flows.
+    let mut std_stream = connect_with_retry(socket_path, options.retries, options.connect_timeout)?;
+    let key = session_key::for_socket(socket_path)?;
+    let handshake = control::handshake(&mut std_stream, hello_request(options, key, None))?;
+    let events = EventLog {
+        fd: options.events_fd,
+    };
+    log::debug!(
+        "Speaking protocol {} with the daemon{}",
+        handshake.version,
+        if handshake.compression {
+            ", zstd output"
+        } else {
+            ""
+        }
+    );
+    if handshake.wire.framing == proto::Framing::Crc32 {
+        log::debug!("Frames are CRC32-checked");
+    }
+    if handshake.wire.cipher.is_some() {
+        log::debug!("Frames are encrypted");
+    }
+    if !handshake.output_seq {
+        log::debug!("The daemon sends no stream positions; a dropped connection ends the attach");
+    }
+    let mut frame_log = match &options.debug_frames {
+        Some(path) => Some(FrameLog::create(path)?),
+        None => None,
+    };
+    if frame_log.is_some() && !handshake.frame_marks {
+        log::warn!("The daemon does not mark frames; every frame is logged unmarked");
+    }
+    let mut credit = Credit::new(handshake.window);
+    let mut wire = handshake.wire;
+    // Only the first attach: a reconnect resumes or needs the full screen.
+    if let Some(frame) = options.replay.frame() {
+        std_stream.write_all(&wire.frames(&frame))?;
     }
 
-    // Connect to daemon socket
-    let std_stream = std::os::unix::net::UnixStream::connect(socket_path)?;
+    // The fd would be handed over on a connection of its own, which an
+    // encrypted session refuses.
+    let pty_input = if options.direct_input && !options.read_only && wire.cipher.is_none() {
+        match request_pty_input_fd(socket_path) {
+            Ok(fd) => Some(fd),
+            Err(e) => {
+                log::warn!("Direct input unavailable, using INPUT frames: {}", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
     std_stream.set_nonblocking(true)?;
     let mut socket = UnixStream::from_std(std_stream);
 
@@ -198,35 +699,101 @@ pub fn run(
     // Send initial RESIZE to sync terminal size.
     // CLI-supplied values take priority, then TIOCGWINSZ, then the default
     // terminal size.
-    let (cols, rows) = {
-        let winsize = get_winsize(stdout_fd).ok();
-        let c = initial_cols
+    let (mut cols, mut rows) = {
+        let winsize = terminal_size(stdout_fd);
+        let c = options
+            .cols
             .or(winsize.map(|(c, _)| c))
             .unwrap_or(DEFAULT_TERMINAL_COLS);
-        let r = initial_rows
+        let r = options
+            .rows
             .or(winsize.map(|(_, r)| r))
             .unwrap_or(DEFAULT_TERMINAL_ROWS);
-        (c, r)
+        (clamp_dimension(c), clamp_dimension(r))
     };
-    {
-        let resize_payload = proto::encode_resize(cols, rows);
-        let msg = proto::encode(proto::client::RESIZE, &resize_payload);
-        socket.write_all(&msg)?;
-    }
+    let session_rows = |rows| {
+        if options.status_bar {
+            status_bar::session_rows(rows)
+        } else {
+            rows
+        }
+    };
+    let announce = |socket: &mut UnixStream, wire: &mut control::Wire, cols, rows| {
+        let identity = ClientIdentity::of_this_process(io::stdin(), cols, session_rows(rows));
+        socket.write_all(&wire.frames(&identity.frame()))?;
+        if options.read_only {
+            socket.write_all(&wire.encode(proto::client::READ_ONLY, &[]))
+        } else {
+            let resize_payload = proto::encode_resize(cols, session_rows(rows));
+            socket.write_all(&wire.encode(proto::client::RESIZE, &resize_payload))
+        }
+    };
+    announce(&mut socket, &mut wire, cols, rows)?;
+    events.emit(
+        "connected",
+        serde_json::json!({
+            "socket": socket_path,
+            "protocol": handshake.version,
+            "cols": cols,
+            "rows": session_rows(rows),
+            "read_only": options.read_only,
+        }),
+    );
 
-    let mut events = Events::with_capacity(16);
+    let mut poll_events = Events::with_capacity(16);
     let mut stdin_buf = [0u8; 8192];
-    let mut sock_buf = [0u8; 65536];
-    let mut recv_buf: Vec<u8> = Vec::new();
+    let mut sock_buf = vec![0u8; options.read_buffer.unwrap_or(65536)];
+    let mut decoder = proto::FrameDecoder::new();
+    decoder.set_framing(wire.framing);
     let mut exit_code: i32 = 0;
+    // Why the session ended for us, told after the terminal is restored.
+    let mut farewell: Option<String> = None;
+    // Id of a guarded command line awaiting the user's answer.
+    let mut pending_confirm: Option<u64> = None;
+    // Bracketed pastes in the keyboard input, and the ones sent as PASTE
+    // whose REPLY has not arrived.
+    let mut pastes = PasteSplitter::default();
+    let mut pending_pastes: VecDeque<Vec<u8>> = VecDeque::new();
+    // When another client's typing stops being shown.
+    let mut typing_until: Option<Instant> = None;
+    // Contents of the status bar, if enabled, and when to refresh them.
+    let mut bar_text = String::new();
+    let mut bar_due = Instant::now();
+    // Output stream position reached, while the daemon reports it; the
+    // bridge resumes from there if the connection drops.
+    let mut stream_position: Option<u64> = None;
+    // Whether the snapshot of the current connection has arrived yet.
+    let mut replayed = false;
     'main: loop {
-        match poll.poll(&mut events, None) {
+        let wake_at = match typing_until {
+            Some(until) if options.status_bar => Some(until.min(bar_due)),
+            Some(until) => Some(until),
+            None => options.status_bar.then_some(bar_due),
+        };
+        let timeout = wake_at.map(|at| at.saturating_duration_since(Instant::now()));
+        match poll.poll(&mut poll_events, timeout) {
             Ok(()) => {}
             Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
             Err(e) => return Err(e),
         }
+        if typing_until.is_some_and(|until| until <= Instant::now()) {
+            typing_until = None;
+            if write_all_raw(stdout_fd, &typing_report("")).is_err() {
+                break 'main;
+            }
+        }
+        if options.status_bar && bar_due <= Instant::now() {
+            bar_due = Instant::now() + STATUS_BAR_INTERVAL;
+            let text = status_bar::text(socket_path, cols, rows);
+            if text != bar_text {
+                bar_text = text;
+                if write_all_raw(stdout_fd, &status_bar::paint(&bar_text, cols, rows)).is_err() {
+                    break 'main;
+                }
+            }
+        }
 
-        for event in events.iter() {
+        for event in poll_events.iter() {
             match event.token() {
                 TOKEN_STDIN => {
                     // Read from stdin, send as INPUT to daemon
@@ -234,13 +801,52 @@ pub fn run(
                         match nix::unistd::read(stdin_fd, &mut stdin_buf) {
                             Ok(0) => {
                                 // stdin EOF: detach and exit
-                                let msg = proto::encode(proto::client::DETACH, &[]);
+                                let msg = wire.encode(proto::client::DETACH, &[]);
                                 let _ = socket.write_all(&msg);
+                                events.emit(
+                                    "detached",
+                                    serde_json::json!({ "reason": "input closed" }),
+                                );
                                 break 'main;
                             }
+                            Ok(_) if options.read_only => {}
+                            Ok(_) if pending_confirm.is_some() => {
+                                // The first key answers the prompt; the rest
+                                // of the read is dropped. REDRAW repaints
+                                // over the prompt.
+                                let accept = matches!(stdin_buf[0], b'y' | b'Y');
+                                let answer = serde_json::json!({
+                                    "id": pending_confirm.take(),
+                                    "accept": accept,
+                                });
+                                let reply = wire.encode(
+                                    proto::client::CONFIRM_REPLY,
+                                    answer.to_string().as_bytes(),
+                                );
+                                let redraw = wire.encode(proto::client::REDRAW, &[]);
+                                if socket.write_all(&reply).is_err()
+                                    || socket.write_all(&redraw).is_err()
+                                {
+                                    break 'main;
+                                }
+                            }
                             Ok(n) => {
-                                let msg = proto::encode(proto::client::INPUT, &stdin_buf[..n]);
-                                if socket.write_all(&msg).is_err() {
+                                let sent = match &pty_input {
+                                    Some(fd) => write_all_raw(fd.as_raw_fd(), &stdin_buf[..n]),
+                                    None => pastes.split(&stdin_buf[..n]).into_iter().try_for_each(
+                                        |typed| match typed {
+                                            Typed::Keys(keys) => socket.write_all(
+                                                &wire.encode(proto::client::INPUT, &keys),
+                                            ),
+                                            Typed::Paste(text) => {
+                                                let msg = wire.encode(proto::client::PASTE, &text);
+                                                pending_pastes.push_back(text);
+                                                socket.write_all(&msg)
+                                            }
+                                        },
+                                    ),
+                                };
+                                if sent.is_err() {
                                     break 'main;
                                 }
                             }
@@ -258,21 +864,25 @@ pub fn run(
                 }
 
                 TOKEN_SOCKET => {
-                    // Read from socket, parse protocol frames
+                    // Read from socket, parse protocol frames. Frames read
+                    // before the connection ended are still processed.
+                    let mut connection_lost = false;
                     loop {
                         match socket.read(&mut sock_buf) {
                             Ok(0) => {
                                 // Socket EOF: daemon closed
-                                break 'main;
+                                connection_lost = true;
+                                break;
                             }
                             Ok(n) => {
-                                recv_buf.extend_from_slice(&sock_buf[..n]);
+                                decoder.feed(&sock_buf[..n]);
                             }
                             Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
                                 break;
                             }
                             Err(_) => {
-                                break 'main;
+                                connection_lost = true;
+                                break;
                             }
                         }
                     }
@@ -280,57 +890,240 @@ pub fn run(
                     // Process complete frames, batching output payloads into
                     // a single write to avoid incremental rendering.
                     let mut output_batch: Vec<u8> = Vec::new();
+                    let mut output_bytes = 0;
                     let mut state_sync_cleanup_queued = false;
-                    for frame in proto::decode_frames(&mut recv_buf) {
+                    while let Some(frame) = decoder.next_frame() {
+                        let frame = match wire.open(frame) {
+                            Ok(frame) => frame,
+                            Err(e) => {
+                                log::warn!("Dropping the connection: {}", e);
+                                farewell = Some(format!("connection not trusted: {}", e));
+                                break 'main;
+                            }
+                        };
+                        let frame = match decompress(frame) {
+                            Ok(frame) => frame,
+                            Err(e) => {
+                                log::warn!("Dropping undecodable compressed frame: {}", e);
+                                continue;
+                            }
+                        };
+                        note_frames(&mut frame_log, |log| log.frame(&frame));
                         match frame.msg_type {
                             proto::server::OUTPUT => {
+                                if let Some(position) = stream_position.as_mut() {
+                                    *position += frame.payload.len() as u64;
+                                }
+                                output_bytes += frame.payload.len() as u64;
                                 output_batch.extend_from_slice(&frame.payload);
                             }
+                            // Checked against the frame after it above.
+                            proto::server::FRAME_MARK => {}
+                            proto::server::OUTPUT_SEQ => {
+                                let mark: serde_json::Value =
+                                    serde_json::from_slice(&frame.payload).unwrap_or_default();
+                                stream_position = mark["seq"].as_u64();
+                            }
                             proto::server::STATE_SYNC => {
+                                // The position follows in OUTPUT_SEQ.
+                                stream_position = None;
+                                if !std::mem::replace(&mut replayed, true) {
+                                    events.emit(
+                                        "scrollback-replayed",
+                                        serde_json::json!({
+                                            "bytes": frame.payload.len(),
+                                            "scrollback_lines": options.replay.scrollback_lines,
+                                        }),
+                                    );
+                                }
                                 if !state_sync_cleanup_queued {
                                     output_batch
                                         .extend_from_slice(STATE_SYNC_KEYBOARD_CLEANUP_SEQUENCES);
                                     state_sync_cleanup_queued = true;
                                 }
+                                output_bytes += frame.payload.len() as u64;
                                 output_batch.extend_from_slice(&frame.payload);
                             }
+                            proto::server::SESSION_INFO => {
+                                if let Some(report) = cwd_report(&frame.payload) {
+                                    output_batch.extend_from_slice(&report);
+                                }
+                            }
+                            proto::server::CONFIRM => {
+                                if let Some((id, prompt)) = confirm_prompt(&frame.payload) {
+                                    output_batch.extend_from_slice(&prompt);
+                                    pending_confirm = Some(id);
+                                }
+                            }
+                            proto::server::PRESENCE => {
+                                let presence: serde_json::Value =
+                                    serde_json::from_slice(&frame.payload).unwrap_or_default();
+                                if let Some(label) = presence["label"].as_str() {
+                                    output_batch.extend_from_slice(&typing_report(label));
+                                    typing_until = Some(Instant::now() + TYPING_SHOWN_FOR);
+                                }
+                            }
+                            proto::server::REPLY => {
+                                // A refused paste (a guard matched it, or the
+                                // daemon predates PASTE) is typed instead.
+                                let reply: serde_json::Value =
+                                    serde_json::from_slice(&frame.payload).unwrap_or_default();
+                                let refused = pending_pastes
+                                    .pop_front()
+                                    .filter(|_| reply["error"].is_string());
+                                if let Some(text) = refused {
+                                    log::info!("Paste refused, typing it: {}", reply["error"]);
+                                    let typed = [PASTE_START, &text, PASTE_END].concat();
+                                    let msg = wire.encode(proto::client::INPUT, &typed);
+                                    if socket.write_all(&msg).is_err() {
+                                        break 'main;
+                                    }
+                                }
+                            }
+                            proto::server::PING => {
+                                let msg = wire.encode(proto::client::PONG, &[]);
+                                if socket.write_all(&msg).is_err() {
+                                    break 'main;
+                                }
+                            }
+                            proto::server::DETACHED => {
+                                let reason = String::from_utf8_lossy(&frame.payload);
+                                events.emit("detached", serde_json::json!({ "reason": reason }));
+                                if !reason.is_empty() {
+                                    farewell = Some(format!("detached: {}", reason));
+                                }
+                                if !output_batch.is_empty() {
+                                    let _ = write_all_raw(stdout_fd, &output_batch);
+                                }
+                                break 'main;
+                            }
                             proto::server::EXIT => {
                                 if let Ok(code) = proto::parse_exit(&frame.payload) {
                                     exit_code = code;
                                 }
+                                let details = proto::exit_details(&frame.payload);
+                                let exit = serde_json::from_slice::<ExitInfo>(details).ok();
+                                events.emit(
+                                    "exited",
+                                    serde_json::json!({ "code": exit_code, "exit": exit }),
+                                );
+                                farewell = exit.filter(|exit| exit.signal.is_some()).map(|exit| {
+                                    format!("the command was killed by {}", exit.reason())
+                                });
                                 // Flush any batched output before exiting
                                 if !output_batch.is_empty() {
                                     let _ = write_all_raw(stdout_fd, &output_batch);
                                 }
                                 break 'main;
                             }
-                            _ => {}
+                            msg_type => handle_unknown_frame(msg_type),
                         }
                     }
 
+                    // A corrupted frame ends the connection like a drop
+                    // does; a resumed one picks up after the last good
+                    // frame.
+                    let corrupted = decoder.checksum_error().cloned();
+                    if let Some(error) = &corrupted {
+                        log::warn!("Dropping the connection: {}", error);
+                        connection_lost = true;
+                    }
+
+                    if options.status_bar
+                        && (state_sync_cleanup_queued || status_bar::needs_repaint(&output_batch))
+                    {
+                        output_batch.extend_from_slice(&status_bar::paint(&bar_text, cols, rows));
+                    }
                     if !output_batch.is_empty() && write_all_raw(stdout_fd, &output_batch).is_err()
                     {
                         break 'main;
                     }
-                }
+                    // Written to the terminal: the daemon may send more.
+                    if let Some(bytes) = credit.passed_on(output_bytes) {
+                        let msg = wire.encode(proto::client::CREDIT, &proto::encode_credit(bytes));
+                        if !connection_lost && socket.write_all(&msg).is_err() {
+                            connection_lost = true;
+                        }
+                    }
 
-                TOKEN_WAKE => {
-                    // Drain wake pipe
-                    let mut drain = [0u8; 64];
-                    loop {
-                        match nix::unistd::read(wake_read_fd, &mut drain) {
-                            Ok(0) | Err(_) => break,
-                            Ok(_) => {}
+                    if connection_lost {
+                        // The daemon dropped the connection without EXIT
+                        // or DETACHED, e.g. as a zombie after the machine
+                        // slept: reconnect and pick up where it ended.
+                        let Some(position) = stream_position.take() else {
+                            if let Some(error) = corrupted {
+                                farewell = Some(format!("connection corrupted: {}", error));
+                            }
+                            events.emit("disconnected", serde_json::json!({}));
+                            break 'main;
+                        };
+                        // A deleted session has no socket left to try.
+                        if !socket_path.exists() {
+                            events.emit("disconnected", serde_json::json!({}));
+                            break 'main;
+                        }
+                        let resumed = reconnect(socket_path, options, key, position).and_then(
+                            |(mut resumed, mut handshake)| {
+                                announce(&mut resumed, &mut handshake.wire, cols, rows)?;
+                                Ok((resumed, handshake))
+                            },
+                        );
+                        match resumed {
+                            Ok((resumed, handshake)) => {
+                                poll.registry().deregister(&mut socket)?;
+                                socket = resumed;
+                                wire = handshake.wire;
+                                credit = Credit::new(handshake.window);
+                                note_frames(&mut frame_log, |log| log.reconnected());
+                                poll.registry().register(
+                                    &mut socket,
+                                    TOKEN_SOCKET,
+                                    Interest::READABLE,
+                                )?;
+                                // Until the daemon marks the position again,
+                                // a second drop ends the bridge.
+                                decoder = proto::FrameDecoder::new();
+                                decoder.set_framing(wire.framing);
+                                replayed = false;
+                                pending_pastes.clear();
+                                log::info!("Reconnected, resuming output at {}", position);
+                                events.emit(
+                                    "reconnected",
+                                    serde_json::json!({ "position": position }),
+                                );
+                            }
+                            Err(e) => {
+                                log::warn!("Reconnect failed: {}", e);
+                                events.emit(
+                                    "disconnected",
+                                    serde_json::json!({ "error": e.to_string() }),
+                                );
+                                break 'main;
+                            }
                         }
                     }
+                }
 
-                    // Handle SIGWINCH
-                    if SIGWINCH_RECEIVED.swap(false, Ordering::SeqCst) {
-                        if let Ok((cols, rows)) = get_winsize(stdout_fd) {
-                            let resize_payload = proto::encode_resize(cols, rows);
-                            let msg = proto::encode(proto::client::RESIZE, &resize_payload);
+                TOKEN_WAKE => {
+                    let resized = take_sigwinch(wake_read_fd);
+                    if let Some(size) = resized.then(|| terminal_size(stdout_fd)).flatten() {
+                        (cols, rows) = size;
+                        if !options.read_only {
+                            let resize_payload = proto::encode_resize(cols, session_rows(rows));
+                            let msg = wire.encode(proto::client::RESIZE, &resize_payload);
                             let _ = socket.write_all(&msg);
                         }
+                        events.emit(
+                            "resized",
+                            serde_json::json!({ "cols": cols, "rows": session_rows(rows) }),
+                        );
+                        if options.status_bar {
+                            bar_text = status_bar::text(socket_path, cols, rows);
+                            let bar = status_bar::paint(&bar_text, cols, rows);
+                            if write_all_raw(stdout_fd, &bar).is_err() {
+                                break 'main;
+                            }
+                        }
                     }
                 }
 
@@ -340,16 +1133,28 @@ pub fn run(
     }
 
     // Send DETACH before exiting
-    let msg = proto::encode(proto::client::DETACH, &[]);
+    let msg = wire.encode(proto::client::DETACH, &[]);
     let _ = socket.write_all(&msg);
+    if options.status_bar {
+        let _ = write_all_raw(stdout_fd, &status_bar::clear(rows));
+    }
     let _ = write_all_raw(stdout_fd, DETACH_CLEANUP_SEQUENCES);
+    if let Some(farewell) = farewell {
+        let note = format!("[pterm: {}]\r\n", farewell);
+        let _ = write_all_raw(stdout_fd, note.as_bytes());
+    }
 
     Ok(exit_code)
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{DETACH_CLEANUP_SEQUENCES, STATE_SYNC_KEYBOARD_CLEANUP_SEQUENCES};
+    use super::{
+        confirm_prompt, connect_with_retry, cwd_report, event_line, retry_backoff, typing_report,
+        Credit, PasteSplitter, Typed, DETACH_CLEANUP_SEQUENCES,
+        STATE_SYNC_KEYBOARD_CLEANUP_SEQUENCES,
+    };
+    use std::time::{Duration, Instant};
 
     #[test]
     fn detach_cleanup_resets_keyboard_protocols() {
@@ -361,9 +1166,111 @@ mod tests {
         assert!(cleanup.contains("\x1b[=0u"));
     }
 
+    #[test]
+    fn credit_is_returned_in_quarters_of_the_window() {
+        let mut credit = Credit::new(Some(400));
+        assert_eq!(credit.passed_on(0), None);
+        assert_eq!(credit.passed_on(60), None);
+        assert_eq!(credit.passed_on(40), Some(100));
+        assert_eq!(credit.passed_on(99), None);
+        assert_eq!(credit.passed_on(1000), Some(1099));
+
+        let mut none = Credit::new(None);
+        assert_eq!(none.passed_on(1 << 30), None);
+    }
+
+    #[test]
+    fn cwd_report_is_percent_encoded_osc7() {
+        let report = cwd_report(br#"{"cwd": "/home/me/my dir", "term": null}"#).unwrap();
+        let report = String::from_utf8(report).unwrap();
+        assert!(report.starts_with("\x1b]7;file://"));
+        assert!(report.ends_with("/home/me/my%20dir\x1b\\"));
+        assert_eq!(cwd_report(br#"{"cwd": null}"#), None);
+    }
+
+    #[test]
+    fn bracketed_pastes_are_split_from_keystrokes() {
+        let keys = |k: &[u8]| Typed::Keys(k.to_vec());
+        let paste = |p: &[u8]| Typed::Paste(p.to_vec());
+        let mut splitter = PasteSplitter::default();
+        assert_eq!(splitter.split(b"\x1b"), [keys(b"\x1b")]);
+        assert_eq!(
+            splitter.split(b"ls\x1b[200~echo a\recho b\x1b[201~\r"),
+            [keys(b"ls"), paste(b"echo a\recho b"), keys(b"\r")]
+        );
+        // A paste over several reads, its end marker split between them.
+        assert_eq!(splitter.split(b"x\x1b[200~one "), [keys(b"x")]);
+        assert_eq!(splitter.split(b"two\x1b[2"), []);
+        assert_eq!(splitter.split(b"01~"), [paste(b"one two")]);
+        assert_eq!(splitter.split(b"\x1b[200~\x1b[201~"), [paste(b"")]);
+    }
+
+    #[test]
+    fn confirm_prompt_names_the_line_and_pattern() {
+        let (id, prompt) =
+            confirm_prompt(br#"{"id": 3, "line": "$ rm -rf / ", "pattern": "rm -rf"}"#).unwrap();
+        assert_eq!(id, 3);
+        let prompt = String::from_utf8(prompt).unwrap();
+        assert!(prompt.contains(r#""$ rm -rf /" matches "rm -rf". Run it? [y/N]"#));
+        assert_eq!(confirm_prompt(br#"{"line": "x"}"#), None);
+    }
+
+    #[test]
+    fn typing_report_is_a_base64_user_var() {
+        let var = |label| String::from_utf8(typing_report(label)).unwrap();
+        assert_eq!(
+            var("alice"),
+            "\x1b]1337;SetUserVar=pterm_typing=YWxpY2U=\x07"
+        );
+        assert_eq!(var("bob"), "\x1b]1337;SetUserVar=pterm_typing=Ym9i\x07");
+        assert_eq!(var("ed"), "\x1b]1337;SetUserVar=pterm_typing=ZWQ=\x07");
+        assert_eq!(var(""), "\x1b]1337;SetUserVar=pterm_typing=\x07");
+    }
+
+    #[test]
+    fn events_are_json_lines_named_by_event() {
+        let line = event_line("exited", serde_json::json!({ "code": 2 }));
+        assert_eq!(line.last(), Some(&b'\n'));
+        let event: serde_json::Value = serde_json::from_slice(&line).unwrap();
+        assert_eq!(event, serde_json::json!({ "event": "exited", "code": 2 }));
+        let line = event_line("disconnected", serde_json::Value::Null);
+        assert_eq!(line, b"{\"event\":\"disconnected\"}\n");
+    }
+
     #[test]
     fn state_sync_cleanup_resets_kitty_keyboard_state() {
         let cleanup = std::str::from_utf8(STATE_SYNC_KEYBOARD_CLEANUP_SEQUENCES).unwrap();
         assert_eq!(cleanup, "\x1b[<u\x1b[=0u");
     }
+
+    #[test]
+    fn retry_backoff_doubles_up_to_cap() {
+        assert_eq!(retry_backoff(0), Duration::from_millis(50));
+        assert_eq!(retry_backoff(1), Duration::from_millis(100));
+        assert_eq!(retry_backoff(4), Duration::from_millis(800));
+        assert_eq!(retry_backoff(5), Duration::from_millis(1000));
+        assert_eq!(retry_backoff(40), Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn connect_retries_until_socket_is_bound() {
+        let dir = std::env::temp_dir().join(format!("pterm-bridge-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let sock = dir.join("socket");
+        let _ = std::fs::remove_file(&sock);
+
+        assert!(connect_with_retry(&sock, 0, None).is_err());
+
+        let bind_path = sock.clone();
+        let binder = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(120));
+            std::os::unix::net::UnixListener::bind(bind_path).unwrap()
+        });
+        let start = Instant::now();
+        let stream = connect_with_retry(&sock, 0, Some(Duration::from_secs(5)));
+        let _listener = binder.join().unwrap();
+        assert!(stream.is_ok());
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
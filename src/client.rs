@@ -0,0 +1,787 @@
+//! `pterm client`: a standalone terminal frontend for users who want pterm
+//! without Neovim or tmux.
+//!
+//! Unlike the bridge, which relays the session's output to the hosting
+//! terminal, the client feeds it to its own `vt100` parser and draws the
+//! screen itself, as the difference to what it drew last. Whatever the
+//! session's programs send, the hosting terminal only ever sees cursor
+//! movement, text and attributes. The bottom row is the client's status
+//! line.
+//!
+//! `Ctrl-]` is the prefix key: `d` detaches, `[` enters copy mode, `/`
+//! enters copy mode and searches, and a second `Ctrl-]` is sent to the
+//! session. Copy mode browses the session's scrollback and screen as plain
+//! text, fetched from the daemon with `CAPTURE`, and yanks whole lines to
+//! the paste buffer (`pterm paste-buffer`) and, through OSC 52, to the
+//! hosting terminal's clipboard. `[` and `]` jump between the prompts the
+//! shell marked with OSC 133.
+//!
+//! `pterm attach --view` runs copy mode on its own as a pager: it shows the
+//! session's scrollback without connecting input at all, and `a` leaves it
+//! for a live attach.
+//!
+//! The hosting terminal is driven through crossterm: raw mode, the
+//! alternate screen, its size, and the cursor movement and attributes of
+//! the status line and copy mode. Input is read from stdin as bytes
+//! rather than through crossterm's event reader, because everything but
+//! the prefix key goes to the session exactly as typed: decoding it into
+//! key events would lose what has no event (mouse reports in the
+//! session's protocol, modified function keys, pastes), and the reader
+//! cannot wait on the session's socket in the same `poll`.
+
+use crate::bridge;
+use crate::clients::ClientIdentity;
+use crate::constants::{DEFAULT_TERMINAL_COLS, DEFAULT_TERMINAL_ROWS};
+use crate::control;
+use crate::paste::{self, Capture};
+use crate::paths::session_socket_path;
+use crossterm::cursor::{Hide, MoveTo};
+use crossterm::queue;
+use crossterm::style::{Attribute, Print, SetAttribute};
+use crossterm::terminal::{
+    self, BeginSynchronizedUpdate, Clear, ClearType, EndSynchronizedUpdate, EnterAlternateScreen,
+    LeaveAlternateScreen,
+};
+use nix::libc;
+use pterm_proto as proto;
+use std::io::{self, Read, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::net::UnixStream;
+
+/// The prefix key, `Ctrl-]`.
+const PREFIX: u8 = 0x1d;
+
+/// The hosting terminal in raw mode on the alternate screen, restored
+/// when dropped.
+struct Screen;
+
+impl Screen {
+    fn enter() -> io::Result<Self> {
+        terminal::enable_raw_mode()?;
+        let screen = Self;
+        let mut out = Vec::new();
+        queue!(
+            out,
+            EnterAlternateScreen,
+            MoveTo(0, 0),
+            Clear(ClearType::All)
+        )?;
+        bridge::write_all_raw(libc::STDOUT_FILENO, &out)?;
+        Ok(screen)
+    }
+}
+
+impl Drop for Screen {
+    fn drop(&mut self) {
+        let mut out = Vec::new();
+        let _ = queue!(out, SetAttribute(Attribute::Reset), LeaveAlternateScreen);
+        let _ = bridge::write_all_raw(libc::STDOUT_FILENO, &out);
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+/// Columns and rows of the hosting terminal.
+fn terminal_size() -> (u16, u16) {
+    terminal::size()
+        .ok()
+        .filter(|&(cols, rows)| cols > 0 && rows > 0)
+        .unwrap_or((DEFAULT_TERMINAL_COLS, DEFAULT_TERMINAL_ROWS))
+}
+
+/// A key from the hosting terminal, as far as copy mode cares.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Key {
+    Byte(u8),
+    Up,
+    Down,
+    PageUp,
+    PageDown,
+    Escape,
+    /// Any other escape sequence.
+    Other,
+}
+
+/// Split terminal input into keys, each with the bytes it was typed as.
+fn keys(input: &[u8]) -> Vec<(Key, &[u8])> {
+    let mut keys = Vec::new();
+    let mut i = 0;
+    while i < input.len() {
+        if input[i] != 0x1b {
+            keys.push((Key::Byte(input[i]), &input[i..i + 1]));
+            i += 1;
+            continue;
+        }
+        if !matches!(input.get(i + 1), Some(b'[' | b'O')) {
+            keys.push((Key::Escape, &input[i..i + 1]));
+            i += 1;
+            continue;
+        }
+        let start = i + 2;
+        let end = input[start..]
+            .iter()
+            .position(|b| (0x40..=0x7e).contains(b))
+            .map_or(input.len(), |n| start + n + 1);
+        let key = match &input[start..end] {
+            b"A" => Key::Up,
+            b"B" => Key::Down,
+            b"5~" => Key::PageUp,
+            b"6~" => Key::PageDown,
+            _ => Key::Other,
+        };
+        keys.push((key, &input[i..end]));
+        i = end;
+    }
+    keys
+}
+
+/// Scrollback and screen being browsed in copy mode. Positions are line
+/// indexes into `lines`.
+#[derive(Debug)]
+struct CopyMode {
+    lines: Vec<String>,
+    /// First line shown.
+    top: usize,
+    cursor: usize,
+    /// Other end of the selection, once started.
+    mark: Option<usize>,
+    /// Lines where prompts start.
+    prompts: Vec<usize>,
+    /// Running as `pterm attach --view`, where `a` attaches.
+    pager: bool,
+    /// Search text being typed after `/`.
+    prompt: Option<String>,
+    search: String,
+    message: String,
+}
+
+/// What a key did in copy mode.
+#[derive(Debug, PartialEq, Eq)]
+enum CopyAction {
+    Stay,
+    Quit,
+    Yank(String),
+    Attach,
+}
+
+impl CopyMode {
+    fn new(capture: Capture, rows: usize) -> Self {
+        let mut mode = Self {
+            cursor: capture.cursor,
+            lines: capture.lines,
+            prompts: capture.prompts,
+            top: 0,
+            mark: None,
+            pager: false,
+            prompt: None,
+            search: String::new(),
+            message: String::new(),
+        };
+        mode.scroll_to_cursor(rows);
+        mode
+    }
+
+    fn scroll_to_cursor(&mut self, rows: usize) {
+        if self.cursor < self.top {
+            self.top = self.cursor;
+        } else if self.cursor >= self.top + rows {
+            self.top = self.cursor + 1 - rows;
+        }
+    }
+
+    fn move_by(&mut self, delta: isize, rows: usize) {
+        let last = self.lines.len().saturating_sub(1);
+        self.cursor = self.cursor.saturating_add_signed(delta).min(last);
+        self.scroll_to_cursor(rows);
+    }
+
+    /// Move to the next line containing the search text, towards older
+    /// lines unless `newer`.
+    fn find(&mut self, newer: bool, rows: usize) {
+        if self.search.is_empty() {
+            return;
+        }
+        let found = if newer {
+            (self.cursor + 1..self.lines.len()).find(|&i| self.lines[i].contains(&self.search))
+        } else {
+            (0..self.cursor)
+                .rev()
+                .find(|&i| self.lines[i].contains(&self.search))
+        };
+        match found {
+            Some(line) => {
+                self.cursor = line;
+                self.scroll_to_cursor(rows);
+            }
+            None => self.message = format!("not found: {}", self.search),
+        }
+    }
+
+    /// Move to the start of the previous prompt, or the next one if
+    /// `newer`, showing it at the top.
+    fn jump_to_prompt(&mut self, newer: bool, rows: usize) {
+        let found = if newer {
+            self.prompts.iter().find(|&&line| line > self.cursor)
+        } else {
+            self.prompts.iter().rev().find(|&&line| line < self.cursor)
+        };
+        match found {
+            Some(&line) => {
+                self.cursor = line;
+                self.top = line.min(self.lines.len().saturating_sub(rows));
+            }
+            None if self.prompts.is_empty() => {
+                self.message = "no prompt marks: the shell does not send OSC 133".to_string()
+            }
+            None if newer => self.message = "no newer prompt".to_string(),
+            None => self.message = "no older prompt".to_string(),
+        }
+    }
+
+    fn selection(&self) -> (usize, usize) {
+        let mark = self.mark.unwrap_or(self.cursor);
+        (mark.min(self.cursor), mark.max(self.cursor))
+    }
+
+    fn key(&mut self, key: Key, rows: usize) -> CopyAction {
+        self.message.clear();
+        if let Some(prompt) = self.prompt.as_mut() {
+            match key {
+                Key::Byte(b'\r' | b'\n') => {
+                    self.search = self.prompt.take().unwrap_or_default();
+                    self.find(false, rows);
+                }
+                Key::Escape | Key::Byte(0x03) => self.prompt = None,
+                Key::Byte(0x7f | 0x08) => {
+                    prompt.pop();
+                }
+                Key::Byte(b) if b >= 0x20 => prompt.push(b as char),
+                _ => {}
+            }
+            return CopyAction::Stay;
+        }
+        let page = rows as isize;
+        match key {
+            Key::Byte(b'q') | Key::Escape | Key::Byte(0x03) => return CopyAction::Quit,
+            Key::Byte(b'k') | Key::Up => self.move_by(-1, rows),
+            Key::Byte(b'j') | Key::Down => self.move_by(1, rows),
+            Key::Byte(0x15) => self.move_by(-page / 2, rows),
+            Key::Byte(0x04) => self.move_by(page / 2, rows),
+            Key::Byte(0x02) | Key::PageUp => self.move_by(-page, rows),
+            Key::Byte(0x06) | Key::PageDown => self.move_by(page, rows),
+            Key::Byte(b'g') => self.move_by(isize::MIN, rows),
+            Key::Byte(b'G') => self.move_by(isize::MAX, rows),
+            Key::Byte(b'v' | b' ') => {
+                self.mark = match self.mark {
+                    Some(_) => None,
+                    None => Some(self.cursor),
+                }
+            }
+            Key::Byte(b'/') => self.prompt = Some(String::new()),
+            Key::Byte(b'n') => self.find(false, rows),
+            Key::Byte(b'N') => self.find(true, rows),
+            Key::Byte(b'[') => self.jump_to_prompt(false, rows),
+            Key::Byte(b']') => self.jump_to_prompt(true, rows),
+            Key::Byte(b'a') if self.pager => return CopyAction::Attach,
+            Key::Byte(b'y' | b'\r') => {
+                let (start, end) = self.selection();
+                return CopyAction::Yank(self.lines[start..=end].join("\n"));
+            }
+            _ => {}
+        }
+        CopyAction::Stay
+    }
+
+    /// Draw the lines in view and the status line on a `cols` x `rows + 1`
+    /// terminal.
+    fn render(&self, cols: u16, rows: u16) -> Vec<u8> {
+        let mut out = Vec::new();
+        let _ = queue!(
+            out,
+            BeginSynchronizedUpdate,
+            Hide,
+            SetAttribute(Attribute::Reset)
+        );
+        let (start, end) = self.selection();
+        for row in 0..rows {
+            let index = self.top + row as usize;
+            let _ = queue!(out, MoveTo(0, row), Clear(ClearType::CurrentLine));
+            let Some(line) = self.lines.get(index) else {
+                continue;
+            };
+            let selected =
+                index == self.cursor || (self.mark.is_some() && (start..=end).contains(&index));
+            let _ = if selected {
+                queue!(
+                    out,
+                    SetAttribute(Attribute::Reverse),
+                    Print(fit(line, cols)),
+                    SetAttribute(Attribute::Reset)
+                )
+            } else {
+                queue!(out, Print(fit(line, cols)))
+            };
+        }
+        let status = match (&self.prompt, self.message.is_empty()) {
+            (Some(prompt), _) => format!("/{}", prompt),
+            (None, false) => self.message.clone(),
+            (None, true) if self.pager => format!(
+                " view  line {}/{}  [ ] prompts  / search  n/N next  a attach  q quit",
+                self.cursor + 1,
+                self.lines.len(),
+            ),
+            (None, true) => format!(
+                " copy mode  line {}/{}  {} y yank  / search  n/N next  q quit",
+                self.cursor + 1,
+                self.lines.len(),
+                if self.mark.is_some() {
+                    "v unmark"
+                } else {
+                    "v mark"
+                },
+            ),
+        };
+        out.extend_from_slice(&status_line(&status, cols, rows));
+        let _ = queue!(out, EndSynchronizedUpdate);
+        out
+    }
+}
+
+/// `text` cut or padded to `cols` characters.
+fn fit(text: &str, cols: u16) -> String {
+    let mut line: String = text.chars().take(cols as usize).collect();
+    let width = line.chars().count();
+    line.push_str(&" ".repeat(cols as usize - width));
+    line
+}
+
+/// Reverse-video `text` on row `row` (0-based).
+fn status_line(text: &str, cols: u16, row: u16) -> Vec<u8> {
+    let mut out = Vec::new();
+    let _ = queue!(
+        out,
+        MoveTo(0, row),
+        SetAttribute(Attribute::Reset),
+        SetAttribute(Attribute::Reverse),
+        Print(fit(text, cols)),
+        SetAttribute(Attribute::Reset)
+    );
+    out
+}
+
+/// The session screen as the client draws it.
+struct View {
+    parser: vt100::Parser,
+    /// The screen as last drawn.
+    shown: vt100::Screen,
+    cols: u16,
+    /// Rows of the hosting terminal; the session gets one less.
+    rows: u16,
+    status: String,
+}
+
+impl View {
+    fn new(cols: u16, rows: u16, status: String) -> Self {
+        let parser = vt100::Parser::new(rows.saturating_sub(1).max(1), cols, 0);
+        let shown = parser.screen().clone();
+        Self {
+            parser,
+            shown,
+            cols,
+            rows,
+            status,
+        }
+    }
+
+    fn resize(&mut self, cols: u16, rows: u16) {
+        self.cols = cols;
+        self.rows = rows;
+        self.parser
+            .screen_mut()
+            .set_size(rows.saturating_sub(1).max(1), cols);
+    }
+
+    /// Bring the hosting terminal up to date with what was fed since the
+    /// last draw, or redraw everything.
+    fn draw(&mut self, full: bool) -> Vec<u8> {
+        let screen = self.parser.screen();
+        let update = if full {
+            let mut update = Vec::new();
+            let _ = queue!(
+                update,
+                SetAttribute(Attribute::Reset),
+                MoveTo(0, 0),
+                Clear(ClearType::All)
+            );
+            update.extend_from_slice(&screen.state_formatted());
+            update.extend_from_slice(&status_line(
+                &self.status,
+                self.cols,
+                self.rows.saturating_sub(1),
+            ));
+            update
+        } else {
+            screen.state_diff(&self.shown)
+        };
+        if update.is_empty() {
+            return update;
+        }
+        // Wrapped in synchronized output, and ending with the attributes
+        // and cursor the next diff starts from.
+        let mut out = Vec::new();
+        let _ = queue!(out, BeginSynchronizedUpdate);
+        out.extend_from_slice(&update);
+        out.extend_from_slice(&screen.attributes_formatted());
+        out.extend_from_slice(&screen.cursor_state_formatted());
+        let _ = queue!(out, EndSynchronizedUpdate);
+        self.shown = screen.clone();
+        out
+    }
+}
+
+fn status_text(name: &str, detail: &str) -> String {
+    format!(
+        " pterm: {}  {}  Ctrl-] d detach  [ copy  / search",
+        name, detail
+    )
+}
+
+/// Attach to session `name` and run the frontend until detach or the
+/// session's command exits. Returns the command's exit code.
+pub fn run(name: &str) -> io::Result<i32> {
+    let stdout_fd = libc::STDOUT_FILENO;
+    if unsafe { libc::isatty(libc::STDIN_FILENO) } != 1 {
+        return Err(io::Error::other("pterm client needs a terminal"));
+    }
+    let (wake_read, _wake_write) = bridge::sigwinch_pipe()?;
+    let mut socket = UnixStream::connect(session_socket_path(name))?;
+    control::hello(&mut socket)?;
+
+    let (cols, rows) = terminal_size();
+    let mut view = View::new(cols, rows, status_text(name, ""));
+    let session_rows = rows.saturating_sub(1).max(1);
+    let identity = ClientIdentity::of_this_process(io::stdin(), cols, session_rows);
+    socket.write_all(&identity.frame())?;
+    socket.write_all(&proto::encode(
+        proto::client::RESIZE,
+        &proto::encode_resize(cols, session_rows),
+    ))?;
+    let screen = Screen::enter()?;
+    let result = event_loop(name, &mut socket, &mut view, wake_read.as_raw_fd());
+    let _ = socket.write_all(&proto::encode(proto::client::DETACH, &[]));
+    drop(screen);
+    let _ = bridge::write_all_raw(stdout_fd, bridge::DETACH_CLEANUP_SEQUENCES);
+    result
+}
+
+/// Page through the scrollback and screen of session `name` without
+/// attaching. Returns whether the user asked to attach.
+pub fn view(name: &str) -> io::Result<bool> {
+    let stdin_fd = libc::STDIN_FILENO;
+    let stdout_fd = libc::STDOUT_FILENO;
+    if unsafe { libc::isatty(stdin_fd) } != 1 {
+        return Err(io::Error::other("pterm attach --view needs a terminal"));
+    }
+    let reply = control::request(name, proto::client::CAPTURE, &[])?;
+    let mut capture = Capture::from_json(&reply)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "malformed capture reply"))?;
+    if capture.lines.is_empty() {
+        capture.lines.push(String::new());
+    }
+    let (wake_read, _wake_write) = bridge::sigwinch_pipe()?;
+    let wake_fd = wake_read.as_raw_fd();
+    let (mut cols, mut rows) = terminal_size();
+    let mut mode = CopyMode::new(capture, rows.saturating_sub(1) as usize);
+    mode.pager = true;
+    let _screen = Screen::enter()?;
+    (|| {
+        let mut stdin_buf = [0u8; 4096];
+        loop {
+            bridge::write_all_raw(stdout_fd, &mode.render(cols, rows.saturating_sub(1)))?;
+            let mut fds = [
+                libc::pollfd {
+                    fd: stdin_fd,
+                    events: libc::POLLIN,
+                    revents: 0,
+                },
+                libc::pollfd {
+                    fd: wake_fd,
+                    events: libc::POLLIN,
+                    revents: 0,
+                },
+            ];
+            if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) } < 0 {
+                let e = io::Error::last_os_error();
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(e);
+            }
+            if fds[1].revents != 0 && bridge::take_sigwinch(wake_fd) {
+                (cols, rows) = terminal_size();
+                mode.scroll_to_cursor(rows.saturating_sub(1) as usize);
+            }
+            if fds[0].revents == 0 {
+                continue;
+            }
+            let n = match nix::unistd::read(stdin_fd, &mut stdin_buf) {
+                Ok(0) => return Ok(false),
+                Ok(n) => n,
+                Err(nix::errno::Errno::EINTR | nix::errno::Errno::EAGAIN) => 0,
+                Err(e) => return Err(e.into()),
+            };
+            for (key, _) in keys(&stdin_buf[..n]) {
+                match mode.key(key, rows.saturating_sub(1) as usize) {
+                    CopyAction::Stay => {}
+                    CopyAction::Quit => return Ok(false),
+                    CopyAction::Attach => return Ok(true),
+                    CopyAction::Yank(text) => {
+                        paste::store(&text)?;
+                        mode.mark = None;
+                        mode.message = format!("copied {} line(s)", text.lines().count());
+                        bridge::write_all_raw(stdout_fd, &paste::clipboard(&text))?;
+                    }
+                }
+            }
+        }
+    })()
+}
+
+fn event_loop(
+    name: &str,
+    socket: &mut UnixStream,
+    view: &mut View,
+    wake_fd: i32,
+) -> io::Result<i32> {
+    let stdin_fd = libc::STDIN_FILENO;
+    let stdout_fd = libc::STDOUT_FILENO;
+    let mut copy: Option<CopyMode> = None;
+    let mut prefix = false;
+    let mut pending_confirm: Option<u64> = None;
+    let mut decoder = proto::FrameDecoder::new();
+    let mut sock_buf = vec![0u8; 65536];
+    let mut stdin_buf = [0u8; 4096];
+    loop {
+        let mut fds = [
+            libc::pollfd {
+                fd: stdin_fd,
+                events: libc::POLLIN,
+                revents: 0,
+            },
+            libc::pollfd {
+                fd: socket.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+            libc::pollfd {
+                fd: wake_fd,
+                events: libc::POLLIN,
+                revents: 0,
+            },
+        ];
+        if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) } < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(e);
+        }
+
+        if fds[2].revents != 0 && bridge::take_sigwinch(wake_fd) {
+            let (cols, rows) = terminal_size();
+            view.resize(cols, rows);
+            socket.write_all(&proto::encode(
+                proto::client::RESIZE,
+                &proto::encode_resize(cols, rows.saturating_sub(1).max(1)),
+            ))?;
+            let out = match &copy {
+                Some(mode) => mode.render(cols, rows.saturating_sub(1)),
+                None => view.draw(true),
+            };
+            bridge::write_all_raw(stdout_fd, &out)?;
+        }
+
+        if fds[0].revents != 0 {
+            let n = match nix::unistd::read(stdin_fd, &mut stdin_buf) {
+                Ok(0) => return Ok(0),
+                Ok(n) => n,
+                Err(nix::errno::Errno::EINTR | nix::errno::Errno::EAGAIN) => 0,
+                Err(e) => return Err(e.into()),
+            };
+            let mut input = Vec::new();
+            for (key, bytes) in keys(&stdin_buf[..n]) {
+                let page = view.rows.saturating_sub(1) as usize;
+                if let Some(mode) = copy.as_mut() {
+                    match mode.key(key, page) {
+                        CopyAction::Stay => {
+                            bridge::write_all_raw(
+                                stdout_fd,
+                                &mode.render(view.cols, view.rows.saturating_sub(1)),
+                            )?;
+                        }
+                        CopyAction::Quit | CopyAction::Attach => {
+                            copy = None;
+                            bridge::write_all_raw(stdout_fd, &view.draw(true))?;
+                        }
+                        CopyAction::Yank(text) => {
+                            copy = None;
+                            paste::store(&text)?;
+                            view.status = status_text(
+                                name,
+                                &format!("copied {} line(s)", text.lines().count()),
+                            );
+                            let mut out = paste::clipboard(&text);
+                            out.extend_from_slice(&view.draw(true));
+                            bridge::write_all_raw(stdout_fd, &out)?;
+                        }
+                    }
+                    continue;
+                }
+                if let Some(id) = pending_confirm.take() {
+                    let accept = matches!(key, Key::Byte(b'y' | b'Y'));
+                    let answer = serde_json::json!({ "id": id, "accept": accept });
+                    socket.write_all(&proto::encode(
+                        proto::client::CONFIRM_REPLY,
+                        answer.to_string().as_bytes(),
+                    ))?;
+                    view.status = status_text(name, "");
+                    bridge::write_all_raw(stdout_fd, &view.draw(true))?;
+                    continue;
+                }
+                if std::mem::take(&mut prefix) {
+                    match key {
+                        Key::Byte(b'd') => return Ok(0),
+                        Key::Byte(PREFIX) => input.push(PREFIX),
+                        Key::Byte(command @ (b'[' | b'/')) => {
+                            let reply = control::request(name, proto::client::CAPTURE, &[])?;
+                            let capture = Capture::from_json(&reply).ok_or_else(|| {
+                                io::Error::new(
+                                    io::ErrorKind::InvalidData,
+                                    "malformed capture reply",
+                                )
+                            })?;
+                            if capture.lines.is_empty() {
+                                continue;
+                            }
+                            let mut mode = CopyMode::new(capture, page);
+                            if command == b'/' {
+                                mode.prompt = Some(String::new());
+                            }
+                            bridge::write_all_raw(
+                                stdout_fd,
+                                &mode.render(view.cols, view.rows.saturating_sub(1)),
+                            )?;
+                            copy = Some(mode);
+                        }
+                        _ => {}
+                    }
+                    continue;
+                }
+                match key {
+                    Key::Byte(PREFIX) => prefix = true,
+                    _ => input.extend_from_slice(bytes),
+                }
+            }
+            if !input.is_empty() {
+                socket.write_all(&proto::encode(proto::client::INPUT, &input))?;
+            }
+        }
+
+        if fds[1].revents != 0 {
+            let n = match socket.read(&mut sock_buf) {
+                Ok(0) => return Ok(0),
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => 0,
+                Err(e) => return Err(e),
+            };
+            for frame in decoder.decode(&sock_buf[..n]) {
+                match frame.msg_type {
+                    proto::server::OUTPUT | proto::server::STATE_SYNC => {
+                        view.parser.process(&frame.payload);
+                    }
+                    proto::server::CONFIRM => {
+                        let ask: serde_json::Value =
+                            serde_json::from_slice(&frame.payload).unwrap_or_default();
+                        if let Some(id) = ask["id"].as_u64() {
+                            pending_confirm = Some(id);
+                            view.status = format!(
+                                " pterm guard: {:?} matches {:?}. Run it? [y/N]",
+                                ask["line"].as_str().unwrap_or_default().trim(),
+                                ask["pattern"].as_str().unwrap_or_default()
+                            );
+                            bridge::write_all_raw(stdout_fd, &view.draw(true))?;
+                        }
+                    }
+                    proto::server::PING => {
+                        socket.write_all(&proto::encode(proto::client::PONG, &[]))?;
+                    }
+                    proto::server::DETACHED => return Ok(0),
+                    proto::server::EXIT => {
+                        return Ok(proto::parse_exit(&frame.payload).unwrap_or(0));
+                    }
+                    _ => {}
+                }
+            }
+            if copy.is_none() {
+                bridge::write_all_raw(stdout_fd, &view.draw(false))?;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn copy_mode_moves_searches_and_yanks() {
+        let typed: Vec<Key> = keys(b"k\x1b[A\x1bOB\x1b[5~\x1bx")
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect();
+        assert_eq!(
+            typed,
+            [
+                Key::Byte(b'k'),
+                Key::Up,
+                Key::Down,
+                Key::PageUp,
+                Key::Escape,
+                Key::Byte(b'x')
+            ]
+        );
+        assert_eq!(keys(b"\x1b[1;5C")[0], (Key::Other, &b"\x1b[1;5C"[..]));
+
+        let lines: Vec<String> = (0..10).map(|i| format!("line {}", i)).collect();
+        let capture = Capture {
+            lines,
+            screen: 6,
+            cursor: 9,
+            prompts: vec![1, 5],
+        };
+        let mut mode = CopyMode::new(capture, 4);
+        assert_eq!((mode.top, mode.cursor), (6, 9));
+        for key in b"/line 2\r" {
+            assert_eq!(mode.key(Key::Byte(*key), 4), CopyAction::Stay);
+        }
+        assert_eq!((mode.top, mode.cursor), (2, 2));
+        mode.key(Key::Byte(b'n'), 4);
+        assert_eq!(mode.message, "not found: line 2");
+        mode.key(Key::Byte(b'v'), 4);
+        mode.key(Key::Down, 4);
+        assert_eq!(
+            mode.key(Key::Byte(b'y'), 4),
+            CopyAction::Yank("line 2\nline 3".to_string())
+        );
+        assert_eq!(mode.key(Key::Byte(b'q'), 4), CopyAction::Quit);
+
+        mode.key(Key::Byte(b'['), 4);
+        assert_eq!((mode.top, mode.cursor), (1, 1));
+        mode.key(Key::Byte(b'['), 4);
+        assert_eq!(mode.message, "no older prompt");
+        mode.key(Key::Byte(b']'), 4);
+        assert_eq!((mode.top, mode.cursor), (5, 5));
+        assert_eq!(mode.key(Key::Byte(b'a'), 4), CopyAction::Stay);
+        mode.pager = true;
+        assert_eq!(mode.key(Key::Byte(b'a'), 4), CopyAction::Attach);
+    }
+}
@@ -0,0 +1,258 @@
+//! `pterm clients`: per-client details reported by the daemon.
+
+use crate::list::format_age;
+use pterm_proto as proto;
+use serde::{Deserialize, Serialize};
+use std::os::fd::AsFd;
+
+/// One connection to the daemon, as reported in a `LIST_CLIENTS` reply.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClientInfo {
+    pub id: usize,
+    #[serde(flatten)]
+    pub peer: PeerIdentity,
+    /// Unix timestamp (seconds) of the connection.
+    pub connected_at: u64,
+    /// Bytes written to the client.
+    pub bytes_sent: u64,
+    /// Bytes read from the client.
+    pub bytes_received: u64,
+    /// Bytes queued for the client but not yet written (backpressure).
+    pub pending: usize,
+    pub attached: bool,
+    pub read_only: bool,
+    /// What the client announced about itself (`client::IDENTIFY`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub identity: Option<ClientIdentity>,
+}
+
+/// A client's own account of where it runs, from `client::IDENTIFY`. Unlike
+/// [`PeerIdentity`], it survives a socket forwarded over SSH, where the
+/// peer is `sshd`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ClientIdentity {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pid: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+    /// The client's terminal device, e.g. `/dev/pts/3`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tty: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cols: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rows: Option<u16>,
+}
+
+impl ClientIdentity {
+    /// This process, attached through the terminal on `fd`, which is
+    /// `cols` x `rows`.
+    pub fn of_this_process<F: AsFd>(fd: F, cols: u16, rows: u16) -> Self {
+        Self {
+            pid: Some(std::process::id() as i32),
+            host: hostname(),
+            tty: nix::unistd::ttyname(fd)
+                .ok()
+                .map(|path| path.display().to_string()),
+            cols: Some(cols),
+            rows: Some(rows),
+        }
+    }
+
+    /// The `IDENTIFY` frame announcing this identity.
+    pub fn frame(&self) -> Vec<u8> {
+        let payload = serde_json::to_vec(self).unwrap_or_default();
+        proto::encode(proto::client::IDENTIFY, &payload)
+    }
+
+    /// `host:tty`, or whichever of them is known.
+    pub fn terminal(&self) -> Option<String> {
+        match (&self.host, &self.tty) {
+            (Some(host), Some(tty)) => Some(format!("{}:{}", host, tty)),
+            (host, tty) => host.clone().or_else(|| tty.clone()),
+        }
+    }
+}
+
+/// This machine's host name.
+pub fn hostname() -> Option<String> {
+    let mut buf = [0u8; 256];
+    if unsafe { nix::libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) } != 0 {
+        return None;
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    Some(String::from_utf8_lossy(&buf[..len]).into_owned()).filter(|host| !host.is_empty())
+}
+
+/// Peer process behind a client socket (`SO_PEERCRED`).
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerIdentity {
+    pub pid: Option<i32>,
+    pub uid: Option<u32>,
+    /// Peer process name from `/proc/<pid>/comm`, where available.
+    pub command: Option<String>,
+}
+
+impl PeerIdentity {
+    /// Name shown for the client to others: the peer's user name, else its
+    /// command, else `client <id>`.
+    pub fn label(&self, id: usize) -> String {
+        self.uid
+            .and_then(|uid| nix::unistd::User::from_uid(uid.into()).ok().flatten())
+            .map(|user| user.name)
+            .or_else(|| self.command.clone())
+            .unwrap_or_else(|| format!("client {}", id))
+    }
+
+    pub fn of<F: AsFd>(stream: &F) -> Self {
+        use nix::sys::socket::{getsockopt, sockopt::PeerCredentials};
+
+        let Ok(cred) = getsockopt(stream, PeerCredentials) else {
+            return Self::default();
+        };
+        let command = std::fs::read_to_string(format!("/proc/{}/comm", cred.pid()))
+            .ok()
+            .map(|comm| comm.trim_end().to_string());
+        Self {
+            pid: Some(cred.pid()),
+            uid: Some(cred.uid()),
+            command,
+        }
+    }
+
+    /// Pid of the peer process's parent, from `/proc/<pid>/stat`.
+    pub fn parent_pid(&self) -> Option<i32> {
+        let stat = std::fs::read_to_string(format!("/proc/{}/stat", self.pid?)).ok()?;
+        // `pid (comm) state ppid ...`; comm may contain spaces and parens.
+        let (_, rest) = stat.rsplit_once(')')?;
+        rest.split_whitespace().nth(1)?.parse().ok()
+    }
+}
+
+/// Human-readable byte count, e.g. `512B`, `3.4K`, `12.0M`.
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["K", "M", "G", "T"];
+    if bytes < 1024 {
+        return format!("{}B", bytes);
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit + 1 < UNITS.len() {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1}{}", value, UNITS[unit])
+}
+
+/// Render clients as an aligned table with a header row.
+pub fn render_table(clients: &[ClientInfo], now: u64) -> String {
+    const HEADERS: [&str; 11] = [
+        "ID", "PID", "UID", "CMD", "TERMINAL", "SIZE", "SINCE", "SENT", "RECV", "PENDING", "MODE",
+    ];
+    let opt = |v: Option<String>| v.unwrap_or_else(|| "-".to_string());
+    let rows: Vec<[String; 11]> = clients
+        .iter()
+        .map(|c| {
+            let mode = match (c.attached, c.read_only) {
+                (_, true) => "read-only",
+                (true, false) => "read-write",
+                (false, false) => "control",
+            };
+            let identity = c.identity.clone().unwrap_or_default();
+            let size = identity.cols.zip(identity.rows);
+            [
+                c.id.to_string(),
+                opt(c.peer.pid.map(|p| p.to_string())),
+                opt(c.peer.uid.map(|u| u.to_string())),
+                opt(c.peer.command.clone()),
+                opt(identity.terminal()),
+                opt(size.map(|(cols, rows)| format!("{}x{}", cols, rows))),
+                format_age(now.saturating_sub(c.connected_at)),
+                format_bytes(c.bytes_sent),
+                format_bytes(c.bytes_received),
+                format_bytes(c.pending as u64),
+                mode.to_string(),
+            ]
+        })
+        .collect();
+
+    let mut widths = HEADERS.map(str::len);
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let headers = HEADERS.map(str::to_string);
+    let mut out = String::new();
+    for row in std::iter::once(&headers).chain(&rows) {
+        let line: Vec<String> = row
+            .iter()
+            .zip(widths)
+            .map(|(cell, w)| format!("{:<w$}", cell, w = w))
+            .collect();
+        out.push_str(line.join("  ").trim_end());
+        out.push('\n');
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_bytes_scales_units() {
+        assert_eq!(format_bytes(512), "512B");
+        assert_eq!(format_bytes(3 * 1024 + 512), "3.5K");
+        assert_eq!(format_bytes(12 * 1024 * 1024), "12.0M");
+    }
+
+    #[test]
+    fn table_shows_mode_and_pending() {
+        let clients = vec![
+            ClientInfo {
+                id: 1,
+                peer: PeerIdentity {
+                    pid: Some(42),
+                    uid: Some(1000),
+                    command: Some("pterm".to_string()),
+                },
+                connected_at: 40,
+                bytes_sent: 2048,
+                pending: 100,
+                attached: true,
+                identity: Some(ClientIdentity {
+                    host: Some("laptop".to_string()),
+                    tty: Some("/dev/pts/3".to_string()),
+                    cols: Some(120),
+                    rows: Some(40),
+                    ..ClientIdentity::default()
+                }),
+                ..ClientInfo::default()
+            },
+            ClientInfo {
+                id: 7,
+                connected_at: 100,
+                read_only: true,
+                attached: true,
+                ..ClientInfo::default()
+            },
+        ];
+        let table = render_table(&clients, 100);
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(
+            lines[0],
+            "ID  PID  UID   CMD    TERMINAL           SIZE    SINCE  SENT  RECV  PENDING  MODE"
+        );
+        assert_eq!(
+            lines[1],
+            "1   42   1000  pterm  laptop:/dev/pts/3  120x40  1m     2.0K  0B    100B     read-write"
+        );
+        assert_eq!(
+            lines[2],
+            "7   -    -     -      -                  -       0s     0B    0B    0B       read-only"
+        );
+    }
+}
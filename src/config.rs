@@ -0,0 +1,335 @@
+//! User configuration read from `config.toml`.
+//!
+//! The file is optional; a missing file is the same as an empty one.
+
+use crate::scrollback::parse_size;
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::PathBuf;
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Command (with arguments) run by `pterm new` when none is given.
+    pub default_command: Option<Vec<String>>,
+    /// Write a banner (session name, creation time, how to detach) into new
+    /// sessions' scrollback.
+    pub banner: bool,
+    /// Message of the day shown below the banner.
+    pub motd: Option<String>,
+    /// `LANG` of new sessions' commands.
+    pub locale: Option<String>,
+    /// `TZ` of new sessions' commands.
+    pub tz: Option<String>,
+    /// Privilege wrapper (with arguments) used by `--as-root`.
+    pub root_wrapper: Option<Vec<String>>,
+    /// Socket root for sessions created or attached with `--as-root`.
+    pub root_socket_dir: Option<PathBuf>,
+    /// Further socket roots searched after the default one, addressed as
+    /// `<name>:<session>`.
+    pub socket_dirs: Vec<SocketDir>,
+    /// Persistent socket root that sessions under `$XDG_RUNTIME_DIR` move
+    /// to while the runtime directory is gone.
+    pub fallback_socket_dir: Option<PathBuf>,
+    /// Public key (`ssh-ed25519 AAAA...`) that `pterm self-update` accepts
+    /// release signatures of besides the built-in one, if
+    /// `trust_release_key` is set.
+    pub release_key: Option<String>,
+    /// Trust `release_key` or `$PTERM_EXTRA_RELEASE_KEY` in addition to the
+    /// built-in key.
+    pub trust_release_key: bool,
+    /// Buffer sizes, see [`buffers`].
+    pub buffers: BufferConfig,
+}
+
+/// `[buffers]`: sizes such as `"256K"`, each overridden by an environment
+/// variable.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct BufferConfig {
+    pub read_buffer: Option<String>,
+    pub drain_limit: Option<String>,
+    pub send_buffer_warn: Option<String>,
+    pub max_frame: Option<String>,
+    pub resume_buffer: Option<String>,
+}
+
+/// Buffer sizes of the daemon and the attach bridge.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Buffers {
+    /// Bytes read from the pty or a socket at once.
+    pub read_buffer: usize,
+    /// PTY bytes the daemon reads per loop iteration at most, so a child
+    /// that writes faster than the terminal state is updated cannot starve
+    /// new connections and client messages.
+    pub drain_limit: usize,
+    /// A client's send-buffer backlog logged as a warning.
+    pub send_buffer_warn: usize,
+    /// Largest frame payload the daemon accepts from a client; a client
+    /// announcing a larger one is disconnected.
+    pub max_frame: usize,
+    /// Newest output the daemon keeps for clients that reconnect and
+    /// resume the output stream instead of taking a snapshot.
+    pub resume_buffer: usize,
+}
+
+impl Default for Buffers {
+    fn default() -> Self {
+        Self {
+            read_buffer: 64 * 1024,
+            drain_limit: 64 * 1024,
+            send_buffer_warn: 64 * 1024,
+            max_frame: pterm_proto::DEFAULT_MAX_FRAME_LEN,
+            resume_buffer: 1 << 20,
+        }
+    }
+}
+
+/// A named socket root (`[[socket_dirs]]`), e.g. a team's shared one.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct SocketDir {
+    pub name: String,
+    pub path: PathBuf,
+}
+
+impl Config {
+    /// Load the configuration file, if there is one.
+    pub fn load() -> io::Result<Self> {
+        let Some(path) = config_path() else {
+            return Ok(Self::default());
+        };
+        let text = match std::fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e),
+        };
+        Self::parse(&text).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {}", path.display(), e),
+            )
+        })
+    }
+
+    fn parse(text: &str) -> Result<Self, String> {
+        toml::from_str(text).map_err(|e| e.message().to_string())
+    }
+}
+
+/// `$PTERM_CONFIG`, else `$XDG_CONFIG_HOME/pterm/config.toml`, else
+/// `~/.config/pterm/config.toml`.
+pub fn config_path() -> Option<PathBuf> {
+    if let Ok(path) = std::env::var("PTERM_CONFIG") {
+        return Some(PathBuf::from(path));
+    }
+    let base = match std::env::var("XDG_CONFIG_HOME") {
+        Ok(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var("HOME").ok()?).join(".config"),
+    };
+    Some(base.join("pterm").join("config.toml"))
+}
+
+/// Command for `pterm new` when none is given on the command line:
+/// `$PTERM_DEFAULT_COMMAND`, then `default_command` from the config file,
+/// then `$SHELL`, then `/bin/sh`.
+pub fn default_command(config: &Config) -> Result<Vec<String>, String> {
+    if let Ok(command) = std::env::var("PTERM_DEFAULT_COMMAND") {
+        let words = split_words(&command)?;
+        if !words.is_empty() {
+            return Ok(words);
+        }
+    }
+    if let Some(command) = config.default_command.as_ref().filter(|c| !c.is_empty()) {
+        return Ok(command.clone());
+    }
+    let shell = std::env::var("SHELL").unwrap_or_else(|_| "/bin/sh".to_string());
+    Ok(vec![shell])
+}
+
+/// Buffer sizes: each `$PTERM_<KEY>` (e.g. `$PTERM_READ_BUFFER`), then
+/// `[buffers]` from the config file, then [`Buffers::default`].
+pub fn buffers(config: &Config) -> Result<Buffers, String> {
+    buffers_from(&config.buffers, |name| std::env::var(name).ok())
+}
+
+fn buffers_from(
+    config: &BufferConfig,
+    env: impl Fn(&str) -> Option<String>,
+) -> Result<Buffers, String> {
+    let size = |var: &str, key: &str, configured: &Option<String>, default, max| {
+        let (origin, value) = match (env(var), configured) {
+            (Some(value), _) => (var.to_string(), value),
+            (None, Some(value)) => (format!("buffers.{}", key), value.clone()),
+            (None, None) => return Ok(default),
+        };
+        parse_size(&value)
+            .filter(|size| (1024..=max).contains(size))
+            .ok_or_else(|| {
+                format!(
+                    "{}: invalid size '{}' (expected 1K to {}M)",
+                    origin,
+                    value,
+                    max >> 20
+                )
+            })
+    };
+    let default = Buffers::default();
+    Ok(Buffers {
+        read_buffer: size(
+            "PTERM_READ_BUFFER",
+            "read_buffer",
+            &config.read_buffer,
+            default.read_buffer,
+            16 << 20,
+        )?,
+        drain_limit: size(
+            "PTERM_DRAIN_LIMIT",
+            "drain_limit",
+            &config.drain_limit,
+            default.drain_limit,
+            1 << 30,
+        )?,
+        send_buffer_warn: size(
+            "PTERM_SEND_BUFFER_WARN",
+            "send_buffer_warn",
+            &config.send_buffer_warn,
+            default.send_buffer_warn,
+            1 << 30,
+        )?,
+        max_frame: size(
+            "PTERM_MAX_FRAME",
+            "max_frame",
+            &config.max_frame,
+            default.max_frame,
+            1 << 30,
+        )?,
+        resume_buffer: size(
+            "PTERM_RESUME_BUFFER",
+            "resume_buffer",
+            &config.resume_buffer,
+            default.resume_buffer,
+            1 << 30,
+        )?,
+    })
+}
+
+/// Split a command line into words the way a POSIX shell would for simple
+/// cases: whitespace separates words, single quotes are literal, and double
+/// quotes allow backslash escapes. No expansion is performed.
+pub fn split_words(line: &str) -> Result<Vec<String>, String> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => words.extend(word.take()),
+            '\'' => {
+                let w = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => w.push(c),
+                        None => return Err("unterminated single quote".to_string()),
+                    }
+                }
+            }
+            '"' => {
+                let w = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c @ ('"' | '\\' | '$' | '`')) => w.push(c),
+                            Some(c) => {
+                                w.push('\\');
+                                w.push(c);
+                            }
+                            None => return Err("unterminated double quote".to_string()),
+                        },
+                        Some(c) => w.push(c),
+                        None => return Err("unterminated double quote".to_string()),
+                    }
+                }
+            }
+            '\\' => match chars.next() {
+                Some(c) => word.get_or_insert_with(String::new).push(c),
+                None => return Err("trailing backslash".to_string()),
+            },
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(word);
+    Ok(words)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_words_handles_quotes() {
+        assert_eq!(split_words("zsh -l").unwrap(), ["zsh", "-l"]);
+        assert_eq!(
+            split_words(r#"  bash -c 'echo "hi there"' "a\"b" c\ d '' "#).unwrap(),
+            ["bash", "-c", "echo \"hi there\"", "a\"b", "c d", ""]
+        );
+        assert!(split_words("fish 'oops").is_err());
+        assert!(split_words("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn parse_default_command() {
+        let config = Config::parse("default_command = [\"fish\", \"--login\"]\n").unwrap();
+        assert_eq!(
+            config.default_command,
+            Some(vec!["fish".to_string(), "--login".to_string()])
+        );
+        assert_eq!(Config::parse("").unwrap(), Config::default());
+        assert!(Config::parse("default_command = \"fish\"").is_err());
+    }
+
+    #[test]
+    fn parse_socket_dirs() {
+        let config = Config::parse(
+            "[[socket_dirs]]\nname = \"shared\"\npath = \"/srv/pterm\"\n\n\
+             [[socket_dirs]]\nname = \"proj\"\npath = \"/home/me/proj/.pterm\"\n",
+        )
+        .unwrap();
+        let names: Vec<&str> = config.socket_dirs.iter().map(|d| d.name.as_str()).collect();
+        assert_eq!(names, ["shared", "proj"]);
+        assert_eq!(config.socket_dirs[0].path, PathBuf::from("/srv/pterm"));
+        assert!(Config::parse("[[socket_dirs]]\nname = \"x\"\n").is_err());
+    }
+
+    #[test]
+    fn buffers_from_config_and_env() {
+        let config =
+            Config::parse("[buffers]\nread_buffer = \"256K\"\ndrain_limit = \"1M\"\n").unwrap();
+        let no_env = |_: &str| None;
+        assert_eq!(
+            buffers_from(&config.buffers, no_env),
+            Ok(Buffers {
+                read_buffer: 256 << 10,
+                drain_limit: 1 << 20,
+                ..Buffers::default()
+            })
+        );
+
+        let env = |name: &str| (name == "PTERM_READ_BUFFER").then(|| "8K".to_string());
+        assert_eq!(
+            buffers_from(&config.buffers, env).map(|b| b.read_buffer),
+            Ok(8 << 10)
+        );
+        let env = |name: &str| (name == "PTERM_MAX_FRAME").then(|| "1M".to_string());
+        assert_eq!(
+            buffers_from(&config.buffers, env).map(|b| b.max_frame),
+            Ok(1 << 20)
+        );
+        let env = |name: &str| (name == "PTERM_SEND_BUFFER_WARN").then(|| "12".to_string());
+        assert_eq!(
+            buffers_from(&config.buffers, env),
+            Err("PTERM_SEND_BUFFER_WARN: invalid size '12' (expected 1K to 1024M)".to_string())
+        );
+    }
+}
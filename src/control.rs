@@ -0,0 +1,420 @@
+//! One-shot control connections from the CLI to a session daemon.
+//!
+//! A control connection is never attached (it sends no `RESIZE`), so it does
+//! not count as a client in `meta.json`. Any `OUTPUT` / `STATE_SYNC` the daemon
+//! pushes to it is ignored.
+//!
+//! On an encrypted session (one with a key, see [`session_key`]) a control
+//! connection says `HELLO` first to encrypt the connection; the daemon
+//! refuses requests that come in the clear.
+//!
+//! [`session_key`]: crate::session_key
+
+use crate::error::{self, Error};
+use crate::paths::session_socket_path;
+use crate::session_key;
+use crate::share;
+use pterm_proto as proto;
+use std::io::{self, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::time::Duration;
+
+/// How long to wait for the daemon to answer a request.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Send a single frame to the daemon of session `name` without waiting for
+/// an answer.
+pub fn send(name: &str, msg_type: u8, payload: &[u8]) -> io::Result<()> {
+    let socket = session_socket_path(name);
+    let mut stream = UnixStream::connect(&socket)?;
+    let mut wire = secure(&mut stream, &socket)?;
+    stream.write_all(&wire.encode(msg_type, payload))?;
+    Ok(())
+}
+
+/// Send a control request and wait for the daemon's `REPLY`.
+pub fn request(name: &str, msg_type: u8, payload: &[u8]) -> io::Result<serde_json::Value> {
+    request_at(&session_socket_path(name), msg_type, payload)
+}
+
+/// [`request`], with an error `REPLY` turned into [`Error::Refused`].
+pub fn call(name: &str, msg_type: u8, payload: &[u8]) -> error::Result<serde_json::Value> {
+    let reply = request(name, msg_type, payload)?;
+    match reply["error"].as_str() {
+        Some(message) => Err(Error::Refused {
+            session: name.to_string(),
+            request: proto::ClientMessage::from_u8(msg_type).map_or("?", |m| m.name()),
+            message: message.to_string(),
+        }),
+        None => Ok(reply),
+    }
+}
+
+/// [`request`] to the daemon listening on `socket`.
+pub fn request_at(socket: &Path, msg_type: u8, payload: &[u8]) -> io::Result<serde_json::Value> {
+    let mut stream = UnixStream::connect(socket)?;
+    stream.set_read_timeout(Some(REPLY_TIMEOUT))?;
+    let mut wire = secure(&mut stream, socket)?;
+    stream.write_all(&wire.encode(msg_type, payload))?;
+
+    let mut decoder = proto::FrameDecoder::new();
+    let mut buf = [0u8; 65536];
+    loop {
+        let n = match stream.read(&mut buf) {
+            Ok(n) => n,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "daemon did not answer (older pterm daemon?)",
+                ));
+            }
+            Err(e) => return Err(e),
+        };
+        if n == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "daemon closed the connection without answering",
+            ));
+        }
+        for frame in decoder.decode(&buf[..n]) {
+            let frame = wire.open(frame)?;
+            if frame.msg_type == proto::server::REPLY {
+                return serde_json::from_slice(&frame.payload)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e));
+            }
+        }
+    }
+}
+
+/// Encrypt the fresh connection `stream` to the daemon on `socket` if its
+/// session has a key; otherwise frames go out as they are.
+fn secure(stream: &mut UnixStream, socket: &Path) -> io::Result<Wire> {
+    let Some(key) = session_key::for_socket(socket)? else {
+        return Ok(Wire::default());
+    };
+    let wanted = HelloRequest {
+        encrypt: Some(key),
+        ..HelloRequest::default()
+    };
+    Ok(handshake(stream, wanted)?.wire)
+}
+
+/// How frames travel after `HELLO`: the negotiated framing and, on an
+/// encrypted session, the connection's cipher.
+#[derive(Debug, Clone, Default)]
+pub struct Wire {
+    pub framing: proto::Framing,
+    pub cipher: Option<proto::crypto::Channel>,
+}
+
+impl Wire {
+    /// A frame, as this connection sends it.
+    pub fn encode(&mut self, msg_type: u8, payload: &[u8]) -> Vec<u8> {
+        self.frames(&proto::encode(msg_type, payload))
+    }
+
+    /// Plainly encoded `frames`, as this connection sends them.
+    pub fn frames(&mut self, frames: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        match &mut self.cipher {
+            Some(cipher) => {
+                let mut sealed = Vec::new();
+                cipher.seal_frames(frames, &mut sealed);
+                self.framing.append(&sealed, &mut out);
+            }
+            None => self.framing.append(frames, &mut out),
+        }
+        out
+    }
+
+    /// A received frame with its payload opened. Frames have to be opened
+    /// in the order they arrived.
+    pub fn open(&mut self, frame: proto::Frame) -> io::Result<proto::Frame> {
+        let Some(cipher) = &mut self.cipher else {
+            return Ok(frame);
+        };
+        let payload = cipher
+            .open(frame.msg_type, &frame.payload)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(proto::Frame {
+            msg_type: frame.msg_type,
+            payload,
+        })
+    }
+}
+
+/// Outcome of the `HELLO` handshake.
+#[derive(Debug, Clone)]
+pub struct Handshake {
+    /// Protocol version both ends speak.
+    pub version: u16,
+    /// The daemon agreed to send zstd-compressed output.
+    pub compression: bool,
+    /// The daemon sends `OUTPUT_SEQ` stream positions.
+    pub output_seq: bool,
+    /// Output window the daemon granted: it holds output back while this
+    /// many bytes of it are not returned with `CREDIT`.
+    pub window: Option<u64>,
+    /// The daemon sends `FRAME_MARK` ahead of each output frame.
+    pub frame_marks: bool,
+    /// How frames travel from now on, in both directions.
+    pub wire: Wire,
+}
+
+/// What a client asks for in `HELLO` besides the protocol version.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct HelloRequest {
+    /// zstd-compressed output.
+    pub compression: bool,
+    /// `OUTPUT_SEQ` stream positions.
+    pub output_seq: bool,
+    /// Stream position to resume from instead of taking the attach
+    /// snapshot; implies `output_seq`.
+    pub resume: Option<u64>,
+    /// How the child ended and what it used, in `EXIT`.
+    pub exit_details: bool,
+    /// CRC32-checked frames, for sockets tunnelled over transports that
+    /// can corrupt data.
+    pub checksum: bool,
+    /// Session key to encrypt the connection with. The handshake fails if
+    /// the daemon does not agree.
+    pub encrypt: Option<[u8; proto::crypto::KEY_LEN]>,
+    /// Output window, in bytes, for the client to return with `CREDIT` as
+    /// it passes output on.
+    pub window: Option<u64>,
+    /// Token from `pterm share` to attach to another user's session with.
+    pub share: Option<[u8; share::TOKEN_LEN]>,
+    /// `FRAME_MARK` ahead of each output frame, for `--debug-frames`.
+    pub frame_marks: bool,
+}
+
+/// How a client wants its attach snapshot replayed (`client::ATTACH`).
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct AttachRequest {
+    /// No snapshot: the client already shows the session.
+    pub skip_replay: bool,
+    /// Newest scrollback lines to replay ahead of the screen.
+    pub scrollback_lines: usize,
+    /// Limit on the size of the replayed scrollback.
+    pub scrollback_bytes: Option<usize>,
+}
+
+impl AttachRequest {
+    /// The `ATTACH` frame asking for this, or `None` for the default
+    /// replay, which needs none.
+    pub fn frame(&self) -> Option<Vec<u8>> {
+        if *self == Self::default() {
+            return None;
+        }
+        let mut request = serde_json::json!({});
+        if self.skip_replay {
+            request["skip_replay"] = true.into();
+        }
+        if self.scrollback_lines > 0 {
+            request["scrollback_lines"] = self.scrollback_lines.into();
+        }
+        if let Some(bytes) = self.scrollback_bytes {
+            request["scrollback_bytes"] = bytes.into();
+        }
+        Some(proto::encode(
+            proto::client::ATTACH,
+            request.to_string().as_bytes(),
+        ))
+    }
+}
+
+/// Negotiate the protocol version on a freshly connected `stream`, before
+/// anything else is sent on it. Returns the version both ends speak, or an
+/// error naming both versions if they have none in common. A daemon that
+/// predates the handshake answers with an error `REPLY` (or, older still,
+/// not at all) and speaks protocol 1.
+pub fn hello(stream: &mut UnixStream) -> io::Result<u16> {
+    handshake(stream, HelloRequest::default()).map(|handshake| handshake.version)
+}
+
+/// [`hello`], also asking for what `wanted` lists. Daemons that do not
+/// know one of those ignore it.
+pub fn handshake(stream: &mut UnixStream, wanted: HelloRequest) -> io::Result<Handshake> {
+    let mut request = serde_json::json!({
+        "version": proto::PROTOCOL_VERSION,
+        "min_version": proto::MIN_PROTOCOL_VERSION,
+    });
+    if wanted.compression {
+        request["compression"] = serde_json::json!([proto::COMPRESSION_ZSTD]);
+    }
+    if wanted.output_seq || wanted.resume.is_some() {
+        request["seq"] = true.into();
+    }
+    if let Some(seq) = wanted.resume {
+        request["resume"] = seq.into();
+    }
+    if wanted.exit_details {
+        request["exit_details"] = true.into();
+    }
+    if wanted.checksum {
+        request["checksum"] = serde_json::json!([proto::CHECKSUM_CRC32]);
+    }
+    if let Some(window) = wanted.window {
+        request["window"] = window.into();
+    }
+    if wanted.frame_marks {
+        request["frame_marks"] = true.into();
+    }
+    if let Some(token) = &wanted.share {
+        request["share"] = session_key::to_hex(token).into();
+    }
+    let encrypt = match wanted.encrypt {
+        Some(key) => Some((key, session_key::random()?)),
+        None => None,
+    };
+    if let Some((_, client_nonce)) = &encrypt {
+        request["encrypt"] = serde_json::json!({
+            "cipher": proto::ENCRYPT_CHACHA20_POLY1305,
+            "nonce": session_key::to_hex(client_nonce),
+        });
+    }
+    stream.write_all(&proto::encode(
+        proto::client::HELLO,
+        request.to_string().as_bytes(),
+    ))?;
+    let previous_timeout = stream.read_timeout()?;
+    stream.set_read_timeout(Some(REPLY_TIMEOUT))?;
+    let answer = read_hello_answer(stream);
+    stream.set_read_timeout(previous_timeout)?;
+
+    let Some(answer) = answer? else {
+        if encrypt.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "daemon did not answer HELLO, so the connection cannot be encrypted",
+            ));
+        }
+        log::warn!("Daemon did not answer HELLO, assuming protocol 1");
+        return Ok(Handshake {
+            version: 1,
+            compression: false,
+            output_seq: false,
+            window: None,
+            frame_marks: false,
+            wire: Wire::default(),
+        });
+    };
+    let version = answer["version"].as_u64().unwrap_or(0);
+    if let Some(error) = answer["error"]
+        .as_str()
+        .filter(|_| answer["version"].is_null())
+    {
+        // Refused for something other than the version, e.g. the token.
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            error.to_string(),
+        ));
+    }
+    if let Some(error) = answer["error"].as_str() {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!(
+                "incompatible daemon: {} (this pterm speaks protocol {} to {}); \
+                 restart the session or use a matching pterm binary",
+                error,
+                proto::MIN_PROTOCOL_VERSION,
+                proto::PROTOCOL_VERSION
+            ),
+        ));
+    }
+    let cipher = match encrypt {
+        Some((key, client_nonce)) => {
+            let server_nonce = answer["encrypt"]["nonce"]
+                .as_str()
+                .and_then(session_key::from_hex)
+                .filter(|_| answer["encrypt"]["cipher"] == proto::ENCRYPT_CHACHA20_POLY1305)
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::Unsupported,
+                        "the daemon did not agree to encrypt the connection \
+                         (a daemon from before encrypted sessions?)",
+                    )
+                })?;
+            Some(proto::crypto::Channel::client(
+                &key,
+                &client_nonce,
+                &server_nonce,
+            ))
+        }
+        None => None,
+    };
+    match u16::try_from(version) {
+        Ok(version) if proto::negotiate_version(version, version).is_some() => Ok(Handshake {
+            version,
+            compression: wanted.compression && answer["compression"] == proto::COMPRESSION_ZSTD,
+            output_seq: answer["seq"] == true,
+            window: answer["window"]
+                .as_u64()
+                .filter(|_| wanted.window.is_some()),
+            frame_marks: answer["frame_marks"] == true,
+            wire: Wire {
+                framing: if wanted.checksum && answer["checksum"] == proto::CHECKSUM_CRC32 {
+                    proto::Framing::Crc32
+                } else {
+                    proto::Framing::Plain
+                },
+                cipher,
+            },
+        }),
+        _ => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!(
+                "incompatible daemon: it answered with protocol {}, this pterm speaks {} to {}",
+                version,
+                proto::MIN_PROTOCOL_VERSION,
+                proto::PROTOCOL_VERSION
+            ),
+        )),
+    }
+}
+
+/// The daemon's `HELLO`, as `{"version"}` or `{"error", ...}`, or
+/// `{"version": 1}` for an error `REPLY` from a daemon without the
+/// handshake. `None` if nothing came back in time. Frames are read one at a
+/// time so nothing after the answer is consumed.
+fn read_hello_answer(stream: &mut UnixStream) -> io::Result<Option<serde_json::Value>> {
+    loop {
+        let mut header = [0u8; proto::HEADER_SIZE];
+        match stream.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                return Ok(None);
+            }
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "daemon closed the connection during the protocol handshake",
+                ));
+            }
+            Err(e) => return Err(e),
+        }
+        let (msg_type, len) = proto::decode_header(&header);
+        let mut payload = vec![0u8; len as usize];
+        stream.read_exact(&mut payload)?;
+        match msg_type {
+            proto::server::HELLO => {
+                return serde_json::from_slice(&payload)
+                    .map(Some)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e));
+            }
+            proto::server::REPLY => return Ok(Some(serde_json::json!({ "version": 1 }))),
+            _ => log::debug!("Ignoring 0x{:02x} before the HELLO answer", msg_type),
+        }
+    }
+}
@@ -0,0 +1,241 @@
+//! `pterm doctor [--fix] [--json]`: find what keeps sessions from being
+//! created, listed or reached, and with `--fix` repair it:
+//!
+//! - sockets whose daemon is gone (connecting is refused), which make
+//!   `pterm new` refuse the name;
+//! - sockets of daemons from before session directories, at `<root>/<name>`
+//!   instead of `<root>/<name>/socket`;
+//! - socket roots and session directories that group or others can write
+//!   to, which pterm refuses to use;
+//! - `meta.json` files that no longer parse, rewritten with whatever fields
+//!   of them still do.
+//!
+//! Each finding names the path and the problem, and, once fixed, exactly
+//! what was changed. Some problems (a root owned by another user, a daemon
+//! from before session directories that is still running) are only
+//! reported.
+
+use crate::meta::{self, SessionMeta};
+use crate::paths::{self, SOCKET_FILENAME};
+use std::io;
+use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+
+/// A problem found, and how `--fix` repairs it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Finding {
+    pub path: PathBuf,
+    pub problem: String,
+    /// `None` for problems that are only reported.
+    pub fix: Option<Fix>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Fix {
+    /// Remove the socket of a daemon that is gone.
+    RemoveSocket,
+    /// Remove the socket a daemon from before session directories left
+    /// where the session directory belongs.
+    RemoveLegacySocket,
+    /// Set the directory's permission bits to this mode.
+    Chmod(u32),
+    /// Replace `meta.json` with this, keeping the listed fields.
+    RewriteMeta(Box<SessionMeta>, Vec<String>),
+}
+
+impl Fix {
+    /// What applying the fix does, e.g. `chmod 700`.
+    pub fn describe(&self) -> String {
+        match self {
+            Fix::RemoveSocket => "remove the stale socket".to_string(),
+            Fix::RemoveLegacySocket => {
+                "remove the socket so the session directory can be created".to_string()
+            }
+            Fix::Chmod(mode) => format!("chmod {:o}", mode),
+            Fix::RewriteMeta(_, kept) if kept.is_empty() => {
+                "rewrite meta.json from scratch".to_string()
+            }
+            Fix::RewriteMeta(_, kept) => {
+                format!("rewrite meta.json, keeping {}", kept.join(", "))
+            }
+        }
+    }
+
+    pub fn apply(&self, path: &Path) -> io::Result<()> {
+        match self {
+            Fix::RemoveSocket => std::fs::remove_file(path),
+            Fix::RemoveLegacySocket => paths::remove_legacy_socket(path).map(|_| ()),
+            Fix::Chmod(mode) => {
+                std::fs::set_permissions(path, std::fs::Permissions::from_mode(*mode))
+            }
+            Fix::RewriteMeta(recovered, _) => {
+                meta::write(path.parent().unwrap_or(Path::new(".")), recovered)
+            }
+        }
+    }
+}
+
+/// Look through every socket root.
+pub fn check() -> io::Result<Vec<Finding>> {
+    let mut findings = Vec::new();
+    for (_, root) in paths::socket_roots() {
+        if !root.exists() {
+            continue;
+        }
+        if let Err(e) = paths::check_socket_dir(&root) {
+            let meta = std::fs::metadata(&root)?;
+            let fixable = meta.is_dir() && meta.uid() == nix::unistd::geteuid().as_raw();
+            findings.push(Finding {
+                path: root.clone(),
+                problem: e.to_string(),
+                fix: fixable.then_some(Fix::Chmod(meta.mode() & 0o777 & !0o077)),
+            });
+        }
+        if root.is_dir() {
+            check_dir(&root, &root, &mut findings)?;
+        }
+    }
+    Ok(findings)
+}
+
+/// Check the session directories below `dir` in socket root `root`.
+fn check_dir(root: &Path, dir: &Path, findings: &mut Vec<Finding>) -> io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let meta = std::fs::symlink_metadata(&path)?;
+        let file_type = meta.file_type();
+        if file_type.is_socket() && dir == root && !path.ends_with(SOCKET_FILENAME) {
+            let live = UnixStream::connect(&path).is_ok();
+            findings.push(Finding {
+                path,
+                problem: if live {
+                    "a daemon from before session directories is still listening here; \
+                     stop it with the pterm that started it"
+                        .to_string()
+                } else {
+                    "socket of a daemon from before session directories".to_string()
+                },
+                fix: (!live).then_some(Fix::RemoveLegacySocket),
+            });
+        } else if file_type.is_dir() {
+            if meta.mode() & 0o022 != 0 {
+                findings.push(Finding {
+                    path: path.clone(),
+                    problem: format!(
+                        "session directory is writable by group or others (mode {:o})",
+                        meta.mode() & 0o777
+                    ),
+                    fix: Some(Fix::Chmod(meta.mode() & 0o777 & !0o022)),
+                });
+            }
+            check_session(root, &path, findings);
+            check_dir(root, &path, findings)?;
+        }
+    }
+    Ok(())
+}
+
+/// Check the socket and metadata of the session directory `dir`, if it is
+/// one.
+fn check_session(root: &Path, dir: &Path, findings: &mut Vec<Finding>) {
+    let socket = dir.join(SOCKET_FILENAME);
+    let is_socket = std::fs::symlink_metadata(&socket).is_ok_and(|m| m.file_type().is_socket());
+    let live = match UnixStream::connect(&socket) {
+        Ok(stream) => Some(stream),
+        Err(e) if is_socket && e.kind() == io::ErrorKind::ConnectionRefused => {
+            findings.push(Finding {
+                path: socket.clone(),
+                problem: "socket of a daemon that is gone".to_string(),
+                fix: Some(Fix::RemoveSocket),
+            });
+            None
+        }
+        Err(_) => None,
+    };
+
+    let meta_path = dir.join(meta::META_FILENAME);
+    let Ok(data) = std::fs::read(&meta_path) else {
+        return;
+    };
+    let Err(e) = serde_json::from_slice::<SessionMeta>(&data) else {
+        return;
+    };
+    let name = dir
+        .strip_prefix(root)
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let (mut recovered, kept) = recover_meta(&data, &name);
+    if recovered.pid == 0 {
+        recovered.pid = live.as_ref().map_or(0, daemon_pid);
+    }
+    findings.push(Finding {
+        path: meta_path,
+        problem: format!("malformed metadata: {}", e),
+        fix: Some(Fix::RewriteMeta(Box::new(recovered), kept)),
+    });
+}
+
+/// Pid of the daemon at the other end of `stream`, or 0.
+fn daemon_pid(stream: &UnixStream) -> i32 {
+    use nix::sys::socket::{getsockopt, sockopt::PeerCredentials};
+    getsockopt(stream, PeerCredentials).map_or(0, |cred| cred.pid())
+}
+
+/// Metadata for session `name` built from the fields of the malformed
+/// `data` that still parse, and the names of those fields.
+fn recover_meta(data: &[u8], name: &str) -> (SessionMeta, Vec<String>) {
+    let fresh = SessionMeta {
+        session: name.to_string(),
+        ..SessionMeta::default()
+    };
+    let mut merged = serde_json::to_value(&fresh).unwrap_or_default();
+    let found = match serde_json::from_slice(data) {
+        Ok(serde_json::Value::Object(found)) => found,
+        _ => return (fresh, Vec::new()),
+    };
+    for (key, value) in &found {
+        if key == "session" {
+            continue;
+        }
+        let mut candidate = merged.clone();
+        candidate[key] = value.clone();
+        if serde_json::from_value::<SessionMeta>(candidate.clone()).is_ok() {
+            merged = candidate;
+        }
+    }
+    let recovered: SessionMeta = serde_json::from_value(merged).unwrap_or(fresh);
+    // Fields this build does not know are dropped, so name only the ones
+    // that made it into the new file.
+    let written = serde_json::to_value(&recovered).unwrap_or_default();
+    let kept = found
+        .iter()
+        .filter(|(key, value)| *key != "session" && written.get(key.as_str()) == Some(value))
+        .map(|(key, _)| key.clone())
+        .collect();
+    (recovered, kept)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recovers_the_fields_that_still_parse() {
+        let data = br#"{"session": "old", "command": ["sh"], "pid": "oops", "created_at": 7, "future": 1}"#;
+        let (meta, kept) = recover_meta(data, "dev");
+        assert_eq!(meta.session, "dev");
+        assert_eq!(meta.command, ["sh"]);
+        assert_eq!(meta.pid, 0);
+        assert_eq!(meta.created_at, 7);
+        assert_eq!(kept, ["command", "created_at"]);
+
+        let (meta, kept) = recover_meta(b"{\"session\": \"dev\", \"pid\": 1", "dev");
+        assert_eq!(meta.session, "dev");
+        assert!(kept.is_empty());
+        assert_eq!(
+            Fix::RewriteMeta(Box::new(meta), vec!["pid".to_string()]).describe(),
+            "rewrite meta.json, keeping pid"
+        );
+    }
+}
@@ -0,0 +1,144 @@
+//! `--as-root`: run `pterm new/attach/open` for a root-owned session through
+//! a privilege wrapper (`doas`, `sudo`, `pkexec`) instead of a setuid
+//! binary or a socket other users can reach.
+//!
+//! The wrapped pterm runs as root against a root-only socket root, so the
+//! daemon and its socket stay inaccessible to the invoking user; only the
+//! wrapper's own authentication grants access, once per attach.
+
+use crate::config::{self, Config};
+use std::io;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Wrappers tried, in order, when none is configured.
+const DEFAULT_WRAPPERS: [&str; 3] = ["doas", "sudo", "pkexec"];
+
+/// Socket root for root sessions when `root_socket_dir` is not configured
+/// (what root resolves to by default, without `XDG_RUNTIME_DIR`).
+pub const DEFAULT_ROOT_SOCKET_DIR: &str = "/tmp/pterm-0";
+
+/// Privilege wrapper: `$PTERM_ROOT_WRAPPER`, then `root_wrapper` from the
+/// config file, then the first of `doas`, `sudo`, `pkexec` on `$PATH`.
+pub fn wrapper(config: &Config) -> Result<Vec<String>, String> {
+    if let Ok(command) = std::env::var("PTERM_ROOT_WRAPPER") {
+        let words = config::split_words(&command)?;
+        if !words.is_empty() {
+            return Ok(words);
+        }
+    }
+    if let Some(command) = config.root_wrapper.as_ref().filter(|c| !c.is_empty()) {
+        return Ok(command.clone());
+    }
+    DEFAULT_WRAPPERS
+        .iter()
+        .find(|name| in_path(name))
+        .map(|name| vec![name.to_string()])
+        .ok_or_else(|| "no privilege wrapper found (doas, sudo or pkexec)".to_string())
+}
+
+fn in_path(name: &str) -> bool {
+    std::env::var_os("PATH")
+        .is_some_and(|path| std::env::split_paths(&path).any(|dir| dir.join(name).is_file()))
+}
+
+/// Command line that reruns `pterm <command> <args>` as root against
+/// `socket_dir`. The first `--as-root` in `args` is dropped; anything after
+/// it (including the session's own command) is passed through unchanged.
+pub fn wrapped_command(
+    wrapper: &[String],
+    pterm: &Path,
+    socket_dir: &Path,
+    command: &str,
+    args: &[String],
+) -> Vec<String> {
+    let mut line = wrapper.to_vec();
+    line.push(pterm.display().to_string());
+    line.push("--socket-dir".to_string());
+    line.push(socket_dir.display().to_string());
+    line.push(command.to_string());
+    let mut dropped = false;
+    for arg in args {
+        if !dropped && arg == "--as-root" {
+            dropped = true;
+            continue;
+        }
+        line.push(arg.clone());
+    }
+    line
+}
+
+/// Replace this process with the wrapped `pterm <command>`. Only returns on
+/// error. The socket root is `root_socket_dir` from the config, else
+/// [`DEFAULT_ROOT_SOCKET_DIR`].
+pub fn exec_as_root(command: &str, args: &[String]) -> io::Error {
+    let (config, wrapper, pterm) = match prepare() {
+        Ok(prepared) => prepared,
+        Err(e) => return e,
+    };
+    let socket_dir = config
+        .root_socket_dir
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_ROOT_SOCKET_DIR));
+    let line = wrapped_command(&wrapper, &pterm, &socket_dir, command, args);
+    exec(line)
+}
+
+/// Replace this process with `<wrapper> <pterm> <args>`, for a single step
+/// that needs root, such as handing a session over with `pterm transfer`.
+/// Only returns on error.
+pub fn exec_root_helper(args: &[String]) -> io::Error {
+    let (_, wrapper, pterm) = match prepare() {
+        Ok(prepared) => prepared,
+        Err(e) => return e,
+    };
+    let mut line = wrapper;
+    line.push(pterm.display().to_string());
+    line.extend(args.iter().cloned());
+    exec(line)
+}
+
+/// The config, the privilege wrapper and this executable.
+fn prepare() -> io::Result<(Config, Vec<String>, PathBuf)> {
+    let config = Config::load()?;
+    let wrapper = wrapper(&config).map_err(|e| io::Error::new(io::ErrorKind::NotFound, e))?;
+    Ok((config, wrapper, std::env::current_exe()?))
+}
+
+fn exec(line: Vec<String>) -> io::Error {
+    let err = Command::new(&line[0]).args(&line[1..]).exec();
+    io::Error::new(err.kind(), format!("{}: {}", line[0], err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wrapped_command_drops_as_root_once() {
+        let args: Vec<String> = ["--as-root", "admin", "--", "sh", "--as-root"]
+            .map(String::from)
+            .to_vec();
+        assert_eq!(
+            wrapped_command(
+                &["doas".to_string(), "-n".to_string()],
+                Path::new("/usr/bin/pterm"),
+                Path::new("/run/pterm"),
+                "new",
+                &args,
+            ),
+            [
+                "doas",
+                "-n",
+                "/usr/bin/pterm",
+                "--socket-dir",
+                "/run/pterm",
+                "new",
+                "admin",
+                "--",
+                "sh",
+                "--as-root"
+            ]
+        );
+    }
+}
@@ -0,0 +1,156 @@
+//! The error type of the CLI commands. Each variant carries what went wrong
+//! where (the session, the request the daemon refused, the path), so that
+//! `main` renders every failure the same way: as `Error: <message>` on
+//! stderr, or, for a command given `--json`, as one JSON object on stdout
+//! with the message, its `kind` and that context.
+//!
+//! The daemon answers a refused request with a `REPLY` carrying `"error"`;
+//! [`control::call`](crate::control::call) turns that into
+//! [`Error::Refused`] naming the request.
+
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Whether the command asked for JSON errors; see [`report_as_json`].
+static JSON: AtomicBool = AtomicBool::new(false);
+
+/// Have errors reported as JSON. Called by a command that parsed `--json`
+/// among its own options, so one meant for a child command does not count.
+pub fn report_as_json() {
+    JSON.store(true, Ordering::Relaxed);
+}
+
+/// Whether [`report_as_json`] was called.
+pub fn json_requested() -> bool {
+    JSON.load(Ordering::Relaxed)
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// The command line does not say what to do.
+    #[error("{0}")]
+    Usage(String),
+    #[error("session '{0}' not found")]
+    SessionNotFound(String),
+    /// The daemon of `session` answered `request` with an error.
+    #[error("{message}")]
+    Refused {
+        session: String,
+        request: &'static str,
+        message: String,
+    },
+    /// A file the command was pointed at could not be used.
+    #[error("{}: {source}", path.display())]
+    Path {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    /// The command could not do what it was asked to.
+    #[error("{0}")]
+    Failed(String),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+impl Error {
+    /// Short, stable name of the variant for `--json` output.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::Usage(_) => "usage",
+            Error::SessionNotFound(_) => "session_not_found",
+            Error::Refused { .. } => "refused",
+            Error::Path { .. } => "path",
+            Error::Failed(_) => "failed",
+            Error::Io(_) => "io",
+        }
+    }
+
+    /// `{"error": message, "kind", ...}` with the context the variant
+    /// carries.
+    pub fn to_json(&self) -> serde_json::Value {
+        let mut json = serde_json::json!({
+            "error": self.to_string(),
+            "kind": self.kind(),
+        });
+        match self {
+            Error::SessionNotFound(session) => json["session"] = session.as_str().into(),
+            Error::Refused {
+                session, request, ..
+            } => {
+                json["session"] = session.as_str().into();
+                json["request"] = (*request).into();
+            }
+            Error::Path { path, .. } => json["path"] = path.to_string_lossy().into(),
+            _ => {}
+        }
+        json
+    }
+
+    /// Print the error for the user, as JSON with `json`.
+    pub fn report(&self, json: bool) {
+        if json {
+            println!("{}", self.to_json());
+        } else {
+            eprintln!("Error: {}", self);
+        }
+    }
+}
+
+/// Wrap the errors of using `path` with it.
+pub trait PathContext<T> {
+    fn at_path(self, path: impl Into<PathBuf>) -> Result<T>;
+}
+
+impl<T> PathContext<T> for io::Result<T> {
+    fn at_path(self, path: impl Into<PathBuf>) -> Result<T> {
+        self.map_err(|source| Error::Path {
+            path: path.into(),
+            source,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_message_kind_and_context() {
+        let refused = Error::Refused {
+            session: "dev".to_string(),
+            request: "SIGNAL",
+            message: "the command has already exited".to_string(),
+        };
+        assert_eq!(refused.to_string(), "the command has already exited");
+        assert_eq!(
+            refused.to_json(),
+            serde_json::json!({
+                "error": "the command has already exited",
+                "kind": "refused",
+                "session": "dev",
+                "request": "SIGNAL",
+            })
+        );
+
+        let missing = std::fs::read("/nonexistent/pterm").at_path("/nonexistent/pterm");
+        let error = missing.unwrap_err();
+        assert!(error
+            .to_string()
+            .starts_with("/nonexistent/pterm: No such file"));
+        assert_eq!(error.to_json()["path"], "/nonexistent/pterm");
+
+        let error = Error::from(io::Error::other("boom"));
+        assert_eq!(
+            error.to_json(),
+            serde_json::json!({"error": "boom", "kind": "io"})
+        );
+        assert_eq!(
+            Error::SessionNotFound("dev".to_string()).to_json()["session"],
+            "dev"
+        );
+    }
+}
@@ -0,0 +1,219 @@
+//! `pterm exec`: type a command line into a session's shell and exit with
+//! the command's status, so scripts can compose sessions with `&&`.
+//!
+//! The status comes back in the session's output as an OSC 133 `D` (command
+//! finished) mark. By default the typed line ends with a `printf` that emits
+//! one tagged with an `aid` unique to the request; with `--no-wrap` the
+//! line is typed as is and the first `D` mark of the shell's own
+//! integration after it counts. The daemon scans its PTY output for these
+//! marks and answers the requesting client with `server::EXEC_STATUS`.
+
+use crate::paths::session_socket_path;
+use crate::schedule;
+use pterm_proto as proto;
+use std::io::{self, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::time::{Duration, Instant};
+
+const MARK: &[u8] = b"\x1b]133;D";
+
+/// Longest unterminated mark kept across PTY reads.
+const MAX_CARRY: usize = 256;
+
+/// The `aid` of the mark ending exec request `id`.
+pub fn aid(id: u32) -> String {
+    format!("pterm-exec-{}", id)
+}
+
+/// The line typed into the shell: the shell-quoted command, followed by a
+/// `printf` of the status mark when `aid` is given (POSIX shells), and Enter.
+pub fn typed_line(command: &[String], aid: Option<&str>) -> String {
+    let line = schedule::shell_join(command);
+    match aid {
+        Some(aid) => format!(
+            "{}; printf '\\033]133;D;%s;aid={}\\007' \"$?\"\r",
+            line, aid
+        ),
+        None => format!("{}\r", line),
+    }
+}
+
+/// A finished command reported by an OSC 133 `D` mark.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Status {
+    /// Exit status; a mark without one counts as success.
+    pub code: i32,
+    pub aid: Option<String>,
+}
+
+/// Finds `ESC ] 133 ; D [; <status>] [; aid=<id>] (BEL | ESC \)` marks in
+/// PTY output, including marks split across reads.
+#[derive(Debug, Default)]
+pub struct StatusScanner {
+    carry: Vec<u8>,
+}
+
+impl StatusScanner {
+    pub fn feed(&mut self, data: &[u8]) -> Vec<Status> {
+        let mut buf = std::mem::take(&mut self.carry);
+        buf.extend_from_slice(data);
+        let mut statuses = Vec::new();
+        let mut pos = 0;
+        while let Some(start) = find(&buf[pos..], MARK).map(|i| pos + i) {
+            let body = start + MARK.len();
+            let Some((len, terminator)) = terminator(&buf[body..]) else {
+                if buf.len() - start <= MAX_CARRY {
+                    self.carry = buf[start..].to_vec();
+                }
+                return statuses;
+            };
+            if let Some(status) = parse(&buf[body..body + len]) {
+                statuses.push(status);
+            }
+            pos = body + len + terminator;
+        }
+        // Keep a tail that could be the start of a mark.
+        let keep = (1..MARK.len())
+            .rev()
+            .find(|&k| buf[pos..].ends_with(&MARK[..k]))
+            .unwrap_or(0);
+        self.carry = buf[buf.len() - keep..].to_vec();
+        statuses
+    }
+
+    pub fn reset(&mut self) {
+        self.carry.clear();
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+/// Length of the mark's parameters and of its terminator.
+fn terminator(data: &[u8]) -> Option<(usize, usize)> {
+    data.iter().enumerate().find_map(|(i, &b)| match b {
+        0x07 => Some((i, 1)),
+        0x1b if data.get(i + 1) == Some(&b'\\') => Some((i, 2)),
+        _ => None,
+    })
+}
+
+/// Parameters after `D`: empty, or `;`-separated status and `key=value`s.
+fn parse(params: &[u8]) -> Option<Status> {
+    let params = std::str::from_utf8(params).ok()?;
+    if !(params.is_empty() || params.starts_with(';')) {
+        return None;
+    }
+    let mut status = Status { code: 0, aid: None };
+    for param in params.split(';').skip(1) {
+        if let Some(aid) = param.strip_prefix("aid=") {
+            status.aid = Some(aid.to_string());
+        } else if let Ok(code) = param.parse() {
+            status.code = code;
+        }
+    }
+    Some(status)
+}
+
+/// Ask the daemon of session `name` to run `command` and wait for its
+/// `EXEC_STATUS`. `Ok(None)` means `timeout` elapsed first.
+pub fn run(
+    name: &str,
+    command: &[String],
+    wrap: bool,
+    timeout: Option<Duration>,
+) -> io::Result<Option<i32>> {
+    let mut stream = UnixStream::connect(session_socket_path(name))?;
+    let request = serde_json::json!({ "command": command, "wrap": wrap });
+    stream.write_all(&proto::encode(
+        proto::client::EXEC,
+        request.to_string().as_bytes(),
+    ))?;
+
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    let mut decoder = proto::FrameDecoder::new();
+    let mut buf = [0u8; 65536];
+    loop {
+        if let Some(deadline) = deadline {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Ok(None);
+            }
+            stream.set_read_timeout(Some(left))?;
+        }
+        let n = match stream.read(&mut buf) {
+            Ok(n) => n,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                return Ok(None);
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        if n == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "the daemon closed the connection before the command finished",
+            ));
+        }
+        for frame in decoder.decode(&buf[..n]) {
+            let value = || -> io::Result<serde_json::Value> {
+                serde_json::from_slice(&frame.payload)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+            };
+            match frame.msg_type {
+                proto::server::REPLY => {
+                    if let Some(error) = value()?["error"].as_str() {
+                        return Err(io::Error::other(error.to_string()));
+                    }
+                }
+                proto::server::EXIT => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "the session's command exited before the command finished",
+                    ));
+                }
+                proto::server::EXEC_STATUS => {
+                    return Ok(Some(value()?["status"].as_i64().unwrap_or(1) as i32));
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scanner_finds_marks_across_reads() {
+        let mut scanner = StatusScanner::default();
+        assert!(scanner.feed(b"make: *** Error 2\r\n\x1b]13").is_empty());
+        assert_eq!(
+            scanner.feed(b"3;D;2;aid=pterm-exec-1\x07\x1b]133;A\x07$ "),
+            [Status {
+                code: 2,
+                aid: Some(aid(1))
+            }]
+        );
+        assert_eq!(
+            scanner.feed(b"\x1b]133;D\x1b\\\x1b]133;D;0\x1b"),
+            [Status { code: 0, aid: None }]
+        );
+        assert_eq!(
+            scanner.feed(b"\\\x1b]133;DX\x07"),
+            [Status { code: 0, aid: None }]
+        );
+
+        assert_eq!(
+            typed_line(&["make".to_string(), "a b".to_string()], Some("x")),
+            "make 'a b'; printf '\\033]133;D;%s;aid=x\\007' \"$?\"\r"
+        );
+    }
+}
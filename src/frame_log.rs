@@ -0,0 +1,206 @@
+//! `pterm attach --debug-frames <logfile>`: log every output frame the
+//! bridge receives against the `FRAME_MARK` the daemon sent ahead of it, to
+//! tell apart why two clients of a session show different screens.
+//!
+//! Each `OUTPUT` / `STATE_SYNC` becomes one JSON line with the daemon's
+//! frame number, the client's mark count (`seq`), the type and the length
+//! and CRC32 of the payload as received, plus a `problem` when the frame
+//! does not add up: marks skipped (`lost`), counting backwards (`out of
+//! order`), a payload that differs from what the daemon marked, or no mark
+//! at all. Logs of two clients that agree frame for frame put the
+//! difference downstream of the bridge, in the terminal that renders them.
+
+use pterm_proto as proto;
+use serde::Deserialize;
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A `FRAME_MARK` payload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+struct Mark {
+    frame: u64,
+    seq: u64,
+    #[serde(rename = "type")]
+    msg_type: u8,
+    len: usize,
+    crc32: u32,
+}
+
+/// Where the frames of one attach are logged.
+pub struct FrameLog<W: Write> {
+    out: W,
+    /// Mark of the frame that comes next.
+    pending: Option<Mark>,
+    /// `seq` of the last marked frame on this connection.
+    last_seq: u64,
+}
+
+impl FrameLog<std::fs::File> {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let file = std::fs::File::create(path).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("cannot create frame log {}: {}", path.display(), e),
+            )
+        })?;
+        Ok(Self::new(file))
+    }
+}
+
+impl<W: Write> FrameLog<W> {
+    fn new(out: W) -> Self {
+        Self {
+            out,
+            pending: None,
+            last_seq: 0,
+        }
+    }
+
+    /// Note `frame`, decompressed; frames other than marks and output are
+    /// skipped.
+    pub fn frame(&mut self, frame: &proto::Frame) -> io::Result<()> {
+        match frame.msg_type {
+            proto::server::FRAME_MARK => {
+                let Ok(mark) = serde_json::from_slice::<Mark>(&frame.payload) else {
+                    return self.line(serde_json::json!({ "problem": "malformed mark" }));
+                };
+                if let Some(orphan) = self.pending.replace(mark) {
+                    self.line(serde_json::json!({
+                        "frame": orphan.frame,
+                        "seq": orphan.seq,
+                        "problem": "mark without a frame",
+                    }))?;
+                }
+                Ok(())
+            }
+            proto::server::OUTPUT | proto::server::STATE_SYNC => self.output(frame),
+            _ => Ok(()),
+        }
+    }
+
+    /// Note that the bridge reconnected: the daemon counts marks for the
+    /// new connection from 1 again.
+    pub fn reconnected(&mut self) -> io::Result<()> {
+        self.pending = None;
+        self.last_seq = 0;
+        self.line(serde_json::json!({ "event": "reconnected" }))
+    }
+
+    fn output(&mut self, frame: &proto::Frame) -> io::Result<()> {
+        let crc32 = proto::crc32(&frame.payload);
+        let mut line = serde_json::json!({
+            "type": proto::ServerMessage::from_u8(frame.msg_type).map(|t| t.name()),
+            "len": frame.payload.len(),
+            "crc32": crc32,
+        });
+        let Some(mark) = self.pending.take() else {
+            line["problem"] = "no mark".into();
+            return self.line(line);
+        };
+        line["frame"] = mark.frame.into();
+        line["seq"] = mark.seq.into();
+        let problem = if mark.seq <= self.last_seq {
+            Some(format!("out of order: after seq {}", self.last_seq))
+        } else if mark.msg_type != frame.msg_type
+            || mark.len != frame.payload.len()
+            || mark.crc32 != crc32
+        {
+            Some(format!(
+                "payload does not match its mark (len {}, crc32 {})",
+                mark.len, mark.crc32
+            ))
+        } else if mark.seq > self.last_seq + 1 {
+            Some(format!("lost {} frame(s)", mark.seq - self.last_seq - 1))
+        } else {
+            None
+        };
+        if let Some(problem) = problem {
+            line["problem"] = problem.into();
+        }
+        self.last_seq = self.last_seq.max(mark.seq);
+        self.line(line)
+    }
+
+    fn line(&mut self, mut fields: serde_json::Value) -> io::Result<()> {
+        let time_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64);
+        fields["time_ms"] = time_ms.into();
+        let mut line = fields.to_string().into_bytes();
+        line.push(b'\n');
+        self.out.write_all(&line)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flags_lost_reordered_and_mismatched_frames() {
+        let mark = |seq: u64, payload: &[u8]| proto::Frame {
+            msg_type: proto::server::FRAME_MARK,
+            payload: serde_json::json!({
+                "frame": 10 + seq,
+                "seq": seq,
+                "type": proto::server::OUTPUT,
+                "len": payload.len(),
+                "crc32": proto::crc32(payload),
+            })
+            .to_string()
+            .into_bytes(),
+        };
+        let output = |payload: &[u8]| proto::Frame {
+            msg_type: proto::server::OUTPUT,
+            payload: payload.to_vec(),
+        };
+        let mut log = FrameLog::new(Vec::new());
+        for frame in [
+            mark(1, b"a"),
+            output(b"a"),
+            mark(3, b"c"),
+            output(b"c"),
+            mark(2, b"b"),
+            output(b"b"),
+            mark(4, b"d"),
+            output(b"x"),
+            output(b"y"),
+            proto::Frame {
+                msg_type: proto::server::PING,
+                payload: Vec::new(),
+            },
+        ] {
+            log.frame(&frame).unwrap();
+        }
+        log.reconnected().unwrap();
+        log.frame(&mark(1, b"e")).unwrap();
+        log.frame(&output(b"e")).unwrap();
+
+        let lines: Vec<serde_json::Value> = String::from_utf8(log.out)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let problems: Vec<_> = lines.iter().map(|l| l["problem"].as_str()).collect();
+        let mismatch = format!(
+            "payload does not match its mark (len 1, crc32 {})",
+            proto::crc32(b"d")
+        );
+        assert_eq!(
+            problems,
+            [
+                None,
+                Some("lost 1 frame(s)"),
+                Some("out of order: after seq 3"),
+                Some(mismatch.as_str()),
+                Some("no mark"),
+                None,
+                None,
+            ]
+        );
+        assert_eq!(lines[0]["frame"], 11);
+        assert_eq!(lines[0]["type"], "OUTPUT");
+        assert_eq!(lines[5]["event"], "reconnected");
+    }
+}
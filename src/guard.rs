@@ -0,0 +1,97 @@
+//! Input guard (`pterm guard`): the daemon holds back the Enter that would
+//! submit a command line matching a dangerous pattern until the client that
+//! typed it confirms.
+//!
+//! The command line is what the terminal shows on the cursor's line when
+//! the input arrives (prompt included, so history recall and completion are
+//! covered) followed by what the input itself types before its Enter. Only
+//! the Enter is held: the characters have already reached the application,
+//! which does not act on them before Enter.
+
+/// Patterns from the `guard` option: `|`-separated substrings.
+pub fn patterns(option: &str) -> impl Iterator<Item = &str> {
+    option.split('|').map(str::trim).filter(|p| !p.is_empty())
+}
+
+/// A held-back Enter: where it is in the input, the line it would submit
+/// and the pattern that line matched.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Guarded {
+    pub offset: usize,
+    pub line: String,
+    pub pattern: String,
+}
+
+/// Find the first Enter (CR or LF) in `input` that submits a line matching
+/// one of the `guard` option's patterns. `screen_line` is the cursor's line
+/// before `input` is applied; later lines of a multi-line `input` start
+/// empty. Escape sequences (cursor keys) are skipped; backspace and `^U`
+/// edit the typed text.
+pub fn find(option: &str, screen_line: &str, input: &[u8]) -> Option<Guarded> {
+    // No patterns, no guard.
+    patterns(option).next()?;
+    let mut line = screen_line.to_string();
+    let mut typed: Vec<u8> = Vec::new();
+    let mut in_escape = false;
+    for (offset, &byte) in input.iter().enumerate() {
+        if in_escape {
+            // CSI / SS3 parameters until the final byte.
+            in_escape = !(byte.is_ascii_alphabetic() || byte == b'~') || byte == b'O';
+            continue;
+        }
+        match byte {
+            b'\r' | b'\n' => {
+                line.push_str(&String::from_utf8_lossy(&typed));
+                if let Some(pattern) = patterns(option).find(|p| line.contains(p)) {
+                    return Some(Guarded {
+                        offset,
+                        line,
+                        pattern: pattern.to_string(),
+                    });
+                }
+                line.clear();
+                typed.clear();
+            }
+            0x1b => in_escape = true,
+            0x7f | 0x08 => {
+                typed.pop();
+            }
+            0x15 => typed.clear(),
+            byte if byte >= 0x20 => typed.push(byte),
+            _ => {}
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_the_enter_of_a_guarded_line() {
+        let option = "rm -rf | DROP TABLE";
+        assert_eq!(
+            find(option, "$ rm -rf /tmp/x", b"\r"),
+            Some(Guarded {
+                offset: 0,
+                line: "$ rm -rf /tmp/x".to_string(),
+                pattern: "rm -rf".to_string(),
+            })
+        );
+        assert_eq!(
+            find(option, "db=> ", b"drop\x7f\x7f\x7f\x7fDROP TABLE t;\r").map(|g| g.offset),
+            Some(21)
+        );
+        // The first line is harmless; the second is not.
+        let guarded = find(option, "$ ", b"ls\rDROP TABLE x\n").unwrap();
+        assert_eq!(
+            (guarded.offset, guarded.line.as_str()),
+            (15, "DROP TABLE x")
+        );
+
+        assert_eq!(find(option, "$ ", b"rm -r\x15ls\r"), None);
+        assert_eq!(find(option, "$ ls", b"\x1b[A"), None);
+        assert_eq!(find("", "$ rm -rf /", b"\r"), None);
+    }
+}
@@ -0,0 +1,94 @@
+//! `pterm here`: a session name for the current project, so shell configs
+//! need no mapping of their own from directories to session names.
+//!
+//! Inside a git work tree the project is the top-level directory of the
+//! repository; elsewhere it is the current directory itself, whose name is
+//! qualified with a hash of its full path so that unrelated directories
+//! called e.g. `src` do not share a session.
+
+use std::path::{Path, PathBuf};
+
+/// Session names created by `pterm here` live below this parent.
+pub const PARENT: &str = "repos";
+
+/// The project containing `cwd`: the nearest ancestor holding `.git` (a
+/// directory, or a file for worktrees and submodules), if any.
+pub fn git_root(cwd: &Path) -> Option<PathBuf> {
+    cwd.ancestors()
+        .find(|dir| dir.join(".git").exists())
+        .map(Path::to_path_buf)
+}
+
+/// `repos/<project>[/<suffix>]` for `cwd`, and the project directory.
+pub fn session_name(cwd: &Path, suffix: Option<&str>) -> (String, PathBuf) {
+    let (project, root) = match git_root(cwd) {
+        Some(root) => (sanitize(&file_name(&root)), root),
+        None => (
+            format!("{}-{:08x}", sanitize(&file_name(cwd)), path_hash(cwd)),
+            cwd.to_path_buf(),
+        ),
+    };
+    let name = match suffix {
+        Some(suffix) => format!("{}/{}/{}", PARENT, project, suffix),
+        None => format!("{}/{}", PARENT, project),
+    };
+    (name, root)
+}
+
+fn file_name(dir: &Path) -> String {
+    dir.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "root".to_string())
+}
+
+/// Keep names usable as a single path component and on a command line:
+/// anything but ASCII alphanumerics, `-`, `_` and `.` becomes `-`.
+fn sanitize(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.') {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect();
+    name.trim_start_matches('.').to_string()
+}
+
+/// 32-bit FNV-1a of the path, stable across runs and builds.
+fn path_hash(path: &Path) -> u32 {
+    path.as_os_str()
+        .as_encoded_bytes()
+        .iter()
+        .fold(0x811c_9dc5, |hash, &b| {
+            (hash ^ b as u32).wrapping_mul(0x0100_0193)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_follow_the_git_root() {
+        let base = std::env::temp_dir().join(format!("pterm-here-test-{}", std::process::id()));
+        let repo = base.join("my repo");
+        std::fs::create_dir_all(repo.join(".git")).unwrap();
+        std::fs::create_dir_all(repo.join("src/deep")).unwrap();
+
+        let (name, root) = session_name(&repo.join("src/deep"), None);
+        assert_eq!((name.as_str(), root), ("repos/my-repo", repo.clone()));
+        assert_eq!(session_name(&repo, Some("test")).0, "repos/my-repo/test");
+
+        let plain = base.join("notes");
+        std::fs::create_dir_all(&plain).unwrap();
+        let (name, root) = session_name(&plain, None);
+        assert!(name.starts_with("repos/notes-"), "{}", name);
+        assert_eq!(name.len(), "repos/notes-".len() + 8);
+        assert_eq!(root, plain);
+        assert_eq!(session_name(&plain, None).0, name);
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+}
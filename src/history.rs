@@ -0,0 +1,417 @@
+//! Compressed archive of a session's whole output (`pterm new --history`),
+//! for logs kept for weeks that are read a slice at a time.
+//!
+//! Output is cut into blocks of at most [`BLOCK_SIZE`] bytes or
+//! [`BLOCK_MAX_AGE`] seconds, each compressed on its own as a zstd frame
+//! appended to `<session_dir>/history/blocks.zst`. For every block,
+//! `history/index` gets a fixed-size record of where the frame is, how many
+//! lines came before it and how many it holds, and when its first and last
+//! bytes were written, so `pterm history --since` / `--from-line` find their
+//! first block without decompressing anything and `--search` decompresses
+//! one block at a time. The open block is kept uncompressed in
+//! `history/tail` (its start time first), so readers see output at once and
+//! a hot upgrade or restart picks the block up where it was.
+//!
+//! Index record (48 bytes, little-endian): offset u64, compressed length
+//! u32, raw length u32, first line u64, line count u32, 4 bytes padding,
+//! start time u64, end time u64 (unix seconds).
+
+use crate::scrollback::SgrStripper;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// History directory within a session directory.
+pub const HISTORY_DIRNAME: &str = "history";
+
+const BLOCKS_FILENAME: &str = "blocks.zst";
+const INDEX_FILENAME: &str = "index";
+const TAIL_FILENAME: &str = "tail";
+
+/// Raw bytes per block at most.
+pub const BLOCK_SIZE: usize = 256 * 1024;
+/// Seconds after which an open block is closed, bounding how coarse
+/// `--since` is.
+pub const BLOCK_MAX_AGE: u64 = 60;
+
+/// Longest line kept by `--search`; the rest of a longer line is dropped.
+const MAX_LINE: usize = 64 * 1024;
+
+const RECORD_SIZE: usize = 48;
+const COMPRESSION_LEVEL: i32 = 3;
+
+/// Index record of a compressed block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Block {
+    pub offset: u64,
+    pub compressed_len: u32,
+    pub raw_len: u32,
+    /// Lines completed before the block.
+    pub first_line: u64,
+    /// Newlines in the block.
+    pub lines: u32,
+    pub start: u64,
+    pub end: u64,
+}
+
+impl Block {
+    fn encode(&self) -> [u8; RECORD_SIZE] {
+        let mut record = [0u8; RECORD_SIZE];
+        record[0..8].copy_from_slice(&self.offset.to_le_bytes());
+        record[8..12].copy_from_slice(&self.compressed_len.to_le_bytes());
+        record[12..16].copy_from_slice(&self.raw_len.to_le_bytes());
+        record[16..24].copy_from_slice(&self.first_line.to_le_bytes());
+        record[24..28].copy_from_slice(&self.lines.to_le_bytes());
+        record[32..40].copy_from_slice(&self.start.to_le_bytes());
+        record[40..48].copy_from_slice(&self.end.to_le_bytes());
+        record
+    }
+
+    fn decode(record: &[u8]) -> Self {
+        let u64_at = |i: usize| u64::from_le_bytes(record[i..i + 8].try_into().unwrap());
+        let u32_at = |i: usize| u32::from_le_bytes(record[i..i + 4].try_into().unwrap());
+        Self {
+            offset: u64_at(0),
+            compressed_len: u32_at(8),
+            raw_len: u32_at(12),
+            first_line: u64_at(16),
+            lines: u32_at(24),
+            start: u64_at(32),
+            end: u64_at(40),
+        }
+    }
+
+    fn end_offset(&self) -> u64 {
+        self.offset + self.compressed_len as u64
+    }
+}
+
+/// Index records in `dir`, ignoring a torn last record.
+fn read_index(dir: &Path) -> io::Result<Vec<Block>> {
+    let data = match std::fs::read(dir.join(INDEX_FILENAME)) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e),
+    };
+    Ok(data.chunks_exact(RECORD_SIZE).map(Block::decode).collect())
+}
+
+/// Start time and contents of the open block in `dir`.
+fn read_tail(dir: &Path) -> io::Result<Option<(u64, Vec<u8>)>> {
+    let data = match std::fs::read(dir.join(TAIL_FILENAME)) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    match data.split_first_chunk::<8>() {
+        Some((start, bytes)) if !bytes.is_empty() => {
+            Ok(Some((u64::from_le_bytes(*start), bytes.to_vec())))
+        }
+        _ => Ok(None),
+    }
+}
+
+fn count_lines(data: &[u8]) -> u32 {
+    data.iter().filter(|&&b| b == b'\n').count() as u32
+}
+
+/// Appends a session's output to its history, run by the daemon.
+pub struct HistoryWriter {
+    blocks: File,
+    index: File,
+    tail: File,
+    /// Bytes of the open block, also in the tail file.
+    pending: Vec<u8>,
+    pending_start: u64,
+    /// End of the last complete frame in `blocks`.
+    offset: u64,
+    next_line: u64,
+}
+
+impl HistoryWriter {
+    /// Open the history in `session_dir`, creating it, or continuing an
+    /// existing one (after a hot upgrade) with its open block.
+    pub fn open(session_dir: &Path) -> io::Result<Self> {
+        let dir = session_dir.join(HISTORY_DIRNAME);
+        std::fs::create_dir_all(&dir)?;
+        let index = read_index(&dir)?;
+        let (offset, next_line) = index.last().map_or((0, 0), |last| {
+            (last.end_offset(), last.first_line + last.lines as u64)
+        });
+        let open = |name: &str| {
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(dir.join(name))
+        };
+        let blocks = open(BLOCKS_FILENAME)?;
+        // Drop a frame or record torn by a crash mid-write.
+        blocks.set_len(offset)?;
+        let index_file = open(INDEX_FILENAME)?;
+        index_file.set_len((index.len() * RECORD_SIZE) as u64)?;
+        let (pending_start, pending) = read_tail(&dir)?.unwrap_or_default();
+        let mut writer = Self {
+            tail: open(TAIL_FILENAME)?,
+            blocks,
+            index: index_file,
+            pending,
+            pending_start,
+            offset,
+            next_line,
+        };
+        writer.blocks.seek(SeekFrom::End(0))?;
+        writer.index.seek(SeekFrom::End(0))?;
+        writer.tail.seek(SeekFrom::End(0))?;
+        Ok(writer)
+    }
+
+    /// Append output written at `now` (unix seconds).
+    pub fn append(&mut self, data: &[u8], now: u64) -> io::Result<()> {
+        let mut data = data;
+        while !data.is_empty() {
+            if self.pending.is_empty() {
+                self.pending_start = now;
+                self.tail.set_len(0)?;
+                self.tail.seek(SeekFrom::Start(0))?;
+                self.tail.write_all(&now.to_le_bytes())?;
+            }
+            let take = data.len().min(BLOCK_SIZE - self.pending.len());
+            self.pending.extend_from_slice(&data[..take]);
+            self.tail.write_all(&data[..take])?;
+            data = &data[take..];
+            if self.pending.len() >= BLOCK_SIZE {
+                self.close_block(now)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Close the open block if it is older than [`BLOCK_MAX_AGE`].
+    pub fn tick(&mut self, now: u64) -> io::Result<()> {
+        if !self.pending.is_empty() && now.saturating_sub(self.pending_start) >= BLOCK_MAX_AGE {
+            self.close_block(now)?;
+        }
+        Ok(())
+    }
+
+    /// Compress the open block and add it to the index.
+    pub fn close_block(&mut self, now: u64) -> io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let frame = zstd::bulk::compress(&self.pending, COMPRESSION_LEVEL)?;
+        let block = Block {
+            offset: self.offset,
+            compressed_len: frame.len() as u32,
+            raw_len: self.pending.len() as u32,
+            first_line: self.next_line,
+            lines: count_lines(&self.pending),
+            start: self.pending_start,
+            end: now.max(self.pending_start),
+        };
+        self.blocks.write_all(&frame)?;
+        self.index.write_all(&block.encode())?;
+        self.offset = block.end_offset();
+        self.next_line += block.lines as u64;
+        self.pending.clear();
+        self.tail.set_len(0)?;
+        self.tail.seek(SeekFrom::Start(0))?;
+        Ok(())
+    }
+}
+
+/// A piece of a history: a compressed block, or the open block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Segment {
+    pub first_line: u64,
+    pub start: u64,
+    /// `None` for the open block, which is still being written.
+    pub block: Option<Block>,
+}
+
+/// Read side of a session's history, usable without the daemon.
+pub struct History {
+    blocks_path: PathBuf,
+    index: Vec<Block>,
+    tail: Option<(u64, Vec<u8>)>,
+}
+
+impl History {
+    pub fn open(session_dir: &Path) -> io::Result<Self> {
+        let dir = session_dir.join(HISTORY_DIRNAME);
+        if !dir.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "the session keeps no history (start it with --history)",
+            ));
+        }
+        Ok(Self {
+            blocks_path: dir.join(BLOCKS_FILENAME),
+            index: read_index(&dir)?,
+            tail: read_tail(&dir)?,
+        })
+    }
+
+    /// Segments, oldest first, that may hold output written at or after
+    /// `since` and lines from `from_line` on.
+    pub fn select(&self, since: Option<u64>, from_line: Option<u64>) -> Vec<Segment> {
+        let skip = self.index.partition_point(|block| {
+            since.is_some_and(|since| block.end < since)
+                || from_line.is_some_and(|line| block.first_line + block.lines as u64 <= line)
+        });
+        let mut segments: Vec<Segment> = self.index[skip..]
+            .iter()
+            .map(|block| Segment {
+                first_line: block.first_line,
+                start: block.start,
+                block: Some(*block),
+            })
+            .collect();
+        if let Some((start, _)) = &self.tail {
+            let first_line = self
+                .index
+                .last()
+                .map_or(0, |last| last.first_line + last.lines as u64);
+            segments.push(Segment {
+                first_line,
+                start: *start,
+                block: None,
+            });
+        }
+        segments
+    }
+
+    /// Raw output of `segment`. Only its own block is decompressed.
+    pub fn load(&self, segment: &Segment) -> io::Result<Vec<u8>> {
+        let Some(block) = segment.block else {
+            return Ok(self
+                .tail
+                .as_ref()
+                .map(|(_, t)| t.clone())
+                .unwrap_or_default());
+        };
+        let mut file = File::open(&self.blocks_path)?;
+        file.seek(SeekFrom::Start(block.offset))?;
+        let mut frame = vec![0u8; block.compressed_len as usize];
+        file.read_exact(&mut frame)?;
+        zstd::bulk::decompress(&frame, block.raw_len as usize)
+    }
+
+    /// Write the raw output of `segments` to `out`, starting at line
+    /// `from_line` (1-based, as `search` numbers them) if given.
+    pub fn write(
+        &self,
+        segments: &[Segment],
+        from_line: Option<u64>,
+        out: &mut impl Write,
+    ) -> io::Result<()> {
+        for segment in segments {
+            let data = self.load(segment)?;
+            let skip = from_line
+                .map_or(0, |line| line.saturating_sub(1))
+                .saturating_sub(segment.first_line);
+            let start = match skip {
+                0 => 0,
+                skip => data
+                    .iter()
+                    .enumerate()
+                    .filter(|&(_, &b)| b == b'\n')
+                    .nth(skip as usize - 1)
+                    .map_or(data.len(), |(i, _)| i + 1),
+            };
+            out.write_all(&data[start..])?;
+        }
+        Ok(())
+    }
+
+    /// Call `found` with the number (1-based) and text of every line of
+    /// `segments` containing `pattern`, with SGR sequences removed. Blocks
+    /// are decompressed one at a time.
+    pub fn search(
+        &self,
+        segments: &[Segment],
+        pattern: &str,
+        mut found: impl FnMut(u64, &str),
+    ) -> io::Result<()> {
+        let mut stripper = SgrStripper::default();
+        let mut line = Vec::new();
+        let mut number = segments.first().map_or(0, |s| s.first_line) + 1;
+        let mut check = |line: &[u8], number: u64| {
+            let text = String::from_utf8_lossy(line);
+            let text = text.trim_end_matches('\r');
+            if text.contains(pattern) {
+                found(number, text);
+            }
+        };
+        for segment in segments {
+            let data = self.load(segment)?;
+            let mut plain = Vec::with_capacity(data.len());
+            stripper.filter(&data, &mut plain);
+            for byte in plain {
+                if byte == b'\n' {
+                    check(&line, number);
+                    line.clear();
+                    number += 1;
+                } else if line.len() < MAX_LINE {
+                    line.push(byte);
+                }
+            }
+        }
+        if !line.is_empty() {
+            check(&line, number);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blocks_are_indexed_by_line_and_time() {
+        let dir = std::env::temp_dir().join(format!("pterm-history-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut writer = HistoryWriter::open(&dir).unwrap();
+        writer.append(b"one\r\ntwo\r\n", 1000).unwrap();
+        writer.tick(1000 + BLOCK_MAX_AGE).unwrap();
+        let big = vec![b'x'; BLOCK_SIZE + 10];
+        writer.append(&big, 2000).unwrap();
+        writer.append(b"\r\nthree\r\n", 2001).unwrap();
+        drop(writer);
+
+        let history = History::open(&dir).unwrap();
+        let all = history.select(None, None);
+        assert_eq!(all.len(), 3);
+        assert_eq!(history.load(&all[0]).unwrap(), b"one\r\ntwo\r\n");
+        assert_eq!(history.load(&all[1]).unwrap(), big[..BLOCK_SIZE]);
+        assert_eq!((all[2].first_line, all[2].block), (2, None));
+
+        let recent = history.select(Some(1500), None);
+        assert_eq!(recent.len(), 2);
+        assert_eq!(recent[0].start, 2000);
+        assert_eq!(history.select(None, Some(2)).len(), 1);
+
+        // A restarted writer carries on with the open block.
+        let mut writer = HistoryWriter::open(&dir).unwrap();
+        writer.close_block(2002).unwrap();
+        let history = History::open(&dir).unwrap();
+        let all = history.select(None, None);
+        assert_eq!(all.len(), 3);
+        let last = all[2].block.unwrap();
+        assert_eq!((last.first_line, last.lines, last.start), (2, 2, 2000));
+        assert!(history.load(&all[2]).unwrap().ends_with(b"three\r\n"));
+
+        let mut found = Vec::new();
+        history
+            .search(&all, "t", |line, text| found.push((line, text.to_string())))
+            .unwrap();
+        assert_eq!(found, [(2, "two".to_string()), (4, "three".to_string())]);
+        let mut out = Vec::new();
+        history.write(&all[..1], Some(2), &mut out).unwrap();
+        assert_eq!(out, b"two\r\n");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
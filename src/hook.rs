@@ -0,0 +1,120 @@
+//! Exit hooks: a command the daemon runs once when the session's command
+//! exits, for notifications that should say what went wrong.
+//!
+//! The hook runs directly (no shell), like the `on-output` watchers, with
+//! the session name and exit status in its environment and the last lines
+//! of the session's output on stdin, as plain text: scrollback and screen
+//! as the terminal shows them, without escape sequences, trailing blank
+//! lines dropped. The daemon does not wait for it.
+
+use crate::meta::ExitInfo;
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+
+/// Lines of output a hook gets unless it asks for another number.
+pub const DEFAULT_LINES: usize = 20;
+pub const MAX_LINES: usize = 10_000;
+
+/// Where the excerpt is written before it becomes the hook's stdin.
+const EXCERPT_FILENAME: &str = "exit-excerpt";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExitHook {
+    pub command: Vec<String>,
+    /// Lines of output passed on stdin.
+    pub lines: usize,
+}
+
+impl ExitHook {
+    pub fn new(command: Vec<String>, lines: usize) -> Result<Self, String> {
+        if command.is_empty() {
+            return Err("a command is required".to_string());
+        }
+        if lines > MAX_LINES {
+            return Err(format!("at most {} lines can be passed", MAX_LINES));
+        }
+        Ok(Self { command, lines })
+    }
+
+    /// Start the hook for a command that ended with `exit`, with the last
+    /// `self.lines` of `lines` on stdin. The excerpt goes through a file in
+    /// `dir`, removed once the hook has it open, so a hook that does not
+    /// read it cannot block the daemon.
+    pub fn run(
+        &self,
+        session: &str,
+        exit: &ExitInfo,
+        lines: &[String],
+        dir: &Path,
+    ) -> io::Result<Child> {
+        let path = dir.join(EXCERPT_FILENAME);
+        std::fs::write(&path, excerpt(lines, self.lines))?;
+        let stdin = std::fs::File::open(&path);
+        let _ = std::fs::remove_file(&path);
+        let mut command = Command::new(&self.command[0]);
+        command
+            .args(&self.command[1..])
+            .env("PTERM_SESSION", session)
+            .env("PTERM_EXIT_CODE", exit.code.to_string())
+            .env("PTERM_EXIT_REASON", exit.reason())
+            .stdin(stdin?)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null());
+        match exit.signal {
+            Some(signal) => command.env("PTERM_EXIT_SIGNAL", signal.to_string()),
+            None => command.env_remove("PTERM_EXIT_SIGNAL"),
+        };
+        command.spawn()
+    }
+
+    /// `<command>` plus the lines it gets, for `pterm info`.
+    pub fn describe(&self) -> String {
+        format!("{} (last {} lines)", self.command.join(" "), self.lines)
+    }
+}
+
+/// The last `n` lines of `lines` that end with a non-blank one, each
+/// followed by a newline.
+pub fn excerpt(lines: &[String], n: usize) -> String {
+    let end = lines
+        .iter()
+        .rposition(|line| !line.trim().is_empty())
+        .map_or(0, |i| i + 1);
+    lines[end.saturating_sub(n)..end]
+        .iter()
+        .map(|line| format!("{}\n", line.trim_end()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn excerpt_takes_the_last_lines_before_the_blank_screen() {
+        let lines: Vec<String> = [
+            "$ make",
+            "cc -c main.c",
+            "main.c:3: error: expected ';'",
+            "make: *** [all] Error 2   ",
+            "$ ",
+            "",
+            "",
+        ]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+        assert_eq!(
+            excerpt(&lines, 3),
+            "main.c:3: error: expected ';'\nmake: *** [all] Error 2\n$\n"
+        );
+        assert_eq!(excerpt(&lines, 100).lines().count(), 5);
+        assert_eq!(excerpt(&lines, 0), "");
+        assert_eq!(excerpt(&[String::new()], 5), "");
+
+        assert!(ExitHook::new(Vec::new(), 5).is_err());
+        assert!(ExitHook::new(vec!["notify".to_string()], MAX_LINES + 1).is_err());
+    }
+}
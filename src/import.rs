@@ -0,0 +1,365 @@
+//! `pterm import-dtach`: adopt a running dtach or abduco session.
+//!
+//! The new pterm session runs `pterm __import-proxy <flavor> <socket>` as its
+//! command. The proxy attaches to the old socket like that tool's own client
+//! would: keystrokes and size changes from the pterm pty go to the old master,
+//! and its output is copied back. When the original process exits, the old
+//! master closes the socket, the proxy exits, and so does the pterm session.
+//! Killing the pterm session only disconnects the proxy; the original keeps
+//! running under dtach/abduco.
+
+use crate::bridge::{get_winsize, sigwinch_pipe, take_sigwinch, write_all_raw, RawModeGuard};
+use crate::constants::{DEFAULT_TERMINAL_COLS, DEFAULT_TERMINAL_ROWS};
+use mio::net::UnixStream;
+use mio::unix::SourceFd;
+use mio::{Events, Interest, Poll, Token};
+use nix::libc;
+use std::io::{self, Read};
+use std::os::fd::AsRawFd;
+use std::path::Path;
+
+/// Hidden subcommand run inside the pterm session.
+pub const PROXY_COMMAND: &str = "__import-proxy";
+
+const TOKEN_STDIN: Token = Token(0);
+const TOKEN_SOCKET: Token = Token(1);
+const TOKEN_WAKE: Token = Token(2);
+
+// dtach `struct packet`: type u8, len u8, then a `struct winsize` sized
+// buffer that carries either keystrokes or the window size.
+const DTACH_MSG_PUSH: u8 = 0;
+const DTACH_MSG_ATTACH: u8 = 1;
+const DTACH_MSG_DETACH: u8 = 2;
+const DTACH_MSG_WINCH: u8 = 3;
+const DTACH_MSG_REDRAW: u8 = 4;
+const DTACH_REDRAW_WINCH: u8 = 3;
+const DTACH_BUF_SIZE: usize = 8;
+
+// abduco `Packet`: type u32, len u32, then `len` bytes of payload.
+const ABDUCO_MSG_CONTENT: u32 = 0;
+const ABDUCO_MSG_ATTACH: u32 = 1;
+const ABDUCO_MSG_DETACH: u32 = 2;
+const ABDUCO_MSG_RESIZE: u32 = 3;
+const ABDUCO_MSG_EXIT: u32 = 4;
+const ABDUCO_HEADER_SIZE: usize = 8;
+const ABDUCO_MAX_PAYLOAD: usize = 4096 - ABDUCO_HEADER_SIZE;
+
+/// Which tool's client protocol to speak.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flavor {
+    Dtach,
+    Abduco,
+}
+
+impl Flavor {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Dtach => "dtach",
+            Self::Abduco => "abduco",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "dtach" => Some(Self::Dtach),
+            "abduco" => Some(Self::Abduco),
+            _ => None,
+        }
+    }
+
+    /// Packets sent right after connecting: attach, then the current size
+    /// (which also makes the old master redraw).
+    fn attach(self, cols: u16, rows: u16) -> Vec<u8> {
+        match self {
+            Self::Dtach => {
+                let mut out = dtach_packet(DTACH_MSG_ATTACH, 0, &[]);
+                out.extend(dtach_packet(
+                    DTACH_MSG_REDRAW,
+                    DTACH_REDRAW_WINCH,
+                    &winsize(cols, rows),
+                ));
+                out
+            }
+            Self::Abduco => {
+                // Attach flags: neither read-only nor low-priority.
+                let mut out = abduco_packet(ABDUCO_MSG_ATTACH, &0u32.to_ne_bytes());
+                out.extend(self.resize(cols, rows));
+                out
+            }
+        }
+    }
+
+    fn input(self, data: &[u8]) -> Vec<u8> {
+        match self {
+            Self::Dtach => data
+                .chunks(DTACH_BUF_SIZE)
+                .flat_map(|chunk| dtach_packet(DTACH_MSG_PUSH, chunk.len() as u8, chunk))
+                .collect(),
+            Self::Abduco => data
+                .chunks(ABDUCO_MAX_PAYLOAD)
+                .flat_map(|chunk| abduco_packet(ABDUCO_MSG_CONTENT, chunk))
+                .collect(),
+        }
+    }
+
+    fn resize(self, cols: u16, rows: u16) -> Vec<u8> {
+        match self {
+            Self::Dtach => dtach_packet(DTACH_MSG_WINCH, 0, &winsize(cols, rows)),
+            Self::Abduco => {
+                let mut ws = rows.to_ne_bytes().to_vec();
+                ws.extend(cols.to_ne_bytes());
+                abduco_packet(ABDUCO_MSG_RESIZE, &ws)
+            }
+        }
+    }
+
+    fn detach(self) -> Vec<u8> {
+        match self {
+            Self::Dtach => dtach_packet(DTACH_MSG_DETACH, 0, &[]),
+            Self::Abduco => abduco_packet(ABDUCO_MSG_DETACH, &[]),
+        }
+    }
+}
+
+/// `struct winsize` with zero pixel sizes.
+fn winsize(cols: u16, rows: u16) -> Vec<u8> {
+    [rows, cols, 0, 0]
+        .iter()
+        .flat_map(|v| v.to_ne_bytes())
+        .collect()
+}
+
+fn dtach_packet(msg_type: u8, len: u8, buf: &[u8]) -> Vec<u8> {
+    let mut out = vec![msg_type, len];
+    out.extend_from_slice(buf);
+    out.resize(2 + DTACH_BUF_SIZE, 0);
+    out
+}
+
+fn abduco_packet(msg_type: u32, payload: &[u8]) -> Vec<u8> {
+    let mut out = msg_type.to_ne_bytes().to_vec();
+    out.extend((payload.len() as u32).to_ne_bytes());
+    out.extend_from_slice(payload);
+    out
+}
+
+/// Something the old master sent us.
+#[derive(Debug, PartialEq, Eq)]
+enum Event {
+    Output(Vec<u8>),
+    /// The master wants the current size resent.
+    Resize,
+    Exit(i32),
+}
+
+/// Decode what has arrived from the old master. dtach sends raw terminal
+/// output; abduco frames everything, so a partial packet stays in `buf`.
+fn decode(flavor: Flavor, buf: &mut Vec<u8>) -> Vec<Event> {
+    if flavor == Flavor::Dtach {
+        return if buf.is_empty() {
+            Vec::new()
+        } else {
+            vec![Event::Output(std::mem::take(buf))]
+        };
+    }
+    let mut events = Vec::new();
+    let mut offset = 0;
+    while buf.len() - offset >= ABDUCO_HEADER_SIZE {
+        let header = &buf[offset..offset + ABDUCO_HEADER_SIZE];
+        let msg_type = u32::from_ne_bytes(header[..4].try_into().unwrap());
+        let len = u32::from_ne_bytes(header[4..].try_into().unwrap()) as usize;
+        let start = offset + ABDUCO_HEADER_SIZE;
+        if buf.len() - start < len {
+            break;
+        }
+        let payload = &buf[start..start + len];
+        match msg_type {
+            ABDUCO_MSG_CONTENT => events.push(Event::Output(payload.to_vec())),
+            ABDUCO_MSG_RESIZE => events.push(Event::Resize),
+            ABDUCO_MSG_EXIT => {
+                let code = payload
+                    .get(..4)
+                    .map_or(0, |b| i32::from_ne_bytes(b.try_into().unwrap()));
+                events.push(Event::Exit(code));
+            }
+            _ => {}
+        }
+        offset = start + len;
+    }
+    buf.drain(..offset);
+    events
+}
+
+/// Session name for an imported socket: its file name, without the
+/// `@hostname` suffix abduco appends.
+pub fn default_session_name(socket: &Path, flavor: Flavor) -> Option<String> {
+    let name = socket.file_name()?.to_str()?;
+    let name = match flavor {
+        Flavor::Abduco => name.rsplit_once('@').map_or(name, |(name, _)| name),
+        Flavor::Dtach => name,
+    };
+    (!name.is_empty()).then(|| name.to_string())
+}
+
+/// Proxy between the pterm pty (stdin/stdout) and an old dtach/abduco
+/// socket. Returns the original process's exit code when abduco reports
+/// one, 0 otherwise.
+pub fn proxy(socket_path: &Path, flavor: Flavor) -> io::Result<i32> {
+    let stdin_fd = libc::STDIN_FILENO;
+    let stdout_fd = libc::STDOUT_FILENO;
+
+    // The old master's own pty does the line editing; pass keys through.
+    let _raw_guard = if unsafe { libc::isatty(stdin_fd) } == 1 {
+        Some(RawModeGuard::enter(stdin_fd)?)
+    } else {
+        None
+    };
+    let (wake_read, _wake_write) = sigwinch_pipe()?;
+
+    let std_stream = std::os::unix::net::UnixStream::connect(socket_path)?;
+    std_stream.set_nonblocking(true)?;
+    let mut socket = UnixStream::from_std(std_stream);
+    let socket_fd = socket.as_raw_fd();
+
+    unsafe {
+        let flags = libc::fcntl(stdin_fd, libc::F_GETFL);
+        libc::fcntl(stdin_fd, libc::F_SETFL, flags | libc::O_NONBLOCK);
+    }
+
+    let mut poll = Poll::new()?;
+    poll.registry()
+        .register(&mut SourceFd(&stdin_fd), TOKEN_STDIN, Interest::READABLE)?;
+    poll.registry()
+        .register(&mut socket, TOKEN_SOCKET, Interest::READABLE)?;
+    let wake_read_fd = wake_read.as_raw_fd();
+    poll.registry()
+        .register(&mut SourceFd(&wake_read_fd), TOKEN_WAKE, Interest::READABLE)?;
+
+    let size = || get_winsize(stdout_fd).unwrap_or((DEFAULT_TERMINAL_COLS, DEFAULT_TERMINAL_ROWS));
+    let (cols, rows) = size();
+    write_all_raw(socket_fd, &flavor.attach(cols, rows))?;
+
+    let mut events = Events::with_capacity(16);
+    let mut buf = [0u8; 65536];
+    let mut recv_buf = Vec::new();
+    let mut exit_code = 0;
+    'main: loop {
+        if let Err(e) = poll.poll(&mut events, None) {
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(e);
+        }
+        for event in events.iter() {
+            match event.token() {
+                TOKEN_STDIN => loop {
+                    match nix::unistd::read(stdin_fd, &mut buf) {
+                        Ok(0) => break 'main,
+                        Ok(n) => write_all_raw(socket_fd, &flavor.input(&buf[..n]))?,
+                        Err(nix::errno::Errno::EAGAIN) => break,
+                        Err(nix::errno::Errno::EINTR) => continue,
+                        Err(_) => break 'main,
+                    }
+                },
+                TOKEN_SOCKET => {
+                    let mut closed = false;
+                    loop {
+                        match socket.read(&mut buf) {
+                            Ok(0) => {
+                                closed = true;
+                                break;
+                            }
+                            Ok(n) => recv_buf.extend_from_slice(&buf[..n]),
+                            Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                            Err(_) => {
+                                closed = true;
+                                break;
+                            }
+                        }
+                    }
+                    for event in decode(flavor, &mut recv_buf) {
+                        match event {
+                            Event::Output(data) => write_all_raw(stdout_fd, &data)?,
+                            Event::Resize => {
+                                let (cols, rows) = size();
+                                write_all_raw(socket_fd, &flavor.resize(cols, rows))?;
+                            }
+                            Event::Exit(code) => {
+                                exit_code = code;
+                                closed = true;
+                            }
+                        }
+                    }
+                    if closed {
+                        return Ok(exit_code);
+                    }
+                }
+                TOKEN_WAKE => {
+                    let resized = take_sigwinch(wake_read_fd);
+                    if resized {
+                        let (cols, rows) = size();
+                        write_all_raw(socket_fd, &flavor.resize(cols, rows))?;
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    let _ = write_all_raw(socket_fd, &flavor.detach());
+    Ok(exit_code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dtach_packets_are_fixed_size() {
+        let input = Flavor::Dtach.input(b"0123456789");
+        assert_eq!(input.len(), 20);
+        assert_eq!(&input[..10], b"\x00\x0801234567");
+        assert_eq!(&input[10..14], b"\x00\x0289");
+
+        let attach = Flavor::Dtach.attach(80, 24);
+        assert_eq!(attach.len(), 20);
+        assert_eq!(attach[..2], [DTACH_MSG_ATTACH, 0]);
+        assert_eq!(attach[10..12], [DTACH_MSG_REDRAW, DTACH_REDRAW_WINCH]);
+        assert_eq!(attach[12..16], winsize(80, 24)[..4]);
+    }
+
+    #[test]
+    fn abduco_packets_round_trip() {
+        let mut buf = abduco_packet(ABDUCO_MSG_CONTENT, b"hello");
+        buf.extend(abduco_packet(ABDUCO_MSG_RESIZE, &[]));
+        buf.extend(abduco_packet(ABDUCO_MSG_EXIT, &3i32.to_ne_bytes()));
+        buf.extend(&abduco_packet(ABDUCO_MSG_CONTENT, b"partial")[..10]);
+        assert_eq!(
+            decode(Flavor::Abduco, &mut buf),
+            [
+                Event::Output(b"hello".to_vec()),
+                Event::Resize,
+                Event::Exit(3)
+            ]
+        );
+        assert_eq!(buf.len(), 10);
+
+        let resize = Flavor::Abduco.resize(100, 40);
+        assert_eq!(resize.len(), ABDUCO_HEADER_SIZE + 4);
+        assert_eq!(resize[8..10], 40u16.to_ne_bytes());
+    }
+
+    #[test]
+    fn default_session_name_strips_abduco_host() {
+        let socket = Path::new("/home/me/.abduco/build@myhost");
+        assert_eq!(
+            default_session_name(socket, Flavor::Abduco).as_deref(),
+            Some("build")
+        );
+        assert_eq!(
+            default_session_name(Path::new("/tmp/job.sock"), Flavor::Dtach).as_deref(),
+            Some("job.sock")
+        );
+    }
+}
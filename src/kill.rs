@@ -0,0 +1,223 @@
+//! `pterm kill`: which sessions a kill ends and which session directories
+//! go for it. Removing a session directory shuts down its daemon and those
+//! of all sessions below it, so a kill removes as few directories as it
+//! can: a name's own, or for `--all` each top-level session's.
+
+use crate::error::{Error, Result};
+use std::io::{self, Write};
+
+const USAGE: &str = "usage: pterm kill <session-name> | <prefix> --recursive | --all [--dry-run]";
+
+/// What `pterm kill` was asked to kill.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Request<'a> {
+    /// Every session in every socket root (`--all`).
+    All,
+    /// A session and the sessions below it, or with `recursive` a prefix
+    /// that is not a session itself.
+    Named { name: &'a str, recursive: bool },
+}
+
+/// What the name of a [`Request::Named`] stands for on disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Found {
+    Missing,
+    Session,
+    /// A directory holding sessions, without one of its own.
+    Prefix,
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Plan {
+    /// Sessions that end, parents before their children.
+    pub targets: Vec<String>,
+    /// Session directories to remove, by session name, in order.
+    pub remove: Vec<String>,
+}
+
+/// Parse the arguments of `pterm kill` into the request and `--dry-run`.
+pub fn parse_args(args: &[String]) -> Result<(Request<'_>, bool)> {
+    let mut name = None;
+    let mut all = false;
+    let mut recursive = false;
+    let mut dry_run = false;
+    for arg in args {
+        match arg.as_str() {
+            "--all" => all = true,
+            "--recursive" | "-r" => recursive = true,
+            "--dry-run" => dry_run = true,
+            a if a.starts_with('-') || name.is_some() => {
+                return Err(Error::Usage(USAGE.to_string()));
+            }
+            a => name = Some(a),
+        }
+    }
+    let request = match (name, all) {
+        (None, true) => Request::All,
+        (Some(name), false) => Request::Named { name, recursive },
+        (Some(_), true) => {
+            return Err(Error::Usage(
+                "--all kills every session; it takes no session name".to_string(),
+            ))
+        }
+        (None, false) => return Err(Error::Usage("session name required".to_string())),
+    };
+    Ok((request, dry_run))
+}
+
+/// Plan `request`, given what its name is (ignored for `--all`) and the
+/// sessions below it (every session for `--all`), sorted by name.
+pub fn plan(request: Request, found: Found, below: Vec<String>) -> Result<Plan> {
+    let (targets, mut remove) = match (request, found) {
+        (Request::All, _) => (below, Vec::new()),
+        (Request::Named { name, .. }, Found::Missing) => {
+            return Err(Error::SessionNotFound(name.to_string()));
+        }
+        (Request::Named { name, .. }, Found::Session) => {
+            let targets = std::iter::once(name.to_string()).chain(below).collect();
+            (targets, vec![name.to_string()])
+        }
+        (Request::Named { name, recursive }, Found::Prefix) => {
+            if !recursive {
+                return Err(Error::Failed(format!(
+                    "'{}' is not a session; {} session(s) below it (kill them with --recursive)",
+                    name,
+                    below.len()
+                )));
+            }
+            // The whole subtree, with the prefix's directory.
+            (below, vec![name.to_string()])
+        }
+    };
+    for target in &targets {
+        let under_removed = remove
+            .iter()
+            .any(|parent| target == parent || target.starts_with(&format!("{}/", parent)));
+        if !under_removed {
+            remove.push(target.clone());
+        }
+    }
+    Ok(Plan { targets, remove })
+}
+
+/// Carry out `plan` with `remove_dir` removing a session's directory, and
+/// report each session on `out`. A dry run only reports.
+pub fn execute(
+    plan: &Plan,
+    dry_run: bool,
+    mut remove_dir: impl FnMut(&str) -> io::Result<()>,
+    out: &mut impl Write,
+) -> io::Result<()> {
+    if plan.targets.is_empty() {
+        return writeln!(out, "No sessions");
+    }
+    if dry_run {
+        for target in &plan.targets {
+            writeln!(out, "Would kill session '{}'", target)?;
+        }
+        return Ok(());
+    }
+    for name in &plan.remove {
+        // The daemon(s) will detect socket removal and shut down.
+        remove_dir(name)?;
+    }
+    for target in &plan.targets {
+        writeln!(out, "Session '{}' killed", target)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn kill_targets_parents_first_and_removes_each_subtree_once() {
+        let args = names(&["ci", "--recursive", "--dry-run"]);
+        let (request, dry_run) = parse_args(&args).unwrap();
+        assert_eq!(
+            request,
+            Request::Named {
+                name: "ci",
+                recursive: true
+            }
+        );
+        assert!(dry_run);
+        for args in [&["--all", "ci"][..], &[], &["a", "b"], &["--force", "a"]] {
+            assert!(matches!(parse_args(&names(args)), Err(Error::Usage(_))));
+        }
+
+        let below = names(&["ci/a", "ci/a/x", "ci/b"]);
+        let plan = plan(request, Found::Prefix, below.clone()).unwrap();
+        assert_eq!(plan.targets, below);
+        assert_eq!(plan.remove, ["ci"]);
+        let not_recursive = Request::Named {
+            name: "ci",
+            recursive: false,
+        };
+        let error = super::plan(not_recursive, Found::Prefix, below.clone()).unwrap_err();
+        assert!(
+            error.to_string().contains("3 session(s) below it"),
+            "{}",
+            error
+        );
+        assert!(matches!(
+            super::plan(not_recursive, Found::Missing, Vec::new()),
+            Err(Error::SessionNotFound(_))
+        ));
+
+        let session = super::plan(not_recursive, Found::Session, below).unwrap();
+        assert_eq!(session.targets, ["ci", "ci/a", "ci/a/x", "ci/b"]);
+        assert_eq!(session.remove, ["ci"]);
+
+        let all = names(&["a", "a/1", "b", "shared:c", "shared:c/2"]);
+        let all = super::plan(Request::All, Found::Prefix, all).unwrap();
+        assert_eq!(all.remove, ["a", "b", "shared:c"]);
+        assert_eq!(all.targets.len(), 5);
+    }
+
+    #[test]
+    fn dry_run_reports_without_removing_anything() {
+        let all = names(&["a", "a/1", "b"]);
+        let plan = plan(Request::All, Found::Prefix, all).unwrap();
+        let mut removed = Vec::new();
+        let mut out = Vec::new();
+        execute(
+            &plan,
+            true,
+            |name| {
+                removed.push(name.to_string());
+                Ok(())
+            },
+            &mut out,
+        )
+        .unwrap();
+        assert!(removed.is_empty());
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "Would kill session 'a'\nWould kill session 'a/1'\nWould kill session 'b'\n"
+        );
+
+        let mut out = Vec::new();
+        execute(
+            &plan,
+            false,
+            |name| {
+                removed.push(name.to_string());
+                Ok(())
+            },
+            &mut out,
+        )
+        .unwrap();
+        assert_eq!(removed, ["a", "b"]);
+        assert_eq!(String::from_utf8(out).unwrap().lines().count(), 3);
+
+        let mut out = Vec::new();
+        execute(&Plan::default(), false, |_| Ok(()), &mut out).unwrap();
+        assert_eq!(out, b"No sessions\n");
+    }
+}
@@ -0,0 +1,495 @@
+//! `pterm list` rendering: session discovery plus table / custom-format output.
+
+use crate::meta::{self, SessionMeta};
+use crate::paths::{find_sessions, session_dir, socket_roots};
+use crate::registry;
+use std::io;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionState {
+    Attached,
+    Detached,
+    /// Child processes stopped by `pterm suspend`.
+    Suspended,
+    /// Daemon or child is gone but the socket is still on disk.
+    Dead,
+    /// No readable metadata (e.g. a daemon started by an older pterm).
+    Unknown,
+}
+
+impl SessionState {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Attached => "attached",
+            Self::Detached => "detached",
+            Self::Suspended => "suspended",
+            Self::Dead => "dead",
+            Self::Unknown => "unknown",
+        }
+    }
+
+    fn color(self) -> &'static str {
+        match self {
+            Self::Attached => "\x1b[32m",
+            Self::Detached => "\x1b[33m",
+            Self::Suspended => "\x1b[34m",
+            Self::Dead => "\x1b[31m",
+            Self::Unknown => "\x1b[2m",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SessionEntry {
+    pub name: String,
+    pub state: SessionState,
+    pub meta: Option<SessionMeta>,
+}
+
+impl SessionEntry {
+    pub fn load(name: String) -> Self {
+        let meta = meta::read(&session_dir(&name)).ok();
+        let state = match &meta {
+            None => SessionState::Unknown,
+            Some(m) if m.exit_code.is_some() || !meta::pid_alive(m.pid) => SessionState::Dead,
+            Some(m) if m.suspended => SessionState::Suspended,
+            Some(m) if m.clients > 0 => SessionState::Attached,
+            Some(_) => SessionState::Detached,
+        };
+        Self { name, state, meta }
+    }
+
+    pub fn command(&self) -> String {
+        self.meta
+            .as_ref()
+            .map(|m| m.command.join(" "))
+            .unwrap_or_else(|| "-".to_string())
+    }
+
+    pub fn clients(&self) -> String {
+        self.meta
+            .as_ref()
+            .map(|m| m.clients.to_string())
+            .unwrap_or_else(|| "-".to_string())
+    }
+
+    pub fn activity(&self, now: u64) -> String {
+        self.meta
+            .as_ref()
+            .map(|m| format_age(now.saturating_sub(m.last_activity)))
+            .unwrap_or_else(|| "-".to_string())
+    }
+
+    /// How the command ended: its exit status or the signal that killed it,
+    /// `-` while it runs.
+    pub fn exit(&self) -> String {
+        let meta = self.meta.as_ref();
+        match (meta.and_then(|m| m.exit), meta.and_then(|m| m.exit_code)) {
+            (Some(exit), _) => exit.reason(),
+            (None, Some(code)) => code.to_string(),
+            (None, None) => "-".to_string(),
+        }
+    }
+
+    /// JSON form of the entry, as returned by `pterm query list`.
+    pub fn to_json(&self) -> serde_json::Value {
+        let meta = self.meta.as_ref();
+        serde_json::json!({
+            "name": self.name,
+            "state": self.state.as_str(),
+            "command": meta.map(|m| &m.command),
+            "clients": meta.map(|m| m.clients),
+            "pid": meta.map(|m| m.pid),
+            "created_at": meta.map(|m| m.created_at),
+            "last_activity": meta.map(|m| m.last_activity),
+            "expires_at": meta.and_then(|m| m.expires_at),
+            "exit": meta.and_then(|m| m.exit),
+        })
+    }
+
+    /// Expand `{name}`, `{state}`, `{cmd}`, `{clients}`, `{activity}`,
+    /// `{pid}` and `{exit}` placeholders. `\t` and `\n` escapes are expanded so formats can
+    /// be passed in single quotes from a shell.
+    pub fn format(&self, template: &str, now: u64) -> String {
+        let pid = self
+            .meta
+            .as_ref()
+            .map(|m| m.pid.to_string())
+            .unwrap_or_else(|| "-".to_string());
+        template
+            .replace("\\t", "\t")
+            .replace("\\n", "\n")
+            .replace("{name}", &self.name)
+            .replace("{state}", self.state.as_str())
+            .replace("{cmd}", &self.command())
+            .replace("{clients}", &self.clients())
+            .replace("{activity}", &self.activity(now))
+            .replace("{pid}", &pid)
+            .replace("{exit}", &self.exit())
+    }
+}
+
+/// Collect sessions under `prefix` (empty for all), sorted by name.
+/// Sessions below `prefix` in the socket root, then in each extra socket
+/// root (named `<dir>:<session>`). A `<dir>:` prefix searches that root
+/// only. Each root's registry is read if it has one; otherwise its
+/// directories are scanned.
+pub fn collect(prefix: &str) -> io::Result<Vec<SessionEntry>> {
+    let roots = socket_roots();
+    let only = prefix
+        .split_once(':')
+        .filter(|(dir, _)| roots.iter().any(|(name, _)| *name == Some(*dir)));
+    let mut names = Vec::new();
+    for (dir, root) in &roots {
+        let within = match only {
+            Some((wanted, rest)) if *dir == Some(wanted) => rest,
+            Some(_) => continue,
+            None => prefix,
+        }
+        .trim_end_matches('/');
+        let mut found = match registry::sessions(root) {
+            Some(registered) if within.is_empty() => registered,
+            Some(registered) => {
+                let below = format!("{}/", within);
+                registered
+                    .into_iter()
+                    .filter(|name| name.starts_with(&below))
+                    .collect()
+            }
+            None => find_sessions(&root.join(within), within)?,
+        };
+        found.sort();
+        names.extend(found.into_iter().map(|name| match dir {
+            Some(dir) => format!("{}:{}", dir, name),
+            None => name,
+        }));
+    }
+    Ok(names.into_iter().map(SessionEntry::load).collect())
+}
+
+/// Rebuild the registry of every socket root from a directory scan, for
+/// sessions it lost track of (`pterm list --scan`).
+pub fn rescan() -> io::Result<()> {
+    for (_, root) in socket_roots() {
+        registry::rebuild(&root)?;
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SortKey {
+    #[default]
+    Name,
+    /// Most recently active first.
+    Activity,
+    /// Oldest first.
+    Created,
+}
+
+impl SortKey {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "name" => Some(Self::Name),
+            "activity" => Some(Self::Activity),
+            "created" => Some(Self::Created),
+            _ => None,
+        }
+    }
+}
+
+/// Arguments of `pterm list`, also accepted by `pterm query list`.
+#[derive(Debug, Clone, Default)]
+pub struct ListArgs {
+    pub prefix: String,
+    pub format: Option<String>,
+    pub sort: SortKey,
+    pub filter: ListFilter,
+    /// Rebuild the session registries from a directory scan first.
+    pub scan: bool,
+}
+
+impl ListArgs {
+    pub fn parse(args: &[String]) -> Result<Self, String> {
+        let mut parsed = Self::default();
+        let mut i = 0;
+        while i < args.len() {
+            match args[i].as_str() {
+                "--format" | "-F" => {
+                    let template = args.get(i + 1).ok_or("--format requires a template")?;
+                    parsed.format = Some(template.clone());
+                    i += 1;
+                }
+                "--sort" => {
+                    parsed.sort = args
+                        .get(i + 1)
+                        .and_then(|s| SortKey::parse(s))
+                        .ok_or("--sort requires one of: activity, name, created")?;
+                    i += 1;
+                }
+                "--filter" => {
+                    let glob = args.get(i + 1).ok_or("--filter requires a glob")?;
+                    parsed.filter.glob = Some(glob.clone());
+                    i += 1;
+                }
+                "--attached-only" => parsed.filter.attached_only = true,
+                "--detached-only" => parsed.filter.detached_only = true,
+                "--scan" => parsed.scan = true,
+                arg => parsed.prefix = arg.to_string(),
+            }
+            i += 1;
+        }
+        if parsed.filter.attached_only && parsed.filter.detached_only {
+            return Err("--attached-only and --detached-only exclude each other".into());
+        }
+        Ok(parsed)
+    }
+}
+
+/// Selection applied to collected entries before rendering.
+#[derive(Debug, Clone, Default)]
+pub struct ListFilter {
+    pub attached_only: bool,
+    pub detached_only: bool,
+    /// Glob over the full session name (`*` and `?`; `*` also matches `/`).
+    pub glob: Option<String>,
+}
+
+impl ListFilter {
+    pub fn matches(&self, entry: &SessionEntry) -> bool {
+        if self.attached_only && entry.state != SessionState::Attached {
+            return false;
+        }
+        if self.detached_only && entry.state != SessionState::Detached {
+            return false;
+        }
+        self.glob
+            .as_deref()
+            .is_none_or(|pattern| glob_match(pattern, &entry.name))
+    }
+}
+
+/// Filter and sort entries in place.
+pub fn select(entries: &mut Vec<SessionEntry>, filter: &ListFilter, sort: SortKey) {
+    entries.retain(|e| filter.matches(e));
+    match sort {
+        SortKey::Name => entries.sort_by(|a, b| a.name.cmp(&b.name)),
+        SortKey::Activity => entries
+            .sort_by_key(|e| std::cmp::Reverse(e.meta.as_ref().map_or(0, |m| m.last_activity))),
+        SortKey::Created => {
+            entries.sort_by_key(|e| e.meta.as_ref().map_or(u64::MAX, |m| m.created_at))
+        }
+    }
+}
+
+/// Minimal glob matcher supporting `*` (any run of characters) and `?`
+/// (exactly one character).
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let p: Vec<char> = pattern.chars().collect();
+    let t: Vec<char> = text.chars().collect();
+    let (mut pi, mut ti) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+
+    while ti < t.len() {
+        if pi < p.len() && (p[pi] == '?' || p[pi] == t[ti]) {
+            pi += 1;
+            ti += 1;
+        } else if pi < p.len() && p[pi] == '*' {
+            star = Some((pi, ti));
+            pi += 1;
+        } else if let Some((star_pi, star_ti)) = star {
+            pi = star_pi + 1;
+            ti = star_ti + 1;
+            star = Some((star_pi, star_ti + 1));
+        } else {
+            return false;
+        }
+    }
+    p[pi..].iter().all(|&c| c == '*')
+}
+
+/// Human-readable age, e.g. `42s`, `5m`, `3h`, `2d`.
+pub fn format_age(secs: u64) -> String {
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m", secs / 60),
+        3600..=86_399 => format!("{}h", secs / 3600),
+        _ => format!("{}d", secs / 86_400),
+    }
+}
+
+/// Render entries as an aligned table with a header row. State cells are
+/// colored when `color` is set.
+pub fn render_table(entries: &[SessionEntry], now: u64, color: bool) -> String {
+    const HEADERS: [&str; 5] = ["NAME", "STATE", "CMD", "CLIENTS", "ACTIVITY"];
+    let rows: Vec<[String; 5]> = entries
+        .iter()
+        .map(|e| {
+            [
+                e.name.clone(),
+                e.state.as_str().to_string(),
+                e.command(),
+                e.clients(),
+                e.activity(now),
+            ]
+        })
+        .collect();
+
+    let mut widths = HEADERS.map(str::len);
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let mut out = String::new();
+    let header: Vec<String> = HEADERS
+        .iter()
+        .zip(widths)
+        .map(|(h, w)| format!("{:<w$}", h, w = w))
+        .collect();
+    if color {
+        out.push_str("\x1b[1m");
+    }
+    out.push_str(header.join("  ").trim_end());
+    if color {
+        out.push_str("\x1b[0m");
+    }
+    out.push('\n');
+
+    for (entry, row) in entries.iter().zip(&rows) {
+        let mut line = String::new();
+        for (idx, (cell, width)) in row.iter().zip(widths).enumerate() {
+            if idx > 0 {
+                line.push_str("  ");
+            }
+            let padded = format!("{:<w$}", cell, w = width);
+            if color && idx == 1 {
+                line.push_str(entry.state.color());
+                line.push_str(&padded);
+                line.push_str("\x1b[0m");
+            } else {
+                line.push_str(&padded);
+            }
+        }
+        out.push_str(line.trim_end());
+        out.push('\n');
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(name: &str, state: SessionState, clients: usize) -> SessionEntry {
+        SessionEntry {
+            name: name.to_string(),
+            state,
+            meta: Some(SessionMeta {
+                session: name.to_string(),
+                command: vec!["zsh".to_string(), "-l".to_string()],
+                pid: 42,
+                clients,
+                last_activity: 100,
+                ..SessionMeta::default()
+            }),
+        }
+    }
+
+    #[test]
+    fn list_args_parse() {
+        let args: Vec<String> = ["--sort", "activity", "work/", "--detached-only"]
+            .map(String::from)
+            .to_vec();
+        let parsed = ListArgs::parse(&args).unwrap();
+        assert_eq!(parsed.prefix, "work/");
+        assert_eq!(parsed.sort, SortKey::Activity);
+        assert!(parsed.filter.detached_only);
+        assert!(ListArgs::parse(&["--filter".to_string()]).is_err());
+        let both = ["--attached-only", "--detached-only"].map(String::from);
+        assert!(ListArgs::parse(&both).is_err());
+    }
+
+    #[test]
+    fn format_age_picks_largest_unit() {
+        assert_eq!(format_age(5), "5s");
+        assert_eq!(format_age(120), "2m");
+        assert_eq!(format_age(7200), "2h");
+        assert_eq!(format_age(3 * 86_400), "3d");
+    }
+
+    #[test]
+    fn format_expands_placeholders_and_escapes() {
+        let e = entry("dev", SessionState::Attached, 2);
+        assert_eq!(
+            e.format(
+                "{name}\\t{cmd}\\t{clients}\\t{state}\\t{activity}\\t{pid}",
+                160
+            ),
+            "dev\tzsh -l\t2\tattached\t1m\t42"
+        );
+
+        let mut dead = entry("build", SessionState::Dead, 0);
+        assert_eq!(dead.format("{exit}", 160), "-");
+        dead.meta.as_mut().unwrap().exit = Some(meta::ExitInfo {
+            code: 137,
+            signal: Some(9),
+            ..meta::ExitInfo::default()
+        });
+        assert_eq!(dead.format("{name} {exit}", 160), "build SIGKILL");
+    }
+
+    #[test]
+    fn glob_match_handles_wildcards() {
+        assert!(glob_match("*", "a/b"));
+        assert!(glob_match("repos/*", "repos/pterm/build"));
+        assert!(glob_match("te?t", "test"));
+        assert!(glob_match("*build", "a/build"));
+        assert!(!glob_match("repos/*", "scratch"));
+        assert!(!glob_match("te?t", "tet"));
+    }
+
+    #[test]
+    fn select_filters_and_sorts() {
+        let mut a = entry("a", SessionState::Detached, 0);
+        a.meta.as_mut().unwrap().last_activity = 10;
+        a.meta.as_mut().unwrap().created_at = 2;
+        let mut b = entry("b", SessionState::Attached, 1);
+        b.meta.as_mut().unwrap().last_activity = 20;
+        b.meta.as_mut().unwrap().created_at = 1;
+        let mut c = entry("c", SessionState::Dead, 0);
+        c.meta.as_mut().unwrap().last_activity = 15;
+        c.meta.as_mut().unwrap().created_at = 3;
+
+        let mut entries = vec![a.clone(), b.clone(), c.clone()];
+        select(&mut entries, &ListFilter::default(), SortKey::Activity);
+        let names: Vec<&str> = entries.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, ["b", "c", "a"]);
+
+        let mut entries = vec![a.clone(), b.clone(), c.clone()];
+        select(&mut entries, &ListFilter::default(), SortKey::Created);
+        assert_eq!(entries[0].name, "b");
+
+        let mut entries = vec![a.clone(), b.clone(), c.clone()];
+        let filter = ListFilter {
+            detached_only: true,
+            ..ListFilter::default()
+        };
+        select(&mut entries, &filter, SortKey::Name);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].name, "a");
+    }
+
+    #[test]
+    fn table_aligns_columns() {
+        let entries = vec![
+            entry("a", SessionState::Detached, 0),
+            entry("long/name", SessionState::Attached, 1),
+        ];
+        let table = render_table(&entries, 100, false);
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(lines[0], "NAME       STATE     CMD     CLIENTS  ACTIVITY");
+        assert_eq!(lines[1], "a          detached  zsh -l  0        0s");
+        assert_eq!(lines[2], "long/name  attached  zsh -l  1        0s");
+    }
+}
@@ -0,0 +1,155 @@
+//! `pterm new --locale` / `--tz`: the locale and time zone of a session.
+//!
+//! A daemon started from a systemd user unit or cron inherits an environment
+//! without `LANG` or `TZ`, so programs in the session fall back to the `C`
+//! locale (mangling UTF-8) and UTC. The child inherits the daemon's
+//! environment, so the daemon sets them on itself right after forking:
+//! `--locale` sets `LANG` and drops inherited `LC_*` overrides, which would
+//! otherwise win over it, and `--tz` sets `TZ`. Both are checked before the
+//! fork, where an error still reaches the user.
+
+use nix::libc;
+use std::ffi::CString;
+use std::path::{Path, PathBuf};
+
+/// Check that `locale` is installed and `tz` names a known time zone.
+pub fn check(locale: Option<&str>, tz: Option<&str>) -> Result<(), String> {
+    if let Some(locale) = locale {
+        check_locale(locale)?;
+    }
+    if let Some(tz) = tz {
+        check_tz(tz, &zoneinfo_dir())?;
+    }
+    Ok(())
+}
+
+/// Set the session's locale and time zone in this process's environment,
+/// which the child inherits.
+pub fn apply(locale: Option<&str>, tz: Option<&str>) {
+    for (name, value) in env_changes(locale, tz, std::env::vars()) {
+        match value {
+            Some(value) => std::env::set_var(name, value),
+            None => std::env::remove_var(name),
+        }
+    }
+}
+
+/// The environment changes for `locale` and `tz` given the current `vars`:
+/// `Some` sets a variable, `None` removes it.
+fn env_changes(
+    locale: Option<&str>,
+    tz: Option<&str>,
+    vars: impl Iterator<Item = (String, String)>,
+) -> Vec<(String, Option<String>)> {
+    let mut changes = Vec::new();
+    if let Some(locale) = locale {
+        changes.extend(
+            vars.filter(|(name, _)| name.starts_with("LC_"))
+                .map(|(name, _)| (name, None)),
+        );
+        changes.push(("LANG".to_string(), Some(locale.to_string())));
+    }
+    if let Some(tz) = tz {
+        changes.push(("TZ".to_string(), Some(tz.to_string())));
+    }
+    changes
+}
+
+fn check_value(what: &str, value: &str) -> Result<(), String> {
+    if value.is_empty() || value.contains('\0') {
+        return Err(format!("invalid {} '{}'", what, value));
+    }
+    Ok(())
+}
+
+fn check_locale(locale: &str) -> Result<(), String> {
+    check_value("locale", locale)?;
+    let name = CString::new(locale).map_err(|e| e.to_string())?;
+    let handle = unsafe { libc::newlocale(libc::LC_ALL_MASK, name.as_ptr(), std::ptr::null_mut()) };
+    if handle.is_null() {
+        return Err(format!(
+            "locale '{}' is not installed (see `locale -a`)",
+            locale
+        ));
+    }
+    unsafe { libc::freelocale(handle) };
+    Ok(())
+}
+
+/// Where the time zone database lives: `$TZDIR`, else the usual place.
+fn zoneinfo_dir() -> PathBuf {
+    std::env::var_os("TZDIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("/usr/share/zoneinfo"))
+}
+
+/// Check `tz` against the zone files in `zoneinfo`. POSIX rules such as
+/// `EST5EDT` name no file and pass, as does anything on a system without
+/// a time zone database.
+fn check_tz(tz: &str, zoneinfo: &Path) -> Result<(), String> {
+    check_value("time zone", tz)?;
+    let name = tz.strip_prefix(':').unwrap_or(tz);
+    let file = if name.starts_with('/') {
+        PathBuf::from(name)
+    } else if name.contains(|c: char| c.is_ascii_digit() || c == ',') && !name.contains('/') {
+        return Ok(());
+    } else if zoneinfo.is_dir() {
+        zoneinfo.join(name)
+    } else {
+        return Ok(());
+    };
+    if !file.is_file() {
+        return Err(format!("unknown time zone '{}'", tz));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sets_lang_and_tz_and_checks_zones() {
+        let vars = [
+            ("LC_ALL", "C"),
+            ("LC_TIME", "C"),
+            ("LANGUAGE", "de"),
+            ("HOME", "/home/me"),
+        ]
+        .map(|(name, value)| (name.to_string(), value.to_string()));
+        let changes = env_changes(
+            Some("en_US.UTF-8"),
+            Some("Asia/Tokyo"),
+            vars.clone().into_iter(),
+        );
+        let changes: Vec<_> = changes
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_deref()))
+            .collect();
+        assert_eq!(
+            changes,
+            [
+                ("LC_ALL", None),
+                ("LC_TIME", None),
+                ("LANG", Some("en_US.UTF-8")),
+                ("TZ", Some("Asia/Tokyo")),
+            ]
+        );
+        assert!(env_changes(None, None, vars.into_iter()).is_empty());
+
+        let zoneinfo = std::env::temp_dir().join(format!("pterm-tz-test-{}", std::process::id()));
+        std::fs::create_dir_all(zoneinfo.join("Asia")).unwrap();
+        std::fs::write(zoneinfo.join("Asia/Tokyo"), b"TZif").unwrap();
+        assert_eq!(check_tz("Asia/Tokyo", &zoneinfo), Ok(()));
+        assert_eq!(check_tz(":Asia/Tokyo", &zoneinfo), Ok(()));
+        assert_eq!(check_tz("EST5EDT,M3.2.0,M11.1.0", &zoneinfo), Ok(()));
+        assert!(check_tz("Asia/Tokio", &zoneinfo).is_err());
+        assert!(check_tz("", &zoneinfo).is_err());
+        // Without a database there is nothing to check against.
+        assert_eq!(check_tz("Asia/Tokio", &zoneinfo.join("missing")), Ok(()));
+        std::fs::remove_dir_all(&zoneinfo).unwrap();
+
+        assert_eq!(check_locale("C"), Ok(()));
+        assert!(check_locale("xx_NOWHERE.UTF-8").is_err());
+    }
+}
@@ -1,16 +1,58 @@
+mod archive;
+mod bench;
 mod bridge;
+mod client;
+mod clients;
+mod config;
 mod constants;
+mod control;
+mod doctor;
+mod elevate;
+mod error;
+mod exec;
+mod frame_log;
+mod guard;
+mod here;
+mod history;
+mod hook;
+mod import;
+mod kill;
+mod list;
+mod locale;
+mod meta;
+mod mux;
+mod options;
+mod panes;
+mod paste;
 mod paths;
 mod pty;
+mod query;
+mod recording;
+mod registry;
+mod sandbox;
+mod schedule;
+mod scrollback;
+mod selfupdate;
 mod server;
 mod session;
+mod session_key;
+mod share;
+mod stats;
+mod status_bar;
+mod template;
+mod tmux_cc;
+mod transfer;
+mod upgrade;
+mod wait;
+mod watch;
 
-use crate::paths::{find_sessions, session_dir, session_socket_path, socket_dir, SOCKET_FILENAME};
+use crate::paths::{session_dir, session_socket_path, SOCKET_FILENAME};
+use error::{Error, PathContext, Result};
 use server::Server;
 use session::Session;
-use std::io;
+use std::io::{self, Write};
 use std::os::unix::fs::FileTypeExt;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::time::{Duration, Instant};
 
 fn print_usage() {
@@ -18,15 +60,270 @@ fn print_usage() {
         "pterm - persistent terminal daemon
 
 Usage:
-  pterm new    <session-name> [--] <command> [args...]
-  pterm attach <session-name>
+  pterm [--socket-dir <dir>] <command> ...
+               # use <dir> as the socket root instead of the default
+  pterm new    [options] <session-name> [--] <command> [args...]
+  pterm attach [options] <session-name>
                # attach to session (bridge mode)
-  pterm open   <session-name> [--] <command> [args...]
+  pterm open   [options] <session-name> [--] <command> [args...]
                # attach if exists, otherwise create and attach
-  pterm list   [prefix]
-  pterm kill   <session-name>
+  pterm here   [--print] [<suffix>] [-- <command> [args...]]
+               # open (create or attach) repos/<project>[/<suffix>], where
+               # <project> is the current git repository (or directory);
+               # --print only prints the name
+  pterm up     <template.toml> [--var <name>=<value>]... [--dry-run]
+               # start the sessions described by a template that are not
+               # running yet; {{{{var}}}}, {{{{project_dir}}}}, {{{{branch}}}} and
+               # {{{{env.NAME}}}} are filled in
+  pterm list   [prefix] [--format <template>] [--sort activity|name|created]
+               [--attached-only | --detached-only] [--filter <glob>] [--scan]
+               # table of NAME/STATE/CMD/CLIENTS/ACTIVITY; --format takes
+               # {{name}} {{state}} {{cmd}} {{clients}} {{activity}} {{pid}}
+               # {{exit}} (exit status, or the signal that killed it); --scan
+               # rebuilds the session registry from the socket directories
+  pterm kill   <session-name> | <prefix> --recursive | --all [--dry-run]
+               # kill a session and the sessions below it; --recursive a
+               # prefix that is no session itself, --all every session in
+               # every socket root; --dry-run lists them instead
   pterm redraw <session-name>   # redraw terminal (resend snapshot)
+  pterm clients <session-name> [--json]
+               # connected clients: identity, traffic, pending buffer, mode
+  pterm info   <session-name> [--activity [--minutes <n>]] [--json]
+               # session TERM, size, command, cwd, child and foreground pid
+               # and pty device; --activity: bytes out and in per minute
+               # over the last <n> (60) minutes, as sparklines
+  pterm metadata <session-name> [--json]
+               # child pid, command, cwd, size, scrollback lines in use and
+               # attached/connected clients
+  pterm command <session-name> <cmd> [<args-json>]
+               # send a named command and print its JSON result: query
+               # {{\"what\": \"info\"|\"metadata\"|\"options\"}}, setenv
+               # {{\"name\": ..., \"value\": ...|null}} (for the on-output
+               # commands the daemon starts from then on)
+  pterm strace <session-name> [--child] [-- <strace args>...]
+               # strace the session's foreground process (--child: its
+               # command)
+  pterm stats  [prefix] [--json]
+               # cumulative CPU time and PTY output/input bytes, summed per
+               # name prefix one level below [prefix]
+  pterm query  list [list options] | info <session-name>
+  pterm query  --stdin-loop
+               # answer as one JSON line ({{\"sessions\"}} / {{\"info\"}} /
+               # {{\"error\"}}); --stdin-loop answers one query per stdin line
+               # until EOF, for editor plugins
+  pterm set    <session-name> [<option> <value>]
+               # show or change live session options: scrollback <lines>,
+               # idle-timeout <secs>, idle-warning <secs>, hold on|off,
+               # replay-filter on|off, output-throttle <bytes/s>,
+               # auto-resume on|off,
+               # guard <patterns>|off, latency low|throughput,
+               # detached scrollback|log|pause
+               # (0 disables timeouts/limits)
+  pterm suspend <session-name> [--auto-resume]
+               # freeze the session's processes (SIGSTOP); with
+               # --auto-resume, attaching or typing continues them
+  pterm resume <session-name>   # SIGCONT a suspended session
+  pterm signal <session-name> <signal>
+               # send a signal (INT, SIGHUP, 15, ...) to the session's
+               # process group and foreground job
+  pterm respawn <session-name> [--force]
+               # run the session's command again once it has exited (with
+               # `hold` on or a client attached), keeping its scrollback;
+               # --force kills it first, and attached clients stay
+  pterm at     <HH:MM|+duration> [--if-detached] <action> <session-glob>
+               [-- <command>...]
+  pterm every  <interval> [--if-detached] <action> <session-glob>
+               [-- <command>...]
+               # run kill, suspend, resume or exec (type <command> into the
+               # session) once / repeatedly; durations like 90s, 5m, 1h30m, 1d
+  pterm schedule [rm <id>...]   # list or remove scheduled jobs
+  pterm on-output <session-name> [--pattern <text>... -- <command>...]
+  pterm on-output <session-name> rm <id>...
+               # run <command> (with $PTERM_SESSION and $PTERM_MATCH set)
+               # whenever an output line contains a pattern; without
+               # arguments, list the session's watches
+  pterm on-exit <session-name> [--lines <n>] -- <command>...
+  pterm on-exit <session-name> rm
+               # run <command> once the session's command exits, with
+               # $PTERM_EXIT_CODE (and $PTERM_EXIT_SIGNAL) set and its last
+               # <n> (20) lines of output, plain text, on stdin; without
+               # arguments, show the hook
+  pterm guard  <session-name> [--require-confirm <patterns> | --off]
+               # hold back Enter on command lines containing any of the
+               # '|'-separated patterns until the attached client confirms
+  pterm send   <session-name> [--no-newline] [--] <text>...
+               # type <text> (words joined by spaces) and Enter into the
+               # session, without attaching
+  pterm exec   <session-name> [--no-wrap] [--timeout <secs>] -- <command>...
+               # type <command> into the session's shell and exit with its
+               # status; --no-wrap relies on the shell's OSC 133 integration
+  pterm wait   [--any | --all] [--timeout <secs>] [--json] <session-name>...
+               # block until the sessions' commands exit (--all, the
+               # default) or the first does (--any), printing each as it
+               # finishes; exits with the first failure (or the first
+               # finisher's status with --any), 124 on timeout
+  pterm detach <session-name> [--client <id>] [--reason <text>]
+               # disconnect all (or one) attached clients, keep the session;
+               # their terminals show --reason
+  pterm transfer <session-name> <user>
+               # hand the session to another user (through the --as-root
+               # wrapper); clients of other users are detached
+  pterm share  <session-name> [--duration <d>] [--json]
+               # print a one-time token (and the attach / ssh command lines)
+               # that lets another user watch the session read-only until it
+               # expires (1h by default); needs a shared socket root
+  pterm unshare <session-name>
+               # revoke the session's share tokens and detach its guests
   pterm socket <session-name>   # print socket path
+  pterm scrollback <session-name> [--tail <size>] [--stderr]
+               # print raw output kept by --scrollback-file (pipe to less -R),
+               # or only what the command wrote to stderr (--split-stderr)
+  pterm history <session-name> [--since <when>] [--from-line <n>]
+               [--search <text>]
+               # print output archived by --history since a duration ago
+               # (2h) or unix time, or from a line on; --search prints the
+               # numbered lines containing <text>
+  pterm capture <session-name> [--rewrap] [-o <file>]
+               # print the scrollback and screen as text (or write them to
+               # <file>), without attaching; --rewrap joins lines broken at
+               # the width the terminal had when they were written
+  pterm copy   <session-name> [--all | --lines <n> | --last-command] [--print]
+               # copy the screen (or scrollback, the last <n> lines, or the
+               # last command's output) into the shared paste buffer
+  pterm copy-output <session-name> [--all | --lines <n> | --last-command]
+               [--print]
+               # the same onto the local clipboard, through OSC 52 to the
+               # terminal pterm runs in
+  pterm paste-buffer <session-name> | --print
+               # type the paste buffer into a session (bracketed if the
+               # application asked for it), or print it
+  pterm panes  --wezterm|--kitty [--split|--window] [--dry-run] [<session-glob>]
+               # open `pterm attach` for each matching session in a new tab
+               # (or split / OS window) via the terminal's remote control
+  pterm export <session-name> [-o <file>]
+               # bundle metadata, output and --record-io recording into a
+               # .tar.zst (default <session-name>.tar.zst)
+  pterm import <file> [--as <session-name>]
+               # recreate an exported session as a held, exited session
+               # showing its output
+  pterm import-dtach [--abduco] <socket> [<session-name>]
+               # adopt a running dtach (or abduco) session; the pterm session
+               # proxies to it and ends when the original process exits
+  pterm client <session-name>
+               # attach with pterm's own screen rendering, status line,
+               # scrollback search and copy mode (prefix key Ctrl-])
+  pterm tmux-cc [-C] [<session-name>]
+               # speak tmux control mode (-CC) on stdin/stdout for tools
+               # such as iTerm2; -C omits the DCS wrapper
+  pterm mux    # carry several sessions' frames over stdin/stdout, each on
+               # a channel opened with OPEN_CHANNEL (for GUI frontends)
+  pterm version [--json] [--check]
+               # binary/protocol version and daemon versions; --check exits
+               # 1 if a running daemon is not on this binary's version
+  pterm doctor [--fix] [--json]
+               # find stale and pre-hierarchy sockets, unsafe directory
+               # permissions and malformed metadata; --fix repairs them and
+               # reports what it changed; exits 1 if problems remain
+  pterm bench  [--payload-size <size>] [--duration <secs>] [--json]
+               # measure output throughput, frame rate and latency
+               # percentiles through an ephemeral session (default 4K
+               # writes for 5s)
+  pterm upgrade-daemons [prefix]
+               # re-exec running daemons into this binary, keeping sessions
+  pterm self-update [--version <v>] [--check]
+               # replace this binary with the signed release binary (default
+               # the latest release); --check only reports whether one is newer
+  pterm self-install [--dir <dir>] [--version <v>]
+               # install the signed release binary of this version (or <v>)
+               # as <dir>/pterm (default ~/.local/bin)
+  pterm replay-io <dir> [--screen]
+               # replay a --record-io recording and verify its snapshots
+
+Options for new/open:
+  --record-io <dir>  Record daemon I/O to <dir> for `pterm replay-io`
+  --scrollback-file <size>
+                     Keep the newest <size> bytes (e.g. 256M) of raw output in
+                     a memory-mapped file; read it with `pterm scrollback`
+  --strip-colors     Drop SGR (color/attribute) sequences from what is stored
+                     in the --scrollback-file; live output keeps them
+  --history          Archive all output as zstd-compressed, indexed blocks;
+                     read it with `pterm history`
+  --split-stderr     Give the command a pipe as stderr instead of the pty;
+                     stderr is still shown, but also kept on its own for
+                     `pterm scrollback --stderr` and tagged for clients
+  --sandbox          Confine the daemon with seccomp and landlock once the
+                     command runs (Linux); output watchers and hot upgrades
+                     are unavailable for such sessions
+  --encrypt          Encrypt every connection to the session with a key only
+                     its owner can read; attach and one-shot commands use it,
+                     other clients are refused
+  --banner, --no-banner
+                     Write (or not) a line naming the session, its creation
+                     time and how to detach into its scrollback; defaults to
+                     `banner` in the config file (off)
+  --motd <text>      Message shown below the banner (implies --banner)
+  --hold             Start with the `hold` option on: keep the session after
+                     the command exits
+  --pause-when-detached
+                     Start with `detached pause`: stop the command's output
+                     (it blocks on its next write) while no client is
+                     attached, for jobs that must not run unobserved
+  --on-exit <command-line>
+                     Run <command-line> with `sh -c` once the command exits,
+                     as `pterm on-exit` does
+  --locale <name>    Run the command with LANG=<name> (e.g. en_US.UTF-8)
+                     instead of the inherited locale
+  --tz <zone>        Run the command with TZ=<zone> (e.g. Europe/Berlin)
+
+Options for new/attach/open:
+  --as-root          Rerun through doas/sudo/pkexec (or $PTERM_ROOT_WRAPPER)
+                     as root against a root-only socket root (default
+                     /tmp/pterm-0), for persistent root shells
+
+Options for new:
+  --json             Print {{session, pid, socket, protocol_version}} (default)
+  --print-socket     Print only the absolute socket path
+  --wait-ready       Return only once the daemon accepts connections
+
+Options for attach/open:
+  --direct-input     Write input straight to the session pty via a passed fd
+                     instead of through the daemon (high-throughput input)
+  --read-only        View the session without sending input or resizing it
+  --view             (attach) Page through the scrollback first, without
+                     connecting input: / searches, [ ] jump between OSC 133
+                     prompts, a attaches live, q quits
+  --force-nested     (attach) Attach even from inside another session
+                     ($PTERM_SESSION set), which is refused otherwise
+  --token <token>    (attach) Watch another user's session read-only with a
+                     token from `pterm share`, giving the session as the
+                     socket path it printed
+  --debug-frames <logfile>
+                     (attach) Log every output frame to <logfile> as JSON
+                     lines, numbered and checksummed by the daemon, to find
+                     frames lost or reordered on the way to this client
+  --status-bar       Keep the bottom row for a bar showing the session's name,
+                     size, attached clients and last activity
+  --compress         Ask the daemon for zstd-compressed output (for a socket
+                     forwarded over a slow link); ignored by older daemons
+  --checksum         Ask for CRC32-checked frames (for a socket tunnelled over
+                     a link that can corrupt data); a corrupted frame drops
+                     the connection, which then resumes; ignored by older
+                     daemons
+  --replay <lines>   Also replay up to <lines> of scrollback into the
+                     terminal's history on attach
+  --replay-bytes <size>
+                     Replay at most <size> of scrollback (e.g. 64K)
+  --no-replay        Skip the screen snapshot on attach, for a terminal that
+                     already shows the session; only new output is drawn
+  --cols <n>, --rows <n>
+                     Attach with this size instead of the terminal's
+                     (clamped to 1..10000)
+  --connect-timeout <seconds>
+                     Keep retrying while the daemon is still starting
+  --retry <n>        Retry a refused/missing socket up to n times
+  --events-fd <fd>   Write lifecycle events (connected, scrollback-replayed,
+                     reconnected, resized, exited, detached, disconnected)
+                     to <fd> as JSON lines
 
 Session names may contain '/' for hierarchical sessions:
   pterm new    parent
@@ -35,22 +332,229 @@ Session names may contain '/' for hierarchical sessions:
 
 Environment:
   PTERM_SOCKET_DIR   Override socket directory
-  SHELL              Default command if none specified"
+  PTERM_ROOT_WRAPPER Privilege wrapper for --as-root, e.g. \"doas -n\"
+  PTERM_DEFAULT_COMMAND
+                     Default command (with arguments) if none specified,
+                     e.g. \"zsh -l\"; overrides default_command in the config
+  PTERM_CONFIG       Config file (default: ~/.config/pterm/config.toml)
+  PTERM_READ_BUFFER, PTERM_DRAIN_LIMIT, PTERM_SEND_BUFFER_WARN, PTERM_MAX_FRAME,
+  PTERM_RESUME_BUFFER
+                     Buffer sizes such as 256K; override [buffers] in the
+                     config
+  SHELL              Default command if neither of the above is set"
     );
 }
 
-fn cmd_new(args: &[String], quiet: bool) -> io::Result<()> {
+/// Arguments accepted by `pterm new` / `pterm open`.
+struct NewArgs {
+    session_name: String,
+    cmd_args: Vec<String>,
+    record_io: Option<PathBuf>,
+    /// Size of the memory-mapped scrollback file, if any.
+    scrollback_file: Option<usize>,
+    /// Drop SGR sequences from the scrollback file (`--strip-colors`).
+    strip_colors: bool,
+    /// Archive all output compressed (`--history`).
+    history: bool,
+    /// Give the child a pipe as stderr (`--split-stderr`).
+    split_stderr: bool,
+    /// `--banner` / `--no-banner`; the config file decides when unset.
+    banner: Option<bool>,
+    /// Message of the day below the banner (`--motd`).
+    motd: Option<String>,
+    /// `LANG` of the child (`--locale`).
+    locale: Option<String>,
+    /// `TZ` of the child (`--tz`).
+    tz: Option<String>,
+    /// Start with the `hold` option on (`--hold`).
+    hold: bool,
+    /// Start with `detached pause` (`--pause-when-detached`).
+    pause_when_detached: bool,
+    /// Exit hook shell command (`--on-exit`).
+    on_exit: Option<String>,
+    /// `open` only: bridge options (`--direct-input`, `--read-only`,
+    /// `--connect-timeout`, `--retry`).
+    bridge: bridge::Options,
+    /// `new` only: print the socket path instead of JSON.
+    print_socket: bool,
+    /// `new` only: return once the daemon accepts connections.
+    wait_ready: bool,
+    /// Rerun as root through a privilege wrapper (`--as-root`).
+    as_root: bool,
+    /// Confine the daemon with seccomp and landlock (`--sandbox`).
+    sandbox: bool,
+    /// Require encrypted connections (`--encrypt`).
+    encrypt: bool,
+    /// `--json`: errors are reported as JSON too.
+    json: bool,
+}
+
+/// Parse `[options] <session-name> [options] [--] <command> [args...]`.
+/// Options are only recognized before the command starts, so flags meant
+/// for the child (e.g. `pterm new dev zsh -l`) are passed through untouched.
+fn parse_new_args(args: &[String]) -> Result<NewArgs, String> {
     let mut session_name = String::new();
     let mut cmd_args: Vec<String> = Vec::new();
+    let mut record_io = None;
+    let mut scrollback_file = None;
+    let mut strip_colors = false;
+    let mut history = false;
+    let mut split_stderr = false;
+    let mut sandbox = false;
+    let mut encrypt = false;
+    let mut banner = None;
+    let mut motd = None;
+    let mut locale = None;
+    let mut tz = None;
+    let mut hold = false;
+    let mut pause_when_detached = false;
+    let mut on_exit = None;
+    let mut json = false;
+    let mut bridge = bridge::Options::default();
+    let mut print_socket = false;
+    let mut wait_ready = false;
+    let mut as_root = false;
     let mut parsing_opts = true;
 
     let mut i = 0;
     while i < args.len() {
-        if parsing_opts && args[i] == "--" {
+        let arg = args[i].as_str();
+        if parsing_opts && arg == "--" {
             parsing_opts = false;
             i += 1;
             continue;
         }
+        if parsing_opts && cmd_args.is_empty() && arg == "--record-io" {
+            let dir = args
+                .get(i + 1)
+                .ok_or_else(|| "--record-io requires a directory".to_string())?;
+            record_io = Some(std::path::absolute(dir).map_err(|e| e.to_string())?);
+            i += 2;
+            continue;
+        }
+        if parsing_opts && cmd_args.is_empty() && arg == "--scrollback-file" {
+            let size = args
+                .get(i + 1)
+                .and_then(|s| scrollback::parse_size(s))
+                .filter(|&size| size > 0)
+                .ok_or_else(|| "--scrollback-file requires a size (e.g. 256M)".to_string())?;
+            scrollback_file = Some(size);
+            i += 2;
+            continue;
+        }
+        if parsing_opts && cmd_args.is_empty() && arg == "--motd" {
+            let text = args
+                .get(i + 1)
+                .ok_or_else(|| "--motd requires a message".to_string())?;
+            motd = Some(text.clone());
+            i += 2;
+            continue;
+        }
+        if parsing_opts && cmd_args.is_empty() && arg == "--on-exit" {
+            let line = args
+                .get(i + 1)
+                .ok_or_else(|| "--on-exit requires a command".to_string())?;
+            on_exit = Some(line.clone());
+            i += 2;
+            continue;
+        }
+        if parsing_opts && cmd_args.is_empty() && (arg == "--locale" || arg == "--tz") {
+            let value = args
+                .get(i + 1)
+                .ok_or_else(|| format!("{} requires a value", arg))?;
+            if arg == "--locale" {
+                locale = Some(value.clone());
+            } else {
+                tz = Some(value.clone());
+            }
+            i += 2;
+            continue;
+        }
+        if parsing_opts && cmd_args.is_empty() && parse_bridge_opt(args, &mut i, &mut bridge)? {
+            continue;
+        }
+        if parsing_opts && cmd_args.is_empty() {
+            let known = match arg {
+                "--direct-input" => {
+                    bridge.direct_input = true;
+                    true
+                }
+                "--read-only" => {
+                    bridge.read_only = true;
+                    true
+                }
+                "--status-bar" => {
+                    bridge.status_bar = true;
+                    true
+                }
+                "--compress" => {
+                    bridge.compress = true;
+                    true
+                }
+                "--checksum" => {
+                    bridge.checksum = true;
+                    true
+                }
+                "--print-socket" => {
+                    print_socket = true;
+                    true
+                }
+                "--wait-ready" => {
+                    wait_ready = true;
+                    true
+                }
+                "--as-root" => {
+                    as_root = true;
+                    true
+                }
+                "--strip-colors" => {
+                    strip_colors = true;
+                    true
+                }
+                "--history" => {
+                    history = true;
+                    true
+                }
+                "--split-stderr" => {
+                    split_stderr = true;
+                    true
+                }
+                "--sandbox" => {
+                    sandbox = true;
+                    true
+                }
+                "--encrypt" => {
+                    encrypt = true;
+                    true
+                }
+                "--banner" => {
+                    banner = Some(true);
+                    true
+                }
+                "--no-banner" => {
+                    banner = Some(false);
+                    true
+                }
+                "--hold" => {
+                    hold = true;
+                    true
+                }
+                "--pause-when-detached" => {
+                    pause_when_detached = true;
+                    true
+                }
+                // JSON is the default output; accepted for explicitness.
+                "--json" => {
+                    json = true;
+                    true
+                }
+                _ => false,
+            };
+            if known {
+                i += 1;
+                continue;
+            }
+        }
         if session_name.is_empty() {
             session_name = args[i].clone();
         } else {
@@ -60,56 +564,304 @@ fn cmd_new(args: &[String], quiet: bool) -> io::Result<()> {
     }
 
     if session_name.is_empty() {
-        eprintln!("Error: session name required");
-        std::process::exit(1);
+        return Err("session name required".to_string());
+    }
+    if strip_colors && scrollback_file.is_none() {
+        return Err("--strip-colors requires --scrollback-file".to_string());
+    }
+
+    Ok(NewArgs {
+        session_name,
+        cmd_args,
+        record_io,
+        scrollback_file,
+        strip_colors,
+        history,
+        split_stderr,
+        banner,
+        motd,
+        locale,
+        tz,
+        hold,
+        pause_when_detached,
+        on_exit,
+        bridge,
+        print_socket,
+        wait_ready,
+        as_root,
+        sandbox,
+        encrypt,
+        json,
+    })
+}
+
+/// Buffer sizes from the environment and config file; invalid ones are
+/// fatal.
+fn buffers() -> Result<config::Buffers> {
+    config::Config::load()
+        .map_err(|e| e.to_string())
+        .and_then(|config| config::buffers(&config))
+        .map_err(Error::Failed)
+}
+
+/// Parse `--connect-timeout <seconds>` / `--retry <n>` at `args[*i]`.
+/// Returns `true` (and advances `i`) if one was consumed.
+fn parse_connect_opt(
+    args: &[String],
+    i: &mut usize,
+    options: &mut bridge::Options,
+) -> Result<bool, String> {
+    let arg = args[*i].as_str();
+    if arg != "--connect-timeout" && arg != "--retry" {
+        return Ok(false);
+    }
+    let value = args
+        .get(*i + 1)
+        .ok_or_else(|| format!("{} requires a value", arg))?;
+    if arg == "--connect-timeout" {
+        let secs: f64 = value
+            .parse()
+            .map_err(|_| format!("invalid --connect-timeout '{}' (seconds)", value))?;
+        let timeout = Duration::try_from_secs_f64(secs)
+            .map_err(|_| format!("invalid --connect-timeout '{}' (seconds)", value))?;
+        options.connect_timeout = Some(timeout);
+    } else {
+        options.retries = value
+            .parse()
+            .map_err(|_| format!("invalid --retry '{}'", value))?;
+    }
+    *i += 2;
+    Ok(true)
+}
+
+/// Parse a bridge option of `attach`/`open` that takes a value (or
+/// `--no-replay`) at `args[*i]`. Returns `true` (and advances `i`) if one
+/// was consumed.
+fn parse_bridge_opt(
+    args: &[String],
+    i: &mut usize,
+    options: &mut bridge::Options,
+) -> Result<bool, String> {
+    Ok(parse_connect_opt(args, i, options)?
+        || parse_replay_opt(args, i, options)?
+        || parse_size_opt(args, i, options)?
+        || parse_events_opt(args, i, options)?)
+}
+
+/// Parse `--events-fd <fd>` at `args[*i]`. Returns `true` (and advances
+/// `i`) if it was consumed.
+fn parse_events_opt(
+    args: &[String],
+    i: &mut usize,
+    options: &mut bridge::Options,
+) -> Result<bool, String> {
+    if args[*i] != "--events-fd" {
+        return Ok(false);
+    }
+    let value = args
+        .get(*i + 1)
+        .ok_or_else(|| "--events-fd requires a value".to_string())?;
+    let fd: i32 = value
+        .parse()
+        .ok()
+        .filter(|&fd| fd >= 0)
+        .ok_or_else(|| format!("invalid --events-fd '{}'", value))?;
+    if unsafe { nix::libc::fcntl(fd, nix::libc::F_GETFD) } < 0 {
+        return Err(format!("--events-fd {}: not an open file descriptor", fd));
+    }
+    options.events_fd = Some(fd);
+    *i += 2;
+    Ok(true)
+}
+
+/// Parse `--cols <n>` / `--rows <n>` at `args[*i]`. Returns `true` (and
+/// advances `i`) if one was consumed.
+fn parse_size_opt(
+    args: &[String],
+    i: &mut usize,
+    options: &mut bridge::Options,
+) -> Result<bool, String> {
+    let arg = args[*i].as_str();
+    if arg != "--cols" && arg != "--rows" {
+        return Ok(false);
+    }
+    let value = args
+        .get(*i + 1)
+        .ok_or_else(|| format!("{} requires a value", arg))?;
+    let n: u16 = value
+        .parse()
+        .map_err(|_| format!("invalid {} '{}'", arg, value))?;
+    if arg == "--cols" {
+        options.cols = Some(n);
+    } else {
+        options.rows = Some(n);
+    }
+    *i += 2;
+    Ok(true)
+}
+
+/// Parse `--replay <lines>` / `--replay-bytes <size>` / `--no-replay` at
+/// `args[*i]`. Returns `true` (and advances `i`) if one was consumed.
+fn parse_replay_opt(
+    args: &[String],
+    i: &mut usize,
+    options: &mut bridge::Options,
+) -> Result<bool, String> {
+    let arg = args[*i].as_str();
+    match arg {
+        "--no-replay" => {
+            options.replay.skip_replay = true;
+            *i += 1;
+            return Ok(true);
+        }
+        "--replay" | "--replay-bytes" => {}
+        _ => return Ok(false),
+    }
+    let value = args
+        .get(*i + 1)
+        .ok_or_else(|| format!("{} requires a value", arg))?;
+    if arg == "--replay" {
+        options.replay.scrollback_lines = value
+            .parse()
+            .map_err(|_| format!("invalid --replay '{}' (lines)", value))?;
+    } else {
+        let bytes = scrollback::parse_size(value)
+            .ok_or_else(|| format!("invalid --replay-bytes '{}' (e.g. 64K)", value))?;
+        options.replay.scrollback_bytes = Some(bytes);
+    }
+    *i += 2;
+    Ok(true)
+}
+
+fn cmd_new(args: &[String], quiet: bool) -> Result<()> {
+    let NewArgs {
+        session_name,
+        mut cmd_args,
+        record_io,
+        scrollback_file,
+        strip_colors,
+        history,
+        split_stderr,
+        banner,
+        motd,
+        locale,
+        tz,
+        hold,
+        pause_when_detached,
+        on_exit,
+        print_socket,
+        wait_ready,
+        as_root,
+        sandbox,
+        encrypt,
+        json,
+        ..
+    } = parse_new_args(args).map_err(|e| Error::Failed(e.to_string()))?;
+    if json {
+        error::report_as_json();
+    }
+    if as_root {
+        return Err(Error::from(elevate::exec_as_root("new", args)));
     }
 
+    let config = config::Config::load().map_err(|e| e.to_string());
+
     // Default command
     if cmd_args.is_empty() {
-        let shell = std::env::var("SHELL").unwrap_or_else(|_| "/bin/sh".to_string());
-        cmd_args.push(shell);
+        cmd_args = config
+            .clone()
+            .and_then(|config| config::default_command(&config))
+            .map_err(|e| Error::Failed(format!("default command: {}", e)))?;
+    }
+    let config = config.unwrap_or_default();
+    let buffers = config::buffers(&config).map_err(|e| Error::Failed(e.to_string()))?;
+    if let Some(dir) = config
+        .fallback_socket_dir
+        .as_ref()
+        .filter(|d| !d.is_absolute())
+    {
+        return Err(Error::Usage(format!(
+            "fallback_socket_dir must be an absolute path, not '{}'",
+            dir.display()
+        )));
+    }
+    // A `--motd` given on the command line is meant to be shown.
+    let banner = banner.unwrap_or(config.banner || motd.is_some());
+    let motd = motd.or(config.motd);
+    let locale = locale.or(config.locale);
+    let tz = tz.or(config.tz);
+    if let Err(e) = locale::check(locale.as_deref(), tz.as_deref()) {
+        return Err(Error::Failed(e.to_string()));
     }
 
-    let sess_dir = session_dir(&session_name);
+    let sess_dir = std::path::absolute(paths::new_session_dir(&session_name))?;
     let sock_path = sess_dir.join(SOCKET_FILENAME);
 
     // Clean up stale socket file from pre-hierarchy daemon layout.
-    // Old daemons created the socket directly at `<socket_dir>/<name>` instead
-    // of `<socket_dir>/<name>/socket`. Remove it so we can create the directory.
-    if sess_dir.exists() && !sess_dir.is_dir() {
-        let meta = std::fs::symlink_metadata(&sess_dir)?;
-        if meta.file_type().is_socket() {
-            std::fs::remove_file(&sess_dir)?;
-        } else {
-            eprintln!(
-                "Error: '{}' exists and is not a directory",
-                sess_dir.display()
-            );
-            std::process::exit(1);
-        }
+    if let Err(e) = paths::remove_legacy_socket(&sess_dir) {
+        return Err(Error::Failed(e.to_string()));
     }
 
     if sock_path.exists() {
-        eprintln!("Error: session '{}' already exists", session_name);
-        std::process::exit(1);
+        return Err(Error::Failed(format!(
+            "session '{}' already exists",
+            session_name
+        )));
+    }
+    let enclosing = paths::enclosing_session().map(|(name, _)| name);
+    if let Some(enclosing) = enclosing.as_ref().filter(|_| !quiet) {
+        eprintln!(
+            "Warning: already inside session '{}'; attaching to '{}' from here nests it",
+            enclosing, session_name
+        );
+    }
+
+    // Fail here rather than in the daemon, whose errors nobody sees.
+    if sandbox {
+        if let Err(e) = sandbox::check() {
+            return Err(Error::Failed(format!(
+                "--sandbox is not available here: {}",
+                e
+            )));
+        }
     }
 
     // Create session directory (including parent directories for hierarchical names)
-    std::fs::create_dir_all(&sess_dir)?;
+    paths::ensure_socket_dir()?;
+    std::fs::create_dir_all(&sess_dir).at_path(&sess_dir)?;
+    // Before the socket exists, so the session is never reachable in the
+    // clear; a key left by an earlier session of this name goes.
+    session_key::remove(&sess_dir)?;
+    if encrypt {
+        session_key::create(&sess_dir)?;
+    }
 
     // Daemonize: fork into background
     match unsafe { nix::unistd::fork() } {
         Ok(nix::unistd::ForkResult::Parent { child }) => {
+            if wait_ready && !wait_until_ready(&sock_path, child, READY_TIMEOUT)? {
+                return Err(Error::Failed(format!(
+                    "session '{}' did not become ready within {}s",
+                    session_name,
+                    READY_TIMEOUT.as_secs()
+                )));
+            }
             // Parent: print info and return.
             // Suppress output when called from cmd_open to avoid JSON
             // leaking into the Neovim terminal buffer.
-            if !quiet {
+            if quiet {
+                return Ok(());
+            }
+            if print_socket {
+                println!("{}", sock_path.display());
+            } else {
                 println!(
                     "{}",
                     serde_json::json!({
                         "session": session_name,
                         "pid": child.as_raw(),
                         "socket": sock_path.to_string_lossy(),
+                        "protocol_version": pterm_proto::PROTOCOL_VERSION,
                     })
                 );
             }
@@ -124,10 +876,14 @@ fn cmd_new(args: &[String], quiet: bool) -> io::Result<()> {
             nix::unistd::dup2(devnull.as_raw_fd(), 0).ok();
             nix::unistd::dup2(devnull.as_raw_fd(), 1).ok();
             nix::unistd::dup2(devnull.as_raw_fd(), 2).ok();
+            locale::apply(locale.as_deref(), tz.as_deref());
+            // Inherited by the child, so programs in the session can tell
+            // they run under pterm, and in which session.
+            std::env::set_var(paths::SESSION_ENV, &session_name);
+            std::env::set_var(paths::SOCKET_ENV, &sock_path);
         }
         Err(e) => {
-            eprintln!("Fork failed: {}", e);
-            std::process::exit(1);
+            return Err(Error::Failed(format!("Fork failed: {}", e)));
         }
     }
 
@@ -141,82 +897,143 @@ fn cmd_new(args: &[String], quiet: bool) -> io::Result<()> {
     let cmd = &cmd_args[0];
     let str_args: Vec<&str> = cmd_args.iter().map(|s| s.as_str()).collect();
 
-    let session = Session::new(session_name, cmd, &str_args)?;
+    let session = Session::new(session_name.clone(), cmd, &str_args, split_stderr)?;
     let mut server = Server::new(&sess_dir, session)?;
+    server.set_buffers(buffers);
+    // A session in $XDG_RUNTIME_DIR outlives the directory's cleanup on logout.
+    let root = std::path::absolute(paths::new_session_root(&session_name))?;
+    if paths::in_runtime_dir(&root) {
+        let home = paths::SocketHome {
+            root,
+            dir: sess_dir.clone(),
+        };
+        server.set_socket_home(home, config.fallback_socket_dir.clone());
+    }
+    if let Some(dir) = record_io {
+        let recorded = recording::RecordedSession {
+            session: session_name,
+            command: cmd_args.clone(),
+            cols: constants::DEFAULT_TERMINAL_COLS,
+            rows: constants::DEFAULT_TERMINAL_ROWS,
+        };
+        match recording::Recorder::create(&dir, &recorded) {
+            Ok(recorder) => server.record_io(recorder, dir),
+            Err(e) => log::warn!("Failed to start I/O recording in {}: {}", dir.display(), e),
+        }
+    }
+    if let Some(size) = scrollback_file {
+        let path = sess_dir.join(scrollback::SCROLLBACK_FILENAME);
+        match scrollback::ScrollbackBuffer::open_file(&path, size) {
+            Ok(mut buffer) => {
+                buffer.set_strip_colors(strip_colors);
+                server.keep_scrollback(buffer);
+            }
+            Err(e) => log::warn!("Failed to map scrollback file {}: {}", path.display(), e),
+        }
+    }
+    if split_stderr {
+        let path = sess_dir.join(scrollback::STDERR_FILENAME);
+        let size = scrollback_file.unwrap_or(scrollback::DEFAULT_STDERR_FILE_SIZE);
+        match scrollback::ScrollbackBuffer::open_file(&path, size) {
+            Ok(buffer) => server.keep_stderr(buffer),
+            Err(e) => log::warn!("Failed to map stderr file {}: {}", path.display(), e),
+        }
+    }
+    if history {
+        match history::HistoryWriter::open(&sess_dir) {
+            Ok(writer) => server.keep_history(writer),
+            Err(e) => log::warn!("Failed to open history in {}: {}", sess_dir.display(), e),
+        }
+    }
+    if banner {
+        server.show_banner(motd.as_deref());
+    }
+    if hold {
+        server.hold();
+    }
+    if pause_when_detached {
+        server.pause_when_detached();
+    }
+    if let Some(line) = on_exit {
+        let command = vec!["sh".to_string(), "-c".to_string(), line];
+        server.on_exit(hook::ExitHook {
+            command,
+            lines: hook::DEFAULT_LINES,
+        });
+    }
+    if let Some(enclosing) = enclosing {
+        server.nested_in(enclosing);
+    }
+    if sandbox {
+        server.sandbox()?;
+    }
     server.run()?;
 
     Ok(())
 }
 
-fn cmd_list(args: &[String]) -> io::Result<()> {
-    let sock_dir = socket_dir();
-    let prefix = args.first().map(|s| s.as_str()).unwrap_or("");
-
-    let search_dir = if prefix.is_empty() {
-        sock_dir
-    } else {
-        sock_dir.join(prefix)
-    };
-
-    let mut sessions = find_sessions(&search_dir, prefix)?;
-    sessions.sort();
-    for name in sessions {
-        println!("{}", name);
-    }
-    Ok(())
-}
-
-fn cmd_kill(args: &[String]) -> io::Result<()> {
-    let name = args.first().map(|s| s.as_str()).unwrap_or_else(|| {
-        eprintln!("Error: session name required");
-        std::process::exit(1);
-    });
-
-    let sess_dir = session_dir(name);
+fn cmd_list(args: &[String]) -> Result<()> {
+    let list::ListArgs {
+        prefix,
+        format,
+        sort,
+        filter,
+        scan,
+    } = list::ListArgs::parse(args).map_err(Error::Usage)?;
 
-    if !sess_dir.exists() {
-        eprintln!("Error: session '{}' not found", name);
-        std::process::exit(1);
+    if scan {
+        list::rescan()?;
     }
-
-    // Recursively remove the session directory (kills parent + all children)
-    // The daemon(s) will detect socket removal and shut down.
-    std::fs::remove_dir_all(&sess_dir)?;
-
-    // Try to clean up empty parent directories
-    let sock_root = socket_dir();
-    let mut parent = sess_dir.parent();
-    while let Some(p) = parent {
-        if p == sock_root {
-            break;
+    let mut entries = list::collect(&prefix)?;
+    list::select(&mut entries, &filter, sort);
+    let now = meta::unix_now();
+    match format {
+        Some(template) => {
+            for entry in &entries {
+                println!("{}", entry.format(&template, now));
+            }
         }
-        // Only remove if empty
-        if std::fs::read_dir(p)?.next().is_none() {
-            std::fs::remove_dir(p).ok();
-        } else {
-            break;
+        None => {
+            let color = unsafe { nix::libc::isatty(nix::libc::STDOUT_FILENO) } == 1
+                && std::env::var_os("NO_COLOR").is_none();
+            print!("{}", list::render_table(&entries, now, color));
         }
-        parent = p.parent();
     }
-
-    println!("Session '{}' killed", name);
     Ok(())
 }
 
-/// Extract session name from args following the same parsing rule as `cmd_new`:
-/// first non-option argument, ignoring an optional `--` separator.
-fn parse_session_name(args: &[String]) -> Option<&str> {
-    let mut parsing_opts = true;
-    let mut i = 0;
-    while i < args.len() {
-        if parsing_opts && args[i] == "--" {
-            parsing_opts = false;
-            i += 1;
-            continue;
+/// `pterm kill <session-name> | <prefix> --recursive | --all [--dry-run]`
+fn cmd_kill(args: &[String]) -> Result<()> {
+    let (request, dry_run) = kill::parse_args(args)?;
+    let names = |prefix| -> Result<Vec<String>> {
+        Ok(list::collect(prefix)?.into_iter().map(|e| e.name).collect())
+    };
+    let plan = match request {
+        kill::Request::All => kill::plan(request, kill::Found::Prefix, names("")?)?,
+        kill::Request::Named { name, .. } => {
+            let found = if !session_dir(name).exists() {
+                kill::Found::Missing
+            } else if session_socket_path(name).exists()
+                || session_dir(name).join(meta::META_FILENAME).exists()
+            {
+                kill::Found::Session
+            } else {
+                kill::Found::Prefix
+            };
+            let below = match found {
+                kill::Found::Missing => Vec::new(),
+                _ => names(name)?,
+            };
+            kill::plan(request, found, below)?
         }
-        return Some(args[i].as_str());
-    }
-    None
+    };
+    kill::execute(
+        &plan,
+        dry_run,
+        |name| paths::remove_session_dir(&session_dir(name)),
+        &mut io::stdout(),
+    )?;
+    Ok(())
 }
 
 fn wait_for_socket(sock: &Path, timeout: Duration, poll: Duration) -> io::Result<bool> {
@@ -235,39 +1052,261 @@ fn wait_for_socket(sock: &Path, timeout: Duration, poll: Duration) -> io::Result
     }
 }
 
-fn cmd_attach(args: &[String]) -> io::Result<()> {
+/// How long `pterm new --wait-ready` waits for the daemon.
+const READY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Wait until the daemon `pid` accepts connections on `sock`. Returns
+/// `false` on timeout; fails early if the daemon exits first.
+fn wait_until_ready(sock: &Path, pid: nix::unistd::Pid, timeout: Duration) -> io::Result<bool> {
+    use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+
+    let deadline = Instant::now() + timeout;
+    loop {
+        if std::os::unix::net::UnixStream::connect(sock).is_ok() {
+            return Ok(true);
+        }
+        match waitpid(pid, Some(WaitPidFlag::WNOHANG)) {
+            Ok(WaitStatus::StillAlive) => {}
+            _ => {
+                return Err(io::Error::other(
+                    "daemon exited before accepting connections",
+                ))
+            }
+        }
+        if Instant::now() >= deadline {
+            return Ok(false);
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    }
+}
+
+fn cmd_attach(args: &[String]) -> Result<()> {
     let mut session_name = String::new();
+    let mut options = bridge::Options::default();
+    let mut as_root = false;
+    let mut view = false;
+    let mut force_nested = false;
 
     let mut i = 0;
     while i < args.len() {
-        if session_name.is_empty() {
+        match parse_bridge_opt(args, &mut i, &mut options) {
+            Ok(true) => continue,
+            Ok(false) => {}
+            Err(e) => {
+                return Err(Error::Failed(e.to_string()));
+            }
+        }
+        if args[i] == "--direct-input" {
+            options.direct_input = true;
+        } else if args[i] == "--read-only" {
+            options.read_only = true;
+        } else if args[i] == "--status-bar" {
+            options.status_bar = true;
+        } else if args[i] == "--compress" {
+            options.compress = true;
+        } else if args[i] == "--checksum" {
+            options.checksum = true;
+        } else if args[i] == "--as-root" {
+            as_root = true;
+        } else if args[i] == "--view" {
+            view = true;
+        } else if args[i] == "--force-nested" {
+            force_nested = true;
+        } else if args[i] == "--token" {
+            i += 1;
+            let token = args.get(i).and_then(|t| session_key::from_hex(t));
+            if token.is_none() {
+                return Err(Error::Usage(
+                    "--token requires a token from `pterm share`".to_string(),
+                ));
+            }
+            options.share_token = token;
+            options.read_only = true;
+        } else if args[i] == "--debug-frames" {
+            i += 1;
+            let Some(path) = args.get(i) else {
+                return Err(Error::Usage(
+                    "--debug-frames requires a log file".to_string(),
+                ));
+            };
+            options.debug_frames = Some(PathBuf::from(path));
+        } else if session_name.is_empty() {
             session_name = args[i].clone();
         }
         i += 1;
     }
 
     if session_name.is_empty() {
-        eprintln!("Error: session name required");
-        std::process::exit(1);
+        return Err(Error::Usage("session name required".to_string()));
     }
-
-    let sock = session_socket_path(&session_name);
-    if !sock.exists() {
-        eprintln!("Error: session '{}' not found", session_name);
-        std::process::exit(1);
+    if as_root {
+        return Err(Error::from(elevate::exec_as_root("attach", args)));
+    }
+    // A shared session is named by its socket, in another user's root.
+    let sock = if Path::new(&session_name).is_absolute() && options.share_token.is_some() {
+        PathBuf::from(&session_name)
+    } else {
+        paths::check_socket_dir(&paths::socket_dir())?;
+        session_socket_path(&session_name)
+    };
+    // With retries the socket may legitimately not exist yet.
+    let retrying = options.retries > 0 || options.connect_timeout.is_some();
+    if !retrying && !sock.exists() {
+        return Err(Error::SessionNotFound(session_name.to_string()));
+    }
+    refuse_enclosing_session(&sock, &session_name)?;
+    if let Some((enclosing, _)) = paths::enclosing_session().filter(|_| !force_nested) {
+        return Err(Error::Failed(format!(
+            "already inside session '{}'; attaching to '{}' here would nest it \
+             (--force-nested to do so anyway)",
+            enclosing, session_name
+        )));
+    }
+    let refused = |e: io::Error| e.kind() == io::ErrorKind::PermissionDenied;
+    if options.share_token.is_some()
+        && std::os::unix::net::UnixStream::connect(&sock).is_err_and(refused)
+    {
+        return Err(Error::Failed(format!(
+            "'{}' is not shared (any more): the token was used, has expired or was revoked",
+            session_name
+        )));
+    }
+    if view && !client::view(&session_name)? {
+        return Ok(());
     }
 
-    let exit_code = bridge::run(&sock, None, None)?;
+    options.read_buffer = Some(buffers()?.read_buffer);
+    let exit_code = bridge::run(&sock, &options)?;
     std::process::exit(exit_code);
 }
 
-fn cmd_open(args: &[String]) -> io::Result<()> {
-    let name = parse_session_name(args).unwrap_or_else(|| {
-        eprintln!("Error: session name required");
+/// Fail if `sock` is the session this command runs inside: attaching would
+/// feed the session's output back into itself.
+fn refuse_enclosing_session(sock: &Path, name: &str) -> Result<()> {
+    if paths::is_enclosing_session(sock) {
+        return Err(Error::Failed(format!(
+            "cannot attach to session '{}' from inside itself",
+            name
+        )));
+    }
+    Ok(())
+}
+
+/// `pterm here [--print] [<suffix>] [-- <command>...]`
+fn cmd_here(args: &[String]) -> Result<()> {
+    let (args, command) = match args.iter().position(|a| a == "--") {
+        Some(i) => (&args[..i], &args[i..]),
+        None => (args, &args[args.len()..]),
+    };
+    let print = args.iter().any(|a| a == "--print");
+    let suffix = match args.iter().filter(|a| *a != "--print").collect::<Vec<_>>()[..] {
+        [] => None,
+        [suffix] => Some(suffix.as_str()),
+        _ => {
+            return Err(Error::Usage(
+                "usage: pterm here [--print] [<suffix>] [-- <command> [args...]]".to_string(),
+            ));
+        }
+    };
+    let cwd = std::env::current_dir()?;
+    let (name, root) = here::session_name(&cwd, suffix);
+    if print {
+        println!("{}", name);
+        return Ok(());
+    }
+    // A new session starts at the top of the project.
+    std::env::set_current_dir(&root)?;
+    let mut open_args = vec![name];
+    open_args.extend(command.iter().cloned());
+    cmd_open(&open_args)
+}
+
+/// `pterm up <template.toml> [--var name=value]... [--dry-run]`
+fn cmd_up(args: &[String]) -> Result<()> {
+    let usage = || {
+        Error::Usage(
+            "usage: pterm up <template.toml> [--var name=value]... [--dry-run]".to_string(),
+        )
+    };
+    let mut path = None;
+    let mut overrides = std::collections::BTreeMap::new();
+    let mut dry_run = false;
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--var" => {
+                let var = iter.next().ok_or_else(usage)?;
+                let (name, value) =
+                    template::parse_var(var).map_err(|e| Error::Usage(format!("--var: {}", e)))?;
+                overrides.insert(name, value);
+            }
+            "--dry-run" => dry_run = true,
+            a if a.starts_with("--") => return Err(usage()),
+            a if path.is_none() => path = Some(PathBuf::from(a)),
+            _ => return Err(usage()),
+        }
+    }
+    let path = path.ok_or_else(usage)?;
+    let sessions = template::Template::load(&path)
+        .and_then(|template| template.expand(&overrides))
+        .map_err(|e| Error::Failed(e.to_string()))?;
+
+    let binary = std::env::current_exe()?;
+    let mut failed = false;
+    for session in &sessions {
+        if dry_run {
+            let mut line = format!(
+                "{}: {}",
+                session.name,
+                schedule::shell_join(&session.command)
+            );
+            if let Some(cwd) = &session.cwd {
+                line.push_str(&format!(" (in {})", cwd.display()));
+            }
+            println!("{}", line);
+            continue;
+        }
+        if session_socket_path(&session.name).exists() {
+            println!("{}: already running", session.name);
+            continue;
+        }
+        let mut command = std::process::Command::new(&binary);
+        command
+            .args(paths::socket_dir_args())
+            .args(["new", "--wait-ready", "--print-socket", &session.name])
+            .stdout(std::process::Stdio::null())
+            .envs(&session.env);
+        if !session.command.is_empty() {
+            command.arg("--").args(&session.command);
+        }
+        if let Some(cwd) = &session.cwd {
+            command.current_dir(cwd);
+        }
+        match command.status() {
+            Ok(status) if status.success() => println!("{}: started", session.name),
+            Ok(_) => failed = true,
+            Err(e) => {
+                eprintln!("Error: {}: {}", session.name, e);
+                failed = true;
+            }
+        }
+    }
+    if failed {
         std::process::exit(1);
-    });
+    }
+    Ok(())
+}
+
+fn cmd_open(args: &[String]) -> Result<()> {
+    let mut parsed = parse_new_args(args).map_err(|e| Error::Failed(e.to_string()))?;
+    if parsed.as_root {
+        return Err(Error::from(elevate::exec_as_root("open", args)));
+    }
+    let name = parsed.session_name.as_str();
 
+    paths::check_socket_dir(&paths::socket_dir())?;
     let sock = session_socket_path(name);
+    refuse_enclosing_session(&sock, name)?;
     if !sock.exists() {
         cmd_new(args, true)?;
         let ok = wait_for_socket(
@@ -276,49 +1315,2035 @@ fn cmd_open(args: &[String]) -> io::Result<()> {
             Duration::from_millis(50),
         )?;
         if !ok {
-            eprintln!(
-                "Error: session '{}' was created but socket did not appear in time",
+            return Err(Error::Failed(format!(
+                "session '{}' was created but socket did not appear in time",
                 name
-            );
-            std::process::exit(1);
+            )));
         }
     }
 
-    let exit_code = bridge::run(&sock, None, None)?;
+    parsed.bridge.read_buffer = Some(buffers()?.read_buffer);
+    let exit_code = bridge::run(&sock, &parsed.bridge)?;
     std::process::exit(exit_code);
 }
 
-fn cmd_redraw(args: &[String]) -> io::Result<()> {
-    let name = args.first().map(|s| s.as_str()).unwrap_or_else(|| {
-        eprintln!("Error: session name required");
-        std::process::exit(1);
-    });
+fn cmd_redraw(args: &[String]) -> Result<()> {
+    let name = args
+        .first()
+        .map(|s| s.as_str())
+        .ok_or_else(|| Error::Usage("session name required".to_string()))?;
 
     let sock = session_socket_path(name);
     if !sock.exists() {
-        eprintln!("Error: session '{}' not found", name);
-        std::process::exit(1);
+        return Err(Error::SessionNotFound(name.to_string()));
+    }
+
+    Ok(control::send(name, pterm_proto::client::REDRAW, &[])?)
+}
+
+fn cmd_clients(args: &[String]) -> Result<()> {
+    let json = args.iter().any(|a| a == "--json");
+    if json {
+        error::report_as_json();
+    }
+    let name = args
+        .iter()
+        .find(|a| !a.starts_with("--"))
+        .map(|s| s.as_str())
+        .ok_or_else(|| Error::Usage("session name required".to_string()))?;
+
+    if !session_socket_path(name).exists() {
+        return Err(Error::SessionNotFound(name.to_string()));
     }
 
-    let mut stream = std::os::unix::net::UnixStream::connect(&sock)?;
-    let msg = pterm_proto::encode(pterm_proto::client::REDRAW, &[]);
-    std::io::Write::write_all(&mut stream, &msg)?;
+    let reply = control::request(name, pterm_proto::client::LIST_CLIENTS, &[])?;
+    if json {
+        println!("{}", reply["clients"]);
+        return Ok(());
+    }
+    let clients: Vec<clients::ClientInfo> = serde_json::from_value(reply["clients"].clone())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    print!("{}", clients::render_table(&clients, meta::unix_now()));
     Ok(())
 }
 
-fn cmd_socket(args: &[String]) -> io::Result<()> {
-    let name = args.first().map(|s| s.as_str()).unwrap_or_else(|| {
-        eprintln!("Error: session name required");
-        std::process::exit(1);
-    });
+fn cmd_info(args: &[String]) -> Result<()> {
+    let mut json = false;
+    let mut activity = None;
+    let mut name = None;
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--json" => {
+                json = true;
+                error::report_as_json();
+            }
+            "--activity" => activity = Some(activity.unwrap_or(60)),
+            "--minutes" => {
+                let minutes = iter.next().and_then(|m| m.parse::<u64>().ok());
+                let minutes = minutes.filter(|m| (1..=meta::ACTIVITY_MINUTES).contains(m));
+                activity = Some(minutes.ok_or_else(|| {
+                    Error::Usage(format!(
+                        "--minutes requires a number from 1 to {}",
+                        meta::ACTIVITY_MINUTES
+                    ))
+                })?);
+            }
+            a if a.starts_with("--") => {
+                return Err(Error::Usage(
+                    "usage: pterm info <session-name> [--activity [--minutes <n>]] [--json]"
+                        .to_string(),
+                ));
+            }
+            a => name = Some(a),
+        }
+    }
+    let name = name.ok_or_else(|| Error::Usage("session name required".to_string()))?;
 
-    let sock_path = session_socket_path(name);
-    println!("{}", sock_path.display());
+    if !session_socket_path(name).exists() {
+        return Err(Error::SessionNotFound(name.to_string()));
+    }
+
+    let reply = control::call(name, pterm_proto::client::INFO, &[])?;
+    let info = &reply["info"];
+    if let Some(minutes) = activity {
+        let history: meta::Activity =
+            serde_json::from_value(info["activity"].clone()).unwrap_or_default();
+        let now = meta::unix_now();
+        let (output, input) = history.histogram(now, minutes);
+        if json {
+            println!(
+                "{}",
+                serde_json::json!({
+                    "session": name,
+                    "end": now / 60 * 60,
+                    "minutes": minutes,
+                    "output": output,
+                    "input": input,
+                })
+            );
+            return Ok(());
+        }
+        println!("activity last {} min, one column per minute", minutes);
+        for (label, values) in [("output", &output), ("input", &input)] {
+            println!(
+                "{:<8} {} {}",
+                label,
+                meta::sparkline(values),
+                clients::format_bytes(values.iter().sum())
+            );
+        }
+        return Ok(());
+    }
+    if json {
+        println!("{}", info);
+        return Ok(());
+    }
+    let text = |v: &serde_json::Value| v.as_str().unwrap_or("-").to_string();
+    let command: Vec<String> = info["command"]
+        .as_array()
+        .map(|words| words.iter().map(text).collect())
+        .unwrap_or_default();
+    println!("session  {}", text(&info["session"]));
+    println!("command  {}", command.join(" "));
+    if let Some(created_at) = info["created_at"].as_u64() {
+        let age = meta::unix_now().saturating_sub(created_at);
+        println!("created  {} ago", list::format_age(age));
+    }
+    println!("cwd      {}", text(&info["cwd"]));
+    println!("term     {}", text(&info["term"]));
+    println!("size     {}x{}", info["cols"], info["rows"]);
+    println!("pid      {}", info["pid"]);
+    println!("fg pid   {}", info["foreground_pid"]);
+    println!("tty      {}", text(&info["tty"]));
+    println!("clients  {} attached", info["clients"]);
+    let scrollback = &info["scrollback"];
+    match scrollback["file_bytes"].as_u64() {
+        Some(bytes) => println!(
+            "history  {}/{} lines, {} in the scrollback file",
+            scrollback["lines"],
+            scrollback["capacity"],
+            clients::format_bytes(bytes)
+        ),
+        None => println!(
+            "history  {}/{} lines",
+            scrollback["lines"], scrollback["capacity"]
+        ),
+    }
+    println!(
+        "snapshot {} built, {} reused",
+        info["snapshots"]["built"], info["snapshots"]["reused"]
+    );
+    println!("sandbox  {}", text(&info["sandbox"]));
+    if let Some(enclosing) = info["nested_in"].as_str() {
+        println!("nested   inside '{}'", enclosing);
+    }
+    if let Ok(hook) = serde_json::from_value::<hook::ExitHook>(info["exit_hook"].clone()) {
+        println!("on exit  {}", hook.describe());
+    }
+    if let Ok(exit) = serde_json::from_value::<meta::ExitInfo>(info["exit"].clone()) {
+        println!("exit     {}", exit.describe());
+    }
     Ok(())
 }
 
-fn main() {
-    let args: Vec<String> = std::env::args().collect();
+fn cmd_metadata(args: &[String]) -> Result<()> {
+    let json = args.iter().any(|a| a == "--json");
+    if json {
+        error::report_as_json();
+    }
+    let name = args
+        .iter()
+        .find(|a| !a.starts_with("--"))
+        .map(|s| s.as_str())
+        .ok_or_else(|| Error::Usage("session name required".to_string()))?;
+
+    if !session_socket_path(name).exists() {
+        return Err(Error::SessionNotFound(name.to_string()));
+    }
+
+    let reply = control::call(name, pterm_proto::client::METADATA, &[])?;
+    let metadata = &reply["metadata"];
+    if json {
+        println!("{}", metadata);
+        return Ok(());
+    }
+    let command: Vec<&str> = metadata["command"]
+        .as_array()
+        .map(|words| words.iter().filter_map(|w| w.as_str()).collect())
+        .unwrap_or_default();
+    let scrollback = &metadata["scrollback"];
+    let clients = &metadata["clients"];
+    println!("pid        {}", metadata["pid"]);
+    println!("command    {}", command.join(" "));
+    println!("cwd        {}", metadata["cwd"].as_str().unwrap_or("-"));
+    println!("size       {}x{}", metadata["cols"], metadata["rows"]);
+    println!(
+        "scrollback {}/{} lines",
+        scrollback["lines"], scrollback["capacity"]
+    );
+    println!(
+        "clients    {} attached, {} connected",
+        clients["attached"], clients["connected"]
+    );
+    Ok(())
+}
+
+/// `pterm command <session-name> <cmd> [<args-json>]`
+fn cmd_command(args: &[String]) -> Result<()> {
+    let (name, cmd, cmd_args) = match args {
+        [name, cmd] => (name, cmd, serde_json::Value::Null),
+        [name, cmd, cmd_args] => match serde_json::from_str(cmd_args) {
+            Ok(cmd_args) => (name, cmd, cmd_args),
+            Err(e) => {
+                return Err(Error::Usage(format!(
+                    "invalid arguments '{}': {}",
+                    cmd_args, e
+                )));
+            }
+        },
+        _ => {
+            return Err(Error::Usage(
+                "usage: pterm command <session-name> <cmd> [<args-json>]".to_string(),
+            ));
+        }
+    };
+    if !session_socket_path(name).exists() {
+        return Err(Error::SessionNotFound(name.to_string()));
+    }
+
+    let payload = serde_json::json!({ "cmd": cmd, "args": cmd_args }).to_string();
+    let reply = control::call(name, pterm_proto::client::COMMAND, payload.as_bytes())?;
+    println!("{}", reply["result"]);
+    Ok(())
+}
+
+/// `pterm strace <session-name> [--child] [-- <strace args>...]`
+fn cmd_strace(args: &[String]) -> Result<()> {
+    let (args, strace_args) = match args.iter().position(|a| a == "--") {
+        Some(i) => (&args[..i], &args[i + 1..]),
+        None => (args, &args[args.len()..]),
+    };
+    let child = args.iter().any(|a| a == "--child");
+    let name = args
+        .iter()
+        .find(|a| !a.starts_with("--"))
+        .map(|s| s.as_str())
+        .ok_or_else(|| {
+            Error::Usage(
+                "usage: pterm strace <session-name> [--child] [-- <strace args>...]".to_string(),
+            )
+        })?;
+    if !session_socket_path(name).exists() {
+        return Err(Error::SessionNotFound(name.to_string()));
+    }
+
+    let reply = control::call(name, pterm_proto::client::INFO, &[])?;
+    let info = &reply["info"];
+    // Daemons from before `foreground_pid` only report the child.
+    let pid = if child {
+        info["pid"].as_i64()
+    } else {
+        info["foreground_pid"].as_i64().or(info["pid"].as_i64())
+    };
+    let Some(pid) = pid else {
+        return Err(Error::Failed(format!(
+            "session '{}' reported no process id",
+            name
+        )));
+    };
+    let e = std::os::unix::process::CommandExt::exec(
+        std::process::Command::new("strace")
+            .arg("-p")
+            .arg(pid.to_string())
+            .args(strace_args),
+    );
+    Err(Error::Failed(format!("failed to run strace: {}", e)))
+}
+
+fn cmd_query(args: &[String]) -> Result<()> {
+    if args.first().is_some_and(|a| a == "--stdin-loop") {
+        return Ok(query::run_loop()?);
+    }
+    if args.is_empty() {
+        return Err(Error::Usage(
+            "query required (list [options] | info <session-name>)".to_string(),
+        ));
+    }
+    println!("{}", query::answer(args));
+    Ok(())
+}
+
+fn cmd_stats(args: &[String]) -> Result<()> {
+    let json = args.iter().any(|a| a == "--json");
+    if json {
+        error::report_as_json();
+    }
+    let prefix = args
+        .iter()
+        .find(|a| !a.starts_with("--"))
+        .map(|s| s.trim_end_matches('/'))
+        .unwrap_or("");
+
+    let entries = list::collect(prefix)?;
+    let (groups, total) = stats::aggregate(&entries, prefix);
+    if json {
+        println!(
+            "{}",
+            serde_json::json!({ "groups": groups, "total": total })
+        );
+    } else {
+        print!("{}", stats::render_table(&groups, &total));
+    }
+    Ok(())
+}
+
+fn cmd_set(args: &[String]) -> Result<()> {
+    let (name, change) = match args {
+        [name] => (name.as_str(), None),
+        [name, option, value] => (name.as_str(), Some((option, value))),
+        _ => {
+            return Err(Error::Usage(
+                "usage: pterm set <session-name> [<option> <value>]".to_string(),
+            ));
+        }
+    };
+
+    if !session_socket_path(name).exists() {
+        return Err(Error::SessionNotFound(name.to_string()));
+    }
+
+    let payload = change
+        .map(|(option, value)| serde_json::json!({ "name": option, "value": value }).to_string())
+        .unwrap_or_default();
+    let reply = control::call(name, pterm_proto::client::OPTIONS, payload.as_bytes())?;
+    let options = reply["options"].as_object().cloned().unwrap_or_default();
+    let width = options.keys().map(String::len).max().unwrap_or(0);
+    for option in options::SessionOptions::NAMES {
+        if change.is_some_and(|(changed, _)| changed != option) {
+            continue;
+        }
+        if let Some(value) = options.get(option).and_then(|v| v.as_str()) {
+            println!("{:<width$}  {}", option, value, width = width);
+        }
+    }
+    Ok(())
+}
+
+fn schedule_path() -> Result<PathBuf> {
+    schedule::Schedule::path().ok_or_else(|| {
+        Error::Failed("cannot locate the config directory (HOME is not set)".to_string())
+    })
+}
+
+/// `pterm every` (`repeat == true`) and `pterm at`.
+fn cmd_schedule_add(args: &[String], repeat: bool) -> Result<()> {
+    let (args, command) = match args.iter().position(|a| a == "--") {
+        Some(i) => (&args[..i], args[i + 1..].to_vec()),
+        None => (args, Vec::new()),
+    };
+    let if_detached = args.iter().any(|a| a == "--if-detached");
+    let positional: Vec<&str> = args
+        .iter()
+        .map(String::as_str)
+        .filter(|&a| a != "--if-detached")
+        .collect();
+    let [when, action, target] = positional[..] else {
+        return Err(Error::Usage(format!(
+            "usage: pterm {} [--if-detached] <action> <session-glob> [-- <command>...]",
+            if repeat {
+                "every <interval>"
+            } else {
+                "at <HH:MM|+duration>"
+            }
+        )));
+    };
+
+    let now = meta::unix_now();
+    let trigger = if repeat {
+        schedule::parse_duration(when).map(|interval| schedule::Trigger::Every {
+            interval,
+            since: now,
+        })
+    } else {
+        schedule::parse_time(when, now).map(|time| schedule::Trigger::At { time })
+    };
+    let trigger = trigger.ok_or_else(|| {
+        Error::Usage(format!(
+            "invalid {} '{}'",
+            if repeat { "interval" } else { "time" },
+            when
+        ))
+    })?;
+    let action =
+        schedule::Action::parse(action, command).map_err(|e| Error::Failed(e.to_string()))?;
+
+    let path = schedule_path()?;
+    let mut jobs = schedule::Schedule::load(&path)?;
+    jobs.prune(now);
+    let id = jobs.add(trigger, action, target, if_detached);
+    jobs.save(&path)?;
+    if let Some(job) = jobs.jobs.iter().find(|j| j.id == id) {
+        println!("Job {}: {}", id, job.describe(now));
+    }
+    Ok(())
+}
+
+/// `pterm schedule [rm <id>...]`
+fn cmd_schedule(args: &[String]) -> Result<()> {
+    let path = schedule_path()?;
+    let now = meta::unix_now();
+    let mut jobs = schedule::Schedule::load(&path)?;
+    jobs.prune(now);
+    match args {
+        [] => {
+            for job in &jobs.jobs {
+                println!("{}  {}", job.id, job.describe(now));
+            }
+        }
+        [rm, ids @ ..] if rm == "rm" && !ids.is_empty() => {
+            for id in ids {
+                let removed = id.parse().is_ok_and(|id| jobs.remove(id));
+                if !removed {
+                    return Err(Error::Failed(format!("no scheduled job '{}'", id)));
+                }
+            }
+            jobs.save(&path)?;
+        }
+        _ => {
+            return Err(Error::Usage(
+                "usage: pterm schedule [rm <id>...]".to_string(),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// `pterm on-output <session> [--pattern <text>... -- <command>... | rm <id>...]`
+fn cmd_on_output(args: &[String]) -> Result<()> {
+    let (args, command) = match args.iter().position(|a| a == "--") {
+        Some(i) => (&args[..i], args[i + 1..].to_vec()),
+        None => (args, Vec::new()),
+    };
+    let mut name = None;
+    let mut patterns = Vec::new();
+    let mut remove = Vec::new();
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--pattern" => {
+                patterns.push(
+                    args.get(i + 1)
+                        .cloned()
+                        .ok_or_else(|| Error::Usage("--pattern requires a text".to_string()))?,
+                );
+                i += 1;
+            }
+            "rm" if name.is_some() => {
+                remove.extend(args[i + 1..].iter().cloned());
+                break;
+            }
+            arg if name.is_none() => name = Some(arg),
+            arg => {
+                return Err(Error::Usage(format!("unexpected argument '{}'", arg)));
+            }
+        }
+        i += 1;
+    }
+    let name = name.ok_or_else(|| {
+        Error::Usage(
+            "usage: pterm on-output <session-name> [--pattern <text>... -- <command>...]"
+                .to_string(),
+        )
+    })?;
+    if patterns.is_empty() != command.is_empty() {
+        return Err(Error::Failed(
+            "a watch needs both --pattern and -- <command>".to_string(),
+        ));
+    }
+
+    if !session_socket_path(name).exists() {
+        return Err(Error::SessionNotFound(name.to_string()));
+    }
+
+    // An empty payload lists the watches.
+    let mut payloads = Vec::new();
+    if !patterns.is_empty() {
+        let add = serde_json::json!({ "patterns": patterns, "command": command });
+        payloads.push(serde_json::json!({ "add": add }).to_string());
+    }
+    for id in &remove {
+        let id: u32 = id
+            .parse()
+            .map_err(|_| Error::Usage(format!("invalid watch id '{}'", id)))?;
+        payloads.push(serde_json::json!({ "remove": id }).to_string());
+    }
+    if payloads.is_empty() {
+        payloads.push(String::new());
+    }
+    for payload in payloads {
+        let reply = control::call(name, pterm_proto::client::WATCH, payload.as_bytes())?;
+        if let Some(id) = reply["added"].as_u64() {
+            println!("Watch {} added", id);
+        } else if payload.is_empty() {
+            let watches: Vec<watch::Watch> =
+                serde_json::from_value(reply["watches"].clone()).unwrap_or_default();
+            for w in watches {
+                println!(
+                    "{}  {}  -> {}  (fired {})",
+                    w.id,
+                    w.patterns.join(" | "),
+                    schedule::shell_join(&w.command),
+                    w.fired
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// `pterm on-exit <session> [--lines <n>] -- <command>... | rm`
+fn cmd_on_exit(args: &[String]) -> Result<()> {
+    let (args, command) = match args.iter().position(|a| a == "--") {
+        Some(i) => (&args[..i], args[i + 1..].to_vec()),
+        None => (args, Vec::new()),
+    };
+    let mut name = None;
+    let mut lines = None;
+    let mut remove = false;
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--lines" => {
+                let n = args
+                    .get(i + 1)
+                    .ok_or_else(|| Error::Usage("--lines requires a number".to_string()))?;
+                lines = Some(
+                    n.parse::<usize>()
+                        .map_err(|_| Error::Usage(format!("invalid line count '{}'", n)))?,
+                );
+                i += 1;
+            }
+            "rm" if name.is_some() => remove = true,
+            arg if name.is_none() => name = Some(arg),
+            arg => {
+                return Err(Error::Usage(format!("unexpected argument '{}'", arg)));
+            }
+        }
+        i += 1;
+    }
+    let name = name.ok_or_else(|| {
+        Error::Usage(
+            "usage: pterm on-exit <session-name> [--lines <n>] -- <command>... | rm".to_string(),
+        )
+    })?;
+    if remove && (!command.is_empty() || lines.is_some()) {
+        return Err(Error::Usage("rm takes no command or --lines".to_string()));
+    }
+    if lines.is_some() && command.is_empty() {
+        return Err(Error::Failed("--lines needs -- <command>".to_string()));
+    }
+
+    if !session_socket_path(name).exists() {
+        return Err(Error::SessionNotFound(name.to_string()));
+    }
+
+    let cmd_args = if remove {
+        serde_json::json!({ "remove": true })
+    } else if !command.is_empty() {
+        serde_json::json!({ "command": command, "lines": lines })
+    } else {
+        serde_json::json!({})
+    };
+    let payload = serde_json::json!({ "cmd": "on-exit", "args": cmd_args }).to_string();
+    let reply = control::call(name, pterm_proto::client::COMMAND, payload.as_bytes())?;
+    let hook = &reply["result"]["exit_hook"];
+    match serde_json::from_value::<hook::ExitHook>(hook.clone()) {
+        Ok(hook) => println!("{}", hook.describe()),
+        Err(_) if remove => println!("Exit hook removed"),
+        Err(_) => println!("No exit hook"),
+    }
+    Ok(())
+}
+
+/// `pterm send <session> [--no-newline] [--] <text>...`: type `text` into
+/// the session as if from an attached terminal, followed by Enter unless
+/// `--no-newline`.
+fn cmd_send(args: &[String]) -> Result<()> {
+    let mut name = None;
+    let mut newline = true;
+    let mut words = Vec::new();
+    let mut options_done = false;
+    for arg in args {
+        match arg.as_str() {
+            "--no-newline" if !options_done => newline = false,
+            "--" if !options_done => options_done = true,
+            _ if name.is_none() => name = Some(arg.as_str()),
+            _ => words.push(arg.as_str()),
+        }
+    }
+    let name = name.ok_or_else(|| Error::Usage("session name required".to_string()))?;
+    if words.is_empty() && !newline {
+        return Err(Error::Usage(
+            "usage: pterm send <session-name> [--no-newline] [--] <text>...".to_string(),
+        ));
+    }
+
+    if !session_socket_path(name).exists() {
+        return Err(Error::SessionNotFound(name.to_string()));
+    }
+
+    let mut input = words.join(" ");
+    if newline {
+        input.push('\r');
+    }
+    Ok(control::send(
+        name,
+        pterm_proto::client::INPUT,
+        input.as_bytes(),
+    )?)
+}
+
+/// `pterm exec <session> [--no-wrap] [--timeout <secs>] -- <command>...`:
+/// run a command line in the session's shell and exit with its status.
+fn cmd_exec(args: &[String]) -> Result<()> {
+    let Some(split) = args.iter().position(|a| a == "--") else {
+        return Err(Error::Usage(
+            "usage: pterm exec <session-name> [--no-wrap] [--timeout <secs>] -- <command>..."
+                .to_string(),
+        ));
+    };
+    let command = &args[split + 1..];
+    let mut name = None;
+    let mut wrap = true;
+    let mut timeout = None;
+    let mut i = 0;
+    while i < split {
+        match args[i].as_str() {
+            "--no-wrap" => wrap = false,
+            "--timeout" => {
+                let secs = args[..split].get(i + 1).and_then(|s| s.parse::<f64>().ok());
+                let secs = secs.filter(|s| s.is_finite() && *s > 0.0).ok_or_else(|| {
+                    Error::Usage("--timeout requires a number of seconds".to_string())
+                })?;
+                timeout = Some(Duration::from_secs_f64(secs));
+                i += 1;
+            }
+            arg if name.is_none() => name = Some(arg),
+            arg => {
+                return Err(Error::Usage(format!("unexpected argument '{}'", arg)));
+            }
+        }
+        i += 1;
+    }
+    let name = name.ok_or_else(|| Error::Usage("session name required".to_string()))?;
+    if command.is_empty() {
+        return Err(Error::Usage("a command is required after --".to_string()));
+    }
+
+    if !session_socket_path(name).exists() {
+        return Err(Error::SessionNotFound(name.to_string()));
+    }
+
+    match exec::run(name, command, wrap, timeout)? {
+        Some(status) => std::process::exit(status),
+        None => {
+            // Same status as timeout(1).
+            eprintln!("Error: no status from '{}' within the timeout", name);
+            std::process::exit(124);
+        }
+    }
+}
+
+/// `pterm wait [--any | --all] [--timeout <secs>] [--json] <session>...`
+fn cmd_wait(args: &[String]) -> Result<()> {
+    let mut names = Vec::new();
+    let mut mode = wait::Mode::default();
+    let mut timeout = None;
+    let mut json = false;
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--any" => mode = wait::Mode::Any,
+            "--all" => mode = wait::Mode::All,
+            "--json" => {
+                json = true;
+                error::report_as_json();
+            }
+            "--timeout" => {
+                let secs = args.get(i + 1).and_then(|s| s.parse::<f64>().ok());
+                let secs = secs.filter(|s| s.is_finite() && *s > 0.0).ok_or_else(|| {
+                    Error::Usage("--timeout requires a number of seconds".to_string())
+                })?;
+                timeout = Some(Duration::from_secs_f64(secs));
+                i += 1;
+            }
+            arg if arg.starts_with("--") => {
+                return Err(Error::Usage(format!("unknown option '{}'", arg)));
+            }
+            name => names.push(name.to_string()),
+        }
+        i += 1;
+    }
+    if names.is_empty() {
+        return Err(Error::Usage(
+            "at least one session name required".to_string(),
+        ));
+    }
+    for name in &names {
+        if !session_socket_path(name).exists() && !session_dir(name).exists() {
+            return Err(Error::SessionNotFound(name.to_string()));
+        }
+    }
+
+    let finished = wait::run(&names, mode, timeout, |finished| {
+        if json {
+            println!("{}", finished.to_json());
+        } else {
+            println!("{}", finished.describe());
+        }
+    });
+    let finished = finished.map_err(|e| Error::Failed(e.to_string()))?;
+    let waited_for = match mode {
+        wait::Mode::Any => 1,
+        wait::Mode::All => names.len(),
+    };
+    if finished.len() < waited_for {
+        let running: Vec<&str> = names
+            .iter()
+            .filter(|name| !finished.iter().any(|f| &f.name == *name))
+            .map(|name| name.as_str())
+            .collect();
+        // Same status as timeout(1).
+        eprintln!("Error: still running: {}", running.join(", "));
+        std::process::exit(124);
+    }
+    std::process::exit(wait::exit_code(&names, &finished, mode));
+}
+
+/// `pterm guard <session> [--require-confirm <patterns> | --off]`: a
+/// front end for the session's `guard` option.
+fn cmd_guard(args: &[String]) -> Result<()> {
+    let (name, value) = match args {
+        [name] => (name.as_str(), None),
+        [name, flag, patterns] if flag == "--require-confirm" => {
+            if guard::patterns(patterns).next().is_none() {
+                return Err(Error::Usage(
+                    "--require-confirm requires at least one pattern".to_string(),
+                ));
+            }
+            (name.as_str(), Some(patterns.as_str()))
+        }
+        [name, flag] if flag == "--off" => (name.as_str(), Some("off")),
+        _ => {
+            return Err(Error::Usage(
+                "usage: pterm guard <session-name> [--require-confirm <patterns> | --off]"
+                    .to_string(),
+            ));
+        }
+    };
+
+    if !session_socket_path(name).exists() {
+        return Err(Error::SessionNotFound(name.to_string()));
+    }
+
+    let payload = value
+        .map(|value| serde_json::json!({ "name": "guard", "value": value }).to_string())
+        .unwrap_or_default();
+    let reply = control::call(name, pterm_proto::client::OPTIONS, payload.as_bytes())?;
+    let guard = reply["options"]["guard"].as_str().unwrap_or("off");
+    if guard == "off" {
+        println!("Guard off");
+    } else {
+        let patterns: Vec<&str> = guard::patterns(guard).collect();
+        println!(
+            "Guard on: Enter waits for confirmation on lines containing {}",
+            patterns.join(" | ")
+        );
+    }
+    Ok(())
+}
+
+/// `pterm suspend` (`suspend == true`) and `pterm resume`.
+fn cmd_suspend(args: &[String], suspend: bool) -> Result<()> {
+    let auto_resume = suspend && args.iter().any(|a| a == "--auto-resume");
+    let name = args
+        .iter()
+        .find(|a| !(suspend && *a == "--auto-resume"))
+        .ok_or_else(|| Error::Usage("session name required".to_string()))?;
+
+    if !session_socket_path(name).exists() {
+        return Err(Error::SessionNotFound(name.to_string()));
+    }
+
+    if auto_resume {
+        let payload = serde_json::json!({ "name": "auto-resume", "value": "on" }).to_string();
+        control::request(name, pterm_proto::client::OPTIONS, payload.as_bytes())?;
+    }
+    let msg_type = if suspend {
+        pterm_proto::client::SUSPEND
+    } else {
+        pterm_proto::client::RESUME
+    };
+    control::call(name, msg_type, &[])?;
+    println!(
+        "Session '{}' {}",
+        name,
+        if suspend { "suspended" } else { "resumed" }
+    );
+    Ok(())
+}
+
+/// `pterm signal <session> <signal>`, the signal given as a name with or
+/// without `SIG` or as a number.
+fn cmd_signal(args: &[String]) -> Result<()> {
+    let [name, signal] = args else {
+        return Err(Error::Usage(
+            "usage: pterm signal <session-name> <signal>".to_string(),
+        ));
+    };
+    let Some(signal) = parse_signal(signal) else {
+        return Err(Error::Usage(format!("unknown signal '{}'", signal)));
+    };
+    if !session_socket_path(name).exists() {
+        return Err(Error::SessionNotFound(name.to_string()));
+    }
+    let payload = serde_json::json!({ "signal": signal as i32 }).to_string();
+    control::call(name, pterm_proto::client::SIGNAL, payload.as_bytes())?;
+    println!("Sent {} to session '{}'", signal, name);
+    Ok(())
+}
+
+/// `pterm respawn <session> [--force]`
+fn cmd_respawn(args: &[String]) -> Result<()> {
+    let force = args.iter().any(|a| a == "--force");
+    let names: Vec<&String> = args.iter().filter(|a| *a != "--force").collect();
+    let [name] = names[..] else {
+        return Err(Error::Usage(
+            "usage: pterm respawn <session-name> [--force]".to_string(),
+        ));
+    };
+    if !session_socket_path(name).exists() {
+        return Err(Error::SessionNotFound(name.to_string()));
+    }
+    let payload = serde_json::json!({ "cmd": "respawn", "args": { "force": force } });
+    let reply = control::call(
+        name,
+        pterm_proto::client::COMMAND,
+        payload.to_string().as_bytes(),
+    )?;
+    println!(
+        "Respawned the command in session '{}' (pid {})",
+        name, reply["result"]["pid"]
+    );
+    Ok(())
+}
+
+fn parse_signal(arg: &str) -> Option<nix::sys::signal::Signal> {
+    use nix::sys::signal::Signal;
+    if let Ok(number) = arg.parse::<i32>() {
+        return Signal::try_from(number).ok();
+    }
+    let name = arg.to_ascii_uppercase();
+    if name.starts_with("SIG") {
+        name.parse().ok()
+    } else {
+        format!("SIG{}", name).parse().ok()
+    }
+}
+
+/// `pterm transfer <session> <user>`: resolve the session as the invoking
+/// user, then hand it over as root.
+fn cmd_transfer(args: &[String]) -> Result<()> {
+    let [name, user] = args else {
+        return Err(Error::Usage(
+            "usage: pterm transfer <session-name> <user>".to_string(),
+        ));
+    };
+    let dir = std::path::absolute(session_dir(name))?;
+    if !dir.join(SOCKET_FILENAME).exists() {
+        return Err(Error::SessionNotFound(name.to_string()));
+    }
+    let helper_args = [
+        transfer::HELPER_COMMAND.to_string(),
+        dir.display().to_string(),
+        user.clone(),
+    ];
+    if !nix::unistd::geteuid().is_root() {
+        return Err(Error::from(elevate::exec_root_helper(&helper_args)));
+    }
+    cmd_transfer_helper(&helper_args[1..])
+}
+
+fn cmd_transfer_helper(args: &[String]) -> Result<()> {
+    let [dir, user] = args else {
+        return Err(Error::Usage(
+            "session directory and user required".to_string(),
+        ));
+    };
+    let user = nix::unistd::User::from_name(user)
+        .map_err(io::Error::other)?
+        .ok_or_else(|| Error::Failed(format!("no user '{}'", user)))?;
+    let reply =
+        transfer::hand_over(Path::new(dir), &user).map_err(|e| Error::Failed(e.to_string()))?;
+    let detached = reply["detached"].as_array().map_or(0, Vec::len);
+    println!(
+        "Session handed to {} ({} client(s) detached)",
+        user.name, detached
+    );
+    Ok(())
+}
+
+/// `pterm share <session> [--duration <d>] [--json]`
+fn cmd_share(args: &[String]) -> Result<()> {
+    let mut name = None;
+    let mut duration = 3600;
+    let mut json = false;
+    let mut i = 0;
+    while i < args.len() {
+        if args[i] == "--duration" {
+            i += 1;
+            duration = args
+                .get(i)
+                .and_then(|d| schedule::parse_duration(d))
+                .ok_or_else(|| {
+                    Error::Usage("--duration requires a duration such as 30m or 1h".to_string())
+                })?;
+        } else if args[i] == "--json" {
+            json = true;
+            error::report_as_json();
+        } else if name.is_none() {
+            name = Some(args[i].as_str());
+        }
+        i += 1;
+    }
+    let Some(name) = name else {
+        return Err(Error::Usage(
+            "usage: pterm share <session-name> [--duration <d>] [--json]".to_string(),
+        ));
+    };
+    if !session_socket_path(name).exists() {
+        return Err(Error::SessionNotFound(name.to_string()));
+    }
+
+    let payload = serde_json::json!({ "cmd": "share", "args": { "duration": duration } });
+    let reply = control::call(
+        name,
+        pterm_proto::client::COMMAND,
+        payload.to_string().as_bytes(),
+    )?;
+    let mut result = reply["result"].clone();
+    let token = result["token"].as_str().unwrap_or_default().to_string();
+    let socket = std::path::absolute(result["socket"].as_str().unwrap_or_default())?;
+    let command = share::attach_command(&token, &socket);
+    let host = clients::hostname().unwrap_or_else(|| "localhost".to_string());
+    let ssh = format!("ssh -t {} {}", host, command);
+    if json {
+        result["command"] = command.into();
+        result["ssh"] = ssh.into();
+        println!("{}", result);
+        return Ok(());
+    }
+    let expires_at = result["expires_at"].as_u64().unwrap_or_default();
+    println!(
+        "Token for one read-only attach to '{}', valid until {} ({}):",
+        name,
+        schedule::local_datetime(expires_at),
+        schedule::format_duration(duration)
+    );
+    println!("  {}", command);
+    println!("  {}", ssh);
+    println!("Revoke it with: pterm unshare {}", name);
+    Ok(())
+}
+
+/// `pterm unshare <session>`
+fn cmd_unshare(args: &[String]) -> Result<()> {
+    let [name] = args else {
+        return Err(Error::Usage(
+            "usage: pterm unshare <session-name>".to_string(),
+        ));
+    };
+    if !session_socket_path(name).exists() {
+        return Err(Error::SessionNotFound(name.to_string()));
+    }
+    let payload = serde_json::json!({ "cmd": "unshare" }).to_string();
+    let reply = control::call(name, pterm_proto::client::COMMAND, payload.as_bytes())?;
+    let revoked = reply["result"]["revoked"].as_u64().unwrap_or_default();
+    let detached = reply["result"]["detached"].as_array().map_or(0, Vec::len);
+    println!(
+        "Revoked {} token(s), detached {} guest(s)",
+        revoked, detached
+    );
+    Ok(())
+}
+
+fn cmd_detach(args: &[String]) -> Result<()> {
+    let mut name = None;
+    let mut client = None;
+    let mut reason = None;
+    let mut i = 0;
+    while i < args.len() {
+        if args[i] == "--reason" {
+            reason = Some(
+                args.get(i + 1)
+                    .cloned()
+                    .ok_or_else(|| Error::Usage("--reason requires a text".to_string()))?,
+            );
+            i += 2;
+            continue;
+        }
+        if args[i] == "--client" {
+            let id = args.get(i + 1).and_then(|v| v.parse::<u32>().ok());
+            client = Some(id.ok_or_else(|| {
+                Error::Usage("--client requires a numeric client id".to_string())
+            })?);
+            i += 2;
+            continue;
+        }
+        if name.is_none() {
+            name = Some(args[i].as_str());
+        }
+        i += 1;
+    }
+    let name = name.ok_or_else(|| Error::Usage("session name required".to_string()))?;
+
+    if !session_socket_path(name).exists() {
+        return Err(Error::SessionNotFound(name.to_string()));
+    }
+
+    // Daemons before detach reasons only take a client id.
+    let payload = match (client, reason) {
+        (client, Some(reason)) => serde_json::json!({ "client": client, "reason": reason })
+            .to_string()
+            .into_bytes(),
+        (Some(id), None) => pterm_proto::encode_client_id(id).to_vec(),
+        (None, None) => Vec::new(),
+    };
+    let reply = control::request(name, pterm_proto::client::DETACH_CLIENTS, &payload)?;
+    let detached: Vec<u64> = reply["detached"]
+        .as_array()
+        .map(|ids| ids.iter().filter_map(|v| v.as_u64()).collect())
+        .unwrap_or_default();
+    if let (Some(id), true) = (client, detached.is_empty()) {
+        return Err(Error::Failed(format!(
+            "session '{}' has no client {}",
+            name, id
+        )));
+    }
+    let ids: Vec<String> = detached.iter().map(|id| id.to_string()).collect();
+    println!(
+        "Detached {} client(s) from '{}'{}",
+        ids.len(),
+        name,
+        if ids.is_empty() {
+            String::new()
+        } else {
+            format!(": {}", ids.join(", "))
+        }
+    );
+    Ok(())
+}
+
+fn cmd_doctor(args: &[String]) -> Result<()> {
+    let mut fix = false;
+    let mut json = false;
+    for arg in args {
+        match arg.as_str() {
+            "--fix" => fix = true,
+            "--json" => {
+                json = true;
+                error::report_as_json();
+            }
+            _ => {
+                return Err(Error::Usage(
+                    "usage: pterm doctor [--fix] [--json]".to_string(),
+                ));
+            }
+        }
+    }
+
+    let findings = doctor::check()?;
+    let mut remaining = 0;
+    let mut report = Vec::new();
+    for finding in &findings {
+        let outcome = match &finding.fix {
+            Some(f) if fix => Some(f.apply(&finding.path)),
+            _ => None,
+        };
+        if !matches!(outcome, Some(Ok(()))) {
+            remaining += 1;
+        }
+        if json {
+            let mut entry = serde_json::json!({
+                "path": finding.path,
+                "problem": finding.problem,
+                "fix": finding.fix.as_ref().map(|f| f.describe()),
+                "fixed": matches!(outcome, Some(Ok(()))),
+            });
+            if let Some(Err(e)) = &outcome {
+                entry["error"] = e.to_string().into();
+            }
+            report.push(entry);
+            continue;
+        }
+        println!("{}: {}", finding.path.display(), finding.problem);
+        match (&finding.fix, outcome) {
+            (Some(f), Some(Ok(()))) => println!("  fixed: {}", f.describe()),
+            (Some(f), Some(Err(e))) => println!("  failed to {}: {}", f.describe(), e),
+            (Some(f), None) => println!("  --fix would {}", f.describe()),
+            (None, _) => println!("  not fixed automatically"),
+        }
+    }
+    if json {
+        println!("{}", serde_json::Value::from(report));
+    } else if findings.is_empty() {
+        println!("No problems found");
+    }
+    if remaining > 0 {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+/// `pterm bench [--payload-size <size>] [--duration <secs>] [--json]`
+fn cmd_bench(args: &[String]) -> Result<()> {
+    let mut options = bench::Options::default();
+    let mut json = false;
+    let mut i = 0;
+    while i < args.len() {
+        let value = args.get(i + 1).map(String::as_str);
+        match args[i].as_str() {
+            "--json" => {
+                json = true;
+                error::report_as_json();
+            }
+            "--payload-size" => {
+                let range = bench::MIN_PAYLOAD_SIZE..=bench::MAX_PAYLOAD_SIZE;
+                options.payload_size = value
+                    .and_then(scrollback::parse_size)
+                    .filter(|size| range.contains(size))
+                    .ok_or_else(|| {
+                        Error::Usage(format!(
+                            "--payload-size requires a size from {} to {}",
+                            range.start(),
+                            range.end()
+                        ))
+                    })?;
+                i += 1;
+            }
+            "--duration" => {
+                let secs = value.and_then(|s| {
+                    s.parse::<u64>()
+                        .ok()
+                        .filter(|&secs| secs > 0)
+                        .or_else(|| schedule::parse_duration(s))
+                });
+                let secs = secs.ok_or_else(|| {
+                    Error::Usage("--duration requires a number of seconds".to_string())
+                })?;
+                options.duration = Duration::from_secs(secs);
+                i += 1;
+            }
+            arg => return Err(Error::Usage(format!("unknown option '{}'", arg))),
+        }
+        i += 1;
+    }
+
+    let report = bench::run(&options)?;
+    if json {
+        println!("{}", serde_json::to_string(&report).unwrap_or_default());
+    } else {
+        print!("{}", report.render());
+    }
+    Ok(())
+}
+
+/// `pterm __bench-generator <payload-size> <millis>`: runs inside the
+/// `pterm bench` session.
+fn cmd_bench_generator(args: &[String]) -> Result<()> {
+    let parsed = match args {
+        [size, millis] => size.parse().ok().zip(millis.parse().ok()),
+        _ => None,
+    };
+    let (size, millis) =
+        parsed.ok_or_else(|| Error::Usage("payload size and duration required".to_string()))?;
+    bench::generate(size, Duration::from_millis(millis))?;
+    Ok(())
+}
+
+fn cmd_upgrade_daemons(args: &[String]) -> Result<()> {
+    let prefix = args.first().map(|s| s.as_str()).unwrap_or("");
+    let binary = std::env::current_exe()?;
+    let payload = binary.as_os_str().as_encoded_bytes();
+
+    let mut failed = false;
+    for entry in list::collect(prefix)? {
+        match entry.state {
+            list::SessionState::Attached
+            | list::SessionState::Detached
+            | list::SessionState::Suspended => {}
+            state => {
+                println!("{}: skipped ({})", entry.name, state.as_str());
+                continue;
+            }
+        }
+        if entry.meta.as_ref().is_some_and(|m| m.sandbox.is_some()) {
+            println!("{}: skipped (sandboxed)", entry.name);
+            continue;
+        }
+        match control::send(&entry.name, pterm_proto::client::UPGRADE, payload) {
+            Ok(()) => println!("{}: upgrading to {}", entry.name, binary.display()),
+            Err(e) => {
+                eprintln!("{}: {}", entry.name, e);
+                failed = true;
+            }
+        }
+    }
+    if failed {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+/// Entry point of a daemon re-exec'd by a hot upgrade (see `upgrade.rs`).
+fn cmd_resume(args: &[String]) -> Result<()> {
+    let sess_dir = args
+        .first()
+        .map(PathBuf::from)
+        .ok_or_else(|| Error::Usage("session directory required".to_string()))?;
+
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"))
+        .target(env_logger::Target::Stderr)
+        .init();
+
+    let (state, snapshot) = upgrade::take_state(&sess_dir)?;
+    // SAFETY: the fds were handed over by the previous daemon image, which
+    // owned them exclusively and cleared FD_CLOEXEC right before exec.
+    let pty = unsafe {
+        pty::Pty::from_raw_parts(
+            state.pty_fd,
+            nix::unistd::Pid::from_raw(state.child_pid),
+            state.stderr_fd,
+        )
+    };
+    let session = Session::resume(
+        state.session.clone(),
+        state.command.clone(),
+        pty,
+        state.cols,
+        state.rows,
+        state.options.scrollback,
+        &snapshot,
+    );
+    let mut server = Server::resume(&sess_dir, session, &state)?;
+    Ok(server.run()?)
+}
+
+/// `pterm scrollback <session-name> [--tail <size>] [--stderr]`
+fn cmd_scrollback(args: &[String]) -> Result<()> {
+    let mut name = None;
+    let mut tail = None;
+    let mut stderr = false;
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--stderr" => stderr = true,
+            "--tail" => {
+                tail = Some(
+                    args.get(i + 1)
+                        .and_then(|s| scrollback::parse_size(s))
+                        .ok_or_else(|| {
+                            Error::Usage("--tail requires a size (e.g. 64K)".to_string())
+                        })?,
+                );
+                i += 1;
+            }
+            arg => name = Some(arg),
+        }
+        i += 1;
+    }
+    let name = name.ok_or_else(|| Error::Usage("session name required".to_string()))?;
+
+    let file = if stderr {
+        scrollback::STDERR_FILENAME
+    } else {
+        scrollback::SCROLLBACK_FILENAME
+    };
+    let path = session_dir(name).join(file);
+    let data = match scrollback::read_file(&path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound && stderr => {
+            return Err(Error::Failed(format!(
+                "session '{}' keeps no separate stderr (start it with --split-stderr)",
+                name
+            )));
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err(Error::Failed(format!(
+                "session '{}' has no scrollback file (start it with --scrollback-file)",
+                name
+            )));
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let start = tail.map_or(0, |tail| data.len().saturating_sub(tail));
+    Ok(io::stdout().write_all(&data[start..])?)
+}
+
+/// `pterm history <session-name> [--since <when>] [--from-line <n>]
+/// [--search <text>]`
+fn cmd_history(args: &[String]) -> Result<()> {
+    let mut name = None;
+    let mut since = None;
+    let mut from_line = None;
+    let mut search = None;
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        let mut value = |what: &str| {
+            iter.next()
+                .cloned()
+                .ok_or_else(|| Error::Usage(format!("{} requires {}", arg, what)))
+        };
+        match arg.as_str() {
+            "--since" => {
+                let when = value("a duration (e.g. 2h) or a unix time")?;
+                since = Some(
+                    parse_since(&when)
+                        .ok_or_else(|| Error::Usage(format!("invalid --since '{}'", when)))?,
+                );
+            }
+            "--from-line" => {
+                let line = value("a line number")?;
+                from_line = Some(
+                    line.parse::<u64>()
+                        .map_err(|_| Error::Usage(format!("invalid line number '{}'", line)))?,
+                );
+            }
+            "--search" => search = Some(value("a text")?),
+            a if a.starts_with("--") => {
+                return Err(Error::Usage("usage: pterm history <session-name> [--since <when>] [--from-line <n>] [--search <text>]".to_string()));
+            }
+            a => name = Some(a),
+        }
+    }
+    let name = name.ok_or_else(|| Error::Usage("session name required".to_string()))?;
+
+    let history = history::History::open(&session_dir(name))
+        .map_err(|e| Error::Failed(format!("session '{}': {}", name, e)))?;
+    let segments = history.select(since, from_line.map(|line| line.saturating_sub(1)));
+    let mut stdout = io::stdout().lock();
+    match search {
+        Some(pattern) => history.search(&segments, &pattern, |line, text| {
+            let _ = writeln!(stdout, "{}: {}", line, text);
+        }),
+        None => history.write(&segments, from_line, &mut stdout),
+    }?;
+    Ok(())
+}
+
+/// `--since` as a unix time: a duration before now (`90m`), or a unix time.
+fn parse_since(when: &str) -> Option<u64> {
+    match schedule::parse_duration(when) {
+        Some(ago) => Some(meta::unix_now().saturating_sub(ago)),
+        None => when.parse().ok(),
+    }
+}
+
+fn cmd_copy(args: &[String]) -> Result<()> {
+    let (text, print) = captured_selection(args)?;
+    paste::store(&text)?;
+    if print {
+        println!("{}", text);
+    }
+    Ok(())
+}
+
+/// `pterm copy-output <session> [--all | --lines <n> | --last-command]
+/// [--print]`: like `pterm copy`, but onto the local clipboard.
+fn cmd_copy_output(args: &[String]) -> Result<()> {
+    let (text, print) = captured_selection(args)?;
+    paste::set_clipboard(&text)?;
+    if print {
+        println!("{}", text);
+    }
+    Ok(())
+}
+
+/// The text the `pterm copy` / `copy-output` arguments select from the
+/// session they name, and whether to print it.
+fn captured_selection(args: &[String]) -> Result<(String, bool)> {
+    let mut name = None;
+    let mut selection = paste::Selection::Screen;
+    let mut print = false;
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--all" => selection = paste::Selection::All,
+            "--last-command" => selection = paste::Selection::LastCommand,
+            "--lines" => {
+                let lines = args.get(i + 1).and_then(|s| s.parse::<usize>().ok());
+                selection = paste::Selection::Lines(
+                    lines.ok_or_else(|| Error::Usage("--lines requires a number".to_string()))?,
+                );
+                i += 1;
+            }
+            "--print" => print = true,
+            arg => name = Some(arg),
+        }
+        i += 1;
+    }
+    let name = name.ok_or_else(|| Error::Usage("session name required".to_string()))?;
+    let text = capture(name, false)?
+        .select(selection)
+        .map_err(|e| Error::Failed(e.to_string()))?;
+    Ok((text, print))
+}
+
+/// The scrollback and screen of `name`, as the daemon's `CAPTURE` reply,
+/// reflowed with `rewrap`.
+fn capture(name: &str, rewrap: bool) -> Result<paste::Capture> {
+    if !session_socket_path(name).exists() {
+        return Err(Error::SessionNotFound(name.to_string()));
+    }
+    let payload = if rewrap {
+        serde_json::json!({ "rewrap": true }).to_string()
+    } else {
+        String::new()
+    };
+    let reply = control::request(name, pterm_proto::client::CAPTURE, payload.as_bytes())?;
+    Ok(paste::Capture::from_json(&reply)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "malformed capture reply"))?)
+}
+
+/// `pterm capture <session> [--rewrap] [-o <file>]`: the scrollback and
+/// screen as text, without attaching.
+fn cmd_capture(args: &[String]) -> Result<()> {
+    let usage =
+        || Error::Usage("usage: pterm capture <session-name> [--rewrap] [-o <file>]".to_string());
+    let mut name = None;
+    let mut output = None;
+    let mut rewrap = false;
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "-o" | "--output" => output = Some(PathBuf::from(iter.next().ok_or_else(usage)?)),
+            "--rewrap" => rewrap = true,
+            a if a.starts_with('-') => return Err(usage()),
+            a => name = Some(a),
+        }
+    }
+    let name = name.ok_or_else(usage)?;
+
+    let mut text = capture(name, rewrap)?
+        .select(paste::Selection::All)
+        .map_err(Error::Failed)?;
+    if !text.is_empty() {
+        text.push('\n');
+    }
+    match output {
+        Some(path) => std::fs::write(&path, text).at_path(path),
+        None => Ok(io::stdout().write_all(text.as_bytes())?),
+    }
+}
+
+fn cmd_paste_buffer(args: &[String]) -> Result<()> {
+    let print = args.iter().any(|a| a == "--print");
+    let name = args.iter().find(|a| !a.starts_with("--"));
+    let text = paste::load()?;
+    if print {
+        print!("{}", text);
+        return Ok(());
+    }
+    let name = name.ok_or_else(|| Error::Usage("session name required".to_string()))?;
+    if text.is_empty() {
+        return Err(Error::Failed(
+            "the paste buffer is empty (fill it with `pterm copy`)".to_string(),
+        ));
+    }
+
+    if !session_socket_path(name).exists() {
+        return Err(Error::SessionNotFound(name.to_string()));
+    }
+
+    control::call(name, pterm_proto::client::PASTE, text.as_bytes())?;
+    Ok(())
+}
+
+fn cmd_socket(args: &[String]) -> Result<()> {
+    let name = args
+        .first()
+        .map(|s| s.as_str())
+        .ok_or_else(|| Error::Usage("session name required".to_string()))?;
+
+    let sock_path = session_socket_path(name);
+    println!("{}", sock_path.display());
+    Ok(())
+}
+
+/// `pterm panes --wezterm|--kitty [--split|--window] [--dry-run] [<glob>]`
+fn cmd_panes(args: &[String]) -> Result<()> {
+    let usage = || {
+        Error::Usage(
+            "usage: pterm panes --wezterm|--kitty [--split|--window] [--dry-run] [<session-glob>]"
+                .to_string(),
+        )
+    };
+    let mut terminal = None;
+    let mut placement = panes::Placement::default();
+    let mut dry_run = false;
+    let mut glob = "*";
+    for arg in args {
+        match arg.as_str() {
+            "--wezterm" => terminal = Some(panes::Terminal::WezTerm),
+            "--kitty" => terminal = Some(panes::Terminal::Kitty),
+            "--tab" => placement = panes::Placement::Tab,
+            "--split" => placement = panes::Placement::Split,
+            "--window" => placement = panes::Placement::Window,
+            "--dry-run" => dry_run = true,
+            a if a.starts_with("--") => return Err(usage()),
+            a => glob = a,
+        }
+    }
+    let terminal = terminal.ok_or_else(usage)?;
+
+    let sessions = panes::matching_sessions(glob)?;
+    if sessions.is_empty() {
+        return Err(Error::Failed(format!("no sessions match '{}'", glob)));
+    }
+    let binary = std::env::current_exe()?;
+    let pterm = binary.to_string_lossy();
+    for session in &sessions {
+        if dry_run {
+            let command = panes::launch_command(terminal, placement, &pterm, session);
+            println!("{}", schedule::shell_join(&command));
+        } else {
+            panes::open(terminal, placement, &pterm, session)?;
+        }
+    }
+    Ok(())
+}
+
+/// `pterm export <session> [-o <file>]`
+fn cmd_export(args: &[String]) -> Result<()> {
+    let (name, output) = match args {
+        [name] => (name.as_str(), None),
+        [name, flag, output] if flag == "-o" => (name.as_str(), Some(PathBuf::from(output))),
+        _ => {
+            return Err(Error::Usage(
+                "usage: pterm export <session-name> [-o <file>]".to_string(),
+            ));
+        }
+    };
+    let dir = session_dir(name);
+    let meta = match meta::read(&dir) {
+        Ok(meta) => meta,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err(Error::SessionNotFound(name.to_string()));
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let output =
+        output.unwrap_or_else(|| PathBuf::from(format!("{}.tar.zst", name.replace('/', "-"))));
+
+    // Raw output from the scrollback file, else the live daemon's text.
+    let text = match scrollback::read_file(&dir.join(scrollback::SCROLLBACK_FILENAME)) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let reply = control::request(name, pterm_proto::client::CAPTURE, &[])?;
+            let capture = paste::Capture::from_json(&reply).unwrap_or_default();
+            let mut text = capture
+                .select(paste::Selection::All)
+                .unwrap_or_default()
+                .replace('\n', "\r\n");
+            text.push_str("\r\n");
+            text.into_bytes()
+        }
+        Err(e) => return Err(e.into()),
+    };
+    archive::write(&output, &meta, &text).at_path(&output)?;
+    println!("Exported '{}' to {}", name, output.display());
+    Ok(())
+}
+
+/// `pterm import <file> [--as <session-name>]`
+fn cmd_import(args: &[String]) -> Result<()> {
+    let (file, name) = match args {
+        [file] => (file, None),
+        [file, flag, name] if flag == "--as" => (file, Some(name.clone())),
+        _ => {
+            return Err(Error::Usage(
+                "usage: pterm import <file> [--as <session-name>]".to_string(),
+            ));
+        }
+    };
+    let file = std::path::absolute(file)?;
+    // Unpack next to the session socket, so `pterm kill` cleans it up.
+    let staging = paths::socket_dir().join(format!(".import-{}", std::process::id()));
+    paths::ensure_socket_dir()?;
+    let meta = archive::unpack(&file, &staging).inspect_err(|_| {
+        let _ = std::fs::remove_dir_all(&staging);
+    })?;
+    let name = name.unwrap_or(meta.session);
+    let dir = std::path::absolute(paths::new_session_dir(&name))?;
+    if dir.join(SOCKET_FILENAME).exists() {
+        let _ = std::fs::remove_dir_all(&staging);
+        return Err(Error::Failed(format!("session '{}' already exists", name)));
+    }
+    std::fs::create_dir_all(&dir)?;
+    let unpacked = dir.join(archive::IMPORT_DIRNAME);
+    let _ = std::fs::remove_dir_all(&unpacked);
+    std::fs::rename(&staging, &unpacked)?;
+
+    let binary = std::env::current_exe()?;
+    let new_args = [
+        "--hold".to_string(),
+        "--wait-ready".to_string(),
+        name,
+        "--".to_string(),
+        binary.to_string_lossy().into_owned(),
+        archive::SHOW_COMMAND.to_string(),
+        unpacked.to_string_lossy().into_owned(),
+    ];
+    cmd_new(&new_args, false)
+}
+
+/// `pterm __show-export <dir>`: runs inside an imported session.
+fn cmd_show_export(args: &[String]) -> Result<()> {
+    let dir = args
+        .first()
+        .map(PathBuf::from)
+        .ok_or_else(|| Error::Usage("export directory required".to_string()))?;
+    let code = archive::show(&dir)?;
+    std::process::exit(code);
+}
+
+/// `pterm import-dtach [--abduco] <socket> [<session-name>]`
+fn cmd_import_dtach(args: &[String]) -> Result<()> {
+    let flavor = if args.iter().any(|a| a == "--abduco") {
+        import::Flavor::Abduco
+    } else {
+        import::Flavor::Dtach
+    };
+    let positional: Vec<&String> = args.iter().filter(|a| !a.starts_with("--")).collect();
+    let (socket, name) = match positional.as_slice() {
+        [socket] => (socket.as_str(), None),
+        [socket, name] => (socket.as_str(), Some(name.to_string())),
+        _ => {
+            return Err(Error::Usage(
+                "usage: pterm import-dtach [--abduco] <socket> [<session-name>]".to_string(),
+            ));
+        }
+    };
+    let socket = std::path::absolute(socket)?;
+    let name = name
+        .or_else(|| import::default_session_name(&socket, flavor))
+        .ok_or_else(|| Error::Usage("session name required".to_string()))?;
+
+    // Fail here rather than in the background daemon if nothing listens.
+    if let Err(e) = std::os::unix::net::UnixStream::connect(&socket) {
+        return Err(Error::Failed(format!(
+            "cannot connect to {} socket {}: {}",
+            flavor.as_str(),
+            socket.display(),
+            e
+        )));
+    }
+
+    let binary = std::env::current_exe()?;
+    let new_args = [
+        name,
+        "--".to_string(),
+        binary.to_string_lossy().into_owned(),
+        import::PROXY_COMMAND.to_string(),
+        flavor.as_str().to_string(),
+        socket.to_string_lossy().into_owned(),
+    ];
+    cmd_new(&new_args, false)
+}
+
+/// `pterm __import-proxy <dtach|abduco> <socket>`: runs inside an imported
+/// session.
+fn cmd_import_proxy(args: &[String]) -> Result<()> {
+    let (flavor, socket) = match args {
+        [flavor, socket] => match import::Flavor::parse(flavor) {
+            Some(flavor) => (flavor, PathBuf::from(socket)),
+            None => {
+                return Err(Error::Failed(format!("unknown import flavor '{}'", flavor)));
+            }
+        },
+        _ => {
+            return Err(Error::Usage("flavor and socket required".to_string()));
+        }
+    };
+    let code = import::proxy(&socket, flavor)?;
+    std::process::exit(code);
+}
+
+/// `pterm client <session-name>`
+fn cmd_client(args: &[String]) -> Result<()> {
+    let name = match args {
+        [name] => name,
+        _ => {
+            return Err(Error::Usage(
+                "usage: pterm client <session-name>".to_string(),
+            ));
+        }
+    };
+    let sock = session_socket_path(name);
+    if !sock.exists() {
+        return Err(Error::SessionNotFound(name.to_string()));
+    }
+    refuse_enclosing_session(&sock, name)?;
+    let exit_code = client::run(name)?;
+    std::process::exit(exit_code);
+}
+
+/// `pterm tmux-cc [-C] [<session-name>]`
+fn cmd_tmux_cc(args: &[String]) -> Result<()> {
+    let plain = args.iter().any(|a| a == "-C");
+    let name = args
+        .iter()
+        .find(|a| !a.starts_with('-'))
+        .map(|s| s.as_str());
+    if let Some(name) = name {
+        if !session_socket_path(name).exists() {
+            return Err(Error::SessionNotFound(name.to_string()));
+        }
+    }
+    Ok(tmux_cc::run(name, !plain)?)
+}
+
+fn cmd_replay_io(args: &[String]) -> Result<()> {
+    let dir = args
+        .first()
+        .map(PathBuf::from)
+        .ok_or_else(|| Error::Usage("recording directory required".to_string()))?;
+    let dump_screen = args[1..].iter().any(|a| a == "--screen");
+
+    let report = recording::replay(&dir).at_path(&dir)?;
+    println!(
+        "{} record(s) over {:.3}s: {} PTY byte(s), {} client frame(s), {} snapshot(s)",
+        report.records,
+        report.duration.as_secs_f64(),
+        report.pty_bytes,
+        report.client_frames,
+        report.snapshots
+    );
+    for d in &report.divergences {
+        println!(
+            "snapshot #{} for client {} at {:.6}s diverged: recorded {} byte(s), replayed {} byte(s)",
+            d.index,
+            d.client_id,
+            d.elapsed.as_secs_f64(),
+            d.recorded_len,
+            d.replayed_len
+        );
+    }
+    if dump_screen {
+        println!("{}", report.final_screen);
+    }
+    if !report.divergences.is_empty() {
+        std::process::exit(2);
+    }
+    Ok(())
+}
+
+/// Optional cargo features compiled into this binary.
+const FEATURES: &[&str] = &[];
+
+/// Whether this binary and the daemon of `entry`, speaking protocol
+/// `version`, share a protocol version.
+fn compatible(entry: &list::SessionEntry, version: u16) -> bool {
+    let min_version = entry
+        .meta
+        .as_ref()
+        .and_then(|m| m.min_protocol_version)
+        .unwrap_or(version);
+    pterm_proto::negotiate_version(version, min_version).is_some()
+}
+
+fn cmd_version(args: &[String]) -> Result<()> {
+    let json = args.iter().any(|a| a == "--json");
+    if json {
+        error::report_as_json();
+    }
+    if args.iter().any(|a| a == "--check") {
+        return cmd_version_check();
+    }
+    let version = env!("CARGO_PKG_VERSION");
+    let protocol = pterm_proto::PROTOCOL_VERSION;
+    let sessions = list::collect("")?;
+
+    if json {
+        let sessions: Vec<_> = sessions
+            .iter()
+            .map(|e| {
+                let daemon_protocol = e
+                    .meta
+                    .as_ref()
+                    .map(|m| m.protocol_version)
+                    .filter(|v| *v != 0);
+                serde_json::json!({
+                    "session": e.name,
+                    "state": e.state.as_str(),
+                    "pid": e.meta.as_ref().map(|m| m.pid),
+                    "protocol_version": daemon_protocol,
+                    "compatible": daemon_protocol.is_some_and(|v| compatible(e, v)),
+                })
+            })
+            .collect();
+        println!(
+            "{}",
+            serde_json::json!({
+                "version": version,
+                "protocol_version": protocol,
+                "features": FEATURES,
+                "sessions": sessions,
+            })
+        );
+        return Ok(());
+    }
+
+    println!("pterm {} (protocol {})", version, protocol);
+    if FEATURES.is_empty() {
+        println!("features: none");
+    } else {
+        println!("features: {}", FEATURES.join(", "));
+    }
+    for e in &sessions {
+        match e.meta.as_ref().map(|m| m.protocol_version) {
+            Some(v) if v == protocol => println!("  {}  protocol {}", e.name, v),
+            Some(v) if v != 0 && compatible(e, v) => {
+                println!("  {}  protocol {} (compatible)", e.name, v)
+            }
+            Some(v) if v != 0 => println!("  {}  protocol {} (mismatch)", e.name, v),
+            _ => println!("  {}  protocol unknown (older daemon)", e.name),
+        }
+    }
+    Ok(())
+}
+
+/// `pterm version --check`: exit 1 if a running daemon is on another pterm
+/// version than this binary (e.g. after `pterm self-update`, until
+/// `pterm upgrade-daemons`).
+fn cmd_version_check() -> Result<()> {
+    let version = env!("CARGO_PKG_VERSION");
+    let mut mismatched = 0;
+    for entry in list::collect("")? {
+        if matches!(
+            entry.state,
+            list::SessionState::Dead | list::SessionState::Unknown
+        ) {
+            continue;
+        }
+        match entry.meta.as_ref().and_then(|m| m.version.as_deref()) {
+            Some(v) if v == version => {}
+            Some(v) => {
+                println!("{}: daemon is pterm {}", entry.name, v);
+                mismatched += 1;
+            }
+            None => {
+                println!("{}: daemon predates pterm {}", entry.name, version);
+                mismatched += 1;
+            }
+        }
+    }
+    if mismatched > 0 {
+        return Err(Error::Failed(format!(
+            "{} daemon(s) not on pterm {}; run `pterm upgrade-daemons`",
+            mismatched, version
+        )));
+    }
+    println!("all daemons run pterm {}", version);
+    Ok(())
+}
+
+/// Trusted release keys, or an error: nothing is installed unverified.
+fn release_keys() -> Result<Vec<String>> {
+    let config = config::Config::load().unwrap_or_default();
+    let keys = selfupdate::release_keys(config.release_key.as_deref(), config.trust_release_key);
+    if keys.is_empty() {
+        return Err(Error::Failed(
+            "no release signing key; this build has none built in, so set \
+             `release_key` (or $PTERM_EXTRA_RELEASE_KEY) and `trust_release_key = true` \
+             in the config file"
+                .to_string(),
+        ));
+    }
+    Ok(keys)
+}
+
+fn cmd_self_update(args: &[String]) -> Result<()> {
+    let mut version = None;
+    let mut check = false;
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--version" if i + 1 < args.len() => {
+                version = Some(args[i + 1].clone());
+                i += 1;
+            }
+            "--check" => check = true,
+            other => {
+                return Err(Error::Failed(format!(
+                    "unknown self-update option '{}'",
+                    other
+                )));
+            }
+        }
+        i += 1;
+    }
+    let tag = match version {
+        Some(v) => selfupdate::tag(&v),
+        None => selfupdate::latest_tag().map_err(|e| Error::Failed(e.to_string()))?,
+    };
+    let current = selfupdate::tag(env!("CARGO_PKG_VERSION"));
+    if check {
+        if tag == current {
+            println!("pterm {} is up to date", current);
+        } else {
+            println!("pterm {} is available (this is {})", tag, current);
+        }
+        return Ok(());
+    }
+    if tag == current {
+        println!("pterm {} is already installed", current);
+        return Ok(());
+    }
+    let keys = release_keys()?;
+    let binary = std::env::current_exe()?;
+    eprintln!(
+        "Downloading pterm {} ({})...",
+        tag,
+        selfupdate::asset_name()
+    );
+    if let Err(e) = selfupdate::install(&tag, &binary, &keys) {
+        return Err(Error::Failed(e.to_string()));
+    }
+    println!("Updated {} to pterm {}", binary.display(), tag);
+    println!("Running daemons keep the old version until `pterm upgrade-daemons`");
+    Ok(())
+}
+
+fn cmd_self_install(args: &[String]) -> Result<()> {
+    let mut dir = None;
+    let mut version = None;
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--dir" if i + 1 < args.len() => {
+                dir = Some(PathBuf::from(&args[i + 1]));
+                i += 1;
+            }
+            "--version" if i + 1 < args.len() => {
+                version = Some(args[i + 1].clone());
+                i += 1;
+            }
+            other => {
+                return Err(Error::Failed(format!(
+                    "unknown self-install option '{}'",
+                    other
+                )));
+            }
+        }
+        i += 1;
+    }
+    let Some(dir) = dir.or_else(selfupdate::default_install_dir) else {
+        return Err(Error::Failed("$HOME is not set; pass --dir".to_string()));
+    };
+    let tag = selfupdate::tag(version.as_deref().unwrap_or(env!("CARGO_PKG_VERSION")));
+    let keys = release_keys()?;
+    let dest = dir.join("pterm");
+    eprintln!(
+        "Downloading pterm {} ({})...",
+        tag,
+        selfupdate::asset_name()
+    );
+    if let Err(e) = selfupdate::install(&tag, &dest, &keys) {
+        return Err(Error::Failed(e.to_string()));
+    }
+    println!("Installed pterm {} as {}", tag, dest.display());
+    Ok(())
+}
+
+/// Take `--socket-dir <dir>` (or `--socket-dir=<dir>`) from before the
+/// command. It overrides the socket root for this invocation, the daemons
+/// it forks and the pterm commands it starts, without touching the
+/// environment the sessions' commands see. It is made absolute, since
+/// those work from other directories.
+fn take_socket_dir(args: &mut Vec<String>) -> Result<Option<PathBuf>> {
+    let mut socket_dir = None;
+    while let Some(arg) = args.get(1).filter(|a| a.starts_with("--socket-dir")) {
+        let (dir, consumed) = match arg.strip_prefix("--socket-dir=") {
+            Some(dir) => (dir.to_string(), 1),
+            None if arg == "--socket-dir" => (args.get(2).cloned().unwrap_or_default(), 2),
+            None => break,
+        };
+        if dir.is_empty() {
+            return Err(Error::Usage(
+                "--socket-dir requires a directory".to_string(),
+            ));
+        }
+        let dir = std::path::absolute(&dir)
+            .map_err(|e| Error::Usage(format!("invalid --socket-dir '{}': {}", dir, e)))?;
+        socket_dir = Some(dir);
+        args.drain(1..1 + consumed);
+    }
+    Ok(socket_dir)
+}
+
+fn main() {
+    let mut args: Vec<String> = std::env::args().collect();
+
+    match take_socket_dir(&mut args) {
+        Ok(Some(dir)) => paths::override_socket_dir(dir),
+        Ok(None) => {}
+        Err(e) => {
+            e.report(false);
+            std::process::exit(1);
+        }
+    }
 
     if args.len() < 2 {
         print_usage();
@@ -329,10 +3354,62 @@ fn main() {
         "new" => cmd_new(&args[2..], false),
         "attach" => cmd_attach(&args[2..]),
         "open" => cmd_open(&args[2..]),
+        "here" => cmd_here(&args[2..]),
+        "up" => cmd_up(&args[2..]),
         "list" | "ls" => cmd_list(&args[2..]),
         "kill" => cmd_kill(&args[2..]),
         "redraw" => cmd_redraw(&args[2..]),
+        "detach" => cmd_detach(&args[2..]),
+        "transfer" => cmd_transfer(&args[2..]),
+        "share" => cmd_share(&args[2..]),
+        "unshare" => cmd_unshare(&args[2..]),
+        transfer::HELPER_COMMAND => cmd_transfer_helper(&args[2..]),
+        "clients" => cmd_clients(&args[2..]),
+        "info" => cmd_info(&args[2..]),
+        "metadata" => cmd_metadata(&args[2..]),
+        "command" => cmd_command(&args[2..]),
+        "strace" => cmd_strace(&args[2..]),
+        "stats" => cmd_stats(&args[2..]),
+        "query" => cmd_query(&args[2..]),
+        "set" => cmd_set(&args[2..]),
+        "at" => cmd_schedule_add(&args[2..], false),
+        "every" => cmd_schedule_add(&args[2..], true),
+        "schedule" => cmd_schedule(&args[2..]),
+        "on-output" => cmd_on_output(&args[2..]),
+        "on-exit" => cmd_on_exit(&args[2..]),
+        "guard" => cmd_guard(&args[2..]),
+        "send" => cmd_send(&args[2..]),
+        "exec" => cmd_exec(&args[2..]),
+        "wait" => cmd_wait(&args[2..]),
+        "suspend" => cmd_suspend(&args[2..], true),
+        "resume" => cmd_suspend(&args[2..], false),
+        "signal" => cmd_signal(&args[2..]),
+        "respawn" => cmd_respawn(&args[2..]),
         "socket" => cmd_socket(&args[2..]),
+        "scrollback" => cmd_scrollback(&args[2..]),
+        "history" => cmd_history(&args[2..]),
+        "capture" => cmd_capture(&args[2..]),
+        "copy" => cmd_copy(&args[2..]),
+        "copy-output" => cmd_copy_output(&args[2..]),
+        "paste-buffer" => cmd_paste_buffer(&args[2..]),
+        "client" => cmd_client(&args[2..]),
+        "tmux-cc" => cmd_tmux_cc(&args[2..]),
+        "mux" => mux::run().map_err(Error::from),
+        "panes" => cmd_panes(&args[2..]),
+        "export" => cmd_export(&args[2..]),
+        "import" => cmd_import(&args[2..]),
+        archive::SHOW_COMMAND => cmd_show_export(&args[2..]),
+        "import-dtach" => cmd_import_dtach(&args[2..]),
+        import::PROXY_COMMAND => cmd_import_proxy(&args[2..]),
+        "replay-io" => cmd_replay_io(&args[2..]),
+        "version" | "--version" | "-V" => cmd_version(&args[2..]),
+        "doctor" => cmd_doctor(&args[2..]),
+        "bench" => cmd_bench(&args[2..]),
+        bench::GENERATOR_COMMAND => cmd_bench_generator(&args[2..]),
+        "upgrade-daemons" => cmd_upgrade_daemons(&args[2..]),
+        "self-update" => cmd_self_update(&args[2..]),
+        "self-install" => cmd_self_install(&args[2..]),
+        upgrade::RESUME_COMMAND => cmd_resume(&args[2..]),
         "-h" | "--help" | "help" => {
             print_usage();
             Ok(())
@@ -345,7 +3422,7 @@ fn main() {
     };
 
     if let Err(e) = result {
-        eprintln!("Error: {}", e);
+        e.report(error::json_requested());
         std::process::exit(1);
     }
 }
@@ -0,0 +1,348 @@
+//! Session metadata persisted by the daemon as `<session_dir>/meta.json`.
+//!
+//! The daemon owns this file: it is written at startup and rewritten when
+//! the attached client count changes, when the child exits, and (throttled)
+//! on activity. CLI commands read it to describe sessions without connecting
+//! to the daemon.
+
+use crate::hook::ExitHook;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Metadata file name within a session directory.
+pub const META_FILENAME: &str = "meta.json";
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionMeta {
+    pub session: String,
+    /// Child command line (argv).
+    pub command: Vec<String>,
+    /// Daemon process id.
+    pub pid: i32,
+    /// Wire protocol version spoken by the daemon. `0` for metadata written
+    /// before this field existed.
+    #[serde(default)]
+    pub protocol_version: u16,
+    /// Oldest protocol version the daemon still speaks; `None` for daemons
+    /// that predate the `HELLO` handshake, which speak only their own.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_protocol_version: Option<u16>,
+    /// pterm version of the daemon binary, for `pterm version --check`;
+    /// `None` for daemons that predate this field.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    /// Child process id.
+    pub child_pid: i32,
+    /// The pty's slave device, e.g. `/dev/pts/3`, for tools that attach to
+    /// the session's processes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tty: Option<PathBuf>,
+    /// Unix timestamp (seconds) when the session was created.
+    pub created_at: u64,
+    /// Number of attached clients.
+    pub clients: usize,
+    /// Unix timestamp (seconds) of the last PTY output or client input.
+    pub last_activity: u64,
+    /// Child processes are stopped by `pterm suspend`.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub suspended: bool,
+    /// Child exit code once it has exited.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<i32>,
+    /// How the child ended, once it has exited.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exit: Option<ExitInfo>,
+    /// Cumulative resource usage, for `pterm stats`.
+    #[serde(default)]
+    pub stats: SessionStats,
+    /// `--record-io` directory, for `pterm export`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recording: Option<PathBuf>,
+    /// Unix timestamp at which the idle timeout ends the session, once its
+    /// warning has been written; cleared when a client attaches or input
+    /// arrives.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+    /// What `pterm new --sandbox` applied, e.g. `seccomp, landlock v3`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sandbox: Option<String>,
+    /// Session `pterm new` ran inside (its `PTERM_SESSION`), if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nested_in: Option<String>,
+    /// Output and input per minute, for `pterm info --activity`.
+    #[serde(default, skip_serializing_if = "Activity::is_empty")]
+    pub activity: Activity,
+    /// Command run when the child exits (`pterm on-exit`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exit_hook: Option<ExitHook>,
+}
+
+/// Resource usage accumulated over the session's lifetime (carried across
+/// hot upgrades).
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SessionStats {
+    /// User + system CPU time of the session's processes, in milliseconds.
+    pub cpu_ms: u64,
+    /// Bytes read from the PTY.
+    pub output_bytes: u64,
+    /// Bytes of client input written to the PTY (`INPUT` frames only;
+    /// `--direct-input` bypasses the daemon).
+    pub input_bytes: u64,
+}
+
+/// Minutes of activity an [`Activity`] keeps.
+pub const ACTIVITY_MINUTES: u64 = 24 * 60;
+
+/// Per-minute histogram of the last [`ACTIVITY_MINUTES`] (carried across hot
+/// upgrades). Only minutes with activity are stored, as
+/// `[unix minute, bytes out, bytes in]`, so an idle session costs nothing.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Activity(VecDeque<(u64, u64, u64)>);
+
+impl Activity {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Count `bytes` read from the PTY at Unix time `now`.
+    pub fn output(&mut self, now: u64, bytes: u64) {
+        self.minute(now).1 += bytes;
+    }
+
+    /// Count `bytes` of client input (keystrokes, pasted text) at `now`.
+    pub fn input(&mut self, now: u64, bytes: u64) {
+        self.minute(now).2 += bytes;
+    }
+
+    fn minute(&mut self, now: u64) -> &mut (u64, u64, u64) {
+        let minute = now / 60;
+        let cutoff = minute.saturating_sub(ACTIVITY_MINUTES - 1);
+        while self.0.front().is_some_and(|m| m.0 < cutoff) {
+            self.0.pop_front();
+        }
+        if self.0.back().is_none_or(|m| m.0 < minute) {
+            self.0.push_back((minute, 0, 0));
+        }
+        self.0.back_mut().expect("pushed above")
+    }
+
+    /// Bytes out and in for each of the `minutes` minutes up to `now`,
+    /// oldest first, idle minutes as 0.
+    pub fn histogram(&self, now: u64, minutes: u64) -> (Vec<u64>, Vec<u64>) {
+        let minutes = minutes.min(ACTIVITY_MINUTES);
+        let end = now / 60;
+        let start = (end + 1).saturating_sub(minutes);
+        let len = (end + 1 - start) as usize;
+        let (mut output, mut input) = (vec![0; len], vec![0; len]);
+        for &(minute, out, inp) in self.0.iter().filter(|m| (start..=end).contains(&m.0)) {
+            output[(minute - start) as usize] = out;
+            input[(minute - start) as usize] = inp;
+        }
+        (output, input)
+    }
+}
+
+/// `values` as a sparkline of block characters scaled to the largest, with
+/// a blank for 0 so idle stretches stand out.
+pub fn sparkline(values: &[u64]) -> String {
+    const BLOCKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+    let max = values.iter().copied().max().unwrap_or(0).max(1);
+    values
+        .iter()
+        .map(|&v| match v {
+            0 => ' ',
+            v => BLOCKS[((v * 8 - 1) / max).min(7) as usize],
+        })
+        .collect()
+}
+
+/// How the child ended: its exit status or the signal that killed it, and
+/// what it used. Usage covers the child and the descendants it waited for.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ExitInfo {
+    /// Exit status, or 128 + the signal for a child killed by a signal.
+    pub code: i32,
+    /// Signal that killed the child.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub signal: Option<i32>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub core_dumped: bool,
+    /// CPU time in user mode, in microseconds.
+    pub user_us: u64,
+    /// CPU time in kernel mode, in microseconds.
+    pub system_us: u64,
+    /// Peak resident set size, in KiB.
+    pub max_rss_kib: u64,
+}
+
+impl ExitInfo {
+    /// The exit status, or the name of the signal that killed the child,
+    /// e.g. `SIGSEGV (core dumped)`.
+    pub fn reason(&self) -> String {
+        let Some(signal) = self.signal else {
+            return self.code.to_string();
+        };
+        let name = nix::sys::signal::Signal::try_from(signal)
+            .map(|s| s.as_str().to_string())
+            .unwrap_or_else(|_| format!("signal {}", signal));
+        if self.core_dumped {
+            format!("{} (core dumped)", name)
+        } else {
+            name
+        }
+    }
+
+    /// One line for people: how the child ended and what it used.
+    pub fn describe(&self) -> String {
+        let ended = match self.signal {
+            Some(_) => format!("killed by {}", self.reason()),
+            None => format!("exited with {}", self.code),
+        };
+        format!(
+            "{}; {:.2}s user, {:.2}s system, {} MiB max RSS",
+            ended,
+            self.user_us as f64 / 1e6,
+            self.system_us as f64 / 1e6,
+            self.max_rss_kib / 1024
+        )
+    }
+}
+
+/// Current time as a Unix timestamp in seconds.
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+pub fn read(session_dir: &Path) -> io::Result<SessionMeta> {
+    let data = std::fs::read(session_dir.join(META_FILENAME))?;
+    serde_json::from_slice(&data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Atomically replace the metadata file (write to a temporary file, then
+/// rename) so readers never observe a partially written document.
+pub fn write(session_dir: &Path, meta: &SessionMeta) -> io::Result<()> {
+    let data = serde_json::to_vec(meta).map_err(io::Error::other)?;
+    let tmp = session_dir.join(format!(".{}.tmp", META_FILENAME));
+    std::fs::write(&tmp, data)?;
+    std::fs::rename(&tmp, session_dir.join(META_FILENAME))
+}
+
+/// `true` if a process with `pid` exists (signal 0 probe).
+pub fn pid_alive(pid: i32) -> bool {
+    if pid <= 0 {
+        return false;
+    }
+    match nix::sys::signal::kill(nix::unistd::Pid::from_raw(pid), None) {
+        Ok(()) => true,
+        Err(nix::errno::Errno::EPERM) => true,
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exit_info_names_signals() {
+        let exited = ExitInfo {
+            code: 3,
+            user_us: 1_250_000,
+            system_us: 40_000,
+            max_rss_kib: 20_480,
+            ..ExitInfo::default()
+        };
+        assert_eq!(exited.reason(), "3");
+        assert_eq!(
+            exited.describe(),
+            "exited with 3; 1.25s user, 0.04s system, 20 MiB max RSS"
+        );
+        let killed = ExitInfo {
+            code: 139,
+            signal: Some(11),
+            core_dumped: true,
+            ..ExitInfo::default()
+        };
+        assert_eq!(killed.reason(), "SIGSEGV (core dumped)");
+        assert!(killed
+            .describe()
+            .starts_with("killed by SIGSEGV (core dumped);"));
+        let json = serde_json::to_value(exited).unwrap();
+        assert!(json.get("signal").is_none(), "{}", json);
+        assert_eq!(serde_json::from_value::<ExitInfo>(json).unwrap(), exited);
+    }
+
+    #[test]
+    fn activity_keeps_a_day_of_busy_minutes() {
+        let mut activity = Activity::default();
+        let t = 1_000 * 60;
+        activity.output(t, 100);
+        activity.output(t + 59, 50);
+        activity.input(t + 59, 2);
+        activity.output(t + 180, 600);
+        assert_eq!(
+            activity.histogram(t + 180, 5),
+            (vec![0, 150, 0, 0, 600], vec![0, 2, 0, 0, 0])
+        );
+        assert_eq!(sparkline(&activity.histogram(t + 180, 5).0), " ▂  █");
+        assert_eq!(
+            serde_json::to_value(&activity).unwrap(),
+            serde_json::json!([[1000, 150, 2], [1003, 600, 0]])
+        );
+
+        activity.input(t + ACTIVITY_MINUTES * 60, 1);
+        assert_eq!(
+            serde_json::to_value(&activity).unwrap(),
+            serde_json::json!([[1003, 600, 0], [2440, 0, 1]])
+        );
+        assert_eq!(activity.histogram(t, 3), (vec![0, 0, 0], vec![0, 0, 0]));
+    }
+
+    #[test]
+    fn write_then_read_roundtrip() {
+        let dir = std::env::temp_dir().join(format!("pterm-meta-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let meta = SessionMeta {
+            session: "dev".to_string(),
+            command: vec!["/bin/sh".to_string()],
+            pid: 10,
+            protocol_version: 2,
+            min_protocol_version: Some(1),
+            version: Some("0.1.0".to_string()),
+            child_pid: 11,
+            tty: Some(PathBuf::from("/dev/pts/3")),
+            created_at: 1,
+            clients: 2,
+            last_activity: 3,
+            suspended: true,
+            exit_code: None,
+            exit: None,
+            stats: SessionStats {
+                cpu_ms: 1200,
+                output_bytes: 4096,
+                input_bytes: 12,
+            },
+            recording: Some(PathBuf::from("/tmp/rec")),
+            expires_at: Some(4),
+            sandbox: Some("seccomp".to_string()),
+            nested_in: Some("outer".to_string()),
+            activity: Activity::default(),
+            exit_hook: Some(ExitHook {
+                command: vec!["notify-send".to_string()],
+                lines: 20,
+            }),
+        };
+        write(&dir, &meta).unwrap();
+        assert_eq!(read(&dir).unwrap(), meta);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,388 @@
+//! `pterm mux`: several sessions over one connection, for frontends (e.g. a
+//! GUI) that manage many terminals and would rather not keep a Unix socket
+//! open per session.
+//!
+//! The frontend speaks pterm frames on stdin/stdout. `OPEN_CHANNEL` connects
+//! a channel id of its choosing to a session; from then on `CHANNEL` frames
+//! carry that session's frames, wrapped with the channel id, in both
+//! directions, and the multiplexer relays them as they are. Everything
+//! between the frontend and the daemon (`HELLO`, attaching, replies) works
+//! as on a connection of its own. When a daemon closes its connection the
+//! frontend gets `CHANNEL_CLOSED`; `CLOSE_CHANNEL` closes one from the
+//! frontend's side. Frames for a session whose socket is full wait in the
+//! multiplexer until it has room.
+
+use crate::bridge::write_all_raw;
+use crate::paths::session_socket_path;
+use mio::net::UnixStream;
+use mio::unix::SourceFd;
+use mio::{Events, Interest, Poll, Token};
+use nix::libc;
+use pterm_proto as proto;
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+
+const TOKEN_STDIN: Token = Token(0);
+
+/// The session connection behind a channel.
+struct Channel {
+    stream: UnixStream,
+    decoder: proto::FrameDecoder,
+    /// Frames for the session the socket had no room for yet.
+    unsent: Vec<u8>,
+    /// Registered for `WRITABLE`, to send `unsent` once there is room.
+    waiting: bool,
+}
+
+struct Mux {
+    poll: Poll,
+    channels: HashMap<u32, Channel>,
+}
+
+/// Relay frames between stdin/stdout and sessions until stdin closes.
+pub fn run() -> io::Result<()> {
+    let stdin_fd = libc::STDIN_FILENO;
+    // Readiness is edge-triggered: stdin is read until it would block.
+    unsafe {
+        let flags = libc::fcntl(stdin_fd, libc::F_GETFL);
+        libc::fcntl(stdin_fd, libc::F_SETFL, flags | libc::O_NONBLOCK);
+    }
+    let poll = Poll::new()?;
+    poll.registry()
+        .register(&mut SourceFd(&stdin_fd), TOKEN_STDIN, Interest::READABLE)?;
+    let mut mux = Mux {
+        poll,
+        channels: HashMap::new(),
+    };
+
+    let mut events = Events::with_capacity(64);
+    let mut decoder = proto::FrameDecoder::new();
+    let mut buf = [0u8; 65536];
+    loop {
+        if let Err(e) = mux.poll.poll(&mut events, None) {
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(e);
+        }
+        let mut reply = Vec::new();
+        for event in events.iter() {
+            match event.token() {
+                TOKEN_STDIN => loop {
+                    let n = match nix::unistd::read(stdin_fd, &mut buf) {
+                        Ok(0) => {
+                            write_all_raw(libc::STDOUT_FILENO, &reply)?;
+                            return Ok(());
+                        }
+                        Ok(n) => n,
+                        Err(nix::errno::Errno::EAGAIN) => break,
+                        Err(nix::errno::Errno::EINTR) => continue,
+                        Err(e) => return Err(e.into()),
+                    };
+                    for frame in decoder.decode(&buf[..n]) {
+                        reply.extend_from_slice(&mux.frame(frame));
+                    }
+                    if let Some(oversized) = decoder.oversized() {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            oversized.to_string(),
+                        ));
+                    }
+                },
+                Token(token) => {
+                    let channel = token as u32 - 1;
+                    if event.is_writable() {
+                        reply.extend_from_slice(&mux.flush(channel));
+                    }
+                    reply.extend_from_slice(&mux.read_channel(channel, &mut buf))
+                }
+            }
+        }
+        // stdout may share stdin's (now nonblocking) file description.
+        write_all_raw(libc::STDOUT_FILENO, &reply)?;
+    }
+}
+
+impl Mux {
+    /// Handle a frame from the frontend; returns the frames to answer with.
+    fn frame(&mut self, frame: proto::Frame) -> Vec<u8> {
+        match frame.msg_type {
+            proto::client::OPEN_CHANNEL => {
+                let request: serde_json::Value =
+                    serde_json::from_slice(&frame.payload).unwrap_or_default();
+                let channel = request["channel"]
+                    .as_u64()
+                    .and_then(|n| u32::try_from(n).ok());
+                let (Some(channel), Some(session)) = (channel, request["session"].as_str()) else {
+                    return reply(None, "OPEN_CHANNEL needs a channel id and a session");
+                };
+                match self.open(channel, session) {
+                    Ok(()) => proto::encode(
+                        proto::server::REPLY,
+                        serde_json::json!({ "channel": channel })
+                            .to_string()
+                            .as_bytes(),
+                    ),
+                    Err(e) => reply(Some(channel), &e),
+                }
+            }
+            proto::client::CLOSE_CHANNEL => {
+                let request: serde_json::Value =
+                    serde_json::from_slice(&frame.payload).unwrap_or_default();
+                if let Some(channel) = request["channel"].as_u64() {
+                    self.close(channel as u32);
+                }
+                Vec::new()
+            }
+            proto::client::CHANNEL => {
+                let (channel, inner) = match proto::parse_channel(&frame.payload) {
+                    Ok(parsed) => parsed,
+                    Err(e) => return reply(None, &e.to_string()),
+                };
+                if matches!(
+                    inner.msg_type,
+                    proto::client::PTY_INPUT_FD | proto::client::UPGRADE
+                ) {
+                    return reply(
+                        Some(channel),
+                        "frames passing file descriptors cannot be carried over a channel",
+                    );
+                }
+                let Some(open) = self.channels.get_mut(&channel) else {
+                    return reply(Some(channel), &format!("channel {} is not open", channel));
+                };
+                open.unsent
+                    .extend_from_slice(&proto::encode(inner.msg_type, &inner.payload));
+                self.flush(channel)
+            }
+            msg_type => reply(
+                None,
+                &format!("message type 0x{:02x} needs a channel", msg_type),
+            ),
+        }
+    }
+
+    fn open(&mut self, channel: u32, session: &str) -> Result<(), String> {
+        if self.channels.contains_key(&channel) {
+            return Err(format!("channel {} is already open", channel));
+        }
+        let stream = std::os::unix::net::UnixStream::connect(session_socket_path(session))
+            .map_err(|e| format!("session '{}': {}", session, e))?;
+        self.add(channel, stream).map_err(|e| e.to_string())
+    }
+
+    fn add(&mut self, channel: u32, stream: std::os::unix::net::UnixStream) -> io::Result<()> {
+        stream.set_nonblocking(true)?;
+        let mut stream = UnixStream::from_std(stream);
+        self.poll
+            .registry()
+            .register(&mut stream, token(channel), Interest::READABLE)?;
+        self.channels.insert(
+            channel,
+            Channel {
+                stream,
+                decoder: proto::FrameDecoder::new(),
+                unsent: Vec::new(),
+                waiting: false,
+            },
+        );
+        Ok(())
+    }
+
+    /// Write what `channel` has not sent yet, as far as the socket takes
+    /// it, and wait for room for the rest. A failed write closes the
+    /// channel; returns `CHANNEL_CLOSED` then.
+    fn flush(&mut self, channel: u32) -> Vec<u8> {
+        let Some(open) = self.channels.get_mut(&channel) else {
+            return Vec::new();
+        };
+        let mut written = 0;
+        let result = loop {
+            if written == open.unsent.len() {
+                break Ok(());
+            }
+            match open.stream.write(&open.unsent[written..]) {
+                Ok(n) => written += n,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break Ok(()),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => break Err(e),
+            }
+        };
+        open.unsent.drain(..written);
+        let waiting = !open.unsent.is_empty();
+        let result = result.and_then(|()| {
+            if waiting == open.waiting {
+                return Ok(());
+            }
+            open.waiting = waiting;
+            let interest = if waiting {
+                Interest::READABLE | Interest::WRITABLE
+            } else {
+                Interest::READABLE
+            };
+            self.poll
+                .registry()
+                .reregister(&mut open.stream, token(channel), interest)
+        });
+        match result {
+            Ok(()) => Vec::new(),
+            Err(_) => {
+                self.close(channel);
+                closed(channel)
+            }
+        }
+    }
+
+    fn close(&mut self, channel: u32) {
+        if let Some(mut closed) = self.channels.remove(&channel) {
+            let _ = self.poll.registry().deregister(&mut closed.stream);
+        }
+    }
+
+    /// Wrap what the session behind `channel` sent in `CHANNEL` frames.
+    fn read_channel(&mut self, channel: u32, buf: &mut [u8]) -> Vec<u8> {
+        let Some(open) = self.channels.get_mut(&channel) else {
+            return Vec::new();
+        };
+        let mut eof = false;
+        loop {
+            match open.stream.read(buf) {
+                Ok(0) => {
+                    eof = true;
+                    break;
+                }
+                Ok(n) => open.decoder.feed(&buf[..n]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(_) => {
+                    eof = true;
+                    break;
+                }
+            }
+        }
+        let mut out = Vec::new();
+        while let Some(frame) = open.decoder.next_frame() {
+            out.extend_from_slice(&proto::encode(
+                proto::server::CHANNEL,
+                &proto::encode_channel(channel, frame.msg_type, &frame.payload),
+            ));
+        }
+        if eof {
+            self.close(channel);
+            out.extend_from_slice(&closed(channel));
+        }
+        out
+    }
+}
+
+/// Poll token of a channel, clear of `TOKEN_STDIN`.
+fn token(channel: u32) -> Token {
+    Token(channel as usize + 1)
+}
+
+fn reply(channel: Option<u32>, error: &str) -> Vec<u8> {
+    let mut reply = serde_json::json!({ "error": error });
+    if let Some(channel) = channel {
+        reply["channel"] = channel.into();
+    }
+    proto::encode(proto::server::REPLY, reply.to_string().as_bytes())
+}
+
+fn closed(channel: u32) -> Vec<u8> {
+    proto::encode(
+        proto::server::CHANNEL_CLOSED,
+        serde_json::json!({ "channel": channel })
+            .to_string()
+            .as_bytes(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unknown_channels_and_bare_frames_are_refused() {
+        let mut mux = Mux {
+            poll: Poll::new().unwrap(),
+            channels: HashMap::new(),
+        };
+        let mut answer = |msg_type, payload: &[u8]| {
+            let out = mux.frame(proto::Frame {
+                msg_type,
+                payload: payload.to_vec(),
+            });
+            let frames = proto::decode_frames(&mut out.clone());
+            assert_eq!(frames.len(), 1);
+            assert_eq!(frames[0].msg_type, proto::server::REPLY);
+            serde_json::from_slice::<serde_json::Value>(&frames[0].payload).unwrap()
+        };
+
+        let input = proto::encode_channel(3, proto::client::INPUT, b"ls\r");
+        let reply = answer(proto::client::CHANNEL, &input);
+        assert_eq!(reply["channel"], 3);
+        assert_eq!(reply["error"], "channel 3 is not open");
+
+        let fd = proto::encode_channel(3, proto::client::PTY_INPUT_FD, &[]);
+        assert!(answer(proto::client::CHANNEL, &fd)["error"]
+            .as_str()
+            .unwrap()
+            .contains("file descriptors"));
+        assert_eq!(
+            answer(proto::client::INPUT, b"ls\r")["error"],
+            "message type 0x01 needs a channel"
+        );
+
+        let missing = format!("pterm-mux-test-missing-{}", std::process::id());
+        let open = serde_json::json!({ "channel": 1, "session": missing });
+        let reply = answer(proto::client::OPEN_CHANNEL, open.to_string().as_bytes());
+        assert_eq!(reply["channel"], 1);
+        assert!(reply["error"].as_str().unwrap().starts_with("session '"));
+    }
+
+    #[test]
+    fn frames_wait_for_a_full_session_socket() {
+        let mut mux = Mux {
+            poll: Poll::new().unwrap(),
+            channels: HashMap::new(),
+        };
+        let (ours, mut session) = std::os::unix::net::UnixStream::pair().unwrap();
+        mux.add(1, ours).unwrap();
+
+        // Far more than the socket buffer, while the session reads nothing.
+        let chunk = vec![b'x'; 64 << 10];
+        for _ in 0..64 {
+            let input = proto::encode_channel(1, proto::client::INPUT, &chunk);
+            let out = mux.frame(proto::Frame {
+                msg_type: proto::client::CHANNEL,
+                payload: input,
+            });
+            assert!(
+                out.is_empty(),
+                "{:?}",
+                proto::decode_frames(&mut out.clone())
+            );
+        }
+        assert!(!mux.channels[&1].unsent.is_empty());
+
+        let expected = 64 * proto::encode(proto::client::INPUT, &chunk).len();
+        let reader = std::thread::spawn(move || {
+            let mut received = vec![0u8; expected];
+            session.read_exact(&mut received).map(|()| received)
+        });
+        let mut events = Events::with_capacity(4);
+        while !mux.channels[&1].unsent.is_empty() {
+            mux.poll
+                .poll(&mut events, Some(std::time::Duration::from_secs(5)))
+                .unwrap();
+            assert!(!events.is_empty(), "the socket never became writable");
+            for event in events.iter() {
+                assert_eq!(event.token(), token(1));
+                assert!(mux.flush(1).is_empty());
+            }
+        }
+        let frames = proto::decode_frames(&mut reader.join().unwrap().unwrap());
+        assert_eq!(frames.len(), 64);
+        assert!(frames.iter().all(|frame| frame.payload == chunk));
+    }
+}
@@ -0,0 +1,273 @@
+//! Session options that can be changed at runtime with `pterm set`.
+
+use crate::session::Terminal;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SessionOptions {
+    /// Lines of scrollback kept by the daemon's terminal state.
+    pub scrollback: usize,
+    /// Seconds without attached clients or activity after which the session
+    /// is terminated. `0` disables.
+    pub idle_timeout: u64,
+    /// Seconds before an idle timeout ends the session at which a warning is
+    /// written into it. `0` disables.
+    pub idle_warning: u64,
+    /// Keep the daemon around after the child exits, until killed.
+    pub hold: bool,
+    /// Strip reply-generating terminal queries from output sent to clients.
+    pub replay_filter: bool,
+    /// Maximum PTY output rate in bytes per second. `0` disables.
+    pub output_throttle: u64,
+    /// Resume a suspended session when a client attaches or sends input.
+    pub auto_resume: bool,
+    /// `|`-separated patterns of command lines that need confirmation
+    /// before their Enter reaches the session (see `guard.rs`). Empty
+    /// disables.
+    pub guard: String,
+    /// Whether PTY output goes to clients as soon as it is read, or is
+    /// batched to save frames and wakeups.
+    pub latency: Latency,
+    /// What happens to output produced while no client is attached.
+    pub detached: Detached,
+}
+
+/// Values of the `latency` option.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Latency {
+    /// Flush each PTY read right away, for interactive use.
+    #[default]
+    Low,
+    /// Coalesce PTY reads for a short interval before flushing, for bulk
+    /// output such as streamed logs.
+    Throughput,
+}
+
+/// Values of the `detached` option.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Detached {
+    /// Keep it in the scrollback only.
+    #[default]
+    Scrollback,
+    /// Also append it to `detached.log` in the session directory.
+    Log,
+    /// Stop the pty's output (`TCOOFF`), so the child blocks on its next
+    /// write until a client attaches.
+    Pause,
+}
+
+impl Default for SessionOptions {
+    fn default() -> Self {
+        Self {
+            scrollback: Terminal::SCROLLBACK_LINES,
+            idle_timeout: 0,
+            idle_warning: 300,
+            hold: false,
+            replay_filter: true,
+            output_throttle: 0,
+            auto_resume: false,
+            guard: String::new(),
+            latency: Latency::Low,
+            detached: Detached::Scrollback,
+        }
+    }
+}
+
+impl SessionOptions {
+    pub const NAMES: [&str; 10] = [
+        "scrollback",
+        "idle-timeout",
+        "idle-warning",
+        "hold",
+        "replay-filter",
+        "output-throttle",
+        "auto-resume",
+        "guard",
+        "latency",
+        "detached",
+    ];
+
+    /// Set option `name` from its string form.
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), String> {
+        match name {
+            "scrollback" => self.scrollback = parse_number(name, value)? as usize,
+            "idle-timeout" => self.idle_timeout = parse_number(name, value)?,
+            "idle-warning" => self.idle_warning = parse_number(name, value)?,
+            "hold" => self.hold = parse_bool(name, value)?,
+            "replay-filter" => self.replay_filter = parse_bool(name, value)?,
+            "output-throttle" => self.output_throttle = parse_number(name, value)?,
+            "auto-resume" => self.auto_resume = parse_bool(name, value)?,
+            "guard" if value == "off" => self.guard.clear(),
+            "guard" => self.guard = value.to_string(),
+            "latency" => {
+                self.latency = match value {
+                    "low" => Latency::Low,
+                    "throughput" => Latency::Throughput,
+                    _ => return Err(format!("latency expects low/throughput, got '{}'", value)),
+                }
+            }
+            "detached" => {
+                self.detached = match value {
+                    "scrollback" => Detached::Scrollback,
+                    "log" => Detached::Log,
+                    "pause" => Detached::Pause,
+                    _ => {
+                        return Err(format!(
+                            "detached expects scrollback/log/pause, got '{}'",
+                            value
+                        ))
+                    }
+                }
+            }
+            _ => {
+                return Err(format!(
+                    "unknown option '{}' (expected one of: {})",
+                    name,
+                    Self::NAMES.join(", ")
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    /// String form of option `name`, as accepted by [`SessionOptions::set`].
+    pub fn get(&self, name: &str) -> Option<String> {
+        let on_off = |b: bool| if b { "on" } else { "off" }.to_string();
+        Some(match name {
+            "scrollback" => self.scrollback.to_string(),
+            "idle-timeout" => self.idle_timeout.to_string(),
+            "idle-warning" => self.idle_warning.to_string(),
+            "hold" => on_off(self.hold),
+            "replay-filter" => on_off(self.replay_filter),
+            "output-throttle" => self.output_throttle.to_string(),
+            "auto-resume" => on_off(self.auto_resume),
+            "guard" if self.guard.is_empty() => "off".to_string(),
+            "guard" => self.guard.clone(),
+            "latency" => match self.latency {
+                Latency::Low => "low",
+                Latency::Throughput => "throughput",
+            }
+            .to_string(),
+            "detached" => match self.detached {
+                Detached::Scrollback => "scrollback",
+                Detached::Log => "log",
+                Detached::Pause => "pause",
+            }
+            .to_string(),
+            _ => return None,
+        })
+    }
+
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        (self.idle_timeout > 0).then(|| Duration::from_secs(self.idle_timeout))
+    }
+
+    /// How long the session has been idle when the expiry warning is due,
+    /// if there is an idle timeout and a warning.
+    pub fn idle_warning_after(&self) -> Option<Duration> {
+        let timeout = self.idle_timeout().filter(|_| self.idle_warning > 0)?;
+        Some(timeout.saturating_sub(Duration::from_secs(self.idle_warning)))
+    }
+}
+
+fn parse_number(name: &str, value: &str) -> Result<u64, String> {
+    value
+        .parse()
+        .map_err(|_| format!("{} expects a non-negative integer, got '{}'", name, value))
+}
+
+fn parse_bool(name: &str, value: &str) -> Result<bool, String> {
+    match value {
+        "on" | "true" | "yes" | "1" => Ok(true),
+        "off" | "false" | "no" | "0" => Ok(false),
+        _ => Err(format!("{} expects on/off, got '{}'", name, value)),
+    }
+}
+
+/// Token bucket limiting PTY reads to `rate` bytes per second, with up to
+/// one second of burst.
+#[derive(Debug)]
+pub struct Throttle {
+    rate: u64,
+    available: f64,
+    last_refill: std::time::Instant,
+}
+
+impl Throttle {
+    pub fn new(rate: u64) -> Self {
+        Self {
+            rate,
+            available: rate as f64,
+            last_refill: std::time::Instant::now(),
+        }
+    }
+
+    /// Bytes that may be read now.
+    pub fn budget(&mut self) -> usize {
+        let now = std::time::Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.last_refill = now;
+        self.available = (self.available + elapsed * self.rate as f64).min(self.rate as f64);
+        self.available as usize
+    }
+
+    pub fn consume(&mut self, bytes: usize) {
+        self.available -= bytes as f64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_and_get_roundtrip() {
+        let mut options = SessionOptions::default();
+        options.set("scrollback", "500").unwrap();
+        options.set("hold", "on").unwrap();
+        options.set("replay-filter", "off").unwrap();
+        options.set("idle-timeout", "3600").unwrap();
+        options.set("auto-resume", "yes").unwrap();
+        assert_eq!(options.get("scrollback").as_deref(), Some("500"));
+        assert_eq!(options.get("hold").as_deref(), Some("on"));
+        assert_eq!(options.get("replay-filter").as_deref(), Some("off"));
+        assert_eq!(options.idle_timeout(), Some(Duration::from_secs(3600)));
+        assert_eq!(
+            options.idle_warning_after(),
+            Some(Duration::from_secs(3300))
+        );
+        options.set("idle-warning", "7200").unwrap();
+        assert_eq!(options.idle_warning_after(), Some(Duration::ZERO));
+        options.set("idle-warning", "0").unwrap();
+        assert_eq!(options.idle_warning_after(), None);
+        assert_eq!(options.get("auto-resume").as_deref(), Some("on"));
+        options.set("guard", "rm -rf|DROP TABLE").unwrap();
+        assert_eq!(options.get("guard").as_deref(), Some("rm -rf|DROP TABLE"));
+        options.set("guard", "off").unwrap();
+        assert_eq!(options.get("guard").as_deref(), Some("off"));
+        assert_eq!(options.get("latency").as_deref(), Some("low"));
+        options.set("latency", "throughput").unwrap();
+        assert_eq!(options.latency, Latency::Throughput);
+        assert!(options.set("latency", "high").is_err());
+        assert_eq!(options.get("detached").as_deref(), Some("scrollback"));
+        options.set("detached", "pause").unwrap();
+        assert_eq!(options.detached, Detached::Pause);
+        assert!(options.set("detached", "drop").is_err());
+
+        assert!(options.set("hold", "maybe").is_err());
+        assert!(options.set("scrollback", "-1").is_err());
+        assert!(options.set("colour", "red").is_err());
+    }
+
+    #[test]
+    fn throttle_caps_budget_at_rate() {
+        let mut throttle = Throttle::new(1000);
+        assert_eq!(throttle.budget(), 1000);
+        throttle.consume(1000);
+        assert!(throttle.budget() < 100);
+    }
+}
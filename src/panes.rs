@@ -0,0 +1,145 @@
+//! `pterm panes`: open `pterm attach` for each matching session in a new tab
+//! or split of WezTerm or kitty, using their remote-control CLIs
+//! (`wezterm cli`, `kitty @`).
+
+use crate::list::{self, SessionState};
+use std::io;
+use std::process::{Command, Stdio};
+
+/// Terminal emulator to drive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Terminal {
+    WezTerm,
+    Kitty,
+}
+
+/// Where each attach command opens.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Placement {
+    #[default]
+    Tab,
+    Split,
+    Window,
+}
+
+/// Remote-control command that opens `pterm attach <session>`.
+pub fn launch_command(
+    terminal: Terminal,
+    placement: Placement,
+    pterm: &str,
+    session: &str,
+) -> Vec<String> {
+    let mut command: Vec<String> = match (terminal, placement) {
+        (Terminal::WezTerm, Placement::Tab) => vec!["wezterm", "cli", "spawn"],
+        (Terminal::WezTerm, Placement::Split) => vec!["wezterm", "cli", "split-pane"],
+        (Terminal::WezTerm, Placement::Window) => {
+            vec!["wezterm", "cli", "spawn", "--new-window"]
+        }
+        (Terminal::Kitty, placement) => vec![
+            "kitty",
+            "@",
+            "launch",
+            match placement {
+                Placement::Tab => "--type=tab",
+                Placement::Split => "--type=window",
+                Placement::Window => "--type=os-window",
+            },
+            "--title",
+            session,
+            "--tab-title",
+            session,
+        ],
+    }
+    .into_iter()
+    .map(String::from)
+    .collect();
+    if terminal == Terminal::WezTerm {
+        command.push("--".to_string());
+    }
+    command.push(pterm.to_string());
+    command.extend(crate::paths::socket_dir_args());
+    command.extend(["attach".to_string(), session.to_string()]);
+    command
+}
+
+/// Live sessions whose name matches `glob`.
+pub fn matching_sessions(glob: &str) -> io::Result<Vec<String>> {
+    Ok(list::collect("")?
+        .into_iter()
+        .filter(|e| !matches!(e.state, SessionState::Dead | SessionState::Unknown))
+        .filter(|e| list::glob_match(glob, &e.name))
+        .map(|e| e.name)
+        .collect())
+}
+
+/// Run the launch command for `session`. WezTerm prints the new pane id,
+/// which is used to title the tab after the session.
+pub fn open(
+    terminal: Terminal,
+    placement: Placement,
+    pterm: &str,
+    session: &str,
+) -> io::Result<()> {
+    let command = launch_command(terminal, placement, pterm, session);
+    let output = Command::new(&command[0])
+        .args(&command[1..])
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", command[0], e)))?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "{} exited with {}",
+            command[..3].join(" "),
+            output.status
+        )));
+    }
+    if terminal == Terminal::WezTerm && placement != Placement::Split {
+        let pane_id = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        if !pane_id.is_empty() {
+            let _ = Command::new("wezterm")
+                .args(["cli", "set-tab-title", "--pane-id", &pane_id, session])
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .status();
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn launch_commands() {
+        assert_eq!(
+            launch_command(Terminal::WezTerm, Placement::Split, "pterm", "dev"),
+            [
+                "wezterm",
+                "cli",
+                "split-pane",
+                "--",
+                "pterm",
+                "attach",
+                "dev"
+            ]
+        );
+        assert_eq!(
+            launch_command(Terminal::Kitty, Placement::Tab, "/bin/pterm", "a/b"),
+            [
+                "kitty",
+                "@",
+                "launch",
+                "--type=tab",
+                "--title",
+                "a/b",
+                "--tab-title",
+                "a/b",
+                "/bin/pterm",
+                "attach",
+                "a/b"
+            ]
+        );
+    }
+}
@@ -0,0 +1,220 @@
+//! Shared paste buffer: `pterm copy` captures text from one session and
+//! `pterm paste-buffer` types it into another, without going through the
+//! system clipboard.
+//!
+//! The buffer is a single file in the socket root, which is private to the
+//! user (mode 0700) and lives as long as the sessions do. Selection happens
+//! here, over the plain-text lines a daemon returns for `client::CAPTURE`;
+//! the daemon itself applies bracketed paste when pasting.
+//!
+//! `pterm copy-output` puts a selection on the system clipboard instead,
+//! with an OSC 52 sequence written to the terminal the CLI runs in.
+
+use crate::paths::{ensure_socket_dir, socket_dir};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+
+/// Paste buffer file name within the socket root.
+pub const PASTE_BUFFER_FILENAME: &str = ".paste-buffer";
+
+/// What `pterm copy` takes from the captured text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Selection {
+    /// The visible screen (default).
+    Screen,
+    /// Scrollback and screen.
+    All,
+    /// The last N lines up to the cursor.
+    Lines(usize),
+    /// Output of the last command: the lines between the previous prompt
+    /// and the one the cursor is on.
+    LastCommand,
+}
+
+/// Text captured from a session (the `client::CAPTURE` reply).
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Capture {
+    pub lines: Vec<String>,
+    /// Index of the first line of the visible screen.
+    pub screen: usize,
+    /// Index of the line holding the cursor.
+    pub cursor: usize,
+    /// Indexes of the lines where the shell started a prompt (OSC 133 `A`),
+    /// in order.
+    pub prompts: Vec<usize>,
+}
+
+impl Capture {
+    pub fn from_json(value: &serde_json::Value) -> Option<Self> {
+        let lines = value
+            .get("lines")?
+            .as_array()?
+            .iter()
+            .map(|line| line.as_str().map(str::to_string))
+            .collect::<Option<Vec<_>>>()?;
+        let index = |key| value.get(key)?.as_u64().map(|n| n as usize);
+        let prompts = match value.get("prompts") {
+            Some(prompts) => prompts
+                .as_array()?
+                .iter()
+                .map(|n| n.as_u64().map(|n| n as usize))
+                .collect::<Option<Vec<_>>>()?
+                .into_iter()
+                .filter(|&n| n < lines.len())
+                .collect(),
+            None => Vec::new(),
+        };
+        Some(Self {
+            screen: index("screen")?.min(lines.len()),
+            cursor: index("cursor")?.min(lines.len().saturating_sub(1)),
+            lines,
+            prompts,
+        })
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "lines": self.lines,
+            "screen": self.screen,
+            "cursor": self.cursor,
+            "prompts": self.prompts,
+        })
+    }
+
+    /// The selected text, lines joined with `\n` and trailing blank lines
+    /// dropped.
+    pub fn select(&self, selection: Selection) -> Result<String, String> {
+        let upto_cursor = &self.lines[..(self.cursor + 1).min(self.lines.len())];
+        let lines = match selection {
+            Selection::Screen => &self.lines[self.screen..],
+            Selection::All => &self.lines[..],
+            Selection::Lines(n) => &upto_cursor[upto_cursor.len().saturating_sub(n)..],
+            Selection::LastCommand => self.last_command()?,
+        };
+        let end = lines
+            .iter()
+            .rposition(|line| !line.trim().is_empty())
+            .map_or(0, |i| i + 1);
+        Ok(lines[..end].join("\n"))
+    }
+
+    /// The prompt is whatever the cursor line shows (the shell is waiting at
+    /// it); the previous line starting with the same text is where the last
+    /// command was typed.
+    fn last_command(&self) -> Result<&[String], String> {
+        let prompt = self
+            .lines
+            .get(self.cursor)
+            .map(|line| line.trim_end())
+            .filter(|prompt| !prompt.is_empty())
+            .ok_or("no prompt at the cursor")?;
+        let start = self.lines[..self.cursor]
+            .iter()
+            .rposition(|line| line.starts_with(prompt))
+            .ok_or("no previous prompt found")?;
+        Ok(&self.lines[start + 1..self.cursor])
+    }
+}
+
+pub fn path() -> PathBuf {
+    socket_dir().join(PASTE_BUFFER_FILENAME)
+}
+
+/// Replace the paste buffer. Written to a temporary file (mode 0600) and
+/// renamed, so a concurrent paste never sees half of it.
+pub fn store(text: &str) -> io::Result<()> {
+    ensure_socket_dir()?;
+    let path = path();
+    let tmp = path.with_extension("tmp");
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp)?;
+    file.write_all(text.as_bytes())?;
+    std::fs::rename(&tmp, &path)
+}
+
+/// OSC 52 setting the terminal's clipboard to `text`.
+pub fn clipboard(text: &str) -> Vec<u8> {
+    format!("\x1b]52;c;{}\x07", crate::bridge::base64(text.as_bytes())).into_bytes()
+}
+
+/// Set the clipboard of the terminal this process runs in (`/dev/tty`,
+/// so it works with stdout redirected) to `text`.
+pub fn set_clipboard(text: &str) -> io::Result<()> {
+    let mut tty = std::fs::OpenOptions::new()
+        .write(true)
+        .open("/dev/tty")
+        .map_err(|e| io::Error::new(e.kind(), format!("no terminal to copy through: {}", e)))?;
+    tty.write_all(&clipboard(text))?;
+    tty.flush()
+}
+
+/// Contents of the paste buffer; a missing buffer is empty.
+pub fn load() -> io::Result<String> {
+    match std::fs::read_to_string(path()) {
+        Ok(text) => Ok(text),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(String::new()),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn capture(lines: &[&str], screen: usize, cursor: usize) -> Capture {
+        Capture {
+            lines: lines.iter().map(|l| l.to_string()).collect(),
+            screen,
+            cursor,
+            prompts: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn selections() {
+        let capture = capture(
+            &["$ ls", "a", "$ make", "cc main.c", "done", "$", "", ""],
+            2,
+            5,
+        );
+        assert_eq!(
+            capture.select(Selection::Screen).unwrap(),
+            "$ make\ncc main.c\ndone\n$"
+        );
+        assert_eq!(capture.select(Selection::All).unwrap().lines().count(), 6);
+        assert_eq!(capture.select(Selection::Lines(2)).unwrap(), "done\n$");
+        assert_eq!(
+            capture.select(Selection::LastCommand).unwrap(),
+            "cc main.c\ndone"
+        );
+
+        let no_prompt = Capture {
+            cursor: 6,
+            ..capture.clone()
+        };
+        assert_eq!(
+            no_prompt.select(Selection::LastCommand),
+            Err("no prompt at the cursor".to_string())
+        );
+    }
+
+    #[test]
+    fn clipboard_is_osc52() {
+        assert_eq!(clipboard("hi\n"), b"\x1b]52;c;aGkK\x07");
+    }
+
+    #[test]
+    fn capture_from_reply() {
+        let reply = serde_json::json!({ "lines": ["x", "y"], "screen": 1, "cursor": 9 });
+        assert_eq!(Capture::from_json(&reply), Some(capture(&["x", "y"], 1, 1)));
+        assert_eq!(
+            Capture::from_json(&serde_json::json!({ "lines": [1] })),
+            None
+        );
+    }
+}
@@ -1,11 +1,42 @@
+use crate::config::{Config, SocketDir};
+use serde::{Deserialize, Serialize};
 use std::io;
-use std::os::unix::fs::FileTypeExt;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, MetadataExt};
 use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
 
 /// Socket file name within a session directory.
 pub const SOCKET_FILENAME: &str = "socket";
 
+/// Environment variable naming the session a command runs in, set for the
+/// session's command.
+pub const SESSION_ENV: &str = "PTERM_SESSION";
+/// Environment variable holding the absolute socket path of the session a
+/// command runs in.
+pub const SOCKET_ENV: &str = "PTERM_SOCKET";
+
+/// Socket root given with `--socket-dir`, which wins over the environment.
+static SOCKET_DIR_OVERRIDE: OnceLock<PathBuf> = OnceLock::new();
+
+/// Use `dir` as the socket root for the rest of this process. Only the
+/// first call has an effect.
+pub fn override_socket_dir(dir: PathBuf) {
+    let _ = SOCKET_DIR_OVERRIDE.set(dir);
+}
+
+/// Arguments that carry a `--socket-dir` override on to another pterm
+/// invocation; empty without one.
+pub fn socket_dir_args() -> Vec<String> {
+    match SOCKET_DIR_OVERRIDE.get() {
+        Some(dir) => vec!["--socket-dir".to_string(), dir.display().to_string()],
+        None => Vec::new(),
+    }
+}
+
 pub fn socket_dir() -> PathBuf {
+    if let Some(dir) = SOCKET_DIR_OVERRIDE.get() {
+        return dir.clone();
+    }
     if let Ok(dir) = std::env::var("PTERM_SOCKET_DIR") {
         return PathBuf::from(dir);
     }
@@ -16,16 +47,193 @@ pub fn socket_dir() -> PathBuf {
     PathBuf::from(format!("/tmp/pterm-{}", uid))
 }
 
+/// Check that the socket root `dir` is safe to create or connect to
+/// sockets in: a directory owned by the effective user that neither group
+/// nor others can write to. A missing directory passes (nothing can be
+/// reached through it yet).
+pub fn check_socket_dir(dir: &Path) -> io::Result<()> {
+    let meta = match std::fs::metadata(dir) {
+        Ok(meta) => meta,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    let euid = nix::unistd::geteuid().as_raw();
+    let problem = if !meta.is_dir() {
+        "is not a directory".to_string()
+    } else if meta.uid() != euid {
+        format!("is owned by uid {}, not {}", meta.uid(), euid)
+    } else if meta.mode() & 0o022 != 0 {
+        format!(
+            "is writable by group or others (mode {:o})",
+            meta.mode() & 0o777
+        )
+    } else {
+        return Ok(());
+    };
+    Err(io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        format!("socket directory '{}' {}", dir.display(), problem),
+    ))
+}
+
+/// Create the socket root (mode 0700) if it does not exist, then check it.
+pub fn ensure_socket_dir() -> io::Result<()> {
+    let dir = socket_dir();
+    if !dir.exists() {
+        std::fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(&dir)?;
+    }
+    check_socket_dir(&dir)
+}
+
+/// Where a daemon's socket belongs: the socket root it was created in and
+/// its session directory there.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SocketHome {
+    pub root: PathBuf,
+    pub dir: PathBuf,
+}
+
+/// Whether `path` lies inside `$XDG_RUNTIME_DIR`, which systemd may empty
+/// or unmount on logout.
+pub fn in_runtime_dir(path: &Path) -> bool {
+    std::env::var_os("XDG_RUNTIME_DIR")
+        .filter(|dir| !dir.is_empty())
+        .is_some_and(|dir| path.starts_with(dir))
+}
+
+/// Further socket roots from `socket_dirs` in the config file, in search
+/// order. A config file that fails to load adds none (the commands that
+/// read it report the error).
+pub fn extra_socket_dirs() -> &'static [SocketDir] {
+    static DIRS: OnceLock<Vec<SocketDir>> = OnceLock::new();
+    DIRS.get_or_init(|| Config::load().map(|c| c.socket_dirs).unwrap_or_default())
+}
+
+/// Split `shared:oncall` into the extra socket root named `shared` and the
+/// session name within it. `None` for a name without a configured prefix.
+fn split_dir_prefix(session_name: &str) -> Option<(&'static Path, &str)> {
+    let (prefix, name) = session_name.split_once(':')?;
+    let root = extra_socket_dirs().iter().find(|d| d.name == prefix)?;
+    Some((&root.path, name))
+}
+
 /// Resolve the socket path for a session name.
 /// Session name may contain `/` for hierarchical sessions (e.g. "parent/child").
-/// Returns: `<socket_dir>/<session_name>/socket`
+/// Returns: `<session_dir>/socket`
 pub fn session_socket_path(session_name: &str) -> PathBuf {
-    socket_dir().join(session_name).join(SOCKET_FILENAME)
+    session_dir(session_name).join(SOCKET_FILENAME)
 }
 
-/// Resolve the session directory for a session name.
+/// Resolve the session directory for a session name: `<dir>:<name>` lives
+/// in the extra socket root `<dir>`; a plain name in the socket root, or
+/// else in the first extra root that has it.
 pub fn session_dir(session_name: &str) -> PathBuf {
-    socket_dir().join(session_name)
+    let dir = new_session_dir(session_name);
+    if split_dir_prefix(session_name).is_some() || dir.join(SOCKET_FILENAME).exists() {
+        return dir;
+    }
+    extra_socket_dirs()
+        .iter()
+        .map(|root| root.path.join(session_name))
+        .find(|extra| extra.join(SOCKET_FILENAME).exists())
+        .unwrap_or(dir)
+}
+
+/// The session this process runs inside, from the environment the
+/// session's command got: its name and socket.
+pub fn enclosing_session() -> Option<(String, PathBuf)> {
+    let name = std::env::var(SESSION_ENV).ok().filter(|n| !n.is_empty())?;
+    let socket = std::env::var_os(SOCKET_ENV).filter(|s| !s.is_empty())?;
+    Some((name, PathBuf::from(socket)))
+}
+
+/// Whether `socket` belongs to the session this process runs inside, which
+/// attaching to would feed the session's output back into itself.
+pub fn is_enclosing_session(socket: &Path) -> bool {
+    let Some((_, enclosing)) = enclosing_session() else {
+        return false;
+    };
+    match (enclosing.canonicalize(), socket.canonicalize()) {
+        (Ok(enclosing), Ok(socket)) => enclosing == socket,
+        _ => enclosing == socket,
+    }
+}
+
+/// Socket root holding [`new_session_dir`].
+pub fn new_session_root(session_name: &str) -> PathBuf {
+    match split_dir_prefix(session_name) {
+        Some((root, _)) => root.to_path_buf(),
+        None => socket_dir(),
+    }
+}
+
+/// Directory for a session `pterm new` creates: like [`session_dir`], but a
+/// plain name always goes to the socket root.
+pub fn new_session_dir(session_name: &str) -> PathBuf {
+    match split_dir_prefix(session_name) {
+        Some((root, name)) => root.join(name),
+        None => socket_dir().join(session_name),
+    }
+}
+
+/// The socket root and the extra ones, with the prefix naming sessions in
+/// each (`None` for the socket root).
+pub fn socket_roots() -> Vec<(Option<&'static str>, PathBuf)> {
+    let extra = extra_socket_dirs()
+        .iter()
+        .map(|d| (Some(d.name.as_str()), d.path.clone()));
+    std::iter::once((None, socket_dir())).chain(extra).collect()
+}
+
+/// Remove the socket a daemon from before session directories created at
+/// `sess_dir` itself (`<root>/<name>` instead of `<root>/<name>/socket`),
+/// so the session directory can be created there. Returns whether there
+/// was one; fails if something else is in the way.
+pub fn remove_legacy_socket(sess_dir: &Path) -> io::Result<bool> {
+    let meta = match std::fs::symlink_metadata(sess_dir) {
+        Ok(meta) if !meta.is_dir() => meta,
+        Ok(_) => return Ok(false),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e),
+    };
+    if !meta.file_type().is_socket() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("'{}' exists and is not a directory", sess_dir.display()),
+        ));
+    }
+    std::fs::remove_file(sess_dir)?;
+    Ok(true)
+}
+
+/// Remove a session directory, which shuts down its daemon and those of all
+/// child sessions, then any parent directories left empty below the socket
+/// root.
+pub fn remove_session_dir(sess_dir: &Path) -> io::Result<()> {
+    let sess_dir = std::path::absolute(sess_dir)?;
+    std::fs::remove_dir_all(&sess_dir)?;
+
+    let sock_roots = socket_roots()
+        .into_iter()
+        .map(|(_, root)| std::path::absolute(root))
+        .collect::<io::Result<Vec<_>>>()?;
+    let mut parent = sess_dir.parent();
+    while let Some(p) = parent {
+        if sock_roots.iter().any(|root| root == p) {
+            break;
+        }
+        // Only remove if empty
+        if std::fs::read_dir(p)?.next().is_none() {
+            std::fs::remove_dir(p).ok();
+        } else {
+            break;
+        }
+        parent = p.parent();
+    }
+    Ok(())
 }
 
 /// Recursively find all sessions under a directory.
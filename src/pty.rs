@@ -1,13 +1,20 @@
+use nix::fcntl::OFlag;
 use nix::libc;
 use nix::pty::{openpty, OpenptyResult};
-use nix::unistd::{dup2, execvp, fork, setsid, ForkResult, Pid};
-use std::ffi::CString;
+use nix::unistd::{dup2, execvp, fork, pipe2, setsid, ForkResult, Pid};
+use std::ffi::{CStr, CString, OsStr};
 use std::io;
-use std::os::fd::{AsRawFd, OwnedFd, RawFd};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
 
 pub struct Pty {
     pub master: OwnedFd,
     pub child_pid: Pid,
+    /// Read end of the child's stderr pipe, when stderr is kept apart from
+    /// the pty (`pterm new --split-stderr`). Non-blocking like `master`.
+    pub stderr: Option<OwnedFd>,
 }
 
 impl Pty {
@@ -15,7 +22,14 @@ impl Pty {
     /// `cmd` is the command to execute (e.g., "/bin/bash").
     /// `args` are the arguments (argv[0] should be the command name).
     /// `cols` and `rows` set the initial terminal size.
-    pub fn spawn(cmd: &str, args: &[&str], cols: u16, rows: u16) -> io::Result<Self> {
+    /// With `split_stderr`, the child's stderr is a pipe instead of the pty.
+    pub fn spawn(
+        cmd: &str,
+        args: &[&str],
+        cols: u16,
+        rows: u16,
+        split_stderr: bool,
+    ) -> io::Result<Self> {
         // Open a pty pair
         let OpenptyResult { master, slave } = openpty(None, None).map_err(io::Error::other)?;
 
@@ -24,10 +38,15 @@ impl Pty {
 
         // Set master fd to non-blocking so the daemon can drain all available
         // data in a loop without blocking on the last read.
-        unsafe {
-            let flags = libc::fcntl(master.as_raw_fd(), libc::F_GETFL);
-            libc::fcntl(master.as_raw_fd(), libc::F_SETFL, flags | libc::O_NONBLOCK);
-        }
+        set_nonblocking(master.as_raw_fd());
+
+        let stderr_pipe = if split_stderr {
+            let (read, write) = pipe2(OFlag::O_CLOEXEC).map_err(io::Error::other)?;
+            set_nonblocking(read.as_raw_fd());
+            Some((read, write))
+        } else {
+            None
+        };
 
         // Fork
         match unsafe { fork() }.map_err(io::Error::other)? {
@@ -37,37 +56,91 @@ impl Pty {
                 Ok(Pty {
                     master,
                     child_pid: child,
+                    stderr: stderr_pipe.map(|(read, _)| read),
                 })
             }
             ForkResult::Child => {
-                // Child: set up pty as controlling terminal
                 drop(master);
+                exec_child(
+                    slave,
+                    stderr_pipe.as_ref().map(|(_, write)| write),
+                    cmd,
+                    args,
+                )
+            }
+        }
+    }
 
-                setsid().ok();
-
-                // Set slave as controlling terminal
-                unsafe {
-                    libc::ioctl(slave.as_raw_fd(), libc::TIOCSCTTY as libc::c_ulong, 0);
-                }
-
-                // Redirect stdin/stdout/stderr to slave
-                dup2(slave.as_raw_fd(), libc::STDIN_FILENO).ok();
-                dup2(slave.as_raw_fd(), libc::STDOUT_FILENO).ok();
-                dup2(slave.as_raw_fd(), libc::STDERR_FILENO).ok();
+    /// Fork a new child running `cmd` on this pty once the previous one is
+    /// gone (`pterm respawn`), keeping the master and with it the terminal
+    /// size and slave device. The child's stderr is the pty: a
+    /// `--split-stderr` pipe is not re-created.
+    pub fn respawn(&mut self, cmd: &str, args: &[&str]) -> io::Result<()> {
+        let slave = OwnedFd::from(self.open_slave()?);
+        match unsafe { fork() }.map_err(io::Error::other)? {
+            ForkResult::Parent { child } => {
+                self.child_pid = child;
+                Ok(())
+            }
+            ForkResult::Child => exec_child(slave, None, cmd, args),
+        }
+    }
 
-                if slave.as_raw_fd() > 2 {
-                    drop(slave);
-                }
+    /// Rebuild a `Pty` from a master fd, child pid and stderr pipe inherited
+    /// across exec.
+    ///
+    /// # Safety
+    /// `master_fd` must be an open pty master fd and `stderr_fd` an open pipe
+    /// read end, neither owned elsewhere.
+    pub unsafe fn from_raw_parts(
+        master_fd: RawFd,
+        child_pid: Pid,
+        stderr_fd: Option<RawFd>,
+    ) -> Self {
+        Pty {
+            master: OwnedFd::from_raw_fd(master_fd),
+            child_pid,
+            stderr: stderr_fd.map(|fd| OwnedFd::from_raw_fd(fd)),
+        }
+    }
 
-                // Exec the command
-                let c_cmd = CString::new(cmd).unwrap();
-                let c_args: Vec<CString> = args.iter().map(|a| CString::new(*a).unwrap()).collect();
-                execvp(&c_cmd, &c_args).ok();
+    /// Path of the pty's slave device (`/dev/pts/N`), which the child has
+    /// as its controlling terminal.
+    pub fn slave_path(&self) -> Option<PathBuf> {
+        let mut buf = [0 as libc::c_char; 64];
+        let rc = unsafe { libc::ptsname_r(self.master.as_raw_fd(), buf.as_mut_ptr(), buf.len()) };
+        if rc != 0 {
+            return None;
+        }
+        let path = unsafe { CStr::from_ptr(buf.as_ptr()) };
+        Some(PathBuf::from(OsStr::from_bytes(path.to_bytes())))
+    }
 
-                // If exec fails
-                std::process::exit(127);
-            }
+    /// Stop (`TCOOFF`) or restart (`TCOON`) output on the slave side, as
+    /// Ctrl-S / Ctrl-Q would with `IXON` but whatever the child's termios
+    /// says: the child blocks in its next write to the terminal until
+    /// output is restarted. The state belongs to the pty, so it outlives
+    /// the slave descriptor opened for it.
+    pub fn set_output_stopped(&self, stopped: bool) -> io::Result<()> {
+        let slave = self.open_slave()?;
+        let action = if stopped { libc::TCOOFF } else { libc::TCOON };
+        if unsafe { libc::tcflow(slave.as_raw_fd(), action) } == -1 {
+            return Err(io::Error::last_os_error());
         }
+        Ok(())
+    }
+
+    /// Open the slave device without making it the daemon's controlling
+    /// terminal.
+    fn open_slave(&self) -> io::Result<std::fs::File> {
+        let path = self
+            .slave_path()
+            .ok_or_else(|| io::Error::other("pty has no slave device"))?;
+        std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NOCTTY)
+            .open(path)
     }
 
     /// Resize the pty.
@@ -76,6 +149,45 @@ impl Pty {
     }
 }
 
+/// In a freshly forked child: make `slave` the controlling terminal and
+/// stdin/stdout/stderr (stderr `stderr` if given), then exec `cmd`.
+fn exec_child(slave: OwnedFd, stderr: Option<&OwnedFd>, cmd: &str, args: &[&str]) -> ! {
+    setsid().ok();
+
+    // Set slave as controlling terminal
+    unsafe {
+        libc::ioctl(slave.as_raw_fd(), libc::TIOCSCTTY as libc::c_ulong, 0);
+    }
+
+    // Redirect stdin/stdout/stderr to slave
+    dup2(slave.as_raw_fd(), libc::STDIN_FILENO).ok();
+    dup2(slave.as_raw_fd(), libc::STDOUT_FILENO).ok();
+    // Both pipe ends are close-on-exec; the dup'd fd 2 is not.
+    match stderr {
+        Some(write) => dup2(write.as_raw_fd(), libc::STDERR_FILENO).ok(),
+        None => dup2(slave.as_raw_fd(), libc::STDERR_FILENO).ok(),
+    };
+
+    if slave.as_raw_fd() > 2 {
+        drop(slave);
+    }
+
+    // Exec the command
+    let c_cmd = CString::new(cmd).unwrap();
+    let c_args: Vec<CString> = args.iter().map(|a| CString::new(*a).unwrap()).collect();
+    execvp(&c_cmd, &c_args).ok();
+
+    // If exec fails
+    std::process::exit(127);
+}
+
+fn set_nonblocking(fd: RawFd) {
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFL);
+        libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK);
+    }
+}
+
 fn set_winsize(fd: RawFd, cols: u16, rows: u16) -> io::Result<()> {
     let ws = libc::winsize {
         ws_row: rows,
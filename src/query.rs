@@ -0,0 +1,95 @@
+//! `pterm query`: answer `list` / `info` queries with one line of JSON each.
+//!
+//! With `--stdin-loop` the process stays alive and answers one query per
+//! line of stdin, in order, until EOF, so an editor plugin can keep a single
+//! child process for completion and statusline ticks instead of spawning
+//! `pterm list` every time.
+
+use crate::config::split_words;
+use crate::control;
+use crate::list::{self, ListArgs};
+use crate::paths::session_socket_path;
+use serde_json::{json, Value};
+use std::io::{self, BufRead, Write};
+
+/// Answer one query line such as `list --sort activity work/` or
+/// `info dev`, split into words like a shell command line.
+pub fn answer_line(line: &str) -> Value {
+    match split_words(line) {
+        Ok(words) => answer(&words),
+        Err(e) => json!({ "error": e }),
+    }
+}
+
+/// Answer a query given as words. Failures are reported as
+/// `{"error": ...}`.
+pub fn answer(words: &[String]) -> Value {
+    let result = match words.first().map(String::as_str) {
+        Some("list") => list(&words[1..]),
+        Some("info") => info(&words[1..]),
+        Some(other) => Err(format!("unknown query '{}' (expected list or info)", other)),
+        None => Err("empty query".to_string()),
+    };
+    result.unwrap_or_else(|e| json!({ "error": e }))
+}
+
+/// `{"sessions": [...]}` for the same arguments as `pterm list`.
+fn list(args: &[String]) -> Result<Value, String> {
+    let args = ListArgs::parse(args)?;
+    if args.scan {
+        list::rescan().map_err(|e| e.to_string())?;
+    }
+    let mut entries = list::collect(&args.prefix).map_err(|e| e.to_string())?;
+    list::select(&mut entries, &args.filter, args.sort);
+    let sessions: Vec<Value> = entries.iter().map(|e| e.to_json()).collect();
+    Ok(json!({ "sessions": sessions }))
+}
+
+/// The daemon's `{"info": {...}}` reply for one session.
+fn info(args: &[String]) -> Result<Value, String> {
+    let [name] = args else {
+        return Err("info requires exactly one session name".to_string());
+    };
+    if !session_socket_path(name).exists() {
+        return Err(format!("session '{}' not found", name));
+    }
+    control::request(name, pterm_proto::client::INFO, &[]).map_err(|e| e.to_string())
+}
+
+/// Answer queries from stdin until EOF. Blank lines are skipped; every other
+/// line gets exactly one response line.
+pub fn run_loop() -> io::Result<()> {
+    let stdout = io::stdout();
+    for line in io::stdin().lock().lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let mut out = stdout.lock();
+        writeln!(out, "{}", answer_line(&line))?;
+        out.flush()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn malformed_queries_answer_with_errors() {
+        assert_eq!(
+            answer_line("bogus"),
+            json!({ "error": "unknown query 'bogus' (expected list or info)" })
+        );
+        assert_eq!(
+            answer_line("info"),
+            json!({ "error": "info requires exactly one session name" })
+        );
+        assert_eq!(
+            answer_line("list --sort size"),
+            json!({ "error": "--sort requires one of: activity, name, created" })
+        );
+        assert!(answer_line("info 'unterminated").get("error").is_some());
+    }
+}
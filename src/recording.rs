@@ -0,0 +1,335 @@
+//! Daemon I/O recording and deterministic replay.
+//!
+//! `pterm new --record-io <dir>` makes the daemon append every raw PTY read
+//! (and, with `--split-stderr`, every stderr read), every client frame and every snapshot it sends to `<dir>/io.rec`, together
+//! with the session parameters in `<dir>/session.json`. `pterm replay-io <dir>`
+//! re-feeds the recorded bytes through the same terminal state machine the
+//! daemon uses and checks that every recorded snapshot is reproduced
+//! byte-for-byte, so a reported rendering glitch can be reproduced offline.
+//!
+//! Record layout (all integers little-endian):
+//!
+//! ```text
+//! [kind: u8][client_id: u32][elapsed_us: u64][len: u32][data: len bytes]
+//! ```
+
+use crate::session::Terminal;
+use pterm_proto as proto;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// Recorded event stream file name within a recording directory.
+pub const RECORD_FILENAME: &str = "io.rec";
+/// Session parameters file name within a recording directory.
+pub const SESSION_FILENAME: &str = "session.json";
+
+const RECORD_HEADER_SIZE: usize = 17;
+
+/// Raw bytes read from the PTY master (before filtering).
+const KIND_PTY_OUTPUT: u8 = 0x01;
+/// A client connected.
+const KIND_CLIENT_CONNECT: u8 = 0x02;
+/// A complete frame received from a client (encoded, header included).
+const KIND_CLIENT_FRAME: u8 = 0x03;
+/// A client disconnected.
+const KIND_CLIENT_DISCONNECT: u8 = 0x04;
+/// A snapshot queued for a client (STATE_SYNC payload without framing).
+const KIND_SNAPSHOT: u8 = 0x05;
+/// Bytes read from the child's stderr pipe (`--split-stderr`), with `\n`
+/// already turned into `\r\n`.
+const KIND_STDERR_OUTPUT: u8 = 0x06;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    PtyOutput(Vec<u8>),
+    ClientConnect,
+    ClientFrame(proto::Frame),
+    ClientDisconnect,
+    Snapshot(Vec<u8>),
+    StderrOutput(Vec<u8>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub elapsed: Duration,
+    pub client_id: u32,
+    pub event: Event,
+}
+
+impl Record {
+    fn encode(&self, out: &mut Vec<u8>) {
+        let (kind, data) = match &self.event {
+            Event::PtyOutput(bytes) => (KIND_PTY_OUTPUT, bytes.clone()),
+            Event::ClientConnect => (KIND_CLIENT_CONNECT, Vec::new()),
+            Event::ClientFrame(frame) => (
+                KIND_CLIENT_FRAME,
+                proto::encode(frame.msg_type, &frame.payload),
+            ),
+            Event::ClientDisconnect => (KIND_CLIENT_DISCONNECT, Vec::new()),
+            Event::Snapshot(bytes) => (KIND_SNAPSHOT, bytes.clone()),
+            Event::StderrOutput(bytes) => (KIND_STDERR_OUTPUT, bytes.clone()),
+        };
+        out.push(kind);
+        out.extend_from_slice(&self.client_id.to_le_bytes());
+        out.extend_from_slice(&(self.elapsed.as_micros() as u64).to_le_bytes());
+        out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        out.extend_from_slice(&data);
+    }
+
+    /// Read the next record. Returns `Ok(None)` at a clean end of file; a
+    /// truncated trailing record (daemon killed mid-write) is also treated as
+    /// the end of the recording.
+    fn read_from(reader: &mut impl Read) -> io::Result<Option<Self>> {
+        let mut header = [0u8; RECORD_HEADER_SIZE];
+        if !read_exact_or_eof(reader, &mut header)? {
+            return Ok(None);
+        }
+        let kind = header[0];
+        let client_id = u32::from_le_bytes(header[1..5].try_into().expect("4-byte slice"));
+        let elapsed_us = u64::from_le_bytes(header[5..13].try_into().expect("8-byte slice"));
+        let len = u32::from_le_bytes(header[13..17].try_into().expect("4-byte slice")) as usize;
+        let mut data = vec![0u8; len];
+        if !read_exact_or_eof(reader, &mut data)? {
+            return Ok(None);
+        }
+
+        let event = match kind {
+            KIND_PTY_OUTPUT => Event::PtyOutput(data),
+            KIND_CLIENT_CONNECT => Event::ClientConnect,
+            KIND_CLIENT_FRAME => {
+                let mut buf = data;
+                let mut frames = proto::decode_frames(&mut buf);
+                if frames.len() != 1 || !buf.is_empty() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "malformed client frame record",
+                    ));
+                }
+                Event::ClientFrame(frames.remove(0))
+            }
+            KIND_CLIENT_DISCONNECT => Event::ClientDisconnect,
+            KIND_SNAPSHOT => Event::Snapshot(data),
+            KIND_STDERR_OUTPUT => Event::StderrOutput(data),
+            other => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unknown record kind 0x{:02x}", other),
+                ));
+            }
+        };
+
+        Ok(Some(Self {
+            elapsed: Duration::from_micros(elapsed_us),
+            client_id,
+            event,
+        }))
+    }
+}
+
+fn read_exact_or_eof(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<bool> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => return Ok(false),
+            Ok(n) => filled += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(true)
+}
+
+/// Session parameters needed to rebuild the terminal state on replay.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct RecordedSession {
+    pub session: String,
+    pub command: Vec<String>,
+    pub cols: u16,
+    pub rows: u16,
+}
+
+/// Appends daemon I/O events to a recording directory.
+pub struct Recorder {
+    writer: BufWriter<File>,
+    started: Instant,
+    buf: Vec<u8>,
+}
+
+impl Recorder {
+    pub fn create(dir: &Path, session: &RecordedSession) -> io::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        let meta = serde_json::to_vec_pretty(session).map_err(io::Error::other)?;
+        std::fs::write(dir.join(SESSION_FILENAME), meta)?;
+        let file = File::create(dir.join(RECORD_FILENAME))?;
+        Ok(Self {
+            writer: BufWriter::new(file),
+            started: Instant::now(),
+            buf: Vec::new(),
+        })
+    }
+
+    pub fn record(&mut self, client_id: usize, event: Event) -> io::Result<()> {
+        let record = Record {
+            elapsed: self.started.elapsed(),
+            client_id: client_id as u32,
+            event,
+        };
+        self.buf.clear();
+        record.encode(&mut self.buf);
+        self.writer.write_all(&self.buf)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Reads a recording back as a sequence of records.
+pub struct RecordReader {
+    reader: BufReader<File>,
+    pub session: RecordedSession,
+}
+
+impl RecordReader {
+    pub fn open(dir: &Path) -> io::Result<Self> {
+        let meta = std::fs::read(dir.join(SESSION_FILENAME))?;
+        let session = serde_json::from_slice(&meta).map_err(io::Error::other)?;
+        let file = File::open(dir.join(RECORD_FILENAME))?;
+        Ok(Self {
+            reader: BufReader::new(file),
+            session,
+        })
+    }
+}
+
+impl Iterator for RecordReader {
+    type Item = io::Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Record::read_from(&mut self.reader).transpose()
+    }
+}
+
+/// A recorded snapshot that the replayed terminal state did not reproduce.
+#[derive(Debug)]
+pub struct Divergence {
+    pub index: usize,
+    pub elapsed: Duration,
+    pub client_id: u32,
+    pub recorded_len: usize,
+    pub replayed_len: usize,
+}
+
+#[derive(Debug, Default)]
+pub struct ReplayReport {
+    pub records: usize,
+    pub pty_bytes: usize,
+    pub client_frames: usize,
+    pub snapshots: usize,
+    pub divergences: Vec<Divergence>,
+    pub duration: Duration,
+    /// Plain-text screen contents after the last record.
+    pub final_screen: String,
+}
+
+/// Re-feed a recording through the daemon's terminal state machine.
+pub fn replay(dir: &Path) -> io::Result<ReplayReport> {
+    let reader = RecordReader::open(dir)?;
+    let mut terminal = Terminal::new(reader.session.rows, reader.session.cols);
+    let mut report = ReplayReport::default();
+    let mut discard = Vec::new();
+
+    for (index, record) in reader.enumerate() {
+        let record = record?;
+        report.records += 1;
+        report.duration = record.elapsed;
+        match record.event {
+            Event::PtyOutput(bytes) | Event::StderrOutput(bytes) => {
+                report.pty_bytes += bytes.len();
+                discard.clear();
+                terminal.process(&bytes, &mut discard);
+            }
+            Event::ClientFrame(frame) => {
+                report.client_frames += 1;
+                if frame.msg_type == proto::client::RESIZE {
+                    if let Ok((cols, rows)) = proto::parse_resize(&frame.payload) {
+                        terminal.resize(cols, rows);
+                    }
+                }
+            }
+            Event::Snapshot(recorded) => {
+                report.snapshots += 1;
+                let replayed = terminal.snapshot();
+                if replayed != recorded {
+                    report.divergences.push(Divergence {
+                        index,
+                        elapsed: record.elapsed,
+                        client_id: record.client_id,
+                        recorded_len: recorded.len(),
+                        replayed_len: replayed.len(),
+                    });
+                }
+            }
+            Event::ClientConnect | Event::ClientDisconnect => {}
+        }
+    }
+
+    report.final_screen = terminal.contents();
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record_roundtrip() {
+        let records = vec![
+            Record {
+                elapsed: Duration::from_micros(5),
+                client_id: 0,
+                event: Event::PtyOutput(b"hello".to_vec()),
+            },
+            Record {
+                elapsed: Duration::from_micros(10),
+                client_id: 3,
+                event: Event::ClientFrame(proto::Frame {
+                    msg_type: proto::client::RESIZE,
+                    payload: proto::encode_resize(100, 30).to_vec(),
+                }),
+            },
+            Record {
+                elapsed: Duration::from_micros(11),
+                client_id: 3,
+                event: Event::ClientDisconnect,
+            },
+        ];
+
+        let mut buf = Vec::new();
+        for record in &records {
+            record.encode(&mut buf);
+        }
+        let mut reader = buf.as_slice();
+        let mut decoded = Vec::new();
+        while let Some(record) = Record::read_from(&mut reader).unwrap() {
+            decoded.push(record);
+        }
+        assert_eq!(decoded, records);
+    }
+
+    #[test]
+    fn truncated_trailing_record_ends_recording() {
+        let mut buf = Vec::new();
+        Record {
+            elapsed: Duration::ZERO,
+            client_id: 0,
+            event: Event::PtyOutput(b"abc".to_vec()),
+        }
+        .encode(&mut buf);
+        buf.truncate(buf.len() - 1);
+        let mut reader = buf.as_slice();
+        assert!(Record::read_from(&mut reader).unwrap().is_none());
+    }
+}
@@ -0,0 +1,214 @@
+//! Registry of the sessions in a socket root, so `pterm list` and everything
+//! built on it can enumerate hundreds of sessions without walking the
+//! directory tree.
+//!
+//! Daemons add their session when they start listening (and again after a
+//! hot upgrade) and remove it when they shut down, by appending `+"name"` /
+//! `-"name"` lines (names JSON-quoted) to `<root>/registry`. Replaying the
+//! lines gives the live set. Writers hold `<root>/registry.lock`; once the
+//! file has grown well past the live set it is compacted to one `+` line
+//! per session. A root without a registry is scanned instead, and the first
+//! daemon to register seeds the registry from a scan, so sessions of daemons
+//! from before the registry are not lost. Readers drop entries whose socket
+//! is gone (a daemon that was killed), and `pterm list --scan` rebuilds the
+//! registry from a scan.
+
+use crate::paths::{self, find_sessions};
+use nix::fcntl::{Flock, FlockArg};
+use std::collections::BTreeSet;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+
+/// Registry file name within a socket root.
+pub const REGISTRY_FILENAME: &str = "registry";
+const LOCK_FILENAME: &str = "registry.lock";
+/// Lines a registry may hold beyond twice its live entries before it is
+/// compacted.
+const COMPACT_SLACK: usize = 64;
+
+/// Note that a daemon listens in `session_dir`. Errors are only logged:
+/// without an entry the session is still found by a scan.
+pub fn register(session_dir: &Path) {
+    if let Err(e) = change(session_dir, true) {
+        log::debug!("Failed to register {}: {}", session_dir.display(), e);
+    }
+}
+
+/// Note that the daemon in `session_dir` stopped listening.
+pub fn unregister(session_dir: &Path) {
+    if let Err(e) = change(session_dir, false) {
+        log::debug!("Failed to unregister {}: {}", session_dir.display(), e);
+    }
+}
+
+/// Sessions registered in socket root `root` whose socket is still there,
+/// sorted; `None` if the root has no registry.
+pub fn sessions(root: &Path) -> Option<Vec<String>> {
+    let text = std::fs::read_to_string(root.join(REGISTRY_FILENAME)).ok()?;
+    let (live, stale): (Vec<String>, Vec<String>) = replay(&text)
+        .into_iter()
+        .partition(|name| is_socket(&root.join(name).join(paths::SOCKET_FILENAME)));
+    if !stale.is_empty() {
+        if let Err(e) = prune(root, &stale) {
+            log::debug!("Failed to prune registry in {}: {}", root.display(), e);
+        }
+    }
+    Some(live)
+}
+
+/// Replace the registry of `root` with the sessions a directory scan finds.
+pub fn rebuild(root: &Path) -> io::Result<()> {
+    if !root.is_dir() {
+        return Ok(());
+    }
+    let _lock = lock(root)?;
+    write_compacted(root, &find_sessions(root, "")?)
+}
+
+/// The live set a registry's lines describe, sorted.
+fn replay(text: &str) -> Vec<String> {
+    let mut live = BTreeSet::new();
+    for entry in text.lines() {
+        let (add, name) = match (entry.strip_prefix('+'), entry.strip_prefix('-')) {
+            (Some(name), _) => (true, name),
+            (_, Some(name)) => (false, name),
+            _ => continue,
+        };
+        let Ok(name) = serde_json::from_str::<String>(name) else {
+            continue;
+        };
+        if add {
+            live.insert(name);
+        } else {
+            live.remove(&name);
+        }
+    }
+    live.into_iter().collect()
+}
+
+fn line(op: char, name: &str) -> String {
+    format!("{}{}\n", op, serde_json::Value::from(name))
+}
+
+/// Add or remove the session in `session_dir` in its socket root's
+/// registry.
+fn change(session_dir: &Path, add: bool) -> io::Result<()> {
+    let session_dir = std::path::absolute(session_dir)?;
+    let Some((root, name)) = root_of(&session_dir) else {
+        return Ok(());
+    };
+    if add && !root.join(REGISTRY_FILENAME).exists() {
+        // Seed from a scan, which finds this session too.
+        return rebuild(&root);
+    }
+    append(&root, vec![line(if add { '+' } else { '-' }, &name)])
+}
+
+/// The socket root `session_dir` lies in and the session's name there.
+fn root_of(session_dir: &Path) -> Option<(PathBuf, String)> {
+    paths::socket_roots().into_iter().find_map(|(_, root)| {
+        let root = std::path::absolute(root).ok()?;
+        let name = session_dir.strip_prefix(&root).ok()?.to_str()?.to_string();
+        (!name.is_empty()).then_some((root, name))
+    })
+}
+
+/// Remove the `stale` sessions from the registry of `root`, unless a
+/// daemon has taken the name again since they were read.
+fn prune(root: &Path, stale: &[String]) -> io::Result<()> {
+    let _lock = lock(root)?;
+    let removed = stale
+        .iter()
+        .filter(|name| !is_socket(&root.join(name).join(paths::SOCKET_FILENAME)))
+        .map(|name| line('-', name))
+        .collect();
+    append_locked(root, removed)
+}
+
+/// Append `lines` to the registry of `root`, if it has one.
+fn append(root: &Path, lines: Vec<String>) -> io::Result<()> {
+    let _lock = lock(root)?;
+    append_locked(root, lines)
+}
+
+/// [`append`] with the lock held, compacting the registry once it has
+/// grown well past its live set.
+fn append_locked(root: &Path, lines: Vec<String>) -> io::Result<()> {
+    let path = root.join(REGISTRY_FILENAME);
+    let mut file = match OpenOptions::new().append(true).open(&path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    file.write_all(lines.concat().as_bytes())?;
+
+    let text = std::fs::read_to_string(&path)?;
+    let live = replay(&text);
+    if text.lines().count() > 2 * live.len() + COMPACT_SLACK {
+        write_compacted(root, &live)?;
+    }
+    Ok(())
+}
+
+/// Atomically replace the registry of `root` with one line per session.
+fn write_compacted(root: &Path, names: &[String]) -> io::Result<()> {
+    let text: String = names.iter().map(|name| line('+', name)).collect();
+    let tmp = root.join(format!(".{}.tmp", REGISTRY_FILENAME));
+    std::fs::write(&tmp, text)?;
+    std::fs::rename(&tmp, root.join(REGISTRY_FILENAME))
+}
+
+/// Hold the registry lock of `root` until the guard is dropped.
+fn lock(root: &Path) -> io::Result<Flock<File>> {
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(root.join(LOCK_FILENAME))?;
+    Flock::lock(file, FlockArg::LockExclusive).map_err(|(_, errno)| errno.into())
+}
+
+fn is_socket(path: &Path) -> bool {
+    std::fs::metadata(path).is_ok_and(|meta| meta.file_type().is_socket())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replays_and_compacts_entries() {
+        let text = [
+            line('+', "dev"),
+            line('+', "repos/pterm"),
+            line('+', "odd\nname"),
+            line('-', "dev"),
+            line('+', "build"),
+            "garbage\n".to_string(),
+        ]
+        .concat();
+        assert_eq!(replay(&text), ["build", "odd\nname", "repos/pterm"]);
+
+        let root = std::env::temp_dir().join(format!("pterm-registry-test-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        // Without a registry nothing is appended; readers scan instead.
+        append(&root, vec![line('+', "dev")]).unwrap();
+        assert_eq!(sessions(&root), None);
+
+        write_compacted(&root, &["dev".to_string()]).unwrap();
+        for _ in 0..COMPACT_SLACK {
+            append(&root, vec![line('+', "tmp"), line('-', "tmp")]).unwrap();
+        }
+        let text = std::fs::read_to_string(root.join(REGISTRY_FILENAME)).unwrap();
+        assert!(text.lines().count() <= 2 + COMPACT_SLACK, "{}", text);
+        assert_eq!(replay(&text), ["dev"]);
+
+        // `dev` has no socket, so reading drops it.
+        assert_eq!(sessions(&root), Some(Vec::new()));
+        let text = std::fs::read_to_string(root.join(REGISTRY_FILENAME)).unwrap();
+        assert!(replay(&text).is_empty());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
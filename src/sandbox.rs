@@ -0,0 +1,245 @@
+//! `pterm new --sandbox`: confine the daemon once the child is running.
+//!
+//! The daemon parses untrusted byte streams (child output, client frames)
+//! for as long as the session lives, so with `--sandbox` it gives up what
+//! it no longer needs after spawning the child:
+//!
+//! - a seccomp filter makes `execve`, `ptrace`, mounts, module loading,
+//!   namespace changes, credential changes, non-Unix sockets and similar
+//!   calls fail with `EPERM`;
+//! - landlock (where the kernel has it) limits file access to the session
+//!   directory and a few other directories the daemon writes to, plus
+//!   read-only `/proc` and `/etc`.
+//!
+//! Both are inherited by anything the daemon would start, and the child,
+//! which is already running, is not affected. Files opened before the
+//! sandbox (the pty, the listening socket) stay usable.
+
+use nix::libc;
+use std::io;
+use std::path::PathBuf;
+
+/// Directories the sandboxed daemon may use.
+#[derive(Debug, Default, Clone)]
+pub struct Rules {
+    /// Read, write, create and remove files beneath these.
+    pub writable: Vec<PathBuf>,
+    /// Read files and list directories beneath these.
+    pub readable: Vec<PathBuf>,
+}
+
+/// Apply the sandbox to the calling thread and everything it starts, i.e.
+/// the whole daemon, which is single-threaded. Returns what was applied,
+/// e.g. `seccomp, landlock v3`; landlock is skipped on kernels without it,
+/// a failing seccomp filter is an error.
+pub fn apply(rules: &Rules) -> io::Result<String> {
+    let landlock = landlock::restrict(rules)?;
+    seccomp::install()?;
+    Ok(match landlock {
+        Some(abi) => format!("seccomp, landlock v{}", abi),
+        None => "seccomp".to_string(),
+    })
+}
+
+/// Check that [`apply`] works on this system, on a throwaway thread so the
+/// caller stays unconfined.
+pub fn check() -> io::Result<String> {
+    std::thread::spawn(|| apply(&Rules::default()))
+        .join()
+        .unwrap_or_else(|_| Err(io::Error::other("sandbox check panicked")))
+}
+
+mod landlock {
+    use super::*;
+    use ::landlock::{
+        Access, AccessFs, LandlockStatus, PathBeneath, PathFd, Ruleset, RulesetAttr,
+        RulesetCreatedAttr, RulesetStatus, ABI,
+    };
+
+    /// The newest rights the daemon knows to grant; `TRUNCATE` came with
+    /// ABI 3. Older kernels enforce what they have.
+    const HANDLED_ABI: ABI = ABI::V3;
+
+    /// Restrict file access to `rules`. Returns the landlock ABI version
+    /// enforced, `None` if the kernel has no landlock.
+    pub(super) fn restrict(rules: &Rules) -> io::Result<Option<ABI>> {
+        let handled = AccessFs::from_all(HANDLED_ABI);
+        let writable = handled & !(AccessFs::Execute | AccessFs::MakeChar | AccessFs::MakeBlock);
+        let readable = AccessFs::ReadFile | AccessFs::ReadDir;
+        let mut ruleset = Ruleset::default()
+            .handle_access(handled)
+            .and_then(|ruleset| ruleset.create())
+            .map_err(io::Error::other)?;
+        for (paths, access) in [(&rules.writable, writable), (&rules.readable, readable)] {
+            for path in paths {
+                // Paths that do not exist (yet) cannot be granted. Files
+                // get only the rights that apply to files.
+                match PathFd::new(path) {
+                    Ok(fd) => {
+                        ruleset = ruleset
+                            .add_rule(PathBeneath::new(fd, access))
+                            .map_err(io::Error::other)?;
+                    }
+                    Err(e) => log::debug!("Sandbox: not granting {}: {}", path.display(), e),
+                }
+            }
+        }
+        let status = ruleset.restrict_self().map_err(io::Error::other)?;
+        Ok(match (status.ruleset, status.landlock) {
+            (RulesetStatus::NotEnforced, _) => None,
+            (_, LandlockStatus::Available { effective_abi, .. }) => {
+                Some(effective_abi.min(HANDLED_ABI))
+            }
+            (_, LandlockStatus::NotEnabled | LandlockStatus::NotImplemented) => None,
+        })
+    }
+}
+
+mod seccomp {
+    use super::*;
+    use seccompiler::{
+        BpfProgram, SeccompAction, SeccompCmpArgLen, SeccompCmpOp, SeccompCondition, SeccompFilter,
+        SeccompRule,
+    };
+    use std::collections::BTreeMap;
+
+    /// x32 system calls on x86_64 carry this bit and pass the same
+    /// architecture check, so each denied call is denied under both numbers.
+    #[cfg(target_arch = "x86_64")]
+    const X32_SYSCALL_BIT: i64 = 0x4000_0000;
+    /// Numbers that exist only for x32 (`execve`, `ptrace`, ...), before
+    /// the x32 bit.
+    #[cfg(target_arch = "x86_64")]
+    const X32_ONLY: std::ops::RangeInclusive<i64> = 512..=547;
+
+    /// System calls the daemon never makes once it runs.
+    const DENIED: &[libc::c_long] = &[
+        libc::SYS_execve,
+        libc::SYS_execveat,
+        libc::SYS_ptrace,
+        libc::SYS_process_vm_readv,
+        libc::SYS_process_vm_writev,
+        libc::SYS_mount,
+        libc::SYS_umount2,
+        libc::SYS_pivot_root,
+        libc::SYS_chroot,
+        libc::SYS_unshare,
+        libc::SYS_setns,
+        libc::SYS_init_module,
+        libc::SYS_finit_module,
+        libc::SYS_delete_module,
+        libc::SYS_kexec_load,
+        libc::SYS_reboot,
+        libc::SYS_swapon,
+        libc::SYS_swapoff,
+        libc::SYS_acct,
+        libc::SYS_settimeofday,
+        libc::SYS_clock_settime,
+        libc::SYS_bpf,
+        libc::SYS_perf_event_open,
+        libc::SYS_userfaultfd,
+        libc::SYS_io_uring_setup,
+        libc::SYS_keyctl,
+        libc::SYS_add_key,
+        libc::SYS_request_key,
+        libc::SYS_open_by_handle_at,
+        libc::SYS_name_to_handle_at,
+        libc::SYS_personality,
+        libc::SYS_quotactl,
+        libc::SYS_setuid,
+        libc::SYS_setgid,
+        libc::SYS_setreuid,
+        libc::SYS_setregid,
+        libc::SYS_setresuid,
+        libc::SYS_setresgid,
+        libc::SYS_setgroups,
+        libc::SYS_setfsuid,
+        libc::SYS_setfsgid,
+        libc::SYS_capset,
+    ];
+
+    /// The filter: kill other architectures, fail denied calls and `socket`
+    /// for anything but `AF_UNIX` with `EPERM`, allow the rest.
+    pub(super) fn filter() -> io::Result<SeccompFilter> {
+        let mut rules: BTreeMap<i64, Vec<SeccompRule>> =
+            DENIED.iter().map(|&nr| (nr, Vec::new())).collect();
+        let not_unix = SeccompCondition::new(
+            0,
+            SeccompCmpArgLen::Dword,
+            SeccompCmpOp::Ne,
+            libc::AF_UNIX as u64,
+        )
+        .and_then(|condition| SeccompRule::new(vec![condition]))
+        .map_err(io::Error::other)?;
+        rules.insert(libc::SYS_socket, vec![not_unix]);
+        #[cfg(target_arch = "x86_64")]
+        {
+            let x32: Vec<_> = rules
+                .iter()
+                .map(|(&nr, rule)| (nr | X32_SYSCALL_BIT, rule.clone()))
+                .chain(X32_ONLY.map(|nr| (nr | X32_SYSCALL_BIT, Vec::new())))
+                .collect();
+            rules.extend(x32);
+        }
+        let arch = std::env::consts::ARCH.try_into().map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::Unsupported,
+                "no seccomp filter for this architecture",
+            )
+        })?;
+        SeccompFilter::new(
+            rules,
+            SeccompAction::Allow,
+            SeccompAction::Errno(libc::EPERM as u32),
+            arch,
+        )
+        .map_err(io::Error::other)
+    }
+
+    pub(super) fn install() -> io::Result<()> {
+        let program = BpfProgram::try_from(filter()?).map_err(io::Error::other)?;
+        seccompiler::apply_filter(&program).map_err(io::Error::other)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sandboxed_thread_cannot_exec_or_leave_its_directories() {
+        let dir = std::env::temp_dir().join(format!("pterm-sandbox-test-{}", std::process::id()));
+        let outside = dir.with_extension("outside");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::create_dir_all(&outside).unwrap();
+
+        // Both seccomp and landlock confine only the calling thread (and
+        // what it starts), so the rest of the test binary is unaffected.
+        let rules = Rules {
+            writable: vec![dir.clone()],
+            readable: vec![PathBuf::from("/proc")],
+        };
+        let (applied, exec, inet, inside, escaped) = std::thread::spawn(move || {
+            let applied = apply(&rules).unwrap();
+            (
+                applied,
+                std::process::Command::new("/bin/true").status(),
+                std::net::TcpListener::bind("127.0.0.1:0"),
+                std::fs::write(rules.writable[0].join("meta.json"), b"{}"),
+                std::fs::write(outside.join("x"), b"x"),
+            )
+        })
+        .join()
+        .unwrap();
+        let exec = exec.expect_err("exec was allowed");
+        assert_eq!(exec.raw_os_error(), Some(libc::EPERM), "{}", exec);
+        assert!(inet.is_err(), "an AF_INET socket was allowed");
+        inside.unwrap();
+        if applied.contains("landlock") {
+            let escaped = escaped.expect_err("wrote outside the sandbox");
+            assert_eq!(escaped.kind(), io::ErrorKind::PermissionDenied);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+        std::fs::remove_dir_all(dir.with_extension("outside")).unwrap();
+    }
+}
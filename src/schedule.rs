@@ -0,0 +1,444 @@
+//! Scheduled session actions (`pterm at` / `pterm every`).
+//!
+//! Jobs are kept in `schedule.json` next to the config file. There is no
+//! central daemon: every session daemon reloads the file when it changes and
+//! runs the jobs whose target glob matches its own session name. Actions
+//! therefore run in-session and can look at the session's state, e.g. skip a
+//! job while clients are attached.
+
+use crate::config;
+use crate::list::{format_age, glob_match};
+use nix::libc;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Schedule file name, in the same directory as the config file.
+pub const SCHEDULE_FILENAME: &str = "schedule.json";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Trigger {
+    /// Once, at a Unix timestamp.
+    At { time: u64 },
+    /// At `since + k * interval` for every `k >= 1`.
+    Every { interval: u64, since: u64 },
+}
+
+impl Trigger {
+    /// Whether the trigger fires in the window `(from, to]`.
+    pub fn fires_between(&self, from: u64, to: u64) -> bool {
+        match *self {
+            Self::At { time } => from < time && time <= to,
+            Self::Every { interval, since } => {
+                let count = |t: u64| t.saturating_sub(since) / interval.max(1);
+                count(to) > count(from)
+            }
+        }
+    }
+
+    /// A one-shot trigger whose time has passed.
+    pub fn expired(&self, now: u64) -> bool {
+        matches!(*self, Self::At { time } if time <= now)
+    }
+
+    /// Human-readable form relative to `now`, e.g. `at 02:00 (in 3h)`.
+    pub fn describe(&self, now: u64) -> String {
+        match *self {
+            Self::At { time } => format!(
+                "at {} (in {})",
+                local_clock(time),
+                format_age(time.saturating_sub(now))
+            ),
+            Self::Every { interval, .. } => format!("every {}", format_duration(interval)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Action {
+    Kill,
+    Suspend,
+    Resume,
+    /// Type `command` into the session, followed by Enter.
+    Exec {
+        command: Vec<String>,
+    },
+}
+
+impl Action {
+    /// Parse an action name; `command` is only accepted (and required) for
+    /// `exec`.
+    pub fn parse(name: &str, command: Vec<String>) -> Result<Self, String> {
+        let action = match name {
+            "kill" => Self::Kill,
+            "suspend" => Self::Suspend,
+            "resume" => Self::Resume,
+            "exec" if command.is_empty() => {
+                return Err("exec requires a command after '--'".to_string())
+            }
+            "exec" => return Ok(Self::Exec { command }),
+            _ => {
+                return Err(format!(
+                    "unknown action '{}' (expected kill, suspend, resume or exec)",
+                    name
+                ))
+            }
+        };
+        if !command.is_empty() {
+            return Err(format!("{} does not take a command", name));
+        }
+        Ok(action)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Kill => "kill",
+            Self::Suspend => "suspend",
+            Self::Resume => "resume",
+            Self::Exec { .. } => "exec",
+        }
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Exec { command } => write!(f, "exec -- {}", shell_join(command)),
+            _ => f.write_str(self.name()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Job {
+    pub id: u64,
+    pub trigger: Trigger,
+    pub action: Action,
+    /// Glob over session names (see [`glob_match`]).
+    pub target: String,
+    /// Skip the job while clients are attached to the session.
+    #[serde(default)]
+    pub if_detached: bool,
+}
+
+impl Job {
+    /// The job as it would be entered on the command line, with the trigger
+    /// relative to `now`, e.g. `every 1h exec backup -- ./snapshot.sh`.
+    pub fn describe(&self, now: u64) -> String {
+        let mut out = format!(
+            "{} {} {}",
+            self.trigger.describe(now),
+            self.action.name(),
+            self.target
+        );
+        if let Action::Exec { command } = &self.action {
+            out.push_str(" -- ");
+            out.push_str(&shell_join(command));
+        }
+        if self.if_detached {
+            out.push_str(" (if detached)");
+        }
+        out
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Schedule {
+    #[serde(default)]
+    pub jobs: Vec<Job>,
+}
+
+impl Schedule {
+    pub fn path() -> Option<PathBuf> {
+        Some(config::config_path()?.parent()?.join(SCHEDULE_FILENAME))
+    }
+
+    /// Read the schedule at `path`; a missing file is an empty schedule.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        match std::fs::read(path) {
+            Ok(data) => serde_json::from_slice(&data)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Atomically replace the schedule file, so daemons never read a
+    /// partially written document.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let data = serde_json::to_vec_pretty(self).map_err(io::Error::other)?;
+        let tmp = path.with_extension("json.tmp");
+        std::fs::write(&tmp, data)?;
+        std::fs::rename(&tmp, path)
+    }
+
+    /// Add a job and return its id.
+    pub fn add(
+        &mut self,
+        trigger: Trigger,
+        action: Action,
+        target: &str,
+        if_detached: bool,
+    ) -> u64 {
+        let id = self.jobs.iter().map(|j| j.id).max().unwrap_or(0) + 1;
+        self.jobs.push(Job {
+            id,
+            trigger,
+            action,
+            target: target.to_string(),
+            if_detached,
+        });
+        id
+    }
+
+    /// Remove job `id`; `false` if there is no such job.
+    pub fn remove(&mut self, id: u64) -> bool {
+        let len = self.jobs.len();
+        self.jobs.retain(|j| j.id != id);
+        self.jobs.len() != len
+    }
+
+    /// Drop one-shot jobs that have already run.
+    pub fn prune(&mut self, now: u64) {
+        self.jobs.retain(|j| !j.trigger.expired(now));
+    }
+}
+
+/// A daemon's view of the schedule file, reloaded when it changes.
+pub struct Scheduler {
+    path: Option<PathBuf>,
+    modified: Option<SystemTime>,
+    jobs: Vec<Job>,
+    last_check: u64,
+}
+
+impl Scheduler {
+    pub fn new(now: u64) -> Self {
+        Self {
+            path: Schedule::path(),
+            modified: None,
+            jobs: Vec::new(),
+            last_check: now,
+        }
+    }
+
+    /// Jobs targeting `session` that came due since the previous call. Only
+    /// jobs due after the scheduler was created are returned.
+    pub fn due(&mut self, session: &str, now: u64) -> Vec<Job> {
+        if now <= self.last_check {
+            return Vec::new();
+        }
+        self.reload();
+        let from = std::mem::replace(&mut self.last_check, now);
+        self.jobs
+            .iter()
+            .filter(|j| glob_match(&j.target, session) && j.trigger.fires_between(from, now))
+            .cloned()
+            .collect()
+    }
+
+    fn reload(&mut self) {
+        let Some(path) = self.path.as_deref() else {
+            return;
+        };
+        let modified = std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        if modified == self.modified {
+            return;
+        }
+        self.modified = modified;
+        match Schedule::load(path) {
+            Ok(schedule) => self.jobs = schedule.jobs,
+            Err(e) => log::warn!("Failed to read {}: {}", path.display(), e),
+        }
+    }
+}
+
+/// Parse a duration such as `90s`, `5m`, `1h30m` or `1d` into seconds.
+pub fn parse_duration(s: &str) -> Option<u64> {
+    let mut total: u64 = 0;
+    let mut digits = String::new();
+    for c in s.chars() {
+        if c.is_ascii_digit() {
+            digits.push(c);
+            continue;
+        }
+        let unit = match c {
+            's' => 1,
+            'm' => 60,
+            'h' => 3600,
+            'd' => 86_400,
+            _ => return None,
+        };
+        let n: u64 = std::mem::take(&mut digits).parse().ok()?;
+        total = total.checked_add(n.checked_mul(unit)?)?;
+    }
+    (digits.is_empty() && total > 0).then_some(total)
+}
+
+/// Inverse of [`parse_duration`] for display, e.g. `1h30m`.
+pub fn format_duration(secs: u64) -> String {
+    let mut out = String::new();
+    let mut rest = secs;
+    for (unit, name) in [(86_400, 'd'), (3600, 'h'), (60, 'm'), (1, 's')] {
+        if rest >= unit {
+            out.push_str(&format!("{}{}", rest / unit, name));
+            rest %= unit;
+        }
+    }
+    out
+}
+
+/// Parse `HH:MM` (next occurrence, local time) or `+<duration>` into a Unix
+/// timestamp.
+pub fn parse_time(s: &str, now: u64) -> Option<u64> {
+    if let Some(duration) = s.strip_prefix('+') {
+        return Some(now + parse_duration(duration)?);
+    }
+    let (hour, minute) = s.split_once(':')?;
+    let hour: i32 = hour.parse().ok().filter(|h| (0..24).contains(h))?;
+    let minute: i32 = minute.parse().ok().filter(|m| (0..60).contains(m))?;
+    Some(next_local_time(hour, minute, now))
+}
+
+/// Next Unix timestamp after `now` whose local wall-clock time is
+/// `hour:minute`.
+fn next_local_time(hour: i32, minute: i32, now: u64) -> u64 {
+    let mut tm = local_tm(now);
+    tm.tm_hour = hour;
+    tm.tm_min = minute;
+    tm.tm_sec = 0;
+    tm.tm_isdst = -1;
+    let mut time = unsafe { libc::mktime(&mut tm) };
+    if time as u64 <= now {
+        tm.tm_mday += 1;
+        tm.tm_hour = hour;
+        tm.tm_min = minute;
+        tm.tm_isdst = -1;
+        time = unsafe { libc::mktime(&mut tm) };
+    }
+    time as u64
+}
+
+fn local_tm(time: u64) -> libc::tm {
+    let time = time as libc::time_t;
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    unsafe { libc::localtime_r(&time, &mut tm) };
+    tm
+}
+
+/// Local date and time of a timestamp as `YYYY-MM-DD HH:MM`.
+pub fn local_datetime(time: u64) -> String {
+    let tm = local_tm(time);
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}",
+        tm.tm_year + 1900,
+        tm.tm_mon + 1,
+        tm.tm_mday,
+        tm.tm_hour,
+        tm.tm_min
+    )
+}
+
+/// Local wall-clock time of a timestamp as `HH:MM`.
+fn local_clock(time: u64) -> String {
+    let tm = local_tm(time);
+    format!("{:02}:{:02}", tm.tm_hour, tm.tm_min)
+}
+
+/// Join words into a command line a POSIX shell splits back into the same
+/// words.
+pub fn shell_join(words: &[String]) -> String {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "-_./=:,+@%".contains(c);
+    words
+        .iter()
+        .map(|w| {
+            if !w.is_empty() && w.chars().all(safe) {
+                w.clone()
+            } else {
+                format!("'{}'", w.replace('\'', "'\\''"))
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn triggers_fire_once_per_window() {
+        let at = Trigger::At { time: 100 };
+        assert!(!at.fires_between(50, 99));
+        assert!(at.fires_between(99, 100));
+        assert!(!at.fires_between(100, 200));
+        assert!(at.expired(100));
+
+        let every = Trigger::Every {
+            interval: 60,
+            since: 1000,
+        };
+        assert!(!every.fires_between(900, 1059));
+        assert!(every.fires_between(1059, 1060));
+        assert!(!every.fires_between(1060, 1119));
+        assert!(every.fires_between(1100, 1300));
+        assert!(!every.expired(u64::MAX));
+    }
+
+    #[test]
+    fn durations_and_times() {
+        assert_eq!(parse_duration("90s"), Some(90));
+        assert_eq!(parse_duration("1h30m"), Some(5400));
+        assert_eq!(parse_duration("1d"), Some(86_400));
+        assert_eq!(parse_duration("5"), None);
+        assert_eq!(parse_duration("0m"), None);
+        assert_eq!(format_duration(5400), "1h30m");
+
+        assert_eq!(parse_time("+10m", 1000), Some(1600));
+        let next = parse_time("02:00", 1_700_000_000).unwrap();
+        assert!(next > 1_700_000_000 && next <= 1_700_000_000 + 86_400);
+        assert_eq!(local_clock(next), "02:00");
+        assert_eq!(parse_time("25:00", 0), None);
+    }
+
+    #[test]
+    fn actions_parse_and_display() {
+        let exec = Action::parse("exec", vec!["./snapshot.sh".into(), "a b".into()]).unwrap();
+        assert_eq!(exec.to_string(), "exec -- ./snapshot.sh 'a b'");
+        assert!(Action::parse("exec", vec![]).is_err());
+        assert!(Action::parse("kill", vec!["x".into()]).is_err());
+        assert_eq!(Action::parse("kill", vec![]), Ok(Action::Kill));
+        assert_eq!(shell_join(&["it's".to_string()]), r"'it'\''s'");
+    }
+
+    #[test]
+    fn schedule_add_and_prune() {
+        let mut schedule = Schedule::default();
+        let a = schedule.add(Trigger::At { time: 10 }, Action::Kill, "scratch/*", false);
+        let b = schedule.add(
+            Trigger::Every {
+                interval: 3600,
+                since: 0,
+            },
+            Action::Suspend,
+            "*",
+            true,
+        );
+        assert_eq!((a, b), (1, 2));
+        schedule.prune(20);
+        assert_eq!(schedule.jobs.len(), 1);
+        assert_eq!(
+            schedule.jobs[0].describe(0),
+            "every 1h suspend * (if detached)"
+        );
+        assert!(schedule.remove(2));
+        assert!(!schedule.remove(2));
+    }
+}
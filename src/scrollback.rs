@@ -0,0 +1,332 @@
+//! Raw PTY output history kept in a fixed-size ring buffer backed by a
+//! memory-mapped file (`pterm new --scrollback-file <size>`).
+//!
+//! The file, `<session_dir>/scrollback`, is mapped with `MAP_SHARED`, so
+//! hundreds of megabytes of history are page cache rather than daemon heap,
+//! and the contents outlive the daemon process: a hot upgrade reopens the
+//! same file and keeps appending, and `pterm scrollback` reads it without
+//! the daemon.
+//!
+//! File layout: a 64-byte header (magic, capacity, total bytes ever written;
+//! little-endian u64s) followed by `capacity` bytes of ring data. Appending
+//! copies the new bytes (at most `capacity` of them) and bumps the counter.
+//!
+//! With `--strip-colors`, SGR sequences are dropped before the bytes are
+//! stored, so archived logs are plain text; live output is not affected.
+//!
+//! A `--split-stderr` session also keeps what the child wrote to stderr in
+//! `<session_dir>/stderr`, read with `pterm scrollback --stderr`; the
+//! scrollback file itself gets both streams, interleaved as on screen.
+
+use nix::libc;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+/// Scrollback file name within a session directory.
+pub const SCROLLBACK_FILENAME: &str = "scrollback";
+/// File of a `--split-stderr` session's stderr, kept apart from the
+/// scrollback in the same format.
+pub const STDERR_FILENAME: &str = "stderr";
+/// Size of the stderr file unless `--scrollback-file` sets one.
+pub const DEFAULT_STDERR_FILE_SIZE: usize = 4 << 20;
+
+const MAGIC: &[u8; 8] = b"PTSB\x01\0\0\0";
+const HEADER_SIZE: usize = 64;
+const CAPACITY_OFFSET: usize = 8;
+const WRITTEN_OFFSET: usize = 16;
+
+pub struct ScrollbackBuffer {
+    mapping: Mapping,
+    capacity: usize,
+    /// Total bytes appended over the buffer's lifetime.
+    written: u64,
+    /// Set by `--strip-colors`.
+    stripper: Option<SgrStripper>,
+}
+
+/// A `MAP_SHARED` mapping of a whole scrollback file.
+struct Mapping {
+    ptr: *mut u8,
+    len: usize,
+    // Keeps the file open for as long as it is mapped.
+    _file: File,
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr as *mut libc::c_void, self.len);
+        }
+    }
+}
+
+impl Mapping {
+    fn bytes(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+
+    fn bytes_mut(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr, self.len) }
+    }
+}
+
+impl ScrollbackBuffer {
+    /// Map the scrollback file at `path`, creating it with room for
+    /// `capacity` bytes. An existing file of the same capacity is reused
+    /// with its contents; anything else is reinitialized.
+    pub fn open_file(path: &Path, capacity: usize) -> io::Result<Self> {
+        if capacity == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "scrollback file size must be positive",
+            ));
+        }
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .mode(0o600)
+            .open(path)?;
+        let len = HEADER_SIZE + capacity;
+        let existing = file.metadata()?.len() == len as u64;
+        file.set_len(len as u64)?;
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        let mut mapping = Mapping {
+            ptr: ptr as *mut u8,
+            len,
+            _file: file,
+        };
+        let written = match parse_header(mapping.bytes()) {
+            Some((cap, written)) if existing && cap == capacity as u64 => written,
+            _ => {
+                let header = &mut mapping.bytes_mut()[..HEADER_SIZE];
+                header.fill(0);
+                header[..MAGIC.len()].copy_from_slice(MAGIC);
+                header[CAPACITY_OFFSET..CAPACITY_OFFSET + 8]
+                    .copy_from_slice(&(capacity as u64).to_le_bytes());
+                0
+            }
+        };
+        Ok(Self {
+            mapping,
+            capacity,
+            written,
+            stripper: None,
+        })
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Bytes the file currently holds: all written so far, up to capacity.
+    pub fn retained(&self) -> usize {
+        self.written.min(self.capacity as u64) as usize
+    }
+
+    /// Drop SGR (color and text attribute) sequences from everything
+    /// appended from now on.
+    pub fn set_strip_colors(&mut self, enabled: bool) {
+        self.stripper = enabled.then(SgrStripper::default);
+    }
+
+    pub fn strips_colors(&self) -> bool {
+        self.stripper.is_some()
+    }
+
+    /// Append `data`, overwriting the oldest bytes once full. Costs
+    /// O(`data.len()`) regardless of how much is retained.
+    pub fn append(&mut self, data: &[u8]) {
+        match self.stripper.as_mut() {
+            Some(stripper) => {
+                let mut stripped = Vec::with_capacity(data.len());
+                stripper.filter(data, &mut stripped);
+                self.store(&stripped);
+            }
+            None => self.store(data),
+        }
+    }
+
+    fn store(&mut self, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        let skipped = data.len().saturating_sub(self.capacity);
+        let tail = &data[skipped..];
+        let capacity = self.capacity;
+        let start = ((self.written + skipped as u64) % capacity as u64) as usize;
+        let first = tail.len().min(capacity - start);
+        let bytes = self.mapping.bytes_mut();
+        let ring = &mut bytes[HEADER_SIZE..];
+        ring[start..start + first].copy_from_slice(&tail[..first]);
+        ring[..tail.len() - first].copy_from_slice(&tail[first..]);
+        self.written += data.len() as u64;
+        bytes[WRITTEN_OFFSET..WRITTEN_OFFSET + 8].copy_from_slice(&self.written.to_le_bytes());
+    }
+}
+
+/// Removes SGR sequences (`CSI <digits ; :> m`) from a byte stream that may
+/// split them across chunks. Every other byte, including other escape
+/// sequences, passes through unchanged.
+#[derive(Debug, Default)]
+pub struct SgrStripper {
+    /// Start of a possible SGR sequence, held until it is complete.
+    pending: Vec<u8>,
+}
+
+impl SgrStripper {
+    /// Longest sequence held back; longer ones are not SGR in practice.
+    const MAX_PENDING: usize = 64;
+
+    pub fn filter(&mut self, input: &[u8], output: &mut Vec<u8>) {
+        for &byte in input {
+            match (self.pending.len(), byte) {
+                (0, 0x1b) => self.pending.push(byte),
+                (0, _) => output.push(byte),
+                (1, b'[') => self.pending.push(byte),
+                (n, b'm') if n >= 2 => self.pending.clear(),
+                (n, b'0'..=b'9' | b';' | b':') if (2..Self::MAX_PENDING).contains(&n) => {
+                    self.pending.push(byte)
+                }
+                (_, 0x1b) => {
+                    output.append(&mut self.pending);
+                    self.pending.push(byte);
+                }
+                _ => {
+                    output.append(&mut self.pending);
+                    output.push(byte);
+                }
+            }
+        }
+    }
+}
+
+/// Capacity and written counter from a scrollback file header.
+fn parse_header(data: &[u8]) -> Option<(u64, u64)> {
+    if data.len() < HEADER_SIZE || &data[..MAGIC.len()] != MAGIC {
+        return None;
+    }
+    let field = |offset: usize| u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap());
+    Some((field(CAPACITY_OFFSET), field(WRITTEN_OFFSET)))
+}
+
+fn ring_contents(ring: &[u8], written: u64) -> Vec<u8> {
+    let capacity = ring.len();
+    if capacity == 0 {
+        return Vec::new();
+    }
+    if written <= capacity as u64 {
+        return ring[..written as usize].to_vec();
+    }
+    let start = (written % capacity as u64) as usize;
+    let mut out = Vec::with_capacity(capacity);
+    out.extend_from_slice(&ring[start..]);
+    out.extend_from_slice(&ring[..start]);
+    out
+}
+
+/// Read the retained contents of a scrollback file without mapping it (for
+/// the CLI, which may run while the daemon keeps appending).
+pub fn read_file(path: &Path) -> io::Result<Vec<u8>> {
+    let data = std::fs::read(path)?;
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "not a pterm scrollback file");
+    let (capacity, written) = parse_header(&data).ok_or_else(invalid)?;
+    let ring = data
+        .get(HEADER_SIZE..HEADER_SIZE + capacity as usize)
+        .ok_or_else(invalid)?;
+    Ok(ring_contents(ring, written))
+}
+
+/// Parse a byte size such as `65536`, `512K`, `256M` or `1G`.
+pub fn parse_size(s: &str) -> Option<usize> {
+    let s = s.trim();
+    let (number, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, ""),
+    };
+    let multiplier: usize = match unit.to_ascii_uppercase().trim_end_matches(['B', 'I']) {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        _ => return None,
+    };
+    number.parse::<usize>().ok()?.checked_mul(multiplier)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ring_keeps_newest_bytes_across_reopen() {
+        let dir = std::env::temp_dir().join(format!("pterm-scrollback-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(SCROLLBACK_FILENAME);
+
+        let mut buffer = ScrollbackBuffer::open_file(&path, 8).unwrap();
+        buffer.append(b"hello");
+        assert_eq!(read_file(&path).unwrap(), b"hello");
+        assert_eq!(buffer.retained(), 5);
+        buffer.append(b" world");
+        assert_eq!(read_file(&path).unwrap(), b"lo world");
+        assert_eq!(buffer.retained(), 8);
+        buffer.append(b"0123456789abc");
+        assert_eq!(read_file(&path).unwrap(), b"56789abc");
+        drop(buffer);
+
+        let mut buffer = ScrollbackBuffer::open_file(&path, 16).unwrap();
+        buffer.append(b"first line\r\n");
+        drop(buffer);
+
+        let mut buffer = ScrollbackBuffer::open_file(&path, 16).unwrap();
+        buffer.append(b"second\r\n");
+        assert_eq!(read_file(&path).unwrap(), b"t line\r\nsecond\r\n");
+
+        // A different capacity starts over.
+        let _buffer = ScrollbackBuffer::open_file(&path, 32).unwrap();
+        assert!(read_file(&path).unwrap().is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn sgr_stripper_handles_split_sequences() {
+        let mut stripper = SgrStripper::default();
+        let mut out = Vec::new();
+        stripper.filter(b"\x1b[1;31mred\x1b[", &mut out);
+        stripper.filter(b"0m \x1b[38:5:2mok\x1b[m", &mut out);
+        assert_eq!(out, b"red ok");
+
+        // Other sequences, a lone ESC and a split non-SGR CSI survive.
+        out.clear();
+        stripper.filter(b"\x1b[2J\x1b\x1b[?25l\x1b[3", &mut out);
+        stripper.filter(b"A\x1b]0;t\x07", &mut out);
+        assert_eq!(out, b"\x1b[2J\x1b\x1b[?25l\x1b[3A\x1b]0;t\x07");
+    }
+
+    #[test]
+    fn parse_size_units() {
+        assert_eq!(parse_size("4096"), Some(4096));
+        assert_eq!(parse_size("512K"), Some(512 << 10));
+        assert_eq!(parse_size("256MiB"), Some(256 << 20));
+        assert_eq!(parse_size("1g"), Some(1 << 30));
+        assert_eq!(parse_size("12X"), None);
+        assert_eq!(parse_size(""), None);
+    }
+}
@@ -0,0 +1,239 @@
+//! `pterm self-install` / `pterm self-update`: fetch the prebuilt release
+//! binary for this platform, check its signature and install it.
+//!
+//! The release workflow attaches a static binary per platform, named
+//! `pterm-<arch>-<os>` (e.g. `pterm-x86_64-linux`), and an OpenSSH signature
+//! of it, `<asset>.sig` (`ssh-keygen -Y sign -n pterm-release`). Downloads go
+//! through `curl` and verification through `ssh-keygen -Y verify`, so pterm
+//! carries no HTTP or crypto code of its own. The key the release workflow
+//! builds in (`PTERM_RELEASE_KEY` at build time) is always trusted. Another
+//! key, from `$PTERM_EXTRA_RELEASE_KEY` or `release_key` in the config, is
+//! trusted as well only with `trust_release_key = true` in the config, so
+//! an environment variable alone cannot change what gets installed. Without
+//! a key nothing is installed.
+
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+const RELEASES_URL: &str = "https://github.com/ttak0422/pterm/releases";
+/// `ssh-keygen -Y` namespace of release signatures, so a signature made
+/// with the same key for another purpose does not verify.
+const SIGNATURE_NAMESPACE: &str = "pterm-release";
+/// Release key built in by the release workflow. The extra key is read
+/// from another variable, so a key set for `self-update` is not built into
+/// the next `cargo install`.
+const BUILTIN_KEY: Option<&str> = option_env!("PTERM_RELEASE_KEY");
+
+/// The trusted release keys: the built-in one and, if `trust_extra`, also
+/// `$PTERM_EXTRA_RELEASE_KEY`, else `configured`.
+pub fn release_keys(configured: Option<&str>, trust_extra: bool) -> Vec<String> {
+    let extra = std::env::var("PTERM_EXTRA_RELEASE_KEY")
+        .ok()
+        .or_else(|| configured.map(str::to_string));
+    trusted_keys(BUILTIN_KEY, extra.as_deref(), trust_extra)
+}
+
+fn trusted_keys(builtin: Option<&str>, extra: Option<&str>, trust_extra: bool) -> Vec<String> {
+    let extra = extra.filter(|_| trust_extra);
+    builtin
+        .into_iter()
+        .chain(extra)
+        .map(|key| key.trim().to_string())
+        .filter(|key| !key.is_empty())
+        .collect()
+}
+
+/// Release asset for this platform.
+pub fn asset_name() -> String {
+    format!("pterm-{}-{}", std::env::consts::ARCH, std::env::consts::OS)
+}
+
+/// Release tag for `version`, e.g. `v0.2.0`.
+pub fn tag(version: &str) -> String {
+    format!("v{}", version.trim_start_matches('v'))
+}
+
+/// Tag of the latest release, from where GitHub redirects `latest` to.
+pub fn latest_tag() -> Result<String, String> {
+    let output = Command::new("curl")
+        .args(["-fsSL", "-o", "/dev/null", "-w", "%{url_effective}"])
+        .arg(format!("{}/latest", RELEASES_URL))
+        .output()
+        .map_err(|e| format!("curl: {}", e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "cannot reach {}: {}",
+            RELEASES_URL,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    let url = String::from_utf8_lossy(&output.stdout);
+    match url.rsplit_once("/tag/") {
+        Some((_, tag)) if !tag.is_empty() => Ok(tag.to_string()),
+        _ => Err(format!("no release found at {}", RELEASES_URL)),
+    }
+}
+
+/// Download, verify and install the release `tag` as `dest`, replacing it
+/// atomically. The binary must report the tag's version before it replaces
+/// anything.
+pub fn install(tag: &str, dest: &Path, keys: &[String]) -> Result<(), String> {
+    let dir = dest.parent().unwrap_or(Path::new("."));
+    std::fs::create_dir_all(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+    let staging = dir.join(format!(".pterm-download-{}", std::process::id()));
+    let signature = staging.with_extension("sig");
+    let result = (|| {
+        let asset = format!("{}/download/{}/{}", RELEASES_URL, tag, asset_name());
+        fetch(&asset, &staging)?;
+        fetch(&format!("{}.sig", asset), &signature)?;
+        verify(&staging, &signature, keys)?;
+        std::fs::set_permissions(&staging, std::fs::Permissions::from_mode(0o755))
+            .map_err(|e| e.to_string())?;
+        check_version(&staging, tag)?;
+        std::fs::rename(&staging, dest).map_err(|e| format!("{}: {}", dest.display(), e))
+    })();
+    let _ = std::fs::remove_file(&staging);
+    let _ = std::fs::remove_file(&signature);
+    result
+}
+
+fn fetch(url: &str, dest: &Path) -> Result<(), String> {
+    let status = Command::new("curl")
+        .args(["-fsSL", "-o"])
+        .arg(dest)
+        .arg(url)
+        .status()
+        .map_err(|e| format!("curl: {}", e))?;
+    if !status.success() {
+        return Err(format!("download of {} failed", url));
+    }
+    Ok(())
+}
+
+/// Check that the OpenSSH signature `signature` of `file` was made with one
+/// of `keys`.
+pub fn verify(file: &Path, signature: &Path, keys: &[String]) -> Result<(), String> {
+    let signers = signature.with_extension("signers");
+    let entries: String = keys
+        .iter()
+        .map(|key| {
+            format!(
+                "{} namespaces=\"{}\" {}\n",
+                SIGNATURE_NAMESPACE,
+                SIGNATURE_NAMESPACE,
+                key.trim()
+            )
+        })
+        .collect();
+    std::fs::write(&signers, entries).map_err(|e| e.to_string())?;
+    let data = std::fs::File::open(file).map_err(|e| format!("{}: {}", file.display(), e))?;
+    let output = Command::new("ssh-keygen")
+        .args(["-Y", "verify", "-f"])
+        .arg(&signers)
+        .args(["-I", SIGNATURE_NAMESPACE, "-n", SIGNATURE_NAMESPACE, "-s"])
+        .arg(signature)
+        .stdin(data)
+        .output();
+    let _ = std::fs::remove_file(&signers);
+    let output = output.map_err(|e| format!("ssh-keygen: {}", e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "signature check failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(())
+}
+
+/// Run the downloaded binary and make sure it is the release asked for.
+fn check_version(binary: &Path, tag: &str) -> Result<(), String> {
+    let output = Command::new(binary)
+        .args(["version", "--json"])
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|e| format!("cannot run the downloaded binary: {}", e))?;
+    let version: serde_json::Value = serde_json::from_slice(&output.stdout)
+        .map_err(|_| "the downloaded binary does not run on this system".to_string())?;
+    let version = version["version"].as_str().unwrap_or_default();
+    if self::tag(version) != tag {
+        return Err(format!("{} reports version {}", tag, version));
+    }
+    Ok(())
+}
+
+/// Default directory of `pterm self-install`.
+pub fn default_install_dir() -> Option<PathBuf> {
+    let home = std::env::var_os("HOME")?;
+    Some(PathBuf::from(home).join(".local/bin"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verifies_release_signatures_with_ssh_keygen() {
+        if Command::new("ssh-keygen").arg("-?").output().is_err() {
+            return;
+        }
+        let dir =
+            std::env::temp_dir().join(format!("pterm-selfupdate-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let keygen = |args: &[&str]| {
+            let status = Command::new("ssh-keygen")
+                .args(args)
+                .current_dir(&dir)
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .status()
+                .unwrap();
+            assert!(status.success(), "ssh-keygen {:?}", args);
+        };
+        keygen(&["-q", "-t", "ed25519", "-N", "", "-f", "key"]);
+        std::fs::write(dir.join("pterm"), b"release binary").unwrap();
+        keygen(&[
+            "-Y",
+            "sign",
+            "-f",
+            "key",
+            "-n",
+            SIGNATURE_NAMESPACE,
+            "pterm",
+        ]);
+        keygen(&["-q", "-t", "ed25519", "-N", "", "-f", "other"]);
+        let key = vec![std::fs::read_to_string(dir.join("key.pub")).unwrap()];
+        let other = vec![std::fs::read_to_string(dir.join("other.pub")).unwrap()];
+        let (binary, signature) = (dir.join("pterm"), dir.join("pterm.sig"));
+
+        assert_eq!(verify(&binary, &signature, &key), Ok(()));
+        assert!(verify(&binary, &signature, &other).is_err());
+        let both = [other.clone(), key.clone()].concat();
+        assert_eq!(verify(&binary, &signature, &both), Ok(()));
+        std::fs::write(&binary, b"tampered binary").unwrap();
+        assert!(verify(&binary, &signature, &key).is_err());
+
+        // A signature for another namespace does not count.
+        std::fs::write(&binary, b"release binary").unwrap();
+        std::fs::remove_file(&signature).unwrap();
+        keygen(&["-Y", "sign", "-f", "key", "-n", "file", "pterm"]);
+        assert!(verify(&binary, &signature, &key).is_err());
+
+        assert_eq!(tag("0.2.0"), "v0.2.0");
+        assert_eq!(tag("v0.2.0"), "v0.2.0");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn an_extra_key_is_trusted_only_next_to_the_builtin_one_and_when_opted_in() {
+        let (builtin, extra) = (Some("ssh-ed25519 BUILTIN"), Some("ssh-ed25519 EXTRA\n"));
+        assert_eq!(trusted_keys(builtin, extra, false), ["ssh-ed25519 BUILTIN"]);
+        assert_eq!(
+            trusted_keys(builtin, extra, true),
+            ["ssh-ed25519 BUILTIN", "ssh-ed25519 EXTRA"]
+        );
+        assert!(trusted_keys(None, extra, false).is_empty());
+        assert_eq!(trusted_keys(None, extra, true), ["ssh-ed25519 EXTRA"]);
+        assert!(trusted_keys(None, Some("  "), true).is_empty());
+    }
+}
@@ -1,31 +1,673 @@
+use crate::clients::{ClientIdentity, ClientInfo, PeerIdentity};
+use crate::config::Buffers;
+use crate::exec::{self, StatusScanner};
+use crate::guard;
+use crate::history::HistoryWriter;
+use crate::hook::{self, ExitHook};
+use crate::meta::{self, SessionMeta};
+use crate::options::{Detached, Latency, SessionOptions, Throttle};
+use crate::paths::{self, SocketHome};
+use crate::recording::{self, Recorder};
+use crate::registry;
+use crate::sandbox;
+use crate::schedule::{self, Scheduler};
+use crate::scrollback::{ScrollbackBuffer, SCROLLBACK_FILENAME, STDERR_FILENAME};
 use crate::session::Session;
+use crate::session_key;
+use crate::share::{Guest, Shares};
+use crate::stats::CpuSampler;
+use crate::upgrade;
+use crate::watch::Watchers;
 use mio::net::{UnixListener, UnixStream};
 use mio::{Events, Interest, Poll, Token};
 use pterm_proto::{self as proto};
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::io::{self, Read, Write};
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
 use std::os::unix::fs::FileTypeExt;
 use std::path::{Path, PathBuf};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 const LISTENER: Token = Token(0);
 const PTY_BASE: Token = Token(0x1000_0000);
+/// The child's stderr pipe with `--split-stderr`.
+const STDERR: Token = Token(PTY_BASE.0 + 1);
 const CLIENT_BASE: Token = Token(0x2000_0000);
 const DA1_RESPONSE: &[u8] = b"\x1b[?62;22c"; // Primary Device Attributes (DA1)
 const DA2_RESPONSE: &[u8] = b"\x1b[>1;10;0c"; // Secondary Device Attributes (DA2)
 const DA_QUERY_WARN_THRESHOLD: usize = 2;
-const LARGE_SEND_BUF_WARN_BYTES: usize = 64 * 1024;
+/// Minimum interval between metadata rewrites caused only by activity or
+/// statistics.
+const META_ACTIVITY_WRITE_INTERVAL: Duration = Duration::from_secs(5);
+/// How often the session's CPU time is sampled from `/proc`.
+const CPU_SAMPLE_INTERVAL: Duration = Duration::from_secs(5);
+/// Attached clients silent for this long are sent a PING.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(30);
+/// A client that has answered PINGs before and leaves one unanswered for
+/// this long is considered gone.
+const KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(30);
+/// A client whose send buffer makes no progress for this long is considered
+/// gone (e.g. a bridge stuck writing to a dead terminal).
+const SEND_STALL_TIMEOUT: Duration = Duration::from_secs(120);
+/// Minimum time between two `PRESENCE` frames for the same client.
+const PRESENCE_INTERVAL: Duration = Duration::from_secs(1);
+/// How often a daemon whose socket root disappeared tries to re-create its
+/// socket, or to move back from the fallback socket root.
+const SOCKET_RECOVERY_INTERVAL: Duration = Duration::from_secs(1);
+/// Note left in the session directory when the socket was re-created.
+pub const RECOVERY_NOTE_FILENAME: &str = "recovered";
+/// Output produced while no client is attached, with `detached log`.
+pub const DETACHED_LOG_FILENAME: &str = "detached.log";
+/// Input held by the `guard` option is dropped if its client does not
+/// confirm within this time.
+const GUARD_CONFIRM_TIMEOUT: Duration = Duration::from_secs(60);
+/// How long `pterm respawn --force` waits for the killed command to exit.
+const RESPAWN_KILL_TIMEOUT: Duration = Duration::from_secs(2);
+/// With `latency throughput`, PTY output is held for up to this long so that
+/// consecutive reads go out as one OUTPUT frame.
+const OUTPUT_BATCH_INTERVAL: Duration = Duration::from_millis(20);
+/// Input streams (`client::INPUT_STREAM`) are remembered this long after
+/// their last use, so a client reconnecting within it does not apply
+/// resent input twice.
+const INPUT_STREAM_WINDOW: Duration = Duration::from_secs(600);
+/// Most input streams remembered; the least recently used is forgotten
+/// first.
+const MAX_INPUT_STREAMS: usize = 64;
+/// Connections per second a peer may open, with up to a second of burst;
+/// further connections wait. Guards against a client reconnecting in a
+/// loop, each attach costing a snapshot.
+const CONNECT_RATE: u64 = 20;
+/// Connections waiting for `CONNECT_RATE` beyond this are closed.
+const MAX_HELD_CONNECTIONS: usize = 256;
+/// What a guest (`pterm share`) may send once it redeemed its token: enough
+/// to attach read-only and stay connected.
+const GUEST_FRAMES: &[u8] = &[
+    proto::client::ATTACH,
+    proto::client::IDENTIFY,
+    proto::client::READ_ONLY,
+    proto::client::RESIZE,
+    proto::client::CREDIT,
+    proto::client::PONG,
+    proto::client::DETACH,
+];
+/// Smallest output window (`HELLO` `window`) granted; a snapshot has to fit
+/// comfortably, or a client would be redrawn after every one.
+const MIN_OUTPUT_WINDOW: u64 = 64 * 1024;
+
+/// Smallest `OUTPUT` / `STATE_SYNC` payload compressed for clients that
+/// negotiated compression; keystroke echoes and the like go out plain.
+const COMPRESS_MIN_LEN: usize = 256;
+/// zstd level of output compression, cheap enough to run on every PTY read.
+const OUTPUT_COMPRESSION_LEVEL: i32 = 1;
+
+/// Whose connections share a rate limit: the peer's uid and parent process,
+/// so a plugin spawning a new bridge for every reconnect is one peer.
+type PeerKey = (Option<u32>, Option<i32>);
+
+/// Input from the Enter of a guarded command line on, waiting for the
+/// client that typed it to confirm.
+struct HeldInput {
+    client_id: usize,
+    id: u32,
+    data: Vec<u8>,
+    since: Instant,
+}
+
+/// Sequence numbers seen in an input stream (`client::INPUT_STREAM`).
+struct InputStream {
+    /// Last sequence number applied.
+    applied: u64,
+    last_used: Instant,
+}
+
+/// A `pterm exec` command waiting for its status mark.
+struct PendingExec {
+    client_id: usize,
+    id: u32,
+    /// Typed with the status `printf`, so its mark carries `exec::aid(id)`.
+    wrapped: bool,
+}
 
 struct Client {
     stream: UnixStream,
-    recv_buf: Vec<u8>,
+    decoder: proto::FrameDecoder,
     send_buf: Vec<u8>,
+    /// Length of the head of `send_buf` that finishes a frame already partly
+    /// written to the socket.
+    in_flight: usize,
     large_send_buf_warned: bool,
     /// `true` until the initial snapshot has been sent.
     pending_snapshot: bool,
+    /// `Server::output_seq` when the client's last snapshot was taken.
+    synced_seq: u64,
+    /// `true` once the client has sent RESIZE, i.e. it is an interactive
+    /// attach rather than a one-shot control connection such as `redraw`.
+    attached: bool,
+    /// When the client last sent anything.
+    last_recv: Instant,
+    /// When an unanswered PING was sent.
+    ping_sent: Option<Instant>,
+    /// `true` once the client has answered a PING. Older bridges ignore
+    /// PING, so only clients known to answer are reaped by keepalive.
+    keepalive: bool,
+    /// Since when `send_buf` has been non-empty without any write progress.
+    send_stalled_since: Option<Instant>,
+    /// Attached as a viewer: INPUT and RESIZE are ignored.
+    read_only: bool,
+    peer: PeerIdentity,
+    /// What the client announced in `client::IDENTIFY`.
+    identity: Option<ClientIdentity>,
+    /// Unix timestamp (seconds) of the connection.
+    connected_at: u64,
+    bytes_sent: u64,
+    bytes_received: u64,
+    /// When `PRESENCE` was last broadcast for this client's input.
+    last_presence: Option<Instant>,
+    /// Send-buffer backlog that is logged as a warning.
+    send_buffer_warn: usize,
+    /// Input stream bound with `client::INPUT_STREAM`.
+    input_stream: Option<u64>,
+    /// Negotiated zstd compression in `HELLO`: `OUTPUT` and `STATE_SYNC` go
+    /// out compressed where that saves bytes.
+    compression: bool,
+    /// Asked for `STDERR_OUTPUT` in `HELLO`.
+    stderr: bool,
+    /// Asked for `OUTPUT_SEQ` in `HELLO`.
+    output_seq: bool,
+    /// Asked for the exit details in `EXIT` in `HELLO`.
+    exit_details: bool,
+    /// Frame checksums negotiated in `HELLO`, applied to every frame after
+    /// the answer.
+    framing: proto::Framing,
+    /// Encryption of the connection, on an encrypted session.
+    sealing: Sealing,
+    /// Output window negotiated in `HELLO`, if any.
+    credit: Option<Credit>,
+    /// `FRAME_MARK`s sent so far, if the client asked for them in `HELLO`.
+    frame_marks: Option<u64>,
+    /// Set for a connection from another user, let in by `pterm share`.
+    guest: Option<Guest>,
+    /// Output stream position to resume from instead of taking the attach
+    /// snapshot (`HELLO` `resume`).
+    resume_from: Option<u64>,
+    /// How the attach snapshot is replayed (`client::ATTACH`).
+    replay: AttachReplay,
+}
+
+/// Flow control of a client that asked for an output window: terminal
+/// output stops going out to it while more than `window` bytes of it are
+/// unacknowledged, rather than piling up in its send buffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Credit {
+    window: u64,
+    /// Output bytes sent and not yet returned with `client::CREDIT`.
+    unacked: u64,
+    /// Output was held back; the client is redrawn once it has room.
+    missed: bool,
+}
+
+impl Credit {
+    fn new(window: u64) -> Self {
+        Self {
+            window,
+            unacked: 0,
+            missed: false,
+        }
+    }
+}
+
+/// Encryption of a client's connection.
+enum Sealing {
+    /// The session is not encrypted.
+    Off,
+    /// The session is encrypted and the client has not encrypted its
+    /// connection yet: nothing but the `HELLO` answer goes out to it, and
+    /// anything but `HELLO` from it is refused.
+    Required,
+    /// Frames are sealed both ways, from the `HELLO` answer on.
+    On(proto::crypto::Channel),
+}
+
+/// Replay options a client sends with `client::ATTACH`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct AttachReplay {
+    /// Skip the snapshot: the client already shows the session.
+    skip: bool,
+    /// Newest scrollback lines replayed ahead of the screen.
+    lines: usize,
+    /// Limit on the size of the replayed scrollback.
+    bytes: Option<usize>,
+}
+
+impl AttachReplay {
+    fn parse(payload: &[u8]) -> Self {
+        let request: serde_json::Value = serde_json::from_slice(payload).unwrap_or_default();
+        let size = |key: &str| request[key].as_u64().map(|n| n as usize);
+        Self {
+            skip: request["skip_replay"].as_bool().unwrap_or(false),
+            lines: size("scrollback_lines").unwrap_or(0),
+            bytes: size("scrollback_bytes"),
+        }
+    }
+}
+
+/// Which clients a `client::DETACH_CLIENTS` detaches, and why.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+struct DetachRequest {
+    /// One client, or every attached client but the sender.
+    client: Option<usize>,
+    reason: Option<String>,
+}
+
+impl DetachRequest {
+    /// Parse the payload: empty, a client id, or JSON.
+    fn parse(payload: &[u8]) -> Result<Self, String> {
+        if payload.is_empty() {
+            return Ok(Self::default());
+        }
+        if let Ok(id) = proto::parse_client_id(payload) {
+            return Ok(Self {
+                client: Some(id as usize),
+                reason: None,
+            });
+        }
+        let request: serde_json::Value =
+            serde_json::from_slice(payload).map_err(|e| e.to_string())?;
+        if !request.is_object() {
+            return Err("expected {\"client\": n, \"reason\": \"...\"}".to_string());
+        }
+        Ok(Self {
+            client: request["client"].as_u64().map(|id| id as usize),
+            reason: request["reason"].as_str().map(str::to_string),
+        })
+    }
+}
+
+/// A `client::COMMAND` envelope.
+#[derive(Debug, Clone, PartialEq)]
+struct Command {
+    cmd: String,
+    /// `null` when the command takes no arguments.
+    args: serde_json::Value,
+}
+
+impl Command {
+    fn parse(payload: &[u8]) -> Result<Self, String> {
+        let mut envelope: serde_json::Value =
+            serde_json::from_slice(payload).map_err(|e| e.to_string())?;
+        let Some(cmd) = envelope["cmd"].as_str() else {
+            return Err("expected {\"cmd\": \"...\", \"args\": {...}}".to_string());
+        };
+        Ok(Self {
+            cmd: cmd.to_string(),
+            args: envelope["args"].take(),
+        })
+    }
+}
+
+/// The variable `setenv` arguments set (`Some` value) or unset (`None`).
+fn env_change(args: &serde_json::Value) -> Result<(String, Option<String>), String> {
+    let name = args["name"].as_str().ok_or("setenv needs a \"name\"")?;
+    if name.is_empty() || name.contains(['=', '\0']) {
+        return Err(format!("invalid variable name '{}'", name));
+    }
+    if name == paths::SESSION_ENV || name == paths::SOCKET_ENV {
+        return Err(format!("{} is set by pterm", name));
+    }
+    let value = match &args["value"] {
+        serde_json::Value::Null => None,
+        serde_json::Value::String(value) if !value.contains('\0') => Some(value.clone()),
+        _ => return Err("setenv \"value\" must be a string or null".to_string()),
+    };
+    Ok((name.to_string(), value))
+}
+
+/// An `OUTPUT` or `STATE_SYNC` frame, encoded plain and, once a client
+/// that negotiated compression needs it, compressed, so a broadcast
+/// compresses only once however many clients get it.
+struct OutputFrame<'a> {
+    msg_type: u8,
+    payload: &'a [u8],
+    /// The daemon's number for the frame in `FRAME_MARK`.
+    number: u64,
+    crc32: Option<u32>,
+    plain: Vec<u8>,
+    /// `Some(None)` once compressing turned out not to save anything.
+    compressed: Option<Option<Vec<u8>>>,
+}
+
+impl<'a> OutputFrame<'a> {
+    fn new(msg_type: u8, payload: &'a [u8], number: u64) -> Self {
+        Self {
+            msg_type,
+            payload,
+            number,
+            crc32: None,
+            plain: proto::encode(msg_type, payload),
+            compressed: None,
+        }
+    }
+
+    /// The frame to queue for a client with or without `compression`.
+    fn encoded(&mut self, compression: bool) -> &[u8] {
+        if !compression || self.payload.len() < COMPRESS_MIN_LEN {
+            return &self.plain;
+        }
+        let (msg_type, payload) = (self.msg_type, self.payload);
+        match self
+            .compressed
+            .get_or_insert_with(|| compress_frame(msg_type, payload))
+        {
+            Some(compressed) => compressed,
+            None => &self.plain,
+        }
+    }
+
+    /// The `FRAME_MARK` announcing this frame as the `seq`th marked frame
+    /// of a client.
+    fn mark(&mut self, seq: u64) -> Vec<u8> {
+        let payload = self.payload;
+        let mark = serde_json::json!({
+            "frame": self.number,
+            "seq": seq,
+            "type": self.msg_type,
+            "len": payload.len(),
+            "crc32": *self.crc32.get_or_insert_with(|| proto::crc32(payload)),
+        });
+        proto::encode(proto::server::FRAME_MARK, mark.to_string().as_bytes())
+    }
+}
+
+/// `payload` as the compressed counterpart of `msg_type`, or `None` if zstd
+/// does not make it smaller.
+fn compress_frame(msg_type: u8, payload: &[u8]) -> Option<Vec<u8>> {
+    let compressed_type = match msg_type {
+        proto::server::OUTPUT => proto::server::COMPRESSED_OUTPUT,
+        proto::server::STATE_SYNC => proto::server::COMPRESSED_STATE_SYNC,
+        _ => return None,
+    };
+    let compressed = zstd::bulk::compress(payload, OUTPUT_COMPRESSION_LEVEL).ok()?;
+    (compressed.len() < payload.len()).then(|| proto::encode(compressed_type, &compressed))
+}
+
+/// The newest bytes of the output stream (every `OUTPUT` and
+/// `STDERR_OUTPUT` payload broadcast), for clients that reconnect and
+/// resume from the stream position they had reached.
+#[derive(Debug, Default)]
+struct ResumeLog {
+    /// Stream position of `data[0]`.
+    start: u64,
+    data: VecDeque<u8>,
+}
+
+impl ResumeLog {
+    fn starting_at(start: u64) -> Self {
+        Self {
+            start,
+            data: VecDeque::new(),
+        }
+    }
+
+    /// Stream position after the last byte broadcast.
+    fn end(&self) -> u64 {
+        self.start + self.data.len() as u64
+    }
+
+    /// Append broadcast bytes, keeping the newest `capacity` of them.
+    fn append(&mut self, bytes: &[u8], capacity: usize) {
+        self.data.extend(bytes);
+        let excess = self.data.len().saturating_sub(capacity);
+        self.data.drain(..excess);
+        self.start += excess as u64;
+    }
+
+    /// Forget the kept bytes, after a change to the screen that is not in
+    /// the stream (a resize).
+    fn reset(&mut self) {
+        *self = Self::starting_at(self.end());
+    }
+
+    /// The bytes broadcast since position `seq`, if all of them are kept.
+    fn since(&self, seq: u64) -> Option<Vec<u8>> {
+        if seq < self.start || seq > self.end() {
+            return None;
+        }
+        Some(
+            self.data
+                .range((seq - self.start) as usize..)
+                .copied()
+                .collect(),
+        )
+    }
+}
+
+impl Client {
+    fn new(stream: UnixStream) -> Self {
+        Self {
+            peer: PeerIdentity::of(&stream),
+            identity: None,
+            stream,
+            decoder: proto::FrameDecoder::new(),
+            send_buf: Vec::new(),
+            in_flight: 0,
+            large_send_buf_warned: false,
+            pending_snapshot: true,
+            synced_seq: 0,
+            attached: false,
+            last_recv: Instant::now(),
+            ping_sent: None,
+            keepalive: false,
+            send_stalled_since: None,
+            read_only: false,
+            connected_at: meta::unix_now(),
+            bytes_sent: 0,
+            bytes_received: 0,
+            last_presence: None,
+            send_buffer_warn: Buffers::default().send_buffer_warn,
+            input_stream: None,
+            compression: false,
+            stderr: false,
+            output_seq: false,
+            exit_details: false,
+            framing: proto::Framing::Plain,
+            sealing: Sealing::Off,
+            credit: None,
+            frame_marks: None,
+            guest: None,
+            resume_from: None,
+            replay: AttachReplay::default(),
+        }
+    }
+
+    fn info(&self, id: usize) -> ClientInfo {
+        ClientInfo {
+            id,
+            peer: self.peer.clone(),
+            connected_at: self.connected_at,
+            bytes_sent: self.bytes_sent,
+            bytes_received: self.bytes_received,
+            pending: self.send_buf.len(),
+            attached: self.attached,
+            read_only: self.read_only,
+            identity: self.identity.clone(),
+        }
+    }
+
+    /// Append an encoded frame to the send buffer. Every outbound frame goes
+    /// through here (or `queue_plain`) and leaves only via `flush`, so
+    /// frames are never reordered, and one a socket cannot take yet is
+    /// kept, not dropped.
+    fn queue(&mut self, frame: &[u8]) {
+        match &mut self.sealing {
+            Sealing::Off => self.framing.append(frame, &mut self.send_buf),
+            Sealing::Required => {}
+            Sealing::On(cipher) => {
+                let mut sealed = Vec::new();
+                cipher.seal_frames(frame, &mut sealed);
+                self.framing.append(&sealed, &mut self.send_buf);
+            }
+        }
+    }
+
+    /// Queue terminal output (`OUTPUT`, `STDERR_OUTPUT`, `STATE_SYNC`),
+    /// counted against the client's window. Returns `false`, queueing
+    /// nothing, if the window is used up.
+    fn queue_output(&mut self, msg: &mut OutputFrame) -> bool {
+        if let Some(credit) = self.credit.as_mut() {
+            if credit.unacked >= credit.window {
+                credit.missed = true;
+                return false;
+            }
+            credit.unacked += msg.payload.len() as u64;
+        }
+        if let Some(marks) = self.frame_marks.as_mut() {
+            *marks += 1;
+            let mark = msg.mark(*marks);
+            self.queue(&mark);
+        }
+        let compression = self.compression;
+        self.queue(msg.encoded(compression));
+        true
+    }
+
+    /// [`queue`](Self::queue) a frame that goes out unsealed even on an
+    /// encrypted session: the `HELLO` answer, or the refusal of a client
+    /// that did not encrypt its connection.
+    fn queue_plain(&mut self, frame: &[u8]) {
+        self.framing.append(frame, &mut self.send_buf);
+    }
+
+    /// Queue `OUTPUT_SEQ` with stream position `seq`, if the client asked
+    /// for it.
+    fn queue_output_seq(&mut self, seq: u64) {
+        if self.output_seq {
+            let payload = serde_json::json!({ "seq": seq }).to_string();
+            self.queue(&proto::encode(
+                proto::server::OUTPUT_SEQ,
+                payload.as_bytes(),
+            ));
+        }
+    }
+
+    /// Drop every queued frame that has not started going out. The rest of
+    /// a partly written frame is kept, so the stream stays framed.
+    fn discard_queued(&mut self) {
+        self.send_buf.truncate(self.in_flight);
+    }
+
+    /// Write as much of the send buffer as the socket accepts without
+    /// blocking. Returns `true` while bytes remain queued.
+    fn flush(&mut self, id: usize) -> io::Result<bool> {
+        let pending_before = self.send_buf.len();
+        while !self.send_buf.is_empty() {
+            match self.stream.write(&self.send_buf) {
+                Ok(0) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::WriteZero,
+                        "client stream closed",
+                    ));
+                }
+                Ok(n) => {
+                    let trailer = self.framing.trailer_len();
+                    self.in_flight = frame_remainder(&self.send_buf, self.in_flight, n, trailer);
+                    self.send_buf.drain(..n);
+                    self.bytes_sent += n as u64;
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+
+        if self.send_buf.len() >= self.send_buffer_warn {
+            if !self.large_send_buf_warned {
+                log::warn!(
+                    "Client {} send buffer backlog reached {} bytes",
+                    id,
+                    self.send_buf.len()
+                );
+                self.large_send_buf_warned = true;
+            }
+        } else {
+            self.large_send_buf_warned = false;
+        }
+
+        if self.send_buf.is_empty() || self.send_buf.len() < pending_before {
+            self.send_stalled_since = None;
+        }
+        if !self.send_buf.is_empty() && self.send_stalled_since.is_none() {
+            self.send_stalled_since = Some(Instant::now());
+        }
+
+        Ok(!self.send_buf.is_empty())
+    }
+
+    /// Why this client should be dropped as a zombie, if it should.
+    fn zombie_reason(&self, now: Instant) -> Option<&'static str> {
+        if self.keepalive
+            && self
+                .ping_sent
+                .is_some_and(|sent| now.duration_since(sent) >= KEEPALIVE_TIMEOUT)
+        {
+            return Some("keepalive timed out");
+        }
+        if self
+            .send_stalled_since
+            .is_some_and(|since| now.duration_since(since) >= SEND_STALL_TIMEOUT)
+        {
+            return Some("send buffer stalled");
+        }
+        None
+    }
+}
+
+/// Bytes left of the frame being written once `written` more bytes of
+/// `buf` have gone out, where the first `in_flight` bytes of `buf` finish a
+/// frame that was already partly written. Each frame is followed by
+/// `trailer` bytes of checksum.
+fn frame_remainder(buf: &[u8], in_flight: usize, written: usize, trailer: usize) -> usize {
+    let mut end = in_flight;
+    while end < written {
+        let header: [u8; proto::HEADER_SIZE] = buf[end..end + proto::HEADER_SIZE]
+            .try_into()
+            .expect("send_buf holds whole frames");
+        end += proto::HEADER_SIZE + proto::decode_header(&header).1 as usize + trailer;
+    }
+    end - written
+}
+
+/// Dimmed banner line plus the optional message of the day, with line
+/// breaks as the terminal expects them.
+fn banner_text(session: &str, created_at: u64, motd: Option<&str>) -> Vec<u8> {
+    let mut text = format!(
+        "\x1b[2m[pterm] session '{}', created {}; detach with `pterm detach {}`\x1b[0m\r\n",
+        session,
+        schedule::local_datetime(created_at),
+        session
+    );
+    if let Some(motd) = motd.filter(|m| !m.is_empty()) {
+        for line in motd.lines() {
+            text.push_str(line);
+            text.push_str("\r\n");
+        }
+    }
+    text.into_bytes()
+}
+
+/// Warning written into an idle session before its `idle-timeout` ends it
+/// at `expires_at`.
+fn idle_warning_text(session: &str, expires_at: u64) -> Vec<u8> {
+    format!(
+        "\r\n\x1b[1;33m[pterm] session '{}' is idle and ends at {}; attach or type to keep it\x1b[0m\r\n",
+        session,
+        schedule::local_datetime(expires_at)
+    )
+    .into_bytes()
 }
 
 pub struct Server {
+    session_dir: PathBuf,
     socket_path: PathBuf,
     session: Session,
     poll: Poll,
@@ -34,8 +676,102 @@ pub struct Server {
     next_client_id: usize,
     /// Accumulated PTY output waiting to be flushed.
     pending_pty_output: Vec<u8>,
+    /// PTY bytes fed to the terminal state so far.
+    output_seq: u64,
+    /// `OUTPUT` / `STATE_SYNC` frames built so far, which numbers them for
+    /// `FRAME_MARK`.
+    output_frames: u64,
+    /// `output_seq` at the last OUTPUT broadcast: `pending_pty_output` holds
+    /// the bytes after it.
+    broadcast_seq: u64,
+    /// Output stream for clients resuming after a reconnect.
+    resume_log: ResumeLog,
     /// `true` after the EXIT message has been broadcast to clients.
     exit_sent: bool,
+    /// I/O recorder enabled by `pterm new --record-io`.
+    recorder: Option<Recorder>,
+    /// Raw output history enabled by `pterm new --scrollback-file`.
+    scrollback: Option<ScrollbackBuffer>,
+    /// The child's stderr with `pterm new --split-stderr`.
+    stderr_file: Option<ScrollbackBuffer>,
+    /// Compressed archive of all output enabled by `pterm new --history`.
+    history: Option<HistoryWriter>,
+    /// Contents of `meta.json`, rewritten on state changes.
+    meta: SessionMeta,
+    /// `true` when `meta.last_activity` or `meta.stats` changed since the
+    /// last write.
+    meta_dirty: bool,
+    last_meta_write: Instant,
+    /// Binary to re-exec into, set by an UPGRADE request.
+    upgrade_to: Option<PathBuf>,
+    options: SessionOptions,
+    /// PTY read rate limit, from the `output-throttle` option.
+    throttle: Option<Throttle>,
+    /// Whether the pty's output is stopped for `detached pause`; `None`
+    /// until first applied, since a daemon from before a hot upgrade may
+    /// have left it stopped.
+    output_stopped: Option<bool>,
+    /// `detached.log` while `detached log` applies; `Some(None)` once it
+    /// failed to open.
+    detached_log: Option<Option<std::fs::File>>,
+    /// PTY reads stopped on the throttle budget rather than `WouldBlock`, so
+    /// no readiness event will arrive for the data still pending.
+    pty_throttled: bool,
+    /// PTY reads stopped at the drain limit with data still pending; the
+    /// next iteration polls without waiting and reads on.
+    pty_backlog: bool,
+    /// When the oldest output in `pending_pty_output` was read, while the
+    /// `latency throughput` option holds it back.
+    batch_started: Option<Instant>,
+    /// Last activity or attached client, for the `idle-timeout` option.
+    idle_since: Instant,
+    /// `idle_since` when the idle-timeout warning was written.
+    idle_warned: Option<Instant>,
+    /// The child's process groups were stopped by a SUSPEND request.
+    suspended: bool,
+    /// Jobs from `pterm at` / `pterm every` targeting this session.
+    scheduler: Scheduler,
+    /// Commands run on matching output (`pterm on-output`).
+    watchers: Watchers,
+    /// The exit hook once started, until reaped.
+    exit_hook_child: Option<std::process::Child>,
+    /// Input held back by the `guard` option until confirmed.
+    held_input: Option<HeldInput>,
+    /// Pids of the processes the pty master was passed to (`PTY_INPUT_FD`),
+    /// whose input does not go through the guard.
+    direct_input: Vec<i32>,
+    next_confirm_id: u32,
+    /// Input streams of clients sending `client::INPUT_SEQ`, by stream id.
+    input_streams: HashMap<u64, InputStream>,
+    /// `pterm exec` commands, oldest first.
+    execs: Vec<PendingExec>,
+    exec_scanner: StatusScanner,
+    next_exec_id: u32,
+    /// Buffer sizes from the config / environment of `pterm new`.
+    buffers: Buffers,
+    /// Uid whose connections are accepted besides root's: the daemon's own
+    /// until `pterm transfer` hands the session over.
+    owner: u32,
+    /// Socket root and session directory the session was created in, for
+    /// sessions that outlive their socket root (see `recover_socket`).
+    socket_home: Option<SocketHome>,
+    /// Persistent socket root to use while `socket_home` cannot be
+    /// re-created.
+    fallback_socket_dir: Option<PathBuf>,
+    last_socket_recovery: Option<Instant>,
+    /// CPU time of the session's processes, persisted as `meta.stats.cpu_ms`.
+    cpu: CpuSampler,
+    last_cpu_sample: Instant,
+    /// Connection rate limits by peer.
+    connect_limits: HashMap<PeerKey, Throttle>,
+    /// Connections over their peer's rate limit, oldest first. They are
+    /// accepted but not read from until the limit lets them in.
+    held_connections: VecDeque<(PeerKey, Client)>,
+    /// Key of an encrypted session: clients must encrypt their connection
+    /// in `HELLO` before anything else.
+    key: Option<[u8; proto::crypto::KEY_LEN]>,
+    /// Tokens from `pterm share`.
+    shares: Shares,
 }
 
 impl Server {
@@ -51,7 +787,7 @@ impl Server {
             std::fs::remove_file(&socket_path)?;
         }
 
-        let mut listener = UnixListener::bind(&socket_path)?;
+        let listener = UnixListener::bind(&socket_path)?;
 
         #[cfg(unix)]
         {
@@ -59,6 +795,125 @@ impl Server {
             std::fs::set_permissions(&socket_path, std::fs::Permissions::from_mode(0o700))?;
         }
 
+        Self::with_listener(session_dir, session, listener, meta::unix_now())
+    }
+
+    /// Rebuild the server after a hot upgrade, adopting the listening socket
+    /// and client connections inherited from the previous daemon image.
+    /// Every adopted client is sent a full redraw so it converges on the
+    /// replayed terminal state.
+    pub fn resume(
+        session_dir: &Path,
+        session: Session,
+        state: &upgrade::UpgradeState,
+    ) -> io::Result<Self> {
+        let listener = unsafe { upgrade::adopt_fd(state.listener_fd)? };
+        let listener = UnixListener::from_std(listener);
+
+        let mut server = Self::with_listener(session_dir, session, listener, state.created_at)?;
+        server.next_client_id = state.next_client_id;
+        server.options = state.options.clone();
+        server.apply_options(&SessionOptions::default());
+        server.suspended = state.suspended;
+        server.meta.stats = state.stats.clone();
+        server.meta.activity = state.activity.clone();
+        server.meta.nested_in = state.nested_in.clone();
+        server.meta.exit_hook = state.exit_hook.clone();
+        server.cpu = CpuSampler::resume(state.child_pid, state.stats.cpu_ms);
+        server.watchers = Watchers::restore(state.watches.clone());
+        server.buffers = state.buffers;
+        server.resume_log = ResumeLog::starting_at(state.stream_position);
+        if let Some(owner) = state.owner {
+            server.owner = owner;
+        }
+        server.socket_home = state.socket_home.clone();
+        server.shares = state.shares.clone();
+        server.output_frames = state.output_frames;
+        server.input_streams = state
+            .input_streams
+            .iter()
+            .map(|(&stream, &applied)| {
+                let last_used = Instant::now();
+                (stream, InputStream { applied, last_used })
+            })
+            .collect();
+        server.fallback_socket_dir = state.fallback_socket_dir.clone();
+        server.direct_input = state.direct_input.clone();
+        if let Some(size) = state.scrollback_file {
+            let path = session_dir.join(SCROLLBACK_FILENAME);
+            match ScrollbackBuffer::open_file(&path, size) {
+                Ok(mut buffer) => {
+                    buffer.set_strip_colors(state.strip_colors);
+                    server.scrollback = Some(buffer);
+                }
+                Err(e) => log::warn!("Failed to reopen scrollback file {}: {}", path.display(), e),
+            }
+        }
+        if let Some(size) = state.stderr_file {
+            let path = session_dir.join(STDERR_FILENAME);
+            match ScrollbackBuffer::open_file(&path, size) {
+                Ok(buffer) => server.stderr_file = Some(buffer),
+                Err(e) => log::warn!("Failed to reopen stderr file {}: {}", path.display(), e),
+            }
+        }
+        if state.history {
+            match HistoryWriter::open(session_dir) {
+                Ok(writer) => server.history = Some(writer),
+                Err(e) => log::warn!("Failed to reopen history: {}", e),
+            }
+        }
+        for adopted in &state.clients {
+            let stream = unsafe { upgrade::adopt_fd(adopted.fd)? };
+            let mut stream = UnixStream::from_std(stream);
+            let token = Token(CLIENT_BASE.0 + adopted.id);
+            server
+                .poll
+                .registry()
+                .register(&mut stream, token, Interest::READABLE)?;
+            let mut client = Client::new(stream);
+            client.decoder = proto::FrameDecoder::with_max_len(server.buffers.max_frame);
+            client.send_buffer_warn = server.buffers.send_buffer_warn;
+            client.pending_snapshot = false;
+            client.attached = adopted.attached;
+            client.read_only = adopted.read_only;
+            client.connected_at = adopted.connected_at;
+            client.input_stream = adopted.input_stream;
+            client.compression = adopted.compression;
+            client.stderr = adopted.stderr;
+            client.output_seq = adopted.output_seq;
+            client.exit_details = adopted.exit_details;
+            if adopted.checksum {
+                client.framing = proto::Framing::Crc32;
+                client.decoder.set_framing(proto::Framing::Crc32);
+            }
+            if server.key.is_some() {
+                client.sealing = Sealing::Required;
+            }
+            client.credit = adopted.window.map(Credit::new);
+            client.frame_marks = adopted.frame_marks;
+            client.identity = adopted.identity.clone();
+            server.clients.insert(adopted.id, client);
+        }
+        server.write_meta();
+        server.redraw_all_clients(None);
+        server.flush_all_clients();
+        log::info!(
+            "Resumed session '{}' with {} client(s) after upgrade",
+            server.session.name,
+            server.clients.len()
+        );
+        Ok(server)
+    }
+
+    fn with_listener(
+        session_dir: &Path,
+        session: Session,
+        mut listener: UnixListener,
+        created_at: u64,
+    ) -> io::Result<Self> {
+        let socket_path = session_dir.join("socket");
+        let key = session_key::read(session_dir)?;
+
         let poll = Poll::new()?;
         poll.registry()
             .register(&mut listener, LISTENER, Interest::READABLE)?;
@@ -67,8 +922,44 @@ impl Server {
         let mut source_fd = mio::unix::SourceFd(&pty_fd);
         poll.registry()
             .register(&mut source_fd, PTY_BASE, Interest::READABLE)?;
+        if let Some(stderr_fd) = session.stderr_fd() {
+            poll.registry().register(
+                &mut mio::unix::SourceFd(&stderr_fd),
+                STDERR,
+                Interest::READABLE,
+            )?;
+        }
+
+        let meta = SessionMeta {
+            session: session.name.clone(),
+            command: session.command.clone(),
+            pid: std::process::id() as i32,
+            protocol_version: proto::PROTOCOL_VERSION,
+            min_protocol_version: Some(proto::MIN_PROTOCOL_VERSION),
+            version: Some(env!("CARGO_PKG_VERSION").to_string()),
+            child_pid: session.pty.child_pid.as_raw(),
+            tty: session.pty.slave_path(),
+            created_at,
+            clients: 0,
+            last_activity: meta::unix_now(),
+            suspended: false,
+            exit_code: None,
+            exit: None,
+            stats: Default::default(),
+            recording: None,
+            expires_at: None,
+            sandbox: None,
+            nested_in: None,
+            activity: Default::default(),
+            exit_hook: None,
+        };
+        if let Err(e) = meta::write(session_dir, &meta) {
+            log::warn!("Failed to write session metadata: {}", e);
+        }
+        registry::register(session_dir);
 
         Ok(Self {
+            session_dir: session_dir.to_path_buf(),
             socket_path,
             session,
             poll,
@@ -76,140 +967,743 @@ impl Server {
             clients: HashMap::new(),
             next_client_id: 0,
             pending_pty_output: Vec::new(),
+            output_seq: 0,
+            output_frames: 0,
+            broadcast_seq: 0,
+            resume_log: ResumeLog::default(),
             exit_sent: false,
+            recorder: None,
+            scrollback: None,
+            stderr_file: None,
+            history: None,
+            meta,
+            meta_dirty: false,
+            last_meta_write: Instant::now(),
+            upgrade_to: None,
+            options: SessionOptions::default(),
+            throttle: None,
+            output_stopped: None,
+            detached_log: None,
+            pty_throttled: false,
+            pty_backlog: false,
+            batch_started: None,
+            idle_since: Instant::now(),
+            idle_warned: None,
+            suspended: false,
+            scheduler: Scheduler::new(meta::unix_now()),
+            watchers: Watchers::default(),
+            exit_hook_child: None,
+            held_input: None,
+            direct_input: Vec::new(),
+            next_confirm_id: 0,
+            input_streams: HashMap::new(),
+            execs: Vec::new(),
+            exec_scanner: StatusScanner::default(),
+            next_exec_id: 0,
+            buffers: Buffers::default(),
+            owner: nix::unistd::geteuid().as_raw(),
+            socket_home: None,
+            fallback_socket_dir: None,
+            last_socket_recovery: None,
+            cpu: CpuSampler::default(),
+            last_cpu_sample: Instant::now(),
+            connect_limits: HashMap::new(),
+            held_connections: VecDeque::new(),
+            key,
+            shares: Shares::default(),
         })
     }
 
-    pub fn run(&mut self) -> io::Result<()> {
-        let mut events = Events::with_capacity(64);
-        let mut pty_buf = vec![0u8; 65536];
-        let mut client_buf = vec![0u8; 65536];
+    fn write_meta(&mut self) {
+        self.meta.clients = self.clients.values().filter(|c| c.attached).count();
+        self.meta.exit_code = self.session.exited.map(|exit| exit.code);
+        self.meta.exit = self.session.exited;
+        self.meta.suspended = self.suspended;
+        if let Err(e) = meta::write(&self.session_dir, &self.meta) {
+            log::warn!("Failed to write session metadata: {}", e);
+        }
+        self.meta_dirty = false;
+        self.last_meta_write = Instant::now();
+    }
 
-        log::info!(
-            "Server running for session '{}' at {:?}",
-            self.session.name,
-            self.socket_path
-        );
+    fn sample_cpu(&mut self) {
+        self.last_cpu_sample = Instant::now();
+        let cpu_ms = self.cpu.sample(self.session.pty.child_pid.as_raw());
+        if cpu_ms != self.meta.stats.cpu_ms {
+            self.meta.stats.cpu_ms = cpu_ms;
+            self.meta_dirty = true;
+        }
+    }
 
-        loop {
-            // If the socket path disappears (or is replaced with a non-socket),
-            // treat the session as deleted and shut down.
-            match std::fs::symlink_metadata(&self.socket_path) {
-                Ok(meta) if meta.file_type().is_socket() => {}
-                _ => {
-                    log::warn!(
-                        "Socket path '{}' is missing; shutting down session '{}'",
-                        self.socket_path.display(),
-                        self.session.name
-                    );
-                    break;
-                }
+    fn note_activity(&mut self) {
+        self.meta.last_activity = meta::unix_now();
+        self.meta_dirty = true;
+        self.idle_since = Instant::now();
+    }
+
+    /// Change option `name` for `client_id` and apply it to the running
+    /// session.
+    fn set_option(&mut self, client_id: usize, name: &str, value: &str) -> Result<(), String> {
+        let old = self.options.clone();
+        self.options.set(name, value)?;
+        if !self.options.guard.is_empty() {
+            if let Err(e) = self.end_direct_input(client_id) {
+                self.options = old;
+                return Err(e);
             }
+        }
+        log::info!("Option {} set to {}", name, value);
+        self.apply_options(&old);
+        Ok(())
+    }
 
-            self.poll
-                .poll(&mut events, Some(Duration::from_millis(100)))?;
+    /// Detach the clients writing to the pty directly, past the guard about
+    /// to be turned on; attached again, they get no pty. Refused while a
+    /// process holding the pty is not attached, as it cannot be detached.
+    fn end_direct_input(&mut self, requested_by: usize) -> Result<(), String> {
+        self.direct_input.retain(|&pid| {
+            let gone = nix::sys::signal::kill(nix::unistd::Pid::from_raw(pid), None);
+            gone != Err(nix::errno::Errno::ESRCH)
+        });
+        let attached: Vec<(usize, i32)> = self
+            .clients
+            .iter()
+            .filter(|(_, c)| c.attached)
+            .filter_map(|(&id, c)| Some((id, c.peer.pid?)))
+            .filter(|(_, pid)| self.direct_input.contains(pid))
+            .collect();
+        if let Some(pid) = self
+            .direct_input
+            .iter()
+            .find(|pid| !attached.iter().any(|(_, attached)| attached == *pid))
+        {
+            return Err(format!(
+                "process {} writes to the pty directly (--direct-input) and is not \
+                 attached; end it before turning the guard on",
+                pid
+            ));
+        }
+        for (id, _) in attached {
+            self.detach_clients(
+                requested_by,
+                Some(id),
+                "the guard was turned on; direct input would bypass it",
+            );
+        }
+        self.direct_input.clear();
+        Ok(())
+    }
 
-            for event in events.iter() {
-                match event.token() {
-                    LISTENER => {
-                        if let Err(e) = self.accept_client() {
-                            log::warn!("Failed to accept client: {}", e);
-                        }
-                    }
-                    token if token.0 >= CLIENT_BASE.0 => {
-                        let id = token.0 - CLIENT_BASE.0;
-                        if event.is_readable() {
-                            if let Err(e) = self.handle_client_data(id, &mut client_buf) {
-                                log::warn!("Client {} read error: {}", id, e);
-                                self.clients.remove(&id);
-                            }
-                        }
-                        if event.is_writable() {
-                            if let Err(e) = self.flush_client_send_buf(id) {
-                                log::warn!("Client {} write error: {}", id, e);
-                                self.clients.remove(&id);
-                            }
-                        }
-                    }
-                    PTY_BASE => self.handle_pty_output(&mut pty_buf)?,
-                    _ => {}
-                }
-            }
+    /// Apply the options that differ from `old`.
+    fn apply_options(&mut self, old: &SessionOptions) {
+        if self.options.scrollback != old.scrollback {
+            self.session.set_scrollback_len(self.options.scrollback);
+        }
+        if self.options.replay_filter != old.replay_filter {
+            self.session.set_output_filter(self.options.replay_filter);
+        }
+        if self.options.output_throttle != old.output_throttle {
+            self.throttle = (self.options.output_throttle > 0)
+                .then(|| Throttle::new(self.options.output_throttle));
+        }
+    }
 
-            // No timer-based snapshot deferral. Snapshots are sent either:
-            // 1. When the client sends RESIZE (handled in process_client_recv_buf)
-            // 2. When PTY OUTPUT arrives for a client still awaiting snapshot
-            //    (handled in flush_pty_output)
+    fn options_reply(&self) -> serde_json::Value {
+        let options: serde_json::Map<String, serde_json::Value> = SessionOptions::NAMES
+            .iter()
+            .filter_map(|&name| Some((name.to_string(), self.options.get(name)?.into())))
+            .collect();
+        serde_json::json!({ "options": options })
+    }
 
-            if !self.exit_sent {
-                if let Some(exit_code) = self.session.check_exit() {
-                    // Flush pending output before the EXIT message.
-                    self.flush_pty_output();
-                    log::info!("Child exited with code {}", exit_code);
+    /// Use `buffers` instead of the default buffer sizes. Called before
+    /// `run`, while no client is connected.
+    pub fn set_buffers(&mut self, buffers: Buffers) {
+        self.buffers = buffers;
+    }
 
-                    let msg = proto::encode(proto::server::EXIT, &exit_code.to_le_bytes());
-                    for client in self.clients.values_mut() {
-                        client.send_buf.extend_from_slice(&msg);
-                    }
-                    self.flush_all_clients();
-                    self.exit_sent = true;
+    /// Re-create the socket in `home` (or in `fallback_socket_dir`) if it
+    /// disappears together with its socket root, instead of shutting down.
+    pub fn set_socket_home(&mut self, home: SocketHome, fallback_socket_dir: Option<PathBuf>) {
+        self.socket_home = Some(home);
+        self.fallback_socket_dir = fallback_socket_dir;
+    }
 
-                    if self.clients.is_empty() {
-                        break;
-                    }
-                }
-            }
+    /// Append all PTY output to the memory-mapped `buffer`.
+    pub fn keep_scrollback(&mut self, buffer: ScrollbackBuffer) {
+        self.scrollback = Some(buffer);
+    }
 
-            if self.session.exited.is_some() && self.clients.is_empty() {
-                break;
-            }
-        }
+    /// Append the child's stderr (`--split-stderr`) to `buffer`.
+    pub fn keep_stderr(&mut self, buffer: ScrollbackBuffer) {
+        self.stderr_file = Some(buffer);
+    }
 
-        let _ = std::fs::remove_file(&self.socket_path);
-        log::info!("Server shut down for session '{}'", self.session.name);
-        Ok(())
+    pub fn keep_history(&mut self, writer: HistoryWriter) {
+        self.history = Some(writer);
     }
 
-    fn accept_client(&mut self) -> io::Result<()> {
-        loop {
-            match self.listener.accept() {
-                Ok((mut stream, _)) => {
-                    let id = self.next_client_id;
-                    self.next_client_id += 1;
-
-                    let token = Token(CLIENT_BASE.0 + id);
-                    self.poll
-                        .registry()
-                        .register(&mut stream, token, Interest::READABLE)?;
-
-                    log::info!("Client {} connected to '{}'", id, self.session.name);
-
-                    self.clients.insert(
-                        id,
-                        Client {
-                            stream,
-                            recv_buf: Vec::new(),
-                            send_buf: Vec::new(),
-                            large_send_buf_warned: false,
-                            pending_snapshot: true,
-                        },
-                    );
-                }
-                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
-                Err(e) => return Err(e),
+    /// Archive output in the history, which is given up on a write error.
+    fn append_history(&mut self, data: &[u8]) {
+        if let Some(history) = self.history.as_mut() {
+            if let Err(e) = history.append(data, meta::unix_now()) {
+                log::warn!("Disabling history after write error: {}", e);
+                self.history = None;
+            }
+        }
+        if let Some(Some(log)) = self.detached_log.as_mut() {
+            if let Err(e) = log.write_all(data) {
+                log::warn!("Closing {} after write error: {}", DETACHED_LOG_FILENAME, e);
+                self.detached_log = Some(None);
             }
         }
-        Ok(())
     }
 
-    /// Send the current terminal snapshot to a specific client and clear its
-    /// pending-snapshot flag.
-    ///
-    /// When `replace_send_buf` is `true`, any queued outbound bytes for that
-    /// client are dropped first. This is used on RESIZE so an older-size
+    /// Apply the `detached` option to whether a client is attached now:
+    /// stop the pty's output or open `detached.log` while none is, and undo
+    /// that once one attaches.
+    fn apply_detached_policy(&mut self) {
+        let detached = self.session.exited.is_none() && !self.clients.values().any(|c| c.attached);
+        let stop = detached && self.options.detached == Detached::Pause;
+        match self.output_stopped {
+            Some(stopped) if stopped == stop => {}
+            // Output was never stopped by this daemon; restarting it only
+            // matters after a hot upgrade, so failing quietly is fine.
+            None if !stop => {
+                let _ = self.session.pty.set_output_stopped(false);
+                self.output_stopped = Some(false);
+            }
+            _ => {
+                let verb = if stop { "pause" } else { "resume" };
+                match self.session.pty.set_output_stopped(stop) {
+                    Ok(()) => log::info!("Output {}d", verb),
+                    Err(e) => log::warn!("Failed to {} output: {}", verb, e),
+                }
+                // Not retried on every iteration after a failure; the next
+                // change is.
+                self.output_stopped = Some(stop);
+            }
+        }
+
+        let log = detached && self.options.detached == Detached::Log;
+        if log != self.detached_log.is_some() {
+            self.detached_log = log.then(|| self.open_detached_log());
+        }
+    }
+
+    fn open_detached_log(&self) -> Option<std::fs::File> {
+        let path = self.session_dir.join(DETACHED_LOG_FILENAME);
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .inspect_err(|e| log::warn!("Failed to open {}: {}", path.display(), e))
+            .ok()
+    }
+
+    /// Write the startup banner (session name, creation time, how to detach)
+    /// and `motd` into the terminal before the child's first output, so it
+    /// stays in the scrollback every later attach replays.
+    pub fn show_banner(&mut self, motd: Option<&str>) {
+        let banner = banner_text(&self.session.name, self.meta.created_at, motd);
+        self.inject(banner);
+    }
+
+    /// Write pterm's own `text` into the terminal as if the child had
+    /// printed it: into the terminal state, the scrollback file, the I/O
+    /// recording and to connected clients. The child never sees it.
+    fn inject(&mut self, text: Vec<u8>) {
+        self.flush_pty_output();
+        self.session.inject_output(&text);
+        if let Some(scrollback) = self.scrollback.as_mut() {
+            scrollback.append(&text);
+        }
+        self.append_history(&text);
+        self.resume_log.append(&text, self.buffers.resume_buffer);
+        if !self.clients.is_empty() {
+            self.output_frames += 1;
+            let mut msg = OutputFrame::new(proto::server::OUTPUT, &text, self.output_frames);
+            for client in self.clients.values_mut() {
+                if !client.pending_snapshot {
+                    client.queue_output(&mut msg);
+                }
+            }
+            self.flush_all_clients();
+        }
+        if self.recorder.is_some() {
+            self.record(0, recording::Event::PtyOutput(text));
+        }
+    }
+
+    /// Enforce the `idle-timeout` option: warn `idle-warning` seconds ahead,
+    /// withdraw the warning once a client attaches or there is activity, and
+    /// return `true` when the session has to end.
+    fn check_idle_timeout(&mut self) -> bool {
+        if self.clients.values().any(|c| c.attached) {
+            self.idle_since = Instant::now();
+        }
+        if self
+            .idle_warned
+            .is_some_and(|since| since != self.idle_since || self.options.idle_timeout().is_none())
+        {
+            self.idle_warned = None;
+            self.meta.expires_at = None;
+            self.write_meta();
+            log::info!("Idle timeout of session '{}' cancelled", self.session.name);
+            self.inject(b"\x1b[2m[pterm] idle timeout cancelled\x1b[0m\r\n".to_vec());
+        }
+        let Some(timeout) = self.options.idle_timeout() else {
+            return false;
+        };
+        let idle = self.idle_since.elapsed();
+        if idle >= timeout {
+            log::info!(
+                "Session '{}' idle for {}s without clients; shutting down",
+                self.session.name,
+                timeout.as_secs()
+            );
+            return true;
+        }
+        if self.idle_warned.is_none()
+            && self
+                .options
+                .idle_warning_after()
+                .is_some_and(|after| idle >= after)
+        {
+            let left = timeout - idle;
+            let expires_at = meta::unix_now() + left.as_secs();
+            self.idle_warned = Some(self.idle_since);
+            self.meta.expires_at = Some(expires_at);
+            self.write_meta();
+            log::warn!(
+                "Session '{}' idle; ending it in {}s",
+                self.session.name,
+                left.as_secs()
+            );
+            self.inject(idle_warning_text(&self.session.name, expires_at));
+        }
+        false
+    }
+
+    /// Record all PTY reads, client frames and snapshots to `recorder`,
+    /// which writes to `dir`.
+    pub fn record_io(&mut self, recorder: Recorder, dir: PathBuf) {
+        self.recorder = Some(recorder);
+        self.meta.recording = Some(dir);
+        self.write_meta();
+    }
+
+    /// Note the session `pterm new` ran inside, for `pterm info`.
+    pub fn nested_in(&mut self, enclosing: String) {
+        self.meta.nested_in = Some(enclosing);
+        self.write_meta();
+    }
+
+    /// Run `hook` when the child exits (`pterm new --on-exit`).
+    pub fn on_exit(&mut self, hook: ExitHook) {
+        self.meta.exit_hook = Some(hook);
+        self.write_meta();
+    }
+
+    /// Start the `pterm on-exit` hook, if any, for the child's `exit`.
+    fn run_exit_hook(&mut self, exit: &meta::ExitInfo) {
+        let Some(hook) = self.meta.exit_hook.clone() else {
+            return;
+        };
+        let lines = self.session.capture(false).lines;
+        match hook.run(&self.session.name, exit, &lines, &self.session_dir) {
+            Ok(child) => {
+                log::info!("Exit hook started: {}", hook.describe());
+                self.exit_hook_child = Some(child);
+            }
+            Err(e) => log::warn!("Failed to run exit hook {:?}: {}", hook.command, e),
+        }
+    }
+
+    /// The `on-exit` command: `{"command": [...], "lines": n}` sets the
+    /// hook, `{"remove": true}` clears it and no arguments show it.
+    fn exit_hook_command(&mut self, args: &serde_json::Value) -> Result<serde_json::Value, String> {
+        if args["remove"].as_bool() == Some(true) {
+            self.meta.exit_hook = None;
+            self.write_meta();
+        } else if !args["command"].is_null() {
+            let command: Vec<String> = serde_json::from_value(args["command"].clone())
+                .map_err(|_| "\"command\" must be a list of strings".to_string())?;
+            let lines = match &args["lines"] {
+                serde_json::Value::Null => hook::DEFAULT_LINES,
+                lines => lines
+                    .as_u64()
+                    .ok_or_else(|| "\"lines\" must be a number".to_string())?
+                    as usize,
+            };
+            self.meta.exit_hook = Some(ExitHook::new(command, lines)?);
+            self.write_meta();
+        }
+        Ok(serde_json::json!({ "exit_hook": self.meta.exit_hook }))
+    }
+
+    /// Keep the daemon running after the child exits (`pterm new --hold`).
+    pub fn hold(&mut self) {
+        self.options.hold = true;
+    }
+
+    /// Stop the child's output while no client is attached (`pterm new
+    /// --pause-when-detached`).
+    pub fn pause_when_detached(&mut self) {
+        self.options.detached = Detached::Pause;
+    }
+
+    /// Confine the daemon (`--sandbox`) to what it needs from now on: its
+    /// session directory, the I/O recording and the fallback socket root,
+    /// plus read-only `/proc`, `/etc` and the schedule's directory. Call it
+    /// once everything is opened, right before `run`.
+    pub fn sandbox(&mut self) -> io::Result<()> {
+        let mut rules = sandbox::Rules {
+            writable: vec![self.session_dir.clone()],
+            readable: vec![PathBuf::from("/proc"), PathBuf::from("/etc")],
+        };
+        rules.writable.extend(self.meta.recording.clone());
+        // Reopened to stop its output for `detached pause`.
+        rules.writable.extend(self.session.pty.slave_path());
+        rules.writable.extend(self.fallback_socket_dir.clone());
+        rules
+            .readable
+            .extend(schedule::Schedule::path().and_then(|p| Some(p.parent()?.to_path_buf())));
+        let applied = sandbox::apply(&rules)?;
+        log::info!("Sandboxed: {}", applied);
+        self.meta.sandbox = Some(applied);
+        self.write_meta();
+        Ok(())
+    }
+
+    fn record(&mut self, client_id: usize, event: recording::Event) {
+        if let Some(recorder) = self.recorder.as_mut() {
+            if let Err(e) = recorder.record(client_id, event) {
+                log::warn!("Disabling I/O recording after write error: {}", e);
+                self.recorder = None;
+            }
+        }
+    }
+
+    fn remove_client(&mut self, client_id: usize) {
+        if let Some(client) = self.clients.remove(&client_id) {
+            self.record(client_id, recording::Event::ClientDisconnect);
+            if self
+                .held_input
+                .as_ref()
+                .is_some_and(|held| held.client_id == client_id)
+            {
+                log::info!("Guard: client {} left, dropping held input", client_id);
+                self.held_input = None;
+            }
+            self.execs.retain(|e| e.client_id != client_id);
+            if client.attached {
+                self.write_meta();
+            }
+        }
+    }
+
+    pub fn run(&mut self) -> io::Result<()> {
+        let mut events = Events::with_capacity(64);
+        let mut pty_buf = vec![0u8; self.buffers.read_buffer];
+        let mut client_buf = vec![0u8; self.buffers.read_buffer];
+
+        log::info!(
+            "Server running for session '{}' at {:?}",
+            self.session.name,
+            self.socket_path
+        );
+
+        loop {
+            // If the socket path disappears (or is replaced with a non-socket),
+            // treat the session as deleted and shut down, unless the whole
+            // socket root went with it and the session has a home to return to.
+            let socket_present = matches!(
+                std::fs::symlink_metadata(&self.socket_path),
+                Ok(meta) if meta.file_type().is_socket()
+            );
+            if !socket_present && !self.socket_root_vanished() {
+                log::warn!(
+                    "Socket path '{}' is missing; shutting down session '{}'",
+                    self.socket_path.display(),
+                    self.session.name
+                );
+                break;
+            }
+            if !socket_present || self.relocated() {
+                self.recover_socket(socket_present);
+            }
+            self.apply_detached_policy();
+
+            let timeout = if self.pty_backlog {
+                Duration::ZERO
+            } else if let Some(started) = self.batch_started {
+                OUTPUT_BATCH_INTERVAL.saturating_sub(started.elapsed())
+            } else {
+                Duration::from_millis(100)
+            };
+            match self.poll.poll(&mut events, Some(timeout)) {
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                result => result?,
+            }
+
+            for event in events.iter() {
+                match event.token() {
+                    LISTENER => {
+                        if let Err(e) = self.accept_client() {
+                            log::warn!("Failed to accept client: {}", e);
+                        }
+                    }
+                    token if token.0 >= CLIENT_BASE.0 => {
+                        let id = token.0 - CLIENT_BASE.0;
+                        if event.is_readable() {
+                            if let Err(e) = self.handle_client_data(id, &mut client_buf) {
+                                log::warn!("Client {} read error: {}", id, e);
+                                self.remove_client(id);
+                            }
+                        }
+                        if event.is_writable() {
+                            if let Err(e) = self.flush_client_send_buf(id) {
+                                log::warn!("Client {} write error: {}", id, e);
+                                self.remove_client(id);
+                            }
+                        }
+                    }
+                    PTY_BASE => self.handle_pty_output(&mut pty_buf)?,
+                    STDERR => self.handle_stderr_output(&mut pty_buf),
+                    _ => {}
+                }
+            }
+
+            if let Err(e) = self.admit_held_connections() {
+                log::warn!("Failed to accept client: {}", e);
+            }
+
+            if let Some(binary) = self.upgrade_to.take() {
+                if self.session.exited.is_some() {
+                    log::warn!("Ignoring upgrade request: child has already exited");
+                } else if self.meta.sandbox.is_some() {
+                    log::warn!("Ignoring upgrade request: the daemon is sandboxed");
+                } else {
+                    let e = self.exec_upgrade(&binary);
+                    log::error!("Upgrade to {} failed: {}", binary.display(), e);
+                }
+            }
+
+            self.keepalive_clients();
+            self.expire_shares();
+            self.run_scheduled_jobs();
+            self.watchers.reap();
+            if let Some(child) = self.exit_hook_child.as_mut() {
+                if !matches!(child.try_wait(), Ok(None)) {
+                    self.exit_hook_child = None;
+                }
+            }
+            if self
+                .held_input
+                .as_ref()
+                .is_some_and(|held| held.since.elapsed() >= GUARD_CONFIRM_TIMEOUT)
+            {
+                log::warn!("Guard: confirmation timed out, dropping held input");
+                self.held_input = None;
+            }
+            if self.last_cpu_sample.elapsed() >= CPU_SAMPLE_INTERVAL {
+                self.sample_cpu();
+            }
+
+            if self.pty_throttled || self.pty_backlog {
+                self.handle_pty_output(&mut pty_buf)?;
+            } else if self
+                .batch_started
+                .is_some_and(|started| started.elapsed() >= OUTPUT_BATCH_INTERVAL)
+            {
+                self.flush_pty_output();
+            }
+
+            if self.meta_dirty && self.last_meta_write.elapsed() >= META_ACTIVITY_WRITE_INTERVAL {
+                self.write_meta();
+            }
+
+            if let Some(recorder) = self.recorder.as_mut() {
+                if let Err(e) = recorder.flush() {
+                    log::warn!("Disabling I/O recording after flush error: {}", e);
+                    self.recorder = None;
+                }
+            }
+
+            if let Some(history) = self.history.as_mut() {
+                if let Err(e) = history.tick(meta::unix_now()) {
+                    log::warn!("Disabling history after write error: {}", e);
+                    self.history = None;
+                }
+            }
+
+            // No timer-based snapshot deferral. Snapshots are sent either:
+            // 1. When the client sends RESIZE (handled in process_client_recv_buf)
+            // 2. When PTY OUTPUT arrives for a client still awaiting snapshot
+            //    (handled in flush_pty_output)
+
+            if !self.exit_sent {
+                if let Some(exit) = self.session.check_exit() {
+                    // Flush pending output before the EXIT message.
+                    self.flush_pty_output();
+                    log::info!("Child {}", exit.describe());
+
+                    self.exit_sent = true;
+                    self.requeue_exit(None);
+                    self.flush_all_clients();
+                    self.run_exit_hook(&exit);
+                    self.write_meta();
+                }
+            }
+
+            if self.session.exited.is_some() && self.clients.is_empty() && !self.options.hold {
+                break;
+            }
+
+            if self.check_idle_timeout() {
+                break;
+            }
+        }
+
+        let _ = std::fs::remove_file(&self.socket_path);
+        log::info!("Server shut down for session '{}'", self.session.name);
+        Ok(())
+    }
+
+    fn accept_client(&mut self) -> io::Result<()> {
+        loop {
+            match self.listener.accept() {
+                Ok((stream, _)) => {
+                    let mut client = Client::new(stream);
+                    if self.key.is_some() {
+                        client.sealing = Sealing::Required;
+                    }
+                    if !self.peer_allowed(&client.peer) && self.shares.active() {
+                        log::info!(
+                            "Connection from uid {:?} may redeem a share token",
+                            client.peer.uid
+                        );
+                        client.guest = Some(Guest::Pending);
+                    } else if !self.peer_allowed(&client.peer) {
+                        log::warn!(
+                            "Refusing connection from uid {:?}: '{}' belongs to uid {}",
+                            client.peer.uid,
+                            self.session.name,
+                            self.owner
+                        );
+                        continue;
+                    }
+                    let key = (client.peer.uid, client.peer.parent_pid());
+                    if self.take_connect_budget(key) {
+                        self.admit_client(client)?;
+                    } else {
+                        self.hold_connection(key, client);
+                    }
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    fn admit_client(&mut self, mut client: Client) -> io::Result<()> {
+        client.decoder = proto::FrameDecoder::with_max_len(self.buffers.max_frame);
+        let id = self.next_client_id;
+        self.next_client_id += 1;
+
+        let token = Token(CLIENT_BASE.0 + id);
+        self.poll
+            .registry()
+            .register(&mut client.stream, token, Interest::READABLE)?;
+
+        log::info!("Client {} connected to '{}'", id, self.session.name);
+        self.record(id, recording::Event::ClientConnect);
+
+        client.send_buffer_warn = self.buffers.send_buffer_warn;
+        self.clients.insert(id, client);
+        Ok(())
+    }
+
+    /// Count a connection from `key` against its rate limit. Returns `false`
+    /// if it has to wait, including behind earlier held connections.
+    fn take_connect_budget(&mut self, key: PeerKey) -> bool {
+        if self.held_connections.iter().any(|(held, _)| *held == key) {
+            return false;
+        }
+        let limit = self
+            .connect_limits
+            .entry(key)
+            .or_insert_with(|| Throttle::new(CONNECT_RATE));
+        if limit.budget() == 0 {
+            return false;
+        }
+        limit.consume(1);
+        true
+    }
+
+    fn hold_connection(&mut self, key: PeerKey, client: Client) {
+        if self.held_connections.len() >= MAX_HELD_CONNECTIONS {
+            log::warn!(
+                "Too many held connections; closing one from {:?}",
+                client.peer
+            );
+            return;
+        }
+        if !self.held_connections.iter().any(|(held, _)| *held == key) {
+            log::warn!(
+                "{:?} reconnects faster than {}/s; holding connections back",
+                client.peer,
+                CONNECT_RATE
+            );
+        }
+        self.held_connections.push_back((key, client));
+    }
+
+    /// Admit held connections as their peers' rate limits allow, and forget
+    /// the limits of peers that have not connected for a while.
+    fn admit_held_connections(&mut self) -> io::Result<()> {
+        let held = std::mem::take(&mut self.held_connections);
+        for (key, client) in held {
+            if self.take_connect_budget(key) {
+                self.admit_client(client)?;
+            } else {
+                self.held_connections.push_back((key, client));
+            }
+        }
+        let held = &self.held_connections;
+        self.connect_limits.retain(|key, limit| {
+            limit.budget() < CONNECT_RATE as usize || held.iter().any(|(k, _)| k == key)
+        });
+        Ok(())
+    }
+
+    /// Send the current terminal snapshot to a specific client and clear its
+    /// pending-snapshot flag. `STATE_SYNC` is therefore the first data frame
+    /// every attached client receives.
+    ///
+    /// The snapshot reflects every PTY byte read so far, including any still
+    /// in `pending_pty_output`; `synced_seq` records that, so the next OUTPUT
+    /// broadcast skips this client instead of delivering those bytes after
+    /// the snapshot that already contains them.
+    ///
+    /// When `replace_send_buf` is `true`, queued frames that have not started
+    /// going out are dropped first. This is used on RESIZE so an older-size
     /// snapshot or stale OUTPUT frame cannot remain queued ahead of the fresh
     /// snapshot for the client's new dimensions.
     fn send_snapshot_to_client(&mut self, client_id: usize, replace_send_buf: bool) {
+        if self.resume_client(client_id, replace_send_buf) {
+            return;
+        }
+        let replay = match self.clients.get_mut(&client_id) {
+            Some(client) if client.pending_snapshot => std::mem::take(&mut client.replay),
+            _ => AttachReplay::default(),
+        };
+        if replay.skip {
+            self.skip_snapshot(client_id, replace_send_buf);
+            return;
+        }
         let buffered_pty_bytes = self.pending_pty_output.len();
         let other_pending_snapshots = self
             .clients
@@ -233,23 +1727,93 @@ impl Server {
         }
 
         let snapshot = self.session.snapshot();
+        if self.recorder.is_some() && self.clients.contains_key(&client_id) {
+            self.record(client_id, recording::Event::Snapshot(snapshot.clone()));
+        }
+        let position = self.snapshot_position();
+        let history = self.session.scrollback_replay(replay.lines, replay.bytes);
+        let snapshot = if history.is_empty() {
+            snapshot
+        } else {
+            [history, snapshot].concat()
+        };
         if let Some(client) = self.clients.get_mut(&client_id) {
             client.pending_snapshot = false;
+            client.synced_seq = self.output_seq;
             if replace_send_buf {
-                client.send_buf.clear();
+                client.discard_queued();
             }
-            if !snapshot.is_empty() {
-                let msg = proto::encode(proto::server::STATE_SYNC, &snapshot);
-                client.send_buf.extend_from_slice(&msg);
+            self.output_frames += 1;
+            let mut msg =
+                OutputFrame::new(proto::server::STATE_SYNC, &snapshot, self.output_frames);
+            if client.queue_output(&mut msg) {
+                client.queue_output_seq(position);
             }
         }
-        if let Err(e) = self.flush_client_send_buf(client_id) {
-            log::warn!(
-                "Client {} flush error during snapshot send: {}",
+        self.flush_or_remove(client_id);
+    }
+
+    /// Stream position a snapshot taken now brings a client to: it already
+    /// reflects the output not yet broadcast, which the client skips.
+    fn snapshot_position(&self) -> u64 {
+        self.resume_log.end() + self.pending_pty_output.len() as u64
+    }
+
+    /// In place of `client_id`'s first snapshot, send the output it missed
+    /// since the stream position it asked to resume from, if that is still
+    /// kept. Returns `true` if the client was resumed.
+    fn resume_client(&mut self, client_id: usize, replace_send_buf: bool) -> bool {
+        let Some(client) = self.clients.get_mut(&client_id) else {
+            return false;
+        };
+        let Some(seq) = client.resume_from.take() else {
+            return false;
+        };
+        let Some(missed) = self.resume_log.since(seq) else {
+            log::info!(
+                "Client {} cannot resume from stream position {}, sending a snapshot",
                 client_id,
-                e
+                seq
             );
+            return false;
+        };
+        log::info!(
+            "Client {} resumed from stream position {} ({} byte(s) missed)",
+            client_id,
+            seq,
+            missed.len()
+        );
+        client.pending_snapshot = false;
+        // The output not yet broadcast still goes out to this client.
+        client.synced_seq = self.broadcast_seq;
+        if replace_send_buf {
+            client.discard_queued();
+        }
+        client.queue_output_seq(seq);
+        if !missed.is_empty() {
+            self.output_frames += 1;
+            let mut msg = OutputFrame::new(proto::server::OUTPUT, &missed, self.output_frames);
+            client.queue_output(&mut msg);
+        }
+        self.flush_or_remove(client_id);
+        true
+    }
+
+    /// In place of `client_id`'s first snapshot, as it asked with
+    /// `client::ATTACH`: the client gets the output from now on only.
+    fn skip_snapshot(&mut self, client_id: usize, replace_send_buf: bool) {
+        let position = self.resume_log.end();
+        if let Some(client) = self.clients.get_mut(&client_id) {
+            log::info!("Client {} attached without a snapshot", client_id);
+            client.pending_snapshot = false;
+            // The output not yet broadcast still goes out to this client.
+            client.synced_seq = self.broadcast_seq;
+            if replace_send_buf {
+                client.discard_queued();
+            }
+            client.queue_output_seq(position);
         }
+        self.flush_or_remove(client_id);
     }
 
     fn send_snapshot_to_all_clients(&mut self, replace_send_buf: bool) {
@@ -261,14 +1825,55 @@ impl Server {
 
     fn handle_pty_output(&mut self, buf: &mut [u8]) -> io::Result<()> {
         // Drain all available PTY data (non-blocking) and flush immediately.
-        // No timer-based batching — the drain loop itself coalesces all bytes
-        // that are available at this instant.
+        // No timer-based batching unless the `latency` option asks for it —
+        // the drain loop itself coalesces all bytes that are available at
+        // this instant, up to the drain limit.
+        let pending_before = self.pending_pty_output.len();
+        self.pty_throttled = false;
+        self.pty_backlog = false;
+        let mut drained = 0;
         loop {
-            match self.session.read_pty(buf) {
+            if drained >= self.buffers.drain_limit {
+                self.pty_backlog = true;
+                break;
+            }
+            let limit = match self.throttle.as_mut() {
+                Some(throttle) => throttle.budget().min(buf.len()),
+                None => buf.len(),
+            };
+            if limit == 0 {
+                self.pty_throttled = true;
+                break;
+            }
+            match self
+                .session
+                .read_pty(&mut buf[..limit], &mut self.pending_pty_output)
+            {
                 Ok(0) => break,
                 Ok(n) => {
-                    self.pending_pty_output.extend_from_slice(&buf[..n]);
+                    drained += n;
+                    self.output_seq += n as u64;
+                    self.meta.stats.output_bytes += n as u64;
+                    self.meta.activity.output(meta::unix_now(), n as u64);
+                    if let Some(scrollback) = self.scrollback.as_mut() {
+                        scrollback.append(&buf[..n]);
+                    }
+                    self.append_history(&buf[..n]);
+                    for m in self.watchers.feed(&buf[..n]) {
+                        self.watchers.run(&m, &self.session.name);
+                    }
+                    if !self.execs.is_empty() {
+                        let statuses = self.exec_scanner.feed(&buf[..n]);
+                        self.finish_execs(statuses);
+                    }
+                    if let Some(throttle) = self.throttle.as_mut() {
+                        throttle.consume(n);
+                    }
+                    if self.recorder.is_some() {
+                        self.record(0, recording::Event::PtyOutput(buf[..n].to_vec()));
+                    }
                 }
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
                 Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
                 Err(e) => {
                     if self.pending_pty_output.is_empty() {
@@ -324,61 +1929,1407 @@ impl Server {
             }
         }
 
-        if !self.pending_pty_output.is_empty() {
-            self.flush_pty_output();
+        if self.pending_pty_output.len() > pending_before {
+            self.note_activity();
+        }
+        if self.options.latency == Latency::Throughput
+            && !self.pending_pty_output.is_empty()
+            && self.pending_pty_output.len() < self.buffers.drain_limit
+        {
+            let started = *self.batch_started.get_or_insert_with(Instant::now);
+            if started.elapsed() < OUTPUT_BATCH_INTERVAL {
+                return Ok(());
+            }
+        }
+        self.flush_pty_output();
+
+        Ok(())
+    }
+
+    /// Drain the child's stderr pipe (`--split-stderr`). Like injected text,
+    /// each read goes into the terminal state and the scrollback right
+    /// away, after the stdout read before it; clients that asked for it in
+    /// `HELLO` get it as `STDERR_OUTPUT`, the others as plain `OUTPUT`.
+    fn handle_stderr_output(&mut self, buf: &mut [u8]) {
+        loop {
+            let n = match self.session.read_stderr(buf) {
+                Ok(0) => {
+                    if let Some(fd) = self.session.stderr_fd() {
+                        let _ = self
+                            .poll
+                            .registry()
+                            .deregister(&mut mio::unix::SourceFd(&fd));
+                    }
+                    self.session.close_stderr();
+                    break;
+                }
+                Ok(n) => n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    log::error!("stderr read error: {}", e);
+                    break;
+                }
+            };
+            self.flush_pty_output();
+            self.meta.stats.output_bytes += n as u64;
+            self.meta.activity.output(meta::unix_now(), n as u64);
+            if let Some(file) = self.stderr_file.as_mut() {
+                file.append(&buf[..n]);
+            }
+            let mut output = Vec::new();
+            let text = self.session.process_stderr(&buf[..n], &mut output);
+            if let Some(scrollback) = self.scrollback.as_mut() {
+                scrollback.append(&text);
+            }
+            self.append_history(&text);
+            for m in self.watchers.feed(&text) {
+                self.watchers.run(&m, &self.session.name);
+            }
+            if !output.is_empty() {
+                self.resume_log.append(&output, self.buffers.resume_buffer);
+                // Either way it is the same frame, so it gets one number.
+                self.output_frames += 1;
+                let number = self.output_frames;
+                let mut tagged = OutputFrame::new(proto::server::STDERR_OUTPUT, &output, number);
+                let mut plain = OutputFrame::new(proto::server::OUTPUT, &output, number);
+                for client in self.clients.values_mut() {
+                    if client.pending_snapshot {
+                        continue;
+                    }
+                    let msg = if client.stderr {
+                        &mut tagged
+                    } else {
+                        &mut plain
+                    };
+                    client.queue_output(msg);
+                }
+                self.flush_all_clients();
+                self.note_activity();
+            }
+            if self.recorder.is_some() {
+                self.record(0, recording::Event::StderrOutput(text));
+            }
+        }
+    }
+
+    /// Flush accumulated PTY output to all connected clients.
+    /// Clients still awaiting a snapshot receive the snapshot first (triggered
+    /// by the arrival of OUTPUT rather than a timer).
+    fn flush_pty_output(&mut self) {
+        self.batch_started = None;
+        if self.pending_pty_output.is_empty() {
+            // Everything read so far was filtered out of the OUTPUT stream.
+            self.broadcast_seq = self.output_seq;
+            return;
+        }
+
+        // Clients awaiting snapshot: the arrival of OUTPUT means the VT state
+        // is populated, so send their snapshot now (no timer needed).
+        // These clients must NOT also receive the raw OUTPUT bytes below,
+        // because the snapshot already reflects the effect of those bytes
+        // (read_pty feeds data to the VT parser before this method runs).
+        // Sending both would cause Neovim's libvterm to process the same
+        // content twice, resulting in duplicated rendering.
+        let snapshot_ids: Vec<usize> = self
+            .clients
+            .iter()
+            .filter_map(|(&id, c)| if c.pending_snapshot { Some(id) } else { None })
+            .collect();
+        for id in &snapshot_ids {
+            log::info!("Client {} snapshot triggered by PTY output arrival", *id);
+            self.send_snapshot_to_client(*id, true);
+        }
+
+        let mut output = std::mem::take(&mut self.pending_pty_output);
+        self.resume_log.append(&output, self.buffers.resume_buffer);
+        self.output_frames += 1;
+        let mut msg = OutputFrame::new(proto::server::OUTPUT, &output, self.output_frames);
+
+        let mut flush_ids = Vec::new();
+        for (&id, client) in self.clients.iter_mut() {
+            // Skip clients whose snapshot was taken after these bytes were
+            // read (just above, or on a RESIZE this iteration) — they already
+            // have the up-to-date screen state and must not get them again.
+            if client.synced_seq > self.broadcast_seq {
+                continue;
+            }
+            client.queue_output(&mut msg);
+            flush_ids.push(id);
+        }
+        // Hand the buffer back to keep its capacity.
+        output.clear();
+        self.pending_pty_output = output;
+        self.broadcast_seq = self.output_seq;
+        for id in flush_ids {
+            self.flush_or_remove(id);
+        }
+    }
+
+    /// Queue a clear-screen plus full snapshot for every client.
+    /// `requested_by` is the client that asked for it, if any (for recording).
+    fn redraw_all_clients(&mut self, requested_by: Option<usize>) {
+        let snapshot = self.session.snapshot();
+        if let Some(client_id) = requested_by {
+            if self.recorder.is_some() {
+                self.record(client_id, recording::Event::Snapshot(snapshot.clone()));
+            }
+        }
+        let mut redraw_data = b"\x1b[2J\x1b[H".to_vec();
+        redraw_data.extend_from_slice(&snapshot);
+        self.output_frames += 1;
+        let mut msg = OutputFrame::new(proto::server::STATE_SYNC, &redraw_data, self.output_frames);
+        let position = self.snapshot_position();
+        for client in self.clients.values_mut() {
+            client.synced_seq = self.output_seq;
+            if client.queue_output(&mut msg) {
+                client.queue_output_seq(position);
+            }
+        }
+    }
+
+    /// Return `bytes` to `client_id`'s output window. A client that missed
+    /// output while the window was used up is redrawn once half of it is
+    /// free again.
+    fn return_credit(&mut self, client_id: usize, bytes: u64) {
+        let Some(client) = self.clients.get_mut(&client_id) else {
+            return;
+        };
+        let Some(credit) = client.credit.as_mut() else {
+            return;
+        };
+        credit.unacked = credit.unacked.saturating_sub(bytes);
+        if !credit.missed || credit.unacked > credit.window / 2 {
+            return;
+        }
+        credit.missed = false;
+        log::info!("Client {} caught up, redrawing", client_id);
+        let mut redraw_data = b"\x1b[2J\x1b[H".to_vec();
+        redraw_data.extend_from_slice(&self.session.snapshot());
+        self.output_frames += 1;
+        let mut msg = OutputFrame::new(proto::server::STATE_SYNC, &redraw_data, self.output_frames);
+        let position = self.snapshot_position();
+        if let Some(client) = self.clients.get_mut(&client_id) {
+            client.synced_seq = self.output_seq;
+            if client.queue_output(&mut msg) {
+                client.queue_output_seq(position);
+            }
+        }
+        self.flush_or_remove(client_id);
+    }
+
+    /// Drop the share tokens that have expired, and the guests whose token
+    /// did.
+    fn expire_shares(&mut self) {
+        let now = meta::unix_now();
+        if self.shares.active() {
+            let expired = self.shares.expire(&self.socket_path, now);
+            if expired > 0 {
+                log::info!("{} share token(s) expired", expired);
+            }
+        }
+        let guests: Vec<usize> = self
+            .clients
+            .iter()
+            .filter(|(_, c)| matches!(c.guest, Some(Guest::Admitted { until }) if until <= now))
+            .map(|(&id, _)| id)
+            .collect();
+        for id in guests {
+            log::info!("Detaching guest {}: the share expired", id);
+            if let Some(client) = self.clients.get_mut(&id) {
+                let reason = b"the share expired";
+                client.queue(&proto::encode(proto::server::DETACHED, reason));
+            }
+            let _ = self.flush_client_send_buf(id);
+            self.remove_client(id);
+        }
+    }
+
+    /// PING idle attached clients and drop clients whose connection is
+    /// half-open: an unanswered PING or a send buffer that stopped draining.
+    fn keepalive_clients(&mut self) {
+        let now = Instant::now();
+        let mut ping_ids = Vec::new();
+        let mut zombies = Vec::new();
+        for (&id, client) in &self.clients {
+            if let Some(reason) = client.zombie_reason(now) {
+                zombies.push((id, reason));
+            } else if client.attached
+                && client.ping_sent.is_none()
+                && now.duration_since(client.last_recv) >= KEEPALIVE_INTERVAL
+            {
+                ping_ids.push(id);
+            }
+        }
+
+        for (id, reason) in zombies {
+            log::warn!("Dropping zombie client {}: {}", id, reason);
+            self.remove_client(id);
+        }
+        for id in ping_ids {
+            if let Some(client) = self.clients.get_mut(&id) {
+                client.ping_sent = Some(now);
+            }
+            self.send(id, proto::server::PING, &[]);
+        }
+    }
+
+    /// Queue EXIT for `client_id` or everyone once the child has exited, and
+    /// in a `hold` session again after a snapshot replaced send buffers.
+    /// Clients that asked for them in `HELLO` get the exit details.
+    /// Returns `true` if anything was queued.
+    fn requeue_exit(&mut self, client_id: Option<usize>) -> bool {
+        let (true, Some(exit)) = (self.exit_sent, self.session.exited) else {
+            return false;
+        };
+        let plain = proto::encode(proto::server::EXIT, &proto::encode_exit(exit.code));
+        let details = serde_json::to_vec(&exit).unwrap_or_default();
+        let detailed = proto::encode(
+            proto::server::EXIT,
+            &proto::encode_exit_details(exit.code, &details),
+        );
+        for (&id, client) in self.clients.iter_mut() {
+            if client_id.is_none_or(|target| target == id) {
+                client.queue(if client.exit_details {
+                    &detailed
+                } else {
+                    &plain
+                });
+            }
+        }
+        true
+    }
+
+    fn is_read_only(&self, client_id: usize) -> bool {
+        self.clients.get(&client_id).is_some_and(|c| c.read_only)
+    }
+
+    /// Stop (`SIGSTOP`) or continue (`SIGCONT`) the child's processes.
+    fn set_suspended(&mut self, suspended: bool) -> io::Result<()> {
+        if suspended == self.suspended {
+            return Ok(());
+        }
+        let signal = if suspended {
+            nix::sys::signal::Signal::SIGSTOP
+        } else {
+            nix::sys::signal::Signal::SIGCONT
+        };
+        self.session.signal_process_groups(signal)?;
+        self.suspended = suspended;
+        log::info!(
+            "Session {}",
+            if suspended { "suspended" } else { "resumed" }
+        );
+        self.write_meta();
+        Ok(())
+    }
+
+    /// Send the signal requested in a SIGNAL frame to the child's processes.
+    fn deliver_signal(
+        &mut self,
+        client_id: usize,
+        payload: &[u8],
+    ) -> Result<nix::sys::signal::Signal, String> {
+        if self.is_read_only(client_id) {
+            return Err("read-only client".to_string());
+        }
+        if self.session.exited.is_some() {
+            return Err("the command has already exited".to_string());
+        }
+        let request: serde_json::Value =
+            serde_json::from_slice(payload).map_err(|e| e.to_string())?;
+        let signal = request["signal"]
+            .as_i64()
+            .and_then(|n| i32::try_from(n).ok())
+            .and_then(|n| nix::sys::signal::Signal::try_from(n).ok())
+            .ok_or_else(|| format!("invalid signal: {}", request["signal"]))?;
+        self.session
+            .signal_process_groups(signal)
+            .map_err(|e| e.to_string())?;
+        log::info!("Client {} sent {}", client_id, signal);
+        Ok(signal)
+    }
+
+    /// The `respawn` command: run the session's command again on the same
+    /// pty, after it exited or, with `{"force": true}`, after killing it.
+    /// A forced respawn sends no `EXIT`, so attached clients stay.
+    fn respawn(
+        &mut self,
+        client_id: usize,
+        args: &serde_json::Value,
+    ) -> Result<serde_json::Value, String> {
+        if self.is_read_only(client_id) {
+            return Err("read-only client".to_string());
+        }
+        if self.meta.sandbox.is_some() {
+            return Err("the daemon is sandboxed".to_string());
+        }
+        if self.session.exited.is_none() {
+            if args["force"].as_bool() != Some(true) {
+                return Err("the command is still running (--force kills it first)".to_string());
+            }
+            self.session
+                .signal_process_groups(nix::sys::signal::Signal::SIGKILL)
+                .map_err(|e| e.to_string())?;
+            let deadline = Instant::now() + RESPAWN_KILL_TIMEOUT;
+            while self.session.check_exit().is_none() {
+                if Instant::now() >= deadline {
+                    return Err("the command did not exit after SIGKILL".to_string());
+                }
+                std::thread::sleep(Duration::from_millis(10));
+            }
+        }
+        self.session.respawn().map_err(|e| e.to_string())?;
+        let pid = self.session.pty.child_pid.as_raw();
+        log::info!("Command respawned as pid {}", pid);
+        self.exit_sent = false;
+        self.suspended = false;
+        self.meta.child_pid = pid;
+        self.write_meta();
+        Ok(serde_json::json!({ "pid": pid }))
+    }
+
+    /// Resume a suspended session on attach or input, if `auto-resume` is on.
+    fn auto_resume(&mut self) {
+        if self.suspended && self.options.auto_resume {
+            if let Err(e) = self.set_suspended(false) {
+                log::warn!("Failed to resume session: {}", e);
+            }
+        }
+    }
+
+    /// The session lives in the fallback socket root.
+    fn relocated(&self) -> bool {
+        self.socket_home
+            .as_ref()
+            .is_some_and(|home| home.dir != self.session_dir)
+    }
+
+    /// The socket root the session currently lives in is gone, e.g. with a
+    /// runtime directory cleaned on logout.
+    fn socket_root_vanished(&self) -> bool {
+        let root = match &self.socket_home {
+            Some(_) if self.relocated() => self.fallback_socket_dir.as_ref(),
+            Some(home) => Some(&home.root),
+            None => None,
+        };
+        root.is_some_and(|root| !root.is_dir())
+    }
+
+    /// Re-create the socket in the session's home once the directory
+    /// holding its socket root exists (again), else move to the fallback
+    /// socket root. Tried at most once per `SOCKET_RECOVERY_INTERVAL`;
+    /// failures are retried.
+    fn recover_socket(&mut self, socket_present: bool) {
+        if self
+            .last_socket_recovery
+            .is_some_and(|at| at.elapsed() < SOCKET_RECOVERY_INTERVAL)
+        {
+            return;
+        }
+        self.last_socket_recovery = Some(Instant::now());
+        let Some(home) = self.socket_home.clone() else {
+            return;
+        };
+        let (root, dir) = if home.root.parent().is_some_and(Path::is_dir) {
+            // Another session may have taken the name while we were away.
+            if self.relocated() && home.dir.join(paths::SOCKET_FILENAME).exists() {
+                return;
+            }
+            (home.root.clone(), home.dir.clone())
+        } else if let (false, Some(fallback)) = (socket_present, &self.fallback_socket_dir) {
+            let name = home.dir.strip_prefix(&home.root).unwrap_or(&home.dir);
+            (fallback.clone(), fallback.join(name))
+        } else {
+            return;
+        };
+        match self.rebind(&root, &dir) {
+            Ok(()) => log::warn!(
+                "Socket root of session '{}' was removed; socket re-created at {}",
+                self.session.name,
+                self.socket_path.display()
+            ),
+            Err(e) => log::debug!("Failed to re-create socket in {}: {}", dir.display(), e),
+        }
+    }
+
+    /// Listen on a new socket in session directory `dir` below socket root
+    /// `root`, creating both as needed, and leave the previous one.
+    fn rebind(&mut self, root: &Path, dir: &Path) -> io::Result<()> {
+        use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+
+        std::fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(dir)?;
+        paths::check_socket_dir(root)?;
+        let socket_path = dir.join(paths::SOCKET_FILENAME);
+        if std::fs::symlink_metadata(&socket_path).is_ok() {
+            std::fs::remove_file(&socket_path)?;
+        }
+        let mut listener = UnixListener::bind(&socket_path)?;
+        std::fs::set_permissions(&socket_path, std::fs::Permissions::from_mode(0o700))?;
+        let registry = self.poll.registry();
+        registry.deregister(&mut self.listener)?;
+        registry.register(&mut listener, LISTENER, Interest::READABLE)?;
+        self.listener = listener;
+
+        let previous = std::mem::replace(&mut self.session_dir, dir.to_path_buf());
+        self.socket_path = socket_path;
+        if previous != self.session_dir {
+            self.leave_session_dir(&previous);
+            registry::unregister(&previous);
+            registry::register(&self.session_dir);
+        }
+        self.write_meta();
+        let note = format!(
+            "{} socket re-created in {} after its socket root had been removed\n",
+            meta::unix_now(),
+            root.display()
+        );
+        std::fs::write(dir.join(RECOVERY_NOTE_FILENAME), note)
+    }
+
+    /// Remove this daemon's files from a session directory it moved away
+    /// from, and the directory itself and its parents below the fallback
+    /// socket root as far as they are empty. Child sessions stay.
+    fn leave_session_dir(&self, dir: &Path) {
+        for file in [
+            paths::SOCKET_FILENAME,
+            meta::META_FILENAME,
+            RECOVERY_NOTE_FILENAME,
+        ] {
+            let _ = std::fs::remove_file(dir.join(file));
+        }
+        let mut dir = Some(dir);
+        while let Some(d) = dir {
+            if Some(d) == self.fallback_socket_dir.as_deref() || std::fs::remove_dir(d).is_err() {
+                break;
+            }
+            dir = d.parent();
+        }
+    }
+
+    /// Run the scheduled jobs for this session that came due.
+    fn run_scheduled_jobs(&mut self) {
+        for job in self.scheduler.due(&self.session.name, meta::unix_now()) {
+            let attached = self.clients.values().any(|c| c.attached);
+            if job.if_detached && attached {
+                log::info!(
+                    "Skipping scheduled job {} ({}): clients attached",
+                    job.id,
+                    job.action
+                );
+                continue;
+            }
+            if self.session.exited.is_some() && job.action != schedule::Action::Kill {
+                continue;
+            }
+            log::info!("Running scheduled job {}: {}", job.id, job.action);
+            let result = match &job.action {
+                // The socket disappears with the directory, which ends the run loop.
+                schedule::Action::Kill => paths::remove_session_dir(&self.session_dir),
+                schedule::Action::Suspend => self.set_suspended(true),
+                schedule::Action::Resume => self.set_suspended(false),
+                schedule::Action::Exec { command } => {
+                    let line = format!("{}\r", schedule::shell_join(command));
+                    self.session.write_pty(line.as_bytes())
+                }
+            };
+            if let Err(e) = result {
+                log::warn!("Scheduled job {} failed: {}", job.id, e);
+            }
+        }
+    }
+
+    /// Start a `pterm exec` request: type its command line into the pty.
+    /// Returns the request id.
+    fn start_exec(&mut self, client_id: usize, payload: &[u8]) -> Result<u32, String> {
+        let request: serde_json::Value =
+            serde_json::from_slice(payload).map_err(|e| e.to_string())?;
+        let command: Vec<String> =
+            serde_json::from_value(request["command"].clone()).map_err(|e| e.to_string())?;
+        if command.is_empty() {
+            return Err("a command is required".to_string());
+        }
+        let wrapped = request["wrap"].as_bool().unwrap_or(true);
+        if self.is_read_only(client_id) {
+            return Err("read-only client".to_string());
+        }
+        if self.session.exited.is_some() {
+            return Err("the command has already exited".to_string());
+        }
+        if self.held_input.is_some() {
+            return Err("input is held for confirmation".to_string());
+        }
+
+        self.next_exec_id += 1;
+        let id = self.next_exec_id;
+        let aid = exec::aid(id);
+        let line = exec::typed_line(&command, wrapped.then_some(aid.as_str()));
+        if let Some(guarded) = guard::find(&self.options.guard, "", line.as_bytes()) {
+            return Err(format!("the command matches guard '{}'", guarded.pattern));
+        }
+        self.auto_resume();
+        if self.execs.is_empty() {
+            self.exec_scanner.reset();
+        }
+        self.session
+            .write_pty(line.as_bytes())
+            .map_err(|e| e.to_string())?;
+        self.meta.stats.input_bytes += line.len() as u64;
+        self.meta
+            .activity
+            .input(meta::unix_now(), line.len() as u64);
+        self.note_activity();
+        log::info!("Exec {}: {}", id, schedule::shell_join(&command));
+        self.execs.push(PendingExec {
+            client_id,
+            id,
+            wrapped,
+        });
+        Ok(id)
+    }
+
+    /// Answer the `pterm exec` requests whose status marks were found: a
+    /// wrapped request by its `aid`, an unwrapped one (oldest first) by any
+    /// mark that is not another request's.
+    fn finish_execs(&mut self, statuses: Vec<exec::Status>) {
+        for status in statuses {
+            let aid = status.aid.as_deref();
+            let index = match aid.filter(|aid| aid.starts_with("pterm-exec-")) {
+                Some(aid) => self
+                    .execs
+                    .iter()
+                    .position(|e| e.wrapped && exec::aid(e.id) == aid),
+                None => self.execs.iter().position(|e| !e.wrapped),
+            };
+            let Some(index) = index else {
+                continue;
+            };
+            let pending = self.execs.remove(index);
+            log::info!("Exec {} finished with status {}", pending.id, status.code);
+            let payload = serde_json::json!({ "id": pending.id, "status": status.code });
+            self.send(
+                pending.client_id,
+                proto::server::EXEC_STATUS,
+                payload.to_string().as_bytes(),
+            );
+        }
+    }
+
+    /// Answer a `WATCH` request: list, add or remove output watchers.
+    fn handle_watch(&mut self, payload: &[u8]) -> serde_json::Value {
+        let mut reply = serde_json::json!({});
+        if !payload.is_empty() {
+            match self.change_watches(payload) {
+                Ok(Some(id)) => reply["added"] = id.into(),
+                Ok(None) => {}
+                Err(e) => return serde_json::json!({ "error": e }),
+            }
+        }
+        reply["watches"] = serde_json::json!(self.watchers.list());
+        reply
+    }
+
+    /// Apply a `WATCH` add or remove request. Returns the id of an added
+    /// watch.
+    fn change_watches(&mut self, payload: &[u8]) -> Result<Option<u32>, String> {
+        let request: serde_json::Value =
+            serde_json::from_slice(payload).map_err(|e| e.to_string())?;
+        if let Some(add) = request.get("add") {
+            if self.meta.sandbox.is_some() {
+                return Err("the daemon is sandboxed (--sandbox) and cannot run commands".into());
+            }
+            let strings = |key: &str| -> Result<Vec<String>, String> {
+                serde_json::from_value(add[key].clone()).map_err(|e| e.to_string())
+            };
+            let id = self
+                .watchers
+                .add(strings("patterns")?, strings("command")?)?;
+            return Ok(Some(id));
+        }
+        let id = request["remove"].as_u64().ok_or("expected add or remove")?;
+        if !self.watchers.remove(id as u32) {
+            return Err(format!("no watch '{}'", id));
+        }
+        Ok(None)
+    }
+
+    /// The `SESSION_INFO` document.
+    fn session_info(&mut self) -> serde_json::Value {
+        let (cols, rows) = self.session.size();
+        let (builds, hits) = self.session.snapshot_stats();
+        serde_json::json!({
+            "session": self.session.name,
+            "command": self.session.command,
+            "created_at": self.meta.created_at,
+            "cwd": self.session.cwd(),
+            "term": std::env::var("TERM").ok(),
+            "cols": cols,
+            "rows": rows,
+            "pid": self.session.pty.child_pid.as_raw(),
+            "foreground_pid": self.session.foreground_pid().as_raw(),
+            "tty": self.session.pty.slave_path(),
+            "clients": self.clients.values().filter(|c| c.attached).count(),
+            "scrollback": {
+                "lines": self.session.history_lines(),
+                "capacity": self.options.scrollback,
+                "file_bytes": self.scrollback.as_ref().map(|s| s.retained()),
+            },
+            "snapshots": { "built": builds, "reused": hits },
+            "sandbox": self.meta.sandbox,
+            "nested_in": self.meta.nested_in,
+            "exit": self.session.exited,
+            "activity": self.meta.activity,
+            "exit_hook": self.meta.exit_hook,
+        })
+    }
+
+    /// The `METADATA` document, for `client_id`: as `session_info` but
+    /// with scrollback usage and the clients other than the asker.
+    fn session_metadata(&mut self, client_id: usize) -> serde_json::Value {
+        let (cols, rows) = self.session.size();
+        let others = self.clients.iter().filter(|&(&id, _)| id != client_id);
+        let attached = others.clone().filter(|(_, c)| c.attached).count();
+        serde_json::json!({
+            "session": self.session.name,
+            "pid": self.session.pty.child_pid.as_raw(),
+            "command": self.session.command,
+            "cwd": self.session.cwd(),
+            "cols": cols,
+            "rows": rows,
+            "scrollback": {
+                "lines": self.session.history_lines(),
+                "capacity": self.options.scrollback,
+            },
+            "clients": { "attached": attached, "connected": others.count() },
+        })
+    }
+
+    /// Answer a `COMMAND` from `client_id`.
+    fn handle_command(&mut self, client_id: usize, payload: &[u8]) -> serde_json::Value {
+        let command = match Command::parse(payload) {
+            Ok(command) => command,
+            Err(e) => return serde_json::json!({ "error": e }),
+        };
+        let result = match command.cmd.as_str() {
+            "query" => match command.args["what"].as_str() {
+                Some("info") => Ok(self.session_info()),
+                Some("metadata") => Ok(self.session_metadata(client_id)),
+                Some("options") => Ok(self.options_reply()["options"].take()),
+                _ => Err("query needs \"what\": info, metadata or options".to_string()),
+            },
+            // For the `on-output` commands the daemon starts from now on;
+            // the session's command keeps the environment it started with.
+            "share" => self.share(&command.args),
+            "unshare" => Ok(self.unshare(client_id)),
+            "on-exit" => self.exit_hook_command(&command.args),
+            "respawn" => self.respawn(client_id, &command.args),
+            "setenv" => env_change(&command.args).map(|(name, value)| {
+                let previous = std::env::var(&name).ok();
+                match &value {
+                    Some(value) => std::env::set_var(&name, value),
+                    None => std::env::remove_var(&name),
+                }
+                serde_json::json!({ "name": name, "value": value, "previous": previous })
+            }),
+            cmd => Err(format!("unknown command '{}'", cmd)),
+        };
+        match result {
+            Ok(result) => serde_json::json!({ "cmd": command.cmd, "result": result }),
+            Err(error) => serde_json::json!({ "cmd": command.cmd, "error": error }),
+        }
+    }
+
+    /// Queue `SESSION_INFO` for a client that has just attached. Queued
+    /// after the attach snapshot, which replaces the send buffer.
+    fn queue_session_info(&mut self, client_id: usize) {
+        let info = self.session_info().to_string();
+        if let Some(client) = self.clients.get_mut(&client_id) {
+            client.queue(&proto::encode(proto::server::SESSION_INFO, info.as_bytes()));
+        }
+    }
+
+    /// Handle a message type this daemon does not know. Unknown control
+    /// requests are answered with an error `REPLY` so the sender fails fast
+    /// instead of waiting for an answer; anything else is dropped.
+    fn handle_unknown_frame(&mut self, client_id: usize, frame: &proto::Frame) {
+        if proto::is_control(frame.msg_type) {
+            log::warn!(
+                "Client {} sent unsupported control message 0x{:02x}",
+                client_id,
+                frame.msg_type
+            );
+            let error = format!("unsupported request 0x{:02x}", frame.msg_type);
+            self.reply(client_id, &serde_json::json!({ "error": error }));
+        } else {
+            log::warn!("Unknown message type: 0x{:02x}", frame.msg_type);
+        }
+    }
+
+    /// Queue a `REPLY` for a control request.
+    fn reply(&mut self, client_id: usize, value: &serde_json::Value) {
+        let reply = value.to_string();
+        self.send(client_id, proto::server::REPLY, reply.as_bytes());
+    }
+
+    /// Disconnect `target` (or, if `None`, every attached client other than
+    /// `requested_by`) with a `DETACHED` notice giving `reason`. Returns the
+    /// detached ids.
+    fn detach_clients(
+        &mut self,
+        requested_by: usize,
+        target: Option<usize>,
+        reason: &str,
+    ) -> Vec<usize> {
+        let mut ids: Vec<usize> = match target {
+            Some(id) if id != requested_by && self.clients.contains_key(&id) => vec![id],
+            Some(_) => Vec::new(),
+            None => self
+                .clients
+                .iter()
+                .filter(|&(&id, c)| id != requested_by && c.attached)
+                .map(|(&id, _)| id)
+                .collect(),
+        };
+        ids.sort_unstable();
+        for &id in &ids {
+            log::info!(
+                "Detaching client {} (requested by client {}): {}",
+                id,
+                requested_by,
+                reason
+            );
+            if let Some(client) = self.clients.get_mut(&id) {
+                client.queue(&proto::encode(proto::server::DETACHED, reason.as_bytes()));
+            }
+            // Best effort: a wedged client may never read it, and closing the
+            // connection below detaches it either way.
+            let _ = self.flush_client_send_buf(id);
+            self.remove_client(id);
+        }
+        ids
+    }
+
+    /// Issue a `pterm share` token valid for `args["duration"]` seconds.
+    fn share(&mut self, args: &serde_json::Value) -> Result<serde_json::Value, String> {
+        let Some(secs) = args["duration"].as_u64().filter(|&secs| secs > 0) else {
+            return Err("share needs \"duration\": seconds".to_string());
+        };
+        if self.key.is_some() {
+            return Err("an encrypted session cannot be shared".to_string());
+        }
+        let share = self.shares.issue(&self.socket_path, secs)?;
+        log::info!("Issued a share token for {}s", secs);
+        Ok(serde_json::json!({
+            "token": share.token,
+            "expires_at": share.expires_at,
+            "socket": self.socket_path,
+        }))
+    }
+
+    /// Revoke every `pterm share` token and detach the guests.
+    fn unshare(&mut self, client_id: usize) -> serde_json::Value {
+        let revoked = self.shares.revoke(&self.socket_path);
+        let guests: Vec<usize> = self
+            .clients
+            .iter()
+            .filter(|(_, c)| c.guest.is_some())
+            .map(|(&id, _)| id)
+            .collect();
+        let mut detached = Vec::new();
+        for id in guests {
+            detached.extend(self.detach_clients(client_id, Some(id), "the share was revoked"));
+        }
+        log::info!("Revoked {} share token(s)", revoked);
+        serde_json::json!({ "revoked": revoked, "detached": detached })
+    }
+
+    /// Connections are accepted from the session's owner and root.
+    fn peer_allowed(&self, peer: &PeerIdentity) -> bool {
+        match peer.uid {
+            Some(uid) => uid == self.owner || uid == 0,
+            // Without credentials, only as long as the socket's permissions
+            // are the daemon's own.
+            None => self.owner == nix::unistd::geteuid().as_raw(),
+        }
+    }
+
+    /// Apply a `TRANSFER` request from `client_id`: make `owner` the
+    /// session's owner and detach the clients of other users.
+    fn transfer(&mut self, client_id: usize, payload: &[u8]) -> serde_json::Value {
+        let requested_by_root = self
+            .clients
+            .get(&client_id)
+            .is_some_and(|c| c.peer.uid == Some(0));
+        if !requested_by_root {
+            return serde_json::json!({ "error": "only root can transfer a session" });
+        }
+        let request: serde_json::Value = serde_json::from_slice(payload).unwrap_or_default();
+        let Some(owner) = request["owner"]
+            .as_u64()
+            .and_then(|o| u32::try_from(o).ok())
+        else {
+            return serde_json::json!({ "error": "expected {\"owner\": uid}" });
+        };
+        log::info!(
+            "Session '{}' transferred from uid {} to uid {}",
+            self.session.name,
+            self.owner,
+            owner
+        );
+        self.owner = owner;
+        let others: Vec<usize> = self
+            .clients
+            .iter()
+            .filter(|&(&id, c)| id != client_id && !self.peer_allowed(&c.peer))
+            .map(|(&id, _)| id)
+            .collect();
+        let reason = format!("session '{}' was given to another user", self.session.name);
+        let mut detached = Vec::new();
+        for id in others {
+            detached.extend(self.detach_clients(client_id, Some(id), &reason));
+        }
+        serde_json::json!({ "owner": owner, "detached": detached })
+    }
+
+    /// Tell the other attached clients that `client_id` is typing.
+    fn broadcast_presence(&mut self, client_id: usize) {
+        let Some(client) = self.clients.get_mut(&client_id) else {
+            return;
+        };
+        if client
+            .last_presence
+            .is_some_and(|sent| sent.elapsed() < PRESENCE_INTERVAL)
+        {
+            return;
+        }
+        client.last_presence = Some(Instant::now());
+        let label = client.peer.label(client_id);
+        // Clients still waiting for their snapshot get nothing before it.
+        let ids: Vec<usize> = self
+            .clients
+            .iter()
+            .filter(|(&id, c)| id != client_id && c.attached && !c.pending_snapshot)
+            .map(|(&id, _)| id)
+            .collect();
+        if ids.is_empty() {
+            return;
+        }
+        let presence = serde_json::json!({
+            "client": client_id,
+            "label": label,
+            "last_input": meta::unix_now(),
+        });
+        let presence = presence.to_string();
+        for id in ids {
+            self.send(id, proto::server::PRESENCE, presence.as_bytes());
+        }
+    }
+
+    /// Write client input to the pty, holding back the Enter of a command
+    /// line the `guard` option matches until the client confirms it.
+    /// Answer a HELLO from `client_id` with the protocol version to speak.
+    /// Returns `false` if the client speaks no version this daemon does; it
+    /// has then been told so and is to be disconnected.
+    fn hello(&mut self, client_id: usize, payload: &[u8]) -> bool {
+        let request: serde_json::Value = serde_json::from_slice(payload).unwrap_or_default();
+        let version = request["version"].as_u64().unwrap_or(0);
+        let min_version = request["min_version"].as_u64().unwrap_or(version);
+        let clamp = |v: u64| u16::try_from(v).unwrap_or(u16::MAX);
+        let compression = request["compression"]
+            .as_array()
+            .is_some_and(|list| list.iter().any(|c| c == proto::COMPRESSION_ZSTD));
+        let stderr =
+            request["stderr"].as_bool() == Some(true) && self.session.stderr_fd().is_some();
+        let output_seq = request["seq"].as_bool() == Some(true);
+        let exit_details = request["exit_details"].as_bool() == Some(true);
+        let frame_marks = request["frame_marks"].as_bool() == Some(true);
+        let window = request["window"].as_u64().map(|w| w.max(MIN_OUTPUT_WINDOW));
+        let checksum = request["checksum"]
+            .as_array()
+            .is_some_and(|list| list.iter().any(|c| c == proto::CHECKSUM_CRC32));
+        let encrypt = self.encryption(client_id, &request["encrypt"]);
+        let mut guest_until = None;
+        if let Some(Guest::Pending) = self.clients.get(&client_id).and_then(|c| c.guest) {
+            let token = request["share"].as_str().unwrap_or_default();
+            guest_until = self.shares.redeem(&self.socket_path, token);
+            if guest_until.is_none() {
+                log::warn!("Client {} refused: no valid share token", client_id);
+                let answer = serde_json::json!({
+                    "error": "the share token is unknown, used up or expired",
+                });
+                if let Some(client) = self.clients.get_mut(&client_id) {
+                    client.queue_plain(&proto::encode(
+                        proto::server::HELLO,
+                        answer.to_string().as_bytes(),
+                    ));
+                }
+                self.flush_or_remove(client_id);
+                return false;
+            }
+        }
+        let answer = match proto::negotiate_version(clamp(version), clamp(min_version)) {
+            Some(version) => {
+                log::info!(
+                    "Client {} speaks protocol {}{}{}",
+                    client_id,
+                    version,
+                    if compression { " with zstd output" } else { "" },
+                    if stderr { " and separate stderr" } else { "" }
+                );
+                if let Some(client) = self.clients.get_mut(&client_id) {
+                    client.compression = compression;
+                    client.stderr = stderr;
+                    client.output_seq = output_seq;
+                    client.exit_details = exit_details;
+                    client.credit = window.map(Credit::new);
+                    client.frame_marks = frame_marks.then_some(0);
+                    if let Some(until) = guest_until {
+                        log::info!("Client {} redeemed a share token", client_id);
+                        client.guest = Some(Guest::Admitted { until });
+                        client.read_only = true;
+                    }
+                    client.resume_from = request["resume"].as_u64().filter(|_| output_seq);
+                }
+                let mut answer = serde_json::json!({ "version": version });
+                if compression {
+                    answer["compression"] = proto::COMPRESSION_ZSTD.into();
+                }
+                if stderr {
+                    answer["stderr"] = true.into();
+                }
+                if output_seq {
+                    answer["seq"] = true.into();
+                }
+                if exit_details {
+                    answer["exit_details"] = true.into();
+                }
+                if checksum {
+                    answer["checksum"] = proto::CHECKSUM_CRC32.into();
+                }
+                if let Some(window) = window {
+                    answer["window"] = window.into();
+                }
+                if frame_marks {
+                    answer["frame_marks"] = true.into();
+                }
+                if let Some((_, server_nonce)) = &encrypt {
+                    answer["encrypt"] = serde_json::json!({
+                        "cipher": proto::ENCRYPT_CHACHA20_POLY1305,
+                        "nonce": session_key::to_hex(server_nonce),
+                    });
+                }
+                answer
+            }
+            None => {
+                log::warn!(
+                    "Client {} speaks protocol {}..={}, rejecting",
+                    client_id,
+                    min_version,
+                    version
+                );
+                let error = format!(
+                    "protocol {} is not supported: the daemon speaks {} to {}",
+                    version,
+                    proto::MIN_PROTOCOL_VERSION,
+                    proto::PROTOCOL_VERSION
+                );
+                serde_json::json!({
+                    "error": error,
+                    "version": proto::PROTOCOL_VERSION,
+                    "min_version": proto::MIN_PROTOCOL_VERSION,
+                })
+            }
+        };
+        let payload = answer.to_string();
+        if let Some(client) = self.clients.get_mut(&client_id) {
+            client.queue_plain(&proto::encode(proto::server::HELLO, payload.as_bytes()));
+        }
+        self.flush_or_remove(client_id);
+        // The answer itself goes out plain: the client only knows to check
+        // and open frames once it has read it.
+        if answer["error"].is_null() {
+            if let Some(client) = self.clients.get_mut(&client_id) {
+                if checksum {
+                    client.framing = proto::Framing::Crc32;
+                }
+                if let Some((cipher, _)) = encrypt {
+                    client.sealing = Sealing::On(cipher);
+                }
+            }
+        }
+        answer["error"].is_null()
+    }
+
+    /// The cipher for `client_id` and the nonce to answer with, if it asks
+    /// to encrypt its connection in `HELLO` (`request` is the `encrypt`
+    /// field) on an encrypted session. A connection is encrypted once.
+    fn encryption(
+        &self,
+        client_id: usize,
+        request: &serde_json::Value,
+    ) -> Option<(
+        proto::crypto::Channel,
+        [u8; proto::crypto::SERVER_NONCE_LEN],
+    )> {
+        let key = self.key.as_ref()?;
+        let client_nonce = request["nonce"]
+            .as_str()
+            .and_then(session_key::from_hex)
+            .filter(|_| request["cipher"] == proto::ENCRYPT_CHACHA20_POLY1305)?;
+        if !matches!(self.clients.get(&client_id)?.sealing, Sealing::Required) {
+            return None;
+        }
+        let server_nonce = match session_key::random() {
+            Ok(nonce) => nonce,
+            Err(e) => {
+                log::warn!("Cannot encrypt client {}'s connection: {}", client_id, e);
+                return None;
+            }
+        };
+        let cipher = proto::crypto::Channel::server(key, &client_nonce, &server_nonce);
+        Some((cipher, server_nonce))
+    }
+
+    /// `frame` from `client_id` as it was before sealing. `None` if it may
+    /// not be taken: it failed to authenticate, or came in the clear on an
+    /// encrypted session. The client has then been told why and
+    /// disconnected.
+    fn unseal(&mut self, client_id: usize, frame: proto::Frame) -> Option<proto::Frame> {
+        let client = self.clients.get_mut(&client_id)?;
+        let refusal = match &mut client.sealing {
+            Sealing::Off => return Some(frame),
+            Sealing::Required if frame.msg_type == proto::client::HELLO => return Some(frame),
+            Sealing::Required => format!(
+                "session '{}' is encrypted: only clients that can read its key may connect",
+                self.session.name
+            ),
+            Sealing::On(cipher) => match cipher.open(frame.msg_type, &frame.payload) {
+                Ok(payload) => {
+                    return Some(proto::Frame {
+                        msg_type: frame.msg_type,
+                        payload,
+                    })
+                }
+                Err(e) => e.to_string(),
+            },
+        };
+        // Unsealed, so a client holding another key learns that much.
+        log::warn!("Client {} refused: {}", client_id, refusal);
+        let reply = serde_json::json!({ "error": refusal }).to_string();
+        client.queue_plain(&proto::encode(proto::server::REPLY, reply.as_bytes()));
+        self.flush_or_remove(client_id);
+        self.remove_client(client_id);
+        None
+    }
+
+    /// `frame` from `client_id`, unless it comes from a guest that may not
+    /// send it. A guest that has not redeemed a token yet may only say
+    /// `HELLO` and is disconnected for anything else; one that has is told
+    /// it can only watch.
+    fn screen_guest(&mut self, client_id: usize, frame: proto::Frame) -> Option<proto::Frame> {
+        let refusal = match self.clients.get(&client_id)?.guest {
+            None => return Some(frame),
+            Some(Guest::Pending) if frame.msg_type == proto::client::HELLO => return Some(frame),
+            Some(Guest::Admitted { .. }) if GUEST_FRAMES.contains(&frame.msg_type) => {
+                return Some(frame)
+            }
+            Some(Guest::Admitted { .. }) => {
+                if proto::is_control(frame.msg_type) {
+                    let error = "a guest of a shared session can only watch";
+                    self.reply(client_id, &serde_json::json!({ "error": error }));
+                }
+                return None;
+            }
+            Some(Guest::Pending) => format!(
+                "session '{}' belongs to another user: attach with a token from `pterm share`",
+                self.session.name
+            ),
+        };
+        log::warn!("Client {} refused: {}", client_id, refusal);
+        self.reply(client_id, &serde_json::json!({ "error": refusal }));
+        self.flush_or_remove(client_id);
+        self.remove_client(client_id);
+        None
+    }
+
+    /// Bind `client_id` to the input stream named in an INPUT_STREAM request.
+    fn bind_input_stream(&mut self, client_id: usize, payload: &[u8]) -> serde_json::Value {
+        let request: serde_json::Value = serde_json::from_slice(payload).unwrap_or_default();
+        let Some(stream) = request["stream"].as_u64() else {
+            return serde_json::json!({ "error": "missing stream id" });
+        };
+        let now = Instant::now();
+        let bound: Vec<u64> = self
+            .clients
+            .values()
+            .filter_map(|c| c.input_stream)
+            .collect();
+        self.input_streams.retain(|id, s| {
+            bound.contains(id) || now.duration_since(s.last_used) < INPUT_STREAM_WINDOW
+        });
+        if !self.input_streams.contains_key(&stream)
+            && self.input_streams.len() >= MAX_INPUT_STREAMS
+        {
+            let oldest = self
+                .input_streams
+                .iter()
+                .min_by_key(|(_, s)| s.last_used)
+                .map(|(&id, _)| id);
+            if let Some(oldest) = oldest {
+                self.input_streams.remove(&oldest);
+            }
+        }
+        let state = self.input_streams.entry(stream).or_insert(InputStream {
+            applied: 0,
+            last_used: now,
+        });
+        state.last_used = now;
+        let applied = state.applied;
+        if let Some(client) = self.clients.get_mut(&client_id) {
+            client.input_stream = Some(stream);
+        }
+        log::info!(
+            "Client {} bound to input stream {:x} (applied {})",
+            client_id,
+            stream,
+            applied
+        );
+        serde_json::json!({ "stream": stream, "applied": applied })
+    }
+
+    /// Whether INPUT_SEQ `seq` from `client_id` is new, recording it as
+    /// applied if so. Resent input at or below the last applied number is
+    /// dropped, as is input from a client that bound no stream.
+    fn accept_input_seq(&mut self, client_id: usize, seq: u64) -> bool {
+        let stream = self.clients.get(&client_id).and_then(|c| c.input_stream);
+        let Some(state) = stream.and_then(|stream| self.input_streams.get_mut(&stream)) else {
+            log::warn!(
+                "Client {} sent INPUT_SEQ without an input stream",
+                client_id
+            );
+            return false;
+        };
+        state.last_used = Instant::now();
+        if seq <= state.applied {
+            log::debug!(
+                "Dropping input {} from client {}: already applied up to {}",
+                seq,
+                client_id,
+                state.applied
+            );
+            return false;
+        }
+        state.applied = seq;
+        true
+    }
+
+    fn write_input(&mut self, client_id: usize, data: &[u8]) -> io::Result<()> {
+        let screen_line = self.session.cursor_line();
+        self.write_guarded(client_id, data, &screen_line)
+    }
+
+    fn write_guarded(
+        &mut self,
+        client_id: usize,
+        data: &[u8],
+        screen_line: &str,
+    ) -> io::Result<()> {
+        if let Some(held) = self.held_input.as_mut() {
+            // Keep later input in order behind the held Enter.
+            held.data.extend_from_slice(data);
+            return Ok(());
+        }
+        let guarded = guard::find(&self.options.guard, screen_line, data);
+        let offset = guarded.as_ref().map_or(data.len(), |g| g.offset);
+        self.session.write_pty(&data[..offset])?;
+        self.meta.stats.input_bytes += offset as u64;
+        self.meta.activity.input(meta::unix_now(), offset as u64);
+        let Some(guarded) = guarded else {
+            return Ok(());
+        };
+
+        self.next_confirm_id += 1;
+        let id = self.next_confirm_id;
+        log::info!(
+            "Guard: holding '{}' from client {} (matches '{}')",
+            guarded.line,
+            client_id,
+            guarded.pattern
+        );
+        self.held_input = Some(HeldInput {
+            client_id,
+            id,
+            data: data[offset..].to_vec(),
+            since: Instant::now(),
+        });
+        let ask = serde_json::json!({
+            "id": id,
+            "line": guarded.line,
+            "pattern": guarded.pattern,
+        });
+        let ask = ask.to_string();
+        self.send(client_id, proto::server::CONFIRM, ask.as_bytes());
+        Ok(())
+    }
+
+    /// Submit (`accept`) or drop the held input. Input queued behind the
+    /// held Enter goes through the guard again, on a fresh line.
+    fn release_input(&mut self, accept: bool) -> io::Result<()> {
+        let Some(held) = self.held_input.take() else {
+            return Ok(());
+        };
+        if !accept {
+            log::info!("Guard: input {} dropped", held.id);
+            return Ok(());
         }
+        log::info!("Guard: input {} confirmed", held.id);
+        self.session.write_pty(&held.data[..1])?;
+        self.meta.stats.input_bytes += 1;
+        self.meta.activity.input(meta::unix_now(), 1);
+        self.write_guarded(held.client_id, &held.data[1..], "")
+    }
 
+    /// Pass the pty master fd to a client over `SCM_RIGHTS` so it can write
+    /// input straight to the pty. Output keeps flowing through the daemon: the
+    /// pty has a single reader, and the terminal state must see every byte.
+    fn send_pty_input_fd(&mut self, client_id: usize) -> io::Result<()> {
+        let Some(client) = self.clients.get(&client_id) else {
+            return Ok(());
+        };
+        let msg = proto::encode(proto::server::PTY_FD, &[]);
+        let fds = [self.session.master_fd()];
+        loop {
+            match nix::sys::socket::sendmsg::<()>(
+                client.stream.as_raw_fd(),
+                &[io::IoSlice::new(&msg)],
+                &[nix::sys::socket::ControlMessage::ScmRights(&fds)],
+                nix::sys::socket::MsgFlags::empty(),
+                None,
+            ) {
+                Err(nix::errno::Errno::EINTR) => continue,
+                result => {
+                    result.map_err(io::Error::other)?;
+                    break;
+                }
+            }
+        }
+        log::info!("Passed pty fd to client {} for direct input", client_id);
         Ok(())
     }
 
-    /// Flush accumulated PTY output to all connected clients.
-    /// Clients still awaiting a snapshot receive the snapshot first (triggered
-    /// by the arrival of OUTPUT rather than a timer).
-    fn flush_pty_output(&mut self) {
-        if self.pending_pty_output.is_empty() {
-            return;
+    /// Hand the session over to `binary` by re-exec'ing in place. Only
+    /// returns if the handoff failed, in which case the daemon keeps running.
+    fn exec_upgrade(&mut self, binary: &Path) -> io::Error {
+        // Held connections would be closed by the exec; the new image
+        // adopts them as clients instead.
+        for (_, client) in std::mem::take(&mut self.held_connections) {
+            if let Err(e) = self.admit_client(client) {
+                return e;
+            }
         }
-
-        // Clients awaiting snapshot: the arrival of OUTPUT means the VT state
-        // is populated, so send their snapshot now (no timer needed).
-        // These clients must NOT also receive the raw OUTPUT bytes below,
-        // because the snapshot already reflects the effect of those bytes
-        // (read_pty feeds data to the VT parser before this method runs).
-        // Sending both would cause Neovim's libvterm to process the same
-        // content twice, resulting in duplicated rendering.
-        let snapshot_ids: Vec<usize> = self
-            .clients
-            .iter()
-            .filter_map(|(&id, c)| if c.pending_snapshot { Some(id) } else { None })
-            .collect();
-        for id in &snapshot_ids {
-            log::info!("Client {} snapshot triggered by PTY output arrival", *id);
-            self.send_snapshot_to_client(*id, true);
+        self.flush_pty_output();
+        self.flush_all_clients();
+        if let Some(recorder) = self.recorder.as_mut() {
+            let _ = recorder.flush();
         }
 
-        let msg = proto::encode(proto::server::OUTPUT, &self.pending_pty_output);
-        self.pending_pty_output.clear();
+        let (cols, rows) = self.session.size();
+        let state = upgrade::UpgradeState {
+            session: self.session.name.clone(),
+            command: self.session.command.clone(),
+            child_pid: self.session.pty.child_pid.as_raw(),
+            pty_fd: self.session.master_fd(),
+            stderr_fd: self.session.stderr_fd(),
+            listener_fd: self.listener.as_raw_fd(),
+            cols,
+            rows,
+            created_at: self.meta.created_at,
+            next_client_id: self.next_client_id,
+            options: self.options.clone(),
+            suspended: self.suspended,
+            stats: self.meta.stats.clone(),
+            scrollback_file: self.scrollback.as_ref().map(|s| s.capacity()),
+            stderr_file: self.stderr_file.as_ref().map(|s| s.capacity()),
+            stream_position: self.resume_log.end(),
+            strip_colors: self.scrollback.as_ref().is_some_and(|s| s.strips_colors()),
+            history: self.history.is_some(),
+            watches: self.watchers.list().to_vec(),
+            buffers: self.buffers,
+            owner: Some(self.owner),
+            socket_home: self.socket_home.clone(),
+            fallback_socket_dir: self.fallback_socket_dir.clone(),
+            nested_in: self.meta.nested_in.clone(),
+            activity: self.meta.activity.clone(),
+            exit_hook: self.meta.exit_hook.clone(),
+            shares: self.shares.clone(),
+            output_frames: self.output_frames,
+            // Sealed connections are not handed over: the cipher state stays
+            // out of the state file, and their bridges reconnect and resume.
+            // Nor are guests, whom nothing would hold to read-only after it.
+            clients: self
+                .clients
+                .iter()
+                .filter(|(_, c)| !matches!(c.sealing, Sealing::On(_)) && c.guest.is_none())
+                .map(|(&id, c)| upgrade::UpgradedClient {
+                    id,
+                    fd: c.stream.as_raw_fd(),
+                    attached: c.attached,
+                    read_only: c.read_only,
+                    connected_at: c.connected_at,
+                    input_stream: c.input_stream,
+                    compression: c.compression,
+                    stderr: c.stderr,
+                    output_seq: c.output_seq,
+                    exit_details: c.exit_details,
+                    checksum: c.framing == proto::Framing::Crc32,
+                    window: c.credit.map(|credit| credit.window),
+                    frame_marks: c.frame_marks,
+                    identity: c.identity.clone(),
+                })
+                .collect(),
+            input_streams: self
+                .input_streams
+                .iter()
+                .map(|(&stream, s)| (stream, s.applied))
+                .collect(),
+            direct_input: self.direct_input.clone(),
+        };
+        let fds: Vec<RawFd> = [state.pty_fd, state.listener_fd]
+            .into_iter()
+            .chain(state.stderr_fd)
+            .chain(state.clients.iter().map(|c| c.fd))
+            .collect();
 
-        let mut disconnected = Vec::new();
-        let mut flush_ids = Vec::new();
-        for (&id, client) in self.clients.iter_mut() {
-            // Skip clients that just received a snapshot — they already have
-            // the up-to-date screen state and must not get the raw bytes again.
-            if snapshot_ids.contains(&id) {
-                continue;
-            }
-            client.send_buf.extend_from_slice(&msg);
-            flush_ids.push(id);
+        if let Err(e) =
+            upgrade::write_state(&self.session_dir, &state, &self.session.upgrade_snapshot())
+        {
+            return e;
         }
-        for id in flush_ids {
-            if self.flush_client_send_buf(id).is_err() {
-                disconnected.push(id);
+        let mut err = None;
+        for &fd in &fds {
+            if let Err(e) = upgrade::set_cloexec(fd, false) {
+                err = Some(e);
+                break;
             }
         }
-        for id in disconnected {
-            log::info!("Client {} disconnected", id);
-            self.clients.remove(&id);
+        let err = err.unwrap_or_else(|| {
+            log::info!(
+                "Upgrading daemon for session '{}' to {}",
+                self.session.name,
+                binary.display()
+            );
+            upgrade::exec_resume(binary, &self.session_dir)
+        });
+
+        // exec failed: undo the handoff preparation and keep serving.
+        for &fd in &fds {
+            let _ = upgrade::set_cloexec(fd, true);
         }
+        let _ = upgrade::take_state(&self.session_dir);
+        err
     }
 
     fn set_client_interest(&mut self, client_id: usize, writable: bool) -> io::Result<()> {
@@ -398,84 +3349,71 @@ impl Server {
         Ok(())
     }
 
+    /// Flush a client's send buffer, polling for writability while bytes
+    /// remain queued.
     fn flush_client_send_buf(&mut self, client_id: usize) -> io::Result<()> {
-        let writable = {
-            let client = match self.clients.get_mut(&client_id) {
-                Some(c) => c,
-                None => return Ok(()),
-            };
-
-            while !client.send_buf.is_empty() {
-                match client.stream.write(&client.send_buf) {
-                    Ok(0) => {
-                        return Err(io::Error::new(
-                            io::ErrorKind::WriteZero,
-                            "client stream closed",
-                        ));
-                    }
-                    Ok(n) => {
-                        client.send_buf.drain(..n);
-                    }
-                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
-                    Err(e) => return Err(e),
-                }
-            }
-
-            if client.send_buf.len() >= LARGE_SEND_BUF_WARN_BYTES {
-                if !client.large_send_buf_warned {
-                    log::warn!(
-                        "Client {} send buffer backlog reached {} bytes",
-                        client_id,
-                        client.send_buf.len()
-                    );
-                    client.large_send_buf_warned = true;
-                }
-            } else {
-                client.large_send_buf_warned = false;
-            }
-
-            !client.send_buf.is_empty()
+        let writable = match self.clients.get_mut(&client_id) {
+            Some(client) => client.flush(client_id)?,
+            None => return Ok(()),
         };
-
         self.set_client_interest(client_id, writable)
     }
 
+    /// Flush a client's send buffer, dropping the client if its connection
+    /// has failed.
+    fn flush_or_remove(&mut self, client_id: usize) {
+        if let Err(e) = self.flush_client_send_buf(client_id) {
+            log::info!("Client {} disconnected: {}", client_id, e);
+            self.remove_client(client_id);
+        }
+    }
+
+    /// Queue a frame for one client and flush it.
+    fn send(&mut self, client_id: usize, msg_type: u8, payload: &[u8]) {
+        if let Some(client) = self.clients.get_mut(&client_id) {
+            client.queue(&proto::encode(msg_type, payload));
+        }
+        self.flush_or_remove(client_id);
+    }
+
     fn flush_all_clients(&mut self) {
         let ids: Vec<usize> = self.clients.keys().copied().collect();
-        let mut disconnected = Vec::new();
         for id in ids {
-            if self.flush_client_send_buf(id).is_err() {
-                disconnected.push(id);
-            }
-        }
-        for id in disconnected {
-            log::info!("Client {} disconnected during flush", id);
-            self.clients.remove(&id);
+            self.flush_or_remove(id);
         }
     }
 
     fn handle_client_data(&mut self, client_id: usize, buf: &mut [u8]) -> io::Result<()> {
-        let remove = {
+        // Readiness is edge-triggered: read until the socket is drained, or
+        // a large frame (e.g. a PASTE) would wait for the next event.
+        let closed = {
             let client = match self.clients.get_mut(&client_id) {
                 Some(c) => c,
                 None => return Ok(()),
             };
-            match client.stream.read(buf) {
-                Ok(0) => true,
-                Ok(n) => {
-                    client.recv_buf.extend_from_slice(&buf[..n]);
-                    false
+            loop {
+                match client.stream.read(buf) {
+                    Ok(0) => break true,
+                    Ok(n) => {
+                        client.decoder.feed(&buf[..n]);
+                        client.bytes_received += n as u64;
+                        client.last_recv = Instant::now();
+                        client.ping_sent = None;
+                        // Stop buffering what would be an oversized frame.
+                        if client.decoder.oversized().is_some() {
+                            break false;
+                        }
+                    }
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break false,
+                    Err(_) => break true,
                 }
-                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => false,
-                Err(_) => true,
             }
         };
 
-        if remove {
-            log::info!("Client {} disconnected", client_id);
-            self.clients.remove(&client_id);
-        } else if let Some(client) = self.clients.get_mut(&client_id) {
-            if !client.recv_buf.is_empty() {
+        // Frames sent right before closing (`control::send`) still count.
+        if let Some(client) = self.clients.get_mut(&client_id) {
+            if client.decoder.buffered() > 0 {
                 // Flush pending PTY output so the vt state is current before
                 // processing client messages (e.g. REDRAW, RESIZE snapshots).
                 self.flush_pty_output();
@@ -485,53 +3423,346 @@ impl Server {
                 }
             }
         }
+        if closed {
+            log::info!("Client {} disconnected", client_id);
+            self.remove_client(client_id);
+        }
         Ok(())
     }
 
     fn process_client_recv_buf(&mut self, client_id: usize) -> io::Result<bool> {
-        // Take the buffer out to avoid borrowing self.clients while using self.session
-        let mut recv_buf = match self.clients.get_mut(&client_id) {
-            Some(c) => std::mem::take(&mut c.recv_buf),
+        // Take the decoder out to avoid borrowing self.clients while using self.session
+        let mut decoder = match self.clients.get_mut(&client_id) {
+            Some(c) => std::mem::take(&mut c.decoder),
             None => return Ok(false),
         };
 
         let mut flush_all = false;
-        for frame in proto::decode_frames(&mut recv_buf) {
+        while let Some(frame) = decoder.next_frame() {
+            let Some(frame) = self.unseal(client_id, frame) else {
+                break;
+            };
+            let Some(frame) = self.screen_guest(client_id, frame) else {
+                if self.clients.contains_key(&client_id) {
+                    continue;
+                }
+                break;
+            };
+            if self.recorder.is_some() {
+                self.record(client_id, recording::Event::ClientFrame(frame.clone()));
+            }
             match frame.msg_type {
                 proto::client::INPUT => {
-                    self.session.write_pty(&frame.payload)?;
+                    if self.is_read_only(client_id) {
+                        continue;
+                    }
+                    self.auto_resume();
+                    self.write_input(client_id, &frame.payload)?;
+                    self.note_activity();
+                    self.broadcast_presence(client_id);
+                }
+                proto::client::INPUT_SEQ => {
+                    if self.is_read_only(client_id) {
+                        continue;
+                    }
+                    let (seq, input) = match proto::parse_input_seq(&frame.payload) {
+                        Ok(parsed) => parsed,
+                        Err(e) => {
+                            log::warn!(
+                                "Client {} sent invalid input seq payload: {}",
+                                client_id,
+                                e
+                            );
+                            continue;
+                        }
+                    };
+                    if !self.accept_input_seq(client_id, seq) {
+                        continue;
+                    }
+                    self.auto_resume();
+                    self.write_input(client_id, input)?;
+                    self.note_activity();
+                    self.broadcast_presence(client_id);
+                }
+                proto::client::HELLO => {
+                    if !self.hello(client_id, &frame.payload) {
+                        self.remove_client(client_id);
+                        break;
+                    }
+                    if let Some(client) = self.clients.get(&client_id) {
+                        decoder.set_framing(client.framing);
+                    }
+                }
+                proto::client::ATTACH => {
+                    if let Some(client) = self.clients.get_mut(&client_id) {
+                        client.replay = AttachReplay::parse(&frame.payload);
+                    }
+                }
+                proto::client::IDENTIFY => {
+                    match serde_json::from_slice::<ClientIdentity>(&frame.payload) {
+                        Ok(identity) => {
+                            if let Some(client) = self.clients.get_mut(&client_id) {
+                                client.identity = Some(identity);
+                            }
+                        }
+                        Err(e) => {
+                            log::warn!("Client {} sent invalid identity: {}", client_id, e);
+                        }
+                    }
+                }
+                proto::client::INPUT_STREAM => {
+                    let reply = self.bind_input_stream(client_id, &frame.payload);
+                    self.reply(client_id, &reply);
+                }
+                proto::client::CREDIT => match proto::parse_credit(&frame.payload) {
+                    Ok(bytes) => self.return_credit(client_id, bytes),
+                    Err(e) => log::warn!("Client {} sent invalid credit: {}", client_id, e),
+                },
+                proto::client::CONFIRM_REPLY => {
+                    let answer: serde_json::Value =
+                        serde_json::from_slice(&frame.payload).unwrap_or_default();
+                    let matches = self.held_input.as_ref().is_some_and(|held| {
+                        held.client_id == client_id && answer["id"].as_u64() == Some(held.id as u64)
+                    });
+                    if matches {
+                        let accept = answer["accept"].as_bool().unwrap_or(false);
+                        self.release_input(accept)?;
+                    }
                 }
+                proto::client::RESIZE if self.is_read_only(client_id) => {}
                 proto::client::RESIZE => {
                     let (cols, rows) = match proto::parse_resize(&frame.payload) {
                         Ok(size) => size,
                         Err(e) => {
+                            // A 0x0 pty wedges full-screen applications.
                             log::warn!("Client {} sent invalid resize payload: {}", client_id, e);
+                            self.reply(client_id, &serde_json::json!({ "error": e.to_string() }));
                             continue;
                         }
                     };
+                    if self.session.size() != (cols, rows) {
+                        self.resume_log.reset();
+                    }
                     self.session.resize(cols, rows)?;
+                    let mut newly_attached = false;
+                    if let Some(client) = self.clients.get_mut(&client_id) {
+                        if let Some(identity) = &mut client.identity {
+                            identity.cols = Some(cols);
+                            identity.rows = Some(rows);
+                        }
+                        if !client.attached {
+                            client.attached = true;
+                            newly_attached = true;
+                            self.write_meta();
+                            self.auto_resume();
+                        }
+                    }
 
                     // The latest RESIZE is authoritative for every attached
                     // client. Replacing all outbound queues prevents stale-size
                     // frames from surviving ahead of the fresh snapshot.
                     self.send_snapshot_to_all_clients(true);
+                    if newly_attached {
+                        self.queue_session_info(client_id);
+                        flush_all = true;
+                    }
+                    if self.requeue_exit(None) {
+                        flush_all = true;
+                    }
                 }
                 proto::client::DETACH => {}
+                proto::client::READ_ONLY => {
+                    if let Some(client) = self.clients.get_mut(&client_id) {
+                        log::info!("Client {} attached read-only", client_id);
+                        client.read_only = true;
+                        client.attached = true;
+                        self.write_meta();
+                    }
+                    self.auto_resume();
+                    self.send_snapshot_to_client(client_id, true);
+                    self.queue_session_info(client_id);
+                    self.requeue_exit(Some(client_id));
+                    flush_all = true;
+                }
+                proto::client::OPTIONS => {
+                    let reply = if frame.payload.is_empty() {
+                        self.options_reply()
+                    } else {
+                        let change = serde_json::from_slice::<serde_json::Value>(&frame.payload)
+                            .map_err(|e| e.to_string())
+                            .and_then(|v| {
+                                let name = v["name"].as_str().ok_or("missing option name")?;
+                                let value = v["value"].as_str().ok_or("missing option value")?;
+                                self.set_option(client_id, name, value)
+                            });
+                        match change {
+                            Ok(()) => self.options_reply(),
+                            Err(e) => serde_json::json!({ "error": e }),
+                        }
+                    };
+                    self.reply(client_id, &reply);
+                }
+                proto::client::SUSPEND | proto::client::RESUME => {
+                    let suspend = frame.msg_type == proto::client::SUSPEND;
+                    let reply = if self.session.exited.is_some() {
+                        serde_json::json!({ "error": "the command has already exited" })
+                    } else {
+                        match self.set_suspended(suspend) {
+                            Ok(()) => serde_json::json!({ "suspended": self.suspended }),
+                            Err(e) => serde_json::json!({ "error": e.to_string() }),
+                        }
+                    };
+                    self.reply(client_id, &reply);
+                }
+                proto::client::SIGNAL => {
+                    let reply = match self.deliver_signal(client_id, &frame.payload) {
+                        Ok(signal) => serde_json::json!({ "signal": signal as i32 }),
+                        Err(e) => serde_json::json!({ "error": e }),
+                    };
+                    self.reply(client_id, &reply);
+                }
+                proto::client::INFO => {
+                    let info = self.session_info();
+                    self.reply(client_id, &serde_json::json!({ "info": info }));
+                }
+                proto::client::METADATA => {
+                    let metadata = self.session_metadata(client_id);
+                    self.reply(client_id, &serde_json::json!({ "metadata": metadata }));
+                }
+                proto::client::COMMAND => {
+                    let reply = self.handle_command(client_id, &frame.payload);
+                    self.reply(client_id, &reply);
+                }
+                proto::client::WATCH => {
+                    let reply = self.handle_watch(&frame.payload);
+                    self.reply(client_id, &reply);
+                }
+                proto::client::TRANSFER => {
+                    let reply = self.transfer(client_id, &frame.payload);
+                    self.reply(client_id, &reply);
+                }
+                proto::client::EXEC => {
+                    let reply = match self.start_exec(client_id, &frame.payload) {
+                        Ok(id) => serde_json::json!({ "id": id }),
+                        Err(e) => serde_json::json!({ "error": e }),
+                    };
+                    self.reply(client_id, &reply);
+                }
+                proto::client::CAPTURE => {
+                    let request: serde_json::Value =
+                        serde_json::from_slice(&frame.payload).unwrap_or_default();
+                    let capture = self.session.capture(request["rewrap"] == true);
+                    self.reply(client_id, &capture.to_json());
+                }
+                proto::client::PASTE => {
+                    let guarded = guard::find(&self.options.guard, "", &frame.payload);
+                    let reply = if self.is_read_only(client_id) {
+                        serde_json::json!({ "error": "read-only client" })
+                    } else if let Some(guarded) = guarded {
+                        let error = format!(
+                            "the paste matches guard '{}'; type it in an attached client to confirm",
+                            guarded.pattern
+                        );
+                        serde_json::json!({ "error": error })
+                    } else if self.held_input.is_some() {
+                        serde_json::json!({ "error": "input is held for confirmation" })
+                    } else if self.session.exited.is_some() {
+                        serde_json::json!({ "error": "the command has already exited" })
+                    } else {
+                        self.auto_resume();
+                        let pasted = self.session.paste(&frame.payload)?;
+                        self.meta.stats.input_bytes += pasted as u64;
+                        self.meta.activity.input(meta::unix_now(), pasted as u64);
+                        self.note_activity();
+                        serde_json::json!({ "pasted": pasted })
+                    };
+                    self.reply(client_id, &reply);
+                }
+                proto::client::LIST_CLIENTS => {
+                    let mut clients: Vec<ClientInfo> = self
+                        .clients
+                        .iter()
+                        .filter(|&(&id, _)| id != client_id)
+                        .map(|(&id, c)| c.info(id))
+                        .collect();
+                    clients.sort_by_key(|c| c.id);
+                    self.reply(client_id, &serde_json::json!({ "clients": clients }));
+                }
+                proto::client::PONG => {
+                    if let Some(client) = self.clients.get_mut(&client_id) {
+                        client.keepalive = true;
+                    }
+                }
                 proto::client::REDRAW => {
                     log::info!("Redraw requested by client {}", client_id);
-                    let mut redraw_data = b"\x1b[2J\x1b[H".to_vec();
-                    redraw_data.extend_from_slice(&self.session.snapshot());
-                    let msg = proto::encode(proto::server::STATE_SYNC, &redraw_data);
-                    for (_, client) in self.clients.iter_mut() {
-                        client.send_buf.extend_from_slice(&msg);
-                    }
+                    self.redraw_all_clients(Some(client_id));
                     flush_all = true;
                 }
-                _ => log::warn!("Unknown message type: 0x{:02x}", frame.msg_type),
+                proto::client::UPGRADE => {
+                    let binary = PathBuf::from(std::ffi::OsStr::from_bytes(&frame.payload));
+                    log::info!(
+                        "Upgrade to {} requested by client {}",
+                        binary.display(),
+                        client_id
+                    );
+                    self.upgrade_to = Some(binary);
+                }
+                proto::client::DETACH_CLIENTS => {
+                    let (target, reason) = match DetachRequest::parse(&frame.payload) {
+                        Ok(request) => (request.client, request.reason),
+                        Err(e) => {
+                            log::warn!("Client {} sent invalid detach payload: {}", client_id, e);
+                            continue;
+                        }
+                    };
+                    let reason =
+                        reason.unwrap_or_else(|| format!("requested by client {}", client_id));
+                    let detached = self.detach_clients(client_id, target, &reason);
+                    self.reply(client_id, &serde_json::json!({ "detached": detached }));
+                }
+                proto::client::PTY_INPUT_FD => {
+                    // Direct input would bypass the guard.
+                    if !self.options.guard.is_empty() {
+                        log::info!("Guard active: not passing pty fd to client {}", client_id);
+                    } else if let Err(e) = self.send_pty_input_fd(client_id) {
+                        log::warn!("Failed to pass pty fd to client {}: {}", client_id, e);
+                    } else if let Some(pid) = self.clients.get(&client_id).and_then(|c| c.peer.pid)
+                    {
+                        if !self.direct_input.contains(&pid) {
+                            self.direct_input.push(pid);
+                        }
+                    }
+                    // The fd is requested on a dedicated connection, which
+                    // has served its purpose.
+                    self.remove_client(client_id);
+                    break;
+                }
+                _ => self.handle_unknown_frame(client_id, &frame),
             }
         }
-        if let Some(client) = self.clients.get_mut(&client_id) {
-            client.recv_buf = recv_buf;
+        if let Some(error) = decoder.oversized() {
+            if self.clients.contains_key(&client_id) {
+                log::warn!(
+                    "Client {} sent an oversized frame, disconnecting: {}",
+                    client_id,
+                    error
+                );
+                let reply =
+                    serde_json::json!({ "error": error.to_string(), "max_frame": error.max_len });
+                self.reply(client_id, &reply);
+                self.remove_client(client_id);
+            }
+        } else if let Some(error) = decoder.checksum_error() {
+            if self.clients.contains_key(&client_id) {
+                log::warn!(
+                    "Client {} sent a corrupted frame, disconnecting: {}",
+                    client_id,
+                    error
+                );
+                self.remove_client(client_id);
+            }
+        } else if let Some(client) = self.clients.get_mut(&client_id) {
+            client.decoder = decoder;
         }
         Ok(flush_all)
     }
@@ -539,6 +3770,487 @@ impl Server {
 
 impl Drop for Server {
     fn drop(&mut self) {
+        // Stopped processes would otherwise linger after the pty hangs up.
+        if self.suspended {
+            let _ = self.set_suspended(false);
+        }
         let _ = std::fs::remove_file(&self.socket_path);
+        registry::unregister(&self.session_dir);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A server for `sh -c script` in a fresh `<temp>/<test>/root/s`.
+    fn test_server(test: &str, script: &str) -> (Server, PathBuf) {
+        let base = std::env::temp_dir().join(format!("pterm-{}-{}", test, std::process::id()));
+        let _ = std::fs::remove_dir_all(&base);
+        let session = Session::new("s".to_string(), "sh", &["sh", "-c", script], false).unwrap();
+        let server = Server::new(&base.join("root/s"), session).unwrap();
+        (server, base)
+    }
+
+    fn connects(socket: &Path) -> bool {
+        std::os::unix::net::UnixStream::connect(socket).is_ok()
+    }
+
+    /// Read the pty until `done` holds for the output or 5 s pass.
+    fn read_until(server: &mut Server, done: impl Fn(&mut Server) -> bool) -> bool {
+        let mut buf = vec![0u8; 4096];
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while Instant::now() < deadline {
+            server.handle_pty_output(&mut buf).unwrap();
+            if done(server) {
+                return true;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        false
+    }
+
+    #[test]
+    fn paused_output_blocks_the_command_until_a_client_attaches() {
+        let (mut server, base) = test_server("pause-test", "echo first; read x; echo second");
+        let output = |server: &mut Server| server.session.capture(false).lines.join("\n");
+        assert!(read_until(&mut server, |s| output(s).contains("first")));
+
+        server.options.detached = Detached::Pause;
+        server.apply_detached_policy();
+        assert_eq!(server.output_stopped, Some(true));
+        server.session.write_pty(b"go\n").unwrap();
+        std::thread::sleep(Duration::from_millis(300));
+        server.handle_pty_output(&mut [0u8; 4096]).unwrap();
+        assert!(!output(&mut server).contains("second"));
+
+        let (stream, _peer) = UnixStream::pair().unwrap();
+        let mut client = Client::new(stream);
+        client.attached = true;
+        server.clients.insert(1, client);
+        server.apply_detached_policy();
+        assert_eq!(server.output_stopped, Some(false));
+        assert!(read_until(&mut server, |s| output(s).contains("second")));
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn detached_output_is_logged_only_while_no_client_is_attached() {
+        let (mut server, base) =
+            test_server("log-test", "echo one; read x; echo two; read x; echo three");
+        let log_path = server.session_dir.join(DETACHED_LOG_FILENAME);
+        let log = || std::fs::read_to_string(&log_path).unwrap_or_default();
+        server.options.detached = Detached::Log;
+        server.apply_detached_policy();
+        assert!(read_until(&mut server, |_| log().contains("one")));
+
+        let (stream, _peer) = UnixStream::pair().unwrap();
+        let mut client = Client::new(stream);
+        client.attached = true;
+        server.clients.insert(1, client);
+        server.apply_detached_policy();
+        server.session.write_pty(b"\n").unwrap();
+        assert!(read_until(&mut server, |s| s
+            .session
+            .capture(false)
+            .lines
+            .join("\n")
+            .contains("two")));
+        assert!(!log().contains("two"));
+
+        server.clients.clear();
+        server.apply_detached_policy();
+        server.session.write_pty(b"\n").unwrap();
+        assert!(read_until(&mut server, |_| log().contains("three")));
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn turning_the_guard_on_ends_direct_input() {
+        let (mut server, base) = test_server("guard-test", "exec cat");
+        let own_pid = std::process::id() as i32;
+        let exited = std::process::Command::new("true").spawn().unwrap();
+        let exited_pid = exited.id() as i32;
+        exited.wait_with_output().unwrap();
+
+        // Holding the pty without being attached: it cannot be detached.
+        server.direct_input = vec![own_pid, exited_pid];
+        let error = server.set_option(0, "guard", "rm -rf").unwrap_err();
+        assert!(error.contains("--direct-input"), "{}", error);
+        assert!(server.options.guard.is_empty());
+        assert_eq!(server.direct_input, [own_pid]);
+
+        let (stream, mut peer) = UnixStream::pair().unwrap();
+        let mut client = Client::new(stream);
+        client.attached = true;
+        server.clients.insert(1, client);
+        server.set_option(0, "guard", "rm -rf").unwrap();
+        assert_eq!(server.options.guard, "rm -rf");
+        assert!(server.direct_input.is_empty());
+        assert!(!server.clients.contains_key(&1));
+        let mut received = Vec::new();
+        peer.read_to_end(&mut received).unwrap();
+        let frames = proto::decode_frames(&mut received);
+        assert_eq!(frames[0].msg_type, proto::server::DETACHED);
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn socket_is_rebound_after_its_socket_root_was_removed() {
+        let (mut server, base) = test_server("rebind-test", "exec cat");
+        let (root, dir) = (base.join("root"), base.join("root/s"));
+        server.set_socket_home(
+            SocketHome {
+                root: root.clone(),
+                dir: dir.clone(),
+            },
+            None,
+        );
+        assert!(!server.socket_root_vanished());
+
+        // A runtime directory cleaned on logout takes socket and root along.
+        std::fs::remove_dir_all(&root).unwrap();
+        assert!(server.socket_root_vanished());
+        server.recover_socket(false);
+        assert_eq!(server.session_dir, dir);
+        assert!(connects(&dir.join(paths::SOCKET_FILENAME)));
+        assert!(dir.join(meta::META_FILENAME).exists());
+        assert!(dir.join(RECOVERY_NOTE_FILENAME).exists());
+        assert!(!server.relocated());
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn session_moves_to_the_fallback_root_and_back_leaving_nothing_behind() {
+        let (mut server, base) = test_server("fallback-test", "exec cat");
+        let (run, root, dir) = (
+            base.join("run"),
+            base.join("run/pterm"),
+            base.join("run/pterm/s"),
+        );
+        let fallback = base.join("state");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::create_dir_all(&fallback).unwrap();
+        server
+            .rebind(&root, &dir)
+            .expect("rebind into the runtime directory");
+        assert!(
+            !base.join("root").exists(),
+            "the first session directory stays"
+        );
+        server.set_socket_home(
+            SocketHome {
+                root: root.clone(),
+                dir: dir.clone(),
+            },
+            Some(fallback.clone()),
+        );
+
+        // The whole runtime directory is gone: move to the fallback root.
+        std::fs::remove_dir_all(&run).unwrap();
+        server.recover_socket(false);
+        let moved = fallback.join("s");
+        assert_eq!(server.session_dir, moved);
+        assert!(server.relocated());
+        assert!(connects(&moved.join(paths::SOCKET_FILENAME)));
+        assert!(!run.exists());
+
+        // Back home once the runtime directory returns. A child session
+        // below the fallback directory stays where it is.
+        std::fs::create_dir_all(moved.join("child")).unwrap();
+        std::fs::create_dir_all(&run).unwrap();
+        server.recover_socket(true);
+        assert_eq!(server.session_dir, moved, "retried before the interval");
+        server.last_socket_recovery = None;
+        server.recover_socket(true);
+        assert_eq!(server.session_dir, dir);
+        assert!(!server.relocated());
+        assert!(connects(&dir.join(paths::SOCKET_FILENAME)));
+        for file in [
+            paths::SOCKET_FILENAME,
+            meta::META_FILENAME,
+            RECOVERY_NOTE_FILENAME,
+        ] {
+            assert!(!moved.join(file).exists(), "{} left behind", file);
+        }
+        assert!(moved.join("child").is_dir());
+        std::fs::remove_dir(moved.join("child")).unwrap();
+        server.leave_session_dir(&moved);
+        assert!(!moved.exists());
+        assert!(fallback.is_dir(), "the fallback root itself stays");
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn detach_request_takes_a_client_id_or_json() {
+        assert_eq!(DetachRequest::parse(&[]), Ok(DetachRequest::default()));
+        assert_eq!(
+            DetachRequest::parse(&proto::encode_client_id(3)),
+            Ok(DetachRequest {
+                client: Some(3),
+                reason: None,
+            })
+        );
+        assert_eq!(
+            DetachRequest::parse(br#"{"reason": "maintenance"}"#),
+            Ok(DetachRequest {
+                client: None,
+                reason: Some("maintenance".to_string()),
+            })
+        );
+        assert!(DetachRequest::parse(b"[1, 2]").is_err());
+    }
+
+    #[test]
+    fn command_envelope_and_setenv_arguments() {
+        let command = Command::parse(br#"{"cmd": "query", "args": {"what": "info"}}"#).unwrap();
+        assert_eq!(command.cmd, "query");
+        assert_eq!(command.args["what"], "info");
+        assert_eq!(
+            Command::parse(br#"{"cmd": "query"}"#).unwrap().args,
+            serde_json::Value::Null
+        );
+        assert!(Command::parse(br#"{"args": {}}"#).is_err());
+        assert!(Command::parse(b"query").is_err());
+
+        let setenv = |args: serde_json::Value| env_change(&args);
+        assert_eq!(
+            setenv(serde_json::json!({ "name": "EDITOR", "value": "vi" })),
+            Ok(("EDITOR".to_string(), Some("vi".to_string())))
+        );
+        assert_eq!(
+            setenv(serde_json::json!({ "name": "EDITOR" })),
+            Ok(("EDITOR".to_string(), None))
+        );
+        assert!(setenv(serde_json::json!({ "name": "A=B", "value": "c" })).is_err());
+        assert!(setenv(serde_json::json!({ "name": "PTERM_SESSION", "value": "x" })).is_err());
+        assert!(setenv(serde_json::json!({ "name": "N", "value": 1 })).is_err());
+    }
+
+    #[test]
+    fn zombie_reason_requires_keepalive_support_and_stall() {
+        let (stream, _peer) = UnixStream::pair().unwrap();
+        let mut client = Client::new(stream);
+        let now = Instant::now();
+        let long_ago = now - KEEPALIVE_TIMEOUT - SEND_STALL_TIMEOUT;
+        assert_eq!(client.zombie_reason(now), None);
+
+        // An unanswered PING only counts for clients known to answer PINGs.
+        client.ping_sent = Some(long_ago);
+        assert_eq!(client.zombie_reason(now), None);
+        client.keepalive = true;
+        assert_eq!(client.zombie_reason(now), Some("keepalive timed out"));
+
+        client.keepalive = false;
+        client.send_stalled_since = Some(now);
+        assert_eq!(client.zombie_reason(now), None);
+        client.send_stalled_since = Some(long_ago);
+        assert_eq!(client.zombie_reason(now), Some("send buffer stalled"));
+    }
+
+    #[test]
+    fn banner_names_session_and_appends_motd() {
+        let text = String::from_utf8(banner_text("dev", 0, Some("line 1\nline 2"))).unwrap();
+        let lines: Vec<&str> = text.split("\r\n").collect();
+        assert!(lines[0].contains("session 'dev', created "));
+        assert!(lines[0].ends_with("detach with `pterm detach dev`\x1b[0m"));
+        assert_eq!(&lines[1..], ["line 1", "line 2", ""]);
+        assert_eq!(banner_text("dev", 0, Some("")), banner_text("dev", 0, None));
+    }
+
+    #[test]
+    fn output_is_compressed_only_for_clients_that_asked() {
+        let output = b"$ cargo build\r\n   Compiling pterm\r\n".repeat(64);
+        let mut msg = OutputFrame::new(proto::server::OUTPUT, &output, 0);
+        assert_eq!(
+            msg.encoded(false),
+            proto::encode(proto::server::OUTPUT, &output)
+        );
+
+        let frames = proto::FrameDecoder::new().decode(msg.encoded(true));
+        assert_eq!(frames[0].msg_type, proto::server::COMPRESSED_OUTPUT);
+        assert!(frames[0].payload.len() < output.len() / 10);
+        assert_eq!(
+            zstd::stream::decode_all(&frames[0].payload[..]).unwrap(),
+            output
+        );
+
+        // Short and incompressible payloads go out plain either way.
+        let mut echo = OutputFrame::new(proto::server::OUTPUT, b"l", 0);
+        assert_eq!(
+            echo.encoded(true),
+            proto::encode(proto::server::OUTPUT, b"l")
+        );
+        let mut x = 0x2545_f491u32;
+        let noise: Vec<u8> = (0..4096)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 17;
+                x ^= x << 5;
+                x as u8
+            })
+            .collect();
+        let mut snapshot = OutputFrame::new(proto::server::STATE_SYNC, &noise, 0);
+        assert_eq!(
+            snapshot.encoded(true),
+            proto::encode(proto::server::STATE_SYNC, &noise)
+        );
+    }
+
+    #[test]
+    fn slow_client_receives_every_frame_in_order() {
+        let (stream, mut peer) = UnixStream::pair().unwrap();
+        let mut client = Client::new(stream);
+        let chunk = vec![b'x'; 64 * 1024];
+        for i in 0..32u8 {
+            let mut payload = chunk.clone();
+            payload[0] = i;
+            client.queue(&proto::encode(proto::server::OUTPUT, &payload));
+        }
+        client.queue(&proto::encode(proto::server::EXIT, &proto::encode_exit(3)));
+        let total = client.send_buf.len() as u64;
+
+        // The peer is not reading: the socket buffer fills and the rest stays queued.
+        assert!(client.flush(0).unwrap());
+        assert!(client.send_stalled_since.is_some());
+
+        let mut received = Vec::new();
+        let mut buf = vec![0u8; 16 * 1024];
+        loop {
+            loop {
+                match peer.read(&mut buf) {
+                    Ok(n) => received.extend_from_slice(&buf[..n]),
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(e) => panic!("read failed: {}", e),
+                }
+            }
+            if !client.flush(0).unwrap() {
+                break;
+            }
+        }
+        while let Ok(n) = peer.read(&mut buf) {
+            received.extend_from_slice(&buf[..n]);
+        }
+
+        assert_eq!(client.bytes_sent, total);
+        assert_eq!(client.send_stalled_since, None);
+        let frames = proto::decode_frames(&mut received);
+        assert!(received.is_empty());
+        assert_eq!(frames.len(), 33);
+        for (i, frame) in frames[..32].iter().enumerate() {
+            assert_eq!(frame.msg_type, proto::server::OUTPUT);
+            assert_eq!(frame.payload[0], i as u8);
+            assert_eq!(frame.payload.len(), chunk.len());
+        }
+        assert_eq!(frames[32].msg_type, proto::server::EXIT);
+        assert_eq!(proto::parse_exit(&frames[32].payload).unwrap(), 3);
+    }
+
+    #[test]
+    fn discard_keeps_partly_written_frame() {
+        let (stream, mut peer) = UnixStream::pair().unwrap();
+        let mut client = Client::new(stream);
+        let big = vec![b'x'; 4 << 20];
+        client.queue(&proto::encode(proto::server::OUTPUT, &big));
+        client.queue(&proto::encode(proto::server::OUTPUT, b"stale"));
+        assert!(client.flush(0).unwrap());
+        let written = client.bytes_sent as usize;
+        assert!(written < big.len());
+        assert_eq!(client.in_flight, proto::HEADER_SIZE + big.len() - written);
+
+        client.discard_queued();
+        client.queue(&proto::encode(proto::server::STATE_SYNC, b"snap"));
+        let mut received = Vec::new();
+        let mut buf = vec![0u8; 64 * 1024];
+        while client.flush(0).unwrap() || received.len() < client.bytes_sent as usize {
+            match peer.read(&mut buf) {
+                Ok(n) => received.extend_from_slice(&buf[..n]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => panic!("read failed: {}", e),
+            }
+        }
+        let frames = proto::decode_frames(&mut received);
+        assert!(received.is_empty());
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].payload.len(), big.len());
+        assert_eq!(frames[1].msg_type, proto::server::STATE_SYNC);
+        assert_eq!(client.in_flight, 0);
+    }
+
+    #[test]
+    fn resume_log_keeps_the_newest_output() {
+        let mut log = ResumeLog::starting_at(100);
+        log.append(b"hello ", 8);
+        log.append(b"world", 8);
+        assert_eq!((log.start, log.end()), (103, 111));
+        assert_eq!(log.since(105).as_deref(), Some(&b" world"[..]));
+        assert_eq!(log.since(111).as_deref(), Some(&b""[..]));
+        // Dropped from the front, or never sent.
+        assert_eq!(log.since(102), None);
+        assert_eq!(log.since(112), None);
+
+        log.reset();
+        assert_eq!(log.since(105), None);
+        assert_eq!(log.since(111).as_deref(), Some(&b""[..]));
+        log.append(b"!", 8);
+        assert_eq!(log.since(111).as_deref(), Some(&b"!"[..]));
+    }
+
+    #[test]
+    fn frame_remainder_walks_frame_headers() {
+        let mut buf = proto::encode(proto::server::OUTPUT, b"abc");
+        buf.extend(proto::encode(proto::server::PING, &[]));
+        assert_eq!(frame_remainder(&buf, 0, 0, 0), 0);
+        assert_eq!(frame_remainder(&buf, 0, 2, 0), 6);
+        assert_eq!(frame_remainder(&buf, 0, 8, 0), 0);
+        assert_eq!(frame_remainder(&buf, 0, 9, 0), 4);
+        assert_eq!(frame_remainder(&buf[6..], 2, 1, 0), 1);
+
+        let mut checked = Vec::new();
+        proto::Framing::Crc32.append(&buf, &mut checked);
+        let crc = proto::CRC_SIZE;
+        assert_eq!(frame_remainder(&checked, 0, 2, crc), 6 + crc);
+        assert_eq!(frame_remainder(&checked, 0, 9 + crc, crc), 4 + crc);
+    }
+
+    #[test]
+    fn flush_to_closed_client_fails() {
+        let (stream, peer) = UnixStream::pair().unwrap();
+        let mut client = Client::new(stream);
+        drop(peer);
+        client.queue(&proto::encode(proto::server::EXIT, &proto::encode_exit(0)));
+        assert!(client.flush(0).is_err());
+    }
+
+    #[test]
+    fn output_frames_match_the_conformance_vectors() {
+        use proto::conformance::{self, Direction, Payload};
+
+        for vector in conformance::vectors() {
+            if vector.direction != Direction::Server {
+                continue;
+            }
+            match (&vector.meaning, vector.msg_type) {
+                (Payload::Bytes, proto::server::OUTPUT | proto::server::STATE_SYNC) => {
+                    let mut frame = OutputFrame::new(vector.msg_type, &vector.payload, 0);
+                    assert_eq!(frame.encoded(false), vector.plain, "{}", vector.name);
+                    let (compressed, _) = proto::parse_frame(frame.encoded(true)).unwrap();
+                    let payload = match compressed.msg_type {
+                        proto::server::COMPRESSED_OUTPUT | proto::server::COMPRESSED_STATE_SYNC => {
+                            zstd::bulk::decompress(compressed.payload, 1 << 20).unwrap()
+                        }
+                        _ => compressed.payload.to_vec(),
+                    };
+                    assert_eq!(payload, vector.payload, "{}", vector.name);
+                }
+                // The golden zstd frames must read back with the zstd the
+                // clients use.
+                (Payload::Zstd { content }, _) => {
+                    let payload = zstd::bulk::decompress(&vector.payload, 1 << 20).unwrap();
+                    assert_eq!(&payload, content, "{}", vector.name);
+                }
+                _ => {}
+            }
+        }
     }
 }
@@ -1,10 +1,13 @@
 use crate::constants::{DEFAULT_TERMINAL_COLS, DEFAULT_TERMINAL_ROWS};
+use crate::meta::ExitInfo;
+use crate::paste::Capture;
 use crate::pty::Pty;
+use nix::libc;
 use nix::sys::termios;
 use std::collections::VecDeque;
 use std::fmt::Write as _;
 use std::io;
-use std::os::fd::AsRawFd;
+use std::os::fd::{AsRawFd, RawFd};
 
 #[derive(Default)]
 struct SessionCallbacks {
@@ -19,6 +22,66 @@ struct SessionCallbacks {
     hyperlink_uri: Option<String>,
     passthrough_sequences: VecDeque<Vec<u8>>,
     passthrough_bytes: usize,
+    /// Lines scrolled into the main screen's history before the output
+    /// being processed, which numbers lines for `prompt_marks`.
+    lines_scrolled: u64,
+    /// How lines scrolled by the output being processed are counted.
+    scroll_count: ScrollCount,
+    /// Lines where the shell started a prompt (OSC 133 `A`), numbered as
+    /// `lines_scrolled` plus the screen row, oldest first.
+    prompt_marks: VecDeque<u64>,
+    /// Column changes as `(line, cols)`, oldest first, numbered like
+    /// `prompt_marks`: the lines before `line` (back to the previous entry)
+    /// were written `cols` wide; later ones at the current width.
+    widths: VecDeque<(u64, u16)>,
+}
+
+/// Counts the lines the output being fed to the parser scrolls into
+/// history. vt100 keeps a nonzero scrollback offset pointing at the same
+/// line as lines scroll in, so with the offset set to 1 beforehand it grows
+/// by one per scrolled line, even once history is full. With no history
+/// yet, the history's growth counts instead.
+#[derive(Debug, Default, Clone, Copy)]
+struct ScrollCount {
+    /// History length before the output.
+    history: usize,
+    /// The offset was set to 1.
+    offset_set: bool,
+    /// The output started on the alternate screen, which has no history.
+    alternate: bool,
+}
+
+impl ScrollCount {
+    fn start(screen: &mut vt100::Screen) -> Self {
+        let history = history_len(screen);
+        screen.set_scrollback(1);
+        Self {
+            history,
+            offset_set: screen.scrollback() == 1,
+            alternate: screen.alternate_screen(),
+        }
+    }
+
+    /// Lines scrolled into history so far.
+    fn scrolled(self, screen: &mut vt100::Screen) -> u64 {
+        if self.alternate || screen.alternate_screen() {
+            return 0;
+        }
+        let offset = screen.scrollback();
+        if self.offset_set {
+            return offset.saturating_sub(1) as u64;
+        }
+        let history = history_len(screen);
+        screen.set_scrollback(offset);
+        history.saturating_sub(self.history) as u64
+    }
+}
+
+/// Lines of history the current screen has. Leaves the scrollback offset
+/// at the top of history.
+fn history_len(screen: &mut vt100::Screen) -> usize {
+    screen.set_scrollback(usize::MAX);
+    screen.scrollback()
 }
 
 #[derive(Default)]
@@ -39,6 +102,10 @@ impl SessionCallbacks {
     // DEC private modes not fully reconstructed by `vt100::Screen::state_formatted()`.
     // Modes 1004 and 2026 are now tracked as explicit struct fields instead.
     const PASSTHROUGH_DEC_PRIVATE_MODES: [u16; 2] = [12, 69];
+    /// Prompt marks kept; older ones have left any history anyway.
+    const MAX_PROMPT_MARKS: usize = 4096;
+    /// Width changes kept.
+    const MAX_WIDTHS: usize = 256;
 
     fn default_da_params(params: &[&[u16]]) -> bool {
         params.is_empty()
@@ -105,6 +172,23 @@ impl SessionCallbacks {
             || matches!(first.first().copied(), Some(58))
     }
 
+    /// Note an OSC 133 `A` (prompt start) mark at the cursor.
+    fn mark_prompt(&mut self, screen: &mut vt100::Screen, params: &[&[u8]]) {
+        let is_prompt_start = params.first() == Some(&&b"133"[..])
+            && params.get(1).is_some_and(|kind| kind.starts_with(b"A"));
+        if !is_prompt_start || screen.alternate_screen() {
+            return;
+        }
+        let row = screen.cursor_position().0 as u64;
+        let line = self.lines_scrolled + self.scroll_count.scrolled(screen) + row;
+        if self.prompt_marks.back() != Some(&line) {
+            self.prompt_marks.push_back(line);
+            if self.prompt_marks.len() > Self::MAX_PROMPT_MARKS {
+                self.prompt_marks.pop_front();
+            }
+        }
+    }
+
     fn take_pending_da_queries(&mut self) -> (usize, usize) {
         let counts = (self.pending_da1_queries, self.pending_da2_queries);
         self.pending_da1_queries = 0;
@@ -223,7 +307,7 @@ impl SessionCallbacks {
         // information. Replaying them from a snapshot would trigger a fresh
         // query against the client terminal, and its reply can then be
         // forwarded into the PTY as if it were user input.
-        !params.last().is_some_and(|param| *param == b"?")
+        params.last().is_none_or(|param| *param != b"?")
     }
 
     fn format_clipboard_copy(screen: &[u8], data: &[u8]) -> Vec<u8> {
@@ -323,6 +407,49 @@ impl SessionCallbacks {
     }
 }
 
+/// Bytes that paste `text` the way a terminal would: line breaks are sent as
+/// carriage returns (Enter) and, if the application enabled bracketed paste,
+/// the text is wrapped in `CSI 200 ~` / `CSI 201 ~` with any end marker
+/// inside it removed, so pasted text cannot end the paste early.
+pub fn paste_bytes(text: &[u8], bracketed: bool) -> Vec<u8> {
+    const END: &[u8] = b"\x1b[201~";
+    let mut out = Vec::with_capacity(text.len() + 12);
+    if bracketed {
+        out.extend_from_slice(b"\x1b[200~");
+    }
+    let mut i = 0;
+    while i < text.len() {
+        if bracketed && text[i..].starts_with(END) {
+            i += END.len();
+            continue;
+        }
+        match text[i] {
+            b'\r' if text.get(i + 1) == Some(&b'\n') => {
+                out.push(b'\r');
+                i += 1;
+            }
+            b'\n' => out.push(b'\r'),
+            byte => out.push(byte),
+        }
+        i += 1;
+    }
+    if bracketed {
+        out.extend_from_slice(END);
+    }
+    out
+}
+
+/// Non-blocking read; `Err(WouldBlock)` when `fd` has no more data.
+fn read_fd(fd: RawFd, buf: &mut [u8]) -> io::Result<usize> {
+    match nix::unistd::read(fd, buf) {
+        Ok(n) => Ok(n),
+        Err(e) if e == nix::errno::Errno::EAGAIN || e == nix::errno::Errno::EWOULDBLOCK => {
+            Err(io::Error::from(io::ErrorKind::WouldBlock))
+        }
+        Err(e) => Err(io::Error::other(e)),
+    }
+}
+
 fn build_snapshot(screen: &vt100::Screen, callbacks: &SessionCallbacks) -> Vec<u8> {
     let passthrough = callbacks.passthrough_sequences_formatted();
     let mut snapshot = screen.state_formatted();
@@ -523,7 +650,8 @@ impl vt100::Callbacks for SessionCallbacks {
         self.push_passthrough_sequence(seq);
     }
 
-    fn unhandled_osc(&mut self, _: &mut vt100::Screen, params: &[&[u8]]) {
+    fn unhandled_osc(&mut self, screen: &mut vt100::Screen, params: &[&[u8]]) {
+        self.mark_prompt(screen, params);
         if self.update_hyperlink_state(params) {
             return;
         }
@@ -539,10 +667,31 @@ impl vt100::Callbacks for SessionCallbacks {
 
 pub struct Session {
     pub name: String,
+    /// Child command line (argv) the session was started with.
+    pub command: Vec<String>,
     pub pty: Pty,
+    terminal: Terminal,
+    pub exited: Option<ExitInfo>,
+}
+
+/// Terminal state tracked for a session: the VT parser used to build
+/// snapshots and the filter applied to live output. Kept separate from the
+/// PTY so recorded output can be re-fed without a child process.
+pub struct Terminal {
     parser: vt100::Parser<SessionCallbacks>,
     output_filter: TerminalOutputFilter,
-    pub exited: Option<i32>,
+    /// When `false`, live output is forwarded without stripping queries.
+    filter_output: bool,
+    /// Last snapshot built, until the terminal state changes: clients that
+    /// reconnect in a loop would otherwise each cost a full rebuild.
+    snapshot_cache: std::cell::RefCell<SnapshotCache>,
+}
+
+#[derive(Default)]
+struct SnapshotCache {
+    snapshot: Option<Vec<u8>>,
+    builds: u64,
+    hits: u64,
 }
 
 #[derive(Default)]
@@ -644,52 +793,457 @@ impl TerminalOutputFilter {
         };
         let body = &seq[2..end];
         body.split(|&byte| byte == b';')
-            .last()
+            .next_back()
             .is_some_and(|param| param == b"?")
     }
 }
 
-impl Session {
-    /// Create a new session with the given name and command.
-    pub fn new(name: String, cmd: &str, args: &[&str]) -> io::Result<Self> {
-        let cols = DEFAULT_TERMINAL_COLS;
-        let rows = DEFAULT_TERMINAL_ROWS;
-        let pty = Pty::spawn(cmd, args, cols, rows)?;
-        Ok(Self {
-            name,
-            pty,
+impl Terminal {
+    pub const SCROLLBACK_LINES: usize = 10_000;
+
+    pub fn new(rows: u16, cols: u16) -> Self {
+        Self::with_scrollback(rows, cols, Self::SCROLLBACK_LINES)
+    }
+
+    fn with_scrollback(rows: u16, cols: u16, lines: usize) -> Self {
+        Self {
             parser: vt100::Parser::new_with_callbacks(
                 rows,
                 cols,
-                10_000,
+                lines,
                 SessionCallbacks::default(),
             ),
             output_filter: TerminalOutputFilter::default(),
+            filter_output: true,
+            snapshot_cache: Default::default(),
+        }
+    }
+
+    /// Feed PTY output to the VT parser and append the bytes that should be
+    /// forwarded to clients (with reply-generating queries removed) to `output`.
+    pub fn process(&mut self, input: &[u8], output: &mut Vec<u8>) {
+        self.snapshot_cache.get_mut().snapshot = None;
+        let scroll_count = ScrollCount::start(self.parser.screen_mut());
+        self.parser.callbacks_mut().scroll_count = scroll_count;
+        self.parser.process(input);
+        let screen = self.parser.screen_mut();
+        let scrolled = scroll_count.scrolled(screen);
+        let history = history_len(screen) as u64;
+        screen.set_scrollback(0);
+        let callbacks = self.parser.callbacks_mut();
+        callbacks.lines_scrolled += scrolled;
+        // Marks on lines that have left history, and all of them if history
+        // was full and the count hit the offset's limit, so may fall short.
+        let oldest = callbacks.lines_scrolled.saturating_sub(history);
+        let full = scroll_count.history as u64 == history;
+        let marks = &mut callbacks.prompt_marks;
+        if scroll_count.offset_set && full && scrolled > 0 && scrolled + 1 >= history {
+            marks.clear();
+        }
+        while marks.front().is_some_and(|&line| line < oldest) {
+            marks.pop_front();
+        }
+        let widths = &mut callbacks.widths;
+        while widths.front().is_some_and(|&(line, _)| line <= oldest) {
+            widths.pop_front();
+        }
+        if self.filter_output {
+            self.output_filter.filter(input, output);
+        } else {
+            // Release a sequence held back before filtering was disabled.
+            self.output_filter.flush_pending(output);
+            output.extend_from_slice(input);
+        }
+    }
+
+    pub fn set_output_filter(&mut self, enabled: bool) {
+        self.filter_output = enabled;
+    }
+
+    /// Change how many lines of scrollback are kept. The parser is rebuilt:
+    /// existing history is re-fed line by line (keeping the newest `lines`),
+    /// then the screen is restored from a snapshot. History is dropped when
+    /// the alternate screen is active, since it is not reachable then.
+    pub fn set_scrollback_len(&mut self, lines: usize) {
+        let (rows, cols) = self.parser.screen().size();
+        let snapshot = self.snapshot();
+        let history = if self.parser.screen().alternate_screen() {
+            Vec::new()
+        } else {
+            self.scrollback_rows(lines)
+        };
+        let callbacks = std::mem::take(self.parser.callbacks_mut());
+
+        let mut parser =
+            vt100::Parser::new_with_callbacks(rows, cols, lines, SessionCallbacks::default());
+        let kept = &history[history.len().saturating_sub(lines)..];
+        for row in kept {
+            parser.process(row);
+            parser.process(b"\x1b[m\r\n");
+        }
+        // The newest lines fed are still on screen (the bottom row holds the
+        // cursor). Scroll exactly those into history before restoring.
+        let on_screen = kept.len().min(rows.saturating_sub(1) as usize);
+        parser.process(format!("\x1b[{};1H", rows).as_bytes());
+        parser.process(&b"\n".repeat(on_screen));
+        parser.process(&snapshot);
+        *parser.callbacks_mut() = callbacks;
+        self.parser = parser;
+        self.snapshot_cache.get_mut().snapshot = None;
+    }
+
+    /// Escape sequences that scroll the newest `lines` lines of scrollback,
+    /// at most `bytes` of them, into a terminal's own history, to go ahead
+    /// of a snapshot. Empty on the alternate screen, whose history is not
+    /// reachable.
+    pub fn scrollback_replay(&mut self, lines: usize, bytes: Option<usize>) -> Vec<u8> {
+        const ROW_END: &[u8] = b"\x1b[m\r\n";
+        if lines == 0 || self.parser.screen().alternate_screen() {
+            return Vec::new();
+        }
+        let rows = self.parser.screen().size().0;
+        let mut history = self.scrollback_rows(lines);
+        if let Some(limit) = bytes {
+            let mut total = 0;
+            let keep = history
+                .iter()
+                .rev()
+                .take_while(|row| {
+                    total += row.len() + ROW_END.len();
+                    total <= limit
+                })
+                .count();
+            history.drain(..history.len() - keep);
+        }
+        if history.is_empty() {
+            return Vec::new();
+        }
+        // Print the lines from the top, then scroll those still on screen
+        // off it, as `set_scrollback_len` does.
+        let mut replay = b"\x1b[H\x1b[J".to_vec();
+        for row in &history {
+            replay.extend_from_slice(row);
+            replay.extend_from_slice(ROW_END);
+        }
+        let on_screen = history.len().min(rows.saturating_sub(1) as usize);
+        replay.extend_from_slice(format!("\x1b[{};1H", rows).as_bytes());
+        replay.extend_from_slice(&b"\n".repeat(on_screen));
+        replay
+    }
+
+    /// What a hot upgrade hands the new daemon image: all of the scrollback,
+    /// replayed as by [`Self::scrollback_replay`], then the snapshot.
+    pub fn upgrade_snapshot(&mut self) -> Vec<u8> {
+        let mut snapshot = self.scrollback_replay(usize::MAX, None);
+        snapshot.extend_from_slice(&self.snapshot());
+        snapshot
+    }
+
+    /// Rebuild a terminal keeping `scrollback` lines from an
+    /// [`Self::upgrade_snapshot`] taken by the previous daemon image.
+    pub fn resume(rows: u16, cols: u16, scrollback: usize, snapshot: &[u8]) -> Self {
+        let mut terminal = Self::with_scrollback(rows, cols, scrollback);
+        terminal.process(snapshot, &mut Vec::new());
+        terminal.take_pending_da_queries();
+        terminal
+    }
+
+    /// The newest `limit` formatted scrollback rows, oldest first.
+    fn scrollback_rows(&mut self, limit: usize) -> Vec<Vec<u8>> {
+        let (rows, cols) = self.parser.screen().size();
+        let screen = self.parser.screen_mut();
+        screen.set_scrollback(usize::MAX);
+        let total = screen.scrollback().min(limit);
+        let mut history = Vec::with_capacity(total);
+        while history.len() < total {
+            // With offset `o`, the first `o` visible rows are the newest `o`
+            // lines of history.
+            let offset = total - history.len();
+            screen.set_scrollback(offset);
+            let take = offset.min(rows as usize);
+            history.extend(screen.rows_formatted(0, cols).take(take));
+        }
+        screen.set_scrollback(0);
+        history
+    }
+
+    pub fn resize(&mut self, cols: u16, rows: u16) {
+        // Every attach resizes, mostly to the size the terminal already has.
+        if self.parser.screen().size() == (rows, cols) {
+            return;
+        }
+        self.snapshot_cache.get_mut().snapshot = None;
+        let (_, old_cols) = self.parser.screen().size();
+        if cols != old_cols {
+            // The cursor's line is redrawn at the new width (a prompt) or
+            // not written yet. Nothing was written at the old width if the
+            // last change happened at or below it.
+            let row = self.parser.screen().cursor_position().0 as u64;
+            let callbacks = self.parser.callbacks_mut();
+            let line = callbacks.lines_scrolled + row;
+            let widths = &mut callbacks.widths;
+            if widths.back().is_none_or(|&(last, _)| last < line) {
+                widths.push_back((line, old_cols));
+                if widths.len() > SessionCallbacks::MAX_WIDTHS {
+                    widths.pop_front();
+                }
+            }
+        }
+        self.parser.screen_mut().set_size(rows, cols);
+    }
+
+    pub fn size(&self) -> (u16, u16) {
+        let (rows, cols) = self.parser.screen().size();
+        (cols, rows)
+    }
+
+    /// Lines of scrollback in use. Zero while the alternate screen is
+    /// active, as its history is the one reachable then.
+    pub fn history_lines(&mut self) -> usize {
+        let screen = self.parser.screen_mut();
+        let lines = history_len(screen);
+        screen.set_scrollback(0);
+        lines
+    }
+
+    /// Generate escape sequences that reproduce the current terminal state.
+    pub fn snapshot(&self) -> Vec<u8> {
+        let cache = &mut *self.snapshot_cache.borrow_mut();
+        match &cache.snapshot {
+            Some(snapshot) => {
+                cache.hits += 1;
+                snapshot.clone()
+            }
+            None => {
+                let snapshot = build_snapshot(self.parser.screen(), self.parser.callbacks());
+                cache.builds += 1;
+                cache.snapshot = Some(snapshot.clone());
+                snapshot
+            }
+        }
+    }
+
+    /// Snapshots built and snapshots served from the cache so far.
+    pub fn snapshot_stats(&self) -> (u64, u64) {
+        let cache = self.snapshot_cache.borrow();
+        (cache.builds, cache.hits)
+    }
+
+    /// Plain-text contents of the visible screen.
+    pub fn contents(&self) -> String {
+        self.parser.screen().contents()
+    }
+
+    /// Scrollback and screen as plain-text lines, oldest first, with
+    /// soft-wrapped rows joined into one line, and the lines where prompts
+    /// start.
+    ///
+    /// With `rewrap`, rows that fill the whole width the terminal had when
+    /// they were written are joined with the next as well. That undoes the
+    /// breaks of programs wrapping at the terminal's edge themselves, and
+    /// soft wraps whose mark the terminal dropped when its width changed.
+    pub fn capture(&mut self, rewrap: bool) -> Capture {
+        let (rows, cols) = self.parser.screen().size();
+        let cursor_row = self.parser.screen().cursor_position().0 as usize;
+        let callbacks = self.parser.callbacks();
+        let (lines_scrolled, mut marks, widths) = if self.parser.screen().alternate_screen() {
+            (0, Vec::new(), None)
+        } else {
+            let marks = callbacks.prompt_marks.iter().copied().collect();
+            let widths: Vec<(u64, u16)> = callbacks.widths.iter().copied().collect();
+            (callbacks.lines_scrolled, marks, rewrap.then_some(widths))
+        };
+        let screen = self.parser.screen_mut();
+        screen.set_scrollback(usize::MAX);
+        let history = screen.scrollback();
+        // Rows are numbered as the marks are from the first row of history.
+        let first = lines_scrolled.saturating_sub(history as u64);
+        // Whether `row` of the visible window, line `line`, continues on the
+        // next row.
+        let continues = |screen: &vt100::Screen, row: u16, line: u64| {
+            if screen.row_wrapped(row) {
+                return true;
+            }
+            let Some(widths) = widths.as_ref() else {
+                return false;
+            };
+            let width = widths
+                .iter()
+                .find(|&&(end, _)| line < end)
+                .map_or(cols, |&(_, width)| width);
+            let used = |col| {
+                screen
+                    .cell(row, col)
+                    .is_some_and(|cell| cell.has_contents() || cell.is_wide_continuation())
+            };
+            width > 0 && width <= cols && used(width - 1) && (width == cols || !used(width))
+        };
+        let mut physical: Vec<(String, bool)> = Vec::new();
+        while physical.len() < history {
+            let offset = history - physical.len();
+            screen.set_scrollback(offset);
+            let take = offset.min(rows as usize);
+            for (row, text) in screen.rows(0, cols).take(take).enumerate() {
+                let line = first + physical.len() as u64;
+                physical.push((text, continues(screen, row as u16, line)));
+            }
+        }
+        screen.set_scrollback(0);
+        for (row, text) in screen.rows(0, cols).enumerate() {
+            let line = first + physical.len() as u64;
+            physical.push((text, continues(screen, row as u16, line)));
+        }
+
+        marks.retain(|&mark| mark >= first);
+        let mut marks = marks
+            .into_iter()
+            .map(|mark| (mark - first) as usize)
+            .peekable();
+        let mut capture = Capture::default();
+        let mut line = String::new();
+        for (row, (text, wrapped)) in physical.into_iter().enumerate() {
+            if row == history {
+                capture.screen = capture.lines.len();
+            }
+            if row == history + cursor_row {
+                capture.cursor = capture.lines.len();
+            }
+            if marks.next_if(|&mark| mark <= row).is_some() {
+                while marks.next_if(|&mark| mark <= row).is_some() {}
+                if capture.prompts.last() != Some(&capture.lines.len()) {
+                    capture.prompts.push(capture.lines.len());
+                }
+            }
+            line.push_str(&text);
+            if !wrapped {
+                capture.lines.push(std::mem::take(&mut line));
+            }
+        }
+        if !line.is_empty() {
+            capture.lines.push(line);
+        }
+        capture
+    }
+
+    /// Text of the screen line holding the cursor, joined with the rows it
+    /// soft-wraps across (e.g. a long shell command line).
+    pub fn cursor_line(&self) -> String {
+        let screen = self.parser.screen();
+        let (rows, cols) = screen.size();
+        let row = screen.cursor_position().0;
+        let mut first = row;
+        while first > 0 && screen.row_wrapped(first - 1) {
+            first -= 1;
+        }
+        let mut last = row;
+        while last + 1 < rows && screen.row_wrapped(last) {
+            last += 1;
+        }
+        screen
+            .rows(0, cols)
+            .skip(first as usize)
+            .take((last - first + 1) as usize)
+            .collect()
+    }
+
+    pub fn bracketed_paste(&self) -> bool {
+        self.parser.screen().bracketed_paste()
+    }
+
+    pub fn take_pending_da_queries(&mut self) -> (usize, usize) {
+        self.parser.callbacks_mut().take_pending_da_queries()
+    }
+}
+
+impl Session {
+    /// Create a new session with the given name and command. With
+    /// `split_stderr`, the child's stderr is a pipe read by
+    /// [`Session::read_stderr`].
+    pub fn new(name: String, cmd: &str, args: &[&str], split_stderr: bool) -> io::Result<Self> {
+        let cols = DEFAULT_TERMINAL_COLS;
+        let rows = DEFAULT_TERMINAL_ROWS;
+        let pty = Pty::spawn(cmd, args, cols, rows, split_stderr)?;
+        Ok(Self {
+            name,
+            command: args.iter().map(|a| a.to_string()).collect(),
+            pty,
+            terminal: Terminal::new(rows, cols),
             exited: None,
         })
     }
 
-    /// Read available data from pty, feed to VT parser, return what was read.
+    /// Rebuild a session after a hot upgrade from its inherited PTY and the
+    /// history and screen handed over by the previous daemon image (see
+    /// [`Terminal::resume`]).
+    pub fn resume(
+        name: String,
+        command: Vec<String>,
+        pty: Pty,
+        cols: u16,
+        rows: u16,
+        scrollback: usize,
+        snapshot: &[u8],
+    ) -> Self {
+        Self {
+            name,
+            command,
+            pty,
+            terminal: Terminal::resume(rows, cols, scrollback, snapshot),
+            exited: None,
+        }
+    }
+
+    /// Read available data from pty into `buf`, feed it to the VT parser and
+    /// append the client-bound bytes to `output`. Returns the number of raw
+    /// bytes read, which remain in `buf`.
     /// Returns `Err(WouldBlock)` when the non-blocking fd has no more data.
-    pub fn read_pty(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        let fd = self.pty.master.as_raw_fd();
-        match nix::unistd::read(fd, buf) {
-            Ok(n) => {
-                if n > 0 {
-                    self.parser.process(&buf[..n]);
-                    let mut filtered = Vec::with_capacity(n);
-                    self.output_filter.filter(&buf[..n], &mut filtered);
-                    let filtered_len = filtered.len();
-                    buf[..filtered_len].copy_from_slice(&filtered);
-                    return Ok(filtered_len);
-                }
-                Ok(n)
-            }
-            Err(e) if e == nix::errno::Errno::EAGAIN || e == nix::errno::Errno::EWOULDBLOCK => {
-                Err(io::Error::from(io::ErrorKind::WouldBlock))
+    pub fn read_pty(&mut self, buf: &mut [u8], output: &mut Vec<u8>) -> io::Result<usize> {
+        let n = read_fd(self.pty.master.as_raw_fd(), buf)?;
+        if n > 0 {
+            self.terminal.process(&buf[..n], output);
+        }
+        Ok(n)
+    }
+
+    /// Read available data from the child's stderr pipe into `buf`; see
+    /// [`Session::process_stderr`]. Returns 0 at end of file, and
+    /// `Err(WouldBlock)` when there is no more data or no pipe.
+    pub fn read_stderr(&self, buf: &mut [u8]) -> io::Result<usize> {
+        match &self.pty.stderr {
+            Some(fd) => read_fd(fd.as_raw_fd(), buf),
+            None => Err(io::Error::from(io::ErrorKind::WouldBlock)),
+        }
+    }
+
+    /// Feed bytes read from the stderr pipe to the VT parser and append the
+    /// client-bound bytes to `output`. A pipe has no `ONLCR`, so `\n` is
+    /// turned into `\r\n` first, as the pty does for stdout; the translated
+    /// bytes are returned.
+    pub fn process_stderr(&mut self, data: &[u8], output: &mut Vec<u8>) -> Vec<u8> {
+        let mut text = Vec::with_capacity(data.len() + data.len() / 16);
+        for &byte in data {
+            if byte == b'\n' {
+                text.push(b'\r');
             }
-            Err(e) => Err(io::Error::other(e)),
+            text.push(byte);
         }
+        self.terminal.process(&text, output);
+        text
+    }
+
+    /// Feed `data` to the terminal state as if the child had written it, so
+    /// it becomes part of the screen, scrollback and snapshots.
+    pub fn inject_output(&mut self, data: &[u8]) {
+        let mut discard = Vec::new();
+        self.terminal.process(data, &mut discard);
+    }
+
+    /// Type `text` into the pty as a paste (see [`paste_bytes`]). Returns the
+    /// number of bytes written.
+    pub fn paste(&self, text: &[u8]) -> io::Result<usize> {
+        let data = paste_bytes(text, self.terminal.bracketed_paste());
+        self.write_pty(&data)?;
+        Ok(data.len())
     }
 
     /// Write input data to pty (forward user keystrokes).
@@ -722,17 +1276,59 @@ impl Session {
     /// Resize the pty and VT parser.
     pub fn resize(&mut self, cols: u16, rows: u16) -> io::Result<()> {
         self.pty.resize(cols, rows)?;
-        self.parser.screen_mut().set_size(rows, cols);
+        self.terminal.resize(cols, rows);
         Ok(())
     }
 
     /// Generate escape sequences that reproduce the current terminal state.
     pub fn snapshot(&self) -> Vec<u8> {
-        build_snapshot(self.parser.screen(), self.parser.callbacks())
+        self.terminal.snapshot()
+    }
+
+    /// See [`Terminal::snapshot_stats`].
+    pub fn snapshot_stats(&self) -> (u64, u64) {
+        self.terminal.snapshot_stats()
     }
 
     pub fn take_pending_da_queries(&mut self) -> (usize, usize) {
-        self.parser.callbacks_mut().take_pending_da_queries()
+        self.terminal.take_pending_da_queries()
+    }
+
+    /// Scrollback and screen as plain-text lines (see [`Terminal::capture`]).
+    pub fn capture(&mut self, rewrap: bool) -> Capture {
+        self.terminal.capture(rewrap)
+    }
+
+    pub fn cursor_line(&self) -> String {
+        self.terminal.cursor_line()
+    }
+
+    /// Current terminal size as `(cols, rows)`.
+    pub fn size(&self) -> (u16, u16) {
+        self.terminal.size()
+    }
+
+    pub fn set_scrollback_len(&mut self, lines: usize) {
+        self.terminal.set_scrollback_len(lines);
+    }
+
+    /// See [`Terminal::history_lines`].
+    pub fn history_lines(&mut self) -> usize {
+        self.terminal.history_lines()
+    }
+
+    /// See [`Terminal::upgrade_snapshot`].
+    pub fn upgrade_snapshot(&mut self) -> Vec<u8> {
+        self.terminal.upgrade_snapshot()
+    }
+
+    /// See [`Terminal::scrollback_replay`].
+    pub fn scrollback_replay(&mut self, lines: usize, bytes: Option<usize>) -> Vec<u8> {
+        self.terminal.scrollback_replay(lines, bytes)
+    }
+
+    pub fn set_output_filter(&mut self, enabled: bool) {
+        self.terminal.set_output_filter(enabled);
     }
 
     /// Get the master fd for polling.
@@ -740,32 +1336,104 @@ impl Session {
         self.pty.master.as_raw_fd()
     }
 
+    /// The stderr pipe for polling, with `--split-stderr`.
+    pub fn stderr_fd(&self) -> Option<i32> {
+        self.pty.stderr.as_ref().map(|fd| fd.as_raw_fd())
+    }
+
+    /// Stop reading the stderr pipe, after its end of file.
+    pub fn close_stderr(&mut self) {
+        self.pty.stderr = None;
+    }
+
+    /// Working directory of the terminal's foreground process, falling back
+    /// to the child. Only available where `/proc` is.
+    pub fn cwd(&self) -> Option<std::path::PathBuf> {
+        let pid = self.foreground_pid();
+        std::fs::read_link(format!("/proc/{}/cwd", pid))
+            .or_else(|_| std::fs::read_link(format!("/proc/{}/cwd", self.pty.child_pid)))
+            .ok()
+    }
+
+    /// Leader of the terminal's foreground process group: the job a shell
+    /// is running, else the child itself.
+    pub fn foreground_pid(&self) -> nix::unistd::Pid {
+        nix::unistd::tcgetpgrp(&self.pty.master).unwrap_or(self.pty.child_pid)
+    }
+
+    /// Send `signal` to the child's process group and, when it differs, to
+    /// the terminal's foreground process group (a job started from a shell).
+    pub fn signal_process_groups(&self, signal: nix::sys::signal::Signal) -> io::Result<()> {
+        let child = self.pty.child_pid;
+        nix::sys::signal::killpg(child, signal).map_err(io::Error::from)?;
+        if let Ok(foreground) = nix::unistd::tcgetpgrp(&self.pty.master) {
+            // 0 once the signal ended the session: `killpg(0)` would signal
+            // the daemon's own group.
+            if foreground != child && foreground.as_raw() > 0 {
+                let _ = nix::sys::signal::killpg(foreground, signal);
+            }
+        }
+        Ok(())
+    }
+
+    /// Run the session's command again on its pty, once the previous child
+    /// has been reaped (`pterm respawn`). The terminal state and scrollback
+    /// carry over.
+    pub fn respawn(&mut self) -> io::Result<()> {
+        let args: Vec<&str> = self.command.iter().map(String::as_str).collect();
+        let cmd = args
+            .first()
+            .ok_or_else(|| io::Error::other("the session has no command"))?;
+        self.pty.respawn(cmd, &args)?;
+        self.exited = None;
+        Ok(())
+    }
+
     /// Check if the child process has exited.
-    pub fn check_exit(&mut self) -> Option<i32> {
+    pub fn check_exit(&mut self) -> Option<ExitInfo> {
         if self.exited.is_some() {
             return self.exited;
         }
-        match nix::sys::wait::waitpid(
-            self.pty.child_pid,
-            Some(nix::sys::wait::WaitPidFlag::WNOHANG),
-        ) {
-            Ok(nix::sys::wait::WaitStatus::Exited(_, code)) => {
-                self.exited = Some(code);
-                Some(code)
-            }
-            Ok(nix::sys::wait::WaitStatus::Signaled(_, sig, _)) => {
-                let code = 128 + sig as i32;
-                self.exited = Some(code);
-                Some(code)
-            }
-            _ => None,
+        // `wait4` rather than `waitpid`, for the child's resource usage.
+        let mut status = 0;
+        let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+        let pid = self.pty.child_pid;
+        if unsafe { libc::wait4(pid.as_raw(), &mut status, libc::WNOHANG, &mut usage) } <= 0 {
+            return None;
         }
+        let mut exit = match nix::sys::wait::WaitStatus::from_raw(pid, status) {
+            Ok(nix::sys::wait::WaitStatus::Exited(_, code)) => ExitInfo {
+                code,
+                ..ExitInfo::default()
+            },
+            Ok(nix::sys::wait::WaitStatus::Signaled(_, sig, core_dumped)) => ExitInfo {
+                code: 128 + sig as i32,
+                signal: Some(sig as i32),
+                core_dumped,
+                ..ExitInfo::default()
+            },
+            _ => return None,
+        };
+        let micros = |t: libc::timeval| t.tv_sec as u64 * 1_000_000 + t.tv_usec as u64;
+        exit.user_us = micros(usage.ru_utime);
+        exit.system_us = micros(usage.ru_stime);
+        // Linux counts KiB, macOS bytes.
+        exit.max_rss_kib = if cfg!(target_os = "macos") {
+            usage.ru_maxrss as u64 / 1024
+        } else {
+            usage.ru_maxrss as u64
+        };
+        self.exited = Some(exit);
+        self.exited
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{build_snapshot, KittyKeyboardState, SessionCallbacks, TerminalOutputFilter};
+    use super::{
+        build_snapshot, paste_bytes, KittyKeyboardState, Session, SessionCallbacks, Terminal,
+        TerminalOutputFilter,
+    };
     use crate::constants::{DEFAULT_TERMINAL_COLS, DEFAULT_TERMINAL_ROWS};
     use std::collections::VecDeque;
 
@@ -860,6 +1528,237 @@ mod tests {
         assert_eq!(output, b"\x1b]2;title\x1b\\\x1b[31mred");
     }
 
+    #[test]
+    fn terminal_forwards_queries_when_output_filter_disabled() {
+        let mut terminal = Terminal::new(24, 80);
+        let mut output = Vec::new();
+        terminal.process(b"a\x1b[", &mut output);
+        terminal.set_output_filter(false);
+        terminal.process(b"cb", &mut output);
+        assert_eq!(output, b"a\x1b[cb");
+    }
+
+    #[test]
+    fn snapshot_cache_is_dropped_on_output_and_resize() {
+        let mut terminal = Terminal::new(5, 20);
+        let mut output = Vec::new();
+        terminal.process(b"$ ", &mut output);
+        let first = terminal.snapshot();
+        assert_eq!(terminal.snapshot(), first);
+        assert_eq!(terminal.snapshot_stats(), (1, 1));
+
+        terminal.process(b"ls", &mut output);
+        let typed = terminal.snapshot();
+        assert_ne!(typed, first);
+        assert_eq!(
+            typed,
+            build_snapshot(terminal.parser.screen(), terminal.parser.callbacks())
+        );
+
+        // Narrow enough to cut "ls" off the screen.
+        terminal.resize(2, 5);
+        assert_eq!(
+            terminal.snapshot(),
+            build_snapshot(terminal.parser.screen(), terminal.parser.callbacks())
+        );
+        assert_ne!(terminal.snapshot(), typed);
+    }
+
+    #[test]
+    fn split_stderr_arrives_on_its_own_pipe() {
+        let mut session = Session::new(
+            "split".to_string(),
+            "sh",
+            &["sh", "-c", "echo out; echo err >&2"],
+            true,
+        )
+        .unwrap();
+        let (mut stdout, mut stderr, mut output) = (Vec::new(), Vec::new(), Vec::new());
+        let mut buf = [0u8; 256];
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+        while session.stderr_fd().is_some() && std::time::Instant::now() < deadline {
+            if let Ok(n) = session.read_pty(&mut buf, &mut output) {
+                stdout.extend_from_slice(&buf[..n]);
+            }
+            match session.read_stderr(&mut buf) {
+                Ok(0) => session.close_stderr(),
+                Ok(n) => stderr.extend(session.process_stderr(&buf[..n], &mut output)),
+                Err(_) => std::thread::sleep(std::time::Duration::from_millis(10)),
+            }
+        }
+        while let Ok(n @ 1..) = session.read_pty(&mut buf, &mut output) {
+            stdout.extend_from_slice(&buf[..n]);
+        }
+        assert_eq!(stderr, b"err\r\n");
+        assert_eq!(String::from_utf8_lossy(&stdout), "out\r\n");
+        let text = session.terminal.contents();
+        assert!(text.contains("out") && text.contains("err"), "{}", text);
+    }
+
+    #[test]
+    fn respawn_runs_the_command_again_on_the_same_pty() {
+        let mut session = Session::new(
+            "respawn".to_string(),
+            "sh",
+            &["sh", "-c", "echo run; exit 3"],
+            false,
+        )
+        .unwrap();
+        let tty = session.pty.slave_path();
+        let mut output = Vec::new();
+        let mut buf = [0u8; 256];
+        let mut wait_exit = |session: &mut Session| {
+            let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+            while std::time::Instant::now() < deadline {
+                let _ = session.read_pty(&mut buf, &mut output);
+                if let Some(exit) = session.check_exit() {
+                    while let Ok(1..) = session.read_pty(&mut buf, &mut output) {}
+                    return exit.code;
+                }
+                std::thread::sleep(std::time::Duration::from_millis(10));
+            }
+            panic!("the command did not exit");
+        };
+        assert_eq!(wait_exit(&mut session), 3);
+        let first = session.pty.child_pid;
+
+        session.respawn().unwrap();
+        assert!(session.exited.is_none());
+        assert_ne!(session.pty.child_pid, first);
+        assert_eq!(session.pty.slave_path(), tty);
+        assert_eq!(wait_exit(&mut session), 3);
+        assert_eq!(session.terminal.contents().matches("run").count(), 2);
+    }
+
+    #[test]
+    fn set_scrollback_len_keeps_newest_history_and_screen() {
+        let mut terminal = Terminal::new(5, 20);
+        let mut output = Vec::new();
+        for i in 0..30 {
+            terminal.process(format!("line {}\r\n", i).as_bytes(), &mut output);
+        }
+        terminal.process(b"prompt$ ", &mut output);
+        let screen_before = terminal.contents();
+
+        terminal.set_scrollback_len(10);
+        assert_eq!(terminal.contents(), screen_before);
+
+        let history = terminal.scrollback_rows(usize::MAX);
+        assert_eq!(history.len(), 10);
+        assert_eq!(terminal.history_lines(), 10);
+        assert_eq!(history[0], b"line 16");
+        assert_eq!(history[9], b"line 25");
+    }
+
+    #[test]
+    fn scrollback_replay_fills_the_client_history() {
+        let mut terminal = Terminal::new(5, 20);
+        let mut output = Vec::new();
+        for i in 0..30 {
+            terminal.process(format!("line {}\r\n", i).as_bytes(), &mut output);
+        }
+        let replayed = |replay: &[u8]| {
+            let mut client = Terminal::new(5, 20);
+            client.process(b"old prompt$ ", &mut Vec::new());
+            client.process(replay, &mut Vec::new());
+            client.scrollback_rows(usize::MAX)
+        };
+
+        let history = replayed(&terminal.scrollback_replay(3, None));
+        assert_eq!(history, [b"line 23", b"line 24", b"line 25"]);
+        // Each line costs its length plus the reset and newline.
+        let history = replayed(&terminal.scrollback_replay(100, Some(26)));
+        assert_eq!(history, [b"line 24", b"line 25"]);
+        let history = replayed(&terminal.scrollback_replay(100, None));
+        assert_eq!(history.len(), 26);
+        assert!(terminal.scrollback_replay(0, None).is_empty());
+    }
+
+    #[test]
+    fn capture_joins_wrapped_rows_across_history_and_screen() {
+        let mut terminal = Terminal::new(3, 10);
+        let mut output = Vec::new();
+        terminal.process(b"one\r\n0123456789abcd\r\n$ ls\r\nfile\r\n$ ", &mut output);
+
+        let capture = terminal.capture(false);
+        assert_eq!(
+            capture.lines,
+            ["one", "0123456789abcd", "$ ls", "file", "$ "]
+        );
+        assert_eq!(capture.screen, 2);
+        assert_eq!(capture.cursor, 4);
+    }
+
+    #[test]
+    fn capture_finds_prompt_marks_in_history() {
+        let mut terminal = Terminal::new(3, 10);
+        terminal.set_scrollback_len(4);
+        let mut output = Vec::new();
+        let prompt = b"\x1b]133;A\x07$ ";
+        terminal.process(prompt, &mut output);
+        terminal.process(b"ls\r\na\r\nb\r\n", &mut output);
+        terminal.process(prompt, &mut output);
+        // A command's output and the next prompt in one read.
+        let output_and_prompt = [&b"make\r\n0123456789abcd\r\n"[..], prompt].concat();
+        terminal.process(&output_and_prompt, &mut output);
+
+        let capture = terminal.capture(false);
+        assert_eq!(
+            capture.lines,
+            ["$ ls", "a", "b", "$ make", "0123456789abcd", "$ "]
+        );
+        assert_eq!(capture.prompts, [0, 3, 5]);
+
+        // The first prompt scrolls out of history.
+        terminal.process(b"\r\n\r\n", &mut output);
+        let capture = terminal.capture(false);
+        assert_eq!(capture.lines[..4], ["b", "$ make", "0123456789abcd", "$ "]);
+        assert_eq!(capture.prompts, [1, 3]);
+
+        // Marks follow the lines kept when history shrinks.
+        terminal.set_scrollback_len(1);
+        let capture = terminal.capture(false);
+        let prompts: Vec<_> = capture.prompts.iter().map(|&p| &capture.lines[p]).collect();
+        assert_eq!(prompts, ["$ "]);
+    }
+
+    #[test]
+    fn rewrap_joins_rows_that_filled_the_width_they_were_written_at() {
+        let mut terminal = Terminal::new(4, 10);
+        let mut output = Vec::new();
+        // Broken at the edge by the program itself, then soft-wrapped.
+        terminal.process(b"0123456789\r\nabc\r\nxxxxxxxxxxyy\r\n", &mut output);
+        // Widening drops the soft wrap mark of the rows on screen.
+        terminal.resize(20, 4);
+        terminal.process(b"0123456789\r\nnext\r\n", &mut output);
+
+        assert_eq!(
+            terminal.capture(false).lines,
+            [
+                "0123456789",
+                "abc",
+                "xxxxxxxxxx",
+                "yy",
+                "0123456789",
+                "next",
+                ""
+            ]
+        );
+        assert_eq!(
+            terminal.capture(true).lines,
+            ["0123456789abc", "xxxxxxxxxxyy", "0123456789", "next", ""]
+        );
+    }
+
+    #[test]
+    fn paste_bytes_sends_enter_and_brackets() {
+        assert_eq!(paste_bytes(b"a\nb\r\nc", false), b"a\rb\rc");
+        assert_eq!(
+            paste_bytes(b"x\x1b[201~y\n", true),
+            b"\x1b[200~xy\r\x1b[201~"
+        );
+    }
+
     #[test]
     fn handled_sgr_is_not_added_to_passthrough_sequences() {
         let mut parser = vt100::Parser::new_with_callbacks(
@@ -0,0 +1,132 @@
+//! The key of an encrypted session (`pterm new --encrypt`): 32 random bytes
+//! in `<session_dir>/key`, readable only by the owner. Clients that can read
+//! it encrypt their connection in `HELLO`; the daemon refuses everyone else,
+//! so a peer that reaches the socket through misconfigured permissions
+//! still cannot read or type into the terminal.
+
+use pterm_proto::crypto::KEY_LEN;
+use std::fs::OpenOptions;
+use std::io::{self, Read, Write};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::path::Path;
+
+/// Key file name within a session directory.
+pub const KEY_FILENAME: &str = "key";
+
+/// Create a fresh key for the session in `session_dir`. An existing key is
+/// an error rather than being replaced.
+pub fn create(session_dir: &Path) -> io::Result<()> {
+    let key: [u8; KEY_LEN] = random()?;
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(session_dir.join(KEY_FILENAME))?;
+    file.write_all(&key)?;
+    file.sync_all()
+}
+
+/// Remove the key of the session in `session_dir`, if there is one.
+pub fn remove(session_dir: &Path) -> io::Result<()> {
+    match std::fs::remove_file(session_dir.join(KEY_FILENAME)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// The key of the session in `session_dir`, or `None` if it is not
+/// encrypted.
+pub fn read(session_dir: &Path) -> io::Result<Option<[u8; KEY_LEN]>> {
+    let path = session_dir.join(KEY_FILENAME);
+    let mut file = match std::fs::File::open(&path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => {
+            return Err(io::Error::new(
+                e.kind(),
+                format!("cannot read the session key {}: {}", path.display(), e),
+            ))
+        }
+    };
+    let mode = file.metadata()?.mode();
+    if mode & 0o077 != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "session key {} is readable by others (mode {:o}); chmod 600 it",
+                path.display(),
+                mode & 0o777
+            ),
+        ));
+    }
+    let mut key = [0u8; KEY_LEN];
+    file.read_exact(&mut key).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("malformed session key {}: {}", path.display(), e),
+        )
+    })?;
+    Ok(Some(key))
+}
+
+/// The key of the session listening on `socket`, see [`read`].
+pub fn for_socket(socket: &Path) -> io::Result<Option<[u8; KEY_LEN]>> {
+    read(socket.parent().unwrap_or(Path::new(".")))
+}
+
+/// `N` bytes from the kernel's random source.
+pub fn random<const N: usize>() -> io::Result<[u8; N]> {
+    let mut bytes = [0u8; N];
+    std::fs::File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// `bytes` as lowercase hex, as nonces travel in `HELLO`.
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// The `N` bytes `hex` spells, if it spells exactly that many.
+pub fn from_hex<const N: usize>(hex: &str) -> Option<[u8; N]> {
+    if hex.len() != 2 * N || !hex.is_ascii() {
+        return None;
+    }
+    let mut bytes = [0u8; N];
+    for (byte, pair) in bytes.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_are_created_once_and_kept_private() {
+        let dir = std::env::temp_dir().join(format!("pterm-key-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        assert_eq!(read(&dir).unwrap(), None);
+
+        create(&dir).unwrap();
+        let key = read(&dir).unwrap().unwrap();
+        assert_eq!(
+            create(&dir).unwrap_err().kind(),
+            io::ErrorKind::AlreadyExists
+        );
+        assert_eq!(read(&dir).unwrap(), Some(key));
+
+        let path = dir.join(KEY_FILENAME);
+        std::fs::set_permissions(&path, std::os::unix::fs::PermissionsExt::from_mode(0o644))
+            .unwrap();
+        assert!(read(&dir).unwrap_err().to_string().contains("chmod 600"));
+        remove(&dir).unwrap();
+        remove(&dir).unwrap();
+        assert_eq!(read(&dir).unwrap(), None);
+
+        assert_eq!(from_hex::<2>(&to_hex(&[0xab, 0x01])), Some([0xab, 0x01]));
+        assert_eq!(from_hex::<2>("ab0"), None);
+        assert_eq!(from_hex::<1>("zz"), None);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
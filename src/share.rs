@@ -0,0 +1,204 @@
+//! `pterm share`: one-time tokens that let another user attach to a session
+//! read-only, e.g. to show a coworker a failing build without handing the
+//! session over.
+//!
+//! The daemon issues a token (16 random bytes, hex) that is valid until it
+//! expires or `pterm unshare` revokes it. While any token is outstanding it
+//! opens the session up for connections from other users: the socket
+//! becomes connectable by everyone (mode 0777) and the session directory
+//! searchable, and the daemon accepts other uids as guests instead of
+//! refusing them. A guest must redeem a token in its `HELLO` before
+//! anything else, which uses the token up, and is then held to read-only
+//! attaching until the token's time runs out. The previous modes come back
+//! once no token is left. Reaching the session directory at all takes a
+//! shared socket root (`[[socket_dirs]]`), as for `pterm transfer`.
+
+use crate::meta;
+use serde::{Deserialize, Serialize};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::Path;
+
+/// Token length in bytes, before hex encoding.
+pub const TOKEN_LEN: usize = 16;
+
+/// Share tokens of a session, carried across hot upgrades.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Shares {
+    tokens: Vec<Share>,
+    /// Modes of the session directory and socket before the first token
+    /// opened them up; `None` while closed.
+    restore: Option<(u32, u32)>,
+}
+
+/// An outstanding token.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Share {
+    pub token: String,
+    /// Unix time the token, and the access of the guest that redeemed it,
+    /// ends.
+    pub expires_at: u64,
+}
+
+/// A connection from another user than the session's owner.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Guest {
+    /// Has to redeem a token in `HELLO` first.
+    Pending,
+    /// Redeemed a token; read-only until this Unix time.
+    Admitted { until: u64 },
+}
+
+impl Shares {
+    /// Whether a token is outstanding, so other users may connect.
+    pub fn active(&self) -> bool {
+        !self.tokens.is_empty()
+    }
+
+    /// Issue a token valid for `secs` seconds, opening up the session whose
+    /// socket is `socket` if it is the first.
+    pub fn issue(&mut self, socket: &Path, secs: u64) -> Result<Share, String> {
+        let token: [u8; TOKEN_LEN] = crate::session_key::random().map_err(|e| e.to_string())?;
+        if self.restore.is_none() {
+            self.restore = Some(open_access(socket)?);
+        }
+        let share = Share {
+            token: crate::session_key::to_hex(&token),
+            expires_at: meta::unix_now().saturating_add(secs),
+        };
+        self.tokens.push(share.clone());
+        Ok(share)
+    }
+
+    /// Use up `token`. Returns when the guest's access ends, or `None` if
+    /// the token is unknown or has expired.
+    pub fn redeem(&mut self, socket: &Path, token: &str) -> Option<u64> {
+        let now = meta::unix_now();
+        let index = self
+            .tokens
+            .iter()
+            .position(|share| share.expires_at > now && same_token(&share.token, token))?;
+        let share = self.tokens.remove(index);
+        self.close_if_unused(socket);
+        Some(share.expires_at)
+    }
+
+    /// Drop the tokens that have expired by `now`. Returns how many.
+    pub fn expire(&mut self, socket: &Path, now: u64) -> usize {
+        let before = self.tokens.len();
+        self.tokens.retain(|share| share.expires_at > now);
+        self.close_if_unused(socket);
+        before - self.tokens.len()
+    }
+
+    /// Drop every token. Returns how many there were.
+    pub fn revoke(&mut self, socket: &Path) -> usize {
+        let revoked = std::mem::take(&mut self.tokens).len();
+        self.close_if_unused(socket);
+        revoked
+    }
+
+    fn close_if_unused(&mut self, socket: &Path) {
+        if self.tokens.is_empty() {
+            if let Some(modes) = self.restore.take() {
+                if let Err(e) = close_access(socket, modes) {
+                    log::warn!("Failed to restore the modes of {}: {}", socket.display(), e);
+                }
+            }
+        }
+    }
+}
+
+/// Let other users connect to `socket`. Returns the modes of its directory
+/// and of the socket to restore afterwards.
+fn open_access(socket: &Path) -> Result<(u32, u32), String> {
+    let dir = socket.parent().unwrap_or(Path::new("."));
+    for ancestor in dir.ancestors().skip(1) {
+        let mode = std::fs::metadata(ancestor)
+            .map_err(|e| format!("{}: {}", ancestor.display(), e))?
+            .mode();
+        if mode & 0o011 == 0 {
+            return Err(format!(
+                "other users cannot reach {}: move the session to a shared socket root first",
+                ancestor.display()
+            ));
+        }
+    }
+    let mode_of = |path: &Path| {
+        std::fs::metadata(path)
+            .map(|meta| meta.mode() & 0o7777)
+            .map_err(|e| format!("{}: {}", path.display(), e))
+    };
+    let modes = (mode_of(dir)?, mode_of(socket)?);
+    let set = |path: &Path, mode: u32| {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+            .map_err(|e| format!("{}: {}", path.display(), e))
+    };
+    set(dir, modes.0 | 0o011)?;
+    set(socket, 0o777)?;
+    Ok(modes)
+}
+
+fn close_access(socket: &Path, (dir_mode, socket_mode): (u32, u32)) -> std::io::Result<()> {
+    let dir = socket.parent().unwrap_or(Path::new("."));
+    std::fs::set_permissions(socket, std::fs::Permissions::from_mode(socket_mode))?;
+    std::fs::set_permissions(dir, std::fs::Permissions::from_mode(dir_mode))
+}
+
+/// Compare tokens in time independent of where they differ.
+fn same_token(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0u8, |diff, (x, y)| diff | (x ^ y))
+            == 0
+}
+
+/// Command line that attaches with `token` to the session at `socket`.
+pub fn attach_command(token: &str, socket: &Path) -> String {
+    format!("pterm attach --token {} {}", token, socket.display())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens_are_used_once_and_close_access_when_gone() {
+        let root = std::env::temp_dir().join(format!("pterm-share-test-{}", std::process::id()));
+        let dir = root.join("dev");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::set_permissions(&root, std::fs::Permissions::from_mode(0o755)).unwrap();
+        std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o700)).unwrap();
+        let socket = dir.join("socket");
+        let _listener = std::os::unix::net::UnixListener::bind(&socket).unwrap();
+        std::fs::set_permissions(&socket, std::fs::Permissions::from_mode(0o700)).unwrap();
+        let mode = |path: &Path| std::fs::metadata(path).unwrap().mode() & 0o777;
+
+        let mut shares = Shares::default();
+        let first = shares.issue(&socket, 3600).unwrap();
+        let second = shares.issue(&socket, 60).unwrap();
+        assert_eq!(first.token.len(), 2 * TOKEN_LEN);
+        assert_ne!(first.token, second.token);
+        assert_eq!((mode(&dir), mode(&socket)), (0o711, 0o777));
+
+        assert_eq!(shares.redeem(&socket, "nope"), None);
+        assert_eq!(shares.redeem(&socket, &first.token), Some(first.expires_at));
+        assert_eq!(shares.redeem(&socket, &first.token), None);
+        assert!(shares.active());
+
+        assert_eq!(shares.expire(&socket, second.expires_at - 1), 0);
+        assert_eq!(shares.expire(&socket, second.expires_at), 1);
+        assert!(!shares.active());
+        assert_eq!((mode(&dir), mode(&socket)), (0o700, 0o700));
+
+        shares.issue(&socket, 60).unwrap();
+        assert_eq!(shares.revoke(&socket), 1);
+        assert_eq!(mode(&socket), 0o700);
+
+        std::fs::set_permissions(&root, std::fs::Permissions::from_mode(0o700)).unwrap();
+        let error = shares.issue(&socket, 60).unwrap_err();
+        assert!(error.contains("shared socket root"), "{}", error);
+        assert!(!shares.active());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,253 @@
+//! Per-session resource accounting: CPU time sampled from `/proc`, and
+//! `pterm stats` aggregation over the totals persisted in `meta.json`.
+
+use crate::clients::format_bytes;
+use crate::list::SessionEntry;
+use crate::meta::SessionStats;
+use crate::schedule::format_duration;
+use nix::libc;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+
+/// Accumulates the CPU time of every process in a session (the pty child is
+/// a session leader, so its session id is the child pid). Processes are
+/// counted by their own user+system time; once one disappears its last
+/// sample is kept, so the total only grows.
+#[derive(Debug, Default)]
+pub struct CpuSampler {
+    /// Clock ticks per process at the last sample.
+    seen: HashMap<i32, u64>,
+    /// Ticks of processes that have exited since sampling started.
+    retired: u64,
+    /// Milliseconds carried over from a previous daemon image.
+    base_ms: u64,
+}
+
+impl CpuSampler {
+    /// Sampler whose first sample of session `sid` continues from `cpu_ms`
+    /// (after a hot upgrade, the processes still running are already
+    /// included in it).
+    pub fn resume(sid: i32, cpu_ms: u64) -> Self {
+        let mut sampler = Self::default();
+        let current = sampler.sample(sid);
+        sampler.base_ms = cpu_ms.saturating_sub(current);
+        sampler
+    }
+
+    /// Total CPU milliseconds used by session `sid` so far.
+    pub fn sample(&mut self, sid: i32) -> u64 {
+        let now = session_cpu_ticks(sid);
+        self.retired += self
+            .seen
+            .iter()
+            .filter(|(pid, _)| !now.contains_key(pid))
+            .map(|(_, ticks)| ticks)
+            .sum::<u64>();
+        self.seen = now;
+        let ticks = self.retired + self.seen.values().sum::<u64>();
+        self.base_ms + ticks * 1000 / clock_ticks_per_sec()
+    }
+}
+
+fn clock_ticks_per_sec() -> u64 {
+    match unsafe { libc::sysconf(libc::_SC_CLK_TCK) } {
+        n if n > 0 => n as u64,
+        _ => 100,
+    }
+}
+
+/// utime + stime (in clock ticks) of every process in session `sid`.
+fn session_cpu_ticks(sid: i32) -> HashMap<i32, u64> {
+    let Ok(dir) = std::fs::read_dir("/proc") else {
+        return HashMap::new();
+    };
+    dir.filter_map(|entry| {
+        let pid: i32 = entry.ok()?.file_name().to_str()?.parse().ok()?;
+        let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+        let (session, ticks) = parse_stat(&stat)?;
+        (session == sid).then_some((pid, ticks))
+    })
+    .collect()
+}
+
+/// Session id and utime + stime from a `/proc/<pid>/stat` line. The command
+/// name may contain spaces and parentheses, so fields are counted from the
+/// last `)`.
+fn parse_stat(stat: &str) -> Option<(i32, u64)> {
+    let fields: Vec<&str> = stat[stat.rfind(')')? + 1..].split_whitespace().collect();
+    // fields[0] is field 3 (state): session is field 6, utime/stime 14/15.
+    let session = fields.get(3)?.parse().ok()?;
+    let utime: u64 = fields.get(11)?.parse().ok()?;
+    let stime: u64 = fields.get(12)?.parse().ok()?;
+    Some((session, utime + stime))
+}
+
+/// Totals for one group of sessions.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct GroupStats {
+    pub prefix: String,
+    pub sessions: usize,
+    pub cpu_ms: u64,
+    pub output_bytes: u64,
+    pub input_bytes: u64,
+}
+
+impl GroupStats {
+    fn add(&mut self, stats: &SessionStats) {
+        self.sessions += 1;
+        self.cpu_ms += stats.cpu_ms;
+        self.output_bytes += stats.output_bytes;
+        self.input_bytes += stats.input_bytes;
+    }
+}
+
+/// Group key for `name` one level below `prefix`: `pterm stats` groups by
+/// top-level name component, `pterm stats team` by `team/<component>`.
+fn group_key(name: &str, prefix: &str) -> String {
+    let prefix = prefix.trim_end_matches('/');
+    let rest = match prefix {
+        "" => name,
+        _ => match name.strip_prefix(prefix).and_then(|r| r.strip_prefix('/')) {
+            Some(rest) => rest,
+            None => return name.to_string(),
+        },
+    };
+    let component = rest.split('/').next().unwrap_or(rest);
+    match prefix {
+        "" => component.to_string(),
+        _ => format!("{}/{}", prefix, component),
+    }
+}
+
+/// Sum the persisted stats of `entries` per group below `prefix`, sorted by
+/// CPU time (highest first), plus the overall total.
+pub fn aggregate(entries: &[SessionEntry], prefix: &str) -> (Vec<GroupStats>, GroupStats) {
+    let mut groups: BTreeMap<String, GroupStats> = BTreeMap::new();
+    let mut total = GroupStats {
+        prefix: prefix.to_string(),
+        ..GroupStats::default()
+    };
+    for entry in entries {
+        let Some(meta) = entry.meta.as_ref() else {
+            continue;
+        };
+        let key = group_key(&entry.name, prefix);
+        groups
+            .entry(key.clone())
+            .or_insert_with(|| GroupStats {
+                prefix: key,
+                ..GroupStats::default()
+            })
+            .add(&meta.stats);
+        total.add(&meta.stats);
+    }
+    let mut groups: Vec<GroupStats> = groups.into_values().collect();
+    groups.sort_by(|a, b| b.cpu_ms.cmp(&a.cpu_ms).then(a.prefix.cmp(&b.prefix)));
+    (groups, total)
+}
+
+fn format_cpu(ms: u64) -> String {
+    if ms < 1000 {
+        return format!("{}ms", ms);
+    }
+    format_duration(ms / 1000)
+}
+
+/// Render groups and the total as an aligned table with a header row.
+pub fn render_table(groups: &[GroupStats], total: &GroupStats) -> String {
+    const HEADERS: [&str; 5] = ["PREFIX", "SESSIONS", "CPU", "OUTPUT", "INPUT"];
+    let total_row = GroupStats {
+        prefix: "TOTAL".to_string(),
+        ..total.clone()
+    };
+    let rows: Vec<[String; 5]> = groups
+        .iter()
+        .chain(std::iter::once(&total_row))
+        .map(|g| {
+            [
+                g.prefix.clone(),
+                g.sessions.to_string(),
+                format_cpu(g.cpu_ms),
+                format_bytes(g.output_bytes),
+                format_bytes(g.input_bytes),
+            ]
+        })
+        .collect();
+    let mut widths = HEADERS.map(str::len);
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    let mut out = String::new();
+    for row in std::iter::once(HEADERS.map(String::from)).chain(rows) {
+        let line: Vec<String> = row
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect();
+        out.push_str(line.join("  ").trim_end());
+        out.push('\n');
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::list::SessionState;
+    use crate::meta::SessionMeta;
+
+    fn entry(name: &str, cpu_ms: u64, output_bytes: u64) -> SessionEntry {
+        SessionEntry {
+            name: name.to_string(),
+            state: SessionState::Detached,
+            meta: Some(SessionMeta {
+                stats: SessionStats {
+                    cpu_ms,
+                    output_bytes,
+                    input_bytes: 0,
+                },
+                ..SessionMeta::default()
+            }),
+        }
+    }
+
+    #[test]
+    fn parse_stat_skips_command_name() {
+        let stat = "42 (a) b (c)) S 1 42 40 0 -1 4194560 10 0 0 0 7 3 0 0 20 0 1 0";
+        assert_eq!(parse_stat(stat), Some((40, 10)));
+        assert_eq!(parse_stat("garbage"), None);
+    }
+
+    #[test]
+    fn sampler_keeps_exited_processes() {
+        // No process is in session -1; the sampler starts from its base.
+        let mut sampler = CpuSampler::resume(-1, 1500);
+        sampler.seen.insert(7, 100);
+        assert_eq!(
+            sampler.sample(-1),
+            1500 + 100 * 1000 / clock_ticks_per_sec()
+        );
+    }
+
+    #[test]
+    fn aggregate_groups_below_prefix() {
+        let entries = [
+            entry("ci/a", 100, 10),
+            entry("ci/b", 200, 20),
+            entry("web/x/1", 5000, 1),
+            entry("solo", 0, 0),
+        ];
+        let (groups, total) = aggregate(&entries, "");
+        let summary: Vec<(&str, usize, u64)> = groups
+            .iter()
+            .map(|g| (g.prefix.as_str(), g.sessions, g.cpu_ms))
+            .collect();
+        assert_eq!(summary, [("web", 1, 5000), ("ci", 2, 300), ("solo", 1, 0)]);
+        assert_eq!((total.sessions, total.output_bytes), (4, 31));
+
+        let (groups, _) = aggregate(&entries[2..3], "web");
+        assert_eq!(groups[0].prefix, "web/x");
+    }
+}
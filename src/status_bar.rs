@@ -0,0 +1,124 @@
+//! Status bar drawn by the bridge on the bottom row of the hosting terminal
+//! (`pterm attach --status-bar`), for users outside Neovim who lose track of
+//! which session they are in.
+//!
+//! The session is sized one row short and the terminal's scroll region
+//! (DECSTBM) stops above the bar, so the session's output never scrolls over
+//! it. The bar is painted between DECSC / DECRC (`ESC 7` / `ESC 8`). Output
+//! that erases the screen, resets the scroll region or switches to or from
+//! the alternate screen takes the bar or the region with it, so the bridge
+//! paints both again after such output.
+
+use crate::list::format_age;
+use crate::meta::{self, SessionMeta};
+use std::path::Path;
+
+/// Rows left to the session below a terminal of `rows` rows.
+pub fn session_rows(rows: u16) -> u16 {
+    rows.saturating_sub(1).max(1)
+}
+
+/// Bar contents for the session whose socket is `socket_path`: name, size,
+/// attached clients and time since the last activity, as in `pterm list`.
+pub fn text(socket_path: &Path, cols: u16, rows: u16) -> String {
+    let dir = socket_path.parent().unwrap_or(Path::new("."));
+    let meta = meta::read(dir).ok();
+    let name = match &meta {
+        Some(SessionMeta { session, .. }) if !session.is_empty() => session.clone(),
+        _ => dir
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+    };
+    let mut text = format!(" pterm: {} | {}x{}", name, cols, session_rows(rows));
+    if let Some(meta) = meta {
+        let idle = meta::unix_now().saturating_sub(meta.last_activity);
+        text.push_str(&format!(
+            " | {} client{} | active {} ago",
+            meta.clients,
+            if meta.clients == 1 { "" } else { "s" },
+            format_age(idle)
+        ));
+    }
+    text
+}
+
+/// Limit the scroll region to the rows above the bar and draw `text` on
+/// the bottom row, in reverse video and padded to the full width.
+pub fn paint(text: &str, cols: u16, rows: u16) -> Vec<u8> {
+    let mut line: String = text.chars().take(cols as usize).collect();
+    let width = line.chars().count();
+    line.push_str(&" ".repeat(cols as usize - width));
+    format!(
+        "\x1b7\x1b[1;{}r\x1b[{};1H\x1b[0;7m{}\x1b[0m\x1b8",
+        session_rows(rows),
+        rows,
+        line
+    )
+    .into_bytes()
+}
+
+/// Give the whole terminal back: reset the scroll region and erase the bar.
+pub fn clear(rows: u16) -> Vec<u8> {
+    format!("\x1b7\x1b[r\x1b[{};1H\x1b[2K\x1b8", rows).into_bytes()
+}
+
+/// Whether `output` may have erased the bar or reset the scroll region:
+/// RIS, ED, DECSTBM, or an alternate screen switch. Sequences split across
+/// writes are missed until the bar is painted again for new contents.
+pub fn needs_repaint(output: &[u8]) -> bool {
+    let mut i = 0;
+    while let Some(pos) = output[i..].iter().position(|&b| b == 0x1b) {
+        i += pos + 1;
+        match output.get(i) {
+            Some(b'c') => return true,
+            Some(b'[') => {
+                let start = i + 1;
+                let Some(len) = output[start..]
+                    .iter()
+                    .position(|b| (0x40..=0x7e).contains(b))
+                else {
+                    return false;
+                };
+                let params = &output[start..start + len];
+                match output[start + len] {
+                    b'J' | b'r' => return true,
+                    b'h' | b'l'
+                        if params.first() == Some(&b'?')
+                            && params[1..]
+                                .split(|&b| b == b';')
+                                .any(|mode| matches!(mode, b"1049" | b"1047" | b"47")) =>
+                    {
+                        return true;
+                    }
+                    _ => {}
+                }
+                i = start + len + 1;
+            }
+            _ => {}
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repaints_after_erase_region_and_screen_switches() {
+        assert!(needs_repaint(b"\x1b[H\x1b[2J$ "));
+        assert!(needs_repaint(b"x\x1b[J"));
+        assert!(needs_repaint(b"\x1b[r"));
+        assert!(needs_repaint(b"\x1b[?25l\x1b[?1049h"));
+        assert!(needs_repaint(b"\x1bc"));
+        assert!(!needs_repaint(b"\x1b[1;31mred\x1b[0m \x1b[?25h\x1b[2K"));
+        assert!(!needs_repaint(b"\x1b[?104"));
+
+        let bar = String::from_utf8(paint("dev", 6, 24)).unwrap();
+        assert_eq!(bar, "\x1b7\x1b[1;23r\x1b[24;1H\x1b[0;7mdev   \x1b[0m\x1b8");
+        assert!(String::from_utf8(paint("a long name", 4, 24))
+            .unwrap()
+            .contains("a lo\x1b[0m"));
+    }
+}
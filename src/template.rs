@@ -0,0 +1,347 @@
+//! Session templates for `pterm up`: a TOML file describing the sessions of
+//! a project, started together.
+//!
+//! ```toml
+//! [vars]
+//! test_cmd = "cargo test"
+//!
+//! [[sessions]]
+//! name = "{{project}}/edit"
+//! command = ["nvim", "."]
+//! cwd = "{{project_dir}}"
+//!
+//! [[sessions]]
+//! name = "{{project}}/test"
+//! command = ["sh", "-c", "{{test_cmd}}; exec $SHELL"]
+//! cwd = "{{project_dir}}"
+//! env = { RUST_LOG = "{{env.RUST_LOG}}" }
+//! ```
+//!
+//! `{{var}}` in a session's name, command, cwd and env values is replaced by
+//! the variable's value: from `--var var=value`, else the template's `[vars]`,
+//! else a built-in (`project_dir`, the current directory; `project`, its
+//! file name; `branch`, the git branch checked out there). `{{env.NAME}}` is
+//! the environment variable `NAME`. Undefined variables are an error, so a
+//! template never starts a session with a half-filled command line.
+
+use crate::here;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Template {
+    /// Default values of variables, which may themselves use variables,
+    /// including ones defined further down.
+    pub vars: BTreeMap<String, String>,
+    pub sessions: Vec<SessionTemplate>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SessionTemplate {
+    pub name: String,
+    /// Command and arguments; the default command if empty.
+    #[serde(default)]
+    pub command: Vec<String>,
+    /// Directory the session starts in; the current directory if unset.
+    pub cwd: Option<String>,
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+}
+
+/// A session of a template with its variables filled in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionSpec {
+    pub name: String,
+    pub command: Vec<String>,
+    pub cwd: Option<PathBuf>,
+    pub env: BTreeMap<String, String>,
+}
+
+impl Template {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text =
+            std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        toml::from_str(&text).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    /// The template's sessions with `overrides` (from `--var`) applied.
+    pub fn expand(&self, overrides: &BTreeMap<String, String>) -> Result<Vec<SessionSpec>, String> {
+        let vars = Vars::new(&self.vars, overrides)?;
+        self.sessions
+            .iter()
+            .map(|session| {
+                let context = |e: String| format!("session '{}': {}", session.name, e);
+                let name = vars.interpolate(&session.name).map_err(context)?;
+                let command = session
+                    .command
+                    .iter()
+                    .map(|arg| vars.interpolate(arg))
+                    .collect::<Result<_, _>>()
+                    .map_err(context)?;
+                let cwd = match &session.cwd {
+                    Some(cwd) => Some(PathBuf::from(vars.interpolate(cwd).map_err(context)?)),
+                    None => None,
+                };
+                let env = session
+                    .env
+                    .iter()
+                    .map(|(key, value)| Ok((key.clone(), vars.interpolate(value)?)))
+                    .collect::<Result<_, String>>()
+                    .map_err(context)?;
+                Ok(SessionSpec {
+                    name,
+                    command,
+                    cwd,
+                    env,
+                })
+            })
+            .collect()
+    }
+}
+
+/// Parse a `--var name=value` argument.
+pub fn parse_var(arg: &str) -> Result<(String, String), String> {
+    match arg.split_once('=') {
+        Some((name, value)) if !name.is_empty() => Ok((name.to_string(), value.to_string())),
+        _ => Err(format!("expected name=value, got '{}'", arg)),
+    }
+}
+
+/// Variable values, resolved in order: `--var`, `[vars]`, built-ins.
+struct Vars {
+    values: BTreeMap<String, String>,
+}
+
+impl Vars {
+    fn new(
+        defaults: &BTreeMap<String, String>,
+        overrides: &BTreeMap<String, String>,
+    ) -> Result<Self, String> {
+        let mut resolver = Resolver {
+            defaults,
+            values: overrides.clone(),
+            resolving: Vec::new(),
+        };
+        // Built-ins derive from `project_dir`, which may come from either.
+        if !resolver.values.contains_key("project_dir") {
+            let dir = if defaults.contains_key("project_dir") {
+                resolver.resolve("project_dir")?
+            } else {
+                std::env::current_dir()
+                    .map_err(|e| e.to_string())?
+                    .to_string_lossy()
+                    .into_owned()
+            };
+            resolver.values.insert("project_dir".to_string(), dir);
+        }
+        let project_dir = PathBuf::from(&resolver.values["project_dir"]);
+        let project_dir = std::path::absolute(&project_dir).unwrap_or(project_dir);
+        for (name, value) in builtins(&project_dir) {
+            if !overrides.contains_key(name) && !defaults.contains_key(name) {
+                resolver.values.insert(name.to_string(), value);
+            }
+        }
+        for name in defaults.keys() {
+            resolver.resolve(name)?;
+        }
+        Ok(Self {
+            values: resolver.values,
+        })
+    }
+
+    /// Replace every `{{name}}` in `text`.
+    fn interpolate(&self, text: &str) -> Result<String, String> {
+        interpolate(text, |name| match self.values.get(name) {
+            Some(value) => Ok(value.clone()),
+            None => lookup_env(name).unwrap_or_else(|| Err(undefined(name))),
+        })
+    }
+}
+
+/// Resolves `[vars]` defaults on first use, so they may refer to each
+/// other in any order.
+struct Resolver<'a> {
+    defaults: &'a BTreeMap<String, String>,
+    /// Overrides, built-ins and the defaults resolved so far.
+    values: BTreeMap<String, String>,
+    /// Defaults being resolved, outermost first, to report cycles.
+    resolving: Vec<String>,
+}
+
+impl Resolver<'_> {
+    fn resolve(&mut self, name: &str) -> Result<String, String> {
+        if let Some(value) = self.values.get(name) {
+            return Ok(value.clone());
+        }
+        if let Some(value) = lookup_env(name) {
+            return value;
+        }
+        let Some(default) = self.defaults.get(name) else {
+            return Err(undefined(name));
+        };
+        if let Some(start) = self.resolving.iter().position(|n| n == name) {
+            let mut cycle = self.resolving[start..].to_vec();
+            cycle.push(name.to_string());
+            return Err(format!(
+                "variables refer to each other: {}",
+                cycle.join(" -> ")
+            ));
+        }
+        self.resolving.push(name.to_string());
+        let value = interpolate(default, |name| self.resolve(name));
+        self.resolving.pop();
+        let value = value?;
+        self.values.insert(name.to_string(), value.clone());
+        Ok(value)
+    }
+}
+
+/// Replace every `{{name}}` in `text` with what `lookup` gives for it.
+fn interpolate(
+    text: &str,
+    mut lookup: impl FnMut(&str) -> Result<String, String>,
+) -> Result<String, String> {
+    let mut out = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find("{{") {
+        out.push_str(&rest[..start]);
+        let Some(len) = rest[start + 2..].find("}}") else {
+            return Err(format!("unterminated '{{{{' in '{}'", text));
+        };
+        let name = rest[start + 2..start + 2 + len].trim();
+        out.push_str(&lookup(name)?);
+        rest = &rest[start + 2 + len + 2..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// The value of `{{env.NAME}}`, or `None` for other names.
+fn lookup_env(name: &str) -> Option<Result<String, String>> {
+    let var = name.strip_prefix("env.")?;
+    Some(std::env::var(var).map_err(|_| format!("environment variable '{}' is not set", var)))
+}
+
+fn undefined(name: &str) -> String {
+    format!("undefined variable '{}' (pass --var {}=...)", name, name)
+}
+
+/// `project` and, inside a git work tree, `branch` for `project_dir`.
+fn builtins(project_dir: &Path) -> Vec<(&'static str, String)> {
+    let project = project_dir
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let mut builtins = vec![("project", project)];
+    if let Some(branch) = here::git_root(project_dir).and_then(|root| git_branch(&root)) {
+        builtins.push(("branch", branch));
+    }
+    builtins
+}
+
+/// The branch checked out in the work tree at `root`, or the commit for a
+/// detached HEAD.
+fn git_branch(root: &Path) -> Option<String> {
+    let dot_git = root.join(".git");
+    // Worktrees and submodules have a `.git` file pointing at the git dir.
+    let git_dir = match std::fs::read_to_string(&dot_git) {
+        Ok(link) => root.join(link.strip_prefix("gitdir:")?.trim()),
+        Err(_) => dot_git,
+    };
+    let head = std::fs::read_to_string(git_dir.join("HEAD")).ok()?;
+    let head = head.trim();
+    match head.strip_prefix("ref: ") {
+        Some(reference) => Some(
+            reference
+                .strip_prefix("refs/heads/")
+                .unwrap_or(reference)
+                .to_string(),
+        ),
+        None => Some(head.chars().take(12).collect()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expands_vars_builtins_and_env() {
+        let base = std::env::temp_dir().join(format!("pterm-template-test-{}", std::process::id()));
+        let repo = base.join("shop");
+        std::fs::create_dir_all(repo.join(".git")).unwrap();
+        std::fs::write(repo.join(".git/HEAD"), "ref: refs/heads/feature/cart\n").unwrap();
+
+        let template: Template = toml::from_str(
+            r#"
+            [vars]
+            test = "make test-{{branch}}"
+
+            [[sessions]]
+            name = "{{project}}/{{ branch }}"
+            command = ["sh", "-c", "{{test}}"]
+            cwd = "{{project_dir}}"
+            env = { HOME_COPY = "{{env.HOME}}" }
+            "#,
+        )
+        .unwrap();
+        let overrides = BTreeMap::from([(
+            "project_dir".to_string(),
+            repo.to_string_lossy().into_owned(),
+        )]);
+        let specs = template.expand(&overrides).unwrap();
+        assert_eq!(specs[0].name, "shop/feature/cart");
+        assert_eq!(specs[0].command[2], "make test-feature/cart");
+        assert_eq!(specs[0].cwd.as_deref(), Some(repo.as_path()));
+        assert_eq!(specs[0].env["HOME_COPY"], std::env::var("HOME").unwrap());
+
+        let overrides = BTreeMap::from([
+            (
+                "project_dir".to_string(),
+                repo.to_string_lossy().into_owned(),
+            ),
+            ("test".to_string(), "true".to_string()),
+        ]);
+        assert_eq!(template.expand(&overrides).unwrap()[0].command[2], "true");
+
+        let broken: Template = toml::from_str("[[sessions]]\nname = \"{{nope}}\"").unwrap();
+        let error = broken.expand(&overrides).unwrap_err();
+        assert!(error.contains("undefined variable 'nope'"), "{}", error);
+        assert_eq!(
+            parse_var("project_dir=/src/a=b").unwrap(),
+            ("project_dir".to_string(), "/src/a=b".to_string())
+        );
+        assert!(parse_var("=x").is_err());
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn vars_may_refer_forward_but_not_in_a_cycle() {
+        let overrides = BTreeMap::from([("project_dir".to_string(), "/src/shop".to_string())]);
+        let template: Template = toml::from_str(
+            r#"
+            [vars]
+            a_cmd = "{{z_bin}} --db {{m_db}}"
+            m_db = "{{project}}.db"
+            z_bin = "{{project_dir}}/bin/serve"
+
+            [[sessions]]
+            name = "serve"
+            command = ["{{a_cmd}}"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            template.expand(&overrides).unwrap()[0].command,
+            ["/src/shop/bin/serve --db shop.db"]
+        );
+
+        let cyclic: Template =
+            toml::from_str("[vars]\na = \"{{b}}\"\nb = \"x{{c}}\"\nc = \"{{a}}\"").unwrap();
+        let error = cyclic.expand(&overrides).unwrap_err();
+        assert!(error.contains("a -> b -> c -> a"), "{}", error);
+    }
+}
@@ -0,0 +1,724 @@
+//! `pterm tmux-cc`: a subset of tmux's control mode protocol on top of pterm
+//! sessions, so tools that speak `tmux -CC` can drive pterm.
+//!
+//! Commands are read one per line from stdin and answered on stdout between
+//! `%begin` and `%end` (or `%error`) guard lines. While a session is attached
+//! its output is sent as `%output` notifications. Each pterm session is shown
+//! as a tmux session with a single window and pane; all three use the session
+//! daemon's pid as their id (`$<pid>`, `@<pid>`, `%<pid>`).
+//!
+//! Supported commands: `list-sessions`, `attach-session`, `switch-client`,
+//! `detach-client`, `kill-session`, `send-keys`, `refresh-client -C`,
+//! `list-windows`, `list-panes` and `display-message -p`.
+
+use crate::config::split_words;
+use crate::control;
+use crate::list::{self, SessionEntry};
+use crate::meta;
+use crate::paths::{session_dir, session_socket_path};
+use crate::schedule::shell_join;
+use mio::net::UnixStream;
+use mio::unix::SourceFd;
+use mio::{Events, Interest, Poll, Token};
+use nix::libc;
+use pterm_proto as proto;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+const TOKEN_STDIN: Token = Token(0);
+const TOKEN_SESSION: Token = Token(1);
+
+/// Size used until the client sends `refresh-client -C`.
+const DEFAULT_SIZE: (u16, u16) = (80, 24);
+
+/// Start of a `-CC` control mode stream (DCS), and its end.
+const DCS_START: &[u8] = b"\x1bP1000p";
+const DCS_END: &[u8] = b"\x1b\\";
+
+struct Attached {
+    name: String,
+    /// Daemon pid, used as the session, window and pane id.
+    id: i32,
+    stream: UnixStream,
+    decoder: proto::FrameDecoder,
+}
+
+struct ControlMode {
+    out: io::Stdout,
+    poll: Poll,
+    attached: Option<Attached>,
+    size: (u16, u16),
+    command_number: u64,
+    exit: bool,
+}
+
+/// Run control mode until stdin closes or the client detaches. `dcs` wraps
+/// the stream in the DCS sequence `tmux -CC` uses (plain `-C` mode without).
+pub fn run(session: Option<&str>, dcs: bool) -> io::Result<()> {
+    let stdin_fd = libc::STDIN_FILENO;
+    let _echo_guard = EchoGuard::disable(stdin_fd);
+
+    let poll = Poll::new()?;
+    poll.registry()
+        .register(&mut SourceFd(&stdin_fd), TOKEN_STDIN, Interest::READABLE)?;
+    let mut cc = ControlMode {
+        out: io::stdout(),
+        poll,
+        attached: None,
+        size: DEFAULT_SIZE,
+        command_number: 0,
+        exit: false,
+    };
+    if dcs {
+        cc.write(DCS_START)?;
+    }
+    if let Some(name) = session {
+        let result = cc.attach(name);
+        cc.reply(result)?;
+    }
+
+    let mut events = Events::with_capacity(16);
+    let mut line_buf = Vec::new();
+    let mut buf = [0u8; 65536];
+    while !cc.exit {
+        if let Err(e) = cc.poll.poll(&mut events, None) {
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(e);
+        }
+        for event in events.iter() {
+            match event.token() {
+                TOKEN_STDIN => {
+                    let n = match io::stdin().lock().read(&mut buf) {
+                        Ok(n) => n,
+                        Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                        Err(e) => return Err(e),
+                    };
+                    if n == 0 {
+                        cc.exit = true;
+                        break;
+                    }
+                    line_buf.extend_from_slice(&buf[..n]);
+                    while let Some(end) = line_buf.iter().position(|&b| b == b'\n') {
+                        let line: Vec<u8> = line_buf.drain(..=end).collect();
+                        let line = String::from_utf8_lossy(&line);
+                        cc.command(line.trim_end_matches(['\r', '\n']))?;
+                    }
+                }
+                TOKEN_SESSION => cc.read_session(&mut buf)?,
+                _ => {}
+            }
+        }
+    }
+
+    cc.write(b"%exit\n")?;
+    if dcs {
+        cc.write(DCS_END)?;
+    }
+    Ok(())
+}
+
+impl ControlMode {
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.out.write_all(data)?;
+        self.out.flush()
+    }
+
+    /// Run one command line and write its `%begin` / `%end` block.
+    fn command(&mut self, line: &str) -> io::Result<()> {
+        // An empty line detaches, as in tmux.
+        if line.trim().is_empty() {
+            self.exit = true;
+            return Ok(());
+        }
+        let result = split_words(line).and_then(|words| self.execute(&words));
+        self.reply(result)
+    }
+
+    fn reply(&mut self, result: Result<String, String>) -> io::Result<()> {
+        let time = meta::unix_now();
+        let number = self.command_number;
+        self.command_number += 1;
+        let (body, guard) = match result {
+            Ok(body) => (body, "%end"),
+            Err(error) => (error + "\n", "%error"),
+        };
+        let block = format!(
+            "%begin {time} {number} 1\n{body}{guard} {time} {number} 1\n",
+            time = time,
+            number = number,
+            body = body,
+            guard = guard
+        );
+        self.write(block.as_bytes())
+    }
+
+    fn execute(&mut self, words: &[String]) -> Result<String, String> {
+        let (command, args) = words.split_first().ok_or("empty command")?;
+        let args = Args::parse(args);
+        match command.as_str() {
+            "list-sessions" | "ls" => self.list_sessions(args.value('F')),
+            "attach-session" | "attach" | "a" | "switch-client" | "switchc" => {
+                let name = match args.value('t') {
+                    Some(target) => self.resolve(target)?,
+                    None => list::collect("")
+                        .map_err(|e| e.to_string())?
+                        .into_iter()
+                        .find(|e| e.meta.is_some())
+                        .map(|e| e.name)
+                        .ok_or("no sessions")?,
+                };
+                self.attach(&name)
+            }
+            "detach-client" | "detach" => {
+                self.exit = true;
+                Ok(String::new())
+            }
+            "kill-session" | "kill-ses" => {
+                let name = self.target(&args)?;
+                crate::paths::remove_session_dir(&session_dir(&name)).map_err(|e| e.to_string())?;
+                Ok(String::new())
+            }
+            "send-keys" | "send" => {
+                let name = self.target(&args)?;
+                let input = if args.flag('H') {
+                    hex_keys(&args.rest)?
+                } else if args.flag('l') {
+                    args.rest.join(" ").into_bytes()
+                } else {
+                    args.rest.iter().flat_map(|k| key_bytes(k)).collect()
+                };
+                self.send_input(&name, &input)?;
+                Ok(String::new())
+            }
+            "refresh-client" | "refresh" => {
+                if let Some(size) = args.value('C') {
+                    self.size = parse_size(size).ok_or("invalid size")?;
+                    if let Some(attached) = self.attached.as_mut() {
+                        let (cols, rows) = self.size;
+                        let msg =
+                            proto::encode(proto::client::RESIZE, &proto::encode_resize(cols, rows));
+                        attached.stream.write_all(&msg).map_err(|e| e.to_string())?;
+                    }
+                }
+                Ok(String::new())
+            }
+            "list-windows" | "lsw" | "list-panes" | "lsp" => {
+                let name = self.target(&args)?;
+                let default = if command.starts_with("list-w") || command == "lsw" {
+                    "#{window_index}: #{window_name} (1 panes) [#{window_width}x#{window_height}] \
+                     [layout #{window_layout}] #{window_id} (active)"
+                } else {
+                    "#{pane_index}: [#{pane_width}x#{pane_height}] #{pane_id} (active)"
+                };
+                let vars = self.pane_vars(&name)?;
+                Ok(expand(args.value('F').unwrap_or(default), &vars) + "\n")
+            }
+            "display-message" | "display" => {
+                let format = args.rest.join(" ");
+                if !args.flag('p') {
+                    return Ok(String::new());
+                }
+                let vars = match &self.attached {
+                    Some(attached) => self.pane_vars(&attached.name.clone())?,
+                    None => Vec::new(),
+                };
+                Ok(expand(&format, &vars) + "\n")
+            }
+            _ => Err(format!("unknown command: {}", command)),
+        }
+    }
+
+    fn list_sessions(&self, format: Option<&str>) -> Result<String, String> {
+        let entries = list::collect("").map_err(|e| e.to_string())?;
+        let format = format.unwrap_or(
+            "#{session_name}: #{session_windows} windows (created #{session_created_string})\
+             #{?session_attached, (attached),}",
+        );
+        let mut out = String::new();
+        for entry in entries.iter().filter(|e| e.meta.is_some()) {
+            out.push_str(&expand(format, &session_vars(entry)));
+            out.push('\n');
+        }
+        Ok(out)
+    }
+
+    /// Session named by a `-t` target: a session name, `$<id>`, or the
+    /// attached session for `%<id>` / `@<id>` or no target at all.
+    fn target(&self, args: &Args) -> Result<String, String> {
+        match args.value('t') {
+            Some(target) => self.resolve(target),
+            None => self
+                .attached
+                .as_ref()
+                .map(|a| a.name.clone())
+                .ok_or_else(|| "no current session".to_string()),
+        }
+    }
+
+    fn resolve(&self, target: &str) -> Result<String, String> {
+        // `session:window.pane` targets; only the session part matters.
+        let target = target.split([':', '.']).next().unwrap_or(target);
+        if let Some(id) = target
+            .strip_prefix(['$', '@', '%'])
+            .and_then(|id| id.parse::<i32>().ok())
+        {
+            let entries = list::collect("").map_err(|e| e.to_string())?;
+            return entries
+                .into_iter()
+                .find(|e| e.meta.as_ref().is_some_and(|m| m.pid == id))
+                .map(|e| e.name)
+                .ok_or_else(|| format!("can't find session: {}", target));
+        }
+        if target.is_empty() {
+            return self.target(&Args::default());
+        }
+        if !session_socket_path(target).exists() {
+            return Err(format!("can't find session: {}", target));
+        }
+        Ok(target.to_string())
+    }
+
+    fn attach(&mut self, name: &str) -> Result<String, String> {
+        let sess_dir = session_dir(name);
+        let id = meta::read(&sess_dir).map(|m| m.pid).unwrap_or(0);
+        let stream = connect(&session_socket_path(name)).map_err(|e| e.to_string())?;
+        let mut stream = UnixStream::from_std(stream);
+        let (cols, rows) = self.size;
+        stream
+            .write_all(&proto::encode(
+                proto::client::RESIZE,
+                &proto::encode_resize(cols, rows),
+            ))
+            .map_err(|e| e.to_string())?;
+        if let Some(mut old) = self.attached.take() {
+            let _ = self.poll.registry().deregister(&mut old.stream);
+        }
+        self.poll
+            .registry()
+            .register(&mut stream, TOKEN_SESSION, Interest::READABLE)
+            .map_err(|e| e.to_string())?;
+        self.attached = Some(Attached {
+            name: name.to_string(),
+            id,
+            stream,
+            decoder: proto::FrameDecoder::new(),
+        });
+        let notification = format!("%session-changed ${} {}\n", id, name);
+        self.write(notification.as_bytes())
+            .map_err(|e| e.to_string())?;
+        Ok(String::new())
+    }
+
+    fn send_input(&mut self, name: &str, input: &[u8]) -> Result<(), String> {
+        let msg = proto::encode(proto::client::INPUT, input);
+        match self.attached.as_mut() {
+            Some(attached) if attached.name == name => attached.stream.write_all(&msg),
+            _ => control::send(name, proto::client::INPUT, input),
+        }
+        .map_err(|e| e.to_string())
+    }
+
+    /// Format variables for the single window / pane of session `name`.
+    fn pane_vars(&self, name: &str) -> Result<Vec<(&'static str, String)>, String> {
+        let entry = SessionEntry::load(name.to_string());
+        let info = control::request(name, proto::client::INFO, &[])
+            .map(|reply| reply["info"].clone())
+            .unwrap_or_default();
+        let id = entry.meta.as_ref().map_or(0, |m| m.pid);
+        let text = |key: &str| info[key].as_str().unwrap_or_default().to_string();
+        let number = |key: &str, default: u16| {
+            info[key]
+                .as_u64()
+                .map_or(default.to_string(), |n| n.to_string())
+        };
+        let cols = number("cols", self.size.0);
+        let rows = number("rows", self.size.1);
+        let command = entry
+            .meta
+            .as_ref()
+            .and_then(|m| m.command.first())
+            .map(|c| c.rsplit('/').next().unwrap_or(c).to_string())
+            .unwrap_or_default();
+        let layout = layout(cols.parse().unwrap_or(0), rows.parse().unwrap_or(0), id);
+
+        let mut vars = session_vars(&entry);
+        vars.extend([
+            ("window_id", format!("@{}", id)),
+            ("window_index", "0".to_string()),
+            ("window_name", command.clone()),
+            ("window_active", "1".to_string()),
+            ("window_panes", "1".to_string()),
+            ("window_width", cols.clone()),
+            ("window_height", rows.clone()),
+            ("window_layout", layout.clone()),
+            ("window_visible_layout", layout),
+            ("pane_id", format!("%{}", id)),
+            ("pane_index", "0".to_string()),
+            ("pane_active", "1".to_string()),
+            ("pane_width", cols),
+            ("pane_height", rows),
+            ("pane_pid", number("pid", 0)),
+            ("pane_current_command", command),
+            ("pane_current_path", text("cwd")),
+            ("pane_title", name.to_string()),
+        ]);
+        Ok(vars)
+    }
+
+    /// Forward daemon output as `%output` notifications.
+    fn read_session(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        let Some(attached) = self.attached.as_mut() else {
+            return Ok(());
+        };
+        let mut closed = false;
+        loop {
+            match attached.stream.read(buf) {
+                Ok(0) => {
+                    closed = true;
+                    break;
+                }
+                Ok(n) => attached.decoder.feed(&buf[..n]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(_) => {
+                    closed = true;
+                    break;
+                }
+            }
+        }
+
+        let mut out = Vec::new();
+        while let Some(frame) = attached.decoder.next_frame() {
+            match frame.msg_type {
+                proto::server::OUTPUT | proto::server::STATE_SYNC => {
+                    out.extend_from_slice(format!("%output %{} ", attached.id).as_bytes());
+                    escape_output(&frame.payload, &mut out);
+                    out.push(b'\n');
+                }
+                proto::server::PING => {
+                    attached
+                        .stream
+                        .write_all(&proto::encode(proto::client::PONG, &[]))?;
+                }
+                proto::server::EXIT | proto::server::DETACHED => closed = true,
+                _ => {}
+            }
+        }
+        if closed {
+            self.exit = true;
+        }
+        self.write(&out)
+    }
+}
+
+/// Connect to a session socket, agree on the protocol and make the stream
+/// non-blocking.
+fn connect(path: &Path) -> io::Result<std::os::unix::net::UnixStream> {
+    let mut stream = std::os::unix::net::UnixStream::connect(path)?;
+    control::hello(&mut stream)?;
+    stream.set_nonblocking(true)?;
+    Ok(stream)
+}
+
+/// Command arguments split into single-letter flags, `-x value` options and
+/// the remaining positional arguments.
+#[derive(Default)]
+struct Args {
+    flags: Vec<char>,
+    values: Vec<(char, String)>,
+    rest: Vec<String>,
+}
+
+impl Args {
+    /// Options that take a value; every other `-x` is a flag.
+    const VALUE_OPTIONS: &'static str = "tFCcs";
+
+    fn parse(words: &[String]) -> Self {
+        let mut args = Self::default();
+        let mut words = words.iter();
+        while let Some(word) = words.next() {
+            if word == "--" {
+                args.rest.extend(words.by_ref().cloned());
+                break;
+            }
+            let Some(letters) = word.strip_prefix('-').filter(|l| !l.is_empty()) else {
+                args.rest.push(word.clone());
+                args.rest.extend(words.by_ref().cloned());
+                break;
+            };
+            for (i, letter) in letters.char_indices() {
+                if Self::VALUE_OPTIONS.contains(letter) {
+                    let inline = &letters[i + letter.len_utf8()..];
+                    let value = if inline.is_empty() {
+                        words.next().cloned().unwrap_or_default()
+                    } else {
+                        inline.to_string()
+                    };
+                    args.values.push((letter, value));
+                    break;
+                }
+                args.flags.push(letter);
+            }
+        }
+        args
+    }
+
+    fn flag(&self, letter: char) -> bool {
+        self.flags.contains(&letter)
+    }
+
+    fn value(&self, letter: char) -> Option<&str> {
+        self.values
+            .iter()
+            .rev()
+            .find(|(l, _)| *l == letter)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+fn session_vars(entry: &SessionEntry) -> Vec<(&'static str, String)> {
+    let meta = entry.meta.clone().unwrap_or_default();
+    vec![
+        ("session_name", entry.name.clone()),
+        ("session_id", format!("${}", meta.pid)),
+        ("session_windows", "1".to_string()),
+        ("session_attached", meta.clients.to_string()),
+        ("session_created", meta.created_at.to_string()),
+        ("session_created_string", ctime(meta.created_at)),
+        ("session_command", shell_join(&meta.command)),
+    ]
+}
+
+/// Expand `#{name}` and `#{?name,then,else}` in a tmux format. Unknown
+/// variables expand to nothing; a variable is true unless empty or `0`.
+fn expand(format: &str, vars: &[(&str, String)]) -> String {
+    let lookup = |name: &str| {
+        vars.iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, v)| v.as_str())
+            .unwrap_or_default()
+    };
+    let mut out = String::new();
+    let mut rest = format;
+    while let Some(start) = rest.find("#{") {
+        out.push_str(&rest[..start]);
+        let Some(len) = rest[start..].find('}') else {
+            rest = &rest[start..];
+            break;
+        };
+        let body = &rest[start + 2..start + len];
+        match body.strip_prefix('?') {
+            Some(conditional) => {
+                let mut parts = conditional.splitn(3, ',');
+                let value = lookup(parts.next().unwrap_or_default());
+                let (then, otherwise) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+                out.push_str(if value.is_empty() || value == "0" {
+                    otherwise
+                } else {
+                    then
+                });
+            }
+            None => out.push_str(lookup(body)),
+        }
+        rest = &rest[start + len + 1..];
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Escape pane output the way tmux does for `%output`: bytes below space and
+/// backslash become `\ooo` octal escapes.
+fn escape_output(data: &[u8], out: &mut Vec<u8>) {
+    for &b in data {
+        if b < b' ' || b == b'\\' {
+            out.extend_from_slice(format!("\\{:03o}", b).as_bytes());
+        } else {
+            out.push(b);
+        }
+    }
+}
+
+/// Bytes for a `send-keys` key name; anything that is not a key name is sent
+/// literally.
+fn key_bytes(key: &str) -> Vec<u8> {
+    let named: &[u8] = match key {
+        "Enter" => b"\r",
+        "Tab" => b"\t",
+        "BTab" => b"\x1b[Z",
+        "Space" => b" ",
+        "Escape" => b"\x1b",
+        "BSpace" => b"\x7f",
+        "Up" => b"\x1b[A",
+        "Down" => b"\x1b[B",
+        "Right" => b"\x1b[C",
+        "Left" => b"\x1b[D",
+        "Home" => b"\x1b[H",
+        "End" => b"\x1b[F",
+        "IC" | "Insert" => b"\x1b[2~",
+        "DC" | "Delete" => b"\x1b[3~",
+        "PPage" | "PageUp" => b"\x1b[5~",
+        "NPage" | "PageDown" => b"\x1b[6~",
+        "F1" => b"\x1bOP",
+        "F2" => b"\x1bOQ",
+        "F3" => b"\x1bOR",
+        "F4" => b"\x1bOS",
+        _ => b"",
+    };
+    if !named.is_empty() {
+        return named.to_vec();
+    }
+    if let Some(rest) = key.strip_prefix("M-").filter(|r| !r.is_empty()) {
+        let mut bytes = vec![0x1b];
+        bytes.extend(key_bytes(rest));
+        return bytes;
+    }
+    if let Some(rest) = key.strip_prefix("C-").or_else(|| key.strip_prefix('^')) {
+        if let [c] = rest.as_bytes() {
+            return match c.to_ascii_lowercase() {
+                c @ b'a'..=b'z' => vec![c - b'a' + 1],
+                b' ' | b'@' => vec![0],
+                b'[' => vec![0x1b],
+                b'\\' => vec![0x1c],
+                b']' => vec![0x1d],
+                b'?' => vec![0x7f],
+                _ => key.as_bytes().to_vec(),
+            };
+        }
+    }
+    key.as_bytes().to_vec()
+}
+
+fn hex_keys(words: &[String]) -> Result<Vec<u8>, String> {
+    words
+        .iter()
+        .map(|w| {
+            u8::from_str_radix(w.trim_start_matches("0x"), 16)
+                .map_err(|_| format!("invalid hex key: {}", w))
+        })
+        .collect()
+}
+
+/// Parse `WxH` or `W,H`.
+fn parse_size(size: &str) -> Option<(u16, u16)> {
+    let (cols, rows) = size.split_once(['x', ','])?;
+    let size = (cols.parse().ok()?, rows.parse().ok()?);
+    (size.0 > 0 && size.1 > 0).then_some(size)
+}
+
+/// Single-pane tmux window layout string, checksum included.
+fn layout(cols: u16, rows: u16, pane: i32) -> String {
+    let body = format!("{}x{},0,0,{}", cols, rows, pane);
+    let mut csum: u16 = 0;
+    for b in body.bytes() {
+        csum = (csum >> 1) | ((csum & 1) << 15);
+        csum = csum.wrapping_add(b as u16);
+    }
+    format!("{:04x},{}", csum, body)
+}
+
+/// Local time in `ctime(3)` format, as tmux shows creation times.
+fn ctime(time: u64) -> String {
+    let time = time as libc::time_t;
+    let mut buf = [0 as libc::c_char; 32];
+    let formatted = unsafe { libc::ctime_r(&time, buf.as_mut_ptr()) };
+    if formatted.is_null() {
+        return time.to_string();
+    }
+    unsafe { std::ffi::CStr::from_ptr(buf.as_ptr()) }
+        .to_string_lossy()
+        .trim_end()
+        .to_string()
+}
+
+/// Turns terminal echo off while control mode runs on a tty, as `tmux -CC`
+/// does; commands typed by the client are not meant to be displayed.
+struct EchoGuard {
+    fd: i32,
+    original: nix::sys::termios::Termios,
+}
+
+impl EchoGuard {
+    fn disable(fd: i32) -> Option<Self> {
+        use nix::sys::termios::{tcgetattr, tcsetattr, LocalFlags, SetArg};
+        let borrowed = unsafe { std::os::fd::BorrowedFd::borrow_raw(fd) };
+        let original = tcgetattr(borrowed).ok()?;
+        let mut termios = original.clone();
+        termios.local_flags.remove(LocalFlags::ECHO);
+        tcsetattr(borrowed, SetArg::TCSANOW, &termios).ok()?;
+        Some(Self { fd, original })
+    }
+}
+
+impl Drop for EchoGuard {
+    fn drop(&mut self) {
+        use nix::sys::termios::{tcsetattr, SetArg};
+        let borrowed = unsafe { std::os::fd::BorrowedFd::borrow_raw(self.fd) };
+        let _ = tcsetattr(borrowed, SetArg::TCSANOW, &self.original);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(line: &str) -> Vec<String> {
+        split_words(line).unwrap()
+    }
+
+    #[test]
+    fn args_split_flags_values_and_rest() {
+        let args = Args::parse(&words("-lt dev:0.0 echo -n hi"));
+        assert!(args.flag('l'));
+        assert_eq!(args.value('t'), Some("dev:0.0"));
+        assert_eq!(args.rest, ["echo", "-n", "hi"]);
+
+        let args = Args::parse(&words("-C120x40 -F '#{session_name}'"));
+        assert_eq!(args.value('C'), Some("120x40"));
+        assert_eq!(args.value('F'), Some("#{session_name}"));
+    }
+
+    #[test]
+    fn expand_formats_and_conditionals() {
+        let vars = [
+            ("session_name", "dev".to_string()),
+            ("session_attached", "0".to_string()),
+        ];
+        assert_eq!(
+            expand(
+                "#{session_name}#{?session_attached, (attached),}#{nope}!",
+                &vars
+            ),
+            "dev!"
+        );
+        assert_eq!(expand("a #{x", &vars), "a #{x");
+    }
+
+    #[test]
+    fn output_is_octal_escaped() {
+        let mut out = Vec::new();
+        escape_output(b"a\\b\r\n\x1b[m", &mut out);
+        assert_eq!(out, b"a\\134b\\015\\012\\033[m");
+    }
+
+    #[test]
+    fn send_keys_translates_key_names() {
+        assert_eq!(key_bytes("Enter"), b"\r");
+        assert_eq!(key_bytes("C-c"), [0x03]);
+        assert_eq!(key_bytes("M-x"), b"\x1bx");
+        assert_eq!(key_bytes("echo"), b"echo");
+        assert_eq!(hex_keys(&words("41 0x0d")).unwrap(), b"A\r");
+        assert_eq!(parse_size("120,40"), Some((120, 40)));
+        assert_eq!(parse_size("0x40"), None);
+    }
+
+    #[test]
+    fn layout_has_checksum_prefix() {
+        let layout = layout(80, 24, 7);
+        let (csum, body) = layout.split_once(',').unwrap();
+        assert_eq!(body, "80x24,0,0,7");
+        assert_eq!(csum.len(), 4);
+        assert!(u16::from_str_radix(csum, 16).is_ok());
+    }
+}
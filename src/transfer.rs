@@ -0,0 +1,113 @@
+//! `pterm transfer`: hand a running session over to another user, e.g. at a
+//! shift change.
+//!
+//! A daemon accepts connections only from its owner (the user that started
+//! it, until a transfer) and root, checked with `SO_PEERCRED`. Handing it
+//! over takes root, reached through the same privilege wrapper as
+//! `--as-root`: the root helper tells the daemon the new owner with
+//! `client::TRANSFER`, which detaches everyone else, then gives the session
+//! directory and socket to the new owner. The directory stays writable for
+//! its group, so the daemon, still running as the previous user, can keep
+//! updating its metadata. The session's processes keep their user.
+
+use crate::control;
+use nix::unistd::{Gid, Uid, User};
+use pterm_proto as proto;
+use std::ffi::CString;
+use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
+use std::path::Path;
+
+/// Hidden subcommand run through the privilege wrapper.
+pub const HELPER_COMMAND: &str = "__transfer";
+
+/// Give the session in `session_dir` to `user`. Must run as root. Returns
+/// the daemon's reply.
+pub fn hand_over(session_dir: &Path, user: &User) -> Result<serde_json::Value, String> {
+    if !nix::unistd::geteuid().is_root() {
+        return Err("transferring a session requires root".to_string());
+    }
+    let socket = session_dir.join(crate::paths::SOCKET_FILENAME);
+    match std::fs::symlink_metadata(&socket) {
+        Ok(meta) if meta.file_type().is_socket() => {}
+        _ => return Err(format!("no session socket in {}", session_dir.display())),
+    }
+    check_reachable(session_dir, user)?;
+
+    let payload = serde_json::json!({ "owner": user.uid.as_raw() }).to_string();
+    let reply = control::request_at(&socket, proto::client::TRANSFER, payload.as_bytes())
+        .map_err(|e| e.to_string())?;
+    if let Some(error) = reply["error"].as_str() {
+        return Err(error.to_string());
+    }
+
+    let give = |path: &Path, mode: u32| -> std::io::Result<()> {
+        nix::unistd::chown(path, Some(user.uid), None)?;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+    };
+    give(session_dir, 0o770)
+        .and_then(|()| give(&socket, 0o700))
+        .map_err(|e| format!("{}: {}", session_dir.display(), e))?;
+    Ok(reply)
+}
+
+/// The new owner must be able to reach the session directory: a session in
+/// a private socket root has to move to a shared one (`[[socket_dirs]]`)
+/// first.
+fn check_reachable(session_dir: &Path, user: &User) -> Result<(), String> {
+    let name = CString::new(user.name.as_str()).map_err(|e| e.to_string())?;
+    let groups: Vec<u32> = nix::unistd::getgrouplist(&name, user.gid)
+        .unwrap_or_else(|_| vec![user.gid])
+        .into_iter()
+        .map(Gid::as_raw)
+        .collect();
+    for dir in session_dir.ancestors().skip(1) {
+        let meta = std::fs::metadata(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+        if !can_search(&meta, user.uid, &groups) {
+            return Err(format!(
+                "{} cannot reach {}: move the session to a shared socket root first",
+                user.name,
+                dir.display()
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn can_search(meta: &std::fs::Metadata, uid: Uid, groups: &[u32]) -> bool {
+    let mode = meta.mode();
+    if uid.is_root() {
+        true
+    } else if meta.uid() == uid.as_raw() {
+        mode & 0o100 != 0
+    } else if groups.contains(&meta.gid()) {
+        mode & 0o010 != 0
+    } else {
+        mode & 0o001 != 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn search_permission_by_owner_group_and_others() {
+        let dir = std::env::temp_dir().join(format!("pterm-transfer-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let meta = std::fs::metadata(&dir).unwrap();
+        let (owner, group) = (Uid::from_raw(meta.uid()), meta.gid());
+        let stranger = Uid::from_raw(meta.uid().wrapping_add(4242));
+
+        std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o710)).unwrap();
+        let meta = std::fs::metadata(&dir).unwrap();
+        assert!(can_search(&meta, owner, &[]));
+        assert!(can_search(&meta, stranger, &[group]));
+        assert!(!can_search(&meta, stranger, &[]));
+
+        std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o701)).unwrap();
+        let meta = std::fs::metadata(&dir).unwrap();
+        assert!(!can_search(&meta, stranger, &[group]));
+        assert!(can_search(&meta, stranger, &[]));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
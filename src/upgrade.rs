@@ -0,0 +1,302 @@
+//! Hot upgrade of a running daemon to a new pterm binary.
+//!
+//! On an UPGRADE request the daemon serializes its session state into the
+//! session directory, clears `FD_CLOEXEC` on the PTY master (and stderr pipe),
+//! the listening socket and every client socket, and `execv`s the new binary
+//! as `pterm __resume <session_dir>`. Because the process image is replaced in
+//! place, the child stays a child of the daemon (so `waitpid` keeps working),
+//! the socket never disappears, and attached clients stay connected.
+//!
+//! The terminal state crosses the exec as escape sequences, which the new
+//! daemon replays into a fresh terminal parser: the scrollback history,
+//! then a snapshot of the screen (history is dropped while the alternate
+//! screen is active). A scrollback file (`--scrollback-file`) is reopened by
+//! the new image, which keeps appending to it.
+
+use crate::clients::ClientIdentity;
+use crate::config::Buffers;
+use crate::hook::ExitHook;
+use crate::meta::{Activity, SessionStats};
+use crate::options::SessionOptions;
+use crate::paths::SocketHome;
+use crate::share::Shares;
+use crate::watch::Watch;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::io;
+use std::os::fd::{FromRawFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+const STATE_FILENAME: &str = "upgrade.json";
+const SNAPSHOT_FILENAME: &str = "upgrade.snapshot";
+
+/// Hidden subcommand the re-exec'd binary is started with.
+pub const RESUME_COMMAND: &str = "__resume";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UpgradedClient {
+    pub id: usize,
+    pub fd: RawFd,
+    pub attached: bool,
+    #[serde(default)]
+    pub read_only: bool,
+    #[serde(default)]
+    pub connected_at: u64,
+    #[serde(default)]
+    pub input_stream: Option<u64>,
+    #[serde(default)]
+    pub compression: bool,
+    #[serde(default)]
+    pub stderr: bool,
+    #[serde(default)]
+    pub output_seq: bool,
+    #[serde(default)]
+    pub exit_details: bool,
+    #[serde(default)]
+    pub checksum: bool,
+    /// Output window negotiated in `HELLO`.
+    #[serde(default)]
+    pub window: Option<u64>,
+    /// `FRAME_MARK`s sent so far, if the client asked for them.
+    #[serde(default)]
+    pub frame_marks: Option<u64>,
+    #[serde(default)]
+    pub identity: Option<ClientIdentity>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UpgradeState {
+    pub session: String,
+    pub command: Vec<String>,
+    pub child_pid: i32,
+    pub pty_fd: RawFd,
+    /// Read end of the child's stderr pipe (`--split-stderr`).
+    #[serde(default)]
+    pub stderr_fd: Option<RawFd>,
+    pub listener_fd: RawFd,
+    pub cols: u16,
+    pub rows: u16,
+    pub created_at: u64,
+    pub next_client_id: usize,
+    pub clients: Vec<UpgradedClient>,
+    #[serde(default)]
+    pub options: SessionOptions,
+    #[serde(default)]
+    pub suspended: bool,
+    #[serde(default)]
+    pub stats: SessionStats,
+    /// Capacity of the session's scrollback file, reopened after the exec.
+    #[serde(default)]
+    pub scrollback_file: Option<usize>,
+    /// Capacity of the session's stderr file (`--split-stderr`).
+    #[serde(default)]
+    pub stderr_file: Option<usize>,
+    /// Output stream position (`OUTPUT_SEQ`) reached.
+    #[serde(default)]
+    pub stream_position: u64,
+    /// Whether SGR sequences are stripped from the scrollback file.
+    #[serde(default)]
+    pub strip_colors: bool,
+    /// Whether the session archives its output (`--history`).
+    #[serde(default)]
+    pub history: bool,
+    /// Output watchers from `pterm on-output`.
+    #[serde(default)]
+    pub watches: Vec<Watch>,
+    /// Buffer sizes the session was created with.
+    #[serde(default)]
+    pub buffers: Buffers,
+    /// Uid the session was handed to with `pterm transfer`.
+    #[serde(default)]
+    pub owner: Option<u32>,
+    /// Where the socket is re-created if its root disappears.
+    #[serde(default)]
+    pub socket_home: Option<SocketHome>,
+    #[serde(default)]
+    pub fallback_socket_dir: Option<PathBuf>,
+    /// Last applied sequence number of each input stream.
+    #[serde(default)]
+    pub input_streams: HashMap<u64, u64>,
+    /// Session the session was created inside.
+    #[serde(default)]
+    pub nested_in: Option<String>,
+    /// Outstanding `pterm share` tokens.
+    #[serde(default)]
+    pub shares: Shares,
+    /// Output frames numbered so far, for `FRAME_MARK`.
+    #[serde(default)]
+    pub output_frames: u64,
+    /// Per-minute activity histogram.
+    #[serde(default)]
+    pub activity: Activity,
+    /// `pterm on-exit` command.
+    #[serde(default)]
+    pub exit_hook: Option<ExitHook>,
+    /// Processes the pty master was passed to for direct input.
+    #[serde(default)]
+    pub direct_input: Vec<i32>,
+}
+
+/// Persist `state` and `snapshot` into `session_dir`.
+pub fn write_state(session_dir: &Path, state: &UpgradeState, snapshot: &[u8]) -> io::Result<()> {
+    let data = serde_json::to_vec(state).map_err(io::Error::other)?;
+    std::fs::write(session_dir.join(STATE_FILENAME), data)?;
+    std::fs::write(session_dir.join(SNAPSHOT_FILENAME), snapshot)
+}
+
+/// Read and remove the state left by the previous daemon image.
+pub fn take_state(session_dir: &Path) -> io::Result<(UpgradeState, Vec<u8>)> {
+    let state_path = session_dir.join(STATE_FILENAME);
+    let snapshot_path = session_dir.join(SNAPSHOT_FILENAME);
+    let data = std::fs::read(&state_path)?;
+    let snapshot = std::fs::read(&snapshot_path).unwrap_or_default();
+    let _ = std::fs::remove_file(&state_path);
+    let _ = std::fs::remove_file(&snapshot_path);
+    let state =
+        serde_json::from_slice(&data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok((state, snapshot))
+}
+
+/// Set or clear `FD_CLOEXEC` on `fd`.
+pub fn set_cloexec(fd: RawFd, cloexec: bool) -> io::Result<()> {
+    let flags = unsafe { nix::libc::fcntl(fd, nix::libc::F_GETFD) };
+    if flags == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    let flags = if cloexec {
+        flags | nix::libc::FD_CLOEXEC
+    } else {
+        flags & !nix::libc::FD_CLOEXEC
+    };
+    if unsafe { nix::libc::fcntl(fd, nix::libc::F_SETFD, flags) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Take over a descriptor inherited from the previous image: restore
+/// `FD_CLOEXEC`, so commands the new image starts do not inherit it, and
+/// make it non-blocking for the event loop.
+///
+/// # Safety
+/// `fd` must be an open descriptor of the kind `T` wraps, owned by nothing
+/// else.
+pub unsafe fn adopt_fd<T: FromRawFd>(fd: RawFd) -> io::Result<T> {
+    set_cloexec(fd, true)?;
+    let flags = nix::libc::fcntl(fd, nix::libc::F_GETFL);
+    if flags == -1 || nix::libc::fcntl(fd, nix::libc::F_SETFL, flags | nix::libc::O_NONBLOCK) == -1
+    {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(T::from_raw_fd(fd))
+}
+
+/// Replace the current process image with `binary __resume <session_dir>`.
+/// Only returns on failure.
+pub fn exec_resume(binary: &Path, session_dir: &Path) -> io::Error {
+    let to_cstring = |bytes: &[u8]| {
+        CString::new(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    };
+    let args = (|| {
+        Ok::<_, io::Error>([
+            to_cstring(binary.as_os_str().as_bytes())?,
+            to_cstring(RESUME_COMMAND.as_bytes())?,
+            to_cstring(session_dir.as_os_str().as_bytes())?,
+        ])
+    })();
+    match args {
+        Ok(args) => match nix::unistd::execv(&args[0], &args) {
+            Ok(never) => match never {},
+            Err(e) => io::Error::other(e),
+        },
+        Err(e) => e,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::Terminal;
+    use std::io::{Read, Write};
+    use std::os::fd::IntoRawFd;
+    use std::os::unix::net::{UnixListener, UnixStream};
+
+    fn cloexec(fd: RawFd) -> bool {
+        unsafe { nix::libc::fcntl(fd, nix::libc::F_GETFD) & nix::libc::FD_CLOEXEC != 0 }
+    }
+
+    #[test]
+    fn state_survives_the_handoff_and_an_inherited_listener_is_adopted() {
+        let dir = std::env::temp_dir().join(format!("pterm-upgrade-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let socket = dir.join("socket");
+        let listener = UnixListener::bind(&socket).unwrap();
+        let listener_fd = listener.into_raw_fd();
+        // What `exec_upgrade` does before the exec.
+        set_cloexec(listener_fd, false).unwrap();
+        assert!(!cloexec(listener_fd));
+
+        let mut state: UpgradeState = serde_json::from_value(serde_json::json!({
+            "session": "dev",
+            "command": ["/bin/sh", "-l"],
+            "child_pid": 42,
+            "pty_fd": 7,
+            "listener_fd": listener_fd,
+            "cols": 120,
+            "rows": 40,
+            "created_at": 1_700_000_000,
+            "next_client_id": 3,
+            "clients": [{ "id": 2, "fd": 9, "attached": true, "window": 65536 }],
+        }))
+        .unwrap();
+        state.stderr_fd = Some(8);
+        state.stream_position = 123_456;
+        state.exit_hook = Some(ExitHook {
+            command: vec!["notify-send".to_string()],
+            lines: 5,
+        });
+        let mut terminal = Terminal::new(5, 20);
+        for i in 0..30 {
+            terminal.process(format!("line {}\r\n", i).as_bytes(), &mut Vec::new());
+        }
+        terminal.process(b"prompt$ ", &mut Vec::new());
+        write_state(&dir, &state, &terminal.upgrade_snapshot()).unwrap();
+
+        let (taken, snapshot) = take_state(&dir).unwrap();
+        assert_eq!(
+            serde_json::to_value(&taken).unwrap(),
+            serde_json::to_value(&state).unwrap()
+        );
+        let mut resumed = Terminal::resume(5, 20, Terminal::SCROLLBACK_LINES, &snapshot);
+        assert_eq!(resumed.contents(), terminal.contents());
+        // Lines long scrolled off the screen come back as history.
+        let lines = resumed.capture(false).lines;
+        assert_eq!(lines, terminal.capture(false).lines);
+        assert_eq!(lines[0], "line 0");
+        // Taken once: a later start must not resume from it again.
+        assert_eq!(
+            take_state(&dir).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+
+        let listener: UnixListener = unsafe { adopt_fd(taken.listener_fd).unwrap() };
+        assert!(cloexec(listener_fd));
+        assert_eq!(
+            listener.accept().unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+        let mut client = UnixStream::connect(&socket).unwrap();
+        client.write_all(b"hello").unwrap();
+        let (accepted, _) = listener.accept().unwrap();
+        let mut accepted: UnixStream = unsafe { adopt_fd(accepted.into_raw_fd()).unwrap() };
+        let mut buf = [0u8; 5];
+        accepted.set_nonblocking(false).unwrap();
+        accepted.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,201 @@
+//! `pterm wait [--any | --all] <session>...`: block until sessions' commands
+//! exit, so scripts can start several persistent jobs and pick up their
+//! results without polling.
+//!
+//! Each session is watched on its own thread over a control connection that
+//! asks for the exit details in `HELLO` and then sends `INFO`: the answer
+//! says whether the command has already exited (a `hold` session), and if
+//! not, the daemon sends `EXIT` on that connection once it does. A session
+//! whose daemon is gone is looked up in its `meta.json`, which keeps the
+//! exit status.
+
+use crate::control;
+use crate::meta::{self, ExitInfo};
+use crate::paths::{session_dir, session_socket_path};
+use pterm_proto as proto;
+use std::io::{self, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+/// Wait for the first session to finish, or for all of them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Mode {
+    Any,
+    #[default]
+    All,
+}
+
+/// A session whose command finished.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Finished {
+    pub name: String,
+    pub exit: ExitInfo,
+}
+
+impl Finished {
+    /// `<name>: exited with <code>` or `<name>: killed by <signal>`.
+    pub fn describe(&self) -> String {
+        match self.exit.signal {
+            Some(_) => format!("{}: killed by {}", self.name, self.exit.reason()),
+            None => format!("{}: exited with {}", self.name, self.exit.code),
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({ "session": self.name, "exit": self.exit })
+    }
+}
+
+/// Wait for `names` as `mode` says, calling `report` as each finishes.
+/// Returns the finished sessions in the order they finished; fewer than
+/// asked for if `timeout` elapsed first.
+pub fn run(
+    names: &[String],
+    mode: Mode,
+    timeout: Option<Duration>,
+    mut report: impl FnMut(&Finished),
+) -> io::Result<Vec<Finished>> {
+    let (tx, rx) = mpsc::channel();
+    for name in names {
+        let (tx, name) = (tx.clone(), name.clone());
+        std::thread::spawn(move || {
+            let result = wait_one(&name);
+            let _ = tx.send((name, result));
+        });
+    }
+    drop(tx);
+
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    let wanted = match mode {
+        Mode::Any => 1,
+        Mode::All => names.len(),
+    };
+    let mut finished = Vec::new();
+    while finished.len() < wanted {
+        let received = match deadline {
+            Some(deadline) => {
+                match rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                    Ok(received) => received,
+                    Err(_) => break,
+                }
+            }
+            None => match rx.recv() {
+                Ok(received) => received,
+                Err(_) => break,
+            },
+        };
+        let (name, exit) = received;
+        let exit = exit.map_err(|e| io::Error::new(e.kind(), format!("{}: {}", name, e)))?;
+        let done = Finished { name, exit };
+        report(&done);
+        finished.push(done);
+    }
+    Ok(finished)
+}
+
+/// The exit status to leave with: the first session's for `--any`, else 0
+/// if every command succeeded and the first failure in `names` otherwise.
+pub fn exit_code(names: &[String], finished: &[Finished], mode: Mode) -> i32 {
+    match mode {
+        Mode::Any => finished.first().map_or(0, |f| f.exit.code),
+        Mode::All => names
+            .iter()
+            .filter_map(|name| finished.iter().find(|f| &f.name == name))
+            .map(|f| f.exit.code)
+            .find(|&code| code != 0)
+            .unwrap_or(0),
+    }
+}
+
+/// Block until the command of session `name` has exited.
+fn wait_one(name: &str) -> io::Result<ExitInfo> {
+    let mut stream = match UnixStream::connect(session_socket_path(name)) {
+        Ok(stream) => stream,
+        Err(e) => return recorded_exit(name).ok_or(e),
+    };
+    control::handshake(
+        &mut stream,
+        control::HelloRequest {
+            exit_details: true,
+            ..control::HelloRequest::default()
+        },
+    )?;
+    stream.write_all(&proto::encode(proto::client::INFO, &[]))?;
+
+    let mut decoder = proto::FrameDecoder::new();
+    let mut buf = [0u8; 65536];
+    loop {
+        let n = match stream.read(&mut buf) {
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        if n == 0 {
+            // The daemon went away without EXIT, e.g. `pterm kill`, which
+            // still records the status if the command had exited.
+            return recorded_exit(name).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "the session ended without an exit status",
+                )
+            });
+        }
+        for frame in decoder.decode(&buf[..n]) {
+            match frame.msg_type {
+                proto::server::REPLY => {
+                    let reply: serde_json::Value =
+                        serde_json::from_slice(&frame.payload).unwrap_or_default();
+                    if let Ok(exit) = serde_json::from_value(reply["info"]["exit"].clone()) {
+                        return Ok(exit);
+                    }
+                }
+                proto::server::EXIT => {
+                    let code = proto::parse_exit(&frame.payload)
+                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                    return Ok(serde_json::from_slice(proto::exit_details(&frame.payload))
+                        .unwrap_or(ExitInfo {
+                            code,
+                            ..ExitInfo::default()
+                        }));
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Exit status kept in the `meta.json` of a session whose daemon is gone.
+fn recorded_exit(name: &str) -> Option<ExitInfo> {
+    let meta = meta::read(&session_dir(name)).ok()?;
+    meta.exit.or(meta.exit_code.map(|code| ExitInfo {
+        code,
+        ..ExitInfo::default()
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn all_fails_with_the_first_failure_in_argument_order() {
+        let names = ["build", "test", "lint"].map(String::from);
+        let finished = |name: &str, code| Finished {
+            name: name.to_string(),
+            exit: ExitInfo {
+                code,
+                ..ExitInfo::default()
+            },
+        };
+        let done = [
+            finished("lint", 1),
+            finished("build", 0),
+            finished("test", 2),
+        ];
+        assert_eq!(exit_code(&names, &done, Mode::All), 2);
+        assert_eq!(exit_code(&names, &done, Mode::Any), 1);
+        assert_eq!(exit_code(&names, &done[1..2], Mode::All), 0);
+        assert_eq!(done[2].describe(), "test: exited with 2");
+    }
+}
@@ -0,0 +1,179 @@
+//! `pterm on-output`: daemon-side watchers that run a command whenever a
+//! pattern appears in a session's output.
+//!
+//! Output is matched line by line against plain substrings, after SGR
+//! sequences are removed so colored output still matches. The command runs
+//! directly (no shell) with the session name and the matching line in its
+//! environment. A watcher whose previous run is still going does not fire
+//! again, so a flood of matching lines starts at most one process per
+//! watcher at a time.
+
+use crate::scrollback::SgrStripper;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::process::{Child, Command, Stdio};
+
+/// Longest line kept for matching; the rest of a longer line is ignored.
+const MAX_LINE: usize = 4096;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Watch {
+    pub id: u32,
+    /// Fires when a line contains any of these.
+    pub patterns: Vec<String>,
+    pub command: Vec<String>,
+    /// Times the command was started.
+    #[serde(default)]
+    pub fired: u64,
+}
+
+/// A line of output that matched watch `id`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Match {
+    pub id: u32,
+    pub line: String,
+}
+
+#[derive(Debug, Default)]
+pub struct Watchers {
+    watches: Vec<Watch>,
+    /// Commands started and not yet reaped, by watch id.
+    running: HashMap<u32, Child>,
+    /// Current output line, without SGR sequences.
+    line: Vec<u8>,
+    stripper: SgrStripper,
+}
+
+impl Watchers {
+    /// Watchers carried over from a previous daemon image.
+    pub fn restore(watches: Vec<Watch>) -> Self {
+        Self {
+            watches,
+            ..Self::default()
+        }
+    }
+
+    pub fn list(&self) -> &[Watch] {
+        &self.watches
+    }
+
+    /// Add a watch and return its id.
+    pub fn add(&mut self, patterns: Vec<String>, command: Vec<String>) -> Result<u32, String> {
+        if patterns.is_empty() || patterns.iter().any(String::is_empty) {
+            return Err("a non-empty pattern is required".to_string());
+        }
+        if command.is_empty() {
+            return Err("a command is required".to_string());
+        }
+        let id = self.watches.iter().map(|w| w.id).max().unwrap_or(0) + 1;
+        self.watches.push(Watch {
+            id,
+            patterns,
+            command,
+            fired: 0,
+        });
+        Ok(id)
+    }
+
+    pub fn remove(&mut self, id: u32) -> bool {
+        let before = self.watches.len();
+        self.watches.retain(|w| w.id != id);
+        self.watches.len() != before
+    }
+
+    /// Feed PTY output and return the watches matched by each line it
+    /// completes.
+    pub fn feed(&mut self, data: &[u8]) -> Vec<Match> {
+        if self.watches.is_empty() {
+            self.line.clear();
+            return Vec::new();
+        }
+        let mut stripped = Vec::with_capacity(data.len());
+        self.stripper.filter(data, &mut stripped);
+        let mut matches = Vec::new();
+        for byte in stripped {
+            if byte != b'\n' {
+                if self.line.len() < MAX_LINE {
+                    self.line.push(byte);
+                }
+                continue;
+            }
+            let line = String::from_utf8_lossy(&self.line);
+            let line = line.trim_end_matches('\r');
+            for watch in &self.watches {
+                if watch.patterns.iter().any(|p| line.contains(p.as_str())) {
+                    matches.push(Match {
+                        id: watch.id,
+                        line: line.to_string(),
+                    });
+                }
+            }
+            self.line.clear();
+        }
+        matches
+    }
+
+    /// Start the command of the matched watch, unless its previous run is
+    /// still going. `PTERM_SESSION` and `PTERM_MATCH` (the line) are set.
+    pub fn run(&mut self, m: &Match, session: &str) {
+        self.reap();
+        if self.running.contains_key(&m.id) {
+            return;
+        }
+        let Some(watch) = self.watches.iter_mut().find(|w| w.id == m.id) else {
+            return;
+        };
+        let spawned = Command::new(&watch.command[0])
+            .args(&watch.command[1..])
+            .env("PTERM_SESSION", session)
+            .env("PTERM_MATCH", &m.line)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn();
+        match spawned {
+            Ok(child) => {
+                log::info!("Watch {} matched, started {}", m.id, watch.command[0]);
+                watch.fired += 1;
+                self.running.insert(m.id, child);
+            }
+            Err(e) => log::warn!("Watch {}: failed to run {}: {}", m.id, watch.command[0], e),
+        }
+    }
+
+    /// Collect commands that have finished.
+    pub fn reap(&mut self) {
+        self.running
+            .retain(|_, child| matches!(child.try_wait(), Ok(None)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn feed_matches_complete_lines_through_colors() {
+        let mut watchers = Watchers::default();
+        assert!(watchers.feed(b"panicked at x\n").is_empty());
+
+        let id = watchers
+            .add(vec!["panicked at".to_string()], vec!["true".to_string()])
+            .unwrap();
+        assert!(watchers.feed(b"thread 'main' \x1b[31mpan").is_empty());
+        let matches = watchers.feed(b"icked\x1b[m at src/main.rs:3\r\nok\n");
+        assert_eq!(
+            matches,
+            [Match {
+                id,
+                line: "thread 'main' panicked at src/main.rs:3".to_string()
+            }]
+        );
+
+        assert!(watchers
+            .add(vec![String::new()], vec!["true".to_string()])
+            .is_err());
+        assert!(watchers.remove(id));
+        assert!(watchers.feed(b"panicked at y\n").is_empty());
+    }
+}
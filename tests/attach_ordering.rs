@@ -0,0 +1,136 @@
+//! Attach ordering under heavy output: every attached client must see
+//! `STATE_SYNC` before any `OUTPUT`, and a re-attach RESIZE must never leave
+//! the frame stream torn, however far behind the client is.
+
+use pterm_proto as proto;
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{Duration, Instant};
+
+const SESSION: &str = "heavy";
+
+struct Daemon {
+    socket_dir: PathBuf,
+}
+
+impl Daemon {
+    fn start() -> Self {
+        let socket_dir =
+            std::env::temp_dir().join(format!("pterm-attach-ordering-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&socket_dir);
+        let status = Command::new(env!("CARGO_BIN_EXE_pterm"))
+            .env("PTERM_SOCKET_DIR", &socket_dir)
+            .args(["new", "--wait-ready", SESSION, "--"])
+            .args(["sh", "-c", "while :; do seq 1 5000; done"])
+            .stdout(std::process::Stdio::null())
+            .status()
+            .unwrap();
+        assert!(status.success());
+        Self { socket_dir }
+    }
+
+    fn socket(&self) -> PathBuf {
+        self.socket_dir.join(SESSION).join("socket")
+    }
+}
+
+impl Drop for Daemon {
+    fn drop(&mut self) {
+        let _ = Command::new(env!("CARGO_BIN_EXE_pterm"))
+            .env("PTERM_SOCKET_DIR", &self.socket_dir)
+            .args(["kill", SESSION])
+            .output();
+        let _ = std::fs::remove_dir_all(&self.socket_dir);
+    }
+}
+
+fn resize(stream: &mut UnixStream, cols: u16, rows: u16) {
+    let mut payload = cols.to_le_bytes().to_vec();
+    payload.extend(rows.to_le_bytes());
+    stream
+        .write_all(&proto::encode(proto::client::RESIZE, &payload))
+        .unwrap();
+}
+
+/// Read frames for `duration`, at most `chunk` bytes every 5ms (small chunks
+/// make the daemon's send queue for this client back up). Returns the
+/// message types seen.
+fn read_frames(
+    stream: &mut UnixStream,
+    recv_buf: &mut Vec<u8>,
+    duration: Duration,
+    chunk: usize,
+) -> Vec<u8> {
+    let mut types = Vec::new();
+    let mut buf = vec![0u8; chunk];
+    let deadline = Instant::now() + duration;
+    while Instant::now() < deadline {
+        match stream.read(&mut buf) {
+            Ok(0) => panic!("daemon closed the connection"),
+            Ok(n) => recv_buf.extend_from_slice(&buf[..n]),
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
+            Err(e) => panic!("read failed: {}", e),
+        }
+        types.extend(proto::decode_frames(recv_buf).iter().map(|f| f.msg_type));
+        std::thread::sleep(Duration::from_millis(5));
+    }
+    types
+}
+
+fn attach_and_reattach(socket: &Path, cols: u16) {
+    let mut stream = UnixStream::connect(socket).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_millis(20)))
+        .unwrap();
+    let mut recv_buf = Vec::new();
+
+    resize(&mut stream, cols, 24);
+    let mut types = read_frames(&mut stream, &mut recv_buf, Duration::from_millis(1500), 512);
+    assert_eq!(
+        types.first(),
+        Some(&proto::server::STATE_SYNC),
+        "first frame must be STATE_SYNC: {:?}",
+        &types[..types.len().min(8)]
+    );
+
+    // Re-attach at another size while the queue is backed up: the fresh
+    // snapshot replaces queued frames without tearing the one in flight.
+    resize(&mut stream, cols + 1, 25);
+    let after = read_frames(&mut stream, &mut recv_buf, Duration::from_secs(2), 1 << 20);
+    assert!(
+        after.contains(&proto::server::STATE_SYNC),
+        "no STATE_SYNC after re-attach (torn frame stream?)"
+    );
+    types.extend(after);
+
+    let known = [
+        proto::server::OUTPUT,
+        proto::server::STATE_SYNC,
+        proto::server::SESSION_INFO,
+        proto::server::PING,
+    ];
+    for msg_type in types {
+        assert!(
+            known.contains(&msg_type),
+            "torn frame stream: type 0x{:02x}",
+            msg_type
+        );
+    }
+}
+
+#[test]
+fn concurrent_attach_during_heavy_output() {
+    let daemon = Daemon::start();
+    let socket = daemon.socket();
+    let handles: Vec<_> = (0..4)
+        .map(|i| {
+            let socket = socket.clone();
+            std::thread::spawn(move || attach_and_reattach(&socket, 80 + i * 10))
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+}